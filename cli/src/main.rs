@@ -1,26 +1,78 @@
 #![feature(let_chains)]
-use anyhow::{bail, ensure, Ok};
-use clap::Parser;
+use anyhow::{bail, ensure, Context as _, Ok};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use pretty_hex::{HexConfig, PrettyHex};
 use rusb::{Context, DeviceHandle};
+use std::cell::OnceCell;
+use std::collections::VecDeque;
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use tool::image_reader::parse_image;
-use tool::rawtrack::{RawImage, TrackFilter};
-use tool::track_parser::read_first_track_discover_format;
-use tool::track_parser::read_tracks_to_diskimage;
-use tool::usb_commands::configure_device;
-use tool::usb_commands::{wait_for_answer, write_raw_track};
-use tool::usb_device::{clear_buffers, init_usb};
-use tool::write_precompensation::{calibration, WritePrecompDb};
-use util::{DriveSelectState, DRIVE_3_5_RPM, DRIVE_5_25_RPM};
+use std::time::Instant;
+use tool::encoding_override::{apply_encoding_overrides, EncodingOverride};
+use tool::error::ToolError;
+use tool::filesystem::{DosFormat, FileSystem};
+use tool::heatmap::Heatmap;
+use tool::image_reader::image_adf::{parse_adf_image_with_options, AMIGA_SYNC_WORD};
+use tool::image_reader::image_g64::parse_g64_image_with_options;
+use tool::image_reader::image_ipf::parse_ipf_image_with_options;
+use tool::image_reader::image_iso::{iso_image_from_data, parse_iso_image_with_options, IsoPreset};
+use tool::image_reader::image_stx::parse_stx_image_with_options;
+use tool::image_reader::{parse_image, supported_read_formats};
+use tool::image_writer::{supported_write_formats, write_image};
+use tool::localization::{tr, tr_with, MessageId};
+use tool::metrics::WriteMetrics;
+use tool::raw_capture::parse_raw_capture;
+use tool::rawtrack::{
+    RawImage, RawTrack, TrackClass, TrackFilter, DEFAULT_ROTATION_MARGIN_PERCENT,
+};
+use tool::resume::ResumeManifest;
+use tool::track_parser::amiga::set_amiga_sync_word;
+use tool::track_parser::iso::set_duplicate_header_threshold;
+use tool::track_parser::verify::{double_read_verify, verify_image, verify_track};
+use tool::track_parser::{
+    check_media_density, discover_scan, estimate_cylinder_count, patch_sector, probe_cylinders,
+    read_first_track_discover_format, read_sector, set_discover_density, set_discover_margin,
+    set_read_rpm, DEFAULT_DISCOVER_MARGIN_PERCENT, DEFAULT_PROBE_CYLINDERS,
+};
+use tool::track_parser::{read_tracks_to_diskimage, ReadOptions};
+use tool::usb_commands::{
+    check_operation_deadline, check_reception, wait_for_answer, write_raw_track,
+};
+use tool::usb_commands::{
+    configure_device, ensure_cylinder_allowed, ensure_max_cylinder_supported,
+    ensure_write_track_supported, is_disk_present, is_write_protected, measure_write_recovery,
+    query_capabilities, query_position, query_rotation, recalibrate, set_cable_type,
+    set_density_latched_at_motor_on, set_detailed_verify, set_head_settle_time,
+    set_invert_density_select, set_max_cylinder, set_motor_off_delay, set_operation_timeout,
+    set_step_rate, set_verify_threshold_extra, verify_threshold_extra, write_pipeline_depth,
+};
+use tool::usb_device::{clear_buffers, init_usb, UsbTransport};
+use tool::write_precompensation::{
+    calibration, precompensation_sweep, WritePrecompDb, WriteProfile,
+    HD_DRIVE_DD_MEDIA_EXTRA_PASSES, HD_DRIVE_DD_MEDIA_EXTRA_PRECOMPENSATION,
+};
+use util::{
+    capabilities::{FEATURE_ROTATION, FEATURE_WRITE_GATE_MARGINS},
+    flippy_index_frequency, index_sim_period, CableType, Correlation, Density, DiskType,
+    DriveSelectState, GapGenerator, PulseDuration, VerifyHistogram, VerifyWindows,
+    WriteGateMargins, DEFAULT_MAX_CYLINDER, DRIVE_3_5_RPM, DRIVE_5_25_RPM,
+    FLIPPY_OFFSET_STEP_TICKS, MAX_FLIPPY_OFFSET_US, MAX_WRITE_PIPELINE_DEPTH, STM_TIMER_HZ,
+    VERIFY_HISTOGRAM_BUCKETS, VERIFY_HISTOGRAM_STEP,
+};
 
 #[derive(Parser, Debug)]
-#[command(author, about, long_about = None)]
+#[command(author, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to disk image
-    filepath: String,
+    #[arg(required = true)]
+    filepath: Option<String>,
 
     /// Read instead of write
     #[arg(short, default_value_t = false)]
@@ -30,6 +82,18 @@ struct Args {
     #[arg(short)]
     debug_text_file: Option<String>,
 
+    /// Number of bytes per line of the raw track data in the debug text file. 0 prints a single line
+    #[arg(long, default_value_t = 16)]
+    hex_width: usize,
+
+    /// Add an ASCII column to the raw track data in the debug text file
+    #[arg(long, default_value_t = false)]
+    hex_ascii: bool,
+
+    /// Separate groups of this many bytes in the raw track data of the debug text file. 0 disables grouping
+    #[arg(long, default_value_t = 0)]
+    hex_group: usize,
+
     /// Only write some tracks: eg. range 2-4 or single track 8
     #[arg(short)]
     track_filter: Option<String>,
@@ -46,29 +110,631 @@ struct Args {
     #[arg(short, default_value_t = false)]
     wprecomp_calib: bool,
 
-    /// Simulate index signal for flipped 5.25" disks with provided timing offset
+    /// Write a single track with every write precompensation value and print the errors: eg. 79:0 (cylinder:head)
+    #[arg(long)]
+    precomp_sweep: Option<String>,
+
+    /// Simulate index signal for flipped 5.25" disks. The rotation is shortened
+    /// in steps of 1000 timer ticks, which are about 11.9µs
     #[arg(short, long)]
     flippy: Option<u32>,
+
+    /// Simulate the index signal with this frequency in Hz instead of deriving it from --flippy
+    #[arg(long)]
+    index_sim_hz: Option<f64>,
+
+    /// Sizes of the verify cross correlation windows as pulses: eg. 20:200 (compare:read)
+    /// Optionally the number of pulses at the start of the track to not verify: eg. 20:200:30
+    #[arg(long)]
+    verify_windows: Option<String>,
+
+    /// Number of revolutions to record and decode independently during reading
+    #[arg(long, default_value_t = 1)]
+    revolutions: usize,
+
+    /// Combine the sectors of this many reads if a track can't be read in one piece
+    #[arg(long, default_value_t = 0)]
+    majority_reads: usize,
+
+    /// Combine ISO sectors with a wrong checksum in every read of --majority-reads byte by byte
+    #[arg(long, default_value_t = false)]
+    vote_raw_bytes: bool,
+
+    /// Transfer flux timings in full resolution during reading. Needs twice the USB bandwidth
+    #[arg(long, default_value_t = false)]
+    high_resolution: bool,
+
+    /// Write every sector to its own file in this directory instead of creating an image
+    #[arg(long)]
+    split_sectors: Option<String>,
+
+    /// Describe the detected geometry in a .geometry.txt file next to the image during reading
+    #[arg(long, default_value_t = false)]
+    write_geometry: bool,
+
+    /// Store the physical order of the sectors in a .interleave.txt file next to the image during reading
+    #[arg(long, default_value_t = false)]
+    preserve_interleave: bool,
+
+    /// Keep the distance of the first sector to the index. Stored like --preserve-interleave
+    /// during reading and applied to ISO images during writing
+    #[arg(long, default_value_t = false)]
+    preserve_index_timing: bool,
+
+    /// List the files touching sectors which were not recovered during reading: fat12
+    #[arg(long)]
+    affected_files: Option<String>,
+
+    /// Additionally store the tracks with their read metadata in a container at this path during reading
+    #[arg(long)]
+    rich_output: Option<String>,
+
+    /// Store the cell size of every 16 data bytes in a .timing.txt file next to the image during reading
+    #[arg(long, default_value_t = false)]
+    capture_timing: bool,
+
+    /// Keep ISO sectors with a wrong checksum of the data during reading instead of dropping them
+    #[arg(long, default_value_t = false)]
+    accept_bad_crc: bool,
+
+    /// Additionally write a hex dump of the decoded data of every track to this text file during reading
+    #[arg(long)]
+    read_dump: Option<String>,
+
+    /// Additionally store the tracks as STX image at this path during reading. Preserves CRC errors and timing
+    #[arg(long)]
+    stx_output: Option<String>,
+
+    /// Additionally store the tracks of a C64 disk as G64 image at this path during reading. Preserves killer tracks
+    #[arg(long)]
+    g64_output: Option<String>,
+
+    /// Read the flipped side of a 5.25" disk with the top head. Requires --flippy or --index-sim-hz
+    #[arg(long, default_value_t = false)]
+    flippy_side: bool,
+
+    /// Fill unreadable tracks with zeros and continue reading instead of aborting
+    #[arg(long, default_value_t = false)]
+    skip_bad_tracks: bool,
+
+    /// Abort skipping bad tracks after this many unreadable tracks in a row
+    #[arg(long)]
+    max_consecutive_failures: Option<usize>,
+
+    /// The 3.5" drive only supports HD media and writes a DD disk. Increases the write
+    /// precompensation and writes failed tracks again
+    #[arg(long, default_value_t = false)]
+    hd_drive_dd_media: bool,
+
+    /// Read with the parser of this format instead of the one of the file extension: eg. st
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Store the flux timings of every read attempt in this directory for offline parser development
+    #[arg(long)]
+    save_raw: Option<String>,
+
+    /// Fill unformatted tracks with zeros during reading instead of aborting
+    #[arg(long, default_value_t = false)]
+    allow_blank_tracks: bool,
+
+    /// Layout of a sector image for ISO based systems: msx or sf7000. Also used by --patch-sector
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Value of the gaps between the sectors of ISO images: eg. 0x4E or 78
+    #[arg(long)]
+    gap_fill: Option<String>,
+
+    /// Numbers of the sectors of every track of ISO images in the order of the image: eg. 0xC1,0xC2,0xC3
+    #[arg(long)]
+    sector_ids: Option<String>,
+
+    /// Correct the boot sector checksum of an Atari ST image to make it bootable
+    #[arg(long, default_value_t = false)]
+    atari_boot: bool,
+
+    /// Only write tracks without signs of a copy protection like multiple densities or an unusual length
+    #[arg(long, default_value_t = false)]
+    standard_only: bool,
+
+    /// Only write tracks with signs of a copy protection. The opposite of --standard-only
+    #[arg(long, default_value_t = false)]
+    irregular_only: bool,
+
+    /// Reject STX tracks which must be shortened by more than this factor to fit into a rotation.
+    /// Between 0.8 and 1.0, 0.99 by default
+    #[arg(long)]
+    stx_min_correction_factor: Option<f64>,
+
+    /// Write tracks of an ADF which are empty and unused by the filesystem without sectors
+    #[arg(long, default_value_t = false)]
+    fast_blank: bool,
+
+    /// Put this number of encoded zero bytes between the sectors of an ADF for picky custom loaders
+    #[arg(long)]
+    amiga_sector_gap: Option<usize>,
+
+    /// Write an ADF with this sync word instead of 0x4489 for custom trackdisk formats.
+    /// Only for .adf images
+    #[arg(long)]
+    amiga_sync_word: Option<String>,
+
+    /// Try multiple cylinders when discovering the format. Useful for damaged disks
+    #[arg(long, default_value_t = false)]
+    discover_scan: bool,
+
+    /// Density tried first when discovering the format: dd or hd. The other one is tried afterwards
+    #[arg(long)]
+    discover_density: Option<String>,
+
+    /// Recording duration when discovering the format in percent of a rotation
+    #[arg(long, default_value_t = DEFAULT_DISCOVER_MARGIN_PERCENT)]
+    discover_margin: u32,
+
+    /// Measured speed of the drive. Shortens the recordings of a read for fast drives
+    #[arg(long)]
+    read_rpm: Option<f64>,
+
+    /// Write the tracks ordered by cylinder to reduce the movement of the head
+    #[arg(long, default_value_t = false)]
+    seek_optimal: bool,
+
+    /// Print the density map of every track. No USB communication
+    #[arg(long, default_value_t = false)]
+    show_density: bool,
+
+    /// Encode tracks again with a different encoding: eg. 2:0=mfm,3:1=gcr
+    #[arg(long)]
+    force_encoding: Option<String>,
+
+    /// Generator for long gaps without flux reversals: weak-bits, non-flux-reversal or off.
+    /// Applies to every track or to single tracks: eg. 2:0=non-flux-reversal,3:1=off
+    #[arg(long)]
+    gap_generator: Option<String>,
+
+    /// Compare the decoded content of the converted IPF tracks with the original ones
+    #[arg(long, default_value_t = false)]
+    verify_ipf: bool,
+
+    /// Decode the tracks of a G64 image again and check their speed zones to find encoding problems
+    #[arg(long, default_value_t = false)]
+    verify_g64: bool,
+
+    /// Read a single sector and print it as hex dump: eg. 0:0:1 (cylinder:head:sector). The image is ignored
+    #[arg(long)]
+    read_sector: Option<String>,
+
+    /// Replace a single sector with the content of a file: eg. 0:0:1=boot.bin (cylinder:head:sector=file).
+    /// The track is read, patched and written again. The image is ignored
+    #[arg(long)]
+    patch_sector: Option<String>,
+
+    /// Read cylinders 39, 40, 79 and 80 to find out how many cylinders the disk has. The image is ignored
+    #[arg(long, default_value_t = false)]
+    probe_cylinders: bool,
+
+    /// Wait after writing a track before verifying it. Given in µs with steps of 100µs
+    #[arg(long)]
+    settle_delay_us: Option<u32>,
+
+    /// Delay the data after the index while the write gate is already active. Given in µs up to 700µs
+    #[arg(long)]
+    write_lead_in_us: Option<u32>,
+
+    /// Keep the write gate active after the data of a track. Given in µs up to 700µs
+    #[arg(long)]
+    write_lead_out_us: Option<u32>,
+
+    /// Synchronize the verification on the position with the longest agreement instead of the first match.
+    /// Slower but avoids false matches on tracks with repetitive patterns
+    #[arg(long, default_value_t = false)]
+    longest_agreement: bool,
+
+    /// Warn about tracks which might be too long for drives rotating faster by this margin
+    #[arg(long, default_value_t = DEFAULT_ROTATION_MARGIN_PERCENT)]
+    rotation_margin_percent: f64,
+
+    /// Extend the gap at the end of every ISO and Amiga track to fill the rotation
+    /// measured by the device minus the rotation margin
+    #[arg(long, default_value_t = false)]
+    fill_rotation: bool,
+
+    /// Continue with the remaining tracks if a track fails to verify and report all failures at the end
+    #[arg(long, default_value_t = false)]
+    keep_going: bool,
+
+    /// Read the first track before writing and warn if the density of the disk doesn't match the image
+    #[arg(long, default_value_t = false)]
+    check_density: bool,
+
+    /// Record the verified tracks in this manifest during the write. If it already exists,
+    /// an interrupted write is continued and the listed tracks are skipped
+    #[arg(long)]
+    resume: Option<String>,
+
+    /// Print the distribution of the timing differences between written and read back flux
+    /// of every verified track
+    #[arg(long, default_value_t = false)]
+    verify_histogram: bool,
+
+    /// After writing, read every track twice and compare the reads with each other.
+    /// Finds weak media which passes the normal verify but doesn't hold the data reliably
+    #[arg(long, default_value_t = false)]
+    double_read_verify: bool,
+
+    /// Only verify every Nth track to speed up writing of known good media. The other tracks are just written
+    #[arg(long)]
+    verify_every: Option<usize>,
+
+    /// Don't write but compare the disk against the image. ISO tracks are also checked for their structure
+    #[arg(long, default_value_t = false)]
+    verify_only: bool,
+
+    /// Only print errors and the final summary instead of a line per track
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+
+    /// Keep the motor spinning after an operation. Minimum is 1200
+    #[arg(long)]
+    motor_off_delay_ms: Option<u32>,
+
+    /// Drive the density select signal with the opposite polarity for non standard drives
+    #[arg(long, default_value_t = false)]
+    invert_density_select: bool,
+
+    /// Restart the motor after a density change for drives which only read the density select at motor on
+    #[arg(long, default_value_t = false)]
+    density_latched_at_motor_on: bool,
+
+    /// Move the head to track 0 to get a known position. The image is ignored
+    #[arg(long, default_value_t = false)]
+    recalibrate: bool,
+
+    /// Measure how long the drive needs after writing until it can read again: eg. 79:0 (cylinder:head).
+    /// The content of this track is destroyed. The image is ignored
+    #[arg(long)]
+    measure_write_recovery: Option<String>,
+
+    /// Write the image, read it back and compare the tracks decoded on the host with the image
+    #[arg(long, default_value_t = false)]
+    selftest: bool,
+
+    /// Wait after stepping before the head is used. Older drives might need more than the default of 20
+    #[arg(long)]
+    head_settle_ms: Option<u32>,
+
+    /// Time between two step pulses. Worn drives which lose steps might need more than the default of 4
+    #[arg(long)]
+    step_rate_ms: Option<u32>,
+
+    /// Abort reading or writing if it takes longer than this. Prevents unattended setups from hanging forever
+    #[arg(long)]
+    operation_timeout_secs: Option<u64>,
+
+    /// Loosen the verification of higher cylinders. Percent of a cell added at cylinder 79. Maximum is 10
+    #[arg(long)]
+    inner_verify_tolerance: Option<u32>,
+
+    /// Wait for Enter after every written track. Combined with -t to debug single cylinders
+    #[arg(long, default_value_t = false)]
+    interactive: bool,
+
+    /// Tracks transferred ahead while another one is written. Limited by the memory of the device. Maximum is 3
+    #[arg(long, default_value_t = 1)]
+    pipeline_depth: usize,
+
+    /// Write the image with every pipeline depth and print the throughput. The disk is written up to 3 times
+    #[arg(long, default_value_t = false)]
+    measure_pipeline: bool,
+
+    /// Last cylinder to read or write. Writing allows up to 83 by default. Reading stops at 79 unless given
+    #[arg(long)]
+    max_cylinder: Option<u32>,
+
+    /// Assume a 5.25" drive for ISO disks if more duplicate sector headers are read.
+    /// Only used if the rotation couldn't be measured. Default is 5
+    #[arg(long)]
+    duplicate_header_threshold: Option<usize>,
+
+    /// Print how the write precompensation of every track is interpolated from wprecomp.cfg
+    #[arg(long, default_value_t = false)]
+    show_precomp: bool,
+
+    /// Bundled write parameters for the media: conservative, normal or aggressive.
+    /// Parameters given explicitly take precedence
+    #[arg(long, default_value = "normal")]
+    profile: String,
+
+    /// Check the write precompensation configuration given as file path. No USB communication.
+    /// Gaps are searched up to --max-cylinder
+    #[arg(long, default_value_t = false)]
+    validate_wprecomp: bool,
+
+    /// Fixed write precompensation for cylinders instead of wprecomp.cfg: eg. 40-79=6,80=8
+    #[arg(long)]
+    precomp: Option<String>,
+
+    /// Write statistics of writing and verifying in the textfile format of Prometheus to this file
+    #[arg(long)]
+    metrics_file: Option<String>,
+
+    /// Write the duration, writes and reads of every track as CSV to this file: eg. timings.csv
+    #[arg(long)]
+    timings: Option<String>,
+
+    /// Write a PNG image of the verify quality of every track after writing: eg. quality.png
+    #[arg(long)]
+    heatmap: Option<String>,
+
+    /// Refuse to write if the disk change signal reports no disk.
+    /// Drives without this signal would always be refused
+    #[arg(long, default_value_t = false)]
+    require_disk: bool,
+
+    /// Wiring of the drive select lines: pc-twist, straight or shugart
+    #[arg(long, default_value = "pc-twist")]
+    cable: String,
+
+    /// Type of the connected drive: 3.5 or 5.25. Warns before writing an image not meant for it.
+    /// Sizes the recordings of a read for the rotation of the drive
+    #[arg(long)]
+    drive_type: Option<String>,
+}
+
+/// Operations which don't write an image file to disk
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert an image to another format given by the extension of the output. No USB communication
+    Convert {
+        /// Path to the image to convert
+        input: String,
+
+        /// Path of the converted image: eg. out.g64
+        output: String,
+    },
+    /// Decode a capture stored by --save-raw with the parser of a format. No USB communication
+    ParseRaw {
+        /// Path to the capture: eg. captures/c05_h1_r0.raw
+        capture: String,
+
+        /// Format of the captured track: eg. adf
+        #[arg(long)]
+        format: String,
+    },
+    /// Write an empty MS-DOS disk with a FAT12 filesystem
+    Format {
+        /// Blank disk to write: dos720 or dos1440
+        #[arg(long, default_value = "dos720")]
+        r#type: String,
+
+        /// Make the disk bootable. It asks for a system disk on start
+        #[arg(long, default_value_t = false)]
+        bootable: bool,
+
+        /// Volume label of the disk
+        #[arg(long)]
+        label: Option<String>,
+    },
+}
+
+/// Lowercase extension of the image. Options of a format are only applied to its images.
+fn image_extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn parse_verify_windows(param: &str) -> anyhow::Result<VerifyWindows> {
+    let mut fields = param.split(':');
+    let compare = fields.next().context("Expected format compare:read")?;
+    let read_data = fields.next().context("Expected format compare:read")?;
+    let skip_pulses = match fields.next() {
+        Some(skip_pulses) => skip_pulses.parse()?,
+        None => VerifyWindows::DEFAULT_SKIP_PULSES,
+    };
+    ensure!(fields.next().is_none(), "Expected format compare:read:skip");
+
+    let windows = VerifyWindows {
+        compare: compare.parse()?,
+        read_data: read_data.parse()?,
+        skip_pulses,
+        settle_delay_us: 0,
+        correlation: Correlation::FirstMatch,
+    };
+
+    ensure!(
+        windows == windows.bounded(),
+        "Verify windows exceed the limits of the firmware. Maximum is {}:{}:{}, read must be at least twice as large as compare and skip must not exceed read minus compare",
+        VerifyWindows::MAX_COMPARE,
+        VerifyWindows::MAX_READ_DATA,
+        VerifyWindows::MAX_SKIP_PULSES
+    );
+
+    Ok(windows)
+}
+
+fn parse_cable_type(param: &str) -> anyhow::Result<CableType> {
+    Ok(match param {
+        "pc-twist" => CableType::PcTwist,
+        "straight" => CableType::Straight,
+        "shugart" => CableType::Shugart,
+        _ => bail!("Unknown cable {param}. Expected pc-twist, straight or shugart"),
+    })
+}
+
+fn parse_drive_type(param: &str) -> anyhow::Result<DiskType> {
+    Ok(match param {
+        "3.5" => DiskType::Inch3_5,
+        "5.25" => DiskType::Inch5_25,
+        _ => bail!("Unknown drive type {param}. Expected 3.5 or 5.25"),
+    })
+}
+
+fn parse_density(param: &str) -> anyhow::Result<Density> {
+    Ok(match param {
+        "dd" => Density::SingleDouble,
+        "hd" => Density::High,
+        _ => bail!("Unknown density {param}. Expected dd or hd"),
+    })
+}
+
+fn parse_gap_generator(param: &str) -> anyhow::Result<GapGenerator> {
+    Ok(match param {
+        "weak-bits" => GapGenerator::WeakBits,
+        "non-flux-reversal" => GapGenerator::NonFluxReversal,
+        "off" => GapGenerator::Disabled,
+        _ => bail!("Unknown gap generator {param}. Expected weak-bits, non-flux-reversal or off"),
+    })
+}
+
+/// Forces the gap generator of every track or of the tracks given like 2:0=off
+fn apply_gap_generators(image: &mut RawImage, param: &str) -> anyhow::Result<()> {
+    for entry in param.split(',') {
+        let Some((position, gap_generator)) = entry.split_once('=') else {
+            let gap_generator = parse_gap_generator(entry)?;
+            for track in &mut image.tracks {
+                track.gap_generator = Some(gap_generator);
+            }
+            continue;
+        };
+
+        let (cylinder, head) = parse_track_position(position)?;
+        let track = image
+            .tracks
+            .iter_mut()
+            .find(|f| f.cylinder == cylinder && f.head == head)
+            .with_context(|| format!("Image has no track {position}"))?;
+        track.gap_generator = Some(parse_gap_generator(gap_generator)?);
+    }
+    Ok(())
+}
+
+/// Parses cylinder ranges with a fixed write precompensation like 40-79=6,80=8
+fn parse_precomp_ranges(param: &str) -> anyhow::Result<Vec<(RangeInclusive<u32>, u32)>> {
+    param
+        .split(',')
+        .map(|entry| {
+            let (cylinders, precomp) = entry
+                .split_once('=')
+                .context("Expected format cylinder-cylinder=precomp")?;
+            let cylinders = match cylinders.split_once('-') {
+                Some((first, last)) => first.parse()?..=last.parse()?,
+                None => {
+                    let cylinder = cylinders.parse()?;
+                    cylinder..=cylinder
+                }
+            };
+            ensure!(!cylinders.is_empty(), "Cylinder range of {entry} is empty");
+            let precomp: u8 = precomp.parse()?;
+            Ok((cylinders, u32::from(precomp)))
+        })
+        .collect()
+}
+
+/// Accepts hex with a leading 0x or decimal
+fn parse_byte(param: &str) -> anyhow::Result<u8> {
+    Ok(match param.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16)?,
+        None => param.parse()?,
+    })
+}
+
+fn parse_sector_ids(param: &str) -> anyhow::Result<Vec<u8>> {
+    param
+        .split(',')
+        .map(|f| parse_byte(f).with_context(|| format!("Sector ID {f} is not a byte value")))
+        .collect()
+}
+
+fn parse_sync_word(param: &str) -> anyhow::Result<u16> {
+    let hex = param.strip_prefix("0x").unwrap_or(param);
+    Ok(u16::from_str_radix(hex, 16)?)
+}
+
+fn parse_track_position(param: &str) -> anyhow::Result<(u32, u32)> {
+    let fields: Vec<&str> = param.split(':').collect();
+    let [cylinder, head] = fields.as_slice() else {
+        bail!("Expected format cylinder:head");
+    };
+
+    Ok((cylinder.parse()?, head.parse()?))
+}
+
+fn parse_sector_position(param: &str) -> anyhow::Result<(u32, u32, u32)> {
+    let fields: Vec<&str> = param.split(':').collect();
+    let [cylinder, head, sector] = fields.as_slice() else {
+        bail!("Expected format cylinder:head:sector");
+    };
+
+    Ok((cylinder.parse()?, head.parse()?, sector.parse()?))
+}
+
+fn parse_sector_patch(param: &str) -> anyhow::Result<((u32, u32, u32), &str)> {
+    let Some((position, path)) = param.split_once('=') else {
+        bail!("Expected format cylinder:head:sector=file");
+    };
+
+    Ok((parse_sector_position(position)?, path))
 }
 
 fn write_and_verify_image(
-    usb_handles: &(DeviceHandle<Context>, u8, u8),
-    image: &RawImage,
+    usb_handles: &impl UsbTransport,
+    tracks: &[RawTrack],
+    keep_going: bool,
+    quiet: bool,
+    metrics: &mut WriteMetrics,
+    mut resume: Option<(&mut ResumeManifest, &Path)>,
+    pipeline_depth: usize,
 ) -> Result<(), anyhow::Error> {
-    let mut write_iterator = image.tracks.iter();
-    let mut verify_iterator = image.tracks.iter();
+    // Tracks verified by a previous run are already on the disk
+    let tracks: Vec<&RawTrack> = tracks
+        .iter()
+        .filter(|f| {
+            !resume
+                .as_ref()
+                .is_some_and(|(progress, _)| progress.is_verified(f))
+        })
+        .collect();
+    if tracks.is_empty() {
+        println!("{}", tr(MessageId::ImageWrittenAndVerified));
+        return Ok(());
+    }
+
+    let mut write_iterator = tracks.iter().copied();
+    let mut verify_iterator = tracks.iter().copied();
 
     let mut expected_to_verify = verify_iterator.next();
+    let mut failed_tracks = Vec::new();
+    let mut unverified_tracks = 0;
+    // Transferred tracks which were not yet taken by the device, oldest first
+    let mut awaiting_reception = VecDeque::with_capacity(pipeline_depth);
+    let mut all_written = false;
+    let mut verify_histogram = None;
 
     loop {
-        if let Some(write_track) = write_iterator.next() {
+        check_operation_deadline(usb_handles)?;
+        while awaiting_reception.len() < pipeline_depth
+            && let Some(write_track) = write_iterator.next()
+        {
+            metrics.track_started(write_track.cylinder, write_track.head);
             write_raw_track(usb_handles, write_track)?;
-        } else {
-            println!("All tracks written. Wait for remaining verifications!");
+            awaiting_reception.push_back(write_track);
+        }
+        if awaiting_reception.is_empty() && !all_written {
+            all_written = true;
+            if !quiet {
+                println!("{}", tr(MessageId::WaitForVerifications));
+            }
         }
 
         loop {
-            match wait_for_answer(usb_handles)? {
+            let answer = wait_for_answer(usb_handles)?;
+            metrics.record(&answer);
+
+            let (cylinder, head) = match answer {
                 tool::usb_commands::UsbAnswer::WrittenAndVerified {
                     cylinder,
                     head,
@@ -77,25 +743,31 @@ fn write_and_verify_image(
                     max_err,
                     write_precomp,
                 } => {
-                    println!(
-                    "Verified write of cylinder {} head {} - writes:{}, reads:{}, max_err:{} write_precomp:{}",
-                    cylinder,
-                head,
-                writes,
-                reads,
-                max_err,
-                write_precomp,
-                );
-
-                    if let Some(track) = expected_to_verify {
-                        ensure!(track.cylinder == cylinder);
-                        ensure!(track.head == head);
+                    if reads == 0 {
+                        unverified_tracks += 1;
+                        if !quiet {
+                            println!(
+                                "Written cylinder {cylinder} head {head} without verification"
+                            );
+                        }
+                    } else if !quiet {
+                        println!(
+                            "Verified write of cylinder {} head {} - writes:{}, reads:{}, max_err:{} write_precomp:{}",
+                            cylinder, head, writes, reads, max_err, write_precomp,
+                        );
                     }
-                    expected_to_verify = verify_iterator.next();
-                    if expected_to_verify.is_none() {
-                        println!("--- Disk Image written and verified! ---");
-                        return Ok(());
+                    if let Some(histogram) = verify_histogram.take() {
+                        print_verify_histogram(&histogram);
                     }
+                    // Stored after every track to survive any kind of interruption
+                    if let Some((progress, path)) = resume.as_mut() {
+                        progress.mark_verified(cylinder, head);
+                        // The write itself is still fine
+                        if let Err(error) = progress.write(path) {
+                            println!("Unable to store the progress: {error:#}");
+                        }
+                    }
+                    (cylinder, head)
                 }
                 tool::usb_commands::UsbAnswer::Fail {
                     cylinder,
@@ -103,35 +775,310 @@ fn write_and_verify_image(
                     writes,
                     reads,
                     error,
-                } => bail!(
-                    "Failed writing track {} head {} - num_writes:{}, num_reads:{} error:{}",
+                } => {
+                    let error = ToolError::VerificationFailed {
+                        cylinder,
+                        head,
+                        writes,
+                        reads,
+                        error,
+                    };
+                    if !keep_going {
+                        bail!(error);
+                    }
+
+                    // Remember the failure and proceed with the remaining tracks
+                    println!("{error}");
+                    failed_tracks.push((cylinder, head));
+                    (cylinder, head)
+                }
+                tool::usb_commands::UsbAnswer::GotCmd { reception } => {
+                    if let Some(track) = awaiting_reception.pop_front() {
+                        check_reception(usb_handles, track, reception)?;
+                    }
+                    break;
+                }
+                tool::usb_commands::UsbAnswer::WriteProtected => bail!(ToolError::WriteProtected),
+                tool::usb_commands::UsbAnswer::OutOfMemory {
                     cylinder,
                     head,
-                    writes,
-                    reads,
-                    error,
-                ),
-                tool::usb_commands::UsbAnswer::GotCmd => {
-                    break;
+                    size,
+                } => bail!(ToolError::OutOfMemory {
+                    cylinder,
+                    head,
+                    size
+                }),
+                tool::usb_commands::UsbAnswer::VerifyHistogram(histogram) => {
+                    // Arrives before the result of the track
+                    verify_histogram = Some(histogram);
+                    continue;
+                }
+                tool::usb_commands::UsbAnswer::TrackShortened {
+                    cylinder,
+                    head,
+                    removed_bytes,
+                } => {
+                    println!(
+                        "Warning: Track {cylinder} {head} is too long for a rotation of the drive. {removed_bytes} bytes at its end were not written."
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(track) = expected_to_verify {
+                ensure!(track.cylinder == cylinder);
+                ensure!(track.head == head);
+            }
+            expected_to_verify = verify_iterator.next();
+            if expected_to_verify.is_none() {
+                if unverified_tracks > 0 {
+                    let verified_tracks = tracks.len() - unverified_tracks;
+                    println!(
+                        "Verified {verified_tracks} of {} tracks ({:.1}%)",
+                        tracks.len(),
+                        100.0 * verified_tracks as f64 / tracks.len() as f64
+                    );
+                }
+
+                if failed_tracks.is_empty() {
+                    println!("{}", tr(MessageId::ImageWrittenAndVerified));
+                    return Ok(());
                 }
-                tool::usb_commands::UsbAnswer::WriteProtected => bail!("Disk is write protected!"),
+
+                println!("Failed tracks:");
+                for (cylinder, head) in &failed_tracks {
+                    println!("  cylinder {cylinder} head {head}");
+                }
+                bail!(ToolError::VerificationsFailed(failed_tracks));
             }
         }
     }
 }
 
-fn write_debug_text_file(path: &str, image: &RawImage) {
-    let f = File::create(path).expect("Unable to create file");
-    let mut f = BufWriter::new(f);
+/// Writes and verifies one track after another. Waits for Enter on `input` before the next one
+/// to allow measurements or a change of the disk. Failed tracks don't stop the write.
+fn interactive_write_and_verify(
+    usb_handles: &impl UsbTransport,
+    input: &mut impl BufRead,
+    tracks: &[RawTrack],
+    quiet: bool,
+    metrics: &mut WriteMetrics,
+    mut resume: Option<(&mut ResumeManifest, &Path)>,
+) -> Result<(), anyhow::Error> {
+    let mut failed_tracks = Vec::new();
+
+    for (index, track) in tracks.iter().enumerate() {
+        let progress = resume
+            .as_mut()
+            .map(|(progress, path)| (&mut **progress, *path));
+        let result = write_and_verify_image(
+            usb_handles,
+            std::slice::from_ref(track),
+            true,
+            quiet,
+            metrics,
+            progress,
+            1,
+        );
+        match result {
+            Result::Ok(()) => {}
+            Err(error) => match error.downcast::<ToolError>()? {
+                ToolError::VerificationsFailed(failed) => failed_tracks.extend(failed),
+                error => bail!(error),
+            },
+        }
+
+        if index + 1 < tracks.len() {
+            println!("Press Enter to write the next track");
+            let mut line = String::new();
+            // Closed input ends the session instead of writing the remaining tracks at once
+            if input.read_line(&mut line)? == 0 {
+                bail!(ToolError::Aborted {
+                    cylinder: track.cylinder,
+                    head: track.head
+                });
+            }
+        }
+    }
+
+    if failed_tracks.is_empty() {
+        return Ok(());
+    }
+    bail!(ToolError::VerificationsFailed(failed_tracks))
+}
+
+/// Prints the differences in timer ticks between read back and written flux.
+/// The outer buckets also count everything beyond them.
+fn print_verify_histogram(histogram: &VerifyHistogram) {
+    for (index, count) in histogram.buckets.iter().enumerate() {
+        let lower_bound = VerifyHistogram::lower_bound(index);
+        let upper_bound = lower_bound + VERIFY_HISTOGRAM_STEP;
+        let range = match index {
+            0 => format!("< {upper_bound}"),
+            i if i == VERIFY_HISTOGRAM_BUCKETS - 1 => format!(">= {lower_bound}"),
+            _ => format!("{lower_bound}..{upper_bound}"),
+        };
+        println!("  {range:>8} ticks: {count}");
+    }
+}
+
+/// Writes the image with a pipeline depth of 1 up to the maximum to compare the throughput.
+/// Depths which don't fit into the free heap of the device are skipped.
+fn measure_pipeline(
+    usb_handles: &impl UsbTransport,
+    tracks: &[RawTrack],
+    free_heap: Option<usize>,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    for requested in 1..=MAX_WRITE_PIPELINE_DEPTH {
+        let depth = free_heap.map_or(1, |f| write_pipeline_depth(requested, f, tracks));
+        if depth != requested {
+            println!("Pipeline depth {requested} doesn't fit into the device");
+            continue;
+        }
+
+        let mut metrics = WriteMetrics::default();
+        let start = Instant::now();
+        write_and_verify_image(usb_handles, tracks, true, quiet, &mut metrics, None, depth)?;
+        let duration = start.elapsed().as_secs_f64();
+        println!(
+            "Pipeline depth {depth}: {} tracks in {duration:.1} s, {:.2} tracks per second",
+            tracks.len(),
+            tracks.len() as f64 / duration
+        );
+    }
+    Ok(())
+}
+
+/// A drive which only supports high density media writes with a lower current
+fn apply_hd_drive_dd_media(image: &mut RawImage) -> anyhow::Result<()> {
+    ensure!(
+        matches!(
+            (image.density, image.disk_type),
+            (Density::SingleDouble, util::DiskType::Inch3_5)
+        ),
+        "--hd-drive-dd-media is only meant for 3.5\" double density images"
+    );
+    for track in &mut image.tracks {
+        if track.flux_timings.is_none() {
+            track.write_precompensation += HD_DRIVE_DD_MEDIA_EXTRA_PRECOMPENSATION;
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the tracks which failed to verify for another pass.
+/// False if the write succeeded or failed for another reason.
+fn retain_failed_tracks(tracks: &mut Vec<RawTrack>, result: &anyhow::Result<()>) -> bool {
+    let Err(error) = result else {
+        return false;
+    };
+    let Some(ToolError::VerificationsFailed(failed_tracks)) = error.downcast_ref() else {
+        return false;
+    };
+    tracks.retain(|f| failed_tracks.contains(&(f.cylinder, f.head)));
+    true
+}
+
+/// Tracks which failed to verify in a write which kept going.
+/// Any other error stops the selftest.
+fn write_failures(result: anyhow::Result<()>) -> anyhow::Result<Vec<(u32, u32)>> {
+    match result {
+        Result::Ok(()) => Ok(Vec::new()),
+        Err(error) => match error.downcast::<ToolError>() {
+            Result::Ok(ToolError::VerificationsFailed(tracks)) => Ok(tracks),
+            Result::Ok(error) => bail!(error),
+            Err(error) => Err(error),
+        },
+    }
+}
+
+/// Writes the image, reads it back and decodes the tracks on the host.
+/// This covers the whole chain from encoding to decoding.
+fn selftest(
+    usb_handles: &(DeviceHandle<Context>, u8, u8),
+    image: &RawImage,
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    // Tracks which fail to verify during writing are still read back
+    let mut metrics = WriteMetrics::default();
+    let result = write_and_verify_image(
+        usb_handles,
+        &image.tracks,
+        true,
+        quiet,
+        &mut metrics,
+        None,
+        1,
+    );
+    let write_failures = write_failures(result)?;
+
+    configure_device(
+        usb_handles,
+        select_drive,
+        image.density,
+        index_sim_frequency,
+    )?;
+
+    let mut failed_tracks = Vec::new();
+
+    for track in &image.tracks {
+        let written = !write_failures.contains(&(track.cylinder, track.head));
+        let read_back = verify_track(usb_handles, track);
+
+        if written && read_back.is_ok() {
+            if !quiet {
+                println!("Cylinder {} head {} passed", track.cylinder, track.head);
+            }
+            continue;
+        }
+
+        println!(
+            "Cylinder {} head {} FAILED - write {} read back {}",
+            track.cylinder,
+            track.head,
+            if written { "ok" } else { "failed" },
+            match &read_back {
+                Result::Ok(()) => "ok".into(),
+                Err(error) => format!("failed: {error}"),
+            }
+        );
+        failed_tracks.push((track.cylinder, track.head));
+    }
+
+    if !failed_tracks.is_empty() {
+        println!(
+            "Selftest failed for {} of {} tracks",
+            failed_tracks.len(),
+            image.tracks.len()
+        );
+        bail!(ToolError::VerificationsFailed(failed_tracks));
+    }
+
+    println!(
+        "--- Selftest passed for all {} tracks! ---",
+        image.tracks.len()
+    );
+    Ok(())
+}
 
-    let cfg = HexConfig {
+/// Layout of the raw track data in the debug text file
+fn hex_config(cli: &Args) -> HexConfig {
+    HexConfig {
         title: true,
-        ascii: false,
-        width: 16,
-        group: 0,
+        ascii: cli.hex_ascii,
+        width: cli.hex_width,
+        group: cli.hex_group,
         chunk: 1,
         ..HexConfig::default()
-    };
+    }
+}
+
+fn write_debug_text_file(path: &str, image: &RawImage, cfg: HexConfig) {
+    let f = File::create(path).expect("Unable to create file");
+    let mut f = BufWriter::new(f);
 
     let mut context = md5::Context::new();
 
@@ -176,20 +1123,197 @@ fn write_debug_text_file(path: &str, image: &RawImage) {
     println!("MD5 for unit test: {md5_hashstr}");
 }
 
+/// Lists the image formats for the help text
+fn formats_help() -> String {
+    let list = |formats: &[tool::image_reader::FormatInfo]| -> String {
+        formats
+            .iter()
+            .map(|f| format!("  {:<5}{}\n", f.extension, f.description))
+            .collect()
+    };
+    format!(
+        "Images which can be written to disk:\n{}\nImages which can be created:\n{}",
+        list(&supported_read_formats()),
+        list(&supported_write_formats())
+    )
+}
+
 fn main() {
     env_logger::init();
-    let cli = Args::parse();
+    let matches = Args::command().after_help(formats_help()).get_matches();
+    let cli = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let filepath = match cli.command.as_ref() {
+        Some(Command::Convert { input, .. }) => input.clone(),
+        Some(Command::ParseRaw { capture, .. }) => capture.clone(),
+        Some(Command::Format { .. }) => String::new(),
+        None => cli.filepath.clone().unwrap_or_default(),
+    };
+
+    if let Some(Command::ParseRaw { format, .. }) = cli.command.as_ref() {
+        let track = parse_raw_capture(&filepath, format).unwrap();
+        println!(
+            "Decoded cylinder {} head {} with {} sectors",
+            track.cylinder,
+            track.head,
+            track.sectors.len()
+        );
+        println!("{:?}", track.payload.hex_dump());
+        exit(0);
+    }
+
+    if cli.validate_wprecomp {
+        let file = std::fs::File::open(&filepath).unwrap();
+        let validation = WritePrecompDb::validate(
+            std::io::BufReader::new(file),
+            cli.max_cylinder.unwrap_or(DEFAULT_MAX_CYLINDER),
+        )
+        .unwrap();
+        print!("{validation}");
+        exit(0);
+    }
+
+    let profile = WriteProfile::from_name(&cli.profile)
+        .with_context(|| {
+            format!(
+                "Unknown profile {}. Expected conservative, normal or aggressive",
+                cli.profile
+            )
+        })
+        .unwrap();
 
-    let image = if cli.read {
+    let image = if cli.read
+        || cli.read_sector.is_some()
+        || cli.patch_sector.is_some()
+        || cli.probe_cylinders
+        || cli.recalibrate
+        || cli.measure_write_recovery.is_some()
+    {
         None
     } else {
         let wprecomp_db = WritePrecompDb::new().ok();
 
         // before the make contact to the USB device, we shall read the image first
         // to be sure that it is writeable.
-        let mut image = parse_image(&cli.filepath).unwrap();
-        let rpm = match image.disk_type {
-            util::DiskType::Inch3_5 => DRIVE_3_5_RPM,
+        let extension = image_extension(&filepath);
+        assert!(
+            !cli.atari_boot || extension == "st",
+            "--atari-boot is only supported for .st images"
+        );
+        assert!(
+            !cli.verify_ipf || extension == "ipf",
+            "--verify-ipf is only supported for .ipf images"
+        );
+        assert!(
+            !cli.verify_g64 || extension == "g64",
+            "--verify-g64 is only supported for .g64 images"
+        );
+        assert!(
+            cli.stx_min_correction_factor.is_none() || extension == "stx",
+            "--stx-min-correction-factor is only supported for .stx images"
+        );
+
+        let preset = cli.preset.as_ref().map(|f| {
+            IsoPreset::from_name(f)
+                .with_context(|| format!("Unknown preset {f}. Expected msx or sf7000"))
+                .unwrap()
+        });
+
+        // Sector images are generated as ISO tracks. A preset applies regardless of the extension
+        let iso_image = preset.is_some() || matches!(extension.as_str(), "st" | "img");
+        assert!(
+            cli.gap_fill.is_none() || iso_image,
+            "--gap-fill is only supported for .st and .img images or with --preset"
+        );
+        assert!(
+            !cli.preserve_index_timing || iso_image,
+            "--preserve-index-timing is only supported for .st and .img images or with --preset"
+        );
+        assert!(
+            cli.sector_ids.is_none() || iso_image,
+            "--sector-ids is only supported for .st and .img images or with --preset"
+        );
+        assert!(
+            !cli.fill_rotation || iso_image || extension == "adf",
+            "--fill-rotation is only supported for .adf, .st and .img images or with --preset"
+        );
+
+        let gap_fill = cli.gap_fill.as_ref().map(|f| {
+            parse_byte(f)
+                .with_context(|| format!("Gap fill {f} is not a byte value"))
+                .unwrap()
+        });
+
+        let sector_ids = cli
+            .sector_ids
+            .as_ref()
+            .map(|f| parse_sector_ids(f).unwrap());
+
+        assert!(
+            cli.amiga_sync_word.is_none() || extension == "adf",
+            "--amiga-sync-word is only supported for .adf images"
+        );
+        let amiga_sync_word = cli.amiga_sync_word.as_ref().map(|f| {
+            parse_sync_word(f)
+                .with_context(|| format!("Sync word {f} is not a 16 bit hex value"))
+                .unwrap()
+        });
+        // Decoding the written tracks on the host expects the same sync word
+        if let Some(sync_word) = amiga_sync_word {
+            set_amiga_sync_word(sync_word);
+        }
+
+        let mut image = if let Some(Command::Format {
+            r#type: format_type,
+            bootable,
+            label,
+        }) = cli.command.as_ref()
+        {
+            let format = DosFormat::from_name(format_type)
+                .with_context(|| {
+                    format!("Unknown blank disk {format_type}. Expected dos720 or dos1440")
+                })
+                .unwrap();
+            let data = format.create_image(*bootable, label.as_deref()).unwrap();
+            iso_image_from_data(&data).unwrap()
+        } else if iso_image
+            && (cli.atari_boot
+                || preset.is_some()
+                || gap_fill.is_some()
+                || sector_ids.is_some()
+                || cli.preserve_index_timing)
+        {
+            parse_iso_image_with_options(
+                &filepath,
+                cli.atari_boot,
+                preset,
+                gap_fill,
+                sector_ids,
+                cli.preserve_index_timing,
+            )
+            .unwrap()
+        } else if extension == "ipf" && cli.verify_ipf {
+            parse_ipf_image_with_options(&filepath, true).unwrap()
+        } else if extension == "g64" && cli.verify_g64 {
+            parse_g64_image_with_options(&filepath, true).unwrap()
+        } else if let (Some(min_correction_factor), "stx") =
+            (cli.stx_min_correction_factor, extension.as_str())
+        {
+            parse_stx_image_with_options(&filepath, min_correction_factor).unwrap()
+        } else if cli.fast_blank || cli.amiga_sector_gap.is_some() || amiga_sync_word.is_some() {
+            parse_adf_image_with_options(
+                &filepath,
+                cli.fast_blank,
+                cli.amiga_sector_gap.unwrap_or(0),
+                amiga_sync_word.unwrap_or(AMIGA_SYNC_WORD),
+            )
+            .unwrap()
+        } else {
+            parse_image(&filepath).unwrap()
+        };
+        // Catches parsers which are not called by parse_image as well
+        image.check_densitymaps().unwrap();
+        let rpm = match image.disk_type {
+            util::DiskType::Inch3_5 => DRIVE_3_5_RPM,
             util::DiskType::Inch5_25 => DRIVE_5_25_RPM,
         };
 
@@ -198,38 +1322,201 @@ fn main() {
             image.filter_tracks(filter);
         }
 
-        if let Some(debug_text_file) = cli.debug_text_file {
-            write_debug_text_file(&debug_text_file, &image);
+        assert!(
+            !(cli.wprecomp_calib && cli.precomp_sweep.is_some()),
+            "Specify either -w or --precomp-sweep. NOT BOTH!"
+        );
+        assert!(
+            !(cli.standard_only && cli.irregular_only),
+            "Specify either --standard-only or --irregular-only. NOT BOTH!"
+        );
+        let track_class = if cli.standard_only {
+            Some(TrackClass::Standard)
+        } else if cli.irregular_only {
+            Some(TrackClass::Irregular)
+        } else {
+            None
+        };
+        if let Some(track_class) = track_class {
+            let number_of_tracks = image.tracks.len();
+            image.retain_track_class(track_class);
+            println!(
+                "{} of {number_of_tracks} tracks are {track_class:?}",
+                image.tracks.len()
+            );
+        }
+
+        if let Some(force_encoding) = cli.force_encoding.as_ref() {
+            let overrides = EncodingOverride::parse_list(force_encoding).unwrap();
+            apply_encoding_overrides(&mut image, &overrides).unwrap();
+        }
+
+        if let Some(gap_generator) = cli.gap_generator.as_ref() {
+            apply_gap_generators(&mut image, gap_generator).unwrap();
+        }
+
+        if cli.seek_optimal {
+            image.sort_tracks_for_seeking();
+        }
+
+        if cli.show_density {
+            for track in &image.tracks {
+                print!("{}", track.format_densitymap());
+            }
             exit(0);
         }
 
+        if let Some(debug_text_file) = cli.debug_text_file.as_ref() {
+            write_debug_text_file(debug_text_file, &image, hex_config(&cli));
+            exit(0);
+        }
+
+        if let Some(Command::Convert { output, .. }) = cli.command.as_ref() {
+            write_image(&image, output).unwrap();
+            println!("{}", tr_with(MessageId::ImageConverted, output));
+            exit(0);
+        }
+
+        if let Some(verify_windows) = cli.verify_windows.as_ref() {
+            let verify_windows = parse_verify_windows(verify_windows).unwrap();
+            for track in &mut image.tracks {
+                track.verify_windows = verify_windows;
+            }
+        }
+
+        if let Some(settle_delay_us) = cli.settle_delay_us.or(profile.settle_delay_us()) {
+            let windows = VerifyWindows {
+                settle_delay_us,
+                ..VerifyWindows::default()
+            };
+            assert!(
+                windows.bounded() == windows,
+                "The settle delay must be a multiple of {}µs up to {}µs",
+                VerifyWindows::SETTLE_DELAY_STEP_US,
+                VerifyWindows::MAX_SETTLE_DELAY_US
+            );
+            for track in &mut image.tracks {
+                track.verify_windows.settle_delay_us = settle_delay_us;
+            }
+        }
+
+        if cli.write_lead_in_us.is_some() || cli.write_lead_out_us.is_some() {
+            let write_gate_margins = WriteGateMargins {
+                lead_in_us: cli.write_lead_in_us.unwrap_or(0),
+                lead_out_us: cli.write_lead_out_us.unwrap_or(0),
+            };
+            assert!(
+                write_gate_margins
+                    .lead_in_us
+                    .max(write_gate_margins.lead_out_us)
+                    <= WriteGateMargins::MAX_US,
+                "The write gate margins are limited to {}µs",
+                WriteGateMargins::MAX_US
+            );
+            for track in &mut image.tracks {
+                track.write_gate_margins = write_gate_margins;
+            }
+        }
+
+        // The write gate margins extend the write beyond the track
         for track in &image.tracks {
             track.assert_fits_into_rotation(rpm).unwrap();
             track.check_writability().unwrap();
+            if let Some(warning) = track.check_rotation_margin(rpm, cli.rotation_margin_percent) {
+                println!("{warning}");
+            }
+        }
+
+        if cli.longest_agreement {
+            for track in &mut image.tracks {
+                track.verify_windows.correlation = Correlation::LongestAgreement;
+            }
         }
 
+        if let Some(verify_every) = cli.verify_every {
+            assert!(verify_every >= 1, "--verify-every must be at least 1");
+            for (index, track) in image.tracks.iter_mut().enumerate() {
+                track.write_only = index % verify_every != 0;
+            }
+        }
+
+        let precomp_ranges = cli.precomp.as_ref().map_or_else(Vec::new, |f| {
+            parse_precomp_ranges(f)
+                .with_context(|| format!("Invalid precomp ranges {f}"))
+                .unwrap()
+        });
+
         let mut already_warned_about_wprecomp_fail = false;
         for track in &mut image.tracks {
+            // Ranges given later override the earlier ones
+            let manual_precomp = precomp_ranges
+                .iter()
+                .rev()
+                .find(|(cylinders, _)| cylinders.contains(&track.cylinder));
+            if let Some((_, precomp)) = manual_precomp
+                && !cli.wprecomp_calib
+                && cli.precomp_sweep.is_none()
+                && track.flux_timings.is_none()
+            {
+                if cli.show_precomp {
+                    println!(
+                        "Write precompensation of track {} {}: {precomp} given by --precomp",
+                        track.cylinder, track.head
+                    );
+                }
+                track.write_precompensation = *precomp;
+                continue;
+            }
+
             // only alter the write precompensation if no calibration is performed!
-            if let Some(wprecomp_db) = &wprecomp_db && !cli.wprecomp_calib {
-            track.write_precompensation = wprecomp_db.calculate(
-                track.densitymap[0].cell_size.0 as u32,
-                track.cylinder,
-            ).unwrap_or_else(||{
-                if !already_warned_about_wprecomp_fail{
-                    already_warned_about_wprecomp_fail=true;
-                    println!("Unable to calculate write precompensation for cylinder {} and density {}",track.cylinder,track.densitymap[0].cell_size.0 );
+            // Flux timings are written as provided and have no precompensation.
+            if cli.wprecomp_calib || cli.precomp_sweep.is_some() || track.flux_timings.is_some() {
+                continue;
+            }
+
+            // The profile only adds to values which weren't given by --precomp
+            let extra_precompensation = profile.extra_precompensation();
+            let Some(wprecomp_db) = &wprecomp_db else {
+                track.write_precompensation += extra_precompensation;
+                continue;
+            };
+            let decision =
+                wprecomp_db.explain(track.densitymap[0].cell_size.0 as u32, track.cylinder);
+            track.write_precompensation = decision.result.unwrap_or_else(|| {
+                if !already_warned_about_wprecomp_fail {
+                    already_warned_about_wprecomp_fail = true;
+                    println!(
+                        "Unable to calculate write precompensation for cylinder {} and density {}",
+                        track.cylinder, track.densitymap[0].cell_size.0
+                    );
                 }
                 0
-            });
+            }) + extra_precompensation;
+            if cli.show_precomp {
+                let profile_note = if extra_precompensation > 0 {
+                    format!(
+                        " + {extra_precompensation} of the profile = {}",
+                        track.write_precompensation
+                    )
+                } else {
+                    String::new()
+                };
+                println!(
+                    "Write precompensation of track {} {}: {decision}{profile_note}",
+                    track.cylinder, track.head
+                );
+            }
         }
+
+        if cli.hd_drive_dd_media {
+            apply_hd_drive_dd_media(&mut image).unwrap();
         }
         Some(image)
     };
 
     // connect to USB
     let usb_handles = init_usb().unwrap_or_else(|e| {
-        println!("Unable to initialize the USB device: {:?}", e);
+        println!("{}", tr_with(MessageId::UsbInitFailed, &format!("{e:?}")));
         exit(1);
     });
 
@@ -239,7 +1526,8 @@ fn main() {
 
     assert!(
         !(cli.a_drive && cli.b_drive),
-        "Specify either drive A or B. NOT BOTH!"
+        "{}",
+        tr(MessageId::BothDrivesSelected)
     );
 
     let select_drive = if cli.a_drive {
@@ -247,35 +1535,375 @@ fn main() {
     } else if cli.b_drive {
         DriveSelectState::B
     } else {
-        panic!("No drive selected! Please specifiy with -a or -b");
+        panic!("{}", tr(MessageId::NoDriveSelected));
     };
 
-    let index_sim_frequency = if let Some(flippy_param) = cli.flippy {
-        (14 * 1000 - flippy_param) * 1000
+    set_invert_density_select(cli.invert_density_select);
+    set_density_latched_at_motor_on(cli.density_latched_at_motor_on);
+    set_detailed_verify(cli.verify_histogram);
+    set_cable_type(parse_cable_type(&cli.cable).unwrap());
+    if let Some(head_settle_ms) = cli.head_settle_ms.or(profile.head_settle_ms()) {
+        set_head_settle_time(head_settle_ms).unwrap();
+    }
+    if let Some(step_rate_ms) = cli.step_rate_ms {
+        set_step_rate(step_rate_ms).unwrap();
+    }
+    if let Some(timeout_secs) = cli.operation_timeout_secs {
+        set_operation_timeout(timeout_secs).unwrap();
+    }
+    if let Some(extra_percent) = cli
+        .inner_verify_tolerance
+        .or(profile.verify_threshold_extra())
+    {
+        set_verify_threshold_extra(extra_percent).unwrap();
+    }
+    if let Some(max_cylinder) = cli.max_cylinder {
+        set_max_cylinder(max_cylinder).unwrap();
+    }
+
+    // Older firmware doesn't answer which costs a timeout. Only ask if a feature depends on it.
+    let queried_capabilities = OnceCell::new();
+    let capabilities = || {
+        *queried_capabilities.get_or_init(|| match query_capabilities(&usb_handles) {
+            Result::Ok(capabilities) => {
+                let [major, minor, patch] = capabilities.firmware_version;
+                if !cli.quiet {
+                    println!("Firmware {major}.{minor}.{patch}");
+                }
+                Some(capabilities)
+            }
+            Err(error) => {
+                println!("{error}");
+                None
+            }
+        })
+    };
+    if cli.max_cylinder.is_some() && let Some(capabilities) = capabilities() {
+        ensure_max_cylinder_supported(&capabilities).unwrap();
+    }
+    if let Some(duplicate_header_threshold) = cli.duplicate_header_threshold {
+        set_duplicate_header_threshold(duplicate_header_threshold);
+    }
+    if let Some(discover_density) = cli.discover_density.as_ref() {
+        set_discover_density(parse_density(discover_density).unwrap());
+    }
+    set_discover_margin(cli.discover_margin).unwrap();
+    if let Some(read_rpm) = cli.read_rpm {
+        set_read_rpm(read_rpm).unwrap();
+    }
+
+    if let Some(motor_off_delay_ms) = cli.motor_off_delay_ms {
+        set_motor_off_delay(&usb_handles, motor_off_delay_ms).unwrap();
+    }
+
+    assert!(
+        !(cli.flippy.is_some() && cli.index_sim_hz.is_some()),
+        "Specify either --flippy or --index-sim-hz. NOT BOTH!"
+    );
+    let index_sim_frequency = if let Some(flippy_offset) = cli.flippy {
+        flippy_offset
+            .checked_mul(FLIPPY_OFFSET_STEP_TICKS)
+            .and_then(flippy_index_frequency)
+            .with_context(|| format!("Flippy offset must not exceed {MAX_FLIPPY_OFFSET_US}µs"))
+            .unwrap()
+    } else if let Some(index_sim_hz) = cli.index_sim_hz {
+        index_sim_period(index_sim_hz)
+            .with_context(|| format!("{index_sim_hz} Hz is out of range for the index simulation"))
+            .unwrap()
     } else {
         0
     };
 
-    if cli.read && cli.filepath == "discover" {
-        println!("Let me see...");
+    if cli.recalibrate {
+        configure_device(
+            &usb_handles,
+            select_drive,
+            Density::SingleDouble,
+            index_sim_frequency,
+        )
+        .unwrap();
+        recalibrate(&usb_handles).unwrap();
+        // Ask the device instead of assuming to notice a desync
+        match query_position(&usb_handles).unwrap().cylinder {
+            Some(cylinder) => println!("Head is on track {cylinder}"),
+            None => println!("Head position is unknown. Track 0 was not found."),
+        }
+    } else if let Some(track_position) = cli.measure_write_recovery.as_ref() {
+        let (cylinder, head) = parse_track_position(track_position).unwrap();
+        configure_device(
+            &usb_handles,
+            select_drive,
+            Density::SingleDouble,
+            index_sim_frequency,
+        )
+        .unwrap();
+        let microseconds = measure_write_recovery(&usb_handles, cylinder, head).unwrap();
+        println!("The drive can read again {microseconds} µs after writing");
+    } else if let Some(read_sector_param) = cli.read_sector.as_ref() {
+        let (cylinder, head, sector) = parse_sector_position(read_sector_param).unwrap();
+        let data = read_sector(
+            &usb_handles,
+            select_drive,
+            index_sim_frequency,
+            cylinder,
+            head,
+            sector,
+        )
+        .unwrap();
+        println!("{:?}", data.hex_dump());
+    } else if let Some(patch_sector_param) = cli.patch_sector.as_ref() {
+        let ((cylinder, head, sector), path) = parse_sector_patch(patch_sector_param).unwrap();
+        let data = std::fs::read(path).unwrap();
+        let preset = cli.preset.as_ref().map(|f| {
+            IsoPreset::from_name(f)
+                .with_context(|| format!("Unknown preset {f}. Expected msx or sf7000"))
+                .unwrap()
+        });
+        let track = patch_sector(
+            &usb_handles,
+            select_drive,
+            index_sim_frequency,
+            (cylinder, head, sector),
+            &data,
+            preset,
+        )
+        .unwrap();
+        ensure_write_track_supported(capabilities().as_ref()).unwrap();
+        write_and_verify_image(
+            &usb_handles,
+            std::slice::from_ref(&track),
+            false,
+            cli.quiet,
+            &mut WriteMetrics::default(),
+            None,
+            1,
+        )
+        .unwrap();
+        println!("Sector {sector} of track {cylinder} {head} replaced");
+    } else if cli.probe_cylinders {
+        let probes = probe_cylinders(
+            &usb_handles,
+            select_drive,
+            index_sim_frequency,
+            &DEFAULT_PROBE_CYLINDERS,
+        )
+        .unwrap();
+        match estimate_cylinder_count(&probes) {
+            None => println!("{}", tr(MessageId::NoKnownFormat)),
+            Some(count) if count.double_step => println!(
+                "The disk has probably {} cylinders, written by a 40 cylinder drive. \
+                Use a 40 cylinder drive to read it.",
+                count.cylinders
+            ),
+            Some(count) => {
+                let more = if count.open_end { " or more" } else { "" };
+                println!(
+                    "The disk has probably {}{more} cylinders. Read it with -t-{}",
+                    count.cylinders,
+                    count.cylinders - 1
+                );
+            }
+        }
+    } else if cli.read && filepath == "discover" && cli.discover_scan {
+        println!("{}", tr(MessageId::LetMeSee));
+        let (_possible_track_parser, possible_formats, cylinder) = discover_scan(
+            &usb_handles,
+            select_drive,
+            index_sim_frequency,
+            &[0, 1, 40],
+        )
+        .unwrap();
+
+        if let Some(cylinder) = cylinder {
+            println!(
+                "Format is probably '{:?}' according to cylinder {}",
+                possible_formats, cylinder
+            );
+        } else {
+            println!("{}", tr(MessageId::NoKnownFormat));
+        }
+    } else if cli.read && filepath == "discover" {
+        println!("{}", tr(MessageId::LetMeSee));
         let (_possible_track_parser, possible_formats) =
             read_first_track_discover_format(&usb_handles, select_drive, index_sim_frequency)
                 .unwrap();
-        println!("Format is probably '{:?}'", possible_formats);
+        println!(
+            "{}",
+            tr_with(MessageId::ProbableFormat, &format!("'{possible_formats:?}'"))
+        );
     } else if cli.read {
         let track_filter = cli.track_filter;
         let track_filter = track_filter.map(|f| TrackFilter::new(&f).unwrap());
+        let affected_files = cli.affected_files.as_ref().map(|f| {
+            FileSystem::from_name(f)
+                .with_context(|| format!("Unknown filesystem {f}. Expected fat12"))
+                .unwrap()
+        });
 
         read_tracks_to_diskimage(
             &usb_handles,
             track_filter,
-            &cli.filepath,
+            &filepath,
             select_drive,
             index_sim_frequency,
+            &ReadOptions {
+                revolutions: cli.revolutions,
+                majority_reads: cli.majority_reads,
+                vote_raw_bytes: cli.vote_raw_bytes,
+                high_resolution: cli.high_resolution,
+                allow_blank_tracks: cli.allow_blank_tracks,
+                quiet: cli.quiet,
+                split_sectors: cli.split_sectors.map(PathBuf::from),
+                write_geometry: cli.write_geometry,
+                save_raw: cli.save_raw.map(PathBuf::from),
+                preserve_interleave: cli.preserve_interleave || cli.preserve_index_timing,
+                affected_files,
+                rich_output: cli.rich_output.map(PathBuf::from),
+                capture_timing: cli.capture_timing,
+                stx_output: cli.stx_output.map(PathBuf::from),
+                g64_output: cli.g64_output.map(PathBuf::from),
+                flippy_side: cli.flippy_side,
+                skip_bad_tracks: cli.skip_bad_tracks,
+                max_consecutive_failures: cli.max_consecutive_failures,
+                format: cli.format,
+                accept_bad_crc: cli.accept_bad_crc,
+                read_dump: cli.read_dump.map(PathBuf::from),
+                drive_type: cli
+                    .drive_type
+                    .as_deref()
+                    .map(|f| parse_drive_type(f).unwrap()),
+            },
         )
         .unwrap();
-    } else {
+    } else if cli.verify_only {
         let image = image.unwrap();
+        verify_image(
+            &usb_handles,
+            &image,
+            select_drive,
+            index_sim_frequency,
+            cli.quiet,
+        )
+        .unwrap();
+        println!("--- Disk matches the image! ---");
+    } else {
+        let mut image = image.unwrap();
+
+        let image_path = Path::new(&filepath);
+        let resume_path = cli.resume.as_ref().map(PathBuf::from);
+        let mut progress = match resume_path.as_ref() {
+            Some(path) if path.exists() => {
+                let progress = ResumeManifest::read(path, image_path).unwrap();
+                println!(
+                    "Resume with {} of {} tracks already verified",
+                    image
+                        .tracks
+                        .iter()
+                        .filter(|f| progress.is_verified(f))
+                        .count(),
+                    image.tracks.len()
+                );
+                progress
+            }
+            _ => ResumeManifest::new(image_path),
+        };
+
+        // Configuration mistakes are cheaper to fix before a disk is wasted
+        if let Some(drive_type) = cli.drive_type.as_ref() {
+            let drive_type = parse_drive_type(drive_type).unwrap();
+            for warning in image.check_drive_compatibility(drive_type) {
+                println!("{warning}");
+            }
+        }
+        if cli.flippy.is_some() {
+            for warning in image.check_flippy_compatibility() {
+                println!("{warning}");
+            }
+        }
+
+        // Refuse images beyond the reach of the drive before anything is written
+        for track in &image.tracks {
+            ensure_cylinder_allowed(track.cylinder).unwrap();
+        }
+
+        // Older firmware ignores the write command of encoded tracks
+        if image.tracks.iter().any(|f| f.flux_timings.is_none()) {
+            ensure_write_track_supported(capabilities().as_ref()).unwrap();
+        }
+
+        // Older firmware would take the margins as part of the density map
+        if image
+            .tracks
+            .iter()
+            .any(|f| f.write_gate_margins != WriteGateMargins::default())
+        {
+            assert!(
+                capabilities().is_some_and(|f| f.has_feature(FEATURE_WRITE_GATE_MARGINS)),
+                "The firmware doesn't support write gate margins. Please update it."
+            );
+        }
+
+        // Refuse to start before the motor is spinning
+        configure_device(
+            &usb_handles,
+            select_drive,
+            image.density,
+            index_sim_frequency,
+        )
+        .unwrap();
+
+        if cli.fill_rotation {
+            assert!(
+                capabilities().is_some_and(|f| f.has_feature(FEATURE_ROTATION)),
+                "The firmware doesn't measure the rotation. Please update it."
+            );
+            let rotation_ticks = query_rotation(&usb_handles).unwrap();
+            let measured_rpm = 60.0 * STM_TIMER_HZ / f64::from(rotation_ticks);
+            let filled_tracks = image
+                .tracks
+                .iter_mut()
+                .map(|f| f.fill_rotation(measured_rpm, cli.rotation_margin_percent))
+                .filter(|added| *added > 0)
+                .count();
+            println!(
+                "Extended the gap of {filled_tracks} tracks to fill the rotation of {measured_rpm:.2} RPM"
+            );
+        }
+        // Writing without a disk only grinds the head
+        if cli.require_disk {
+            match is_disk_present(&usb_handles).unwrap() {
+                Some(true) => {}
+                Some(false) => {
+                    println!("{}", tr(MessageId::NoDisk));
+                    exit(1);
+                }
+                None => {
+                    println!(
+                        "The firmware doesn't report the disk change signal. Please update it."
+                    );
+                    exit(1);
+                }
+            }
+        }
+        if is_write_protected(&usb_handles).unwrap() {
+            println!("{}", tr(MessageId::WriteProtected));
+            exit(1);
+        }
+
+        // Writing DD data on a HD formatted disk or vice versa might cause
+        // subtle verification problems. Warn the user before writing.
+        if cli.check_density {
+            match check_media_density(
+                &usb_handles,
+                select_drive,
+                index_sim_frequency,
+                image.density,
+            ) {
+                Result::Ok(Some(warning)) => println!("{warning}"),
+                Result::Ok(None) => {}
+                Err(e) => println!("Unable to check density of disk: {e}"),
+            }
+        }
 
         configure_device(
             &usb_handles,
@@ -287,8 +1915,632 @@ fn main() {
 
         if cli.wprecomp_calib {
             calibration(&usb_handles, image).unwrap();
+        } else if let Some(precomp_sweep) = cli.precomp_sweep.as_ref() {
+            let (cylinder, head) = parse_track_position(precomp_sweep).unwrap();
+            precompensation_sweep(&usb_handles, image, cylinder, head).unwrap();
+        } else if cli.selftest {
+            selftest(
+                &usb_handles,
+                &image,
+                select_drive,
+                index_sim_frequency,
+                cli.quiet,
+            )
+            .unwrap();
+        } else if cli.measure_pipeline {
+            let free_heap = capabilities().map(|f| f.free_heap as usize);
+            measure_pipeline(&usb_handles, &image.tracks, free_heap, cli.quiet).unwrap();
         } else {
-            write_and_verify_image(&usb_handles, &image).unwrap();
+            let rpm = match image.disk_type {
+                util::DiskType::Inch3_5 => DRIVE_3_5_RPM,
+                util::DiskType::Inch5_25 => DRIVE_5_25_RPM,
+            };
+            let estimate = image.estimated_write_time(rpm).as_secs();
+            println!(
+                "{}",
+                tr_with(
+                    MessageId::EstimatedWriteTime,
+                    &format!("{}:{:02} min", estimate / 60, estimate % 60)
+                )
+            );
+            // The extra passes reduce the image to the failed tracks
+            let default_cell_size = match image.density {
+                Density::High => PulseDuration(84),
+                Density::SingleDouble => PulseDuration(168),
+            };
+            let double_read_tracks: Vec<_> = image
+                .tracks
+                .iter()
+                .map(|f| {
+                    let cell_size = f
+                        .densitymap
+                        .first()
+                        .map_or(default_cell_size, |f| f.cell_size);
+                    (f.cylinder, f.head, cell_size)
+                })
+                .collect();
+
+            // Without knowing the free heap of the device, a deeper pipeline might not fit
+            let pipeline_depth = if cli.pipeline_depth > 1 {
+                capabilities().map_or(1, |f| {
+                    write_pipeline_depth(cli.pipeline_depth, f.free_heap as usize, &image.tracks)
+                })
+            } else {
+                cli.pipeline_depth
+            };
+            if pipeline_depth != cli.pipeline_depth {
+                println!("Pipeline depth limited to {pipeline_depth}");
+            }
+
+            let mut metrics = WriteMetrics::default();
+            let mut result = if cli.interactive {
+                interactive_write_and_verify(
+                    &usb_handles,
+                    &mut std::io::stdin().lock(),
+                    &image.tracks,
+                    cli.quiet,
+                    &mut metrics,
+                    resume_path.as_deref().map(|path| (&mut progress, path)),
+                )
+            } else {
+                write_and_verify_image(
+                    &usb_handles,
+                    &image.tracks,
+                    cli.keep_going || cli.hd_drive_dd_media || profile.extra_passes() > 0,
+                    cli.quiet,
+                    &mut metrics,
+                    resume_path.as_deref().map(|path| (&mut progress, path)),
+                    pipeline_depth,
+                )
+            };
+
+            // Marginal tracks often succeed in another attempt
+            let extra_passes = if cli.hd_drive_dd_media {
+                HD_DRIVE_DD_MEDIA_EXTRA_PASSES
+            } else {
+                0
+            }
+            .max(profile.extra_passes());
+            for _ in 0..extra_passes {
+                if !retain_failed_tracks(&mut image.tracks, &result) {
+                    break;
+                }
+                println!("Write {} failed tracks again", image.tracks.len());
+                result = write_and_verify_image(
+                    &usb_handles,
+                    &image.tracks,
+                    true,
+                    cli.quiet,
+                    &mut metrics,
+                    resume_path.as_deref().map(|path| (&mut progress, path)),
+                    pipeline_depth,
+                );
+            }
+
+            if cli.double_read_verify && result.is_ok() {
+                result = double_read_verify(&usb_handles, &double_read_tracks, cli.quiet);
+            }
+
+            // Failed runs are of interest for monitoring as well
+            if let Some(metrics_file) = cli.metrics_file.as_ref() {
+                metrics.write_textfile(Path::new(metrics_file)).unwrap();
+            }
+            if let Some(timings) = cli.timings.as_ref() {
+                std::fs::write(timings, metrics.timings_to_csv()).unwrap();
+            }
+            if let Some(heatmap) = cli.heatmap.as_ref() {
+                Heatmap::render(&metrics, &image, verify_threshold_extra())
+                    .write_png(Path::new(heatmap))
+                    .unwrap();
+            }
+            // Lost steps are a likely reason for failed tracks
+            if let Result::Ok(status) = query_position(&usb_handles)
+                && status.failed_seeks > 0
+            {
+                println!(
+                    "{} seeks of the drive lost steps. Consider a slower --step-rate-ms",
+                    status.failed_seeks
+                );
+            }
+            result.unwrap();
+            if let Some(resume_path) = resume_path.as_ref() {
+                ResumeManifest::remove(resume_path).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        time::Duration,
+    };
+
+    use util::{DensityMapEntry, Encoding, CONFIRM_RECEPTION_COMMAND, WRITE_TRACK_COMMAND};
+
+    use super::*;
+
+    /// Replays scripted answers of the firmware and collects the transfers of the host
+    struct MockTransport {
+        answers: RefCell<VecDeque<&'static str>>,
+        written: RefCell<Vec<Vec<u8>>>,
+        consumed: Cell<usize>,
+        /// Number of answers which were consumed before every transfer of the host
+        answers_consumed: RefCell<Vec<usize>>,
+    }
+
+    impl MockTransport {
+        fn new(script: &'static str) -> Self {
+            Self {
+                answers: RefCell::new(
+                    script
+                        .lines()
+                        .filter(|f| !f.is_empty() && !f.starts_with('#'))
+                        .collect(),
+                ),
+                written: RefCell::new(Vec::new()),
+                consumed: Cell::new(0),
+                answers_consumed: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn is_write_command(transfer: &[u8]) -> bool {
+            transfer.len() == 64 && transfer.starts_with(&u32::to_le_bytes(WRITE_TRACK_COMMAND))
+        }
+
+        /// Cylinder and head of every requested track write
+        fn write_commands(&self) -> Vec<(u8, u8)> {
+            self.written
+                .borrow()
+                .iter()
+                .filter(|f| Self::is_write_command(f))
+                .map(|f| (*f.get(12).unwrap(), *f.get(13).unwrap() & 1))
+                .collect()
+        }
+
+        /// Parameters of the answers to the reception of every track
+        fn reception_confirmations(&self) -> Vec<u32> {
+            self.written
+                .borrow()
+                .iter()
+                .filter(|f| f.starts_with(&u32::to_le_bytes(CONFIRM_RECEPTION_COMMAND)))
+                .map(|f| u32::from_le_bytes(f.get(4..8).unwrap().try_into().unwrap()))
+                .collect()
+        }
+
+        /// Number of consumed answers at the moment of every requested track write
+        fn answers_before_write_commands(&self) -> Vec<usize> {
+            self.written
+                .borrow()
+                .iter()
+                .zip(self.answers_consumed.borrow().iter())
+                .filter(|(transfer, _)| Self::is_write_command(transfer))
+                .map(|(_, consumed)| *consumed)
+                .collect()
+        }
+    }
+
+    impl UsbTransport for MockTransport {
+        fn write_bulk(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            self.written.borrow_mut().push(data.to_vec());
+            self.answers_consumed.borrow_mut().push(self.consumed.get());
+            Result::Ok(data.len())
+        }
+
+        fn read_bulk(&self, data: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+            // The real device also runs into a timeout if nothing is left to say
+            let answer = self
+                .answers
+                .borrow_mut()
+                .pop_front()
+                .ok_or(rusb::Error::Timeout)?;
+            self.consumed.set(self.consumed.get() + 1);
+            data.get_mut(..answer.len())
+                .ok_or(rusb::Error::Overflow)?
+                .copy_from_slice(answer.as_bytes());
+            Result::Ok(answer.len())
+        }
+
+        fn abort(&self) -> Result<(), ToolError> {
+            panic!("No abort expected");
+        }
+    }
+
+    #[test]
+    fn parse_sector_ids_test() {
+        assert_eq!(parse_sector_ids("0xC1,0xC6,2").unwrap(), [0xc1, 0xc6, 2]);
+        assert!(parse_sector_ids("1,,2").is_err());
+        assert!(parse_sector_ids("256").is_err());
+    }
+
+    #[test]
+    fn parse_precomp_ranges_test() {
+        let ranges = parse_precomp_ranges("40-79=6,80=8").unwrap();
+        assert_eq!(ranges, [(40..=79, 6), (80..=80, 8)]);
+
+        assert!(parse_precomp_ranges("40-79").is_err());
+        assert!(parse_precomp_ranges("79-40=6").is_err());
+        assert!(parse_precomp_ranges("40-79=256").is_err());
+    }
+
+    #[test]
+    fn parse_verify_windows_test() {
+        let windows = parse_verify_windows("20:200").unwrap();
+        assert_eq!(windows.skip_pulses, VerifyWindows::DEFAULT_SKIP_PULSES);
+
+        let windows = parse_verify_windows("20:200:30").unwrap();
+        assert_eq!(
+            (windows.compare, windows.read_data, windows.skip_pulses),
+            (20, 200, 30)
+        );
+
+        // The skipped pulses must leave room for the compare window
+        assert!(parse_verify_windows("20:200:180").is_ok());
+        assert!(parse_verify_windows("20:200:181").is_err());
+        assert!(parse_verify_windows("20:200:30:1").is_err());
+    }
+
+    #[test]
+    fn parse_sector_position_test() {
+        assert_eq!(parse_sector_position("0:1:5").unwrap(), (0, 1, 5));
+        assert!(parse_sector_position("0:1").is_err());
+        assert!(parse_sector_position("0:1:5:2").is_err());
+        assert!(parse_sector_position("a:1:5").is_err());
+    }
+
+    #[test]
+    fn quiet_argument_test() {
+        Args::command().debug_assert();
+
+        let args = Args::try_parse_from(["usbfloppytracer", "-q", "image.adf"]).unwrap();
+        assert!(args.quiet);
+        let args = Args::try_parse_from(["usbfloppytracer", "--quiet", "image.adf"]).unwrap();
+        assert!(args.quiet);
+        let args = Args::try_parse_from(["usbfloppytracer", "image.adf"]).unwrap();
+        assert!(!args.quiet);
+    }
+
+    #[test]
+    fn parse_cable_type_test() {
+        assert_eq!(parse_cable_type("pc-twist").unwrap(), CableType::PcTwist);
+        assert_eq!(parse_cable_type("straight").unwrap(), CableType::Straight);
+        assert_eq!(parse_cable_type("shugart").unwrap(), CableType::Shugart);
+        assert!(parse_cable_type("Shugart").is_err());
+    }
+
+    #[test]
+    fn apply_gap_generators_test() {
+        let mut image = scripted_image();
+        apply_gap_generators(&mut image, "weak-bits,0:1=off").unwrap();
+        let gap_generators: Vec<_> = image.tracks.iter().map(|f| f.gap_generator).collect();
+        assert_eq!(
+            gap_generators,
+            [
+                Some(GapGenerator::WeakBits),
+                Some(GapGenerator::Disabled),
+                Some(GapGenerator::WeakBits)
+            ]
+        );
+
+        assert!(apply_gap_generators(&mut image, "5:0=off").is_err());
+        assert!(apply_gap_generators(&mut image, "non-flux").is_err());
+    }
+
+    #[test]
+    fn hex_config_test() {
+        let data: Vec<u8> = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ".to_vec();
+
+        // The layout stays as it was without any argument
+        let args = Args::try_parse_from(["usbfloppytracer", "image.adf"]).unwrap();
+        let cfg = hex_config(&args);
+        assert_eq!((cfg.width, cfg.group, cfg.ascii), (16, 0, false));
+        let text = format!("{:?}", data.hex_conf(cfg));
+        assert_eq!(text.lines().count(), 3);
+        assert!(!text.contains("ABCD"));
+
+        let args = Args::try_parse_from([
+            "usbfloppytracer",
+            "--hex-width",
+            "8",
+            "--hex-group",
+            "4",
+            "--hex-ascii",
+            "image.adf",
+        ])
+        .unwrap();
+        let text = format!("{:?}", data.hex_conf(hex_config(&args)));
+        // Title and four lines of eight bytes
+        assert_eq!(text.lines().count(), 5);
+        assert!(text.contains("ABCDEFGH"));
+    }
+
+    #[test]
+    fn query_rotation_test() {
+        // The motor needs some time to measure a rotation
+        let usb = MockTransport::new("Rotation Unknown\nRotation 16800000");
+        assert_eq!(query_rotation(&usb).unwrap(), 16_800_000);
+        assert_eq!(usb.written.borrow().len(), 2);
+    }
+
+    #[test]
+    fn query_capabilities_test() {
+        // Older firmware doesn't answer the request
+        let usb = MockTransport::new("");
+        assert!(query_capabilities(&usb).is_err());
+        assert_eq!(usb.written.borrow().len(), 1);
+    }
+
+    fn scripted_image() -> RawImage {
+        let track = |cylinder, head| {
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: 6250,
+                cell_size: PulseDuration(168),
+            }];
+            RawTrack::new(cylinder, head, vec![0xaa; 6250], densitymap, Encoding::MFM)
+        };
+        RawImage {
+            tracks: vec![track(0, 0), track(0, 1), track(1, 0)],
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
         }
     }
+
+    #[test]
+    fn scripted_write_and_verify_test() {
+        let usb = MockTransport::new(include_str!("../fixtures/write_and_verify.txt"));
+
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+        write_and_verify_image(&usb, &image.tracks, false, true, &mut metrics, None, 1).unwrap();
+
+        assert_eq!(usb.write_commands(), [(0, 0), (0, 1), (1, 0)]);
+        // Every track waits for the reception of the previous one
+        assert_eq!(usb.answers_before_write_commands(), [0, 1, 2]);
+        // Header and 98 blocks of data for every track and the confirmation of its reception
+        assert_eq!(usb.written.borrow().len(), 3 * 100);
+        assert_eq!(usb.reception_confirmations(), [1, 1, 1]);
+        assert!(usb.answers.borrow().is_empty());
+    }
+
+    #[test]
+    fn pipelined_write_and_verify_test() {
+        let usb = MockTransport::new(include_str!("../fixtures/write_and_verify.txt"));
+
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+        write_and_verify_image(&usb, &image.tracks, false, true, &mut metrics, None, 2).unwrap();
+
+        // The second track is transferred without waiting for the first one
+        assert_eq!(usb.write_commands(), [(0, 0), (0, 1), (1, 0)]);
+        assert_eq!(usb.answers_before_write_commands(), [0, 0, 1]);
+        assert!(usb.answers.borrow().is_empty());
+    }
+
+    const FAILED_VERIFICATION_SCRIPT: &str = "GotCmd 6250 2273495245
+GotCmd 6250 2273495245
+WrittenAndVerified 0 0 1 1 3 0
+GotCmd 6250 2273495245
+Fail 0 1 5 10 Mismatch
+WrittenAndVerified 1 0 1 1 2 0";
+
+    #[test]
+    fn keep_going_write_and_verify_test() {
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+
+        // The failed track stops the write immediately
+        let usb = MockTransport::new(FAILED_VERIFICATION_SCRIPT);
+        let error = write_and_verify_image(&usb, &image.tracks, false, true, &mut metrics, None, 1)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::VerificationFailed {
+                cylinder: 0,
+                head: 1,
+                ..
+            })
+        ));
+        assert_eq!(usb.answers.borrow().len(), 1);
+
+        // The remaining tracks are still written and all failures are reported at the end
+        let usb = MockTransport::new(FAILED_VERIFICATION_SCRIPT);
+        let error = write_and_verify_image(&usb, &image.tracks, true, true, &mut metrics, None, 1)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::VerificationsFailed(failed)) if failed == &[(0, 1)]
+        ));
+        assert_eq!(usb.write_commands(), [(0, 0), (0, 1), (1, 0)]);
+        assert!(usb.answers.borrow().is_empty());
+    }
+
+    #[test]
+    fn resume_write_and_verify_test() {
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+        let dir = std::env::temp_dir().join(format!("resume_cli_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("disk.st");
+        let path = tool::resume::manifest_path(&image_path);
+
+        // The first track was verified by the interrupted run
+        let mut progress = ResumeManifest::new(&image_path);
+        progress.mark_verified(0, 0);
+        let usb = MockTransport::new(
+            "GotCmd 6250 2273495245
+GotCmd 6250 2273495245
+WrittenAndVerified 0 1 1 1 3 0
+WrittenAndVerified 1 0 1 1 2 0",
+        );
+        write_and_verify_image(
+            &usb,
+            &image.tracks,
+            false,
+            true,
+            &mut metrics,
+            Some((&mut progress, &path)),
+            1,
+        )
+        .unwrap();
+        assert_eq!(usb.write_commands(), [(0, 1), (1, 0)]);
+        assert_eq!(progress.verified, [(0, 0), (0, 1), (1, 0)]);
+        assert_eq!(ResumeManifest::read(&path, &image_path).unwrap(), progress);
+
+        // Nothing is left to write
+        let usb = MockTransport::new("");
+        write_and_verify_image(
+            &usb,
+            &image.tracks,
+            false,
+            true,
+            &mut metrics,
+            Some((&mut progress, &path)),
+            1,
+        )
+        .unwrap();
+        assert!(usb.write_commands().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interactive_write_and_verify_test() {
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+
+        // Every track is written alone. A failed track doesn't stop the session.
+        let usb = MockTransport::new(
+            "GotCmd 6250 2273495245
+WrittenAndVerified 0 0 1 1 3 0
+GotCmd 6250 2273495245
+Fail 0 1 5 10 Mismatch
+GotCmd 6250 2273495245
+WrittenAndVerified 1 0 1 1 2 0",
+        );
+        let error = interactive_write_and_verify(
+            &usb,
+            &mut "\n\n".as_bytes(),
+            &image.tracks,
+            true,
+            &mut metrics,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::VerificationsFailed(failed)) if failed == &[(0, 1)]
+        ));
+        assert_eq!(usb.write_commands(), [(0, 0), (0, 1), (1, 0)]);
+
+        // Closed input ends the session after the current track
+        let usb = MockTransport::new(
+            "GotCmd 6250 2273495245
+WrittenAndVerified 0 0 1 1 3 0",
+        );
+        let error = interactive_write_and_verify(
+            &usb,
+            &mut "".as_bytes(),
+            &image.tracks,
+            true,
+            &mut metrics,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::Aborted {
+                cylinder: 0,
+                head: 0
+            })
+        ));
+        assert_eq!(usb.write_commands(), [(0, 0)]);
+    }
+
+    #[test]
+    fn out_of_memory_test() {
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+
+        // The device drops the track. Writing the others would leave a gap on the disk.
+        let usb = MockTransport::new("OutOfMemory 0 0 6250");
+        let error = write_and_verify_image(&usb, &image.tracks, true, true, &mut metrics, None, 1)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::OutOfMemory {
+                cylinder: 0,
+                head: 0,
+                size: 6250
+            })
+        ));
+        assert_eq!(usb.write_commands(), [(0, 0)]);
+    }
+
+    #[test]
+    fn hd_drive_dd_media_test() {
+        let mut image = scripted_image();
+        image.tracks.first_mut().unwrap().write_precompensation = 2;
+        apply_hd_drive_dd_media(&mut image).unwrap();
+        let precompensation: Vec<u32> = image
+            .tracks
+            .iter()
+            .map(|f| f.write_precompensation)
+            .collect();
+        assert_eq!(
+            precompensation,
+            [
+                2 + HD_DRIVE_DD_MEDIA_EXTRA_PRECOMPENSATION,
+                HD_DRIVE_DD_MEDIA_EXTRA_PRECOMPENSATION,
+                HD_DRIVE_DD_MEDIA_EXTRA_PRECOMPENSATION
+            ]
+        );
+
+        image.density = Density::High;
+        assert!(apply_hd_drive_dd_media(&mut image).is_err());
+    }
+
+    #[test]
+    fn retain_failed_tracks_test() {
+        let mut tracks = scripted_image().tracks;
+        let mut metrics = WriteMetrics::default();
+
+        // Only the failed track is written again
+        let usb = MockTransport::new(FAILED_VERIFICATION_SCRIPT);
+        let result = write_and_verify_image(&usb, &tracks, true, true, &mut metrics, None, 1);
+        assert!(retain_failed_tracks(&mut tracks, &result));
+        let positions: Vec<(u32, u32)> = tracks.iter().map(|f| (f.cylinder, f.head)).collect();
+        assert_eq!(positions, [(0, 1)]);
+
+        let usb = MockTransport::new("GotCmd 6250 2273495245\nWrittenAndVerified 0 1 2 2 5 0");
+        let result = write_and_verify_image(&usb, &tracks, true, true, &mut metrics, None, 1);
+        assert!(result.is_ok());
+        assert!(!retain_failed_tracks(&mut tracks, &result));
+        assert_eq!(tracks.len(), 1);
+
+        // Other errors can't be solved by another pass
+        let result = Err(ToolError::WriteProtected.into());
+        assert!(!retain_failed_tracks(&mut tracks, &result));
+    }
+
+    #[test]
+    fn selftest_write_failures_test() {
+        let image = scripted_image();
+        let mut metrics = WriteMetrics::default();
+
+        // The failed track is still read back by the selftest
+        let usb = MockTransport::new(FAILED_VERIFICATION_SCRIPT);
+        let result = write_and_verify_image(&usb, &image.tracks, true, true, &mut metrics, None, 1);
+        assert_eq!(write_failures(result).unwrap(), [(0, 1)]);
+
+        assert!(write_failures(Ok(())).unwrap().is_empty());
+
+        // A write protected disk makes the read back pointless
+        let usb = MockTransport::new("WriteProtected");
+        let result = write_and_verify_image(&usb, &image.tracks, true, true, &mut metrics, None, 1);
+        let error = write_failures(result).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::WriteProtected)
+        ));
+    }
 }
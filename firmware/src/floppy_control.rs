@@ -8,60 +8,168 @@ use stm32f4xx_hal::{
     hal::digital::v2::{InputPin, OutputPin},
 };
 use unwrap_infallible::UnwrapInfallible;
-use util::{Density, DriveSelectState, Track};
+use util::{
+    CableType, Cylinder, Density, DriveSelectState, Head, Track, DEFAULT_HEAD_SETTLE_MS,
+    DEFAULT_STEP_RATE_MS,
+};
 
 use crate::{
     floppy_drive_unit::{FloppyDriveUnit, HeadPosition},
     floppy_stepper::FloppyStepperSignals,
+    SYSTICK_PERIOD_MS,
 };
 
+/// Time from activating the select signal until write protection and disk change are sampled.
+/// The drive provides the signals within a microsecond but the open collector lines are only
+/// pulled up by resistors. 10 µs at 168 MHz leave enough time for them to rise on long cables.
+const DRIVE_STATUS_SETTLE_CYCLES: u32 = 168 * 10;
+
 type FutureHeadPosition =
     Cassette<Pin<Box<dyn Future<Output = (FloppyStepperSignals, HeadPosition)> + Send>>>;
 
+type BusLine = Box<dyn OutputPin<Error = Infallible> + Send>;
+
+/// Drive select and motor enable lines named after the pins of the 34 pin connector
+pub struct DriveBusLines {
+    pub pin_10: BusLine,
+    pub pin_12: BusLine,
+    pub pin_14: BusLine,
+    pub pin_16: BusLine,
+}
+
 pub struct FloppyControl {
+    bus_lines: DriveBusLines,
+    cable_type: CableType,
     out_head_select: Box<dyn OutputPin<Error = Infallible> + Send>,
     out_density_select: Box<dyn OutputPin<Error = Infallible> + Send>,
     in_write_protect: Box<dyn InputPin<Error = Infallible> + Send>,
+    /// Disk change of PC drives. Active while no disk is inserted
+    /// and after insertion until the head was stepped.
+    in_disk_change: Box<dyn InputPin<Error = Infallible> + Send>,
     floppy_step_signals: Option<FloppyStepperSignals>,
     floppy_step_progress: Option<FutureHeadPosition>,
     drive_a: FloppyDriveUnit,
     drive_b: FloppyDriveUnit,
     drive_select: DriveSelectState,
+    invert_density_select: bool,
+    /// The drive only takes over the density select signal when the motor is switched on
+    density_latched_at_motor_on: bool,
+    /// Last density driven on the density select signal
+    high_density: bool,
+    /// Number of SysTick periods to wait after stepping
+    head_settle_ticks: usize,
+    /// Number of SysTick periods between two step pulses
+    step_ticks: usize,
 }
 
 impl FloppyControl {
     #[must_use]
     pub fn new(
-        drive_a: FloppyDriveUnit,
-        drive_b: FloppyDriveUnit,
+        bus_lines: DriveBusLines,
         stepper: FloppyStepperSignals,
         out_head_select: Box<dyn OutputPin<Error = Infallible> + Send>,
         out_density_select: Box<dyn OutputPin<Error = Infallible> + Send>,
         in_write_protect: Box<dyn InputPin<Error = Infallible> + Send>,
+        in_disk_change: Box<dyn InputPin<Error = Infallible> + Send>,
     ) -> Self {
         Self {
-            drive_a,
-            drive_b,
+            bus_lines,
+            cable_type: CableType::PcTwist,
+            drive_a: FloppyDriveUnit::new(),
+            drive_b: FloppyDriveUnit::new(),
             floppy_step_signals: Some(stepper),
             floppy_step_progress: None,
             drive_select: DriveSelectState::None,
+            invert_density_select: false,
+            density_latched_at_motor_on: false,
+            high_density: false,
+            head_settle_ticks: DEFAULT_HEAD_SETTLE_MS.div_ceil(SYSTICK_PERIOD_MS) as usize,
+            step_ticks: DEFAULT_STEP_RATE_MS.div_ceil(SYSTICK_PERIOD_MS) as usize,
             out_head_select,
             out_density_select,
             in_write_protect,
+            in_disk_change,
+        }
+    }
+
+    /// Some drives expect the density select signal with the opposite polarity
+    pub fn set_density_select_inverted(&mut self, inverted: bool) {
+        self.invert_density_select = inverted;
+    }
+
+    /// Some drives ignore a density change while the motor is spinning
+    pub fn set_density_latched_at_motor_on(&mut self, latched: bool) {
+        self.density_latched_at_motor_on = latched;
+    }
+
+    /// Changes the assignment of the drives to the lines of the bus
+    pub fn set_cable_type(&mut self, cable_type: CableType) {
+        if self.cable_type != cable_type {
+            // Release the lines of the old assignment first
+            self.drive_a.stop_motor();
+            self.drive_b.stop_motor();
+            self.update_drive_lines();
+            self.cable_type = cable_type;
+        }
+    }
+
+    /// Drives the select and motor lines according to the state of both drives
+    fn update_drive_lines(&mut self) {
+        let select_a = self.drive_a.selection_signal_active();
+        let select_b = self.drive_b.selection_signal_active();
+        let motor_a = self.drive_a.is_spinning();
+        let motor_b = self.drive_b.is_spinning();
+
+        let [pin_10, pin_12, pin_14, pin_16] = match self.cable_type {
+            CableType::PcTwist => [motor_a, select_b, select_a, motor_b],
+            CableType::Straight => [motor_b, select_a, select_b, motor_a],
+            CableType::Shugart => [select_a, select_b, false, motor_a || motor_b],
+        };
+
+        let lines = &mut self.bus_lines;
+        for (line, active) in [
+            (&mut lines.pin_10, pin_10),
+            (&mut lines.pin_12, pin_12),
+            (&mut lines.pin_14, pin_14),
+            (&mut lines.pin_16, pin_16),
+        ] {
+            // All lines are active low
+            line.set_state(if active {
+                PinState::Low
+            } else {
+                PinState::High
+            })
+            .unwrap_infallible();
         }
     }
 
     pub fn select_density(&mut self, dens: Density) {
-        match dens {
+        let high = match dens {
             Density::High => {
-                self.out_density_select.set_high().unwrap_infallible();
                 rprintln!("High Density selected!");
+                true
             }
             Density::SingleDouble => {
-                self.out_density_select.set_low().unwrap_infallible();
                 rprintln!("Double Density selected!");
+                false
             }
+        };
+
+        self.out_density_select
+            .set_state(if high == self.invert_density_select {
+                PinState::Low
+            } else {
+                PinState::High
+            })
+            .unwrap_infallible();
+
+        // The next access spins the motor up again which makes the drive take over the density.
+        // The USB transfer of the next command keeps the motor off long enough.
+        if self.density_latched_at_motor_on && high != self.high_density && self.is_spinning() {
+            rprintln!("Stop motor to apply the density");
+            self.stop_motor();
         }
+        self.high_density = high;
     }
 
     pub fn write_protection_is_active(&mut self) -> bool {
@@ -72,10 +180,27 @@ impl FloppyControl {
         self.in_write_protect.is_low().unwrap_infallible()
     }
 
+    /// Checks the write protection and the presence of a disk in the selected drive
+    /// without spinning the motor. Returns None if no drive is selected.
+    pub fn query_drive_status(&mut self) -> Option<(bool, bool)> {
+        self.selected_drive_unit()?.activate_select_signal();
+        self.update_drive_lines();
+
+        cortex_m::asm::delay(DRIVE_STATUS_SETTLE_CYCLES);
+        let write_protected = self.in_write_protect.is_low().unwrap_infallible();
+        let disk_present = self.in_disk_change.is_high().unwrap_infallible();
+
+        self.selected_drive_unit()?
+            .disable_select_signal_if_possible();
+        self.update_drive_lines();
+        Some((write_protected, disk_present))
+    }
+
     pub fn spin_motor(&mut self) {
         if let Some(f) = self.selected_drive_unit().as_mut() {
             f.spin_motor()
         }
+        self.update_drive_lines();
     }
 
     #[must_use]
@@ -106,6 +231,33 @@ impl FloppyControl {
         if let Some(f) = self.selected_drive_unit() {
             f.stop_motor()
         }
+        self.update_drive_lines();
+    }
+
+    pub fn set_motor_off_delay(&mut self, delay_ms: u32) {
+        self.drive_a.set_motor_off_delay(delay_ms);
+        self.drive_b.set_motor_off_delay(delay_ms);
+    }
+
+    /// Time to wait after stepping before the head is used. Zero selects the default.
+    pub fn set_head_settle_time(&mut self, settle_ms: u32) {
+        let settle_ms = if settle_ms == 0 {
+            DEFAULT_HEAD_SETTLE_MS
+        } else {
+            settle_ms
+        };
+        self.head_settle_ticks = settle_ms.div_ceil(SYSTICK_PERIOD_MS) as usize;
+    }
+
+    /// Time between two step pulses. Worn drives might miss steps if they are too fast.
+    /// Zero selects the default.
+    pub fn set_step_rate(&mut self, step_rate_ms: u32) {
+        let step_rate_ms = if step_rate_ms == 0 {
+            DEFAULT_STEP_RATE_MS
+        } else {
+            step_rate_ms
+        };
+        self.step_ticks = step_rate_ms.div_ceil(SYSTICK_PERIOD_MS) as usize;
     }
 
     pub fn select_drive(&mut self, state: DriveSelectState) {
@@ -122,10 +274,16 @@ impl FloppyControl {
                 self.floppy_step_signals
                     .take()
                     .expect("Program flow error")
-                    .step_to_cylinder(current_head_position, u32::from(track.cylinder.0)),
+                    .step_to_cylinder(
+                        current_head_position,
+                        u32::from(track.cylinder.0),
+                        self.step_ticks,
+                        self.head_settle_ticks,
+                    ),
             );
 
             self.floppy_step_progress = Some(Cassette::new(func));
+            self.update_drive_lines();
         }
 
         self.out_head_select
@@ -137,6 +295,40 @@ impl FloppyControl {
             .unwrap_infallible();
     }
 
+    /// Moves the head of the selected drive outward until track 0 is detected.
+    /// Returns false if no drive is selected.
+    pub fn recalibrate(&mut self) -> bool {
+        let Some(selected_drive) = self.selected_drive_unit() else {
+            return false;
+        };
+
+        selected_drive.forget_head_position();
+        self.select_track(Track {
+            cylinder: Cylinder(0),
+            head: Head(0),
+        });
+        true
+    }
+
+    /// Provides the cylinder the head of the selected drive is believed to be on
+    /// and the number of failed seeks. Returns None if no drive is selected.
+    #[must_use]
+    pub fn query_head_position(&self) -> Option<(Option<u32>, u32)> {
+        self.selected_drive_unit_ref().map(|f| (f.head_cylinder(), f.failed_seeks()))
+    }
+
+    /// Checks if the head of the selected drive is known to be on track 0
+    pub fn head_at_track_zero(&mut self) -> bool {
+        self.head_at_cylinder(0)
+    }
+
+    /// Checks if the head of the selected drive is known to be on the cylinder.
+    /// False after lost steps as the position is unknown then.
+    pub fn head_at_cylinder(&mut self, cylinder: u32) -> bool {
+        self.selected_drive_unit()
+            .map_or(false, |f| f.head_position_equals(cylinder))
+    }
+
     #[must_use]
     pub fn reached_selected_cylinder(&self) -> bool {
         self.floppy_step_progress.is_none()
@@ -157,5 +349,7 @@ impl FloppyControl {
                 self.floppy_step_progress = None;
             }
         }
+
+        self.update_drive_lines();
     }
 }
@@ -1,8 +1,6 @@
-use core::convert::Infallible;
+use util::MIN_MOTOR_OFF_DELAY_MS;
 
-use alloc::boxed::Box;
-use stm32f4xx_hal::hal::digital::v2::{OutputPin, StatefulOutputPin};
-use unwrap_infallible::UnwrapInfallible;
+use crate::SYSTICK_PERIOD_MS;
 
 enum MotorState {
     Off,
@@ -13,24 +11,26 @@ pub enum HeadPosition {
     Cylinder(u32),
 }
 
+/// State of a single drive. The lines of the floppy bus are driven by
+/// `FloppyControl` as their assignment depends on the cable.
 pub struct FloppyDriveUnit {
-    out_motor_enable: Box<dyn OutputPin<Error = Infallible> + Send>,
-    out_drive_select: Box<dyn StatefulOutputPin<Error = Infallible> + Send>,
+    select_signal: bool,
     motor_state: MotorState,
+    motor_off_delay: u32,
     head_position: Option<HeadPosition>,
+    /// Seeks which ended without a known position, e.g. because of lost steps
+    failed_seeks: u32,
 }
 
 impl FloppyDriveUnit {
     #[must_use]
-    pub fn new(
-        out_motor_enable: Box<dyn OutputPin<Error = Infallible> + Send>,
-        out_drive_select: Box<dyn StatefulOutputPin<Error = Infallible> + Send>,
-    ) -> Self {
+    pub fn new() -> Self {
         Self {
-            out_motor_enable,
-            out_drive_select,
+            select_signal: false,
             motor_state: MotorState::Off,
+            motor_off_delay: MIN_MOTOR_OFF_DELAY_MS / SYSTICK_PERIOD_MS,
             head_position: Some(HeadPosition::Unknown),
+            failed_seeks: 0,
         }
     }
 
@@ -45,24 +45,33 @@ impl FloppyDriveUnit {
     }
 
     pub fn spin_motor(&mut self) {
-        self.out_motor_enable.set_low().unwrap_infallible();
-        self.out_drive_select.set_low().unwrap_infallible();
-        self.motor_state = MotorState::On(600);
+        self.select_signal = true;
+        self.motor_state = MotorState::On(self.motor_off_delay);
+    }
+
+    /// Keeps the motor spinning for the given time after the last request
+    /// to avoid spinning up again if another operation follows shortly.
+    pub fn set_motor_off_delay(&mut self, delay_ms: u32) {
+        self.motor_off_delay = delay_ms.max(MIN_MOTOR_OFF_DELAY_MS) / SYSTICK_PERIOD_MS;
     }
 
     pub fn disable_select_signal_if_possible(&mut self) {
         if matches!(self.motor_state, MotorState::Off) && self.head_position.is_some() {
-            self.out_drive_select.set_high().unwrap_infallible();
+            self.select_signal = false;
         }
     }
 
+    /// Activates the select signal without spinning the motor
+    pub fn activate_select_signal(&mut self) {
+        self.select_signal = true;
+    }
+
     #[must_use]
     pub fn selection_signal_active(&self) -> bool {
-        self.out_drive_select.is_set_low().unwrap_infallible()
+        self.select_signal
     }
 
     pub fn stop_motor(&mut self) {
-        self.out_motor_enable.set_high().unwrap_infallible();
         self.motor_state = MotorState::Off;
         self.disable_select_signal_if_possible();
     }
@@ -74,16 +83,40 @@ impl FloppyDriveUnit {
 
     pub fn take_head_position_for_stepping(&mut self) -> HeadPosition {
         let taken = self.head_position.take().expect("Program flow error");
-        self.out_drive_select.set_low().unwrap_infallible();
+        self.select_signal = true;
         taken
     }
 
     pub fn insert_current_head_position(&mut self, pos: HeadPosition) {
+        if matches!(pos, HeadPosition::Unknown) {
+            self.failed_seeks += 1;
+        }
         let old = self.head_position.replace(pos);
         assert!(old.is_none(), "Program flow error");
         self.disable_select_signal_if_possible();
     }
 
+    /// Forces a search for track 0 on the next step
+    pub fn forget_head_position(&mut self) {
+        if self.head_position.is_some() {
+            self.head_position = Some(HeadPosition::Unknown);
+        }
+    }
+
+    /// Cylinder the head is known to be on. None if unknown or while stepping.
+    #[must_use]
+    pub fn head_cylinder(&self) -> Option<u32> {
+        match self.head_position {
+            Some(HeadPosition::Cylinder(cylinder)) => Some(cylinder),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn failed_seeks(&self) -> u32 {
+        self.failed_seeks
+    }
+
     pub fn head_position_equals(&mut self, cylinder: u32) -> bool {
         if let Some(HeadPosition::Cylinder(c)) = self.head_position.as_ref() && *c==cylinder {
             true
@@ -92,3 +125,9 @@ impl FloppyDriveUnit {
         }
     }
 }
+
+impl Default for FloppyDriveUnit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
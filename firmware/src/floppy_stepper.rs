@@ -1,6 +1,7 @@
 use core::convert::Infallible;
 
 use alloc::boxed::Box;
+use rtt_target::rprintln;
 use stm32f4xx_hal::{
     gpio::PinState,
     hal::digital::v2::{InputPin, OutputPin, StatefulOutputPin},
@@ -28,10 +29,12 @@ enum StepDirection {
 }
 
 const DURATION_CHANGE_SETTLE_TIME: usize = 10;
-const HEAD_SETTLE_TIME: usize = 10;
+/// Upper bound of steps while searching for track 0 to avoid grinding against the stop
+const MAX_RECALIBRATION_STEPS: usize = 90;
 
-async fn wait_for_head_to_settle() {
-    wait(HEAD_SETTLE_TIME).await;
+/// Every yield takes one `SYSTICK_PERIOD_MS`
+async fn wait_for_head_to_settle(settle_ticks: usize) {
+    wait(settle_ticks).await;
 }
 
 impl FloppyStepperSignals {
@@ -56,31 +59,34 @@ impl FloppyStepperSignals {
         wait(DURATION_CHANGE_SETTLE_TIME).await;
     }
 
-    async fn perform_step(&mut self) {
+    /// Every step takes at least two ticks. One for the pulse and one after it.
+    async fn perform_step(&mut self, step_ticks: usize) {
         self.out_step_perform.set_low().unwrap_infallible();
         cassette::yield_now().await;
         self.out_step_perform.set_high().unwrap_infallible();
-        cassette::yield_now().await;
+        wait(step_ticks.max(2) - 1).await;
     }
 
     pub async fn step_to_cylinder(
         mut self,
         current_position: HeadPosition,
         wanted_cylinder: u32,
+        step_ticks: usize,
+        settle_ticks: usize,
     ) -> (Self, HeadPosition) {
         let current_pos = match current_position {
             HeadPosition::Unknown => {
                 // We need to get to track 0 before we know our position
                 self.set_direction(StepDirection::Outward).await;
 
-                for _ in 0..90 {
-                    self.perform_step().await;
+                for _ in 0..MAX_RECALIBRATION_STEPS {
+                    self.perform_step(step_ticks).await;
 
                     if self.in_track_00.is_low().unwrap_infallible() {
                         break;
                     }
                 }
-                wait_for_head_to_settle().await;
+                wait_for_head_to_settle(settle_ticks).await;
                 if self.in_track_00.is_high().unwrap_infallible() {
                     return (self, HeadPosition::Unknown);
                 };
@@ -103,9 +109,17 @@ impl FloppyStepperSignals {
         let steps_to_perform = current_pos.abs_diff(wanted_cylinder);
 
         for _ in 0..steps_to_perform {
-            self.perform_step().await;
+            self.perform_step(step_ticks).await;
+        }
+        wait_for_head_to_settle(settle_ticks).await;
+
+        // Track 0 is the only position which can be checked.
+        // A mismatch means that the drive missed some steps.
+        let at_track_zero = self.in_track_00.is_low().unwrap_infallible();
+        if at_track_zero != (wanted_cylinder == 0) {
+            rprintln!("Seek to cylinder {} lost steps", wanted_cylinder);
+            return (self, HeadPosition::Unknown);
         }
-        wait_for_head_to_settle().await;
 
         (self, HeadPosition::Cylinder(wanted_cylinder))
     }
@@ -9,6 +9,14 @@ use stm32f4xx_hal::pac::{DMA1, TIM2};
 
 pub const BUFFER_SIZE: usize = 8;
 
+/// Pulses written after the end of a track. The track end is erased, so without them
+/// the last pulses of the track stay in the DMA buffer until the next rotation.
+/// At least `BUFFER_SIZE` are required to push all of the track to the reader.
+/// Drives which need more time to provide the first pulses after the write gate
+/// might need some more.
+pub const TRAILING_IDLE_PULSES: usize = BUFFER_SIZE;
+const _: () = assert!(TRAILING_IDLE_PULSES >= BUFFER_SIZE);
+
 /*
  * Input using Timer 2, Input Channel 3.
  * Connected to PA2.
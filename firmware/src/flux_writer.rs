@@ -7,6 +7,7 @@ use heapless::spsc::Consumer;
 use heapless::Vec;
 use stm32f4xx_hal::hal::digital::v2::OutputPin;
 use unwrap_infallible::UnwrapInfallible;
+use util::WriteGateMargins;
 
 use stm32f4xx_hal::pac::{DMA1, TIM4};
 
@@ -28,6 +29,7 @@ pub struct FluxWriter {
     number_of_last_pulses: i32,
     cons: Consumer<'static, u32, 128>,
     write_gate: Box<dyn OutputPin<Error = Infallible> + Send>,
+    margins: WriteGateMargins,
 }
 
 impl FluxWriter {
@@ -66,8 +68,18 @@ impl FluxWriter {
                 self.tim4
                     .ccmr2_output()
                     .modify(|_, w| w.oc3m().force_inactive());
+                // Used by the timer after the next update. Keeps the write gate active a bit longer.
+                let lead_out_ticks = self.margins.lead_out_ticks();
+                if lead_out_ticks > 0 {
+                    self.tim4.arr.write(|w| w.arr().bits(lead_out_ticks as u16));
+                }
             }
-            if self.number_of_last_pulses == -2 {
+            let last_update = if self.margins.lead_out_ticks() > 0 {
+                -3
+            } else {
+                -2
+            };
+            if self.number_of_last_pulses == last_update {
                 self.tim4.cr1.modify(|_, w| w.cen().clear_bit()); // disable timer
                 self.write_gate.set_high().unwrap_infallible();
             }
@@ -178,10 +190,17 @@ impl FluxWriter {
 
         self.tim4.sr.write(|w| w.uif().clear()); // Clear interrupt
 
-        self.tim4.cnt.write(|w| w.cnt().bits(400)); // reset count to 0
+        // The lead in delays the first flux reversal after the start of the transmission
+        let first_update = 400 + self.margins.lead_in_ticks() as u16;
+        self.tim4.cnt.write(|w| w.cnt().bits(first_update)); // reset count to 0
         self.tim4.arr.write(|w| w.arr().bits(400)); // count to 200 and reset
     }
 
+    /// Used by the following transmissions
+    pub fn set_margins(&mut self, margins: WriteGateMargins) {
+        self.margins = margins;
+    }
+
     pub fn enable_write_head(&mut self) {
         self.write_gate.set_low().unwrap_infallible();
     }
@@ -228,6 +247,7 @@ impl FluxWriter {
             number_of_last_pulses: 0,
             cons,
             write_gate,
+            margins: WriteGateMargins::default(),
         }
     }
 }
@@ -1,4 +1,5 @@
 use stm32f4xx_hal::pac::TIM5;
+use util::INDEX_SIM_PULSE_TICKS;
 
 pub struct IndexSim {
     tim5: TIM5,
@@ -10,7 +11,7 @@ impl IndexSim {
         tim5.cr1.modify(|_, w| w.dir().up());
         tim5.cnt.write(|w| w.cnt().bits(0)); // reset count to 0
         tim5.arr.write(|w| w.arr().bits(14 * 1000 * 1000)); // 6 Hz == 360 RPM
-        tim5.ccr2().write(|w| w.ccr().bits(200_000)); // output compare value, have something like 3ms
+        tim5.ccr2().write(|w| w.ccr().bits(INDEX_SIM_PULSE_TICKS)); // output compare value, have something like 3ms
         tim5.ccmr1_output().modify(|_, w| w.oc2m().force_inactive());
         tim5.ccer.write(|w| w.cc2e().set_bit().cc2p().set_bit()); //activate channel 2 output with inverted polarity
 
@@ -17,6 +17,16 @@ use crate::{
 pub static INDEX_OCCURED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
 pub static START_TRANSMIT_ON_INDEX: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
 pub static START_RECEIVE_ON_INDEX: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+pub static ABORT_REQUESTED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+/// Cycle counter at the last index pulse
+static LAST_INDEX_CYCLES: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+/// Duration of the last rotation in ticks of the 84 MHz timer
+static ROTATION_TICKS: Mutex<Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// The core is clocked with 168 MHz, the timers with 84 MHz
+const CYCLES_PER_TIMER_TICK: u32 = 2;
+/// Between 400 and 200 RPM. Everything else is the first index after the motor was stopped.
+const PLAUSIBLE_ROTATION_TICKS: core::ops::Range<u32> = 12_600_000..25_200_000;
 
 pub static FLUX_WRITER: Mutex<RefCell<Option<FluxWriter>>> = Mutex::new(RefCell::new(None));
 pub static FLUX_READER: Mutex<RefCell<Option<FluxReader>>> = Mutex::new(RefCell::new(None));
@@ -34,16 +44,75 @@ pub fn flux_reader_stop_reception() {
     });
 }
 
-pub fn async_select_and_wait_for_track(track: Track) -> impl Future<Output = ()> {
+/// Starts reading immediately instead of waiting for the index
+pub fn flux_reader_start_reception() {
     cortex_m::interrupt::free(|cs| {
-        FLOPPY_CONTROL
+        FLUX_READER
             .borrow(cs)
             .borrow_mut()
             .as_mut()
             .expect("Program flow error")
-            .select_track(track);
+            .start_reception(cs);
     });
+}
+
+/// Duration of a rotation as measured between the last index pulses.
+/// None if the motor doesn't spin as the measurement might belong to another drive or speed.
+pub fn rotation_ticks() -> Option<u32> {
+    cortex_m::interrupt::free(|cs| {
+        let last_index = LAST_INDEX_CYCLES.borrow(cs).get()?;
+        let since_index = cortex_m::peripheral::DWT::cycle_count().wrapping_sub(last_index)
+            / CYCLES_PER_TIMER_TICK;
+
+        // Without an index pulse during the slowest plausible rotation, the motor is off
+        if since_index < PLAUSIBLE_ROTATION_TICKS.end {
+            ROTATION_TICKS.borrow(cs).get()
+        } else {
+            None
+        }
+    })
+}
+
+/// Checks if the host requested to abort the current operation
+pub fn abort_requested() -> bool {
+    cortex_m::interrupt::free(|cs| ABORT_REQUESTED.borrow(cs).get())
+}
+
+/// Moves the head to the cylinder of the track. If steps were lost, the position is unknown
+/// and the second attempt seeks from track 0 again.
+/// Returns false if the cylinder wasn't reached.
+pub async fn async_select_and_wait_for_track(track: Track) -> bool {
+    for _ in 0..2 {
+        cortex_m::interrupt::free(|cs| {
+            FLOPPY_CONTROL
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .select_track(track);
+        });
+
+        async_wait_for_selected_cylinder().await;
+
+        let reached = cortex_m::interrupt::free(|cs| {
+            FLOPPY_CONTROL
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .head_at_cylinder(u32::from(track.cylinder.0))
+        });
+
+        if reached {
+            return true;
+        }
+        rprintln!("Cylinder {} not reached. Seek again", track.cylinder.0);
+    }
+
+    false
+}
 
+fn async_wait_for_selected_cylinder() -> impl Future<Output = ()> {
     poll_fn(|_| {
         let reached = cortex_m::interrupt::free(|cs| {
             FLOPPY_CONTROL
@@ -62,6 +131,34 @@ pub fn async_select_and_wait_for_track(track: Track) -> impl Future<Output = ()>
     })
 }
 
+/// Searches for track 0 on the selected drive.
+/// Returns None if no drive is selected and false if track 0 was not found.
+pub async fn async_recalibrate() -> Option<bool> {
+    let started = cortex_m::interrupt::free(|cs| {
+        FLOPPY_CONTROL
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .expect("Program flow error")
+            .recalibrate()
+    });
+
+    if !started {
+        return None;
+    }
+
+    async_wait_for_selected_cylinder().await;
+
+    Some(cortex_m::interrupt::free(|cs| {
+        FLOPPY_CONTROL
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .expect("Program flow error")
+            .head_at_track_zero()
+    }))
+}
+
 pub fn async_wait_for_index() -> impl Future<Output = Result<(), ()>> {
     cortex_m::interrupt::free(|cs| {
         INDEX_OCCURED.borrow(cs).set(false);
@@ -119,6 +216,26 @@ pub fn async_wait_for_transmit() -> impl Future<Output = Result<(), ()>> {
     })
 }
 
+/// Waits until the last pulse was written and the write gate is released
+pub fn async_wait_for_transmit_end() -> impl Future<Output = ()> {
+    poll_fn(|_| {
+        let transmission_active = cortex_m::interrupt::free(|cs| {
+            FLUX_WRITER
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .expect("Program flow error")
+                .transmission_active()
+        });
+
+        if transmission_active {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+}
+
 pub fn async_wait_for_receive() -> impl Future<Output = Result<(), ()>> {
     poll_fn(|_| {
         let (transmission_active, motor_spinning) = cortex_m::interrupt::free(|cs| {
@@ -140,7 +257,7 @@ pub fn async_wait_for_receive() -> impl Future<Output = Result<(), ()>> {
 
         if transmission_active {
             Poll::Ready(Ok(()))
-        } else if !motor_spinning {
+        } else if !motor_spinning || abort_requested() {
             Poll::Ready(Err(()))
         } else {
             Poll::Pending
@@ -177,6 +294,15 @@ fn EXTI3() {
     cortex_m::interrupt::free(|cs| {
         INDEX_OCCURED.borrow(cs).set(true);
 
+        let now = cortex_m::peripheral::DWT::cycle_count();
+        if let Some(last) = LAST_INDEX_CYCLES.borrow(cs).replace(Some(now)) {
+            let ticks = now.wrapping_sub(last) / CYCLES_PER_TIMER_TICK;
+            // The first index after the motor was stopped invalidates the old measurement
+            ROTATION_TICKS
+                .borrow(cs)
+                .set(PLAUSIBLE_ROTATION_TICKS.contains(&ticks).then_some(ticks));
+        }
+
         if FLUX_WRITER
             .borrow(cs)
             .borrow()
@@ -21,11 +21,12 @@ extern crate alloc;
 
 use alloc::boxed::Box;
 use alloc::format;
+use alloc::string::String;
 use cassette::Cassette;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
-use floppy_control::FloppyControl;
+use floppy_control::{DriveBusLines, FloppyControl};
 use flux_reader::FluxReader;
 use flux_writer::FluxWriter;
 use heapless::spsc::Queue;
@@ -40,7 +41,9 @@ use track_raw::{RawTrackHandler, WriteVerifyError, WriteVerifySuccess};
 use usb::UsbHandler;
 use usb_device::class_prelude::UsbBusAllocator;
 use usb_device::prelude::*;
-use util::{USB_PID, USB_VID};
+use util::{
+    reception_checksum, shorten_to_rotation, RawCellData, Track, VerifyHistogram, USB_PID, USB_VID,
+};
 use vendor_class::Command;
 
 static DEBUG_LED_GREEN: Mutex<RefCell<Option<Pin<'D', 12, Output>>>> =
@@ -48,10 +51,21 @@ static DEBUG_LED_GREEN: Mutex<RefCell<Option<Pin<'D', 12, Output>>>> =
 
 static INDEX_SIM: Mutex<RefCell<Option<IndexSim>>> = Mutex::new(RefCell::new(None));
 
+/// Set by the host to receive the distribution of verify differences after each track.
+static DETAILED_VERIFY: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Set by the host to loosen the verification of higher cylinders
+static VERIFY_THRESHOLD_EXTRA_PERCENT: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Memory given to the allocator
+pub const HEAP_SIZE: usize = 13509 * 8;
+
+/// SysTick is clocked with HCLK/8 and reloaded every 42000 cycles
+pub const SYSTICK_PERIOD_MS: u32 = 2;
+
 use alloc::sync::Arc;
 use alloc_cortex_m::CortexMHeap;
 
-use crate::floppy_drive_unit::FloppyDriveUnit;
 use crate::floppy_stepper::FloppyStepperSignals;
 use crate::vendor_class::FloppyTracerVendorClass;
 
@@ -74,7 +88,6 @@ fn main() -> ! {
 
     {
         use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 13509 * 8;
         static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
         unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
     }
@@ -144,7 +157,7 @@ fn main() -> ! {
     let out_head_select = gpiob
         .pb11
         .into_push_pull_output_in_state(stm32f4xx_hal::gpio::PinState::High);
-    let _in_disk_change_ready = gpiob.pb12.into_pull_up_input();
+    let in_disk_change_ready = gpiob.pb12.into_pull_up_input();
 
     // Check if there was a panic message, if so, send to UART
     if let Some(msg) = get_panic_message_bytes() {
@@ -154,8 +167,13 @@ fn main() -> ! {
         );
     }
 
-    let drive_a = FloppyDriveUnit::new(Box::new(out_motor_enable_a), Box::new(out_drive_select_a));
-    let drive_b = FloppyDriveUnit::new(Box::new(out_motor_enable_b), Box::new(out_drive_select_b));
+    // The assignment of these lines to the drives depends on the cable
+    let bus_lines = DriveBusLines {
+        pin_10: Box::new(out_motor_enable_a),
+        pin_12: Box::new(out_drive_select_b),
+        pin_14: Box::new(out_drive_select_a),
+        pin_16: Box::new(out_motor_enable_b),
+    };
     let stepper = FloppyStepperSignals::new(
         Box::new(out_step_direction),
         Box::new(out_step_perform),
@@ -163,12 +181,12 @@ fn main() -> ! {
     );
 
     let floppy_control = FloppyControl::new(
-        drive_a,
-        drive_b,
+        bus_lines,
         stepper,
         Box::new(out_head_select),
         Box::new(out_density_select),
         Box::new(in_write_protect),
+        Box::new(in_disk_change_ready),
     );
 
     let usb = USB {
@@ -250,11 +268,76 @@ fn main() -> ! {
     let raw_track_writer = track_raw::RawTrackHandler {
         read_cons,
         write_prod_cell: RefCell::new(write_prod),
+        histogram: VerifyHistogram::default(),
     };
 
     mainloop(usb_handler, raw_track_writer);
 }
 
+fn write_verify_response(
+    track: Track,
+    result: Result<WriteVerifySuccess, WriteVerifyError>,
+) -> String {
+    match result {
+        Ok(WriteVerifySuccess {
+            write_operations,
+            verify_operations,
+            max_err,
+            write_precompensation,
+            ..
+        }) => {
+            format!(
+                "WrittenAndVerified {} {} {} {} {} {}",
+                track.cylinder.0,
+                track.head.0,
+                write_operations,
+                verify_operations,
+                max_err.0,
+                write_precompensation.0
+            )
+        }
+        Err(WriteVerifyError {
+            write_operations,
+            verify_operations,
+            error,
+        }) => format!(
+            "Fail {} {} {} {} {:?}",
+            track.cylinder.0, track.head.0, write_operations, verify_operations, error
+        ),
+    }
+}
+
+fn histogram_response(result: &Result<WriteVerifySuccess, WriteVerifyError>) -> Option<String> {
+    let detailed = cortex_m::interrupt::free(|cs| DETAILED_VERIFY.borrow(cs).get());
+
+    match result {
+        Ok(success) if detailed && success.verify_operations > 0 => {
+            // Saturate the counts to keep the response short.
+            let b = success.histogram.buckets.map(|count| count.min(99_999));
+            Some(format!(
+                "Histogram {} {} {} {} {} {} {} {}",
+                b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Waits until the host has checked the size and checksum of the received track.
+/// Returns false if the track shall be discarded.
+fn wait_for_reception_confirmation(usb_handler: &mut UsbHandler) -> bool {
+    loop {
+        usb_handler.handle();
+
+        if let Some(write) = usb_handler.vendor_class.take_reception_confirmation() {
+            return write;
+        }
+        if cortex_m::interrupt::free(|cs| interrupts::ABORT_REQUESTED.borrow(cs).get()) {
+            return false;
+        }
+    }
+}
+
 fn mainloop(mut usb_handler: UsbHandler, mut raw_track_writer: RawTrackHandler) -> ! {
     let mut next_command: Option<Command>;
 
@@ -262,16 +345,23 @@ fn mainloop(mut usb_handler: UsbHandler, mut raw_track_writer: RawTrackHandler)
         usb_handler.handle();
         next_command = usb_handler.vendor_class.take_command();
 
+        if next_command.is_some() {
+            // An abort only affects the operation which was running
+            cortex_m::interrupt::free(|cs| interrupts::ABORT_REQUESTED.borrow(cs).set(false));
+        }
+
         match next_command.take() {
             Some(Command::ReadTrack {
                 track,
                 duration_to_record,
                 wait_for_index,
+                high_resolution,
             }) => {
                 let write_verify_fut = Box::pin(raw_track_writer.read_track(
                     track,
                     duration_to_record,
                     wait_for_index,
+                    high_resolution,
                     &mut usb_handler,
                 ));
                 let cm = Cassette::new(write_verify_fut);
@@ -286,8 +376,58 @@ fn mainloop(mut usb_handler: UsbHandler, mut raw_track_writer: RawTrackHandler)
                 track,
                 raw_cell_data,
                 write_precompensation,
+                verify_windows,
+                write_only,
+                write_gate_margins,
+                confirm_reception,
             }) => {
-                usb_handler.vendor_class.response("GotCmd");
+                // Allows the host to check the assembled track before it is written
+                let str_response = format!(
+                    "GotCmd {} {}",
+                    raw_cell_data.borrow_cells().len(),
+                    reception_checksum(raw_cell_data.borrow_speeds(), raw_cell_data.borrow_cells())
+                );
+                usb_handler.vendor_class.response(&str_response);
+
+                if confirm_reception && !wait_for_reception_confirmation(&mut usb_handler) {
+                    rprintln!("Track was not received correctly and is discarded");
+                    continue;
+                }
+
+                // The end of a track which is too long would overwrite its own start.
+                // The lead-in and lead-out of the write gate need their share of the rotation.
+                let raw_cell_data = match interrupts::rotation_ticks() {
+                    Some(rotation_ticks) => {
+                        let rotation_ticks = rotation_ticks.saturating_sub(
+                            write_gate_margins.lead_in_ticks()
+                                + write_gate_margins.lead_out_ticks(),
+                        );
+                        let heads = raw_cell_data.into_heads();
+                        let mut speeds = heads.speeds;
+                        let mut cells = heads.cells;
+                        let removed = shorten_to_rotation(
+                            &mut speeds,
+                            &mut cells,
+                            rotation_ticks,
+                            2 * flux_reader::TRAILING_IDLE_PULSES as u32,
+                        );
+                        if removed > 0 {
+                            rprintln!(
+                                "Warning! Track is too long for a rotation. Shortened by {} bytes.",
+                                removed
+                            );
+                            // Precedes the result of the track like the histogram
+                            let str_response = format!(
+                                "Shortened {} {} {}",
+                                track.cylinder.0, track.head.0, removed
+                            );
+                            usb_handler.vendor_class.response(&str_response);
+                        }
+                        RawCellData::construct(speeds, cells, heads.gap_generator)
+                            .expect("Program flow error")
+                    }
+                    None => raw_cell_data,
+                };
 
                 cortex_m::interrupt::free(|cs| {
                     interrupts::FLOPPY_CONTROL
@@ -296,11 +436,20 @@ fn mainloop(mut usb_handler: UsbHandler, mut raw_track_writer: RawTrackHandler)
                         .as_mut()
                         .expect("Program flow error")
                         .spin_motor();
+
+                    interrupts::FLUX_WRITER
+                        .borrow(cs)
+                        .borrow_mut()
+                        .as_mut()
+                        .expect("Program flow error")
+                        .set_margins(write_gate_margins);
                 });
 
                 let write_verify_fut = Box::pin(raw_track_writer.write_and_verify(
                     track,
                     write_precompensation,
+                    verify_windows,
+                    write_only,
                     raw_cell_data,
                 ));
                 let mut cm = Cassette::new(write_verify_fut);
@@ -313,35 +462,100 @@ fn mainloop(mut usb_handler: UsbHandler, mut raw_track_writer: RawTrackHandler)
                     }
                 };
 
-                let str_response = match result {
-                    Ok(WriteVerifySuccess {
-                        write_operations,
-                        verify_operations,
-                        max_err,
-                        write_precompensation,
-                    }) => {
-                        format!(
-                            "WrittenAndVerified {} {} {} {} {} {}",
-                            track.cylinder.0,
-                            track.head.0,
-                            write_operations,
-                            verify_operations,
-                            max_err.0,
-                            write_precompensation.0
-                        )
+                // The histogram precedes the result to keep the result the last answer of a track
+                if let Some(str_response) = histogram_response(&result) {
+                    usb_handler.vendor_class.response(&str_response);
+                }
+                let str_response = write_verify_response(track, result);
+                usb_handler.vendor_class.response(&str_response);
+            }
+            Some(Command::WriteVerifyFluxTrack {
+                track,
+                flux_data,
+                verify_windows,
+                write_only,
+                write_gate_margins,
+                confirm_reception,
+            }) => {
+                let str_response = format!(
+                    "GotCmd {} {}",
+                    flux_data.len(),
+                    reception_checksum(&[], &flux_data)
+                );
+                usb_handler.vendor_class.response(&str_response);
+
+                if confirm_reception && !wait_for_reception_confirmation(&mut usb_handler) {
+                    rprintln!("Track was not received correctly and is discarded");
+                    continue;
+                }
+
+                cortex_m::interrupt::free(|cs| {
+                    interrupts::FLUX_WRITER
+                        .borrow(cs)
+                        .borrow_mut()
+                        .as_mut()
+                        .expect("Program flow error")
+                        .set_margins(write_gate_margins);
+                });
+
+                let write_verify_fut = Box::pin(raw_track_writer.write_and_verify_flux(
+                    track,
+                    verify_windows,
+                    write_only,
+                    flux_data,
+                ));
+                let mut cm = Cassette::new(write_verify_fut);
+
+                let result = loop {
+                    usb_handler.handle();
+
+                    if let Some(result) = cm.poll_on() {
+                        break result;
                     }
-                    Err(WriteVerifyError {
-                        write_operations,
-                        verify_operations,
-                        error,
-                    }) => format!(
-                        "Fail {} {} {} {} {:?}",
-                        track.cylinder.0, track.head.0, write_operations, verify_operations, error
-                    ),
                 };
 
+                // The histogram precedes the result to keep the result the last answer of a track
+                if let Some(str_response) = histogram_response(&result) {
+                    usb_handler.vendor_class.response(&str_response);
+                }
+                let str_response = write_verify_response(track, result);
                 usb_handler.vendor_class.response(&str_response);
             }
+            Some(Command::MeasureWriteRecovery { track }) => {
+                let mut cm =
+                    Cassette::new(Box::pin(raw_track_writer.measure_write_recovery(track)));
+
+                let result = loop {
+                    usb_handler.handle();
+
+                    if let Some(result) = cm.poll_on() {
+                        break result;
+                    }
+                };
+
+                let str_response = match result {
+                    Ok(microseconds) => format!("WriteRecovery {microseconds}"),
+                    Err(err) => format!("Fail {err:?}"),
+                };
+                usb_handler.vendor_class.response(&str_response);
+            }
+            Some(Command::Recalibrate) => {
+                let mut cm = Cassette::new(Box::pin(interrupts::async_recalibrate()));
+
+                let result = loop {
+                    usb_handler.handle();
+
+                    if let Some(result) = cm.poll_on() {
+                        break result;
+                    }
+                };
+
+                usb_handler.vendor_class.response(match result {
+                    Some(true) => "Recalibrated",
+                    Some(false) => "Fail Track0NotFound",
+                    None => "Fail NoDriveSelected",
+                });
+            }
             _ => {}
         }
     }
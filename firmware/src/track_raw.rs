@@ -1,35 +1,88 @@
 use core::{cell::RefCell, cmp::max, future::Future, mem, task::Poll};
 
-use alloc::{collections::VecDeque, vec::Vec};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use cassette::futures::poll_fn;
 use heapless::spsc::{Consumer, Producer};
 
 use util::{
-    bitstream::to_bit_stream, fluxpulse::FluxPulseGenerator, Bit, PulseDuration, RawCellData,
-    Track, PULSE_REDUCE_SHIFT,
+    bitstream::to_bit_stream, cross_correlate, fluxpulse::FluxPulseGenerator,
+    skip_repeated_pulses, verify_threshold_percent, Bit, Correlation, GapGenerator,
+    PhaseDriftDetector, PulseDuration, RawCellData, Track, VerifyHistogram, VerifyWindows,
+    DRIVE_3_5_RPM, MAX_AGREEMENT, PULSE_REDUCE_SHIFT, STM_TIMER_HZ, VERIFY_THRESHOLD_PERCENT,
+    WRITE_PREFILL_PULSES,
 };
 
 use crate::{
     interrupts::{
         self, async_select_and_wait_for_track, async_wait_for_receive, async_wait_for_transmit,
-        flux_reader_stop_reception, FLUX_READER, START_RECEIVE_ON_INDEX, START_TRANSMIT_ON_INDEX,
+        async_wait_for_transmit_end, flux_reader_start_reception, flux_reader_stop_reception,
+        FLUX_READER, START_RECEIVE_ON_INDEX, START_TRANSMIT_ON_INDEX,
     },
     rprintln,
     usb::UsbHandler,
+    VERIFY_THRESHOLD_EXTRA_PERCENT,
 };
 
+/// The system clock is configured to 168 MHz in main
+const CYCLES_PER_MICROSECOND: u32 = 168;
+/// The timers are clocked with half of the system clock
+const TIMER_TICKS_PER_MICROSECOND: u32 = CYCLES_PER_MICROSECOND / 2;
+
+/// Pattern the track is filled with to measure the write recovery. 4 µs like "10" of DD MFM
+const RECOVERY_BACKGROUND_PULSE: u32 = 336;
+/// Pattern of the short write after which the recovery is measured
+const RECOVERY_BURST_PULSE: u32 = 504;
+const RECOVERY_BURST_PULSES: usize = 2000;
+/// Consecutive pulses of the background which must be read to consider the drive recovered
+const RECOVERY_CLEAN_PULSES: u32 = 32;
+/// Used if the rotation was not measured yet
+const DEFAULT_ROTATION_TICKS: u32 = (60.0 / DRIVE_3_5_RPM * STM_TIMER_HZ) as u32;
+
+/// Waits for the given time while allowing other tasks to continue
+async fn async_wait_us(microseconds: u32) {
+    let start = cortex_m::peripheral::DWT::cycle_count();
+    let cycles = microseconds * CYCLES_PER_MICROSECOND;
+
+    while cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start) < cycles {
+        cassette::yield_now().await;
+    }
+}
+
+/// Polls the future while keeping the USB connection alive.
+/// This allows the host to abort long waits.
+async fn handle_usb_while<F: Future>(usb_handler: &mut UsbHandler<'_>, future: F) -> F::Output {
+    let mut future = Box::pin(future);
+
+    poll_fn(|cx| {
+        usb_handler.handle();
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
 pub struct RawTrackHandler {
     pub read_cons: Consumer<'static, u32, 512>,
     pub write_prod_cell: RefCell<Producer<'static, u32, 128>>,
+    /// Differences of the last verification
+    pub histogram: VerifyHistogram,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum RawTrackError {
     NoIndexPulse,
     NoIncomingData,
     NoCrossCorrelation,
     DataNotEqual,
     WriteProtected,
+    /// Read back data is consistently longer or shorter than the ground truth.
+    /// Provided in timer ticks per 1000 pulses. Usually caused by the rotation speed of the drive.
+    PhaseDrift(i32),
+    /// Less pulses than required to start the transmission.
+    TrackTooShort,
+    /// The track consists of a single repeated pulse which can't be correlated with the read data.
+    UniformTrack,
+    /// The head didn't reach the cylinder even after seeking from track 0 again.
+    SeekFailed,
 }
 
 pub struct WriteVerifyError {
@@ -43,6 +96,7 @@ pub struct WriteVerifySuccess {
     pub verify_operations: u8,
     pub write_precompensation: PulseDuration,
     pub max_err: PulseDuration,
+    pub histogram: VerifyHistogram,
 }
 
 impl RawTrackHandler {
@@ -60,11 +114,14 @@ impl RawTrackHandler {
                         .is_spinning()
                 });
 
-                if motor_is_spinning {
-                    Poll::Pending
-                } else {
+                if !motor_is_spinning {
                     rprintln!("async_read_flux timeout!");
                     Poll::Ready(None)
+                } else if interrupts::abort_requested() {
+                    rprintln!("async_read_flux aborted!");
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
                 }
             }
         })
@@ -74,9 +131,17 @@ impl RawTrackHandler {
         &mut self,
         track: Track,
         write_precompensation: PulseDuration,
+        verify_windows: VerifyWindows,
+        write_only: bool,
         mut raw_cell_data: RawCellData,
     ) -> Result<WriteVerifySuccess, WriteVerifyError> {
-        async_select_and_wait_for_track(track).await;
+        if !async_select_and_wait_for_track(track).await {
+            return Err(WriteVerifyError {
+                write_operations: 0,
+                verify_operations: 0,
+                error: RawTrackError::SeekFailed,
+            });
+        }
 
         let write_protected = cortex_m::interrupt::free(|cs| {
             interrupts::FLOPPY_CONTROL
@@ -99,6 +164,16 @@ impl RawTrackHandler {
             });
         }
 
+        // Reported if all attempts have failed
+        let mut last_error = RawTrackError::DataNotEqual;
+
+        let threshold_percent = cortex_m::interrupt::free(|cs| {
+            verify_threshold_percent(
+                u32::from(track.cylinder.0),
+                VERIFY_THRESHOLD_EXTRA_PERCENT.borrow(cs).get(),
+            )
+        });
+
         for _ in 0..5 {
             rprintln!(
                 "Write track at cyl:{} head:{}",
@@ -116,10 +191,26 @@ impl RawTrackHandler {
                     verify_operations,
                 })?;
 
+            // The host decided to trust this track without reading it back
+            if write_only {
+                return Ok(WriteVerifySuccess {
+                    write_operations,
+                    verify_operations,
+                    write_precompensation,
+                    max_err: PulseDuration(0),
+                    histogram: VerifyHistogram::default(),
+                });
+            }
+
+            // Give the drive time to settle before reading back
+            async_wait_us(verify_windows.settle_delay_us).await;
+
             for read_try in 0..3 {
                 verify_operations += 1;
 
-                let verify_result = self.verify_track(raw_cell_data).await;
+                let verify_result = self
+                    .verify_track(raw_cell_data, verify_windows, threshold_percent)
+                    .await;
 
                 match verify_result {
                     Ok(max_err) => {
@@ -128,11 +219,16 @@ impl RawTrackHandler {
                             verify_operations,
                             write_precompensation,
                             max_err,
+                            histogram: self.histogram,
                         });
                     }
-                    Err((RawTrackError::DataNotEqual, track)) => {
+                    Err((
+                        error @ (RawTrackError::DataNotEqual | RawTrackError::PhaseDrift(_)),
+                        track,
+                    )) => {
                         // We shall do nothing. Maybe it was a fluke?
                         // Just read again...
+                        last_error = error;
                         raw_cell_data = track;
                     }
                     Err((RawTrackError::NoCrossCorrelation, track)) if read_try == 0 => {
@@ -166,7 +262,18 @@ impl RawTrackHandler {
                             error: RawTrackError::NoIndexPulse,
                         });
                     }
-                    Err((RawTrackError::WriteProtected, _)) => {
+                    Err((
+                        error @ (RawTrackError::TrackTooShort | RawTrackError::UniformTrack),
+                        _track,
+                    )) => {
+                        // Abort. The track can't be verified no matter how often it is written
+                        return Err(WriteVerifyError {
+                            write_operations,
+                            verify_operations,
+                            error,
+                        });
+                    }
+                    Err((RawTrackError::WriteProtected | RawTrackError::SeekFailed, _)) => {
                         panic!("Program flow error")
                     }
                 }
@@ -175,7 +282,7 @@ impl RawTrackHandler {
         Err(WriteVerifyError {
             write_operations,
             verify_operations,
-            error: RawTrackError::DataNotEqual,
+            error: last_error,
         })
     }
 
@@ -246,16 +353,18 @@ impl RawTrackHandler {
 
         write_prod_fpg.precompensation = write_precompensation.0 as u32;
 
-        if *track_data_to_write.borrow_has_non_flux_reversal_area() {
-            write_prod_fpg.enable_non_flux_reversal_generator = true;
-        } else {
-            write_prod_fpg.enable_weak_bit_generator = true;
+        match track_data_to_write.borrow_gap_generator() {
+            GapGenerator::WeakBits => write_prod_fpg.enable_weak_bit_generator = true,
+            GapGenerator::NonFluxReversal => {
+                write_prod_fpg.enable_non_flux_reversal_generator = true
+            }
+            GapGenerator::Disabled => {}
         }
 
         let mut track_data_iter = part.cells.iter();
 
         // prefill buffer with first data
-        while self.write_prod_cell.borrow().len() < 70 {
+        while self.write_prod_cell.borrow().len() < WRITE_PREFILL_PULSES {
             let mfm_byte = *track_data_iter.next().expect("Unexpected underflow");
             to_bit_stream(mfm_byte, |bit| write_prod_fpg.feed(bit));
         }
@@ -294,11 +403,11 @@ impl RawTrackHandler {
         /* Now this might be weird. We have to solve an issue here with our DMA.
          * At the moment, we erase the end of the track before writing to keep it clean
          * from any residual data. But this also means that we don't have any pulses
-         * for reading after our groundtruth data. We need to add as many pulses here
+         * for reading after our groundtruth data. We need to add at least as many pulses here
          * as the reading DMA buffer is in length to fix this problem.
          * Otherwise reading will stall and slow us down for exactly one rotation of the disk.
          */
-        for _ in 0..crate::flux_reader::BUFFER_SIZE {
+        for _ in 0..crate::flux_reader::TRAILING_IDLE_PULSES {
             write_prod_fpg.feed(Bit(false));
             write_prod_fpg.feed(Bit(true));
         }
@@ -308,11 +417,283 @@ impl RawTrackHandler {
         Ok(track_data_to_write)
     }
 
+    /// Writes and verifies flux timings as they are provided without any encoding.
+    /// The timings are expected in the same reduced form as they are sent during reading.
+    pub async fn write_and_verify_flux(
+        &mut self,
+        track: Track,
+        verify_windows: VerifyWindows,
+        write_only: bool,
+        flux_data: Vec<u8>,
+    ) -> Result<WriteVerifySuccess, WriteVerifyError> {
+        if !async_select_and_wait_for_track(track).await {
+            return Err(WriteVerifyError {
+                write_operations: 0,
+                verify_operations: 0,
+                error: RawTrackError::SeekFailed,
+            });
+        }
+
+        let write_protected = cortex_m::interrupt::free(|cs| {
+            interrupts::FLOPPY_CONTROL
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .write_protection_is_active()
+        });
+
+        let mut write_operations = 0;
+        let mut verify_operations = 0;
+
+        if write_protected {
+            rprintln!("Write Protected!");
+            return Err(WriteVerifyError {
+                write_operations,
+                verify_operations,
+                error: RawTrackError::WriteProtected,
+            });
+        }
+
+        for _ in 0..5 {
+            rprintln!(
+                "Write flux at cyl:{} head:{}",
+                track.cylinder.0,
+                track.head.0,
+            );
+            write_operations += 1;
+
+            self.write_flux_track(&flux_data)
+                .await
+                .map_err(|error| WriteVerifyError {
+                    error,
+                    write_operations,
+                    verify_operations,
+                })?;
+
+            if write_only {
+                return Ok(WriteVerifySuccess {
+                    write_operations,
+                    verify_operations,
+                    write_precompensation: PulseDuration(0),
+                    max_err: PulseDuration(0),
+                    histogram: VerifyHistogram::default(),
+                });
+            }
+
+            // Give the drive time to settle before reading back
+            async_wait_us(verify_windows.settle_delay_us).await;
+
+            for read_try in 0..3 {
+                verify_operations += 1;
+
+                match self.verify_flux_track(&flux_data, verify_windows).await {
+                    Ok(max_err) => {
+                        return Ok(WriteVerifySuccess {
+                            write_operations,
+                            verify_operations,
+                            write_precompensation: PulseDuration(0),
+                            max_err,
+                            histogram: self.histogram,
+                        });
+                    }
+                    Err(RawTrackError::DataNotEqual) => {}
+                    Err(RawTrackError::NoCrossCorrelation) if read_try == 0 => {}
+                    Err(RawTrackError::NoCrossCorrelation) => break,
+                    Err(error) => {
+                        return Err(WriteVerifyError {
+                            write_operations,
+                            verify_operations,
+                            error,
+                        });
+                    }
+                }
+            }
+        }
+        Err(WriteVerifyError {
+            write_operations,
+            verify_operations,
+            error: RawTrackError::DataNotEqual,
+        })
+    }
+
+    async fn write_flux_track(&mut self, flux_data: &[u8]) -> Result<(), RawTrackError> {
+        let flux_iter = flux_data
+            .iter()
+            .map(|pulse| u32::from(*pulse) << PULSE_REDUCE_SHIFT);
+
+        // Same as with cell data, the reading DMA needs some additional pulses
+        // after the groundtruth data. Just repeat the last one.
+        let last_pulse =
+            u32::from(*flux_data.last().ok_or(RawTrackError::TrackTooShort)?) << PULSE_REDUCE_SHIFT;
+        let trailing_pulses =
+            core::iter::repeat(last_pulse).take(crate::flux_reader::TRAILING_IDLE_PULSES);
+
+        self.write_pulses(flux_iter.chain(trailing_pulses)).await
+    }
+
+    /// Writes pulses given in timer ticks, starting at the next index pulse.
+    /// Returns after the last pulse was handed to the writer.
+    async fn write_pulses(
+        &mut self,
+        mut pulses: impl Iterator<Item = u32>,
+    ) -> Result<(), RawTrackError> {
+        cortex_m::interrupt::free(|cs| {
+            interrupts::FLUX_WRITER
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .clear_buffers();
+        });
+
+        // prefill buffer with first data.
+        // Done before the write head is enabled to leave the disk untouched if there is not enough.
+        while self.write_prod_cell.borrow().len() < WRITE_PREFILL_PULSES {
+            let pulse = pulses.next().ok_or(RawTrackError::TrackTooShort)?;
+            self.write_prod_cell
+                .borrow_mut()
+                .enqueue(pulse)
+                .expect("Unexpected Buffer Overflow");
+        }
+
+        // keep it spinning!
+        cortex_m::interrupt::free(|cs| {
+            interrupts::FLUX_WRITER
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .enable_write_head();
+
+            interrupts::FLOPPY_CONTROL
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .spin_motor();
+        });
+
+        cortex_m::interrupt::free(|cs| {
+            interrupts::FLUX_WRITER
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .prepare_transmit(cs);
+        });
+
+        // start transmit on index pulse
+        cortex_m::interrupt::free(|cs| {
+            START_TRANSMIT_ON_INDEX.borrow(cs).set(true);
+        });
+
+        if async_wait_for_transmit().await.is_err() {
+            rprintln!("Transmit timeout? Drive not responsing.");
+            return Err(RawTrackError::NoIndexPulse);
+        }
+
+        // continue until whole track is written.
+        for pulse in pulses {
+            assert!(self.write_prod_cell.borrow().len() > 20); // check for underflow
+
+            while self.write_prod_cell.borrow().len() > 70 {
+                cassette::yield_now().await;
+            }
+
+            self.write_prod_cell
+                .borrow_mut()
+                .enqueue(pulse)
+                .expect("Unexpected Buffer Overflow");
+        }
+
+        Ok(())
+    }
+
+    /// Measures the time in µs the drive needs after the write gate is released
+    /// until written data can be read again. The track is filled with a pattern first.
+    /// Then a short burst of another pattern is written and the time until the first pattern
+    /// is read reliably again is measured. The content of the track is destroyed.
+    pub async fn measure_write_recovery(&mut self, track: Track) -> Result<u32, RawTrackError> {
+        if !async_select_and_wait_for_track(track).await {
+            return Err(RawTrackError::SeekFailed);
+        }
+
+        let write_protected = cortex_m::interrupt::free(|cs| {
+            interrupts::FLOPPY_CONTROL
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .write_protection_is_active()
+        });
+        if write_protected {
+            return Err(RawTrackError::WriteProtected);
+        }
+
+        // Leave some space to the index to not overwrite the start of the background
+        let rotation_ticks = interrupts::rotation_ticks().unwrap_or(DEFAULT_ROTATION_TICKS);
+        let background_pulses = rotation_ticks * 95 / 100 / RECOVERY_BACKGROUND_PULSE;
+        self.write_pulses(
+            core::iter::repeat(RECOVERY_BACKGROUND_PULSE).take(background_pulses as usize),
+        )
+        .await?;
+        async_wait_for_transmit_end().await;
+
+        self.write_pulses(core::iter::repeat(RECOVERY_BURST_PULSE).take(RECOVERY_BURST_PULSES))
+            .await?;
+        async_wait_for_transmit_end().await;
+        let write_end = cortex_m::peripheral::DWT::cycle_count();
+
+        // Throw away all data in the queue before we read real data
+        while self.read_cons.dequeue().is_some() {}
+        flux_reader_start_reception();
+        let reception_delay_cycles =
+            cortex_m::peripheral::DWT::cycle_count().wrapping_sub(write_end);
+
+        let threshold = RECOVERY_BACKGROUND_PULSE as i32 * VERIFY_THRESHOLD_PERCENT / 100;
+        let mut elapsed_ticks: u32 = 0;
+        let mut clean_pulses = 0;
+        let mut clean_start_ticks = 0;
+
+        while clean_pulses < RECOVERY_CLEAN_PULSES {
+            let Some(pulse) = self.async_read_flux().await else {
+                flux_reader_stop_reception();
+                return Err(RawTrackError::NoIncomingData);
+            };
+
+            if PulseDuration(pulse)
+                .similar(&PulseDuration(RECOVERY_BACKGROUND_PULSE as i32), threshold)
+            {
+                if clean_pulses == 0 {
+                    clean_start_ticks = elapsed_ticks;
+                }
+                clean_pulses += 1;
+            } else {
+                clean_pulses = 0;
+            }
+            elapsed_ticks += pulse as u32;
+
+            // The background should have been found long before the next index
+            if elapsed_ticks > rotation_ticks / 2 {
+                flux_reader_stop_reception();
+                return Err(RawTrackError::DataNotEqual);
+            }
+        }
+        flux_reader_stop_reception();
+
+        let recovery_us = reception_delay_cycles / CYCLES_PER_MICROSECOND
+            + clean_start_ticks / TIMER_TICKS_PER_MICROSECOND;
+        rprintln!("Write recovery of {} µs", recovery_us);
+        Ok(recovery_us)
+    }
+
     pub async fn read_track(
         &mut self,
         track: Track,
         duration_to_record: u32,
         wait_for_index: bool,
+        high_resolution: bool,
         usb_handler: &mut UsbHandler<'_>,
     ) -> Result<(), RawTrackError> {
         // keep the motor spinning
@@ -327,7 +708,10 @@ impl RawTrackHandler {
 
         while self.read_cons.dequeue().is_some() {}
 
-        async_select_and_wait_for_track(track).await;
+        // Data of another cylinder would be stored silently at the wrong place
+        if !async_select_and_wait_for_track(track).await {
+            return Err(RawTrackError::SeekFailed);
+        }
 
         if wait_for_index {
             // Throw away all data in the queue before we read real data
@@ -337,7 +721,10 @@ impl RawTrackHandler {
             cortex_m::interrupt::free(|cs| {
                 START_RECEIVE_ON_INDEX.borrow(cs).set(true);
             });
-            if async_wait_for_receive().await.is_err() {
+            if handle_usb_while(usb_handler, async_wait_for_receive())
+                .await
+                .is_err()
+            {
                 return Err(RawTrackError::NoIndexPulse);
             };
         } else {
@@ -375,18 +762,26 @@ impl RawTrackHandler {
                 if let Some(pulse) = self.read_cons.dequeue() {
                     timeout = 0;
                     duration_yet_recorded += pulse;
-                    let mut reduced_pulse = pulse >> PULSE_REDUCE_SHIFT;
 
-                    if pulse & 0b100 != 0 {
-                        //round up
-                        reduced_pulse += 1;
-                    }
+                    if high_resolution {
+                        // Two bytes per pulse. As 64 is even, a pulse is never split
+                        // over two USB frames.
+                        let pulse = pulse.min(0xffff) as u16;
+                        collect_buffer.extend_from_slice(&pulse.to_le_bytes());
+                    } else {
+                        let mut reduced_pulse = pulse >> PULSE_REDUCE_SHIFT;
+
+                        if pulse & 0b100 != 0 {
+                            //round up
+                            reduced_pulse += 1;
+                        }
 
-                    if reduced_pulse > 0xff {
-                        reduced_pulse = 0xff;
-                    }
+                        if reduced_pulse > 0xff {
+                            reduced_pulse = 0xff;
+                        }
 
-                    collect_buffer.push(reduced_pulse as u8);
+                        collect_buffer.push(reduced_pulse as u8);
+                    }
 
                     if collect_buffer.len() == 64 {
                         let new_buffer: Vec<u8> = Vec::with_capacity(64);
@@ -430,18 +825,139 @@ impl RawTrackHandler {
         Ok(())
     }
 
+    /// Compares the flux timings against the data read back from disk.
+    /// Works like `verify_track` but without the need to generate the flux first.
+    async fn verify_flux_track(
+        &mut self,
+        flux_data: &[u8],
+        verify_windows: VerifyWindows,
+    ) -> Result<PulseDuration, RawTrackError> {
+        let compare_window_size = verify_windows.compare;
+        let read_data_window_size = verify_windows.read_data;
+
+        // Without a cell size we need a tolerance relative to the pulse itself.
+        // A sixth is close to the 35% of a cell used for the encoded data.
+        let similarity_treshold = |reference: PulseDuration| reference.0 / 6;
+
+        // keep the motor spinning
+        cortex_m::interrupt::free(|cs| {
+            interrupts::FLOPPY_CONTROL
+                .borrow(cs)
+                .borrow_mut()
+                .as_mut()
+                .expect("Program flow error")
+                .spin_motor();
+        });
+
+        // Throw away all data in the queue before we read real data
+        while self.read_cons.dequeue().is_some() {}
+
+        // Like with the encoded data, the first pulses are not verified.
+        // The groundtruth is not expanded in advance as this would take too much memory.
+        let groundtruth = flux_data
+            .get(verify_windows.skip_pulses..)
+            .unwrap_or_default();
+        let to_pulse = |pulse: &u8| PulseDuration(i32::from(*pulse) << PULSE_REDUCE_SHIFT);
+
+        if groundtruth.len() < compare_window_size {
+            return Err(RawTrackError::NoCrossCorrelation);
+        }
+
+        // start reception of track on next index pulse
+        cortex_m::interrupt::free(|cs| {
+            START_RECEIVE_ON_INDEX.borrow(cs).set(true);
+        });
+
+        if async_wait_for_receive().await.is_err() {
+            return Err(RawTrackError::NoIndexPulse);
+        };
+
+        let mut read_flux_data_queue: VecDeque<PulseDuration> =
+            VecDeque::with_capacity(read_data_window_size);
+        while read_flux_data_queue.len() < read_data_window_size {
+            if let Some(pulse) = self.async_read_flux().await {
+                read_flux_data_queue.push_back(PulseDuration(pulse))
+            } else {
+                rprintln!("Timeout2");
+                flux_reader_stop_reception();
+                return Err(RawTrackError::NoIncomingData);
+            };
+        }
+
+        // slide the start of the groundtruth over the read data to find the matching position
+        let match_after_pulses = cross_correlate(
+            read_flux_data_queue.iter().copied(),
+            groundtruth.iter().map(to_pulse),
+            compare_window_size,
+            verify_windows.correlation,
+            |reference, readback| reference.similar(&readback, similarity_treshold(reference)),
+        );
+
+        let Some(match_after_pulses) = match_after_pulses else {
+            rprintln!("Unable to cross correlate!");
+            flux_reader_stop_reception();
+            return Err(RawTrackError::NoCrossCorrelation);
+        };
+
+        read_flux_data_queue.drain(0..match_after_pulses);
+
+        let mut maximum_diff = 0;
+        let mut successful_compares = 0;
+        let mut histogram = VerifyHistogram::default();
+
+        for reference in groundtruth.iter().map(to_pulse) {
+            let readback = if let Some(readback) = read_flux_data_queue.pop_front() {
+                readback
+            } else if let Some(readback) = self.async_read_flux().await {
+                PulseDuration(readback)
+            } else {
+                rprintln!("Timeout3");
+                flux_reader_stop_reception();
+                return Err(RawTrackError::NoIncomingData);
+            };
+
+            if !reference.similar(&readback, similarity_treshold(reference)) {
+                flux_reader_stop_reception();
+                rprintln!(
+                    "{} != {}, successful_compares until compare fail: {}",
+                    reference.0,
+                    readback.0,
+                    successful_compares
+                );
+
+                return Err(RawTrackError::DataNotEqual);
+            }
+
+            maximum_diff = max(maximum_diff, (reference.0).abs_diff(readback.0));
+            histogram.feed(reference, readback);
+            successful_compares += 1;
+        }
+
+        flux_reader_stop_reception();
+        self.histogram = histogram;
+        rprintln!(
+            "Verified {} pulses, max error {}, match offset {}",
+            successful_compares,
+            maximum_diff,
+            match_after_pulses
+        );
+        Ok(PulseDuration(maximum_diff as i32))
+    }
+
     async fn verify_track(
         &mut self,
         track_data_to_write: RawCellData,
+        verify_windows: VerifyWindows,
+        threshold_percent: i32,
     ) -> Result<PulseDuration, (RawTrackError, RawCellData)> {
         // Size of sliding window, containing the significant data we use, trying
         // to match the data we read back against the groundtruth data we thought
         // to have written before
-        const COMPARE_WINDOW_SIZE: usize = 20;
+        let compare_window_size = verify_windows.compare;
 
-        // We record this amount of pulses to slide the COMPARE_WINDOW on
+        // We record this amount of pulses to slide the compare window on
         // to perfom cross correlation
-        const READ_DATA_WINDOW_SIZE: usize = 200;
+        let read_data_window_size = verify_windows.read_data;
 
         // keep the motor spinning
         cortex_m::interrupt::free(|cs| {
@@ -462,38 +978,46 @@ impl RawTrackHandler {
 
         // How similar should the data be against the reference?
         // The minimum similarity is half of the bit cell. But we are better than that!
-        // 35% should be ok!
-        let similarity_treshold = part.cell_size.0 * 35 / 100;
+        // 35% should be ok! The host might allow a bit more on higher cylinders.
+        let similarity_treshold = part.cell_size.0 * threshold_percent / 100;
 
         // prepare compare data around the first significant position to compare the data we read back to
         let flux_data_to_write_queue: RefCell<VecDeque<PulseDuration>> =
-            RefCell::new(VecDeque::with_capacity(COMPARE_WINDOW_SIZE * 8));
+            RefCell::new(VecDeque::with_capacity(compare_window_size * 8));
         let mut flux_data_to_write_fpg = FluxPulseGenerator::new(
             |f| flux_data_to_write_queue.borrow_mut().push_back(f),
             part.cell_size.0 as u32,
         );
 
-        if *track_data_to_write.borrow_has_non_flux_reversal_area() {
-            // It is important to have the non flux reversal generator disabled here.
-            // We will be reading an area of nothing after all!
-            flux_data_to_write_fpg.enable_non_flux_reversal_generator = false;
-        } else {
-            flux_data_to_write_fpg.enable_weak_bit_generator = true;
-        }
+        // It is important to have the non flux reversal generator disabled here.
+        // We will be reading an area of nothing after all!
+        flux_data_to_write_fpg.enable_weak_bit_generator =
+            *track_data_to_write.borrow_gap_generator() == GapGenerator::WeakBits;
 
         let mut track_data_to_write_iter = part.cells.iter();
 
+        // Searching for the longest agreement needs ground truth for the whole read data window.
+        // Agreements beyond MAX_AGREEMENT are not followed.
+        let ground_truth_size = match verify_windows.correlation {
+            Correlation::FirstMatch => compare_window_size,
+            Correlation::LongestAgreement => read_data_window_size.min(MAX_AGREEMENT),
+        };
+
+        // Takes as much of the track as available for the ground truth.
+        // Provides false if the track ends before the compare window is filled.
         let mut generate_ground_truth = || {
-            while flux_data_to_write_queue.borrow().len() < COMPARE_WINDOW_SIZE {
-                to_bit_stream(
-                    *track_data_to_write_iter.next().unwrap_or_else(|| {
-                        panic!("Not filled {}", flux_data_to_write_queue.borrow().len())
-                    }),
-                    |bit| flux_data_to_write_fpg.feed(bit),
-                )
+            while flux_data_to_write_queue.borrow().len() < ground_truth_size {
+                let Some(cells) = track_data_to_write_iter.next() else {
+                    break;
+                };
+                to_bit_stream(*cells, |bit| flux_data_to_write_fpg.feed(bit));
             }
+            flux_data_to_write_queue.borrow().len() >= compare_window_size
         };
-        generate_ground_truth();
+        if !generate_ground_truth() {
+            rprintln!("Not filled {}", flux_data_to_write_queue.borrow().len());
+            return Err((RawTrackError::TrackTooShort, track_data_to_write));
+        }
 
         // start reception of track on next index pulse
         cortex_m::interrupt::free(|cs| {
@@ -504,44 +1028,53 @@ impl RawTrackHandler {
             return Err((RawTrackError::NoIndexPulse, track_data_to_write));
         };
 
-        // remove the first 6 pulses from the groundtruth data to better
-        // allow matching. Those 6 pulses are not verified but I guess that this is ok.
-        for _ in 0..5 {
+        // remove the first pulses from the groundtruth data to better
+        // allow matching. Those pulses are not verified but I guess that this is ok.
+        // The number of skipped pulses might exceed the compare window. Keep it filled.
+        for _ in 1..verify_windows.skip_pulses {
             flux_data_to_write_queue.borrow_mut().pop_front();
+            generate_ground_truth();
         }
         let last = flux_data_to_write_queue
             .borrow_mut()
             .pop_front()
             .expect("No data to work with?");
-        let mut removed = 6;
 
         // avoid lack of entropy by removing repeated data
-        while flux_data_to_write_queue
+        let repeated = skip_repeated_pulses(last, || {
+            let pulse = flux_data_to_write_queue.borrow_mut().pop_front();
+            generate_ground_truth();
+            pulse
+        });
+        let Some((repeated, first_different)) = repeated else {
+            rprintln!("Track consists of a single repeated pulse");
+            flux_reader_stop_reception();
+            return Err((RawTrackError::UniformTrack, track_data_to_write));
+        };
+        flux_data_to_write_queue
             .borrow_mut()
-            .front()
-            .expect("Unexpected buffer underflow")
-            .0
-            == last.0
-        {
-            removed += 1;
-            flux_data_to_write_queue.borrow_mut().pop_front();
+            .push_front(first_different);
 
-            // discard incoming value.
+        // discard the incoming values of the removed pulses
+        for _ in 0..repeated {
             if self.async_read_flux().await.is_none() {
                 rprintln!("Timeout2");
                 flux_reader_stop_reception();
                 return Err((RawTrackError::NoIncomingData, track_data_to_write));
             };
-
-            generate_ground_truth();
         }
+        let removed = verify_windows.skip_pulses + repeated;
         rprintln!("Remove repeated: {}", removed);
-        generate_ground_truth();
+        if !generate_ground_truth() {
+            rprintln!("Not filled {}", flux_data_to_write_queue.borrow().len());
+            flux_reader_stop_reception();
+            return Err((RawTrackError::TrackTooShort, track_data_to_write));
+        }
         // reserve some memory for reading flux data from disk
         let mut read_mfm_flux_data_queue: VecDeque<PulseDuration> =
-            VecDeque::with_capacity(READ_DATA_WINDOW_SIZE * 2);
+            VecDeque::with_capacity(read_data_window_size * 2);
         // now record something slightly larger than the "significant window"
-        while read_mfm_flux_data_queue.len() < READ_DATA_WINDOW_SIZE {
+        while read_mfm_flux_data_queue.len() < read_data_window_size {
             if let Some(pulse) = self.async_read_flux().await {
                 read_mfm_flux_data_queue.push_back(PulseDuration(pulse))
             } else {
@@ -551,34 +1084,30 @@ impl RawTrackHandler {
             };
         }
 
-        let mut equal = false; // set to true if correlation is found
-        let mut match_after_pulses = 0;
         // now move the reference significant window over the already read data and compare it.
-        // there should be one position where it matches!
-        for read_window_index in 0..READ_DATA_WINDOW_SIZE {
-            if read_mfm_flux_data_queue.len() < COMPARE_WINDOW_SIZE {
-                rprintln!("Unable to cross correlate!");
-                flux_reader_stop_reception();
-                return Err((RawTrackError::NoCrossCorrelation, track_data_to_write));
-            }
-            equal = read_mfm_flux_data_queue
-                .range(0..COMPARE_WINDOW_SIZE)
-                .zip(flux_data_to_write_queue.borrow().iter())
-                .all(|(x, y)| y.similar(x, similarity_treshold));
-
-            if equal {
-                match_after_pulses = read_window_index;
-                break;
-            }
+        // there should be one position where it matches! Repetitive patterns might match
+        // at multiple positions. In this case only the longest agreement is reliable.
+        let match_after_pulses = cross_correlate(
+            read_mfm_flux_data_queue.iter().copied(),
+            flux_data_to_write_queue.borrow().iter().copied(),
+            compare_window_size,
+            verify_windows.correlation,
+            |reference, readback| reference.similar(&readback, similarity_treshold),
+        );
 
-            read_mfm_flux_data_queue.pop_front();
-        }
+        let Some(match_after_pulses) = match_after_pulses else {
+            rprintln!("Unable to cross correlate!");
+            flux_reader_stop_reception();
+            return Err((RawTrackError::NoCrossCorrelation, track_data_to_write));
+        };
 
-        assert!(equal); // program flow check
+        read_mfm_flux_data_queue.drain(0..match_after_pulses);
 
         // We are now synchronized and shall compare upcoming data
         let mut maximum_diff = 0;
         let mut successful_compares = 0;
+        let mut drift_detector = PhaseDriftDetector::default();
+        let mut histogram = VerifyHistogram::default();
 
         let mut generate_groundtruth = || {
             if flux_data_to_write_queue.borrow().len() < 30 {
@@ -626,9 +1155,16 @@ impl RawTrackHandler {
                     successful_compares
                 );
 
-                return Err((RawTrackError::DataNotEqual, track_data_to_write));
+                return Err((
+                    drift_detector
+                        .drift()
+                        .map_or(RawTrackError::DataNotEqual, RawTrackError::PhaseDrift),
+                    track_data_to_write,
+                ));
             } else {
                 maximum_diff = max(maximum_diff, (reference.0).abs_diff(readback.0));
+                drift_detector.feed(reference, readback);
+                histogram.feed(reference, readback);
             }
             successful_compares += 1;
         }
@@ -668,9 +1204,16 @@ impl RawTrackHandler {
                         successful_compares
                     );
 
-                    return Err((RawTrackError::DataNotEqual, track_data_to_write));
+                    return Err((
+                        drift_detector
+                            .drift()
+                            .map_or(RawTrackError::DataNotEqual, RawTrackError::PhaseDrift),
+                        track_data_to_write,
+                    ));
                 } else {
                     maximum_diff = max(maximum_diff, (reference.0).abs_diff(readback as i32));
+                    drift_detector.feed(reference, PulseDuration(readback as i32));
+                    histogram.feed(reference, PulseDuration(readback as i32));
                 }
                 successful_compares += 1;
             } else {
@@ -680,6 +1223,7 @@ impl RawTrackHandler {
         }
 
         flux_reader_stop_reception();
+        self.histogram = histogram;
         rprintln!(
             "Verified {} pulses, max error {}/{}, match offset {}",
             successful_compares,
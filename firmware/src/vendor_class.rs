@@ -11,25 +11,53 @@ const WCID_OS_STRING_DESC_INDEX: u8 = 0xEE;
 
 use core::convert::TryInto;
 
-use alloc::{collections::VecDeque, vec::Vec};
+use alloc::{collections::VecDeque, format, vec::Vec};
 use usb_device::class_prelude::UsbBus;
 use util::{
-    Cylinder, Density, DensityMap, DensityMapEntry, DriveSelectState, Head, PulseDuration,
-    RawCellData, Track,
+    capabilities::{
+        Capabilities, DENSITY_HIGH, DENSITY_SINGLE_DOUBLE, FEATURE_DETAILED_VERIFY,
+        FEATURE_DRIVE_STATUS, FEATURE_EXTENDED_DENSITY_MAP, FEATURE_FLUX_WRITE,
+        FEATURE_HEAD_POSITION, FEATURE_ROTATION, FEATURE_VERIFY_WINDOWS,
+        FEATURE_WRITE_GATE_MARGINS, FEATURE_WRITE_RECOVERY, GET_CAPABILITIES, SENSOR_DISK_CHANGE,
+        SENSOR_INDEX, SENSOR_WRITE_PROTECT,
+    },
+    CableType, Correlation, Cylinder, Density, DensityMap, DensityMapEntry, DriveSelectState,
+    GapGenerator, Head, PulseDuration, RawCellData, Track, VerifyWindows, WriteGateMargins,
+    CONFIRM_RECEPTION, CONFIRM_RECEPTION_COMMAND, DENSITY_MAP_SIZE_MASK, EXTENDED_DENSITY_MAP,
+    GAP_GENERATOR_DISABLED, LONGEST_AGREEMENT_CORRELATION, MAX_WRITE_PIPELINE_DEPTH,
+    USB_ABORT_REQUEST, WRITE_HEAP_RESERVE, WRITE_TRACK_COMMAND, WRITE_WITHOUT_VERIFY,
 };
 
-use crate::{interrupts, rprintln, INDEX_SIM};
+use crate::{interrupts, rprintln, DETAILED_VERIFY, INDEX_SIM, VERIFY_THRESHOLD_EXTRA_PERCENT};
 
 pub enum Command {
     WriteVerifyRawTrack {
         track: Track,
         raw_cell_data: RawCellData,
         write_precompensation: PulseDuration,
+        verify_windows: VerifyWindows,
+        write_only: bool,
+        write_gate_margins: WriteGateMargins,
+        /// Wait for the host to check the reception before writing
+        confirm_reception: bool,
+    },
+    WriteVerifyFluxTrack {
+        track: Track,
+        flux_data: Vec<u8>,
+        verify_windows: VerifyWindows,
+        write_only: bool,
+        write_gate_margins: WriteGateMargins,
+        confirm_reception: bool,
     },
     ReadTrack {
         track: Track,
         duration_to_record: u32,
         wait_for_index: bool,
+        high_resolution: bool,
+    },
+    Recalibrate,
+    MeasureWriteRecovery {
+        track: Track,
     },
 }
 
@@ -59,10 +87,23 @@ pub struct FloppyTracerVendorClass<'a, B: UsbBus> {
     expected_size: usize,
     cylinder: u32,
     head: u32,
-    has_non_flux_reversal_area: bool,
+    gap_generator: GapGenerator,
     write_precompensation: PulseDuration,
+    verify_windows: VerifyWindows,
+    write_only: bool,
+    write_gate_margins: WriteGateMargins,
+    is_flux_transfer: bool,
+    confirm_reception: bool,
+    /// Answer of the host to the reception of the last track. True to write it.
+    reception_confirmation: Option<bool>,
+    /// The track doesn't fit into the heap or is invalid. The transfer is received but dropped.
+    discard_transfer: bool,
+    /// The density map of the dropped track has an entry without cell size
+    invalid_density_map: bool,
     tx_buffer: VecDeque<Vec<u8>>,
-    current_command: Option<Command>,
+    /// Received commands in the order of reception. Only tracks to write are queued
+    /// behind each other. Every other command requires an empty queue.
+    pending_commands: VecDeque<Command>,
 }
 
 impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
@@ -79,15 +120,54 @@ impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
             expected_size: 0,
             cylinder: 0,
             head: 0,
-            has_non_flux_reversal_area: false,
+            gap_generator: GapGenerator::WeakBits,
             write_precompensation: PulseDuration(0),
+            verify_windows: VerifyWindows::default(),
+            write_only: false,
+            write_gate_margins: WriteGateMargins::default(),
+            is_flux_transfer: false,
+            confirm_reception: false,
+            reception_confirmation: None,
+            discard_transfer: false,
+            invalid_density_map: false,
             tx_buffer: VecDeque::new(),
-            current_command: None,
+            pending_commands: VecDeque::with_capacity(MAX_WRITE_PIPELINE_DEPTH),
         }
     }
 
     pub fn take_command(&mut self) -> Option<Command> {
-        self.current_command.take()
+        self.pending_commands.pop_front()
+    }
+
+    pub fn take_reception_confirmation(&mut self) -> Option<bool> {
+        self.reception_confirmation.take()
+    }
+
+    fn queue_command(&mut self, command: Command) {
+        let is_write = matches!(
+            command,
+            Command::WriteVerifyRawTrack { .. } | Command::WriteVerifyFluxTrack { .. }
+        );
+        let limit = if is_write {
+            MAX_WRITE_PIPELINE_DEPTH
+        } else {
+            1
+        };
+
+        // A command beyond the limit is a protocol violation of the host. It is refused.
+        if self.pending_commands.len() >= limit {
+            rprintln!("Command queue is full");
+            match command {
+                Command::WriteVerifyRawTrack { track, .. }
+                | Command::WriteVerifyFluxTrack { track, .. } => self.response(&format!(
+                    "Fail {} {} 0 0 QueueFull",
+                    track.cylinder.0, track.head.0
+                )),
+                _ => self.response("Fail QueueFull"),
+            }
+            return;
+        }
+        self.pending_commands.push_back(command);
     }
     /// Gets the maximum packet size in bytes.
     pub fn max_packet_size(&self) -> u16 {
@@ -131,36 +211,143 @@ impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
         }
     }
 
+    /// Forgets a partially received track. The next packet is a command again.
+    fn reset_reception(&mut self) {
+        self.remaining_blocks = 0;
+        self.expected_size = 0;
+        self.receive_buffer = Vec::with_capacity(64);
+        self.speeds.clear();
+        self.discard_transfer = false;
+        self.invalid_density_map = false;
+    }
+
+    /// Allocating a track which doesn't fit into the heap would crash the firmware.
+    /// Such a track is refused and the host is informed after the transfer.
+    fn reserve_receive_buffer(&mut self) {
+        self.discard_transfer = crate::ALLOCATOR.free() < self.expected_size + WRITE_HEAP_RESERVE;
+
+        if self.discard_transfer {
+            rprintln!(
+                "Track with {} bytes doesn't fit into the heap",
+                self.expected_size
+            );
+            self.speeds.clear();
+        } else {
+            self.receive_buffer.reserve(self.expected_size);
+        }
+    }
+
     fn handle_command(&mut self, buf: &[u8]) -> Option<()> {
         let mut header = buf.chunks(4);
 
         let command = u32::from_le_bytes(header.next()?.try_into().ok()?);
         match command {
-            // Write track
-            0x1234_0001 => {
+            // Write track. Older tools don't send the verify windows.
+            0x1234_0001 | WRITE_TRACK_COMMAND => {
                 self.expected_size = u32::from_le_bytes(header.next()?.try_into().ok()?) as usize;
                 self.remaining_blocks = u32::from_le_bytes(header.next()?.try_into().ok()?);
 
-                // Fields 00000000 PPPPPPPP 000000NH CCCCCCCC
+                // Fields SSSSSSSS PPPPPPPP DDDDDDNH CCCCCCCC
                 let packed_configuration = u32::from_le_bytes(header.next()?.try_into().ok()?);
 
                 self.cylinder = packed_configuration & 0xff;
                 self.head = (packed_configuration >> 8) & 1;
-                self.has_non_flux_reversal_area = (packed_configuration & 0x200) != 0;
+                let non_flux_reversal_area = (packed_configuration & 0x200) != 0;
                 self.write_precompensation =
                     PulseDuration(((packed_configuration >> 16) & 0xff) as i32);
 
+                // Fields WRRRRRRR RRRRRRRR LCCCCCCC CCCCCCCC
+                let packed_verify_windows = if command == WRITE_TRACK_COMMAND {
+                    u32::from_le_bytes(header.next()?.try_into().ok()?)
+                } else {
+                    0
+                };
+                self.write_only = packed_verify_windows & WRITE_WITHOUT_VERIFY != 0;
+                self.verify_windows = VerifyWindows {
+                    compare: (packed_verify_windows & 0x7fff) as usize,
+                    read_data: ((packed_verify_windows >> 16) & 0x7fff) as usize,
+                    skip_pulses: (packed_configuration >> 24) as usize,
+                    settle_delay_us: ((packed_configuration >> 10) & 0x3f)
+                        * VerifyWindows::SETTLE_DELAY_STEP_US,
+                    correlation: if packed_verify_windows & LONGEST_AGREEMENT_CORRELATION != 0 {
+                        Correlation::LongestAgreement
+                    } else {
+                        Correlation::FirstMatch
+                    },
+                }
+                .bounded();
+
                 let speed_table_size = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                let extended_density_map = speed_table_size & EXTENDED_DENSITY_MAP != 0;
+                self.confirm_reception = speed_table_size & CONFIRM_RECEPTION != 0;
+                self.write_gate_margins = WriteGateMargins::unpacked(speed_table_size);
+                self.gap_generator = if speed_table_size & GAP_GENERATOR_DISABLED != 0 {
+                    GapGenerator::Disabled
+                } else if non_flux_reversal_area {
+                    GapGenerator::NonFluxReversal
+                } else {
+                    GapGenerator::WeakBits
+                };
 
-                for _ in 0..speed_table_size {
+                for _ in 0..speed_table_size & DENSITY_MAP_SIZE_MASK {
                     let table_entry = u32::from_le_bytes(header.next()?.try_into().ok()?);
 
-                    self.speeds.push(DensityMapEntry {
-                        number_of_cellbytes: (table_entry >> 9) as usize,
-                        cell_size: (PulseDuration((table_entry & 0x1ff) as i32)),
-                    });
+                    let entry = if extended_density_map {
+                        DensityMapEntry::unpacked_extended(table_entry)
+                    } else {
+                        DensityMapEntry::unpacked(table_entry)
+                    };
+                    // A cell size of 0 results from an overflow on the host side
+                    self.invalid_density_map |= entry.cell_size.0 <= 0;
+                    self.speeds.push(entry);
+                }
+                self.is_flux_transfer = false;
+                if self.invalid_density_map {
+                    rprintln!("Track with a density map entry without cell size");
+                    self.discard_transfer = true;
+                    self.speeds.clear();
+                } else {
+                    self.reserve_receive_buffer();
+                }
+            }
+            // Write flux timings without encoding
+            0x1234_0005 => {
+                self.expected_size = u32::from_le_bytes(header.next()?.try_into().ok()?) as usize;
+                self.remaining_blocks = u32::from_le_bytes(header.next()?.try_into().ok()?);
+
+                // Fields SSSSSSSS 00000000 DDDDDD0H CCCCCCCC
+                let packed_configuration = u32::from_le_bytes(header.next()?.try_into().ok()?);
+
+                self.cylinder = packed_configuration & 0xff;
+                self.head = (packed_configuration >> 8) & 1;
+
+                // Fields WRRRRRRR RRRRRRRR LCCCCCCC CCCCCCCC
+                let packed_verify_windows = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                self.write_only = packed_verify_windows & WRITE_WITHOUT_VERIFY != 0;
+                self.verify_windows = VerifyWindows {
+                    compare: (packed_verify_windows & 0x7fff) as usize,
+                    read_data: ((packed_verify_windows >> 16) & 0x7fff) as usize,
+                    skip_pulses: (packed_configuration >> 24) as usize,
+                    settle_delay_us: ((packed_configuration >> 10) & 0x3f)
+                        * VerifyWindows::SETTLE_DELAY_STEP_US,
+                    correlation: if packed_verify_windows & LONGEST_AGREEMENT_CORRELATION != 0 {
+                        Correlation::LongestAgreement
+                    } else {
+                        Correlation::FirstMatch
+                    },
                 }
-                self.receive_buffer.reserve(self.expected_size);
+                .bounded();
+
+                // Older tools don't send the margins
+                let margins = header
+                    .next()
+                    .and_then(|f| f.try_into().ok())
+                    .map_or(0, u32::from_le_bytes);
+                self.write_gate_margins = WriteGateMargins::unpacked(margins);
+                self.confirm_reception = margins & CONFIRM_RECEPTION != 0;
+
+                self.is_flux_transfer = true;
+                self.reserve_receive_buffer();
             }
             // Configure drive
             0x1234_0002 => {
@@ -186,31 +373,152 @@ impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
                         .expect("Program flow error")
                         .configure(index_sim_frequency);
 
+                    DETAILED_VERIFY
+                        .borrow(cs)
+                        .set(settings & util::DETAILED_VERIFY != 0);
+                    VERIFY_THRESHOLD_EXTRA_PERCENT
+                        .borrow(cs)
+                        .set((settings >> util::VERIFY_THRESHOLD_EXTRA_SHIFT) & 0xf);
+
                     let mut floppy_control_borrow =
                         interrupts::FLOPPY_CONTROL.borrow(cs).borrow_mut();
                     let floppy_control =
                         floppy_control_borrow.as_mut().expect("Program flow error");
 
+                    floppy_control.set_cable_type(CableType::from_bits((settings >> 3) & 3));
                     floppy_control.select_drive(selected_drive);
+                    floppy_control.set_density_select_inverted(settings & 4 != 0);
+                    floppy_control.set_density_latched_at_motor_on(
+                        settings & util::DENSITY_LATCHED_AT_MOTOR_ON != 0,
+                    );
+                    floppy_control.set_head_settle_time((settings >> 8) & 0xff);
+                    floppy_control.set_step_rate((settings >> 16) & 0xff);
                     floppy_control.select_density(floppy_density);
                 });
             }
             // step to track
             0x1234_0003 => {
-                let cylinder = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                // Older tools only provide the cylinder which selects head 0
+                let parameter = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                let cylinder = parameter & 0xff;
+                let head = (parameter >> 8) & 1;
                 cortex_m::interrupt::free(|cs| {
                     let mut floppy_control_borrow =
                         interrupts::FLOPPY_CONTROL.borrow(cs).borrow_mut();
                     let floppy_control =
                         floppy_control_borrow.as_mut().expect("Program flow error");
 
-                    rprintln!("Step to track {}", cylinder);
+                    rprintln!("Step to track {} {}", cylinder, head);
                     floppy_control.select_track(Track {
                         cylinder: Cylinder(cylinder as u8),
-                        head: Head(0),
+                        head: Head(head as u8),
                     });
                 });
             }
+            // set motor off delay
+            0x1234_0006 => {
+                let motor_off_delay_ms = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                cortex_m::interrupt::free(|cs| {
+                    let mut floppy_control_borrow =
+                        interrupts::FLOPPY_CONTROL.borrow(cs).borrow_mut();
+                    let floppy_control =
+                        floppy_control_borrow.as_mut().expect("Program flow error");
+
+                    rprintln!("Motor off delay {} ms", motor_off_delay_ms);
+                    floppy_control.set_motor_off_delay(motor_off_delay_ms);
+                });
+            }
+            // query drive status. The disk change signal is only reported on request
+            // to keep the answer for older hosts.
+            0x1234_0007 => {
+                let with_disk_change = header
+                    .next()
+                    .and_then(|f| f.try_into().ok())
+                    .map(u32::from_le_bytes)
+                    == Some(1);
+                let status = cortex_m::interrupt::free(|cs| {
+                    let mut floppy_control_borrow =
+                        interrupts::FLOPPY_CONTROL.borrow(cs).borrow_mut();
+                    let floppy_control =
+                        floppy_control_borrow.as_mut().expect("Program flow error");
+
+                    floppy_control.query_drive_status()
+                });
+
+                match status {
+                    Some((write_protected, disk_present)) if with_disk_change => {
+                        self.response(&format!(
+                            "DriveStatus {} {}",
+                            u8::from(write_protected),
+                            u8::from(disk_present)
+                        ))
+                    }
+                    Some((true, _)) => self.response("DriveStatus 1"),
+                    Some((false, _)) => self.response("DriveStatus 0"),
+                    None => self.response("Fail NoDriveSelected"),
+                }
+            }
+            // query head position
+            0x1234_0010 => {
+                let position = cortex_m::interrupt::free(|cs| {
+                    let floppy_control_borrow = interrupts::FLOPPY_CONTROL.borrow(cs).borrow();
+                    let floppy_control =
+                        floppy_control_borrow.as_ref().expect("Program flow error");
+
+                    floppy_control.query_head_position()
+                });
+
+                match position {
+                    Some((Some(cylinder), failed_seeks)) => {
+                        self.response(&format!("Position {cylinder} {failed_seeks}"))
+                    }
+                    Some((None, failed_seeks)) => {
+                        self.response(&format!("Position Unknown {failed_seeks}"))
+                    }
+                    None => self.response("Fail NoDriveSelected"),
+                }
+            }
+            // query duration of the last rotation
+            0x1234_0014 => match interrupts::rotation_ticks() {
+                Some(rotation_ticks) => self.response(&format!("Rotation {rotation_ticks}")),
+                None => self.response("Rotation Unknown"),
+            },
+            // answer of the host to the size and checksum of the received track
+            CONFIRM_RECEPTION_COMMAND => {
+                let parameter = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                self.reception_confirmation = Some(parameter == 1);
+            }
+            // measure write recovery time of the drive
+            0x1234_0011 => {
+                let parameter = u32::from_le_bytes(header.next()?.try_into().ok()?);
+                let drive_selected = cortex_m::interrupt::free(|cs| {
+                    let floppy_control_borrow = interrupts::FLOPPY_CONTROL.borrow(cs).borrow();
+                    let floppy_control =
+                        floppy_control_borrow.as_ref().expect("Program flow error");
+
+                    floppy_control.selected_drive_unit_ref().is_some()
+                });
+
+                if drive_selected {
+                    let new_command = Command::MeasureWriteRecovery {
+                        track: Track {
+                            cylinder: Cylinder((parameter & 0xff) as u8),
+                            head: Head(((parameter >> 8) & 1) as u8),
+                        },
+                    };
+                    self.queue_command(new_command);
+                } else {
+                    self.response("Fail NoDriveSelected");
+                }
+            }
+            GET_CAPABILITIES => {
+                let capabilities = capabilities();
+                self.write(&capabilities.to_bytes());
+            }
+            // recalibrate to track 0
+            0x1234_000F => {
+                self.queue_command(Command::Recalibrate);
+            }
             // read track
             0x1234_0004 => {
                 let packed_configuration = u32::from_le_bytes(header.next()?.try_into().ok()?);
@@ -218,6 +526,7 @@ impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
                 let cylinder = packed_configuration & 0xff;
                 let head = (packed_configuration >> 8) & 1;
                 let wait_for_index = ((packed_configuration >> 9) & 1) != 0;
+                let high_resolution = ((packed_configuration >> 10) & 1) != 0;
                 let new_command = Command::ReadTrack {
                     track: Track {
                         cylinder: Cylinder(cylinder as u8),
@@ -225,13 +534,10 @@ impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
                     },
                     duration_to_record,
                     wait_for_index,
+                    high_resolution,
                 };
 
-                let old_command = self.current_command.replace(new_command);
-
-                // Last command shall be not existing.
-                // If it exists, it was dropped now, which is not good
-                assert!(old_command.is_none());
+                self.queue_command(new_command);
             }
             _ => {
                 rprintln!("Unknown command");
@@ -241,6 +547,32 @@ impl<B: UsbBus> FloppyTracerVendorClass<'_, B> {
     }
 }
 
+/// Everything the firmware supports. The board wires every sensor of the drive.
+fn capabilities() -> Capabilities {
+    let version = |part: &str| part.parse().unwrap_or(0);
+
+    Capabilities {
+        firmware_version: [
+            version(env!("CARGO_PKG_VERSION_MAJOR")),
+            version(env!("CARGO_PKG_VERSION_MINOR")),
+            version(env!("CARGO_PKG_VERSION_PATCH")),
+        ],
+        features: FEATURE_FLUX_WRITE
+            | FEATURE_DETAILED_VERIFY
+            | FEATURE_WRITE_RECOVERY
+            | FEATURE_HEAD_POSITION
+            | FEATURE_DRIVE_STATUS
+            | FEATURE_EXTENDED_DENSITY_MAP
+            | FEATURE_WRITE_GATE_MARGINS
+            | FEATURE_ROTATION
+            | FEATURE_VERIFY_WINDOWS,
+        max_cylinder: util::MAX_CYLINDER as u8,
+        densities: DENSITY_SINGLE_DOUBLE | DENSITY_HIGH,
+        sensors: SENSOR_INDEX | SENSOR_DISK_CHANGE | SENSOR_WRITE_PROTECT,
+        free_heap: crate::ALLOCATOR.free() as u32,
+    }
+}
+
 impl<B: UsbBus> UsbClass<B> for FloppyTracerVendorClass<'_, B> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
         writer.interface(self.data_if, USB_CLASS_VENDOR, SUBCLASS_NONE, PROTOCOL_NONE)?;
@@ -289,6 +621,19 @@ impl<B: UsbBus> UsbClass<B> for FloppyTracerVendorClass<'_, B> {
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
 
+        if req.request_type == control::RequestType::Vendor
+            && req.recipient == control::Recipient::Device
+            && req.request == USB_ABORT_REQUEST
+        {
+            rprintln!("Abort requested");
+            cortex_m::interrupt::free(|cs| interrupts::ABORT_REQUESTED.borrow(cs).set(true));
+            // Tracks waiting behind the running one belong to the aborted operation
+            self.pending_commands.clear();
+            self.reset_reception();
+            xfer.accept().expect("Unexpected USB problem");
+            return;
+        }
+
         if !(req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
             && req.index == u8::from(self.data_if) as u16)
@@ -323,10 +668,26 @@ impl<B: UsbBus> UsbClass<B> for FloppyTracerVendorClass<'_, B> {
                 self.handle_command(&buf);
             } else {
                 let buf = buf.get(0..count).expect("Cannot fail.");
-                self.receive_buffer.extend(buf.iter());
-
                 self.remaining_blocks -= 1;
 
+                if self.discard_transfer {
+                    if self.remaining_blocks == 0 {
+                        self.discard_transfer = false;
+                        let str_response = if core::mem::take(&mut self.invalid_density_map) {
+                            format!("Fail {} {} 0 0 InvalidDensityMap", self.cylinder, self.head)
+                        } else {
+                            format!(
+                                "OutOfMemory {} {} {}",
+                                self.cylinder, self.head, self.expected_size
+                            )
+                        };
+                        self.response(&str_response);
+                    }
+                    return;
+                }
+
+                self.receive_buffer.extend(buf.iter());
+
                 if self.remaining_blocks == 0 {
                     // We have received everything we need.
                     assert!(self.expected_size == self.receive_buffer.len());
@@ -338,25 +699,38 @@ impl<B: UsbBus> UsbClass<B> for FloppyTracerVendorClass<'_, B> {
                     core::mem::swap(&mut recv_buffer, &mut self.receive_buffer);
                     core::mem::swap(&mut speeds, &mut self.speeds);
 
-                    let new_command = Command::WriteVerifyRawTrack {
-                        track: Track {
-                            cylinder: Cylinder(self.cylinder as u8),
-                            head: Head(self.head as u8),
-                        },
-                        raw_cell_data: RawCellData::construct(
-                            speeds,
-                            recv_buffer,
-                            self.has_non_flux_reversal_area,
-                        )
-                        .expect("Program flow error"),
-                        write_precompensation: self.write_precompensation,
+                    let track = Track {
+                        cylinder: Cylinder(self.cylinder as u8),
+                        head: Head(self.head as u8),
                     };
 
-                    let old_command = self.current_command.replace(new_command);
+                    let new_command = if self.is_flux_transfer {
+                        Command::WriteVerifyFluxTrack {
+                            track,
+                            flux_data: recv_buffer,
+                            verify_windows: self.verify_windows,
+                            write_only: self.write_only,
+                            write_gate_margins: self.write_gate_margins,
+                            confirm_reception: self.confirm_reception,
+                        }
+                    } else {
+                        Command::WriteVerifyRawTrack {
+                            track,
+                            raw_cell_data: RawCellData::construct(
+                                speeds,
+                                recv_buffer,
+                                self.gap_generator,
+                            )
+                            .expect("Program flow error"),
+                            write_precompensation: self.write_precompensation,
+                            verify_windows: self.verify_windows,
+                            write_only: self.write_only,
+                            write_gate_margins: self.write_gate_margins,
+                            confirm_reception: self.confirm_reception,
+                        }
+                    };
 
-                    // Last command shall be not existing.
-                    // If it exists, it was dropped now, which is not good
-                    assert!(old_command.is_none());
+                    self.queue_command(new_command);
                 }
             }
         }
@@ -12,7 +12,7 @@ use debugless_unwrap::DebuglessUnwrap;
 use fltk::{
     app::{self, channel, Receiver, Sender},
     button::*,
-    dialog::alert_default,
+    dialog::{alert_default, choice2_default},
     frame::Frame,
     group::{Pack, PackType},
     image::{JpegImage, TiledImage},
@@ -26,17 +26,30 @@ use std::sync::atomic::Ordering::Relaxed;
 use std::{
     fs::File,
     io::Write,
+    path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     thread::{self, JoinHandle},
 };
 use tool::{
-    image_reader::parse_image,
+    error::ToolError,
+    image_reader::{parse_image, supported_read_formats},
+    localization::{error_message, tr, tr_with, MessageId},
     rawtrack::RawImage,
-    track_parser::{read_first_track_discover_format, TrackPayload},
-    usb_commands::{configure_device, read_raw_track, wait_for_answer, write_raw_track},
+    resume::{manifest_path, ResumeManifest},
+    track_parser::{
+        apply_drive_type, check_media_density, configured_trackfilter,
+        read_first_track_discover_format, TrackPayload,
+    },
+    usb_commands::{
+        check_reception, configure_device, is_write_protected, read_raw_track, wait_for_answer,
+        write_raw_track,
+    },
     usb_device::{clear_buffers, init_usb},
 };
-use util::{DriveSelectState, DRIVE_3_5_RPM, DRIVE_5_25_RPM};
+use util::{
+    flippy_index_frequency, DiskType, DriveSelectState, DRIVE_3_5_RPM, DRIVE_5_25_RPM,
+    MAX_FLIPPY_OFFSET_US, STM_TIMER_MHZ,
+};
 
 struct Tools {
     usb_handles: (DeviceHandle<rusb::Context>, u8, u8),
@@ -54,8 +67,12 @@ enum Message {
     Discover,
     ToolsReturned(Arc<Tools>),
     StatusMessage(String),
+    CheckWriteProtection,
 }
 
+/// Seconds between checks of the write protection while idle
+const WRITE_PROTECTION_CHECK_INTERVAL: f64 = 2.0;
+
 use fltk::enums::Event;
 type FrameEventClosure = Box<dyn FnMut(&mut Frame, Event) -> bool>;
 
@@ -188,7 +205,12 @@ struct UsbFloppyTracerWindow {
     button_stop: Button,
     radio_drive_a: RadioLightButton,
     radio_drive_b: RadioLightButton,
+    radio_inch_3_5: RadioLightButton,
+    radio_inch_5_25: RadioLightButton,
     checkbox_flippy_disk: CheckButton,
+    spinner_flippy_offset: misc::Spinner,
+    checkbox_keep_going: CheckButton,
+    checkbox_check_density: CheckButton,
     receiver: Receiver<Message>,
     sender: Sender<Message>,
     maybe_image: Option<RawImage>,
@@ -226,6 +248,11 @@ impl UsbFloppyTracerWindow {
             move |_| {
                 let mut nfc =
                     dialog::NativeFileChooser::new(dialog::NativeFileChooserType::BrowseFile);
+                let extensions: Vec<&str> = supported_read_formats()
+                    .iter()
+                    .map(|f| f.extension)
+                    .collect();
+                nfc.set_filter(&format!("Disk images\t*.{{{}}}", extensions.join(",")));
                 nfc.show();
                 let path = nfc.filename();
                 if path.exists() {
@@ -268,8 +295,43 @@ impl UsbFloppyTracerWindow {
         radio_drive_a.set(true);
         pack2.end();
 
+        // Separate group to not interfere with the drive selection
+        let pack3 = Pack::default()
+            .with_type(PackType::Horizontal)
+            .with_size(150, 30);
+
+        let mut radio_inch_3_5 = RadioLightButton::default()
+            .with_label("3.5\"")
+            .with_size(150 / 2, 30);
+        let radio_inch_5_25 = RadioLightButton::default()
+            .with_label("5.25\"")
+            .with_size(150 / 2, 30);
+        radio_inch_3_5.set(true);
+        pack3.end();
+
+        let pack4 = Pack::default()
+            .with_type(PackType::Horizontal)
+            .with_size(150, 25);
+
         let checkbox_flippy_disk = CheckButton::default()
-            .with_label("Flippy Disk")
+            .with_label("Flippy")
+            .with_size(150 / 2, 25);
+
+        // Shortens the simulated rotation to catch the next one on faster drives
+        let mut spinner_flippy_offset = misc::Spinner::default().with_size(150 / 2, 25);
+        spinner_flippy_offset.set_range(0.0, f64::from(MAX_FLIPPY_OFFSET_US));
+        spinner_flippy_offset.set_step(10.0);
+        spinner_flippy_offset.set_value(0.0);
+        spinner_flippy_offset.set_tooltip("Offset of the simulated index in µs");
+        pack4.end();
+
+        let checkbox_keep_going = CheckButton::default()
+            .with_label("Keep Going")
+            .with_size(0, 25);
+
+        // Reads the first track of the disk before writing
+        let checkbox_check_density = CheckButton::default()
+            .with_label("Check Density")
             .with_size(0, 25);
 
         pack.end();
@@ -277,7 +339,7 @@ impl UsbFloppyTracerWindow {
         let cellsize = 22;
 
         let mut loaded_image_path = Output::default().with_size(500, 30).right_of(&pack, 15);
-        loaded_image_path.set_value("No image loaded");
+        loaded_image_path.set_value(tr(MessageId::NoImageLoaded));
 
         let side_0 = Pack::new(0, 0, cellsize * 11, cellsize * 10, "Side 0")
             .right_of(&pack, 10)
@@ -304,16 +366,25 @@ impl UsbFloppyTracerWindow {
 
         frame.handle(custom_handle(&sender));
 
+        // Gray out the write button if the inserted disk is write protected
+        app::add_timeout3(WRITE_PROTECTION_CHECK_INTERVAL, {
+            let sender = sender.clone();
+            move |handle| {
+                sender.send(Message::CheckWriteProtection);
+                app::repeat_timeout3(WRITE_PROTECTION_CHECK_INTERVAL, handle);
+            }
+        });
+
         let maybe_image: Option<RawImage> = None;
         let thread_handle: Option<JoinHandle<_>> = None;
         let usb_handle = init_usb();
 
         if usb_handle.is_ok() {
-            status_text.set_value("Systems ready!");
+            status_text.set_value(tr(MessageId::SystemsReady));
         } else {
-            status_text.set_value(&format!(
-                "Failed to initialize USB device: {:?}",
-                usb_handle
+            status_text.set_value(&tr_with(
+                MessageId::UsbInitFailed,
+                &format!("{:?}", usb_handle),
             ));
         }
 
@@ -327,6 +398,8 @@ impl UsbFloppyTracerWindow {
             button_stop,
             radio_drive_a,
             radio_drive_b,
+            radio_inch_3_5,
+            radio_inch_5_25,
             receiver,
             sender,
             maybe_image,
@@ -337,6 +410,9 @@ impl UsbFloppyTracerWindow {
             tracklabels,
             loaded_image_path,
             checkbox_flippy_disk,
+            spinner_flippy_offset,
+            checkbox_keep_going,
+            checkbox_check_density,
         }
     }
 
@@ -349,6 +425,27 @@ impl UsbFloppyTracerWindow {
             .context("USB Device still not available!")
     }
 
+    /// Checks the write protection of the inserted disk while no operation is active.
+    /// Without an image, there is nothing to write and the check is skipped.
+    fn disk_is_write_protected(
+        &self,
+        selected_drive: DriveSelectState,
+        index_sim_frequency: u32,
+    ) -> anyhow::Result<bool> {
+        let (Some(usb_handle), Some(image)) = (self.usb_handle.as_ref(), self.maybe_image.as_ref())
+        else {
+            return Ok(false);
+        };
+
+        configure_device(
+            usb_handle,
+            selected_drive,
+            image.density,
+            index_sim_frequency,
+        )?;
+        is_write_protected(usb_handle)
+    }
+
     fn handle(&mut self) -> anyhow::Result<()> {
         let selected_drive = if self.radio_drive_a.is_set() {
             DriveSelectState::A
@@ -356,9 +453,15 @@ impl UsbFloppyTracerWindow {
             DriveSelectState::B
         };
 
-        // TODO better documentation here
+        let disk_type = if self.radio_inch_5_25.is_set() {
+            DiskType::Inch5_25
+        } else {
+            DiskType::Inch3_5
+        };
+
         let index_sim_frequency = if self.checkbox_flippy_disk.is_checked() {
-            (14 * 1000) * 1000
+            flippy_index_frequency(self.spinner_flippy_offset.value() as u32 * STM_TIMER_MHZ as u32)
+                .context("Flippy offset out of range")?
         } else {
             0
         };
@@ -378,6 +481,8 @@ impl UsbFloppyTracerWindow {
                 self.button_discover.activate();
                 self.radio_drive_a.activate();
                 self.radio_drive_b.activate();
+                self.radio_inch_3_5.activate();
+                self.radio_inch_5_25.activate();
 
                 self.button_stop.deactivate();
             }
@@ -391,7 +496,7 @@ impl UsbFloppyTracerWindow {
                 let taken_image = self.maybe_image.take();
                 let sender = self.sender.clone();
 
-                self.status_text.set_value("Checking...");
+                self.status_text.set_value(tr(MessageId::Checking));
 
                 self.button_write.deactivate();
                 self.button_read.deactivate();
@@ -399,6 +504,8 @@ impl UsbFloppyTracerWindow {
                 self.button_discover.deactivate();
                 self.radio_drive_a.deactivate();
                 self.radio_drive_b.deactivate();
+                self.radio_inch_3_5.deactivate();
+                self.radio_inch_5_25.deactivate();
 
                 // it might be sometimes possible during an abort, that the endpoint
                 // still contains data. Must be removed before proceeding
@@ -414,12 +521,12 @@ impl UsbFloppyTracerWindow {
                     let status_string = match result {
                         Ok((_possible_parser, possible_formats)) => {
                             if possible_formats.is_empty() {
-                                "No known format detected".into()
+                                tr(MessageId::NoKnownFormat).into()
                             } else {
-                                format!("Format is probably {:?}", possible_formats)
+                                tr_with(MessageId::ProbableFormat, &format!("{possible_formats:?}"))
                             }
                         }
-                        Err(x) => x.to_string(),
+                        Err(x) => error_message(&x),
                     };
                     sender.send(Message::StatusMessage(status_string));
 
@@ -449,6 +556,8 @@ impl UsbFloppyTracerWindow {
                 self.button_discover.deactivate();
                 self.radio_drive_a.deactivate();
                 self.radio_drive_b.deactivate();
+                self.radio_inch_3_5.deactivate();
+                self.radio_inch_5_25.deactivate();
 
                 self.atomic_stop.store(false, Relaxed);
                 let atomic_stop = self.atomic_stop.clone();
@@ -459,7 +568,7 @@ impl UsbFloppyTracerWindow {
 
                 self.tracklabels.all_black();
 
-                self.status_text.set_value("Reading...");
+                self.status_text.set_value(tr(MessageId::Reading));
 
                 self.thread_handle = Some(thread::spawn(move || {
                     let result = read_tracks_to_diskimage(
@@ -468,11 +577,12 @@ impl UsbFloppyTracerWindow {
                         sender.clone(),
                         atomic_stop,
                         index_sim_frequency,
+                        disk_type,
                     );
 
                     let status_string = match result {
-                        Ok(()) => "Disk read to image!".into(),
-                        Err(x) => x.to_string(),
+                        Ok(()) => tr(MessageId::DiskRead).into(),
+                        Err(x) => error_message(&x),
                     };
 
                     sender.send(Message::StatusMessage(status_string));
@@ -484,6 +594,26 @@ impl UsbFloppyTracerWindow {
                 }));
             }
             Some(Message::WriteToDisk) => {
+                if self.disk_is_write_protected(selected_drive, index_sim_frequency)? {
+                    self.status_text.set_value(tr(MessageId::WriteProtected));
+                    self.button_write.deactivate();
+                    return Ok(());
+                }
+
+                // Configuration mistakes are cheaper to fix before a disk is wasted
+                let image = self.maybe_image.as_ref().context("No image loaded!")?;
+                let mut warnings = image.check_drive_compatibility(disk_type);
+                if self.checkbox_flippy_disk.is_checked() {
+                    warnings.extend(image.check_flippy_compatibility());
+                }
+                if !warnings.is_empty() {
+                    let text = warnings.join("\n");
+                    println!("{text}");
+                    if choice2_default(&text, "Cancel", "Write anyway", "") != Some(1) {
+                        return Ok(());
+                    }
+                }
+
                 let taken_image = self.maybe_image.take().context("No image loaded!")?;
                 let taken_usb_handle = self.take_usb_handle()?;
 
@@ -491,12 +621,6 @@ impl UsbFloppyTracerWindow {
                 // still contains data. Must be removed before proceeding
                 clear_buffers(&taken_usb_handle);
 
-                configure_device(
-                    &taken_usb_handle,
-                    selected_drive,
-                    taken_image.density,
-                    index_sim_frequency,
-                )?;
                 let sender = self.sender.clone();
 
                 self.button_stop.activate();
@@ -507,6 +631,8 @@ impl UsbFloppyTracerWindow {
                 self.button_discover.deactivate();
                 self.radio_drive_a.deactivate();
                 self.radio_drive_b.deactivate();
+                self.radio_inch_3_5.deactivate();
+                self.radio_inch_5_25.deactivate();
 
                 self.atomic_stop.store(false, Relaxed);
                 let atomic_stop = self.atomic_stop.clone();
@@ -516,20 +642,47 @@ impl UsbFloppyTracerWindow {
                 }
 
                 self.tracklabels.black_if_existing(&taken_image);
+                let keep_going = self.checkbox_keep_going.is_checked();
+                let check_density = self.checkbox_check_density.is_checked();
+                let image_path = PathBuf::from(self.loaded_image_path.value());
 
-                self.status_text.set_value("Writing...");
+                self.status_text.set_value(tr(MessageId::Writing));
 
                 self.thread_handle = Some(thread::spawn(move || {
-                    let result = write_and_verify_image(
+                    // Writing DD data on a HD formatted disk or vice versa might cause
+                    // subtle verification problems. Warn the user before writing.
+                    if check_density
+                        && let Ok(Some(warning)) = check_media_density(
+                            &taken_usb_handle,
+                            selected_drive,
+                            index_sim_frequency,
+                            taken_image.density,
+                        )
+                    {
+                        println!("{warning}");
+                        sender.send(Message::StatusMessage(warning));
+                    }
+
+                    let result = configure_device(
                         &taken_usb_handle,
-                        &taken_image,
-                        sender.clone(),
-                        atomic_stop,
-                    );
+                        selected_drive,
+                        taken_image.density,
+                        index_sim_frequency,
+                    )
+                    .and_then(|_| {
+                        write_and_verify_image(
+                            &taken_usb_handle,
+                            &taken_image,
+                            sender.clone(),
+                            atomic_stop,
+                            keep_going,
+                            &image_path,
+                        )
+                    });
 
                     let status_string = match result {
-                        Ok(()) => "Image written!".into(),
-                        Err(x) => x.to_string(),
+                        Ok(()) => tr(MessageId::ImageWritten).into(),
+                        Err(x) => error_message(&x),
                     };
 
                     sender.send(Message::StatusMessage(status_string));
@@ -542,8 +695,8 @@ impl UsbFloppyTracerWindow {
             }
             Some(Message::LoadFile(filepath)) => match parse_image(&filepath).and_then(|x| {
                 let rpm = match x.disk_type {
-                    util::DiskType::Inch3_5 => DRIVE_3_5_RPM,
-                    util::DiskType::Inch5_25 => DRIVE_5_25_RPM,
+                    DiskType::Inch3_5 => DRIVE_3_5_RPM,
+                    DiskType::Inch5_25 => DRIVE_5_25_RPM,
                 };
 
                 for track in &x.tracks {
@@ -553,7 +706,21 @@ impl UsbFloppyTracerWindow {
                 Ok(x)
             }) {
                 Ok(i) => {
+                    // The image knows best for which drive it is intended
+                    match i.disk_type {
+                        DiskType::Inch3_5 => self.radio_inch_3_5.set(true),
+                        DiskType::Inch5_25 => self.radio_inch_5_25.set(true),
+                    }
                     self.tracklabels.black_if_existing(&i);
+                    let rpm = match i.disk_type {
+                        DiskType::Inch3_5 => DRIVE_3_5_RPM,
+                        DiskType::Inch5_25 => DRIVE_5_25_RPM,
+                    };
+                    let estimate = i.estimated_write_time(rpm).as_secs();
+                    self.status_text.set_value(&tr_with(
+                        MessageId::EstimatedWriteTime,
+                        &format!("{}:{:02} min", estimate / 60, estimate % 60),
+                    ));
                     self.maybe_image = Some(i);
                     self.loaded_image_path.set_value(&filepath);
                     self.button_write.activate();
@@ -564,6 +731,16 @@ impl UsbFloppyTracerWindow {
                     self.status_text.set_value(&s.to_string())
                 }
             },
+            Some(Message::CheckWriteProtection) => {
+                // Only possible while idle as the USB handle is taken during operations
+                if self.usb_handle.is_some() && self.maybe_image.is_some() {
+                    match self.disk_is_write_protected(selected_drive, index_sim_frequency) {
+                        Ok(true) => self.button_write.deactivate(),
+                        Ok(false) => self.button_write.activate(),
+                        Err(e) => println!("Unable to check write protection: {e}"),
+                    }
+                }
+            }
             Some(Message::FailedOnTrack { cylinder, head }) => {
                 self.tracklabels
                     .set_color(cylinder, head, Color::from_rgb(255, 0, 0));
@@ -597,6 +774,7 @@ fn read_tracks_to_diskimage(
     sender: Sender<Message>,
     atomic_stop: Arc<AtomicBool>,
     index_sim_frequency: u32,
+    disk_type: DiskType,
 ) -> Result<(), anyhow::Error> {
     let (possible_track_parser, possible_formats) =
         read_first_track_discover_format(usb_handles, select_drive, index_sim_frequency)?;
@@ -610,7 +788,9 @@ fn read_tracks_to_diskimage(
 
     println!("Resulting image will be {filepath}");
 
-    let track_filter = track_parser.default_trackfilter();
+    // The recording must cover a rotation of the selected drive type
+    apply_drive_type(track_parser.as_mut(), disk_type);
+    let track_filter = configured_trackfilter(track_parser.as_ref());
     let duration_to_record = track_parser.duration_to_record();
     configure_device(
         usb_handles,
@@ -646,13 +826,21 @@ fn read_tracks_to_diskimage(
 
             let mut possible_track: Option<TrackPayload> = None;
 
-            for _ in 0..5 {
+            for attempt in 0..5 {
                 if atomic_stop.load(Relaxed) {
                     bail!("Stopped before finishing the operation");
                 }
 
-                let raw_data =
-                    read_raw_track(usb_handles, cylinder, head, false, duration_to_record)?;
+                // Some tracks are only decodable either with or without index alignment.
+                // Alternate between both to increase the chance of success.
+                let wait_for_index = attempt % 2 == 1;
+                let raw_data = read_raw_track(
+                    usb_handles,
+                    cylinder,
+                    head,
+                    wait_for_index,
+                    duration_to_record,
+                )?;
                 let track = track_parser.parse_raw_track(&raw_data).ok();
 
                 if track.is_some() {
@@ -684,25 +872,29 @@ fn write_and_verify_image(
     image: &RawImage,
     sender: Sender<Message>,
     atomic_stop: Arc<AtomicBool>,
+    keep_going: bool,
+    image_path: &Path,
 ) -> Result<(), anyhow::Error> {
     let mut write_iterator = image.tracks.iter();
     let mut verify_iterator = image.tracks.iter();
 
     let mut expected_to_verify = verify_iterator.next();
+    let mut failed_tracks = Vec::new();
 
     let mut last_written_track = None;
+    let mut progress = ResumeManifest::new(image_path);
     loop {
         if !atomic_stop.load(Relaxed) {
             if let Some(write_track) = write_iterator.next() {
                 write_raw_track(usb_handles, write_track)?;
                 last_written_track = Some(write_track);
             } else {
-                println!("All tracks written. Wait for remaining verifications!");
+                println!("{}", tr(MessageId::WaitForVerifications));
             }
         }
 
         loop {
-            match wait_for_answer(usb_handles)? {
+            let (cylinder, head) = match wait_for_answer(usb_handles)? {
                 tool::usb_commands::UsbAnswer::WrittenAndVerified {
                     cylinder,
                     head,
@@ -712,20 +904,8 @@ fn write_and_verify_image(
                     write_precomp: _,
                 } => {
                     sender.send(Message::VerifiedTrack { cylinder, head });
-
-                    if let Some(track) = expected_to_verify {
-                        ensure!(track.cylinder == cylinder);
-                        ensure!(track.head == head);
-
-                        if let Some(last_written_track) = last_written_track && atomic_stop.load(Relaxed) && last_written_track.cylinder == track.cylinder && last_written_track.head == track.head{
-                            bail!("Stopped before finishing the operation");
-                        }
-                    }
-                    expected_to_verify = verify_iterator.next();
-                    if expected_to_verify.is_none() {
-                        println!("--- Disk Image written and verified! ---");
-                        return Ok(());
-                    }
+                    progress.mark_verified(cylinder, head);
+                    (cylinder, head)
                 }
                 tool::usb_commands::UsbAnswer::Fail {
                     cylinder,
@@ -736,19 +916,75 @@ fn write_and_verify_image(
                 } => {
                     sender.send(Message::FailedOnTrack { cylinder, head });
 
-                    bail!(
-                        "Failed writing track {} head {} - num_writes:{}, num_reads:{} error:{}",
+                    let error = ToolError::VerificationFailed {
                         cylinder,
                         head,
                         writes,
                         reads,
                         error,
-                    )
+                    };
+                    if !keep_going {
+                        bail!(error);
+                    }
+
+                    // The track stays marked as failed. Proceed with the remaining tracks.
+                    println!("{error}");
+                    failed_tracks.push((cylinder, head));
+                    (cylinder, head)
                 }
-                tool::usb_commands::UsbAnswer::GotCmd => {
+                tool::usb_commands::UsbAnswer::GotCmd { reception } => {
+                    if let Some(track) = last_written_track {
+                        check_reception(usb_handles, track, reception)?;
+                    }
                     break;
                 }
-                tool::usb_commands::UsbAnswer::WriteProtected => bail!("Disk is write protected!"),
+                tool::usb_commands::UsbAnswer::WriteProtected => bail!(ToolError::WriteProtected),
+                tool::usb_commands::UsbAnswer::OutOfMemory {
+                    cylinder,
+                    head,
+                    size,
+                } => bail!(ToolError::OutOfMemory {
+                    cylinder,
+                    head,
+                    size
+                }),
+                tool::usb_commands::UsbAnswer::VerifyHistogram(_) => continue,
+                tool::usb_commands::UsbAnswer::TrackShortened {
+                    cylinder,
+                    head,
+                    removed_bytes,
+                } => {
+                    println!(
+                        "Warning: Track {cylinder} {head} is too long for a rotation of the drive. {removed_bytes} bytes at its end were not written."
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(track) = expected_to_verify {
+                ensure!(track.cylinder == cylinder);
+                ensure!(track.head == head);
+
+                if let Some(last_written_track) = last_written_track && atomic_stop.load(Relaxed) && last_written_track.cylinder == track.cylinder && last_written_track.head == track.head{
+                    let Some((cylinder, head)) = progress.last_verified() else {
+                        bail!("Stopped before finishing the operation");
+                    };
+                    // Allows to continue the write with --resume. The abort is reported anyway.
+                    if let Err(error) = progress.write(&manifest_path(image_path)) {
+                        println!("Unable to store the progress: {error:#}");
+                    }
+                    bail!(ToolError::Aborted { cylinder, head });
+                }
+            }
+            expected_to_verify = verify_iterator.next();
+            if expected_to_verify.is_none() {
+                if !failed_tracks.is_empty() {
+                    bail!(ToolError::VerificationsFailed(failed_tracks));
+                }
+
+                println!("{}", tr(MessageId::ImageWrittenAndVerified));
+                ResumeManifest::remove(&manifest_path(image_path))?;
+                return Ok(());
             }
         }
     }
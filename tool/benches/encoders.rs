@@ -0,0 +1,98 @@
+// Benchmarks for the CPU bound hot paths during the preparation of tracks.
+// Execute with "cargo bench -p tool". Criterion reports the baseline numbers
+// and compares against the previous run automatically.
+//
+// Parsing of complete images is measured in parsers.rs.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use tool::image_reader::{image_adf, image_iso};
+use util::{
+    bitstream::{to_bit_stream, BitStreamCollector},
+    fluxpulse::FluxPulseGenerator,
+    mfm::{MfmEncoder, MfmWord},
+    PulseDuration,
+};
+
+const AMIGA_DD_TRACK_SIZE: usize = 512 * 11;
+const ISO_DD_TRACK_SIZE: usize = 512 * 9;
+
+fn random_data(size: usize) -> Vec<u8> {
+    let mut rng = SmallRng::seed_from_u64(0x42);
+    let mut buffer = vec![0; size];
+    rng.fill_bytes(&mut buffer);
+    buffer
+}
+
+fn mfm_encoder(c: &mut Criterion) {
+    let data = random_data(ISO_DD_TRACK_SIZE);
+
+    c.bench_function("MfmEncoder one track", |b| {
+        b.iter(|| {
+            let mut trackbuf: Vec<u8> = Vec::new();
+            let mut collector = BitStreamCollector::new(|f| trackbuf.push(f));
+            let mut encoder = MfmEncoder::new(|cell| collector.feed(cell));
+
+            for byte in black_box(&data) {
+                encoder.feed(MfmWord::Enc(*byte));
+            }
+            trackbuf
+        })
+    });
+}
+
+fn flux_pulse_generator(c: &mut Criterion) {
+    let data = random_data(ISO_DD_TRACK_SIZE * 2);
+
+    c.bench_function("FluxPulseGenerator one track", |b| {
+        b.iter(|| {
+            let mut accumulator = 0;
+            let mut generator = FluxPulseGenerator::new(|f: PulseDuration| accumulator += f.0, 168);
+
+            for byte in black_box(&data) {
+                to_bit_stream(*byte, |bit| generator.feed(bit));
+            }
+            generator.flush();
+            accumulator
+        })
+    });
+}
+
+fn amiga_track(c: &mut Criterion) {
+    let data = random_data(AMIGA_DD_TRACK_SIZE);
+
+    c.bench_function("Amiga generate_track", |b| {
+        b.iter(|| {
+            let mut sectors = data.chunks_exact(512);
+            image_adf::generate_track(
+                black_box(3),
+                black_box(1),
+                11,
+                0,
+                image_adf::AMIGA_SYNC_WORD,
+                &mut sectors,
+            )
+        })
+    });
+}
+
+fn iso_track(c: &mut Criterion) {
+    let data = random_data(ISO_DD_TRACK_SIZE);
+    let geometry = image_iso::IsoGeometry::new(9);
+
+    c.bench_function("ISO generate_iso_track", |b| {
+        b.iter(|| {
+            let mut sectors = data.chunks_exact(512);
+            image_iso::generate_iso_track(black_box(3), black_box(1), &geometry, &mut sectors)
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    mfm_encoder,
+    flux_pulse_generator,
+    amiga_track,
+    iso_track
+);
+criterion_main!(benches);
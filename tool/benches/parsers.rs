@@ -0,0 +1,78 @@
+// Benchmarks for the parsing of complete images.
+// Execute with "cargo bench -p tool".
+//
+// The images are created once with random sectors in the temporary directory.
+// As the operating system caches the files, reading them has only a minor share
+// in the measurement. G64 and STX images are converted from sector images.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{rngs::SmallRng, RngCore, SeedableRng};
+use std::{env, fs};
+use tool::{image_reader::parse_image, image_writer::write_image};
+
+const ADF_SIZE: usize = 80 * 2 * 11 * 512;
+const ST_SIZE: usize = 80 * 2 * 9 * 512;
+const D64_SIZE: usize = 683 * 256;
+
+fn random_data(size: usize) -> Vec<u8> {
+    let mut rng = SmallRng::seed_from_u64(0x42);
+    let mut buffer = vec![0; size];
+    rng.fill_bytes(&mut buffer);
+    buffer
+}
+
+fn temp_path(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("usbfloppytracer_bench_{name}"))
+        .to_str()
+        .unwrap()
+        .into()
+}
+
+fn random_image_file(name: &str, size: usize) -> String {
+    let path = temp_path(name);
+    fs::write(&path, random_data(size)).unwrap();
+    path
+}
+
+fn converted_image_file(name: &str, source: &str) -> String {
+    let path = temp_path(name);
+    write_image(&parse_image(source).unwrap(), &path).unwrap();
+    path
+}
+
+fn bench_parse_image(c: &mut Criterion, description: &str, path: &str) {
+    c.bench_function(description, |b| {
+        b.iter(|| parse_image(black_box(path)).unwrap())
+    });
+}
+
+fn adf_image(c: &mut Criterion) {
+    let path = random_image_file("image.adf", ADF_SIZE);
+    bench_parse_image(c, "ADF parse_image", &path);
+}
+
+fn iso_image(c: &mut Criterion) {
+    let path = random_image_file("image.st", ST_SIZE);
+    bench_parse_image(c, "ISO parse_image", &path);
+}
+
+fn d64_image(c: &mut Criterion) {
+    let path = random_image_file("image.d64", D64_SIZE);
+    bench_parse_image(c, "D64 parse_image", &path);
+}
+
+fn g64_image(c: &mut Criterion) {
+    let source = random_image_file("source.d64", D64_SIZE);
+    let path = converted_image_file("image.g64", &source);
+    bench_parse_image(c, "G64 parse_image", &path);
+}
+
+fn stx_image(c: &mut Criterion) {
+    let source = random_image_file("source.st", ST_SIZE);
+    let path = converted_image_file("image.stx", &source);
+    bench_parse_image(c, "STX parse_image", &path);
+}
+
+criterion_group!(benches, adf_image, iso_image, d64_image, g64_image, stx_image);
+criterion_main!(benches);
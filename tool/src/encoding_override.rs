@@ -0,0 +1,214 @@
+use anyhow::{bail, ensure, Context};
+use std::convert::TryFrom;
+use util::{c64_geometry::get_track_settings, Density, DensityMapEntry, Encoding, PulseDuration};
+
+use crate::{
+    image_reader::{
+        image_d64::generate_track,
+        image_iso::{generate_iso_track, IsoGeometry},
+    },
+    rawtrack::{RawImage, RawTrack},
+    track_parser::{
+        amiga::AmigaTrackParser, c64::C64TrackParser, decode_raw_track, iso::IsoTrackParser,
+        TrackParser,
+    },
+};
+
+const ISO_BYTES_PER_SECTOR: usize = 512;
+const C64_BYTES_PER_SECTOR: usize = 256;
+
+/// Forces a track to be written with a different encoding than provided by the image.
+#[derive(Clone, Copy, Debug)]
+pub struct EncodingOverride {
+    pub cylinder: u32,
+    pub head: u32,
+    pub encoding: Encoding,
+}
+
+impl EncodingOverride {
+    /// Parses a list of overrides like `2:0=mfm,3:1=gcr`
+    pub fn parse_list(param: &str) -> anyhow::Result<Vec<Self>> {
+        param
+            .split(',')
+            .map(|entry| {
+                let (track, encoding) = entry
+                    .split_once('=')
+                    .with_context(|| format!("Expected cylinder:head=encoding in {entry}"))?;
+                let (cylinder, head) = track
+                    .split_once(':')
+                    .with_context(|| format!("Expected cylinder:head in {entry}"))?;
+
+                let encoding = match encoding.to_lowercase().as_str() {
+                    "mfm" => Encoding::MFM,
+                    "gcr" => Encoding::GCR,
+                    "fm" => bail!("FM encoding is not supported"),
+                    _ => bail!("{encoding} is an unknown encoding"),
+                };
+
+                Ok(Self {
+                    cylinder: cylinder.parse()?,
+                    head: head.parse()?,
+                    encoding,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Decodes the sectors of the track and encodes them again with the requested encoding.
+/// MFM tracks are generated with an ISO layout, GCR tracks with a C64 layout.
+fn reencode_track(track: &RawTrack, encoding: Encoding) -> anyhow::Result<RawTrack> {
+    let mut track_parsers: Vec<Box<dyn TrackParser>> = vec![
+        Box::new(AmigaTrackParser::new(Density::SingleDouble)),
+        Box::new(IsoTrackParser::new(None, Density::SingleDouble)),
+        Box::new(IsoTrackParser::new(None, Density::High)),
+        Box::new(C64TrackParser::new()),
+    ];
+    let payload = decode_raw_track(track, &mut track_parsers)?.payload;
+
+    let (trackbuf, cell_size) = match encoding {
+        Encoding::MFM => {
+            ensure!(
+                payload.len() % ISO_BYTES_PER_SECTOR == 0,
+                "{} bytes can't be split into sectors of {} bytes",
+                payload.len(),
+                ISO_BYTES_PER_SECTOR
+            );
+            let sectors_per_track = payload.len() / ISO_BYTES_PER_SECTOR;
+            let geometry = IsoGeometry::new(sectors_per_track);
+            let cell_size = if sectors_per_track >= 15 { 84 } else { 168 };
+
+            let trackbuf = generate_iso_track(
+                track.cylinder,
+                track.head,
+                &geometry,
+                &mut payload.chunks_exact(ISO_BYTES_PER_SECTOR),
+            )?;
+            (trackbuf, cell_size)
+        }
+        Encoding::GCR => {
+            // C64 images use every second cylinder
+            let tracknum = u8::try_from(track.cylinder / 2 + 1)?;
+            let settings = get_track_settings(usize::from(tracknum));
+            ensure!(
+                payload.len() == usize::from(settings.sectors) * C64_BYTES_PER_SECTOR,
+                "{} bytes don't match the {} sectors of C64 track {}",
+                payload.len(),
+                settings.sectors,
+                tracknum
+            );
+
+            let (trackbuf, settings) =
+                generate_track(tracknum, &mut payload.chunks_exact(C64_BYTES_PER_SECTOR))?;
+            (trackbuf, settings.cellsize as i32)
+        }
+    };
+
+    let densitymap = vec![DensityMapEntry {
+        number_of_cellbytes: trackbuf.len(),
+        cell_size: PulseDuration(cell_size),
+    }];
+
+    Ok(RawTrack::new(
+        track.cylinder,
+        track.head,
+        trackbuf,
+        densitymap,
+        encoding,
+    ))
+}
+
+/// Applies the overrides on the matching tracks of the image.
+/// Every regenerated track must still be writable.
+pub fn apply_encoding_overrides(
+    image: &mut RawImage,
+    overrides: &[EncodingOverride],
+) -> anyhow::Result<()> {
+    for encoding_override in overrides {
+        let track = image
+            .tracks
+            .iter_mut()
+            .find(|f| f.cylinder == encoding_override.cylinder && f.head == encoding_override.head)
+            .with_context(|| {
+                format!(
+                    "Track {} {} doesn't exist",
+                    encoding_override.cylinder, encoding_override.head
+                )
+            })?;
+
+        let reencoded_track =
+            reencode_track(track, encoding_override.encoding).with_context(|| {
+                format!("Unable to reencode track {} {}", track.cylinder, track.head)
+            })?;
+        reencoded_track.check_writability()?;
+
+        println!(
+            "Track {} {} reencoded with {:?}",
+            track.cylinder, track.head, encoding_override.encoding
+        );
+        *track = reencoded_track;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_override_parse_test() {
+        let overrides = EncodingOverride::parse_list("2:0=mfm,34:1=GCR").unwrap();
+        let parsed: Vec<(u32, u32, bool)> = overrides
+            .iter()
+            .map(|f| (f.cylinder, f.head, matches!(f.encoding, Encoding::MFM)))
+            .collect();
+        assert_eq!(parsed, vec![(2, 0, true), (34, 1, false)]);
+
+        assert!(EncodingOverride::parse_list("2:0=fm").is_err());
+        assert!(EncodingOverride::parse_list("2=mfm").is_err());
+        assert!(EncodingOverride::parse_list("2:0").is_err());
+    }
+
+    #[test]
+    fn reencode_track_test() {
+        // 9 ISO sectors have the same size as the 18 sectors of a C64 track in this zone
+        let cylinder = 48;
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 253) as u8).collect();
+        let trackbuf = generate_iso_track(
+            cylinder,
+            0,
+            &IsoGeometry::new(9),
+            &mut sectors.chunks_exact(512),
+        )
+        .unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: util::DiskType::Inch5_25,
+            tracks: vec![RawTrack::new(
+                cylinder,
+                0,
+                trackbuf,
+                densitymap,
+                Encoding::MFM,
+            )],
+        };
+
+        let overrides = EncodingOverride::parse_list("48:0=gcr").unwrap();
+        apply_encoding_overrides(&mut image, &overrides).unwrap();
+
+        let track = image.tracks.first().unwrap();
+        assert!(matches!(track.encoding, Encoding::GCR));
+
+        let mut track_parsers: Vec<Box<dyn TrackParser>> = vec![Box::new(C64TrackParser::new())];
+        let payload = decode_raw_track(track, &mut track_parsers).unwrap();
+        assert_eq!(payload.payload, sectors);
+
+        let overrides = EncodingOverride::parse_list("50:0=gcr").unwrap();
+        assert!(apply_encoding_overrides(&mut image, &overrides).is_err());
+    }
+}
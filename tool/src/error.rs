@@ -0,0 +1,102 @@
+use thiserror::Error;
+
+/// Errors of the public API which a caller might want to react on.
+/// Everything else is provided as `Other`.
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("File {0} doesn't exist!")]
+    FileNotFound(String),
+
+    #[error("{0} is an unknown file extension!")]
+    UnknownFormat(String),
+
+    #[error("File {0} has no file extension. Unable to determine the format!")]
+    MissingExtension(String),
+
+    #[error("Unable to guess the geometry of a disk image with {size} bytes")]
+    GeometryMismatch { size: usize },
+
+    #[error("{format} image is {size} bytes, expected {expected}. Is the file truncated?")]
+    TruncatedImage {
+        format: &'static str,
+        size: usize,
+        expected: usize,
+    },
+
+    #[error("Unable to find USB Floppy Tracer")]
+    DeviceNotFound,
+
+    #[error("USB Floppy Tracer found but failed to open: {0}")]
+    DeviceOpenFailed(rusb::Error),
+
+    #[error("USB Problem: {0}")]
+    UsbError(#[from] rusb::Error),
+
+    #[error("With {duration} seconds, the track {cylinder} {head} will not fit into one single rotation of the disk!")]
+    TrackTooLong {
+        cylinder: u32,
+        head: u32,
+        duration: f64,
+    },
+
+    #[error("Failed writing track {cylinder} head {head} - num_writes:{writes}, num_reads:{reads} error:{error}")]
+    VerificationFailed {
+        cylinder: u32,
+        head: u32,
+        writes: u32,
+        reads: u32,
+        error: String,
+    },
+
+    #[error("Verification failed on {} tracks", .0.len())]
+    VerificationsFailed(Vec<(u32, u32)>),
+
+    #[error("The operation took longer than {seconds} seconds and was aborted")]
+    OperationTimeout { seconds: u64 },
+
+    #[error("Aborted after cylinder {cylinder} head {head}")]
+    Aborted { cylinder: u32, head: u32 },
+
+    #[error("Track {cylinder} {head} was not received correctly by the device. USB Problem?")]
+    TransferCorrupted { cylinder: u32, head: u32 },
+
+    #[error("Track {cylinder} {head} with {size} bytes doesn't fit into the memory of the device")]
+    OutOfMemory {
+        cylinder: u32,
+        head: u32,
+        size: usize,
+    },
+
+    #[error("Disk is write protected!")]
+    WriteProtected,
+
+    #[error("No disk detected!")]
+    NoDisk,
+
+    #[error("Density map of track {cylinder} {head} describes {densitymap_bytes} bytes but the track has {raw_data_bytes}. Bug in the image parser?")]
+    DensityMapMismatch {
+        cylinder: u32,
+        head: u32,
+        densitymap_bytes: usize,
+        raw_data_bytes: usize,
+    },
+
+    #[error("Track {cylinder} {head} contains no formatted data")]
+    BlankTrack { cylinder: u32, head: u32 },
+
+    #[error("Track {cylinder} {head} contains only ones and no sync mark. Probably a killer track.")]
+    KillerTrack { cylinder: u32, head: u32 },
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ToolError {
+    fn from(error: anyhow::Error) -> Self {
+        // Internally we work with anyhow. Restore the original error if possible.
+        match error.downcast::<ToolError>() {
+            Ok(tool_error) => tool_error,
+            Err(error) => ToolError::Other(error),
+        }
+    }
+}
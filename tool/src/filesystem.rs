@@ -0,0 +1,493 @@
+use std::{convert::TryInto, ops::Range, time::SystemTime};
+
+use anyhow::{ensure, Context};
+
+/// Filesystems which can be analyzed for files affected by unrecovered sectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileSystem {
+    /// FAT of MS-DOS and Atari ST floppy disks
+    Fat12,
+}
+
+impl FileSystem {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fat12" => Some(Self::Fat12),
+            _ => None,
+        }
+    }
+
+    /// Lists the structures and files of the filesystem which overlap
+    /// with one of the unrecovered byte ranges of the image
+    pub fn affected_files(
+        self,
+        image: &[u8],
+        unrecovered: &[Range<usize>],
+    ) -> anyhow::Result<Vec<String>> {
+        match self {
+            Self::Fat12 => Fat12::new(image)?.affected_files(unrecovered),
+        }
+    }
+}
+
+/// Layouts of blank MS-DOS disks which can be created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DosFormat {
+    /// 3.5" double density disk with 9 sectors per track
+    Dos720,
+    /// 3.5" high density disk with 18 sectors per track
+    Dos1440,
+}
+
+/// Jumps over the BIOS parameter block to the boot code
+const BOOT_JUMP: [u8; 3] = [0xeb, 0x3c, 0x90];
+const BOOT_CODE_OFFSET: usize = 0x3e;
+/// Prints the message following it, waits for a key and boots again
+const BOOT_CODE: [u8; 24] = [
+    0x31, 0xc0, // xor ax, ax
+    0x8e, 0xd8, // mov ds, ax
+    0xbe, 0x56, 0x7c, // mov si, message
+    0xac, // lodsb
+    0x08, 0xc0, // or al, al
+    0x74, 0x06, // jz wait
+    0xb4, 0x0e, // mov ah, 0x0e
+    0xcd, 0x10, // int 0x10
+    0xeb, 0xf5, // jmp lodsb
+    0x30, 0xe4, // wait: xor ah, ah
+    0xcd, 0x16, // int 0x16
+    0xcd, 0x19, // int 0x19
+];
+const BOOT_MESSAGE: &[u8] = b"Non-system disk\r\nPress any key to reboot\r\n\0";
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Characters which are not allowed in a volume label
+const INVALID_LABEL_CHARACTERS: &str = "*?./\\|,;:+=<>[]\"";
+
+impl DosFormat {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dos720" => Some(Self::Dos720),
+            "dos1440" => Some(Self::Dos1440),
+            _ => None,
+        }
+    }
+
+    /// Total sectors, sectors per cluster, root entries, media descriptor,
+    /// sectors per FAT and sectors per track
+    const fn parameters(self) -> (u16, u8, u16, u8, u16, u16) {
+        match self {
+            Self::Dos720 => (1440, 2, 112, 0xf9, 3, 9),
+            Self::Dos1440 => (2880, 1, 224, 0xf0, 9, 18),
+        }
+    }
+
+    /// Creates the sectors of an empty FAT12 filesystem.
+    /// A bootable disk tells the user that it contains no operating system.
+    /// Otherwise it has no boot signature and the BIOS doesn't boot from it.
+    pub fn create_image(self, bootable: bool, label: Option<&str>) -> anyhow::Result<Vec<u8>> {
+        const BYTES_PER_SECTOR: usize = 512;
+        const RESERVED_SECTORS: usize = 1;
+        const NUMBER_OF_FATS: usize = 2;
+
+        let (sectors, sectors_per_cluster, root_entries, media, sectors_per_fat, sectors_per_track) =
+            self.parameters();
+
+        let label = match label {
+            Some(label) => {
+                let label = label.to_ascii_uppercase();
+                ensure!(
+                    label.len() <= 11
+                        && label.chars().all(|f| {
+                            f == ' '
+                                || (f.is_ascii_graphic() && !INVALID_LABEL_CHARACTERS.contains(f))
+                        }),
+                    "Volume label {label} must be up to 11 characters without special characters"
+                );
+                Some(format!("{label:11}"))
+            }
+            None => None,
+        };
+        let serial = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |f| f.as_secs() as u32);
+
+        let mut image = vec![0; usize::from(sectors) * BYTES_PER_SECTOR];
+        let mut write = |offset: usize, bytes: &[u8]| -> anyhow::Result<()> {
+            image
+                .get_mut(offset..offset + bytes.len())
+                .context(index_out_of_bounds!())?
+                .copy_from_slice(bytes);
+            Ok(())
+        };
+
+        write(0, &BOOT_JUMP)?;
+        write(3, b"USBFT1.0")?;
+        write(11, &(BYTES_PER_SECTOR as u16).to_le_bytes())?;
+        write(13, &[sectors_per_cluster])?;
+        write(14, &(RESERVED_SECTORS as u16).to_le_bytes())?;
+        write(16, &[NUMBER_OF_FATS as u8])?;
+        write(17, &root_entries.to_le_bytes())?;
+        write(19, &sectors.to_le_bytes())?;
+        write(21, &[media])?;
+        write(22, &sectors_per_fat.to_le_bytes())?;
+        write(24, &sectors_per_track.to_le_bytes())?;
+        write(26, &2u16.to_le_bytes())?;
+        // Extended boot record
+        write(38, &[0x29])?;
+        write(39, &serial.to_le_bytes())?;
+        write(43, label.as_deref().unwrap_or("NO NAME    ").as_bytes())?;
+        write(54, b"FAT12   ")?;
+
+        if bootable {
+            write(BOOT_CODE_OFFSET, &BOOT_CODE)?;
+            write(BOOT_CODE_OFFSET + BOOT_CODE.len(), BOOT_MESSAGE)?;
+            write(BYTES_PER_SECTOR - 2, &BOOT_SIGNATURE)?;
+        }
+
+        // The first two entries of every FAT are reserved
+        for fat in 0..NUMBER_OF_FATS {
+            let fat_start =
+                (RESERVED_SECTORS + fat * usize::from(sectors_per_fat)) * BYTES_PER_SECTOR;
+            write(fat_start, &[media, 0xff, 0xff])?;
+        }
+
+        if let Some(label) = label {
+            let root_start = (RESERVED_SECTORS + NUMBER_OF_FATS * usize::from(sectors_per_fat))
+                * BYTES_PER_SECTOR;
+            write(root_start, label.as_bytes())?;
+            write(root_start + 11, &[ATTRIBUTE_VOLUME_LABEL])?;
+        }
+
+        Ok(image)
+    }
+}
+
+/// Entries of the FAT at or above this value end a cluster chain
+const FAT12_END_OF_CHAIN: u16 = 0xff8;
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+const ATTRIBUTE_VOLUME_LABEL: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+/// Long file names are stored in entries with all of these attributes
+const ATTRIBUTE_LONG_NAME: u8 = 0x0f;
+const DELETED_ENTRY: u8 = 0xe5;
+
+struct Fat12<'a> {
+    image: &'a [u8],
+    bytes_per_sector: usize,
+    sectors_per_cluster: usize,
+    fat_start: usize,
+    root_start: usize,
+    root_entries: usize,
+    data_start: usize,
+}
+
+impl<'a> Fat12<'a> {
+    /// Interprets the BIOS parameter block in the boot sector
+    fn new(image: &'a [u8]) -> anyhow::Result<Self> {
+        let byte = |offset: usize| {
+            image
+                .get(offset)
+                .map(|f| usize::from(*f))
+                .context(index_out_of_bounds!())
+        };
+        let word = |offset: usize| -> anyhow::Result<usize> {
+            Ok(byte(offset)? | (byte(offset + 1)? << 8))
+        };
+
+        let bytes_per_sector = word(11)?;
+        let sectors_per_cluster = byte(13)?;
+        let reserved_sectors = word(14)?;
+        let number_of_fats = byte(16)?;
+        let root_entries = word(17)?;
+        let sectors_per_fat = word(22)?;
+
+        ensure!(
+            matches!(bytes_per_sector, 128 | 256 | 512 | 1024),
+            "Boot sector has no valid FAT12 parameters"
+        );
+        ensure!(
+            sectors_per_cluster > 0 && number_of_fats > 0 && sectors_per_fat > 0,
+            "Boot sector has no valid FAT12 parameters"
+        );
+
+        let fat_start = reserved_sectors * bytes_per_sector;
+        let root_start = fat_start + number_of_fats * sectors_per_fat * bytes_per_sector;
+        let root_size = (root_entries * DIRECTORY_ENTRY_SIZE).div_ceil(bytes_per_sector);
+
+        Ok(Self {
+            image,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start,
+            root_start,
+            root_entries,
+            data_start: root_start + root_size * bytes_per_sector,
+        })
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    fn cluster_range(&self, cluster: u16) -> Range<usize> {
+        let start = self.data_start + (usize::from(cluster) - 2) * self.cluster_size();
+        start..start + self.cluster_size()
+    }
+
+    fn fat_entry(&self, cluster: u16) -> anyhow::Result<u16> {
+        // Two entries share three bytes
+        let offset = self.fat_start + usize::from(cluster) * 3 / 2;
+        let bytes = self
+            .image
+            .get(offset..offset + 2)
+            .context(index_out_of_bounds!())?;
+        let value = u16::from_le_bytes(bytes.try_into()?);
+
+        Ok(if cluster & 1 == 0 {
+            value & 0xfff
+        } else {
+            value >> 4
+        })
+    }
+
+    /// Follows the chain of clusters. Loops in a damaged FAT end the chain.
+    fn cluster_chain(&self, first_cluster: u16) -> anyhow::Result<Vec<u16>> {
+        let max_clusters = self.image.len() / self.cluster_size();
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+
+        while (2..FAT12_END_OF_CHAIN).contains(&cluster) && chain.len() < max_clusters {
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+
+        Ok(chain)
+    }
+
+    /// Provides the name, attributes and first cluster of every used entry
+    fn directory_entries(&self, directory: &[u8]) -> Vec<(String, u8, u16)> {
+        directory
+            .chunks_exact(DIRECTORY_ENTRY_SIZE)
+            .take_while(|entry| entry.first() != Some(&0))
+            .filter_map(|entry| {
+                let (name, rest) = entry.split_at(8);
+                let (extension, rest) = rest.split_at(3);
+                let attributes = *rest.first()?;
+                let first_cluster = u16::from_le_bytes(rest.get(15..17)?.try_into().ok()?);
+
+                if name.first() == Some(&DELETED_ENTRY)
+                    || attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME
+                    || attributes & ATTRIBUTE_VOLUME_LABEL != 0
+                {
+                    return None;
+                }
+
+                let name = String::from_utf8_lossy(name).trim_end().to_string();
+                let extension = String::from_utf8_lossy(extension).trim_end().to_string();
+                if name == "." || name == ".." {
+                    return None;
+                }
+
+                let name = if extension.is_empty() {
+                    name
+                } else {
+                    format!("{name}.{extension}")
+                };
+                Some((name, attributes, first_cluster))
+            })
+            .collect()
+    }
+
+    /// Collects the path and clusters of every file and directory below the given one
+    fn collect_files(
+        &self,
+        directory: &[u8],
+        path: &str,
+        files: &mut Vec<(String, Vec<u16>)>,
+    ) -> anyhow::Result<()> {
+        for (name, attributes, first_cluster) in self.directory_entries(directory) {
+            let path = format!("{path}/{name}");
+            let chain = self.cluster_chain(first_cluster)?;
+
+            // Directories which were already visited are ignored to avoid endless recursion
+            if attributes & ATTRIBUTE_DIRECTORY != 0
+                && !files.iter().any(|f| f.1.first() == chain.first())
+            {
+                let subdirectory: Vec<u8> = chain
+                    .iter()
+                    .filter_map(|cluster| self.image.get(self.cluster_range(*cluster)))
+                    .flatten()
+                    .copied()
+                    .collect();
+                files.push((path.clone(), chain));
+                self.collect_files(&subdirectory, &path, files)?;
+            } else {
+                files.push((path, chain));
+            }
+        }
+        Ok(())
+    }
+
+    fn affected_files(&self, unrecovered: &[Range<usize>]) -> anyhow::Result<Vec<String>> {
+        let overlaps = |area: &Range<usize>| {
+            unrecovered
+                .iter()
+                .any(|f| f.start < area.end && area.start < f.end)
+        };
+
+        let root_range = self.root_start..self.data_start;
+        let mut affected = Vec::new();
+        for (name, area) in [
+            ("Boot sector", 0..self.fat_start),
+            ("File allocation table", self.fat_start..self.root_start),
+            ("Root directory", root_range.clone()),
+        ] {
+            if overlaps(&area) {
+                affected.push(name.to_string());
+            }
+        }
+
+        let root_directory = self
+            .image
+            .get(root_range)
+            .context("Image ends before the data area")?;
+        let root_directory = root_directory
+            .get(..self.root_entries * DIRECTORY_ENTRY_SIZE)
+            .unwrap_or(root_directory);
+
+        let mut files = Vec::new();
+        self.collect_files(root_directory, "", &mut files)?;
+
+        affected.extend(
+            files
+                .into_iter()
+                .filter(|(_, chain)| {
+                    chain
+                        .iter()
+                        .any(|cluster| overlaps(&self.cluster_range(*cluster)))
+                })
+                .map(|(path, _)| path),
+        );
+
+        Ok(affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image
+            .get_mut(offset..offset + bytes.len())
+            .unwrap()
+            .copy_from_slice(bytes);
+    }
+
+    fn entry(name: &[u8; 11], attributes: u8, cluster: u8) -> [u8; 32] {
+        let mut entry = [0; 32];
+        write(&mut entry, 0, name);
+        write(&mut entry, 11, &[attributes]);
+        write(&mut entry, 26, &[cluster]);
+        entry
+    }
+
+    /// 720K disk with FILE.TXT in clusters 2 and 3
+    /// and DIR in cluster 4 containing INNER.BIN in cluster 5.
+    fn fat12_image() -> Vec<u8> {
+        let mut image = vec![0; 1440 * 512];
+
+        // 512 bytes per sector, 2 sectors per cluster, 1 reserved sector,
+        // 2 FATs, 112 root entries, 1440 sectors, 3 sectors per FAT
+        write(
+            &mut image,
+            11,
+            &[0x00, 0x02, 2, 1, 0, 2, 112, 0, 0xa0, 0x05, 0xf9, 3, 0],
+        );
+
+        // Entries 0 to 5: media, reserved, 2->3, 3->end, 4->end, 5->end
+        write(
+            &mut image,
+            512,
+            &[0xf9, 0xff, 0xff, 0x03, 0xf0, 0xff, 0xff, 0xff, 0xff],
+        );
+
+        // Root directory after the boot sector and both FATs
+        let root = 7 * 512;
+        write(&mut image, root, &entry(b"FILE    TXT", 0x20, 2));
+        write(
+            &mut image,
+            root + 32,
+            &entry(b"DIR        ", ATTRIBUTE_DIRECTORY, 4),
+        );
+        let mut deleted = entry(b"GONE    TXT", 0x20, 2);
+        write(&mut deleted, 0, &[DELETED_ENTRY]);
+        write(&mut image, root + 64, &deleted);
+
+        // Data area starts after 7 sectors of root directory
+        let cluster_4 = (14 + 2 * 2) * 512;
+        write(
+            &mut image,
+            cluster_4,
+            &entry(b".          ", ATTRIBUTE_DIRECTORY, 4),
+        );
+        write(&mut image, cluster_4 + 32, &entry(b"INNER   BIN", 0x20, 5));
+        image
+    }
+
+    #[test]
+    fn fat12_affected_files_test() {
+        let image = fat12_image();
+        let sector = |number: usize| number * 512..(number + 1) * 512;
+        let affected =
+            |ranges: &[Range<usize>]| FileSystem::Fat12.affected_files(&image, ranges).unwrap();
+
+        assert!(affected(&[]).is_empty());
+        // Second sector of the second cluster of FILE.TXT
+        assert_eq!(affected(&[sector(17)]), vec!["/FILE.TXT"]);
+        assert_eq!(affected(&[sector(20)]), vec!["/DIR/INNER.BIN"]);
+        // Damaged directories might hide further files
+        assert_eq!(affected(&[sector(18)]), vec!["/DIR"]);
+        assert_eq!(
+            affected(&[sector(1), sector(10)]),
+            vec!["File allocation table", "Root directory"]
+        );
+
+        // Not a FAT12 filesystem
+        assert!(FileSystem::Fat12.affected_files(&[0; 1024], &[]).is_err());
+    }
+
+    #[test]
+    fn create_blank_disk_test() {
+        let image = DosFormat::Dos720
+            .create_image(true, Some("mydisk"))
+            .unwrap();
+        assert_eq!(image.len(), 1440 * 512);
+        // Same parameters as the handcrafted 720K disk
+        assert_eq!(image.get(11..24), fat12_image().get(11..24));
+        assert_eq!(image.get(43..54), Some(b"MYDISK     ".as_slice()));
+        assert_eq!(image.get(510..512), Some(BOOT_SIGNATURE.as_slice()));
+
+        let root = 7 * 512;
+        assert_eq!(image.get(root..root + 11), Some(b"MYDISK     ".as_slice()));
+        // The volume label is no file
+        let sector = |number: usize| number * 512..(number + 1) * 512;
+        assert_eq!(
+            FileSystem::Fat12
+                .affected_files(&image, &[sector(1), sector(7), sector(14)])
+                .unwrap(),
+            vec!["File allocation table", "Root directory"]
+        );
+
+        let image = DosFormat::Dos1440.create_image(false, None).unwrap();
+        assert_eq!(image.len(), 2880 * 512);
+        assert_eq!(image.get(510..512), Some([0, 0].as_slice()));
+        assert_eq!(image.get(43..54), Some(b"NO NAME    ".as_slice()));
+
+        assert!(DosFormat::Dos720
+            .create_image(false, Some("TOO LONG NAME"))
+            .is_err());
+        assert!(DosFormat::Dos720.create_image(false, Some("A*B")).is_err());
+    }
+}
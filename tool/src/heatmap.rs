@@ -0,0 +1,177 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use anyhow::Context;
+use util::{verify_threshold_percent, Density};
+
+use crate::{
+    metrics::{TrackResult, WriteMetrics},
+    rawtrack::RawImage,
+};
+
+/// Size of a track in pixels
+const TRACK_WIDTH: u32 = 6;
+const TRACK_HEIGHT: u32 = 24;
+const HEADS: u32 = 2;
+
+const COLOR_NOT_WRITTEN: [u8; 3] = [40, 40, 40];
+const COLOR_UNVERIFIED: [u8; 3] = [160, 160, 160];
+const COLOR_FAILED: [u8; 3] = [255, 0, 255];
+
+/// RGB image with a track per rectangle. Cylinders are arranged from left to right,
+/// head 0 is above head 1.
+pub struct Heatmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Green for a perfect track and red for a track at the limit of the verification
+fn quality_color(max_err: u32, threshold: u32) -> [u8; 3] {
+    let ratio = (f64::from(max_err) / f64::from(threshold.max(1))).min(1.0);
+    let red = (ratio * 2.0).min(1.0);
+    let green = ((1.0 - ratio) * 2.0).min(1.0);
+    [(red * 255.0) as u8, (green * 255.0) as u8, 0]
+}
+
+impl Heatmap {
+    /// Colors every track by its `max_err` relative to the deviation which
+    /// the firmware accepts for the cell size and cylinder of the track. Tracks without
+    /// a density map, e.g. flux tracks, use the cell size of the image density.
+    /// `extra_percent` is the additional verify tolerance the firmware was configured with.
+    #[must_use]
+    pub fn render(metrics: &WriteMetrics, image: &RawImage, extra_percent: u32) -> Self {
+        let default_cell_size = match image.density {
+            Density::High => 84,
+            Density::SingleDouble => 168,
+        };
+        let cylinders = image
+            .tracks
+            .iter()
+            .map(|f| f.cylinder + 1)
+            .chain(metrics.track_results().keys().map(|f| f.0 + 1))
+            .max()
+            .unwrap_or(0);
+
+        let width = cylinders * TRACK_WIDTH;
+        let height = HEADS * TRACK_HEIGHT;
+        let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+
+        for y in 0..height {
+            let head = y / TRACK_HEIGHT;
+            for x in 0..width {
+                let cylinder = x / TRACK_WIDTH;
+                let threshold = image
+                    .tracks
+                    .iter()
+                    .find(|f| f.cylinder == cylinder && f.head == head)
+                    .and_then(|f| f.densitymap.first())
+                    .map_or(default_cell_size, |f| f.cell_size.0)
+                    * verify_threshold_percent(cylinder, extra_percent)
+                    / 100;
+
+                let color = match metrics.track_results().get(&(cylinder, head)) {
+                    Some(TrackResult::Verified { max_err }) => {
+                        quality_color(*max_err, threshold.unsigned_abs())
+                    }
+                    Some(TrackResult::Unverified) => COLOR_UNVERIFIED,
+                    Some(TrackResult::Failed) => COLOR_FAILED,
+                    None => COLOR_NOT_WRITTEN,
+                };
+                pixels.extend_from_slice(&color);
+            }
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn write_png(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path).with_context(|| format!("Unable to create {path:?}"))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use util::{DensityMapEntry, DiskType, PulseDuration};
+
+    use super::*;
+    use crate::{rawtrack::RawTrack, usb_commands::UsbAnswer};
+
+    #[test]
+    fn heatmap_test() {
+        let track = |cylinder, head| {
+            RawTrack::new(
+                cylinder,
+                head,
+                vec![0xaa; 100],
+                vec![DensityMapEntry {
+                    number_of_cellbytes: 100,
+                    cell_size: PulseDuration(168),
+                }],
+                util::Encoding::MFM,
+            )
+        };
+        let image = RawImage {
+            density: util::Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![track(0, 0), track(0, 1), track(1, 0), track(1, 1)],
+        };
+
+        let mut metrics = WriteMetrics::default();
+        let verified = |cylinder, head, max_err| UsbAnswer::WrittenAndVerified {
+            cylinder,
+            head,
+            writes: 1,
+            reads: 1,
+            max_err,
+            write_precomp: 0,
+        };
+        metrics.record(&verified(0, 0, 0));
+        metrics.record(&verified(0, 1, 58));
+        metrics.record(&UsbAnswer::Fail {
+            cylinder: 1,
+            head: 0,
+            writes: 5,
+            reads: 10,
+            error: "Verification failed".into(),
+        });
+
+        let heatmap = Heatmap::render(&metrics, &image, 0);
+        assert_eq!(heatmap.width, 2 * TRACK_WIDTH);
+        assert_eq!(heatmap.height, 2 * TRACK_HEIGHT);
+
+        let pixel = |cylinder: u32, head: u32| {
+            let offset =
+                ((head * TRACK_HEIGHT * heatmap.width + cylinder * TRACK_WIDTH) * 3) as usize;
+            heatmap.pixels.get(offset..offset + 3).unwrap().to_vec()
+        };
+        assert_eq!(pixel(0, 0), vec![0, 255, 0]);
+        assert_eq!(pixel(0, 1), vec![255, 0, 0]);
+        assert_eq!(pixel(1, 0), COLOR_FAILED.to_vec());
+        assert_eq!(pixel(1, 1), COLOR_NOT_WRITTEN.to_vec());
+
+        // The additional tolerance of inner cylinders is accepted by the firmware
+        let image = RawImage {
+            density: util::Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![track(79, 0)],
+        };
+        let mut metrics = WriteMetrics::default();
+        metrics.record(&verified(79, 0, 58));
+        let offset = (79 * TRACK_WIDTH * 3) as usize;
+        let pixel = |heatmap: &Heatmap| heatmap.pixels.get(offset..offset + 3).unwrap().to_vec();
+        let strict = Heatmap::render(&metrics, &image, 0);
+        let tolerant = Heatmap::render(&metrics, &image, 10);
+        assert_eq!(pixel(&strict), vec![255, 0, 0]);
+        assert_eq!(pixel(&tolerant), vec![255, 115, 0]);
+    }
+}
@@ -1,30 +1,82 @@
+use crate::image_reader::truncated_image_error;
 use crate::rawtrack::RawImage;
 use crate::rawtrack::RawTrack;
-use anyhow::ensure;
 use anyhow::Context;
+use anyhow::{bail, ensure};
 use std::convert::TryInto;
 use std::fs::{self, File};
 use std::io::Read;
 use std::slice::ChunksExact;
 use util::bitstream::BitStreamCollector;
 use util::mfm::MfmEncoder;
-use util::mfm::MfmWord;
-use util::{Bit, DensityMapEntry, PulseDuration};
+use util::{Bit, Density, DensityMapEntry, PulseDuration, DRIVE_3_5_RPM};
 
 // info from http://lclevy.free.fr/adflib/adf_info.html
 
 const AMIGA_MFM_MASK: u32 = 0x5555_5555;
-const SECTORS_PER_TRACK: u32 = 11;
+const SECTORS_PER_DD_TRACK: u32 = 11;
+/// High density disks rotate with the same speed but with twice the data rate
+const SECTORS_PER_HD_TRACK: u32 = 22;
 
 const CYLINDERS: u32 = 80;
+/// Over-formatted disks use up to 4 additional cylinders
+const MAX_CYLINDERS: u32 = util::MAX_CYLINDER + 1;
 const HEADS: u32 = 2;
 const BYTES_PER_SECTOR: u32 = 512;
+/// Size of a sector after MFM encoding including sync and header
+const MFM_BYTES_PER_SECTOR: usize = 1088;
+
+/// Cells of MFM encoded 0x4E like the gaps of ISO tracks. Unlike MFM encoded zeros the
+/// pulses have different lengths which is required to verify the track. It contains no sync word.
+const MFM_BLANK_PATTERN: [u8; 2] = [0x92, 0x54];
+
+const ROOT_BLOCK_TYPE: u32 = 2; // T_HEADER
+const ROOT_BLOCK_SECONDARY_TYPE: u32 = 1; // ST_ROOT
+const ROOT_BLOCK_BITMAP_VALID: u32 = 0xffff_ffff;
+const ROOT_BLOCK_BITMAP_PAGES: usize = 25;
+/// The bitmap starts after the boot block
+const BITMAP_FIRST_BLOCK: usize = 2;
+/// Every bitmap block starts with a checksum followed by the bits
+const BITMAP_BITS_PER_BLOCK: usize = 127 * 32;
+
+/// Sync word of AmigaDOS. Protected disks often use a custom one.
+pub const AMIGA_SYNC_WORD: u16 = 0x4489;
+
+/// Ensures that `sync_word` can be written and can't be confused with regular data.
+/// It must be valid MFM, also when written twice, but must have a missing clock bit
+/// in both possible alignments.
+pub fn validate_sync_word(sync_word: u16) -> anyhow::Result<()> {
+    let doubled = (u32::from(sync_word) << 16) | u32::from(sync_word);
+    ensure!(
+        doubled & (doubled >> 1) == 0,
+        "Sync word {sync_word:#06x} has adjacent flux reversals"
+    );
+    ensure!(
+        (0..29).all(|shift| (doubled >> shift) & 0b1111 != 0),
+        "Sync word {sync_word:#06x} has more than 3 cells without a flux reversal"
+    );
+
+    let cell = |index: u32| (sync_word >> (15 - index)) & 1 == 1;
+    // A clock cell is set if both surrounding data cells are cleared
+    let has_missing_clock = |first_clock: u32| {
+        (first_clock..15)
+            .step_by(2)
+            .any(|clock| cell(clock) != (!cell(clock - 1) && !cell(clock + 1)))
+    };
+    ensure!(
+        has_missing_clock(2) && has_missing_clock(1),
+        "Sync word {sync_word:#06x} can also occur in regular MFM data"
+    );
+    Ok(())
+}
 
 fn generate_sector<T>(
     cylinder: u32,
     head: u32,
     sector: u32,
+    sectors_per_track: u32,
     sectordata: &[u8],
+    sync_word: u16,
     encoder: &mut MfmEncoder<T>,
 ) -> anyhow::Result<()>
 where
@@ -34,9 +86,9 @@ where
     encoder.feed_encoded8(0);
     encoder.feed_encoded8(0);
 
-    // 2x Sync Word 0x4489 4489
-    encoder.feed(MfmWord::SyncWord);
-    encoder.feed(MfmWord::SyncWord);
+    // 2x Sync Word, 0x4489 4489 for AmigaDOS
+    encoder.feed_raw16(sync_word);
+    encoder.feed_raw16(sync_word);
 
     /*
      * decoded long is : 0xFF TT SS SG
@@ -52,7 +104,7 @@ where
         | (cylinder << 17)
         | (head << 16)
         | (sector << 8)
-        | (SECTORS_PER_TRACK - sector);
+        | (sectors_per_track - sector);
 
     encoder.feed_odd16_32(amiga_sectorHeader);
     encoder.feed_even16_32(amiga_sectorHeader);
@@ -108,80 +160,245 @@ where
     Ok(())
 }
 
+/// Encodes the sectors of a track. AmigaDOS writes them without a gap in between.
+/// Some custom loaders expect a gap which can be given as `sector_gap` encoded zero bytes.
 pub fn generate_track(
     cylinder: u32,
     head: u32,
+    sectors_per_track: u32,
+    sector_gap: usize,
+    sync_word: u16,
     sectors: &mut ChunksExact<u8>,
 ) -> anyhow::Result<Vec<u8>> {
     let mut trackbuf: Vec<u8> = Vec::new();
     let mut collector = BitStreamCollector::new(|f| trackbuf.push(f));
     let mut encoder = MfmEncoder::new(|cell| collector.feed(cell));
 
-    for sector in 0..SECTORS_PER_TRACK {
+    for sector in 0..sectors_per_track {
         let sectordata = sectors.next().context(program_flow_error!())?;
 
-        generate_sector(cylinder, head, sector, sectordata, &mut encoder)?;
+        if sector > 0 {
+            for _ in 0..sector_gap {
+                encoder.feed_encoded8(0);
+            }
+        }
+
+        generate_sector(
+            cylinder,
+            head,
+            sector,
+            sectors_per_track,
+            sectordata,
+            sync_word,
+            &mut encoder,
+        )?;
     }
 
     Ok(trackbuf)
 }
 
+fn long_at(buffer: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        buffer.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// The root block is in the middle of the disk. That's block 880 on DD and 1760 on HD disks.
+fn root_block(blocks: usize) -> usize {
+    blocks / 2
+}
+
+/// Provides for every block if it is unused according to the bitmap of the OFS/FFS filesystem.
+/// Returns None if the image doesn't contain a valid filesystem.
+fn free_blocks(buffer: &[u8]) -> Option<Vec<bool>> {
+    let block_size = BYTES_PER_SECTOR as usize;
+    let root = root_block(buffer.len() / block_size) * block_size;
+
+    if long_at(buffer, root)? != ROOT_BLOCK_TYPE
+        || long_at(buffer, root + block_size - 4)? != ROOT_BLOCK_SECONDARY_TYPE
+        || long_at(buffer, root + block_size - 200)? != ROOT_BLOCK_BITMAP_VALID
+    {
+        return None;
+    }
+
+    let bitmap_pages: Vec<usize> = (0..ROOT_BLOCK_BITMAP_PAGES)
+        .map_while(|page| long_at(buffer, root + block_size - 196 + page * 4))
+        .take_while(|f| *f != 0)
+        .map(|f| f as usize)
+        .collect();
+
+    let mut free = vec![false; buffer.len() / block_size];
+
+    for (block, is_free) in free.iter_mut().enumerate().skip(BITMAP_FIRST_BLOCK) {
+        let bit = block - BITMAP_FIRST_BLOCK;
+        let Some(page) = bitmap_pages.get(bit / BITMAP_BITS_PER_BLOCK) else {
+            break;
+        };
+        let long_offset = 4 + (bit % BITMAP_BITS_PER_BLOCK) / 32 * 4;
+        let long = long_at(buffer, page * block_size + long_offset)?;
+
+        // A set bit marks a free block
+        *is_free = long & (1 << (bit % 32)) != 0;
+    }
+
+    Some(free)
+}
+
+/// Provides the density, the number of sectors per track and the number of cylinders
+/// for the size of an image
+fn adf_layout(size: u64) -> anyhow::Result<(Density, u32, u32)> {
+    let layouts = (CYLINDERS..=MAX_CYLINDERS).flat_map(|cylinders| {
+        [
+            (Density::SingleDouble, SECTORS_PER_DD_TRACK, cylinders),
+            (Density::High, SECTORS_PER_HD_TRACK, cylinders),
+        ]
+    });
+    let layout_size = |(_, sectors_per_track, cylinders): &(Density, u32, u32)| {
+        u64::from(BYTES_PER_SECTOR * HEADS * cylinders * sectors_per_track)
+    };
+
+    if let Some(layout) = layouts.clone().find(|f| layout_size(f) == size) {
+        return Ok(layout);
+    }
+    if let Some(error) = truncated_image_error(
+        "ADF",
+        size as usize,
+        layouts.map(|f| layout_size(&f) as usize),
+    ) {
+        bail!(error);
+    }
+    bail!("{size} bytes is neither the size of a DD nor of a HD ADF")
+}
+
 pub fn parse_adf_image(path: &str) -> anyhow::Result<RawImage> {
+    parse_adf_image_with_options(path, false, 0, AMIGA_SYNC_WORD)
+}
+
+/// With `fast_blank`, tracks without data which are not used by the filesystem
+/// are written as a plain MFM pattern without sectors. AmigaDOS never reads them
+/// but copying such a disk track by track will report them as unformatted.
+///
+/// `sector_gap` is the number of encoded zero bytes between two sectors.
+/// `sync_word` is written twice in front of every sector header.
+pub fn parse_adf_image_with_options(
+    path: &str,
+    fast_blank: bool,
+    sector_gap: usize,
+    sync_word: u16,
+) -> anyhow::Result<RawImage> {
+    validate_sync_word(sync_word)?;
     println!("Reading ADF from {path} ...");
 
     let mut f = File::open(path).context("no file found")?;
     let metadata = fs::metadata(path).context("unable to read metadata")?;
-    ensure!(metadata.len() as u32 == BYTES_PER_SECTOR * HEADS * SECTORS_PER_TRACK * CYLINDERS);
+    let (density, sectors_per_track, cylinders) = adf_layout(metadata.len())?;
+    let cell_size = match density {
+        Density::High => 84,
+        Density::SingleDouble => 168,
+    };
     let mut buffer = vec![0; metadata.len() as usize];
 
     let bytes_read = f.read(&mut buffer).context("buffer overflow")?;
     ensure!(bytes_read == metadata.len() as usize);
 
-    let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR as usize);
+    let free_blocks = if fast_blank {
+        let free_blocks = free_blocks(&buffer);
+        if free_blocks.is_none() {
+            println!("No OFS/FFS filesystem found. Blank tracks are written as usual.");
+        }
+        free_blocks
+    } else {
+        None
+    };
+
+    let bytes_per_track = (BYTES_PER_SECTOR * sectors_per_track) as usize;
+    // Every encoded byte of a gap takes two cell bytes
+    let mfm_bytes_per_track = MFM_BYTES_PER_SECTOR * sectors_per_track as usize
+        + sector_gap * 2 * (sectors_per_track as usize - 1);
+    let mut track_data_iter = buffer.chunks_exact(bytes_per_track);
 
     let mut tracks: Vec<RawTrack> = Vec::new();
+    let mut blank_tracks = 0;
 
-    for cylinder in 0..CYLINDERS {
+    for cylinder in 0..cylinders {
         for head in 0..HEADS {
-            let trackbuf = generate_track(cylinder, head, &mut sectors)?;
+            let track_data = track_data_iter.next().context(program_flow_error!())?;
+
+            let first_block = ((cylinder * HEADS + head) * sectors_per_track) as usize;
+            let is_unused = free_blocks.as_ref().is_some_and(|free_blocks| {
+                free_blocks
+                    .get(first_block..first_block + sectors_per_track as usize)
+                    .is_some_and(|f| f.iter().all(|is_free| *is_free))
+            });
+
+            let trackbuf = if is_unused && track_data.iter().all(|f| *f == 0) {
+                blank_tracks += 1;
+                MFM_BLANK_PATTERN
+                    .iter()
+                    .copied()
+                    .cycle()
+                    .take(mfm_bytes_per_track)
+                    .collect()
+            } else {
+                generate_track(
+                    cylinder,
+                    head,
+                    sectors_per_track,
+                    sector_gap,
+                    sync_word,
+                    &mut track_data.chunks_exact(BYTES_PER_SECTOR as usize),
+                )?
+            };
 
             let densitymap = vec![DensityMapEntry {
                 number_of_cellbytes: trackbuf.len(),
-                cell_size: PulseDuration(168),
+                cell_size: PulseDuration(cell_size),
             }];
 
-            tracks.push(RawTrack::new(
-                cylinder,
-                head,
-                trackbuf,
-                densitymap,
-                util::Encoding::MFM,
-            ));
+            let track = RawTrack::new(cylinder, head, trackbuf, densitymap, util::Encoding::MFM);
+            if sector_gap > 0 {
+                track
+                    .assert_fits_into_rotation(DRIVE_3_5_RPM)
+                    .with_context(|| format!("Sector gap of {sector_gap} bytes is too long"))?;
+            }
+            tracks.push(track);
         }
     }
 
+    if fast_blank {
+        println!("{blank_tracks} unused tracks are written without sectors");
+    }
+
     Ok(RawImage {
         tracks,
-        density: util::Density::SingleDouble,
+        density,
         disk_type: util::DiskType::Inch3_5,
     })
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::VecDeque;
     use std::convert::TryInto;
 
+    use util::{
+        bitstream::to_bit_stream, fluxpulse::FluxPulseGenerator, skip_repeated_pulses,
+        VerifyWindows,
+    };
+
     use super::*;
+    use crate::error::ToolError;
+    use crate::track_parser::{amiga::AmigaTrackParser, TrackParser};
 
-    fn check_aligned_amiga_mfm_track(buffer: &[u8]) {
+    fn check_aligned_amiga_mfm_track(buffer: &[u8], sync_word: u16) {
         let mut longs = buffer.chunks(4);
 
-        for _ in 0..SECTORS_PER_TRACK {
+        for _ in 0..SECTORS_PER_DD_TRACK {
             loop {
                 let longbuf = longs.next().unwrap();
                 let long = u32::from_be_bytes(longbuf.try_into().unwrap());
 
-                if long == 0x4489_4489 {
+                if long == (u32::from(sync_word) << 16) | u32::from(sync_word) {
                     println!("Detected sync!");
                     break;
                 }
@@ -256,10 +473,217 @@ mod tests {
 
     #[test]
     fn track_check_test() {
-        let buffer = vec![0x12; (BYTES_PER_SECTOR * SECTORS_PER_TRACK) as usize];
+        let buffer = vec![0x12; (BYTES_PER_SECTOR * SECTORS_PER_DD_TRACK) as usize];
         let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR as usize);
 
-        let trackbuf = generate_track(30, 1, &mut sectors).unwrap();
-        check_aligned_amiga_mfm_track(&trackbuf);
+        let trackbuf = generate_track(
+            30,
+            1,
+            SECTORS_PER_DD_TRACK,
+            0,
+            AMIGA_SYNC_WORD,
+            &mut sectors,
+        )
+        .unwrap();
+        check_aligned_amiga_mfm_track(&trackbuf, AMIGA_SYNC_WORD);
+        assert_eq!(
+            trackbuf.len(),
+            MFM_BYTES_PER_SECTOR * SECTORS_PER_DD_TRACK as usize
+        );
+    }
+
+    #[test]
+    fn sector_gap_test() {
+        let buffer = vec![0x12; (BYTES_PER_SECTOR * SECTORS_PER_DD_TRACK) as usize];
+        let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR as usize);
+
+        let trackbuf = generate_track(
+            30,
+            1,
+            SECTORS_PER_DD_TRACK,
+            8,
+            AMIGA_SYNC_WORD,
+            &mut sectors,
+        )
+        .unwrap();
+        check_aligned_amiga_mfm_track(&trackbuf, AMIGA_SYNC_WORD);
+        assert_eq!(
+            trackbuf.len(),
+            (MFM_BYTES_PER_SECTOR + 16) * SECTORS_PER_DD_TRACK as usize - 16
+        );
+
+        let path = std::env::temp_dir().join("sector_gap_test.adf");
+        let path = path.to_str().unwrap();
+        let size = BYTES_PER_SECTOR * SECTORS_PER_DD_TRACK * HEADS * CYLINDERS;
+        fs::write(path, vec![0; size as usize]).unwrap();
+        let image = parse_adf_image_with_options(path, false, 8, AMIGA_SYNC_WORD);
+        // Only a few hundred bytes are left on a track
+        let too_long = parse_adf_image_with_options(path, false, 100, AMIGA_SYNC_WORD);
+        fs::remove_file(path).unwrap();
+
+        assert!(image.is_ok());
+        assert!(too_long.is_err());
+    }
+
+    #[test]
+    fn sync_word_test() {
+        validate_sync_word(AMIGA_SYNC_WORD).unwrap();
+        validate_sync_word(0x8914).unwrap();
+        // Regular encoded zeros
+        assert!(validate_sync_word(0xaaaa).is_err());
+        // Adjacent flux reversals
+        assert!(validate_sync_word(0x9521).is_err());
+        // Too many cells without flux reversal
+        assert!(validate_sync_word(0x4521).is_err());
+        // Clock violation only in one of both alignments
+        assert!(validate_sync_word(0x4a89).is_err());
+
+        let buffer = vec![0x12; (BYTES_PER_SECTOR * SECTORS_PER_DD_TRACK) as usize];
+        let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR as usize);
+        let trackbuf =
+            generate_track(30, 1, SECTORS_PER_DD_TRACK, 0, 0x8914, &mut sectors).unwrap();
+        check_aligned_amiga_mfm_track(&trackbuf, 0x8914);
+    }
+
+    #[test]
+    fn fast_blank_test() {
+        let block_size = BYTES_PER_SECTOR as usize;
+        let mut buffer = vec![0; block_size * (SECTORS_PER_DD_TRACK * HEADS * CYLINDERS) as usize];
+        let root_block = root_block(buffer.len() / block_size);
+        assert_eq!(root_block, 880);
+
+        // Root block with a single bitmap block after it
+        let root = root_block * block_size;
+        let bitmap = (root_block + 1) * block_size;
+        let mut put_long = |offset: usize, value: u32| {
+            buffer
+                .get_mut(offset..offset + 4)
+                .unwrap()
+                .copy_from_slice(&value.to_be_bytes());
+        };
+        put_long(root, ROOT_BLOCK_TYPE);
+        put_long(root + block_size - 4, ROOT_BLOCK_SECONDARY_TYPE);
+        put_long(root + block_size - 200, ROOT_BLOCK_BITMAP_VALID);
+        put_long(root + block_size - 196, (root_block + 1) as u32);
+
+        // Everything is free but the root and the bitmap
+        for long in 0..127 {
+            put_long(bitmap + 4 + long * 4, 0xffff_ffff);
+        }
+        let used_long = (root_block - BITMAP_FIRST_BLOCK) / 32;
+        let used_bits = 0b11 << ((root_block - BITMAP_FIRST_BLOCK) % 32);
+        put_long(bitmap + 4 + used_long * 4, !used_bits);
+
+        let free = free_blocks(&buffer).unwrap();
+        assert!(!free.first().unwrap());
+        assert!(free.get(2).unwrap());
+        assert!(!free.get(root_block).unwrap());
+        assert!(!free.get(root_block + 1).unwrap());
+        assert!(free.get(root_block + 2).unwrap());
+
+        let path = std::env::temp_dir().join("fast_blank_test.adf");
+        let path = path.to_str().unwrap();
+        fs::write(path, &buffer).unwrap();
+        let image = parse_adf_image_with_options(path, true, 0, AMIGA_SYNC_WORD).unwrap();
+        fs::remove_file(path).unwrap();
+
+        // Only the boot block and the root block tracks keep their sectors
+        let blank = |cylinder: u32, head: u32| {
+            let track = image
+                .tracks
+                .iter()
+                .find(|f| f.cylinder == cylinder && f.head == head)
+                .unwrap();
+            track.check_writability().unwrap();
+            track
+                .raw_data
+                .chunks(MFM_BLANK_PATTERN.len())
+                .all(|f| f == MFM_BLANK_PATTERN)
+        };
+        assert!(!blank(0, 0));
+        assert!(blank(0, 1));
+        assert!(!blank(40, 0));
+        assert!(blank(79, 1));
+
+        // Without a filesystem nothing is trimmed
+        assert!(free_blocks(&vec![0; buffer.len()]).is_none());
+
+        // The device removes repeated pulses at the start of the track before it
+        // searches the read data. A blank track must still provide something to verify.
+        let track = image
+            .tracks
+            .iter()
+            .find(|f| f.cylinder == 79 && f.head == 1)
+            .unwrap();
+        let mut pulses = VecDeque::new();
+        let cell_size = track.densitymap.first().unwrap().cell_size.0 as u32;
+        let mut generator = FluxPulseGenerator::new(|f| pulses.push_back(f), cell_size);
+        track
+            .raw_data
+            .iter()
+            .for_each(|f| to_bit_stream(*f, |bit| generator.feed(bit)));
+        generator.flush();
+
+        let skip_pulses = VerifyWindows::default().skip_pulses;
+        let last = *pulses.get(skip_pulses - 1).unwrap();
+        let mut remaining = pulses.into_iter().skip(skip_pulses);
+        let (repeated, _) = skip_repeated_pulses(last, || remaining.next()).unwrap();
+        assert!(repeated < 10);
+
+        // Reading it back is possible with --allow-blank-tracks
+        let mut parser = AmigaTrackParser::new(Density::SingleDouble);
+        parser.expect_track(79, 1);
+        let result = parser.parse_raw_track(&track.simulate_read(2).unwrap());
+        assert!(matches!(
+            result.err().unwrap().downcast_ref::<ToolError>(),
+            Some(ToolError::BlankTrack { .. })
+        ));
+    }
+
+    #[test]
+    fn high_density_test() {
+        let block_size = BYTES_PER_SECTOR as usize;
+        let dd_size = block_size * (SECTORS_PER_DD_TRACK * HEADS * CYLINDERS) as usize;
+
+        let parse = |size: usize| {
+            let path = std::env::temp_dir().join(format!("high_density_test_{size}.adf"));
+            let path = path.to_str().unwrap().to_owned();
+            fs::write(&path, vec![0x34; size]).unwrap();
+            let image = parse_adf_image(&path);
+            fs::remove_file(&path).unwrap();
+            image
+        };
+
+        let dd = parse(dd_size).unwrap();
+        let hd = parse(dd_size * 2).unwrap();
+        assert!(matches!(dd.density, Density::SingleDouble));
+        assert!(matches!(hd.density, Density::High));
+        assert_eq!(hd.tracks.len(), dd.tracks.len());
+
+        let dd_track = dd.tracks.first().unwrap();
+        let hd_track = hd.tracks.first().unwrap();
+        assert_eq!(dd_track.densitymap.first().unwrap().cell_size.0, 168);
+        assert_eq!(hd_track.densitymap.first().unwrap().cell_size.0, 84);
+        // Twice the sectors on the same rotation
+        assert_eq!(hd_track.raw_data.len(), dd_track.raw_data.len() * 2);
+        hd_track
+            .assert_fits_into_rotation(util::DRIVE_3_5_RPM)
+            .unwrap();
+
+        assert!(parse(dd_size + block_size).is_err());
+        // An interrupted download
+        let truncated = parse(dd_size - 1000).err().unwrap().to_string();
+        assert_eq!(
+            truncated,
+            format!(
+                "ADF image is {} bytes, expected {dd_size}. Is the file truncated?",
+                dd_size - 1000
+            )
+        );
+        assert!(parse(dd_size * 3).is_err());
+
+        // Over-formatted with 84 cylinders
+        let over_formatted = parse(dd_size / 80 * 84).unwrap();
+        assert_eq!(over_formatted.tracks.last().unwrap().cylinder, 83);
     }
 }
@@ -1,23 +1,73 @@
+use crate::image_reader::truncated_image_error;
 use crate::rawtrack::{RawImage, RawTrack};
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
+use std::convert::TryFrom;
 use std::fs::{self, File};
 use std::io::Read;
 use std::slice::ChunksExact;
 use util::bitstream::{to_bit_stream, BitStreamCollector};
-use util::c64_geometry::{get_track_settings, TrackConfiguration};
+use util::c64_geometry::{
+    get_track_settings, sectors_total, TrackConfiguration, EXTENDED_TRACKS, STANDARD_TRACKS,
+};
 use util::gcr::to_gcr_stream;
 use util::{DensityMapEntry, PulseDuration};
 
 // Info from http://www.baltissen.org/newhtm/1541c.htm
 
-const CYLINDERS: u8 = 35;
-const SECTORS_TOTAL: usize = 683;
 const BYTES_PER_SECTOR: usize = 256;
 
 // Nothing specific as disk id. Just something random.
 const ID1: u8 = 0x39_u8;
 const ID2: u8 = 0x30_u8;
 
+/// Variant of a D64 image which is derived from the file size
+#[derive(Debug, PartialEq, Eq)]
+pub struct D64Layout {
+    pub tracks: usize,
+    /// One byte of error information per sector is appended after the sectors
+    pub error_info: bool,
+}
+
+impl D64Layout {
+    /// Number of bytes occupied by the sectors
+    #[must_use]
+    pub fn sector_bytes(&self) -> usize {
+        sectors_total(self.tracks) * BYTES_PER_SECTOR
+    }
+}
+
+/// Detects standard 35 track and extended 40 track images with and without error information
+pub fn d64_layout(file_size: usize) -> anyhow::Result<D64Layout> {
+    for tracks in [STANDARD_TRACKS, EXTENDED_TRACKS] {
+        let sectors = sectors_total(tracks);
+
+        if file_size == sectors * BYTES_PER_SECTOR {
+            return Ok(D64Layout {
+                tracks,
+                error_info: false,
+            });
+        }
+
+        if file_size == sectors * (BYTES_PER_SECTOR + 1) {
+            return Ok(D64Layout {
+                tracks,
+                error_info: true,
+            });
+        }
+    }
+
+    let valid_sizes = [STANDARD_TRACKS, EXTENDED_TRACKS]
+        .iter()
+        .flat_map(|&tracks| {
+            let sectors = sectors_total(tracks);
+            [sectors * BYTES_PER_SECTOR, sectors * (BYTES_PER_SECTOR + 1)]
+        });
+    if let Some(error) = truncated_image_error("D64", file_size, valid_sizes) {
+        bail!(error);
+    }
+    bail!("D64 image has wrong size of {file_size} bytes")
+}
+
 trait RawGcrSink {
     fn feed_raw(&mut self, word: u8);
     fn feed_gcr(&mut self, word: u8);
@@ -114,13 +164,19 @@ pub fn parse_d64_image(path: &str) -> anyhow::Result<RawImage> {
     let bytes_read = file.read(whole_file_buffer.as_mut())?;
     ensure!(bytes_read == metadata.len() as usize);
 
-    ensure!(metadata.len() as u32 == 174_848, "D64 image has wrong size");
+    let layout = d64_layout(whole_file_buffer.len())?;
+    if layout.tracks != STANDARD_TRACKS {
+        println!("Extended D64 image with {} tracks", layout.tracks);
+    }
+    if layout.error_info {
+        println!("Error information of the D64 image is ignored");
+    }
 
     let mut tracks: Vec<RawTrack> = Vec::new();
-    let mut sectors = whole_file_buffer.chunks_exact(BYTES_PER_SECTOR);
-    ensure!(sectors.len() == SECTORS_TOTAL);
+    let mut sectors =
+        ensure_index!(whole_file_buffer[..layout.sector_bytes()]).chunks_exact(BYTES_PER_SECTOR);
 
-    for src_cylinder in 0..CYLINDERS {
+    for src_cylinder in 0..u8::try_from(layout.tracks)? {
         let tracknum = src_cylinder + 1;
 
         let (trackbuf, settings) = generate_track(tracknum, &mut sectors)?;
@@ -145,3 +201,31 @@ pub fn parse_d64_image(path: &str) -> anyhow::Result<RawImage> {
         density: util::Density::SingleDouble,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ToolError;
+
+    #[test]
+    fn d64_layout_test() {
+        let standard = d64_layout(174_848).unwrap();
+        assert_eq!(standard.tracks, 35);
+        assert!(!standard.error_info);
+        assert!(d64_layout(175_531).unwrap().error_info);
+
+        let extended = d64_layout(196_608).unwrap();
+        assert_eq!(extended.tracks, 40);
+        assert_eq!(extended.sector_bytes(), 196_608);
+        assert!(d64_layout(197_376).unwrap().error_info);
+
+        assert!(matches!(
+            ToolError::from(d64_layout(174_847).unwrap_err()),
+            ToolError::TruncatedImage {
+                expected: 174_848,
+                ..
+            }
+        ));
+        assert!(d64_layout(200_000).is_err());
+    }
+}
@@ -178,3 +178,40 @@ pub fn parse_dsk_image(path: &str) -> anyhow::Result<RawImage> {
         density: Density::SingleDouble,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_reader::assert_truncation_rejected;
+
+    /// A standard DSK with a single track of 9 sectors with 512 bytes
+    fn dsk_file() -> Vec<u8> {
+        let mut data = b"MV - CPCEMU Disk-File\r\nDisk-Info\r\n".to_vec();
+        data.resize(0x30, 0);
+        data.extend([1, 1]);
+        data.extend(0x1300u16.to_le_bytes());
+        data.resize(0x100, 0);
+
+        data.extend(b"Track-Info\r\n");
+        data.resize(0x110, 0);
+        data.extend([0, 0, 0, 0, 2, 9, 0x4e, 0xe5]);
+        for sector_id in 1..=9 {
+            data.extend([0, 0, sector_id, 2, 0, 0, 0, 0]);
+        }
+        data.resize(0x200, 0);
+        data.extend((0..9 * 512).map(|f| (f % 251) as u8));
+        data
+    }
+
+    #[test]
+    fn truncated_dsk_test() {
+        let data = dsk_file();
+        let path = std::env::temp_dir().join("truncated_dsk_test.dsk");
+        fs::write(&path, &data).unwrap();
+        let image = parse_dsk_image(path.to_str().unwrap()).unwrap();
+        assert_eq!(image.tracks.len(), 1);
+        fs::remove_file(path).unwrap();
+
+        assert_truncation_rejected("truncated_dsk_test.dsk", &data, data.len(), parse_dsk_image);
+    }
+}
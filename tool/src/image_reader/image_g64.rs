@@ -1,11 +1,15 @@
 use crate::rawtrack::{auto_cell_size, RawImage, RawTrack};
+use crate::track_parser::{c64::C64TrackParser, decode_raw_track, TrackParser};
 use anyhow::{ensure, Context};
 use std::convert::TryInto;
 use std::fs::{self, File};
 use std::io::Read;
-use util::{DensityMapEntry, PulseDuration, DRIVE_5_25_RPM};
+use util::{
+    c64_geometry::{get_track_settings, HalfTrack},
+    DensityMapEntry, PulseDuration, DRIVE_5_25_RPM,
+};
 
-const G64_SPEED_TABLE: [u32; 4] = [227, 245, 262, 280];
+pub(crate) const G64_SPEED_TABLE: [u32; 4] = [227, 245, 262, 280];
 
 // http://www.unusedino.de/ec64/technical/formats/g64.html
 
@@ -81,7 +85,31 @@ fn patch_trackdata(source: &[u8], file_hash_str: &str, cyl: u8) -> Option<Vec<u8
     Some(result)
 }
 
+/// Reads back the generated track with our own GCR decoder to detect silent
+/// encoding problems before the track is written. `declared_cell_size` is the
+/// speed zone of the track in the image before any adjustments.
+fn verify_g64_track(track: &RawTrack, declared_cell_size: u32) -> anyhow::Result<()> {
+    let half_track = HalfTrack(track.cylinder);
+    let expected_cell_size = get_track_settings(half_track.track() as usize).cellsize as u32;
+    ensure!(
+        declared_cell_size == expected_cell_size,
+        "Track {half_track} uses the speed zone of cell size {declared_cell_size} instead of {expected_cell_size}"
+    );
+
+    let mut track_parsers: Vec<Box<dyn TrackParser>> = vec![Box::new(C64TrackParser::new())];
+    decode_raw_track(track, &mut track_parsers)
+        .with_context(|| format!("Track {half_track} has no valid sync, header or data"))?;
+
+    Ok(())
+}
+
 pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
+    parse_g64_image_with_options(path, false)
+}
+
+/// Parses a G64 image. With `verify`, every track is decoded again and
+/// checked for the speed zone of the 1541 to find problems before writing.
+pub fn parse_g64_image_with_options(path: &str, verify: bool) -> anyhow::Result<RawImage> {
     println!("Reading G64 from {path} ...");
 
     let mut file = File::open(path)?;
@@ -94,7 +122,8 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
     let file_hash = md5::compute(&whole_file_buffer);
     let file_hashstr = format!("{file_hash:x}");
 
-    let (file_header_view, rest_of_file) = whole_file_buffer.split_at(12);
+    let file_header_view = &ensure_index!(whole_file_buffer[0..12]);
+    let rest_of_file = &ensure_index!(whole_file_buffer[12..]);
 
     ensure!(b"GCR-1541".eq(&ensure_index!(file_header_view[0..8])));
     let g64_version = ensure_index!(file_header_view[8]);
@@ -102,10 +131,9 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
     let number_of_tracks = ensure_index!(file_header_view[9]);
     let _size_of_track = u16::from_le_bytes(ensure_index!(file_header_view[10..12]).try_into()?);
 
-    let (track_offsets_u8, rest_of_file) =
-        rest_of_file.split_at(number_of_tracks as usize * std::mem::size_of::<u32>());
-    let (speed_offsets_u8, _rest_of_file) =
-        rest_of_file.split_at(number_of_tracks as usize * std::mem::size_of::<u32>());
+    let table_size = number_of_tracks as usize * std::mem::size_of::<u32>();
+    let track_offsets_u8 = &ensure_index!(rest_of_file[..table_size]);
+    let speed_offsets_u8 = &ensure_index!(rest_of_file[table_size..2 * table_size]);
 
     let track_offsets = u8_buf_to_u32_buf(track_offsets_u8)?;
     let speed_offsets = u8_buf_to_u32_buf(speed_offsets_u8)?;
@@ -116,7 +144,8 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
         let track_offset = ensure_index!(track_offsets[track_index as usize]) as usize;
         let speed_offset = 3 - ensure_index!(speed_offsets[track_index as usize]) as usize;
 
-        let mut cellsize = ensure_index!(G64_SPEED_TABLE[speed_offset]);
+        let declared_cellsize = ensure_index!(G64_SPEED_TABLE[speed_offset]);
+        let mut cellsize = declared_cellsize;
 
         if track_offset > 0 {
             let trackdata_copy: Vec<u8>;
@@ -131,13 +160,19 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
                 );
 
                 if trackdata.iter().all(|f| *f == 0) {
-                    println!("Track {track_index} is all zero? Remove it...",);
+                    println!(
+                        "Track {} is all zero? Remove it...",
+                        HalfTrack(u32::from(track_index))
+                    );
                     continue;
                 }
 
                 let bytecells_with_ff = trackdata.iter().filter(|f| **f == 0xff).count();
                 if bytecells_with_ff >= trackdata.len() - 2 {
-                    println!("Track {track_index} is all 0xff? Remove it...",);
+                    println!(
+                        "Track {} is all 0xff? Remove it...",
+                        HalfTrack(u32::from(track_index))
+                    );
                     continue;
                 }
 
@@ -152,7 +187,7 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
 
             println!(
                 "Track {} Len {:?} cellsize {} auto_cell_size {}",
-                track_index,
+                HalfTrack(u32::from(track_index)),
                 trackdata_copy.len(),
                 cellsize,
                 auto_cell_size
@@ -175,13 +210,19 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
                 cell_size: PulseDuration(cellsize as i32),
             }];
 
-            tracks.push(RawTrack::new(
+            let track = RawTrack::new(
                 u32::from(track_index),
                 0,
                 trackdata_copy,
                 densitymap,
                 util::Encoding::GCR,
-            ));
+            );
+
+            if verify && let Err(e) = verify_g64_track(&track, declared_cellsize) {
+                println!("Warning: G64 track self check failed: {e:#}");
+            }
+
+            tracks.push(track);
         }
     }
 
@@ -191,3 +232,71 @@ pub fn parse_g64_image(path: &str) -> anyhow::Result<RawImage> {
         density: util::Density::SingleDouble,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_reader::{assert_truncation_rejected, image_d64::generate_track};
+    use crate::image_writer::image_g64::g64_image_to_bytes;
+    use crate::rawtrack::RawImage;
+
+    #[test]
+    fn verify_g64_track_test() {
+        let tracknum = 20;
+        let sectors: Vec<u8> = (0..19 * 256).map(|f| (f % 251) as u8).collect();
+        let (trackbuf, config) = generate_track(tracknum, &mut sectors.chunks_exact(256)).unwrap();
+        let track = |cell_size| {
+            RawTrack::new(
+                2 * (u32::from(tracknum) - 1),
+                0,
+                trackbuf.clone(),
+                vec![DensityMapEntry {
+                    number_of_cellbytes: trackbuf.len(),
+                    cell_size: PulseDuration(cell_size),
+                }],
+                util::Encoding::GCR,
+            )
+        };
+
+        verify_g64_track(&track(config.cellsize as i32), config.cellsize as u32).unwrap();
+        // The speed zone of track 20 is wrong
+        assert!(verify_g64_track(&track(227), 227).is_err());
+        // Broken GCR data
+        let mut broken = track(config.cellsize as i32);
+        broken.raw_data.iter_mut().for_each(|f| *f = 0x55);
+        assert!(verify_g64_track(&broken, config.cellsize as u32).is_err());
+    }
+
+    #[test]
+    fn truncated_g64_test() {
+        let sectors: Vec<u8> = (0..21 * 256).map(|f| (f % 251) as u8).collect();
+        let (trackbuf, config) = generate_track(1, &mut sectors.chunks_exact(256)).unwrap();
+        let image = RawImage {
+            tracks: vec![RawTrack::new(
+                0,
+                0,
+                trackbuf.clone(),
+                vec![DensityMapEntry {
+                    number_of_cellbytes: trackbuf.len(),
+                    cell_size: PulseDuration(config.cellsize as i32),
+                }],
+                util::Encoding::GCR,
+            )],
+            disk_type: util::DiskType::Inch5_25,
+            density: util::Density::SingleDouble,
+        };
+        let data = g64_image_to_bytes(&image).unwrap();
+
+        // The track is followed by padding which is not required
+        let track_offset =
+            u32::from_le_bytes(data.get(12..16).unwrap().try_into().unwrap()) as usize;
+        let end = track_offset + 2 + trackbuf.len();
+        let path = std::env::temp_dir().join("truncated_g64_test.g64");
+        fs::write(&path, data.get(..end).unwrap()).unwrap();
+        let image = parse_g64_image(path.to_str().unwrap()).unwrap();
+        assert_eq!(image.tracks.len(), 1);
+        fs::remove_file(path).unwrap();
+
+        assert_truncation_rejected("truncated_g64_test.g64", &data, end, parse_g64_image);
+    }
+}
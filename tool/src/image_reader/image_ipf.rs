@@ -1,12 +1,15 @@
 use crate::rawtrack::auto_cell_size;
 use crate::rawtrack::{RawImage, RawTrack};
+use crate::track_parser::amiga::AmigaTrackParser;
+use crate::track_parser::iso::IsoTrackParser;
+use crate::track_parser::{decode_raw_track, TrackParser};
 use anyhow::{ensure, Context};
 use std::cell::Cell;
 use std::ffi::CString;
 use std::mem::{self, MaybeUninit};
 use std::slice;
 use std::sync::Mutex;
-use util::{DensityMap, DensityMapEntry, PulseDuration, DRIVE_3_5_RPM};
+use util::{reduce_densitymap, Density, DensityMap, DensityMapEntry, PulseDuration, DRIVE_3_5_RPM};
 
 // Information source:
 // http://www.softpres.org/_media/files:ipfdoc102a.zip?id=download&cache=cache
@@ -40,140 +43,314 @@ fn sparse_timebuf(timebuf: &[u32]) -> anyhow::Result<DensityMap> {
     }
 
     // ensure that the lengths do match up!
-    ensure!(timebuf.len() == sparse_timebuf.iter().map(|f| f.number_of_cellbytes).sum());
+    let number_of_cellbytes: usize = sparse_timebuf.iter().map(|f| f.number_of_cellbytes).sum();
+    ensure!(timebuf.len() == number_of_cellbytes);
 
     Ok(sparse_timebuf)
 }
 
-pub fn parse_ipf_image(path: &str) -> anyhow::Result<RawImage> {
-    println!("Reading IPF from {path} ...");
+/// Converts the density map of a variable density track to cell sizes.
+/// The IPF provides the data rate of every byte relative to 1000.
+fn scaled_densitymap(timebuf: &[u32], auto_cell_size: f64) -> anyhow::Result<DensityMap> {
+    let mut densitymap = sparse_timebuf(timebuf)?;
 
-    let mut tracks: Vec<RawTrack> = Vec::new();
+    for d in &mut densitymap {
+        d.cell_size = PulseDuration((f64::from(d.cell_size.0) * auto_cell_size / 1000.0) as i32);
+    }
 
-    // The CAPS libary is not thread safe!
-    // In unit tests this can become an issue as this code is called multiple
-    // times. We need a mutex so the code between CAPSInit()
-    // and CAPSExit() is not processed in multiple threads.
-    static caps_mutex: Mutex<Cell<()>> = Mutex::new(Cell::new(()));
-    let caps_mutex_guard = caps_mutex.lock();
+    // Different data rates might result in the same cell size
+    Ok(reduce_densitymap(densitymap))
+}
 
-    ensure!(unsafe { CAPSInit() == 0 });
+/// Describes the error codes of the CAPS library
+fn caps_error_description(code: i32) -> &'static str {
+    // We have to allow this exception as Windows and Linux differ here
+    #[allow(clippy::unnecessary_cast)]
+    let descriptions = [
+        (imgeOk as i32, "Ok"),
+        (imgeUnsupported as i32, "Unsupported"),
+        (imgeGeneric as i32, "Generic error"),
+        (imgeOutOfRange as i32, "Out of range"),
+        (imgeReadOnly as i32, "Read only"),
+        (imgeOpen as i32, "Unable to open"),
+        (imgeType as i32, "Wrong image type"),
+        (imgeShort as i32, "Image too short"),
+        (imgeTrackHeader as i32, "Broken track header"),
+        (imgeTrackStream as i32, "Broken track stream"),
+        (imgeTrackData as i32, "Broken track data"),
+        (imgeDensityHeader as i32, "Broken density header"),
+        (imgeDensityStream as i32, "Broken density stream"),
+        (imgeDensityData as i32, "Broken density data"),
+        (imgeIncompatible as i32, "Incompatible library version"),
+        (imgeUnsupportedType as i32, "Unsupported image type"),
+        (imgeBadBlockType as i32, "Unsupported block type"),
+        (imgeBadBlockSize as i32, "Unsupported block size"),
+        (imgeBadDataStart as i32, "Unsupported data start"),
+        (imgeBufferShort as i32, "Buffer too short"),
+    ];
+
+    descriptions
+        .iter()
+        .find(|(known_code, _)| *known_code == code)
+        .map_or("Unknown error", |(_, description)| description)
+}
 
-    let id = unsafe { CAPSAddImage() };
+fn caps_track_type_description(track_type: u32) -> &'static str {
+    // We have to allow this exception as Windows and Linux differ here
+    #[allow(clippy::unnecessary_cast)]
+    let descriptions = [
+        (ctitNA as u32, "not available"),
+        (ctitNoise as u32, "noise"),
+        (ctitAuto as u32, "auto density"),
+        (ctitVar as u32, "variable density"),
+    ];
+
+    descriptions
+        .iter()
+        .find(|(known_type, _)| *known_type == track_type)
+        .map_or("unknown", |(_, description)| description)
+}
+
+/// Converts a locked track of the CAPS library. Tracks without data result in `None`.
+fn convert_ipf_track(
+    trackInf: &CapsTrackInfoT1,
+    cylinder: u32,
+    head: u32,
+) -> anyhow::Result<Option<RawTrack>> {
+    if trackInf.tracklen == 0 {
+        return Ok(None);
+    }
+
+    // Noise tracks are provided with random data by the CAPS library.
+    // They are written like tracks with auto density.
+    // We have to allow this exception as Windows and Linux differ here
+    #[allow(clippy::unnecessary_cast)]
+    let supported_track_type = trackInf.type_ == ctitNoise as u32
+        || trackInf.type_ == ctitAuto as u32
+        || trackInf.type_ == ctitVar as u32;
+
+    ensure!(
+        supported_track_type,
+        "IPF track {cylinder} {head} has {} data which is not supported",
+        caps_track_type_description(trackInf.type_)
+    );
+
+    // Some tracks have more than one rotation inside. The overlap must be removed
+    // as that additional data would increase writing frequency.
+    // It is also possible that the overlap position contains
+    // invalid MFM data...
+    let overlap = trackInf.overlap;
+
+    let trackbuf_orig =
+        unsafe { slice::from_raw_parts(trackInf.trackbuf, trackInf.tracklen as usize) };
+
+    let trackbuf: Vec<u8> = if overlap == -1 {
+        // No overlap
+        trackbuf_orig.into()
+    } else if overlap < 10 {
+        // Some images have the overlap at the beginning
+        ensure_index!(trackbuf_orig[1 + overlap as usize..]).into()
+    } else {
+        // We have some overlap at the end
+        ensure!(
+            trackInf.tracklen >= overlap as u32,
+            "Overlap of IPF track {cylinder} {head} behind end of data?"
+        );
+        ensure_index!(trackbuf_orig[0..overlap as usize]).into()
+    };
+
+    let auto_cell_size = auto_cell_size(trackbuf.len() as u32, DRIVE_3_5_RPM).min(168.0_f64);
+
+    // We have to allow this exception as Windows and Linux differ here
+    #[allow(clippy::unnecessary_cast)]
+    let densitymap = if trackInf.type_ == ctitVar as u32 {
+        println!("Variable Density Track {cylinder} {head} - Auto cell size {auto_cell_size} ");
+
+        ensure!(
+            trackInf.timelen == trackInf.tracklen,
+            "IPF track {cylinder} {head} has a density map of {} entries for {} bytes",
+            trackInf.timelen,
+            trackInf.tracklen
+        );
+
+        let timebuf_orig =
+            unsafe { slice::from_raw_parts(trackInf.timebuf, trackInf.timelen as usize).to_vec() };
+
+        let timebuf: Vec<u32> = if overlap == -1 {
+            // No overlap
+            timebuf_orig
+        } else if overlap < 10 {
+            // Some images have the overlap at the beginning
+            ensure_index!(timebuf_orig[1 + overlap as usize..]).into()
+        } else {
+            // We have some overlap at the end
+            ensure!(
+                trackInf.timelen >= overlap as u32,
+                "Overlap of IPF density map {cylinder} {head} behind end of data?"
+            );
+            ensure_index!(timebuf_orig[0..overlap as usize]).into()
+        };
+
+        scaled_densitymap(&timebuf, auto_cell_size)?
+    } else {
+        vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(auto_cell_size as i32),
+        }]
+    };
+
+    Ok(Some(RawTrack::new(
+        cylinder,
+        head,
+        trackbuf,
+        densitymap,
+        util::Encoding::MFM,
+    )))
+}
+
+/// Decodes the converted track and the cells provided by the CAPS library with our own
+/// decoders and compares the content. Detects problems of the conversion before the
+/// track is written. Tracks in a format unknown to us can't be checked.
+fn verify_ipf_track(track: &RawTrack, caps_cells: Vec<u8>) {
+    let track_parsers = || -> Vec<Box<dyn TrackParser>> {
+        vec![
+            Box::new(AmigaTrackParser::new(Density::SingleDouble)),
+            Box::new(IsoTrackParser::new(None, Density::SingleDouble)),
+        ]
+    };
+
+    let reference = RawTrack::new(
+        track.cylinder,
+        track.head,
+        caps_cells.clone(),
+        vec![DensityMapEntry {
+            number_of_cellbytes: caps_cells.len(),
+            cell_size: PulseDuration(168),
+        }],
+        util::Encoding::MFM,
+    );
+
+    let Ok(expected) = decode_raw_track(&reference, &mut track_parsers()) else {
+        // Nothing we could compare against
+        return;
+    };
+
+    match decode_raw_track(track, &mut track_parsers()) {
+        Ok(payload) if payload.payload == expected.payload => {}
+        Ok(_) => println!(
+            "Warning: IPF track {} {} decodes to different data after conversion",
+            track.cylinder, track.head
+        ),
+        Err(e) => println!(
+            "Warning: IPF track {} {} is not decodable after conversion: {e}",
+            track.cylinder, track.head
+        ),
+    }
+}
+
+fn read_ipf_tracks(id: i32, path: &str, verify: bool) -> anyhow::Result<Vec<RawTrack>> {
+    let mut tracks: Vec<RawTrack> = Vec::new();
 
     let mut cii = MaybeUninit::<CapsImageInfo>::uninit();
     let cpath = CString::new(path)?.into_raw();
-    ensure!(unsafe { CAPSLockImage(id, cpath) == 0 });
+    let result = unsafe { CAPSLockImage(id, cpath) };
     let _ = unsafe { CString::from_raw(cpath) };
+    ensure!(
+        result == 0,
+        "Unable to open IPF: {}",
+        caps_error_description(result)
+    );
 
-    ensure!(unsafe { CAPSGetImageInfo(cii.as_mut_ptr(), id) == 0 });
+    let result = unsafe { CAPSGetImageInfo(cii.as_mut_ptr(), id) };
+    ensure!(
+        result == 0,
+        "Unable to get IPF image info: {}",
+        caps_error_description(result)
+    );
 
     let cii = unsafe { cii.assume_init_mut() };
 
+    // We have to allow this exception as Windows and Linux differ here
+    #[allow(clippy::unnecessary_cast)]
+    let is_floppy_image = cii.type_ == ciitFDD as u32;
+    ensure!(
+        is_floppy_image,
+        "IPF image type {} is not supported. Only floppy disk images are.",
+        cii.type_
+    );
+    ensure!(
+        cii.maxhead <= 1,
+        "IPF image has {} heads. Only 2 are supported.",
+        cii.maxhead + 1
+    );
+
     for cylinder in cii.mincylinder..=cii.maxcylinder {
         for head in cii.minhead..=cii.maxhead {
             let mut trackInf = MaybeUninit::<CapsTrackInfoT1>::uninit();
 
+            let result = unsafe {
+                (*trackInf.as_mut_ptr()).type_ = 1;
+                CAPSLockTrack(
+                    trackInf.as_mut_ptr().cast::<std::ffi::c_void>(),
+                    id,
+                    cylinder,
+                    head,
+                    FLAG_LOCK_TYPE | FLAG_LOCK_INDEX | FLAG_LOCK_DENVAR,
+                )
+            };
             ensure!(
-                unsafe {
-                    (*trackInf.as_mut_ptr()).type_ = 1;
-                    CAPSLockTrack(
-                        trackInf.as_mut_ptr().cast::<std::ffi::c_void>(),
-                        id,
-                        cylinder,
-                        head,
-                        FLAG_LOCK_TYPE | FLAG_LOCK_INDEX | FLAG_LOCK_DENVAR,
-                    )
-                } == 0
+                result == 0,
+                "Unable to decode IPF track {cylinder} {head}: {}",
+                caps_error_description(result)
             );
 
             let trackInf = unsafe { trackInf.assume_init_mut() };
+            let track = convert_ipf_track(trackInf, cylinder, head);
 
-            if trackInf.tracklen > 0 {
-                // Some tracks have more than one rotation inside. The overlap must be removed
-                // as that additional data would increase writing frequency.
-                // It is also possible that the overlap position contains
-                // invalid MFM data...
-                let overlap = trackInf.overlap;
-
-                let trackbuf_orig =
-                    unsafe { slice::from_raw_parts(trackInf.trackbuf, trackInf.tracklen as usize) };
-
-                let trackbuf: Vec<u8> = if overlap == -1 {
-                    // No overlap
-                    trackbuf_orig.into()
-                } else if overlap < 10 {
-                    // Some images have the overlap at the beginning
-                    ensure_index!(trackbuf_orig[1 + overlap as usize..]).into()
-                } else {
-                    // We have some overlap at the end
-                    ensure!(
-                        trackInf.tracklen >= overlap as u32,
-                        "Overlap behind end of data?"
-                    );
-                    ensure_index!(trackbuf_orig[0..overlap as usize]).into()
-                };
-
-                let auto_cell_size =
-                    auto_cell_size(trackbuf.len() as u32, DRIVE_3_5_RPM).min(168.0_f64);
-
-                let mut densitymap;
-
-                // We have to allow this exception as Windows and Linux differ here
-                #[allow(clippy::unnecessary_cast)]
-                if trackInf.type_ == ctitVar as u32 {
-                    println!(
-                        "Variable Density Track {cylinder} {head} - Auto cell size {auto_cell_size} "
-                    );
-
-                    ensure!((trackInf.timelen == trackInf.tracklen));
-
-                    let timebuf_orig = unsafe {
-                        slice::from_raw_parts(trackInf.timebuf, trackInf.timelen as usize).to_vec()
-                    };
-
-                    let timebuf: Vec<u32> = if overlap == -1 {
-                        // No overlap
-                        timebuf_orig
-                    } else if overlap < 10 {
-                        // Some images have the overlap at the beginning
-                        ensure_index!(timebuf_orig[1 + overlap as usize..]).into()
-                    } else {
-                        // We have some overlap at the end
-                        ensure!(
-                            trackInf.timelen >= overlap as u32,
-                            "Overlap behind end of data?"
-                        );
-                        ensure_index!(timebuf_orig[0..overlap as usize]).into()
-                    };
-
-                    densitymap = sparse_timebuf(&timebuf)?;
-
-                    for d in &mut densitymap {
-                        d.cell_size = PulseDuration(
-                            (f64::from(d.cell_size.0) * auto_cell_size / 1000.0) as i32,
-                        );
-                    }
-                } else {
-                    densitymap = vec![DensityMapEntry {
-                        number_of_cellbytes: trackbuf.len(),
-                        cell_size: PulseDuration(auto_cell_size as i32),
-                    }];
-                }
+            // The cells are only valid while the track is locked
+            let caps_cells = (verify && trackInf.tracklen > 0).then(|| unsafe {
+                slice::from_raw_parts(trackInf.trackbuf, trackInf.tracklen as usize).to_vec()
+            });
 
-                tracks.push(RawTrack::new(
-                    cylinder,
-                    head,
-                    trackbuf,
-                    densitymap,
-                    util::Encoding::MFM,
-                ));
-            }
             unsafe {
                 CAPSUnlockTrack(id, cylinder, head);
             }
+
+            if let Some(track) = track? {
+                if let Some(caps_cells) = caps_cells {
+                    verify_ipf_track(&track, caps_cells);
+                }
+                tracks.push(track);
+            }
         }
     }
+
+    Ok(tracks)
+}
+
+pub fn parse_ipf_image(path: &str) -> anyhow::Result<RawImage> {
+    parse_ipf_image_with_options(path, false)
+}
+
+/// Parses an IPF image. With `verify` every track is decoded again
+/// after conversion to check for problems of the IPF decoding.
+pub fn parse_ipf_image_with_options(path: &str, verify: bool) -> anyhow::Result<RawImage> {
+    println!("Reading IPF from {path} ...");
+
+    // The CAPS libary is not thread safe!
+    // In unit tests this can become an issue as this code is called multiple
+    // times. We need a mutex so the code between CAPSInit()
+    // and CAPSExit() is not processed in multiple threads.
+    static caps_mutex: Mutex<Cell<()>> = Mutex::new(Cell::new(()));
+    let caps_mutex_guard = caps_mutex.lock();
+
+    ensure!(unsafe { CAPSInit() == 0 });
+
+    let id = unsafe { CAPSAddImage() };
+
+    // Always clean up the CAPS library, even if the image is not supported
+    let tracks = read_ipf_tracks(id, path, verify);
+
     unsafe {
         CAPSUnlockImage(id);
         CAPSRemImage(id);
@@ -183,6 +360,8 @@ pub fn parse_ipf_image(path: &str) -> anyhow::Result<RawImage> {
     // It is now safe to drop the guard as we have finished using the CAPS library
     mem::drop(caps_mutex_guard);
 
+    let tracks = tracks?;
+
     let smallest_cell_size = tracks
         .iter()
         .filter_map(|f| {
@@ -204,3 +383,39 @@ pub fn parse_ipf_image(path: &str) -> anyhow::Result<RawImage> {
         density: util::Density::SingleDouble,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_reader::assert_truncation_rejected;
+
+    #[test]
+    fn scaled_densitymap_test() {
+        let timebuf: Vec<u32> = [vec![1000; 4], vec![1003; 2], vec![1100; 3]].concat();
+        let densitymap = scaled_densitymap(&timebuf, 168.0).unwrap();
+
+        // 1000 and 1003 both result in a cell size of 168
+        let cell_sizes: Vec<i32> = densitymap.iter().map(|f| f.cell_size.0).collect();
+        assert_eq!(cell_sizes, vec![168, 184]);
+        assert_eq!(
+            densitymap
+                .iter()
+                .map(|f| f.number_of_cellbytes)
+                .sum::<usize>(),
+            timebuf.len()
+        );
+    }
+
+    #[test]
+    fn truncated_ipf_test() {
+        // The CAPS record followed by an INFO record of a floppy disk image
+        let mut data = b"CAPS".to_vec();
+        data.extend(12u32.to_be_bytes());
+        data.extend(0u32.to_be_bytes());
+        data.extend(b"INFO");
+        data.extend(96u32.to_be_bytes());
+        data.resize(12 + 96, 0);
+
+        assert_truncation_rejected("truncated_ipf_test.ipf", &data, data.len(), parse_ipf_image);
+    }
+}
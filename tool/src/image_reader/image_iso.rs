@@ -1,20 +1,27 @@
-use anyhow::bail;
 use anyhow::ensure;
 use anyhow::Context;
 use util::bitstream::BitStreamCollector;
 use util::mfm::MfmEncoder;
 use util::mfm::MfmWord;
+use util::mfm::ISO_IAM_SYNC_WORD;
 use util::mfm::ISO_SYNC_BYTE;
 use util::Bit;
 use util::Density;
+use util::DiskType;
 use util::{DensityMapEntry, PulseDuration};
 
+use std::convert::{TryFrom, TryInto};
+use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::Read;
+use std::path::Path;
 use std::slice::ChunksExact;
 
+use crate::error::ToolError;
+use crate::image_reader::truncated_image_error;
 use crate::rawtrack::RawImage;
 use crate::rawtrack::RawTrack;
+use crate::sector_order::SectorOrder;
 
 // Information sources:
 // https://www-user.tu-chemnitz.de/~heha/basteln/PC/usbfloppy/floppy.chm/
@@ -25,26 +32,76 @@ pub const ISO_IDAM: u8 = 0xfe; // sector header address mark
 pub const ISO_DAM: u8 = 0xfb; // data address mark
 pub const ISO_DDAM: u8 = 0xf8; // deleted data address mark
 
+/// Gap between the index address mark and the first sector
+const ISO_IAM_GAP_SIZE: usize = 50;
+/// Usual value of the gaps which are not in front of an address mark
+pub const ISO_GAP_FILL: u8 = 0x4e;
+
 const HEADS: usize = 2;
 const BYTES_PER_SECTOR: usize = 512;
 
-const POSSIBLE_CYLINDER_COUNTS: [usize; 10] = [38, 39, 40, 41, 42, 78, 79, 80, 81, 82];
-const POSSIBLE_SECTOR_COUNTS: [usize; 5] = [9, 10, 11, 15, 18];
+const POSSIBLE_CYLINDER_COUNTS: [usize; 12] = [38, 39, 40, 41, 42, 78, 79, 80, 81, 82, 83, 84];
+const POSSIBLE_SECTOR_COUNTS: [usize; 6] = [8, 9, 10, 11, 15, 18];
+/// Cylinders and sectors of the single sided PC 5.25" disks with 160K and 180K.
+/// Other single sided sizes are not guessed as they would collide with double sided ones.
+const SINGLE_SIDED_GEOMETRIES: [(usize, usize); 2] = [(40, 8), (40, 9)];
+/// Cylinders and sectors of single sided Atari ST disks.
+/// They are preferred over the double sided geometries of the same size.
+const ST_SINGLE_SIDED_GEOMETRIES: [(usize, usize); 3] = [(80, 9), (80, 10), (82, 9)];
+
+/// Atari ST images are always made for 3.5" drives.
+/// The 5.25" geometries are only considered for other images.
+fn is_atari_st_image(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|f| f.eq_ignore_ascii_case("st"))
+}
 
-fn calculate_floppy_geometry(number_bytes: usize) -> anyhow::Result<(usize, usize)> {
+/// Provides cylinders, heads and sectors per track of an image with the given size
+fn calculate_floppy_geometry(
+    number_bytes: usize,
+    atari_st: bool,
+) -> anyhow::Result<(usize, usize, usize)> {
     // Iterate first over sectors and then over cylinders
     // This favors 80 cyl/9 sec over 40 cyl/18 sec which could make sense
     // but doesn't really...
-    for sectors in POSSIBLE_SECTOR_COUNTS {
-        for cylinders in POSSIBLE_CYLINDER_COUNTS {
-            if number_bytes == cylinders * HEADS * BYTES_PER_SECTOR * sectors {
-                println!("Disk has {cylinders} cylinders and {sectors} sectors!");
-                return Ok((cylinders, sectors));
-            }
-        }
+    let double_sided = POSSIBLE_SECTOR_COUNTS.iter().flat_map(|sectors| {
+        POSSIBLE_CYLINDER_COUNTS
+            .iter()
+            .map(move |cylinders| (*cylinders, HEADS, *sectors))
+    });
+    let single_sided = |geometries: &'static [(usize, usize)]| {
+        geometries
+            .iter()
+            .map(|(cylinders, sectors)| (*cylinders, 1, *sectors))
+    };
+    let geometries: Vec<(usize, usize, usize)> = if atari_st {
+        single_sided(&ST_SINGLE_SIDED_GEOMETRIES)
+            .chain(double_sided)
+            .collect()
+    } else {
+        double_sided
+            .chain(single_sided(&SINGLE_SIDED_GEOMETRIES))
+            .collect()
+    };
+    let geometry_size = |(cylinders, heads, sectors): (usize, usize, usize)| {
+        cylinders * heads * BYTES_PER_SECTOR * sectors
+    };
+
+    if let Some((cylinders, heads, sectors)) = geometries
+        .iter()
+        .copied()
+        .find(|f| geometry_size(*f) == number_bytes)
+    {
+        println!("Disk has {cylinders} cylinders, {heads} heads and {sectors} sectors!");
+        return Ok((cylinders, heads, sectors));
     }
 
-    bail!("Unable to guess the geometry of the disk image")
+    let valid_sizes = geometries.into_iter().map(geometry_size);
+    Err(truncated_image_error("ISO", number_bytes, valid_sizes)
+        .unwrap_or(ToolError::GeometryMismatch { size: number_bytes })
+        .into())
 }
 
 pub struct IsoGeometry {
@@ -56,6 +113,13 @@ pub struct IsoGeometry {
     pub gap4_size: i32,    // 40x 0x4E after data
     pub gap5_size: i32,    // ends the track, not really sure what this value shall be...
     pub interleaving: u32, // with 0 no interleaving applied
+    /// Emit the index address mark after gap1. Some controllers expect it.
+    pub index_address_mark: bool,
+    /// Value of gap1, gap4 and gap5
+    pub gap_fill: u8,
+    /// Number in the header of every sector in the order of the image.
+    /// The sectors are numbered from 1 if not given.
+    pub sector_ids: Option<Vec<u8>>,
 }
 
 impl IsoGeometry {
@@ -72,6 +136,9 @@ impl IsoGeometry {
                 gap5_size: 20,
                 sectors_per_track,
                 interleaving: 1,
+                index_address_mark: false,
+                gap_fill: ISO_GAP_FILL,
+                sector_ids: None,
             },
             11 => Self {
                 gap1_size: 10,
@@ -82,6 +149,9 @@ impl IsoGeometry {
                 gap5_size: 10,
                 sectors_per_track,
                 interleaving: 1,
+                index_address_mark: false,
+                gap_fill: ISO_GAP_FILL,
+                sector_ids: None,
             },
             1 => Self {
                 gap1_size: 60,
@@ -92,20 +162,117 @@ impl IsoGeometry {
                 gap5_size: 10,
                 sectors_per_track,
                 interleaving: 0,
+                index_address_mark: false,
+                gap_fill: ISO_GAP_FILL,
+                sector_ids: None,
             },
             // standard for 9 and 18
-            _ => Self {
-                gap1_size: 60,
-                gap2_size: 12,
-                gap3a_size: 22,
-                gap3b_size: 12,
-                gap4_size: 40,
-                //usually it would be 664 but this makes the verification slower
-                //My drive requires 588 microseconds to recover after writing
-                // to read again. In this time we are already at index.
-                gap5_size: 600,
-                sectors_per_track,
-                interleaving: 0,
+            _ => Self::standard(sectors_per_track),
+        }
+    }
+
+    fn standard(sectors_per_track: usize) -> Self {
+        Self {
+            gap1_size: 60,
+            gap2_size: 12,
+            gap3a_size: 22,
+            gap3b_size: 12,
+            gap4_size: 40,
+            //usually it would be 664 but this makes the verification slower
+            //My drive requires 588 microseconds to recover after writing
+            // to read again. In this time we are already at index.
+            gap5_size: 600,
+            sectors_per_track,
+            interleaving: 0,
+            index_address_mark: false,
+            gap_fill: ISO_GAP_FILL,
+            sector_ids: None,
+        }
+    }
+}
+
+/// Formats which are based on ISO but expect a different layout on the disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsoPreset {
+    /// MSX 360K and 720K disks as formatted by MSX-DOS
+    Msx,
+    /// Sega SF-7000 with 16 sectors of 256 bytes on a single side
+    Sf7000,
+}
+
+impl IsoPreset {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "msx" => Some(Self::Msx),
+            "sf7000" => Some(Self::Sf7000),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn bytes_per_sector(self) -> usize {
+        match self {
+            Self::Msx => 512,
+            Self::Sf7000 => 256,
+        }
+    }
+
+    /// Drive which provides the expected number of cells per rotation
+    #[must_use]
+    pub const fn disk_type(self) -> DiskType {
+        match self {
+            Self::Msx | Self::Sf7000 => DiskType::Inch3_5,
+        }
+    }
+
+    #[must_use]
+    pub const fn density(self) -> Density {
+        match self {
+            Self::Msx | Self::Sf7000 => Density::SingleDouble,
+        }
+    }
+
+    /// Provides cylinders, heads and sectors per track of an image with the given size
+    fn layout(self, number_bytes: usize) -> anyhow::Result<(usize, usize, usize)> {
+        let layouts: &[(usize, usize, usize)] = match self {
+            Self::Msx => &[(80, 2, 9), (80, 1, 9)],
+            Self::Sf7000 => &[(40, 1, 16)],
+        };
+
+        let layout_size = |(cylinders, heads, sectors): &(usize, usize, usize)| {
+            cylinders * heads * sectors * self.bytes_per_sector()
+        };
+
+        layouts
+            .iter()
+            .copied()
+            .find(|f| layout_size(f) == number_bytes)
+            .ok_or_else(|| {
+                let valid_sizes = layouts.iter().map(layout_size);
+                truncated_image_error("ISO", number_bytes, valid_sizes)
+                    .unwrap_or(ToolError::GeometryMismatch { size: number_bytes })
+                    .into()
+            })
+    }
+
+    /// Gaps of the preset. The tracks must still fit into a rotation of 300 RPM.
+    #[must_use]
+    pub fn geometry(self, sectors_per_track: usize) -> IsoGeometry {
+        match self {
+            // MSX-DOS formats with an index address mark,
+            // a large gap after the data and no interleaving
+            Self::Msx => IsoGeometry {
+                gap1_size: 80,
+                gap4_size: 84,
+                gap5_size: 34,
+                index_address_mark: true,
+                ..IsoGeometry::standard(sectors_per_track)
+            },
+            Self::Sf7000 => IsoGeometry {
+                gap4_size: 54,
+                gap5_size: 100,
+                ..IsoGeometry::standard(sectors_per_track)
             },
         }
     }
@@ -140,6 +307,17 @@ pub fn generate_iso_sectorheader<T>(
     encoder.feed_encoded8((crc16 & 0xff) as u8);
 }
 
+pub fn generate_iso_index_address_mark<T>(gap2_size: usize, encoder: &mut MfmEncoder<T>)
+where
+    T: FnMut(Bit),
+{
+    generate_iso_gap(gap2_size, 0, encoder);
+    encoder.feed_raw16(ISO_IAM_SYNC_WORD);
+    encoder.feed_raw16(ISO_IAM_SYNC_WORD);
+    encoder.feed_raw16(ISO_IAM_SYNC_WORD);
+    encoder.feed_encoded8(ISO_IAM);
+}
+
 pub fn generate_iso_data_header<T>(
     gap3b_size: usize,
     encoder: &mut MfmEncoder<T>,
@@ -208,40 +386,94 @@ fn generate_interleaving_table(
     sectors_per_track: usize,
     interleaving: usize,
 ) -> anyhow::Result<Vec<usize>> {
-    let mut interleaving_table = vec![0_usize; sectors_per_track];
+    let mut interleaving_table: Vec<Option<usize>> = vec![None; sectors_per_track];
+    let mut target = 0;
 
     for index in 0..sectors_per_track {
-        let target = (index * (interleaving + 1)) % sectors_per_track;
-        ensure_index_mut!(interleaving_table[target]) = index;
+        // Positions are taken twice if the step divides the number of sectors, like 2 with 10 sectors
+        while ensure_index!(interleaving_table[target]).is_some() {
+            target = (target + 1) % sectors_per_track;
+        }
+        ensure_index_mut!(interleaving_table[target]) = Some(index);
+        target = (target + interleaving + 1) % sectors_per_track;
     }
 
-    Ok(interleaving_table)
+    interleaving_table
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .context(program_flow_error!())
+}
+
+pub fn generate_iso_track(
+    cylinder: u32,
+    head: u32,
+    geometry: &IsoGeometry,
+    sectors_in: &mut ChunksExact<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let interleaving_table =
+        generate_interleaving_table(geometry.sectors_per_track, geometry.interleaving as usize)?;
+
+    generate_iso_track_in_order(cylinder, head, geometry, sectors_in, &interleaving_table)
 }
 
-fn generate_iso_track(
+/// Like `generate_iso_track` but places the sectors in the given order
+/// instead of applying the interleaving of the geometry.
+/// The order refers to the sectors of the track as they are taken from the image.
+pub fn generate_iso_track_in_order(
     cylinder: u32,
     head: u32,
     geometry: &IsoGeometry,
     sectors_in: &mut ChunksExact<u8>,
+    sector_order: &[usize],
 ) -> anyhow::Result<Vec<u8>> {
+    let mut sorted_order = sector_order.to_vec();
+    sorted_order.sort_unstable();
+    ensure!(
+        sorted_order == (0..geometry.sectors_per_track).collect::<Vec<_>>(),
+        "Sector order {sector_order:?} doesn't cover the {} sectors of track {cylinder} {head}",
+        geometry.sectors_per_track
+    );
+
     let mut trackbuf: Vec<u8> = Vec::new();
     let mut collector = BitStreamCollector::new(|f| trackbuf.push(f));
     let mut encoder = MfmEncoder::new(|cell| collector.feed(cell));
 
+    if let Some(sector_ids) = geometry.sector_ids.as_ref() {
+        ensure!(
+            sector_ids.len() == geometry.sectors_per_track,
+            "{} sector IDs given for the {} sectors of track {cylinder} {head}",
+            sector_ids.len(),
+            geometry.sectors_per_track
+        );
+    }
+
     let mut sectors: Vec<(u8, &[u8])> = Vec::new();
     for sector in 0..geometry.sectors_per_track {
         let sectordata = sectors_in.next().context(program_flow_error!())?;
-        sectors.push((sector as u8 + 1, sectordata));
+        let idam_sector = match geometry.sector_ids.as_ref() {
+            Some(sector_ids) => ensure_index!(sector_ids[sector]),
+            None => sector as u8 + 1,
+        };
+        sectors.push((idam_sector, sectordata));
     }
 
-    let interleaving_table =
-        generate_interleaving_table(geometry.sectors_per_track, geometry.interleaving as usize)?;
-
     // just after the index pulse
-    generate_iso_gap(geometry.gap1_size as usize, 0x4e, &mut encoder);
+    generate_iso_gap(geometry.gap1_size as usize, geometry.gap_fill, &mut encoder);
+
+    if geometry.index_address_mark {
+        generate_iso_index_address_mark(geometry.gap2_size as usize, &mut encoder);
+        generate_iso_gap(ISO_IAM_GAP_SIZE, 0x4e, &mut encoder);
+    }
 
-    for index in interleaving_table {
-        let (idam_sector, sectordata) = ensure_index!(sectors[index]);
+    for index in sector_order {
+        let (idam_sector, sectordata) = ensure_index!(sectors[*index]);
+        // 128 << size provides the number of bytes
+        let idam_size = (sectordata.len() / 128).trailing_zeros() as u8;
+        ensure!(
+            128 << idam_size == sectordata.len(),
+            "Sectors with {} bytes are not supported",
+            sectordata.len()
+        );
 
         // sector header
         generate_iso_sectorheader(
@@ -249,7 +481,7 @@ fn generate_iso_track(
             cylinder as u8,
             head as u8,
             idam_sector,
-            2,
+            idam_size,
             &mut encoder,
         );
 
@@ -259,42 +491,231 @@ fn generate_iso_track(
         generate_iso_data_with_crc(sectordata, &mut encoder, None);
 
         // gap after the sector
-        generate_iso_gap(geometry.gap4_size as usize, 0x4e, &mut encoder);
+        generate_iso_gap(geometry.gap4_size as usize, geometry.gap_fill, &mut encoder);
     }
     // end the track
-    generate_iso_gap(geometry.gap5_size as usize, 0x4e, &mut encoder);
+    generate_iso_gap(geometry.gap5_size as usize, geometry.gap_fill, &mut encoder);
 
     Ok(trackbuf)
 }
 
+/// Places the first sector header at `position` ticks after the index by sizing gap1.
+/// The position refers to the address mark which follows gap2 and three sync words.
+/// The index address mark is dropped if the first sector is too close to the index for it.
+/// If the sectors move away from the index, gap4 is shrunk to keep the length of the track.
+fn place_first_sector(geometry: &mut IsoGeometry, position: usize, cellsize: i32) {
+    // The index address mark takes gap2, three sync words and the mark itself
+    let index_address_mark_size = geometry.gap2_size + 4 + ISO_IAM_GAP_SIZE as i32;
+    let lead_in_size = |geometry: &IsoGeometry| {
+        geometry.gap1_size
+            + if geometry.index_address_mark {
+                index_address_mark_size
+            } else {
+                0
+            }
+    };
+    let original_lead_in = lead_in_size(geometry);
+
+    // Every byte takes 16 cells
+    let bytes_before_mark = i32::try_from(position).unwrap_or(i32::MAX) / (16 * cellsize);
+    let lead_in = (bytes_before_mark - geometry.gap2_size - 4).max(0);
+
+    if geometry.index_address_mark && lead_in >= index_address_mark_size {
+        geometry.gap1_size = lead_in - index_address_mark_size;
+    } else {
+        geometry.index_address_mark = false;
+        geometry.gap1_size = lead_in;
+    }
+
+    let grown = lead_in_size(geometry) - original_lead_in;
+    if grown > 0 {
+        let sectors = i32::try_from(geometry.sectors_per_track.max(1)).unwrap_or(i32::MAX);
+        geometry.gap4_size = (geometry.gap4_size - (grown + sectors - 1) / sectors).max(1);
+    }
+}
+
+// The sum of all big endian words of an Atari ST boot sector must be equal to this value
+// to make the TOS execute it.
+const ATARI_BOOT_CHECKSUM: u16 = 0x1234;
+
+/// Alters the last word of the boot sector to make an Atari ST boot from it.
+pub fn make_atari_boot_sector_bootable(boot_sector: &mut [u8]) -> anyhow::Result<()> {
+    ensure!(boot_sector.len() == BYTES_PER_SECTOR);
+
+    // Sum of all words but the last one, which is the one we change.
+    let sum = boot_sector
+        .chunks_exact(2)
+        .take(BYTES_PER_SECTOR / 2 - 1)
+        .filter_map(|word| word.try_into().ok())
+        .map(u16::from_be_bytes)
+        .fold(0_u16, u16::wrapping_add);
+
+    let checksum_word = ATARI_BOOT_CHECKSUM.wrapping_sub(sum);
+    ensure_index_mut!(boot_sector[BYTES_PER_SECTOR - 2..BYTES_PER_SECTOR])
+        .copy_from_slice(&checksum_word.to_be_bytes());
+
+    Ok(())
+}
+
+/// PC 5.25" double density disks have 40 cylinders with 8 or 9 sectors.
+/// Everything else, including every Atari ST image, is assumed to be a 3.5" disk.
+const fn disk_type_of_geometry(
+    cylinders: usize,
+    sectors_per_track: usize,
+    atari_st: bool,
+) -> DiskType {
+    if !atari_st && cylinders <= 42 && sectors_per_track <= 9 {
+        DiskType::Inch5_25
+    } else {
+        DiskType::Inch3_5
+    }
+}
+
+/// High density is assumed for tracks with many sectors.
+const fn density_of_track(sectors_per_track: usize) -> Density {
+    if sectors_per_track >= 15 {
+        Density::High
+    } else {
+        Density::SingleDouble
+    }
+}
+
+/// Double density on a 5.25" drive with 360 RPM requires a higher data rate
+/// to get the same number of cells per rotation as with 300 RPM.
+const fn cell_size_of_track(density: Density, disk_type: DiskType) -> i32 {
+    match (density, disk_type) {
+        (Density::High, _) => 84,
+        (Density::SingleDouble, DiskType::Inch5_25) => 140,
+        (Density::SingleDouble, DiskType::Inch3_5) => 168,
+    }
+}
+
 pub fn parse_iso_image(path: &str) -> anyhow::Result<RawImage> {
+    parse_iso_image_with_options(path, false, None, None, None, false)
+}
+
+/// Like `parse_iso_image` but allows to make the first sector
+/// bootable for the Atari ST by correcting the checksum.
+/// This alters the data and shall only be used on purpose.
+/// A preset replaces the guessed geometry and layout of the image.
+/// The gap fill replaces the value of the gaps which are not in front of an address mark.
+/// The sector IDs replace the numbering of the sectors from 1.
+/// A sector order stored next to the image during reading is applied to the tracks.
+pub fn parse_iso_image_with_options(
+    path: &str,
+    atari_boot: bool,
+    preset: Option<IsoPreset>,
+    gap_fill: Option<u8>,
+    sector_ids: Option<Vec<u8>>,
+    index_timing: bool,
+) -> anyhow::Result<RawImage> {
     println!("Reading ISO image from {path} ...");
 
     let mut f = File::open(path)?;
     let metadata = fs::metadata(path)?;
 
-    let (cylinders, sectors_per_track) = calculate_floppy_geometry(metadata.len() as usize)?;
+    let (cylinders, heads, mut geometry, bytes_per_sector, disk_type, density) = match preset {
+        Some(preset) => {
+            let (cylinders, heads, sectors_per_track) = preset.layout(metadata.len() as usize)?;
+            println!("{preset:?} disk has {cylinders} cylinders, {heads} heads and {sectors_per_track} sectors!");
+            (
+                cylinders,
+                heads,
+                preset.geometry(sectors_per_track),
+                preset.bytes_per_sector(),
+                preset.disk_type(),
+                preset.density(),
+            )
+        }
+        None => {
+            let atari_st = is_atari_st_image(path);
+            let (cylinders, heads, sectors_per_track) =
+                calculate_floppy_geometry(metadata.len() as usize, atari_st)?;
+            (
+                cylinders,
+                heads,
+                IsoGeometry::new(sectors_per_track),
+                BYTES_PER_SECTOR,
+                disk_type_of_geometry(cylinders, sectors_per_track, atari_st),
+                density_of_track(sectors_per_track),
+            )
+        }
+    };
 
-    let geometry = IsoGeometry::new(sectors_per_track);
+    if let Some(gap_fill) = gap_fill {
+        geometry.gap_fill = gap_fill;
+    }
+    if let Some(sector_ids) = sector_ids {
+        ensure!(
+            sector_ids.len() == geometry.sectors_per_track,
+            "{} sector IDs given for the {} sectors per track of the image",
+            sector_ids.len(),
+            geometry.sectors_per_track
+        );
+        geometry.sector_ids = Some(sector_ids);
+    }
 
-    let (cellsize, density) = if sectors_per_track >= 15 {
-        (84, Density::High)
-    } else {
-        (168, Density::SingleDouble)
-    };
+    let cellsize = cell_size_of_track(density, disk_type);
 
     let mut buffer = vec![0; metadata.len() as usize];
 
     let bytes_read = f.read(&mut buffer)?;
     ensure!(bytes_read == metadata.len() as usize);
 
-    let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR);
+    if atari_boot {
+        println!("Correct boot sector checksum for Atari ST");
+        make_atari_boot_sector_bootable(&mut ensure_index_mut!(buffer[0..BYTES_PER_SECTOR]))?;
+    }
+
+    // An order recorded while reading the disk reproduces its interleave
+    let order_path = SectorOrder::path_for_image(Path::new(path));
+    let sector_order = if order_path.is_file() {
+        let sector_order = SectorOrder::load(&order_path)?;
+        println!(
+            "Sector order of {} tracks is taken from {}. Remove it to write the default order.",
+            sector_order.number_of_tracks(),
+            order_path.display()
+        );
+        Some(sector_order)
+    } else {
+        None
+    };
+    ensure!(
+        !index_timing || sector_order.is_some(),
+        "The index timing is taken from {} which is missing",
+        order_path.display()
+    );
+    let (gap1_size, gap4_size, index_address_mark) = (
+        geometry.gap1_size,
+        geometry.gap4_size,
+        geometry.index_address_mark,
+    );
+
+    let mut sectors = buffer.chunks_exact(bytes_per_sector);
     let mut tracks: Vec<RawTrack> = Vec::new();
 
     for cylinder in 0..cylinders {
-        for head in 0..HEADS {
-            let trackbuf =
-                generate_iso_track(cylinder as u32, head as u32, &geometry, &mut sectors)?;
+        for head in 0..heads {
+            let cylinder = cylinder as u32;
+            let head = head as u32;
+
+            geometry.gap1_size = gap1_size;
+            geometry.gap4_size = gap4_size;
+            geometry.index_address_mark = index_address_mark;
+            if let Some(position) = sector_order
+                .as_ref()
+                .filter(|_| index_timing)
+                .and_then(|f| f.first_position(cylinder, head))
+            {
+                place_first_sector(&mut geometry, position, cellsize);
+            }
+
+            let trackbuf = match sector_order.as_ref().and_then(|f| f.track(cylinder, head)) {
+                Some(order) => {
+                    generate_iso_track_in_order(cylinder, head, &geometry, &mut sectors, &order)?
+                }
+                None => generate_iso_track(cylinder, head, &geometry, &mut sectors)?,
+            };
 
             let densitymap = vec![DensityMapEntry {
                 number_of_cellbytes: trackbuf.len(),
@@ -302,8 +723,8 @@ pub fn parse_iso_image(path: &str) -> anyhow::Result<RawImage> {
             }];
 
             tracks.push(RawTrack::new(
-                cylinder as u32,
-                head as u32,
+                cylinder,
+                head,
                 trackbuf,
                 densitymap,
                 util::Encoding::MFM,
@@ -313,7 +734,436 @@ pub fn parse_iso_image(path: &str) -> anyhow::Result<RawImage> {
 
     Ok(RawImage {
         tracks,
-        disk_type: util::DiskType::Inch3_5,
+        disk_type,
         density,
     })
 }
+
+/// Encodes sectors which were generated instead of read from a file.
+/// The geometry is guessed from the size like for PC images.
+pub fn iso_image_from_data(data: &[u8]) -> anyhow::Result<RawImage> {
+    let (cylinders, heads, sectors_per_track) = calculate_floppy_geometry(data.len(), false)?;
+    let geometry = IsoGeometry::new(sectors_per_track);
+    let disk_type = disk_type_of_geometry(cylinders, sectors_per_track, false);
+    let density = density_of_track(sectors_per_track);
+    let cellsize = cell_size_of_track(density, disk_type);
+
+    let mut sectors = data.chunks_exact(BYTES_PER_SECTOR);
+    let mut tracks: Vec<RawTrack> = Vec::new();
+
+    for cylinder in 0..cylinders as u32 {
+        for head in 0..heads as u32 {
+            let trackbuf = generate_iso_track(cylinder, head, &geometry, &mut sectors)?;
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(cellsize),
+            }];
+            tracks.push(RawTrack::new(
+                cylinder,
+                head,
+                trackbuf,
+                densitymap,
+                util::Encoding::MFM,
+            ));
+        }
+    }
+
+    Ok(RawImage {
+        tracks,
+        disk_type,
+        density,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msx_preset_test() {
+        let preset = IsoPreset::from_name("msx").unwrap();
+        assert_eq!(preset.layout(737_280).unwrap(), (80, 2, 9));
+        assert_eq!(preset.layout(368_640).unwrap(), (80, 1, 9));
+        assert!(preset.layout(1_474_560).is_err());
+        // A truncated single sided image
+        assert!(matches!(
+            ToolError::from(preset.layout(368_000).unwrap_err()),
+            ToolError::TruncatedImage {
+                expected: 368_640,
+                ..
+            }
+        ));
+
+        // Every sector is placed once, even if the step divides the number of sectors
+        assert_eq!(
+            generate_interleaving_table(10, 1).unwrap(),
+            [0, 5, 1, 6, 2, 7, 3, 8, 4, 9]
+        );
+        assert_eq!(
+            generate_interleaving_table(9, 1).unwrap(),
+            [0, 5, 1, 6, 2, 7, 3, 8, 4]
+        );
+
+        // MSX-DOS writes the sectors in order
+        let geometry = preset.geometry(9);
+        assert_eq!(
+            generate_interleaving_table(9, geometry.interleaving as usize).unwrap(),
+            (0..9).collect::<Vec<_>>()
+        );
+
+        // Every sector takes 658 bytes with its gaps. Every byte are 16 cells.
+        // The index address mark takes 66 bytes with its gaps.
+        let sectors = vec![0xe5; 9 * 512];
+        let trackbuf = generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+        assert_eq!(trackbuf.len() * 8 / 16, 80 + 66 + 9 * 658 + 34);
+    }
+
+    #[test]
+    fn sector_ids_test() {
+        use crate::track_parser::{iso::IsoTrackParser, TrackParser};
+
+        // Data disks of the Amstrad CPC number their sectors from 0xC1
+        let geometry = IsoGeometry {
+            sector_ids: Some((0xc1..=0xc9).collect()),
+            ..IsoGeometry::new(9)
+        };
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf = generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let track = RawTrack::new(0, 0, trackbuf, densitymap, util::Encoding::MFM);
+
+        let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        parser.expect_track(0, 0);
+        let payload = parser
+            .parse_raw_track(&track.simulate_read(2).unwrap())
+            .unwrap();
+        assert_eq!(payload.payload, sectors);
+        let ids: Vec<u32> = payload.sectors.iter().map(|f| f.index()).collect();
+        assert_eq!(ids, (0xc1..=0xc9).collect::<Vec<u32>>());
+
+        // Every sector needs an ID
+        let geometry = IsoGeometry {
+            sector_ids: Some(vec![1, 2, 3]),
+            ..IsoGeometry::new(9)
+        };
+        assert!(generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).is_err());
+
+        // The IDs are checked against the geometry of the image before any track is generated
+        let path = std::env::temp_dir().join("sector_ids_test.st");
+        fs::write(&path, vec![0; 80 * 2 * 9 * 512]).unwrap();
+        let path = path.to_str().unwrap();
+        let parse =
+            |sector_ids| parse_iso_image_with_options(path, false, None, None, sector_ids, false);
+        assert!(parse(Some(vec![1, 2, 3])).is_err());
+        let image = parse(Some((0xc1..=0xc9).collect())).unwrap();
+        assert_eq!(image.tracks.len(), 160);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn place_first_sector_test() {
+        let byte = 16 * 168;
+
+        // Address mark after 100 bytes of gap1, 12 bytes of gap2 and 3 sync words.
+        // The 40 additional bytes are taken from gap4 of the 9 sectors.
+        let mut geometry = IsoGeometry::new(9);
+        place_first_sector(&mut geometry, 116 * byte, 168);
+        assert_eq!(geometry.gap1_size, 100);
+        assert_eq!(geometry.gap4_size, 40 - 5);
+        assert!(!geometry.index_address_mark);
+
+        // The index address mark is kept if there is enough space in front of the sector
+        let mut geometry = IsoGeometry::new(9);
+        geometry.index_address_mark = true;
+        place_first_sector(&mut geometry, 116 * byte + byte / 2, 168);
+        assert_eq!(geometry.gap1_size, 100 - 66);
+        assert_eq!(geometry.gap4_size, 40);
+        assert!(geometry.index_address_mark);
+
+        // Moving the sectors towards the index keeps gap4
+        let mut geometry = IsoGeometry::new(9);
+        geometry.index_address_mark = true;
+        place_first_sector(&mut geometry, 50 * byte, 168);
+        assert_eq!(geometry.gap1_size, 34);
+        assert_eq!(geometry.gap4_size, 40);
+        assert!(!geometry.index_address_mark);
+    }
+
+    #[test]
+    fn index_timing_roundtrip_test() {
+        use crate::track_parser::{iso::IsoTrackParser, TrackParser};
+
+        let sectors: Vec<u8> = (0..18 * 512).map(|f| (f % 253) as u8).collect();
+        let read_track = |geometry: &IsoGeometry| {
+            let trackbuf =
+                generate_iso_track(0, 0, geometry, &mut sectors.chunks_exact(512)).unwrap();
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(84),
+            }];
+            let track = RawTrack::new(0, 0, trackbuf, densitymap, util::Encoding::MFM);
+            assert!(track.assert_fits_into_rotation(util::DRIVE_3_5_RPM).is_ok());
+
+            let mut parser = IsoTrackParser::new(Some(18), Density::High);
+            parser.expect_track(0, 0);
+            let payload = parser
+                .parse_raw_track(&track.simulate_read(1).unwrap())
+                .unwrap();
+            assert_eq!(payload.payload, sectors);
+            let mut sector_order = SectorOrder::default();
+            assert!(sector_order.insert(&payload));
+            (
+                track.raw_data.len(),
+                sector_order.first_position(0, 0).unwrap(),
+            )
+        };
+
+        // Read the position of a disk with a late first sector and write it again
+        let byte = 16 * 84;
+        let standard = IsoGeometry::new(18);
+        let (standard_length, standard_position) = read_track(&standard);
+        let mut original = IsoGeometry::new(18);
+        original.gap1_size += 200;
+        let (_, recorded_position) = read_track(&original);
+
+        let mut geometry = IsoGeometry::new(18);
+        place_first_sector(&mut geometry, recorded_position, 84);
+        let (length, position) = read_track(&geometry);
+        // Reading rounds the pulses which lengthens the measurement by about one percent
+        assert!(position.abs_diff(recorded_position) < recorded_position / 50);
+        assert!(position.abs_diff(standard_position) > 190 * byte);
+        // gap4 takes the additional bytes in front of the first sector
+        assert!(length <= standard_length + 2 * 18);
+    }
+
+    #[test]
+    fn index_address_mark_test() {
+        // C2 C2 C2 with missing clock bits followed by FC
+        let index_address_mark = [0x52, 0x24, 0x52, 0x24, 0x52, 0x24, 0x55, 0x52];
+        let contains_mark = |trackbuf: &[u8]| trackbuf.windows(8).any(|f| f == index_address_mark);
+
+        let sectors = vec![0xe5; 9 * 512];
+        let mut geometry = IsoGeometry::new(9);
+        let trackbuf = generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+        assert!(!contains_mark(&trackbuf));
+
+        geometry.index_address_mark = true;
+        let trackbuf = generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+        // Directly after gap1 and the zeros of gap2
+        let position = 2 * (geometry.gap1_size + geometry.gap2_size) as usize;
+        assert_eq!(
+            trackbuf.get(position..position + 8),
+            Some(&index_address_mark[..])
+        );
+        assert!(contains_mark(&trackbuf));
+    }
+
+    #[test]
+    fn sf7000_preset_test() {
+        let preset = IsoPreset::from_name("sf7000").unwrap();
+        assert_eq!(preset.layout(163_840).unwrap(), (40, 1, 16));
+
+        let sectors = vec![0xe5; 16 * 256];
+        let geometry = preset.geometry(16);
+        let trackbuf = generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(256)).unwrap();
+        // Every sector takes 372 bytes with its gaps
+        assert_eq!(trackbuf.len() * 8 / 16, 60 + 16 * 372 + 100);
+
+        // 16 sectors are still double density and not high density
+        let path = std::env::temp_dir().join("sf7000_preset_test.sf7");
+        std::fs::write(&path, vec![0xe5; 163_840]).unwrap();
+        let image = parse_iso_image_with_options(
+            path.to_str().unwrap(),
+            false,
+            Some(preset),
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.density, Density::SingleDouble);
+        assert_eq!(image.disk_type, DiskType::Inch3_5);
+        assert_eq!(image.tracks.len(), 40);
+        for track in &image.tracks {
+            assert!(track
+                .densitymap
+                .iter()
+                .all(|f| f.cell_size == PulseDuration(168)));
+            assert!(track.assert_fits_into_rotation(util::DRIVE_3_5_RPM).is_ok());
+        }
+    }
+
+    #[test]
+    fn gap_fill_test() {
+        let sectors = vec![0xe5; 9 * 512];
+        let gap1 = |trackbuf: &[u8], geometry: &IsoGeometry| {
+            trackbuf
+                .get(..2 * geometry.gap1_size as usize)
+                .unwrap()
+                .to_vec()
+        };
+
+        let standard = IsoGeometry::new(9);
+        let standard_track =
+            generate_iso_track(0, 0, &standard, &mut sectors.chunks_exact(512)).unwrap();
+
+        for gap_fill in [0x00, 0xff, 0xaa, 0x01] {
+            let mut geometry = IsoGeometry::new(9);
+            geometry.gap_fill = gap_fill;
+            let trackbuf =
+                generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+            assert_eq!(trackbuf.len(), standard_track.len());
+            assert_ne!(gap1(&trackbuf, &geometry), gap1(&standard_track, &standard));
+
+            // MFM never places two flux reversals next to each other, regardless of the value
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(168),
+            }];
+            let track = RawTrack::new(0, 0, trackbuf, densitymap, util::Encoding::MFM);
+            track.check_writability().unwrap();
+        }
+    }
+
+    #[test]
+    fn image_from_data_test() {
+        let image = iso_image_from_data(&vec![0; 1440 * BYTES_PER_SECTOR]).unwrap();
+        assert_eq!(image.tracks.len(), 160);
+        assert_eq!(image.density, Density::SingleDouble);
+        assert_eq!(image.disk_type, DiskType::Inch3_5);
+        let track = image.tracks.last().unwrap();
+        assert_eq!((track.cylinder, track.head), (79, 1));
+        assert!(track.assert_fits_into_rotation(util::DRIVE_3_5_RPM).is_ok());
+
+        let image = iso_image_from_data(&vec![0; 2880 * BYTES_PER_SECTOR]).unwrap();
+        assert_eq!(image.density, Density::High);
+        assert!(iso_image_from_data(&[0; 1000]).is_err());
+    }
+
+    #[test]
+    fn pc_5_25_geometry_test() {
+        assert_eq!(
+            calculate_floppy_geometry(163_840, false).unwrap(),
+            (40, 1, 8)
+        );
+        assert_eq!(
+            calculate_floppy_geometry(184_320, false).unwrap(),
+            (40, 1, 9)
+        );
+        assert_eq!(
+            calculate_floppy_geometry(327_680, false).unwrap(),
+            (40, 2, 8)
+        );
+        assert_eq!(
+            calculate_floppy_geometry(368_640, false).unwrap(),
+            (40, 2, 9)
+        );
+
+        // 180K is single sided
+        let image = iso_image_from_data(&vec![0; 184_320]).unwrap();
+        assert_eq!(image.tracks.len(), 40);
+        assert!(image.tracks.iter().all(|f| f.head == 0));
+        assert_eq!(image.disk_type, DiskType::Inch5_25);
+        assert_eq!(image.density, Density::SingleDouble);
+
+        // 360K must fit into a rotation of a 5.25" drive
+        let image = iso_image_from_data(&vec![0; 368_640]).unwrap();
+        assert_eq!(image.tracks.len(), 80);
+        assert_eq!(image.disk_type, DiskType::Inch5_25);
+        for track in &image.tracks {
+            assert!(track
+                .assert_fits_into_rotation(util::DRIVE_5_25_RPM)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn atari_st_geometry_test() {
+        // Single sided ST disks have the size of a PC 360K disk
+        assert_eq!(
+            calculate_floppy_geometry(368_640, true).unwrap(),
+            (80, 1, 9)
+        );
+        assert_eq!(
+            calculate_floppy_geometry(409_600, true).unwrap(),
+            (80, 1, 10)
+        );
+        assert_eq!(
+            calculate_floppy_geometry(737_280, true).unwrap(),
+            (80, 2, 9)
+        );
+        // The 5.25" geometries are not used for ST images
+        assert!(calculate_floppy_geometry(184_320, true).is_err());
+
+        let path = std::env::temp_dir().join("atari_st_geometry_test.st");
+        fs::write(&path, vec![0; 368_640]).unwrap();
+        let image = parse_iso_image(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(image.tracks.len(), 80);
+        assert!(image.tracks.iter().all(|f| f.head == 0));
+        assert_eq!(image.disk_type, DiskType::Inch3_5);
+        assert_eq!(
+            image
+                .tracks
+                .first()
+                .unwrap()
+                .densitymap
+                .first()
+                .unwrap()
+                .cell_size
+                .0,
+            168
+        );
+
+        // The same size as .img is still a PC 360K disk
+        let path = std::env::temp_dir().join("atari_st_geometry_test.img");
+        fs::write(&path, vec![0; 368_640]).unwrap();
+        let image = parse_iso_image(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(image.tracks.len(), 80);
+        assert_eq!(image.disk_type, DiskType::Inch5_25);
+    }
+
+    #[test]
+    fn truncated_image_test() {
+        assert_eq!(
+            calculate_floppy_geometry(737_280, false).unwrap(),
+            (80, 2, 9)
+        );
+        assert!(matches!(
+            ToolError::from(calculate_floppy_geometry(1_474_000, false).unwrap_err()),
+            ToolError::TruncatedImage {
+                expected: 1_474_560,
+                ..
+            }
+        ));
+        assert!(matches!(
+            ToolError::from(calculate_floppy_geometry(2_000_000, false).unwrap_err()),
+            ToolError::GeometryMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn atari_boot_sector_checksum_test() {
+        let mut boot_sector: Vec<u8> = (0..BYTES_PER_SECTOR).map(|x| x as u8).collect();
+        make_atari_boot_sector_bootable(&mut boot_sector).unwrap();
+
+        let sum = boot_sector
+            .chunks_exact(2)
+            .map(|word| u16::from_be_bytes(word.try_into().unwrap()))
+            .fold(0_u16, u16::wrapping_add);
+        assert_eq!(sum, ATARI_BOOT_CHECKSUM);
+
+        // Only the last word shall be altered
+        assert!(boot_sector
+            .iter()
+            .take(BYTES_PER_SECTOR - 2)
+            .enumerate()
+            .all(|(i, x)| *x == i as u8));
+    }
+}
@@ -0,0 +1,319 @@
+use std::convert::TryInto;
+use std::fs;
+
+use anyhow::{ensure, Context};
+use util::{
+    Density, DiskType, Encoding, PulseDuration, DRIVE_3_5_RPM, DRIVE_5_25_RPM, PULSE_REDUCE_SHIFT,
+    STM_TIMER_HZ,
+};
+
+use crate::rawtrack::{RawImage, RawTrack};
+
+// info from https://www.cbmstuff.com/downloads/scp/scp_image_specs.txt
+
+/// Resolution of the flux timings if the header doesn't select a lower one
+const SCP_TICK_SECONDS: f64 = 25e-9;
+const SCP_HEADER_SIZE: usize = 0x10;
+/// The track offset table doesn't start at the header but later
+const SCP_FLAG_EXTENDED: u8 = 1 << 6;
+const SCP_FLAG_RPM_360: u8 = 1 << 2;
+/// The Commodore 64 is the only system of this format using GCR
+const SCP_DISK_TYPE_C64: u8 = 0x00;
+
+/// The flux of a revolution is cut to leave room for drives which are a bit faster
+const ROTATION_USAGE_PERCENT: f64 = 98.0;
+/// Double density flux timings are usually longer than this
+const HIGH_DENSITY_MEDIAN_PULSE: i32 = 300;
+/// Longest flux timing which can be transferred to the device in its reduced form
+const MAX_PULSE: i32 = (u8::MAX as i32) << PULSE_REDUCE_SHIFT;
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    Ok(u32::from_le_bytes(
+        ensure_index!(data[offset..offset + 4]).try_into()?,
+    ))
+}
+
+/// Converts the flux of the first revolution of a track.
+/// A value of zero marks an overflow which adds to the next value.
+/// Also provides the duration of the revolution in STM timer ticks if it was recorded.
+fn flux_timings_of_track(
+    data: &[u8],
+    offset: usize,
+    stm_ticks_per_scp_tick: f64,
+) -> anyhow::Result<(Vec<PulseDuration>, Option<f64>)> {
+    ensure!(
+        ensure_index!(data[offset..offset + 3]) == *b"TRK",
+        "Track header at offset {offset} not found"
+    );
+    let revolution = offset + 4;
+    // The index time is always given in the default resolution
+    let index_time = read_u32(data, revolution)?;
+    let rotation_ticks =
+        (index_time > 0).then(|| f64::from(index_time) * SCP_TICK_SECONDS * STM_TIMER_HZ);
+    let number_of_timings = read_u32(data, revolution + 4)? as usize;
+    let data_offset = offset + read_u32(data, revolution + 8)? as usize;
+
+    let mut flux_timings = Vec::with_capacity(number_of_timings);
+    let mut overflow = 0;
+    for value in ensure_index!(data[data_offset..data_offset + 2 * number_of_timings]).chunks(2) {
+        let value = u32::from(u16::from_be_bytes(value.try_into()?));
+        if value == 0 {
+            overflow += 0x1_0000;
+            continue;
+        }
+        let ticks = f64::from(overflow + value) * stm_ticks_per_scp_tick;
+        flux_timings.push(PulseDuration(ticks.round() as i32));
+        overflow = 0;
+    }
+    Ok((flux_timings, rotation_ticks))
+}
+
+/// Splits flux timings which are too long to be transferred into shorter ones of the same
+/// total duration. Such long times without flux reversals are written as weak bits anyway.
+fn split_long_pulses(flux_timings: &[PulseDuration]) -> Vec<PulseDuration> {
+    let mut result = Vec::with_capacity(flux_timings.len());
+    for pulse in flux_timings {
+        let parts = (pulse.0 + MAX_PULSE - 1) / MAX_PULSE;
+        if parts <= 1 {
+            result.push(*pulse);
+            continue;
+        }
+        // Distribute the remainder to keep the total duration
+        let (part, remainder) = (pulse.0 / parts, pulse.0 % parts);
+        result.extend((0..parts).map(|f| PulseDuration(part + i32::from(f < remainder))));
+    }
+    result
+}
+
+fn median_pulse(flux_timings: &[PulseDuration]) -> Option<i32> {
+    let mut pulses: Vec<i32> = flux_timings.iter().map(|f| f.0).collect();
+    pulses.sort_unstable();
+    pulses.get(pulses.len() / 2).copied()
+}
+
+pub fn parse_scp_image(path: &str) -> anyhow::Result<RawImage> {
+    println!("Reading SCP from {path} ...");
+    let data = fs::read(path).context("no file found")?;
+    parse_scp_data(&data)
+}
+
+/// Every track is written with the flux timings of its first revolution.
+/// The encoding of the image is neither decoded nor generated again.
+fn parse_scp_data(data: &[u8]) -> anyhow::Result<RawImage> {
+    ensure!(
+        ensure_index!(data[0..3]) == *b"SCP",
+        "SCP File not in expected format!"
+    );
+    let scp_disk_type = ensure_index!(data[4]);
+    let revolutions = ensure_index!(data[5]);
+    let start_track = ensure_index!(data[6]) as usize;
+    let end_track = ensure_index!(data[7]) as usize;
+    let flags = ensure_index!(data[8]);
+    let cell_width = ensure_index!(data[9]);
+    let heads = ensure_index!(data[0xa]);
+    let resolution = ensure_index!(data[0xb]);
+    let checksum = read_u32(data, 0xc)?;
+
+    ensure!(revolutions > 0, "SCP File without revolutions");
+    ensure!(
+        flags & SCP_FLAG_EXTENDED == 0,
+        "Extended SCP files are not supported"
+    );
+    ensure!(
+        cell_width == 0 || cell_width == 16,
+        "Flux timings with {cell_width} bits are not supported"
+    );
+    if checksum != 0 {
+        let sum = ensure_index!(data[SCP_HEADER_SIZE..])
+            .iter()
+            .fold(0u32, |sum, f| sum.wrapping_add(u32::from(*f)));
+        ensure!(sum == checksum, "Checksum of SCP File is wrong");
+    }
+
+    let stm_ticks_per_scp_tick = SCP_TICK_SECONDS * (f64::from(resolution) + 1.0) * STM_TIMER_HZ;
+    let (disk_type, rpm) = if scp_disk_type == SCP_DISK_TYPE_C64 || flags & SCP_FLAG_RPM_360 != 0 {
+        (DiskType::Inch5_25, DRIVE_5_25_RPM)
+    } else {
+        (DiskType::Inch3_5, DRIVE_3_5_RPM)
+    };
+    let encoding = if scp_disk_type == SCP_DISK_TYPE_C64 {
+        Encoding::GCR
+    } else {
+        Encoding::MFM
+    };
+    let drive_rotation = STM_TIMER_HZ * 60.0 / rpm;
+    let max_duration = drive_rotation * ROTATION_USAGE_PERCENT / 100.0;
+
+    let mut tracks: Vec<RawTrack> = Vec::new();
+
+    for track_number in start_track..=end_track {
+        let offset = read_u32(data, SCP_HEADER_SIZE + 4 * track_number)? as usize;
+        // Tracks which were not recorded have no offset
+        if offset == 0 {
+            continue;
+        }
+
+        let (cylinder, head) = match heads {
+            0 => (track_number / 2, track_number % 2),
+            1 => (track_number, 0),
+            _ => (track_number, 1),
+        };
+
+        let (flux_timings, recorded_rotation) =
+            flux_timings_of_track(data, offset, stm_ticks_per_scp_tick)
+                .with_context(|| format!("Track {track_number} of SCP File is broken"))?;
+
+        // A disk recorded with a slower drive, e.g. a 1541 with 300 RPM,
+        // is compressed to the rotation of the drive it is written with
+        let mut flux_timings = match recorded_rotation {
+            Some(recorded_rotation) if recorded_rotation > drive_rotation => {
+                let factor = drive_rotation / recorded_rotation;
+                flux_timings
+                    .iter()
+                    .map(|f| PulseDuration((f64::from(f.0) * factor).round() as i32))
+                    .collect()
+            }
+            _ => flux_timings,
+        };
+        flux_timings = split_long_pulses(&flux_timings);
+
+        // The revolution starts at the index. Cut its end as it overlaps with the start
+        let mut duration = 0.0;
+        let fitting = flux_timings
+            .iter()
+            .take_while(|f| {
+                duration += f64::from(f.0);
+                duration < max_duration
+            })
+            .count();
+        flux_timings.truncate(fitting);
+
+        tracks.push(RawTrack::new_with_flux_timings(
+            cylinder as u32,
+            head as u32,
+            flux_timings,
+            encoding,
+        ));
+    }
+
+    let density = match tracks
+        .first()
+        .and_then(|f| median_pulse(f.flux_timings.as_ref()?))
+    {
+        Some(median) if median < HIGH_DENSITY_MEDIAN_PULSE => Density::High,
+        _ => Density::SingleDouble,
+    };
+
+    Ok(RawImage {
+        tracks,
+        density,
+        disk_type,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_reader::assert_truncation_rejected;
+
+    const SCP_REVOLUTION_ENTRY_SIZE: usize = 12;
+
+    fn scp_file(disk_type: u8, index_time: u32, tracks: &[Vec<u16>]) -> Vec<u8> {
+        let mut data = b"SCP".to_vec();
+        data.extend([0x19, disk_type, 1, 0, tracks.len() as u8 - 1, 0, 0, 0, 0]);
+        data.extend([0; 4]);
+        data.resize(SCP_HEADER_SIZE + 4 * 168, 0);
+
+        for (track_number, flux) in tracks.iter().enumerate() {
+            let offset = data.len();
+            let table_entry = SCP_HEADER_SIZE + 4 * track_number;
+            data.get_mut(table_entry..table_entry + 4)
+                .unwrap()
+                .copy_from_slice(&(offset as u32).to_le_bytes());
+
+            data.extend(b"TRK");
+            data.push(track_number as u8);
+            data.extend(index_time.to_le_bytes());
+            data.extend((flux.len() as u32).to_le_bytes());
+            data.extend(((4 + SCP_REVOLUTION_ENTRY_SIZE) as u32).to_le_bytes());
+            data.extend(flux.iter().flat_map(|f| f.to_be_bytes()));
+        }
+
+        let sum = data
+            .get(SCP_HEADER_SIZE..)
+            .unwrap()
+            .iter()
+            .fold(0u32, |sum, f| sum.wrapping_add(u32::from(*f)));
+        data.get_mut(0xc..0x10)
+            .unwrap()
+            .copy_from_slice(&sum.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn scp_parse_test() {
+        // 4 µs between two flux reversals, more than a rotation long
+        let mut flux = vec![0x0000, 0x00a0];
+        flux.extend([160; 60000]);
+        let data = scp_file(0x04, 0, &[flux.clone(), flux]);
+
+        let image = parse_scp_data(&data).unwrap();
+        assert_eq!(image.tracks.len(), 2);
+        assert!(matches!(image.disk_type, DiskType::Inch3_5));
+        assert_eq!(image.density, Density::SingleDouble);
+
+        let track = image.tracks.get(1).unwrap();
+        assert_eq!((track.cylinder, track.head), (0, 1));
+        // The overflow is split into pulses which can be transferred
+        let flux_timings = track.flux_timings.as_ref().unwrap();
+        let long_pulse = flux_timings.get(..68).unwrap();
+        assert_eq!(long_pulse.iter().map(|f| f.0).sum::<i32>(), 137_962);
+        assert!(long_pulse.iter().all(|f| f.0 <= MAX_PULSE));
+        assert_eq!(flux_timings.get(68), Some(&PulseDuration(336)));
+        assert!(track.check_writability().is_ok());
+
+        // Cut to fit into a rotation of the drive
+        assert!(track.assert_fits_into_rotation(DRIVE_3_5_RPM).is_ok());
+        assert!(track.check_rotation_margin(DRIVE_3_5_RPM, 1.5).is_none());
+        assert!(flux_timings.len() > 48000);
+
+        let mut broken = data;
+        *broken.last_mut().unwrap() ^= 1;
+        assert!(parse_scp_data(&broken).is_err());
+    }
+
+    #[test]
+    fn c64_scp_test() {
+        // A revolution of a 1541 with 300 RPM. 3.25 µs between two flux reversals
+        // with a long gap without flux reversals at the end.
+        let mut flux = vec![130; 60_000];
+        flux.extend([0x0000, 0x2000]);
+        let data = scp_file(SCP_DISK_TYPE_C64, 8_000_000, &[flux.clone(), flux]);
+
+        let image = parse_scp_data(&data).unwrap();
+        assert!(matches!(image.disk_type, DiskType::Inch5_25));
+        for track in &image.tracks {
+            assert!(matches!(track.encoding, Encoding::GCR));
+            assert!(track.assert_fits_into_rotation(DRIVE_5_25_RPM).is_ok());
+            assert!(track.check_writability().is_ok());
+            assert!(track.reduced_flux_timings().is_ok());
+
+            // Compressed instead of cut. Only the end of the gap is lost.
+            let flux_timings = track.flux_timings.as_ref().unwrap();
+            assert!(flux_timings.len() > 60_000);
+            let compressed = f64::from(flux_timings.first().unwrap().0) / (130.0 * 2.1);
+            assert!((compressed - 300.0 / DRIVE_5_25_RPM).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn truncated_scp_test() {
+        let flux = vec![160; 1000];
+        let mut data = scp_file(0x04, 0, &[flux.clone(), flux]);
+        // Without checksum the truncation must be found by the parser itself
+        data.get_mut(0xc..0x10).unwrap().fill(0);
+        assert!(parse_scp_data(&data).is_ok());
+
+        assert_truncation_rejected("truncated_scp_test.scp", &data, data.len(), parse_scp_image);
+    }
+}
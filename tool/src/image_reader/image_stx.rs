@@ -4,11 +4,13 @@ use super::image_iso::{
 };
 use crate::image_reader::image_iso::{ISO_DAM, ISO_IDAM};
 use crate::rawtrack::{RawImage, RawTrack};
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
 use std::cell::RefCell;
+use std::convert::TryFrom;
 use std::fs::{self, File};
 use std::io::Cursor;
 use std::io::Read;
+use std::ops::RangeInclusive;
 use util::bitstream::BitStreamCollector;
 use util::mfm::{MfmEncoder, MfmWord, ISO_SYNC_BYTE};
 use util::{
@@ -23,13 +25,19 @@ use util::{
 const TRK_SYNC: u16 = 0x80; // track image header contains sync offset info
 const TRK_IMAGE: u16 = 0x40; // track record contains track image
 const _TRK_PROT: u16 = 0x20; // track contains protections ? not used?
-const TRK_SECT: u16 = 0x01; // track record contains sector descriptor
+pub(crate) const TRK_SECT: u16 = 0x01; // track record contains sector descriptor
 
 const _FDC_FLAG_FUZZY_MASK_RECORD: u8 = 1 << 7;
 const _FDC_FLAG_DELETED_DATA: u8 = 1 << 5;
 const FDC_FLAG_RECORD_NOT_FOUND: u8 = 1 << 4;
-const FDC_FLAG_CRC_ERROR: u8 = 1 << 3;
-const FDC_FLAG_INTRA_SECTOR_BIT_WIDTH_VARIATION: u8 = 1; // Macrodos / Speedlock
+pub(crate) const FDC_FLAG_CRC_ERROR: u8 = 1 << 3;
+pub(crate) const FDC_FLAG_INTRA_SECTOR_BIT_WIDTH_VARIATION: u8 = 1; // Macrodos / Speedlock
+
+const TIMING_RECORD_FLAGS: u16 = 5;
+/// Every timing value covers 16 data bytes which are 32 raw bytes on the track
+const TIMING_RAW_BYTES: usize = 32;
+/// Plausible timing values. 128 is the nominal value of a double density track.
+const TIMING_VALUE_RANGE: RangeInclusive<u16> = 96..=160;
 
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 
@@ -50,28 +58,116 @@ const gap2_size: usize = 3; // Minimal allowed preamble with 0x00 before sector
 const gap3a_size: usize = 22; // Minimal allowed gap between sector header and data (0x4E)
 const gap3b_size: usize = 12; // 12x 0x00 before actual data
 
-fn patch_discard_sector(sector: &StxSector, file_hash_str: &str) -> bool {
-    matches!(
-        (file_hash_str, sector.idam_sector),
-        ("4865957cd83562547a722c95e9a5421a", 16)
-    )
+/// Size of the gap between index and the preamble of the first sector header,
+/// to position the first sync mark at the offset stored in the STX file.
+/// The offset is provided in bytes relative to the index.
+fn lead_in_gap_size(first_sync_offset: usize) -> usize {
+    first_sync_offset.saturating_sub(gap2_size)
+}
+
+/// Offset in cell bytes between the bit positions of the STX file and the track buffer.
+/// The first sector is placed directly after the content of the track buffer, which might
+/// be an optional lead-in. A single sector is placed at its bit position.
+fn first_sector_offset(bit_position: usize, sector_count: usize, trackbuf_len: usize) -> usize {
+    if sector_count == 1 {
+        0
+    } else {
+        (bit_position / 4).saturating_sub(trackbuf_len)
+    }
+}
+
+/// Number of gap bytes in front of a sector to place it at its bit position.
+/// A bit position of the STX file is a data bit, which takes two cells.
+/// Four of them are a cell byte of the track buffer. A gap byte takes two cell bytes.
+/// Returns `None` if the sector overlaps with the previous one.
+fn dynamic_gap_size(bit_position: usize, offset: usize, trackbuf_len: usize) -> Option<usize> {
+    let mfm_word_position = bit_position / 4 - offset;
+    let dynamic_gap_size = (mfm_word_position as i32 - trackbuf_len as i32) / 2;
+    usize::try_from(dynamic_gap_size).ok()
+}
+
+/// A bit position of the STX file is a data bit. The data of a sector takes at least
+/// this many bits. Headers and gaps come on top.
+fn is_inside_sector(sector: &StxSector, previous: &StxSector) -> bool {
+    sector.bit_position < previous.bit_position + 8 * previous.sector_size
+}
+
+/// Removes every sector which starts inside the data of a preceding sector.
+/// Such a sector can't be generated on its own. Copy protections like the one
+/// of Turrican hide a sector inside another. The sectors must be sorted by bit position.
+/// Returns the discarded sectors with the index of the remaining sector containing them.
+fn discard_overlapping_sectors(sectors: &mut Vec<StxSector>) -> Vec<(usize, StxSector)> {
+    let mut kept: Vec<StxSector> = Vec::with_capacity(sectors.len());
+    let mut discarded = Vec::new();
+
+    for sector in sectors.drain(..) {
+        match kept.last() {
+            Some(previous) if is_inside_sector(&sector, previous) => {
+                discarded.push((kept.len() - 1, sector));
+            }
+            _ => kept.push(sector),
+        }
+    }
+
+    *sectors = kept;
+    discarded
 }
 
-fn patch_custom_sector<T>(
+/// Copy protection which is recognized by the structure of a track.
+/// STX files only contain the view of the floppy controller on the data.
+/// The original track must be reconstructed from this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProtectionProfile {
+    /// The header of a sector is hidden inside the data of another sector.
+    /// Reading the outer sector provides the clock bits of the data of the hidden one.
+    /// Known from Turrican.
+    HiddenSector { hidden_sector: u8 },
+}
+
+/// Turrican hides the header of sector 16 inside the data of sector 0.
+/// Both are sectors of 512 bytes on the same track.
+fn is_turrican_hidden_sector(outer: &StxSector, hidden: &StxSector) -> bool {
+    outer.idam_sector == 0
+        && hidden.idam_sector == 16
+        && outer.idam_size == 2
+        && hidden.idam_size == 2
+        && (outer.idam_track, outer.idam_head) == (hidden.idam_track, hidden.idam_head)
+}
+
+/// Assigns a protection profile to every remaining sector.
+/// Hidden sectors of an unknown structure get no profile.
+fn detect_protection_profiles(
+    sectors: &[StxSector],
+    discarded: &[(usize, StxSector)],
+) -> Vec<Option<ProtectionProfile>> {
+    sectors
+        .iter()
+        .enumerate()
+        .map(|(index, outer)| {
+            let mut hidden = discarded.iter().filter(|(outer, _)| *outer == index);
+            match (hidden.next(), hidden.next()) {
+                (Some((_, hidden)), None) if is_turrican_hidden_sector(outer, hidden) => {
+                    Some(ProtectionProfile::HiddenSector {
+                        hidden_sector: hidden.idam_sector,
+                    })
+                }
+                // Multiple hidden sectors are not known yet
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn generate_protected_sector<T>(
     sector: &StxSector,
-    file_hash_str: &str,
+    profile: ProtectionProfile,
     encoder: &mut MfmEncoder<T>,
     has_non_flux_reversal_area: &mut bool,
-) -> bool
-where
+) where
     T: FnMut(Bit),
 {
-    match (file_hash_str, sector.idam_sector) {
-        ("4865957cd83562547a722c95e9a5421a", 0) => {
-            // Ok, this is ugly.
-            // The copy protection of Turrican is not recorded in the STX file very well.
-            // STX files only contain the view on the data based on what the floppy controller has seen.
-            // We must reconstruct the original data here
+    match profile {
+        ProtectionProfile::HiddenSector { hidden_sector } => {
             // This is based on
             // https://info-coach.fr/atari/documents/_mydoc/Atari-Copy-Protection.pdf
             // https://github.com/sarnau/AtariSTCopyProtections/blob/master/protection_turrican.md
@@ -80,7 +176,7 @@ where
                 gap2_size,
                 sector.idam_track,
                 sector.idam_head,
-                0,
+                sector.idam_sector,
                 sector.idam_size,
                 encoder,
             );
@@ -93,20 +189,20 @@ where
                 16,
                 sector.idam_track,
                 sector.idam_head,
-                16,
+                hidden_sector,
                 sector.idam_size,
                 encoder,
             );
             generate_iso_gap(22, 0x4e, encoder);
 
-            // shift the data to allow reading data bits using sector 16
-            // and clock bits by reading sector 0.
+            // shift the data to allow reading data bits using the hidden sector
+            // and clock bits by reading the outer sector.
             // this is insane
             encoder.feed_raw_var(0x5555 >> 1, 15);
 
             generate_iso_data_header(11, encoder, None);
 
-            // actual data which is 0x00 in sector 16 but 0xff in sector 0
+            // actual data which is 0x00 in the hidden sector but 0xff in the outer one
             generate_iso_gap(16, 0x00, encoder);
             encoder.feed_raw_var(0xa000, 16);
 
@@ -121,9 +217,7 @@ where
             encoder.feed_raw8(0b1010_1010);
 
             *has_non_flux_reversal_area = true;
-            true
         }
-        _ => false, // No patch? Just return false to indicate that a normal sector shall be generated
     }
 }
 
@@ -141,10 +235,35 @@ fn total_number_raw_bytes_deviation_map(deviation_map: &[SectorTimingDeviation])
     deviation_map.iter().map(|f| f.number_of_raw_bytes).sum()
 }
 
-const SECTOR_DESCRIPTOR_SIZE: usize = 16;
-const TRACK_DESCRIPTOR_SIZE: usize = 16;
+pub(crate) const SECTOR_DESCRIPTOR_SIZE: usize = 16;
+pub(crate) const TRACK_DESCRIPTOR_SIZE: usize = 16;
+
+/// Tracks which exceed a rotation are shortened silently if the cells are
+/// scaled by more than this factor. The read times don't include the gaps.
+const SILENT_CORRECTION_FACTOR: f64 = 0.99;
+/// Tracks which need more correction are considered impossible.
+/// Only a lowered bound results into warnings.
+pub const DEFAULT_MIN_CORRECTION_FACTOR: f64 = SILENT_CORRECTION_FACTOR;
+/// Shortening by more than 20% is beyond the tolerance of drives and controllers
+const MIN_CORRECTION_FACTOR_RANGE: RangeInclusive<f64> = 0.8..=1.0;
 
 pub fn parse_stx_image(path: &str) -> anyhow::Result<RawImage> {
+    parse_stx_image_with_options(path, DEFAULT_MIN_CORRECTION_FACTOR)
+}
+
+/// Tracks which are too long for a rotation are shortened by scaling every cell.
+/// Scaling by less than `SILENT_CORRECTION_FACTOR` down to `min_correction_factor`
+/// is reported as warning. Tracks requiring more correction are rejected.
+pub fn parse_stx_image_with_options(
+    path: &str,
+    min_correction_factor: f64,
+) -> anyhow::Result<RawImage> {
+    ensure!(
+        MIN_CORRECTION_FACTOR_RANGE.contains(&min_correction_factor),
+        "Minimum correction factor {} is outside of {:?}",
+        min_correction_factor,
+        MIN_CORRECTION_FACTOR_RANGE
+    );
     println!("Reading STX from {path} ...");
 
     let mut f = File::open(path)?;
@@ -154,9 +273,6 @@ pub fn parse_stx_image(path: &str) -> anyhow::Result<RawImage> {
     let bytes_read = f.read(whole_file_buffer.as_mut())?;
     ensure!(bytes_read == metadata.len() as usize);
 
-    let file_hash = md5::compute(&whole_file_buffer);
-    let file_hash_str = format!("{file_hash:x}");
-
     ensure!(
         b"RSY\0".eq(&ensure_index!(whole_file_buffer[0..4])),
         "Is this really an STX / Pasti file?"
@@ -185,8 +301,8 @@ pub fn parse_stx_image(path: &str) -> anyhow::Result<RawImage> {
         let (optional_track, next_track_record_offset) = process_track_record(
             &whole_file_buffer,
             current_track_record_position,
-            &file_hash_str,
             revision,
+            min_correction_factor,
         )?;
 
         if let Some(track) = optional_track {
@@ -271,7 +387,10 @@ fn read_timing_record(optional_timing_record: &[u8]) -> anyhow::Result<Vec<f64>>
     let timing_record_size = timing_record_reader.read_u16::<LittleEndian>()? as usize;
     let timing_data_size = timing_record_size - 4;
 
-    ensure!(flags == 5, "Unexpected flags in timing descriptor");
+    ensure!(
+        flags == TIMING_RECORD_FLAGS,
+        "Unexpected flags in timing descriptor"
+    );
     ensure!(
         timing_record_size == optional_timing_record.len(),
         "Timing record sizes don't match!"
@@ -291,9 +410,72 @@ fn read_timing_record(optional_timing_record: &[u8]) -> anyhow::Result<Vec<f64>>
     Ok(timing_data)
 }
 
+/// Inverse of `read_timing_record`. Converts cell sizes to timing values.
+fn timing_value(cell_size_in_seconds: f64) -> u16 {
+    (cell_size_in_seconds * 64.0 / 1e-6).round() as u16
+}
+
+/// Converts the bit width profile of a sector to the timing of every 16 data bytes
+#[must_use]
+pub fn timing_deviation_from_profile(profile: &[PulseDuration]) -> Vec<SectorTimingDeviation> {
+    profile
+        .iter()
+        .map(|f| SectorTimingDeviation {
+            number_of_raw_bytes: TIMING_RAW_BYTES,
+            cell_size_in_seconds: f64::from(f.0) / STM_TIMER_HZ,
+        })
+        .collect()
+}
+
+/// Provides the timing values of a sector as they are stored in the timing record of an STX image
+#[must_use]
+pub fn timing_values(deviation: &[SectorTimingDeviation]) -> Vec<u16> {
+    deviation
+        .iter()
+        .map(|f| timing_value(f.cell_size_in_seconds))
+        .collect()
+}
+
+/// Provides the timing values of a sector for the timing record of an STX image.
+/// Fails if the profile doesn't cover the sector data or a value is implausible.
+pub fn sector_timing_values(
+    profile: &[PulseDuration],
+    sector_size: usize,
+) -> anyhow::Result<Vec<u16>> {
+    ensure!(
+        profile.len() * TIMING_RAW_BYTES == sector_size * 2,
+        "{} timing values don't cover a sector of {sector_size} bytes",
+        profile.len()
+    );
+
+    let values = timing_values(&timing_deviation_from_profile(profile));
+    if let Some(value) = values.iter().find(|f| !TIMING_VALUE_RANGE.contains(f)) {
+        bail!("Timing value {value} is outside of {TIMING_VALUE_RANGE:?}");
+    }
+    Ok(values)
+}
+
+/// Creates a timing record like it is embedded in STX images
+#[must_use]
+pub fn timing_record(deviation: &[SectorTimingDeviation]) -> Vec<u8> {
+    let values = timing_values(deviation);
+    let size = (4 + values.len() * 2) as u16;
+
+    let mut record = Vec::with_capacity(usize::from(size));
+    record.extend_from_slice(&TIMING_RECORD_FLAGS.to_le_bytes());
+    record.extend_from_slice(&size.to_le_bytes());
+    values
+        .iter()
+        .for_each(|f| record.extend_from_slice(&f.to_be_bytes()));
+    record
+}
+
+/// Provides the density map and the factor which was applied to the cells
+/// to fit the track into one rotation
 fn convert_timing_deviation_to_densitymap(
     mut deviation_map: Vec<SectorTimingDeviation>,
-) -> anyhow::Result<DensityMap> {
+    min_correction_factor: f64,
+) -> anyhow::Result<(DensityMap, f64)> {
     // now the deviation map should have the same number of raw bytes as the track buffer contains.
     let deviation_map_total_time: f64 = deviation_map
         .iter()
@@ -303,15 +485,17 @@ fn convert_timing_deviation_to_densitymap(
     let one_rotation_in_seconds = 0.1999; // little bit less than 200ms to be safe.
 
     // does our current data fit into one single rotation of the disk?
+    let mut correction_factor = 1.0;
     if deviation_map_total_time > one_rotation_in_seconds {
         // No it doesn't. We need to fix this a bit.
         // The reason for this is that the read time doesn't contain the gaps.
 
-        let correction_factor = one_rotation_in_seconds / deviation_map_total_time;
+        correction_factor = one_rotation_in_seconds / deviation_map_total_time;
         ensure!(
-            correction_factor > 0.99,
-            "Correction factor {} not plausible",
-            correction_factor
+            correction_factor > min_correction_factor,
+            "Correction factor {} not plausible. Minimum is {}",
+            correction_factor,
+            min_correction_factor
         );
 
         deviation_map
@@ -329,14 +513,14 @@ fn convert_timing_deviation_to_densitymap(
         })
         .collect();
 
-    Ok(reduce_densitymap(densitymap))
+    Ok((reduce_densitymap(densitymap), correction_factor))
 }
 
 fn process_track_record(
     whole_file_buffer: &[u8],
     current_track_record_position: usize,
-    file_hash_str: &str,
     revision: u8,
+    min_correction_factor: f64,
 ) -> anyhow::Result<(Option<RawTrack>, usize)> {
     let mut has_non_flux_reversal_area = false;
 
@@ -367,9 +551,27 @@ fn process_track_record(
     let cylinder = track_number & 0x7f;
     let head = track_number >> 7;
 
-    let (sectors, timing_data_size) =
+    let (mut sectors, timing_data_size) =
         read_sector_descriptors(sector_count, &mut track_record_reader)?;
 
+    let discarded = discard_overlapping_sectors(&mut sectors);
+    let profiles = detect_protection_profiles(&sectors, &discarded);
+    for (outer_index, hidden) in &discarded {
+        let outer = &ensure_index!(sectors[*outer_index]);
+        if ensure_index!(profiles[*outer_index]).is_some() {
+            println!(
+                "Warning: Sector {} of track {cylinder} {head} is hidden inside sector {}",
+                hidden.idam_sector, outer.idam_sector
+            );
+        } else {
+            println!(
+                "Warning: Sector {} of track {cylinder} {head} starts inside sector {} \
+                and is left out. The protection is unknown.",
+                hidden.idam_sector, outer.idam_sector
+            );
+        }
+    }
+
     let optional_timing_record_size = if timing_data_size > 0 {
         ensure!(
             revision == 2,
@@ -401,6 +603,10 @@ fn process_track_record(
         None
     };
 
+    // Offset of the first sync mark relative to the index in bytes.
+    // Only available with a track image.
+    let mut first_sync_offset = None;
+
     // The optional track image is provided for emulator usage when the "Read track" command is issued
     // to the WD1772. We don't really need it as it only contains the data bits and a reconstruction
     // of flux signals is impossible with this.
@@ -408,13 +614,12 @@ fn process_track_record(
         let mut track_image_header_reader =
             Cursor::new(&ensure_index!(whole_file_buffer[track_data_start..]));
 
-        let (_first_sync_offset, track_image_start) = if (track_flags & TRK_SYNC) == 0 {
-            (0, 2)
+        let track_image_start = if (track_flags & TRK_SYNC) == 0 {
+            2
         } else {
-            (
-                track_image_header_reader.read_u16::<LittleEndian>()? as usize,
-                4,
-            )
+            first_sync_offset =
+                Some(track_image_header_reader.read_u16::<LittleEndian>()? as usize);
+            4
         };
 
         let track_image_size = track_image_header_reader.read_u16::<LittleEndian>()? as usize;
@@ -448,14 +653,13 @@ fn process_track_record(
     let mut deviation_map: Vec<SectorTimingDeviation> = Vec::new();
     let mut byte_position_offset = None;
 
-    for sector in &sectors {
-        // Optional patching to remove sectors.
-        // This is required in case a sector is inside another.
-        // Turrican requires this.
-        if patch_discard_sector(sector, file_hash_str) {
-            continue;
-        }
+    // Tracks are written index aligned. Position the first sync mark
+    // at the same rotational position as the original.
+    if let Some(first_sync_offset) = first_sync_offset && sector_count > 1 {
+        generate_iso_gap(lead_in_gap_size(first_sync_offset), 0x4e, &mut encoder);
+    }
 
+    for (sector, profile) in sectors.iter().zip(profiles) {
         // calculate the assumed cell size for this sector
         // the read time is the time it takes to read the data section in microseconds.
         // This is slightly problematic as the gaps are not considered here.
@@ -469,28 +673,28 @@ fn process_track_record(
         // The gap sizes are not part of the stx file. We are generating them on the fly
         // based on the bit position in the sector descriptor which can be transformed into
         // byte positions.
-        let byte_position_offset_value =
-            *byte_position_offset.get_or_insert(if sectors.len() == 1 {
-                0
-            } else {
-                sector.bit_position / 4
-            });
-
-        let mfm_word_position = sector.bit_position / 4 - byte_position_offset_value;
-        let dynamic_gap_size = (mfm_word_position as i32 - trackbuf.borrow().len() as i32) / 2;
+        // An optional lead-in is already part of the track buffer and must be considered.
+        let trackbuf_len = trackbuf.borrow().len();
+        let byte_position_offset_value = *byte_position_offset.get_or_insert_with(|| {
+            first_sector_offset(sector.bit_position, sector_count, trackbuf_len)
+        });
 
-        if dynamic_gap_size >= 0 {
-            generate_iso_gap(dynamic_gap_size as usize, 0x4e, &mut encoder);
+        if let Some(dynamic_gap_size) = dynamic_gap_size(
+            sector.bit_position,
+            byte_position_offset_value,
+            trackbuf_len,
+        ) {
+            generate_iso_gap(dynamic_gap_size, 0x4e, &mut encoder);
         }
 
-        let custom_sector = patch_custom_sector(
-            sector,
-            file_hash_str,
-            &mut encoder,
-            &mut has_non_flux_reversal_area,
-        );
-
-        if !custom_sector {
+        if let Some(profile) = profile {
+            generate_protected_sector(
+                sector,
+                profile,
+                &mut encoder,
+                &mut has_non_flux_reversal_area,
+            );
+        } else {
             // No special code required to fix this sector? Then do a normal ISO one.
 
             let sector_data = &ensure_index!(
@@ -593,7 +797,14 @@ fn process_track_record(
         .context(program_flow_error!())?
         .number_of_raw_bytes += raw_bytes_to_add;
 
-    let densitymap = convert_timing_deviation_to_densitymap(deviation_map)?;
+    let (densitymap, correction_factor) =
+        convert_timing_deviation_to_densitymap(deviation_map, min_correction_factor)?;
+    if correction_factor <= SILENT_CORRECTION_FACTOR {
+        println!(
+            "Warning: Track {cylinder} {head} exceeds a rotation. Cells are shortened by {:.1}%",
+            (1.0 - correction_factor) * 100.0
+        );
+    }
 
     ensure!(!densitymap.is_empty());
 
@@ -608,3 +819,329 @@ fn process_track_record(
 
     Ok((Some(track), next_track_record_offset))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_reader::assert_truncation_rejected;
+    use crate::image_reader::image_iso::{generate_iso_track, IsoGeometry};
+    use crate::image_writer::image_stx::stx_image_to_bytes;
+    use crate::track_parser::{iso::IsoTrackParser, TrackParser};
+    use util::Encoding;
+
+    #[test]
+    fn single_sector_gap_test() {
+        // A single sector is placed at its bit position
+        let offset = first_sector_offset(800, 1, 0);
+        assert_eq!(offset, 0);
+        assert_eq!(dynamic_gap_size(800, offset, 0), Some(100));
+    }
+
+    #[test]
+    fn discard_overlapping_sectors_test() {
+        let sector = |idam_sector, bit_position| StxSector {
+            data_offset: 0,
+            bit_position,
+            read_time: 0,
+            idam_track: 0,
+            idam_head: 0,
+            idam_sector,
+            idam_size: 2,
+            idam_crc: 0,
+            fdc_flags: 0,
+            sector_size: 512,
+        };
+
+        // Sector 16 is hidden inside the data of sector 0
+        let mut sectors = vec![
+            sector(0, 1000),
+            sector(16, 1400),
+            sector(1, 1000 + 8 * 614),
+            sector(2, 1000 + 8 * 1228),
+        ];
+        let discarded = discard_overlapping_sectors(&mut sectors);
+
+        let ids: Vec<u8> = sectors.iter().map(|f| f.idam_sector).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(discarded.len(), 1);
+        let (outer, hidden) = discarded.first().unwrap();
+        assert_eq!((*outer, hidden.idam_sector), (0, 16));
+
+        // The outer sector is reconstructed with the hidden one
+        assert_eq!(
+            detect_protection_profiles(&sectors, &discarded),
+            [
+                Some(ProtectionProfile::HiddenSector { hidden_sector: 16 }),
+                None,
+                None
+            ]
+        );
+
+        // Regular tracks stay untouched
+        assert!(discard_overlapping_sectors(&mut sectors).is_empty());
+        assert_eq!(sectors.len(), 3);
+
+        // Other hidden sectors are not reconstructed like the one of Turrican
+        let mut sectors = vec![sector(0, 1000), sector(5, 1400), sector(1, 1000 + 8 * 614)];
+        let discarded = discard_overlapping_sectors(&mut sectors);
+        assert_eq!(
+            detect_protection_profiles(&sectors, &discarded),
+            [None, None]
+        );
+    }
+
+    #[test]
+    fn hidden_sector_track_test() {
+        // Sector 16 of Turrican is hidden inside sector 0
+        let record = stx_track_record(None, &[(0, 8 * 400), (16, 8 * 450), (1, 8 * 1014)]);
+        let (track, _) =
+            process_track_record(&record, 0, 3, DEFAULT_MIN_CORRECTION_FACTOR).unwrap();
+        assert!(track.unwrap().has_non_flux_reversal_area);
+
+        // An unknown hidden sector is left out and the track is generated normally
+        let record = stx_track_record(None, &[(0, 8 * 400), (5, 8 * 450), (1, 8 * 1014)]);
+        let (track, _) =
+            process_track_record(&record, 0, 3, DEFAULT_MIN_CORRECTION_FACTOR).unwrap();
+        let track = track.unwrap();
+        assert!(!track.has_non_flux_reversal_area);
+
+        let record = stx_track_record(None, &[(0, 8 * 400), (1, 8 * 1014)]);
+        let (regular_track, _) =
+            process_track_record(&record, 0, 3, DEFAULT_MIN_CORRECTION_FACTOR).unwrap();
+        assert_eq!(track.raw_data, regular_track.unwrap().raw_data);
+    }
+
+    #[test]
+    fn multiple_sector_gap_test() {
+        // The first sector follows the lead-in of 60 cell bytes directly
+        let offset = first_sector_offset(4000, 9, 60);
+        assert_eq!(offset, 940);
+        assert_eq!(dynamic_gap_size(4000, offset, 60), Some(0));
+
+        // The first sector took 1000 cell bytes. The next one starts 1200 cell bytes later.
+        assert_eq!(dynamic_gap_size(4000 + 4 * 1200, offset, 1060), Some(100));
+        // An odd number of cell bytes is rounded down
+        assert_eq!(dynamic_gap_size(4000 + 4 * 1201, offset, 1060), Some(100));
+
+        // Without a lead-in, the offset is the position of the first sector
+        assert_eq!(first_sector_offset(4000, 9, 0), 1000);
+        // A lead-in after the position of the first sector leads to no offset
+        assert_eq!(first_sector_offset(200, 9, 60), 0);
+    }
+
+    #[test]
+    fn overlapping_sector_gap_test() {
+        // The next sector starts before the end of the previous one.
+        // No gap is inserted in front of it.
+        assert_eq!(dynamic_gap_size(4000 + 4 * 900, 940, 1060), None);
+        // Less than a gap byte of overlap is rounded towards zero
+        assert_eq!(dynamic_gap_size(4000 + 4 * 999, 940, 1060), Some(0));
+    }
+
+    #[test]
+    fn correction_factor_test() {
+        // 100000 cells which need to be shortened by about 3% to fit into a rotation
+        let deviation_map = vec![SectorTimingDeviation {
+            number_of_raw_bytes: 12500,
+            cell_size_in_seconds: 0.1999 / 0.97 / 100_000.0,
+        }];
+
+        let (densitymap, correction_factor) =
+            convert_timing_deviation_to_densitymap(deviation_map.clone(), 0.95).unwrap();
+        assert!((correction_factor - 0.97).abs() < 1e-6);
+        assert_eq!(densitymap.len(), 1);
+        let entry = densitymap.first().unwrap();
+        assert_eq!(entry.number_of_cellbytes, 12500);
+        // 2µs cells scaled to fit into 199.9ms
+        assert_eq!(entry.cell_size, PulseDuration(167));
+
+        assert!(
+            convert_timing_deviation_to_densitymap(deviation_map, DEFAULT_MIN_CORRECTION_FACTOR)
+                .is_err()
+        );
+
+        // The bound is checked before the image is opened
+        let result = parse_stx_image_with_options("missing.stx", 0.5);
+        assert!(result.is_err_and(|f| f.to_string().contains("outside")));
+    }
+
+    /// Track record of track 0 with sectors of 512 bytes at the given bit positions.
+    /// The data of every sector is filled with its sector number.
+    fn stx_track_record(first_sync_offset: Option<u16>, sectors: &[(u8, usize)]) -> Vec<u8> {
+        let mut track_flags = TRK_SECT;
+        let mut track_data = Vec::new();
+        if let Some(first_sync_offset) = first_sync_offset {
+            // Track image header with an empty track image
+            track_flags |= TRK_IMAGE | TRK_SYNC;
+            track_data.extend_from_slice(&first_sync_offset.to_le_bytes());
+            track_data.extend_from_slice(&0u16.to_le_bytes());
+        }
+
+        let mut sector_descriptors = Vec::new();
+        for (idam_sector, bit_position) in sectors {
+            sector_descriptors.extend_from_slice(&(track_data.len() as u32).to_le_bytes());
+            sector_descriptors.extend_from_slice(&(*bit_position as u16).to_le_bytes());
+            sector_descriptors.extend_from_slice(&0u16.to_le_bytes()); // standard read time
+            sector_descriptors.extend_from_slice(&[0, 0, *idam_sector, 2]);
+            sector_descriptors.extend_from_slice(&0u16.to_be_bytes()); // idam crc
+            sector_descriptors.extend_from_slice(&[0, 0]); // fdc flags, reserved
+            track_data.extend_from_slice(&[*idam_sector; 512]);
+        }
+
+        let record_size = TRACK_DESCRIPTOR_SIZE + sector_descriptors.len() + track_data.len();
+        let mut record = Vec::new();
+        record.extend_from_slice(&(record_size as u32).to_le_bytes());
+        record.extend_from_slice(&0u32.to_le_bytes()); // fuzzy count
+        record.extend_from_slice(&(sectors.len() as u16).to_le_bytes());
+        record.extend_from_slice(&track_flags.to_le_bytes());
+        record.extend_from_slice(&6200u16.to_le_bytes()); // track length
+        record.extend_from_slice(&[0, 0]); // track number, track type
+        record.extend(sector_descriptors);
+        record.extend(track_data);
+        record
+    }
+
+    /// Position of the first MFM sync word in cell bytes
+    fn first_sync_position(track: &RawTrack) -> Option<usize> {
+        track.raw_data.windows(2).position(|f| f == [0x44, 0x89])
+    }
+
+    #[test]
+    fn lead_in_matches_first_sync_offset_test() {
+        let sectors = [(1, 8 * 400), (2, 8 * 1014)];
+
+        // The first sync is 100 bytes after the index. That are 200 cell bytes.
+        let record = stx_track_record(Some(100), &sectors);
+        let (track, _) =
+            process_track_record(&record, 0, 3, DEFAULT_MIN_CORRECTION_FACTOR).unwrap();
+        assert_eq!(first_sync_position(&track.unwrap()), Some(200));
+
+        // Without the sync offset, the track starts with the preamble of the first sector
+        let record = stx_track_record(None, &sectors);
+        let (track, _) =
+            process_track_record(&record, 0, 3, DEFAULT_MIN_CORRECTION_FACTOR).unwrap();
+        assert_eq!(first_sync_position(&track.unwrap()), Some(2 * gap2_size));
+    }
+
+    #[test]
+    fn timing_record_from_measurement_test() {
+        // Timing record of a sector protected by Macrodos / Speedlock
+        let values: Vec<u16> = [127, 133, 121, 128].iter().flat_map(|f| [*f; 8]).collect();
+        let mut stx_record = vec![5, 0, 68, 0];
+        values
+            .iter()
+            .for_each(|f| stx_record.extend_from_slice(&f.to_be_bytes()));
+        let timing_data = read_timing_record(&stx_record).unwrap();
+
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf =
+            generate_iso_track(0, 0, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+
+        // The data of the first sector follows the second sync of the track
+        let data_start = trackbuf
+            .windows(6)
+            .enumerate()
+            .filter(|(_, f)| *f == [0x44, 0x89, 0x44, 0x89, 0x44, 0x89])
+            .nth(1)
+            .unwrap()
+            .0
+            + 8;
+
+        let mut densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: data_start,
+            cell_size: PulseDuration(168),
+        }];
+        densitymap.extend(timing_data.iter().map(|f| DensityMapEntry {
+            number_of_cellbytes: TIMING_RAW_BYTES,
+            cell_size: PulseDuration((f * STM_TIMER_HZ).round() as i32),
+        }));
+        densitymap.push(DensityMapEntry {
+            number_of_cellbytes: trackbuf.len() - data_start - 1024,
+            cell_size: PulseDuration(168),
+        });
+        let track = RawTrack::new(0, 0, trackbuf, densitymap, Encoding::MFM);
+
+        let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        parser.set_measure_timing(true);
+        parser.expect_track(0, 0);
+        let payload = parser
+            .parse_raw_track(&track.simulate_read(1).unwrap())
+            .unwrap();
+        assert_eq!(payload.payload, sectors);
+
+        // Without variation, the timing is still measured
+        let second_sector = payload.sectors.get(1).unwrap();
+        assert!(!second_sector.has_variable_bit_width());
+        assert!(second_sector.bit_width_profile().is_some());
+
+        let first_sector = payload.sectors.first().unwrap();
+        assert!(first_sector.has_variable_bit_width());
+        let deviation = timing_deviation_from_profile(first_sector.bit_width_profile().unwrap());
+        let record = timing_record(&deviation);
+        assert_eq!(record.get(..4), stx_record.get(..4));
+
+        let measured = timing_values(&deviation);
+        assert_eq!(measured.len(), values.len());
+        for (measured, expected) in measured.iter().zip(&values) {
+            assert!(
+                measured.abs_diff(*expected) <= 2,
+                "Measured {measured} instead of {expected}"
+            );
+        }
+        assert_eq!(
+            sector_timing_values(first_sector.bit_width_profile().unwrap(), 512).unwrap(),
+            measured
+        );
+    }
+
+    #[test]
+    fn sector_timing_values_test() {
+        let profile = vec![PulseDuration(168); 32];
+        assert_eq!(sector_timing_values(&profile, 512).unwrap(), vec![128; 32]);
+
+        // The profile must cover the whole sector
+        assert!(sector_timing_values(&profile, 1024).is_err());
+
+        // A cell size of 1 µs can't be a double density track
+        let mut profile = profile;
+        if let Some(cell) = profile.get_mut(3) {
+            *cell = PulseDuration(84);
+        }
+        assert!(sector_timing_values(&profile, 512).is_err());
+    }
+
+    #[test]
+    fn truncated_stx_test() {
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 253) as u8).collect();
+        let trackbuf =
+            generate_iso_track(0, 0, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+        let image = RawImage {
+            tracks: vec![RawTrack::new(
+                0,
+                0,
+                trackbuf.clone(),
+                vec![DensityMapEntry {
+                    number_of_cellbytes: trackbuf.len(),
+                    cell_size: PulseDuration(168),
+                }],
+                Encoding::MFM,
+            )],
+            disk_type: util::DiskType::Inch3_5,
+            density: Density::SingleDouble,
+        };
+        let data = stx_image_to_bytes(&image).unwrap();
+
+        let path = std::env::temp_dir().join("truncated_stx_test.stx");
+        fs::write(&path, &data).unwrap();
+        assert_eq!(
+            parse_stx_image(path.to_str().unwrap())
+                .unwrap()
+                .tracks
+                .len(),
+            1
+        );
+        fs::remove_file(path).unwrap();
+
+        assert_truncation_rejected("truncated_stx_test.stx", &data, data.len(), parse_stx_image);
+    }
+}
@@ -1,12 +1,17 @@
-use anyhow::{bail, ensure, Context};
-use std::{ffi::OsStr, path::Path};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    path::Path,
+    sync::{Arc, LazyLock, RwLock},
+};
 
-use crate::rawtrack::RawImage;
+use crate::{error::ToolError, rawtrack::RawImage};
 
 use self::{
     image_adf::parse_adf_image, image_d64::parse_d64_image, image_dsk::parse_dsk_image,
     image_g64::parse_g64_image, image_ipf::parse_ipf_image, image_iso::parse_iso_image,
-    image_stx::parse_stx_image,
+    image_scp::parse_scp_image, image_stx::parse_stx_image,
 };
 
 pub mod image_adf;
@@ -15,33 +20,212 @@ pub mod image_dsk;
 pub mod image_g64;
 pub mod image_ipf;
 pub mod image_iso;
+pub mod image_scp;
 pub mod image_stx;
 
-pub fn parse_image(path: &str) -> anyhow::Result<RawImage> {
+/// Describes an image format which is known to the tool
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatInfo {
+    pub extension: &'static str,
+    pub description: &'static str,
+    /// Images of this format can also be created from a disk or another image
+    pub can_write: bool,
+}
+
+/// Parses the image file at the given path
+type FormatParser = fn(&str) -> anyhow::Result<RawImage>;
+
+/// Every built-in image format with its parser
+const FORMATS: &[(FormatInfo, FormatParser)] = &[
+    (
+        FormatInfo {
+            extension: "adf",
+            description: "Amiga Disk File",
+            can_write: true,
+        },
+        parse_adf_image,
+    ),
+    (
+        FormatInfo {
+            extension: "ipf",
+            description: "Interchangeable Preservation Format",
+            can_write: false,
+        },
+        parse_ipf_image,
+    ),
+    (
+        FormatInfo {
+            extension: "d64",
+            description: "Commodore 1541 sector image",
+            can_write: true,
+        },
+        parse_d64_image,
+    ),
+    (
+        FormatInfo {
+            extension: "g64",
+            description: "Commodore 1541 GCR image",
+            can_write: true,
+        },
+        parse_g64_image,
+    ),
+    (
+        FormatInfo {
+            extension: "st",
+            description: "Atari ST sector image",
+            can_write: true,
+        },
+        parse_iso_image,
+    ),
+    (
+        FormatInfo {
+            extension: "img",
+            description: "PC sector image",
+            can_write: true,
+        },
+        parse_iso_image,
+    ),
+    (
+        FormatInfo {
+            extension: "stx",
+            description: "Pasti Atari ST image",
+            can_write: true,
+        },
+        parse_stx_image,
+    ),
+    (
+        FormatInfo {
+            extension: "dsk",
+            description: "Amstrad CPC disk image",
+            can_write: false,
+        },
+        parse_dsk_image,
+    ),
+    (
+        FormatInfo {
+            extension: "scp",
+            description: "SuperCard Pro flux image",
+            can_write: false,
+        },
+        parse_scp_image,
+    ),
+];
+
+/// Creates an image from the content of a file
+pub type ImageParser = Box<dyn Fn(&[u8]) -> anyhow::Result<RawImage> + Send + Sync>;
+
+/// Shared to call a parser without holding the lock
+type RegisteredParser = (FormatInfo, Arc<ImageParser>);
+
+static IMAGE_PARSERS: LazyLock<RwLock<BTreeMap<&'static str, RegisteredParser>>> =
+    LazyLock::new(|| RwLock::new(BTreeMap::new()));
+
+/// Adds support for an image format which is not part of the tool.
+/// The extension is expected in lower case. Images of this format can't be created.
+/// The parser is preferred over a built-in one of the same extension.
+pub fn register_image_parser(
+    extension: &'static str,
+    description: &'static str,
+    parser: ImageParser,
+) {
+    let format = FormatInfo {
+        extension,
+        description,
+        can_write: false,
+    };
+    IMAGE_PARSERS
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .insert(extension, (format, Arc::new(parser)));
+}
+
+/// Checks an image which matches none of the `valid_sizes` of its format.
+/// If it is shorter than one of them, the file is probably truncated and the
+/// next larger size is reported as expectation.
+pub(crate) fn truncated_image_error(
+    format: &'static str,
+    size: usize,
+    valid_sizes: impl IntoIterator<Item = usize>,
+) -> Option<ToolError> {
+    valid_sizes
+        .into_iter()
+        .filter(|expected| *expected > size)
+        .min()
+        .map(|expected| ToolError::TruncatedImage {
+            format,
+            size,
+            expected,
+        })
+}
+
+/// Every image format which can be parsed to be written to a disk.
+/// Registered formats replace a built-in one of the same extension and follow the built-in ones.
+#[must_use]
+pub fn supported_read_formats() -> Vec<FormatInfo> {
+    let parsers = IMAGE_PARSERS
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+    FORMATS
+        .iter()
+        .map(|f| f.0)
+        .filter(|f| !parsers.contains_key(f.extension))
+        .chain(parsers.values().map(|f| f.0))
+        .collect()
+}
+
+pub fn parse_image(path: &str) -> Result<RawImage, ToolError> {
     let path2 = Path::new(path);
 
-    ensure!(path2.exists(), "File doesn't exist!");
+    if !path2.exists() {
+        return Err(ToolError::FileNotFound(path.into()));
+    }
 
     let extension = path2
         .extension()
         .and_then(OsStr::to_str)
-        .context("Unknown file extension!")?;
-
-    let image = match extension {
-        "ipf" => parse_ipf_image(path)?,
-        "adf" => parse_adf_image(path)?,
-        "d64" => parse_d64_image(path)?,
-        "g64" => parse_g64_image(path)?,
-        "st" => parse_iso_image(path)?,
-        "img" => parse_iso_image(path)?,
-        "stx" => parse_stx_image(path)?,
-        "dsk" => parse_dsk_image(path)?,
-        _ => bail!("{} is an unknown file extension!", extension),
-    };
+        .ok_or_else(|| ToolError::MissingExtension(path.into()))?;
+
+    let parser = IMAGE_PARSERS
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .get(extension.to_lowercase().as_str())
+        .map(|f| Arc::clone(&f.1));
+    if let Some(parser) = parser {
+        let content = fs::read(path).map_err(anyhow::Error::from)?;
+        let image = parser(&content)?;
+        image.check_densitymaps()?;
+        return Ok(image);
+    }
+
+    let (_, parse) = FORMATS
+        .iter()
+        .find(|f| f.0.extension == extension)
+        .ok_or_else(|| ToolError::UnknownFormat(extension.into()))?;
+    let image = parse(path)?;
+    image.check_densitymaps()?;
 
     Ok(image)
 }
 
+/// Writes the image shortened to several lengths up to `end` and
+/// expects the parser to reject every truncated file without panicking.
+#[cfg(test)]
+pub(crate) fn assert_truncation_rejected(
+    name: &str,
+    data: &[u8],
+    end: usize,
+    parser: fn(&str) -> anyhow::Result<RawImage>,
+) {
+    let path = std::env::temp_dir().join(name);
+    let path = path.to_str().unwrap();
+    for length in (0..end).step_by(end / 50 + 1).chain([end - 1]) {
+        std::fs::write(path, data.get(..length).unwrap()).unwrap();
+        assert!(parser(path).is_err(), "{name} truncated to {length} bytes");
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -50,6 +234,7 @@ mod tests {
     };
 
     use super::*;
+    use anyhow::{bail, ensure};
     use rstest::rstest;
     use util::{DRIVE_3_5_RPM, DRIVE_5_25_RPM};
 
@@ -126,6 +311,11 @@ mod tests {
         "8bd150d9c57dc0a016db759e8dc903e2",
         "022d98d018f1aa871a0239c260ad4e11"
     )]
+    #[case( // 13 - High Density ADF. Created with: head -c 1802240 /dev/zero
+        "../images/blank_hd.adf",
+        "0d95a33c6418ba99244df5c602a72077",
+        "eed1edb1bf6513edc9ea12366788d3e8"
+    )]
     fn known_image_regression_test(
         #[case] filepath: &str,
         #[case] expected_file_md5: &str,
@@ -164,4 +354,90 @@ mod tests {
         let md5_hashstr = format!("{md5_hash:x}");
         assert_eq!(md5_hashstr, expected_md5);
     }
+
+    #[test]
+    fn registered_image_parser_test() {
+        register_image_parser(
+            "rawtest",
+            "Raw test image",
+            Box::new(|content| {
+                // The parsers are not locked while a parser is running
+                register_image_parser("rawtest2", "Nested test image", Box::new(|_| bail!("")));
+                Ok(RawImage {
+                    density: util::Density::SingleDouble,
+                    disk_type: util::DiskType::Inch3_5,
+                    tracks: vec![crate::rawtrack::RawTrack::new(
+                        0,
+                        0,
+                        content.to_vec(),
+                        vec![util::DensityMapEntry {
+                            number_of_cellbytes: content.len(),
+                            cell_size: util::PulseDuration(168),
+                        }],
+                        util::Encoding::MFM,
+                    )],
+                })
+            }),
+        );
+
+        let path = std::env::temp_dir().join("registered_image_parser_test.rawtest");
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let image = parse_image(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let image = image.unwrap();
+        assert_eq!(image.tracks.first().unwrap().raw_data, vec![1, 2, 3]);
+
+        let formats = supported_read_formats();
+        assert!(formats
+            .iter()
+            .any(|f| f.extension == "rawtest" && !f.can_write));
+        assert!(formats.iter().any(|f| f.extension == "rawtest2"));
+        assert!(formats.iter().any(|f| f.extension == "scp"));
+    }
+
+    #[test]
+    fn parse_image_error_test() {
+        assert!(matches!(
+            parse_image("../images/does_not_exist.adf"),
+            Err(ToolError::FileNotFound(_))
+        ));
+
+        let path = std::env::temp_dir().join("parse_image_error_test.unknown");
+        fs::write(&path, [0]).unwrap();
+        let result = parse_image(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(
+            matches!(result, Err(ToolError::UnknownFormat(extension)) if extension == "unknown")
+        );
+
+        // Errors raised inside the parsers keep their kind
+        let error = anyhow::Error::from(ToolError::WriteProtected);
+        assert!(matches!(ToolError::from(error), ToolError::WriteProtected));
+        assert!(matches!(
+            ToolError::from(anyhow::anyhow!("Broken image")),
+            ToolError::Other(_)
+        ));
+    }
+
+    #[test]
+    fn known_image_long_track_density_test() {
+        // The long tracks of this image are written with a higher data rate in some areas
+        let image = parse_image(
+            "../atarist_ipf/Turrican II - The Final Fight (Europe) (Budget - Kixx).ipf",
+        )
+        .unwrap();
+
+        assert!(image.tracks.iter().any(|f| f.densitymap.len() > 1));
+
+        // Data rates with the same cell size are merged. Every change of the
+        // density map must change the cell size and the cells must still add up.
+        for track in &image.tracks {
+            assert!(track
+                .densitymap
+                .windows(2)
+                .all(|f| f.first().unwrap().cell_size != f.last().unwrap().cell_size));
+            track.check_densitymap().unwrap();
+        }
+    }
 }
@@ -0,0 +1,151 @@
+use std::{convert::TryFrom, fs};
+
+use anyhow::{ensure, Context};
+use util::Encoding;
+
+use crate::{image_reader::image_g64::G64_SPEED_TABLE, rawtrack::RawImage};
+
+// http://www.unusedino.de/ec64/technical/formats/g64.html
+
+/// Number of half tracks in the file. The same as most emulators are using.
+const G64_NUMBER_OF_TRACKS: usize = 84;
+/// Default size of a track which is the maximum for track 1.
+const G64_DEFAULT_TRACK_SIZE: usize = 7928;
+
+/// Provides the speed zone of the table which is the closest to the cell size
+fn speed_zone_of_cell_size(cell_size: i32) -> anyhow::Result<u32> {
+    let (index, _) = G64_SPEED_TABLE
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, f)| (i64::from(**f) - i64::from(cell_size)).abs())
+        .context(program_flow_error!())?;
+
+    // The table starts with the fastest zone which is 3
+    Ok(3 - index as u32)
+}
+
+pub fn g64_image_to_bytes(image: &RawImage) -> anyhow::Result<Vec<u8>> {
+    let mut track_slots: Vec<Option<(&[u8], u32)>> = vec![None; G64_NUMBER_OF_TRACKS];
+
+    for track in &image.tracks {
+        ensure!(
+            matches!(track.encoding, Encoding::GCR),
+            "Track {} {} is not GCR encoded",
+            track.cylinder,
+            track.head
+        );
+        ensure!(track.head == 0, "G64 images have no second side");
+        ensure!(
+            track.flux_timings.is_none(),
+            "Track {} consists of flux timings which can't be stored in G64",
+            track.cylinder
+        );
+        ensure!(
+            track.densitymap.len() == 1,
+            "Track {} has multiple densities which can't be stored in G64",
+            track.cylinder
+        );
+
+        let cell_size = track
+            .densitymap
+            .first()
+            .context(program_flow_error!())?
+            .cell_size;
+
+        let slot = track_slots
+            .get_mut(track.cylinder as usize)
+            .with_context(|| format!("Track {} exceeds the G64 format", track.cylinder))?;
+        *slot = Some((&track.raw_data, speed_zone_of_cell_size(cell_size.0)?));
+    }
+
+    let track_size = track_slots
+        .iter()
+        .flatten()
+        .map(|(data, _)| data.len())
+        .max()
+        .unwrap_or_default()
+        .max(G64_DEFAULT_TRACK_SIZE);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(b"GCR-1541");
+    header.push(0); // version
+    header.push(G64_NUMBER_OF_TRACKS as u8);
+    header.extend_from_slice(&u16::try_from(track_size)?.to_le_bytes());
+
+    let first_track_offset = header.len() + 2 * G64_NUMBER_OF_TRACKS * std::mem::size_of::<u32>();
+    let mut track_offsets = Vec::new();
+    let mut speed_zones = Vec::new();
+    let mut track_data = Vec::new();
+
+    for slot in track_slots {
+        if let Some((data, speed_zone)) = slot {
+            let offset = first_track_offset + track_data.len();
+            track_offsets.extend_from_slice(&u32::try_from(offset)?.to_le_bytes());
+            speed_zones.extend_from_slice(&speed_zone.to_le_bytes());
+
+            track_data.extend_from_slice(&u16::try_from(data.len())?.to_le_bytes());
+            track_data.extend_from_slice(data);
+            // Every track occupies the same space in the file
+            track_data.resize(track_data.len() + track_size - data.len(), 0x55);
+        } else {
+            track_offsets.extend_from_slice(&0u32.to_le_bytes());
+            speed_zones.extend_from_slice(&0u32.to_le_bytes());
+        }
+    }
+
+    let mut result = header;
+    result.append(&mut track_offsets);
+    result.append(&mut speed_zones);
+    result.append(&mut track_data);
+    Ok(result)
+}
+
+pub fn write_g64_image(image: &RawImage, path: &str) -> anyhow::Result<()> {
+    println!("Writing G64 to {path} ...");
+    fs::write(path, g64_image_to_bytes(image)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_reader::{image_d64::generate_track, image_g64::parse_g64_image},
+        rawtrack::RawTrack,
+    };
+    use util::{Density, DensityMapEntry, DiskType, PulseDuration};
+
+    #[test]
+    fn g64_roundtrip_test() {
+        let sectors: Vec<u8> = (0..21 * 256).map(|f| (f % 251) as u8).collect();
+        let (trackbuf, settings) = generate_track(1, &mut sectors.chunks_exact(256)).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(settings.cellsize as i32),
+        }];
+        let image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch5_25,
+            tracks: vec![RawTrack::new(
+                0,
+                0,
+                trackbuf.clone(),
+                densitymap,
+                Encoding::GCR,
+            )],
+        };
+
+        let path = std::env::temp_dir().join("g64_roundtrip_test.g64");
+        let path = path.to_str().unwrap();
+        write_g64_image(&image, path).unwrap();
+        let parsed = parse_g64_image(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let tracks: Vec<(u32, &Vec<u8>)> = parsed
+            .tracks
+            .iter()
+            .map(|f| (f.cylinder, &f.raw_data))
+            .collect();
+        assert_eq!(tracks, vec![(0, &trackbuf)]);
+    }
+}
@@ -0,0 +1,393 @@
+use std::{convert::TryFrom, fs};
+
+use anyhow::ensure;
+use util::{duration_of_rotation_as_stm_tim_raw, Density, Encoding, PulseDuration, STM_TIMER_HZ};
+
+use crate::{
+    image_reader::image_stx::{
+        timing_deviation_from_profile, timing_record, FDC_FLAG_CRC_ERROR,
+        FDC_FLAG_INTRA_SECTOR_BIT_WIDTH_VARIATION, SECTOR_DESCRIPTOR_SIZE, TRACK_DESCRIPTOR_SIZE,
+        TRK_SECT,
+    },
+    rawtrack::RawImage,
+    track_parser::{
+        expand_reduced_pulses,
+        iso::{scan_iso_sectors, ScannedSector},
+    },
+};
+
+// http://info-coach.fr/atari/documents/_mydoc/Pasti-documentation.pdf
+
+const STX_VERSION: u16 = 3;
+/// Timing records require revision 2
+const STX_REVISION: u8 = 2;
+/// Ticks of the 84 MHz timer for a data bit of a double density track
+const TICKS_PER_BIT: usize = 2 * 168;
+/// Three sync bytes and the address mark precede the sector header
+const BITS_BEFORE_SECTOR_HEADER: usize = 4 * 8;
+/// Pasti describes the tracks of a drive with exactly 300 RPM
+const STX_RPM: f64 = 300.0;
+
+fn sector_descriptor(
+    sector: &ScannedSector,
+    data_offset: usize,
+    fdc_flags: u8,
+) -> anyhow::Result<Vec<u8>> {
+    let [idam_track, idam_head, idam_sector, idam_size, crc_high, crc_low] = sector.header;
+    // Positions are given in bits from the index
+    let bit_position =
+        (sector.header_position / TICKS_PER_BIT).saturating_sub(BITS_BEFORE_SECTOR_HEADER);
+    // Time to read the sector data in microseconds
+    let read_time = (sector.data_duration as f64 * 1e6 / STM_TIMER_HZ).round() as usize;
+
+    let mut descriptor = Vec::with_capacity(SECTOR_DESCRIPTOR_SIZE);
+    descriptor.extend_from_slice(&u32::try_from(data_offset)?.to_le_bytes());
+    descriptor.extend_from_slice(&u16::try_from(bit_position)?.to_le_bytes());
+    descriptor.extend_from_slice(&u16::try_from(read_time)?.to_le_bytes());
+    descriptor.extend_from_slice(&[
+        idam_track,
+        idam_head,
+        idam_sector,
+        idam_size,
+        crc_high,
+        crc_low,
+        fdc_flags,
+        0,
+    ]);
+    Ok(descriptor)
+}
+
+/// Creates the record of a track from flux timings which start at the index.
+/// Sectors are stored with their position, data, CRC errors and variable bit width.
+/// Only the first rotation is considered.
+pub fn stx_track_record(
+    cylinder: u32,
+    head: u32,
+    flux_timings: &[PulseDuration],
+) -> anyhow::Result<Vec<u8>> {
+    let rotation = duration_of_rotation_as_stm_tim_raw(STX_RPM);
+    let sectors: Vec<ScannedSector> = scan_iso_sectors(flux_timings, Density::SingleDouble)
+        .into_iter()
+        .filter(|f| f.header_position < rotation)
+        .collect();
+
+    // The track covers a full rotation even if the recorded data is shorter
+    let track_length = rotation / (TICKS_PER_BIT * 8);
+
+    let mut descriptors = Vec::new();
+    let mut sector_data = Vec::new();
+    let mut timing_deviation = Vec::new();
+    for sector in &sectors {
+        let mut fdc_flags = 0;
+        if !sector.data_crc_valid {
+            fdc_flags |= FDC_FLAG_CRC_ERROR;
+        }
+        if sector.variable_bit_width {
+            fdc_flags |= FDC_FLAG_INTRA_SECTOR_BIT_WIDTH_VARIATION;
+            timing_deviation.extend(timing_deviation_from_profile(&sector.bit_width_profile));
+        }
+
+        descriptors.append(&mut sector_descriptor(
+            sector,
+            sector_data.len(),
+            fdc_flags,
+        )?);
+        sector_data.extend_from_slice(&sector.data);
+    }
+
+    let timing = if timing_deviation.is_empty() {
+        Vec::new()
+    } else {
+        timing_record(&timing_deviation)
+    };
+
+    let record_size = TRACK_DESCRIPTOR_SIZE + descriptors.len() + sector_data.len() + timing.len();
+    // Bit 7 of the track number contains the side of the disk
+    let track_number = u8::try_from(cylinder | (head << 7))?;
+
+    let mut record = Vec::with_capacity(record_size);
+    record.extend_from_slice(&u32::try_from(record_size)?.to_le_bytes());
+    record.extend_from_slice(&0u32.to_le_bytes()); // no fuzzy bytes
+    record.extend_from_slice(&u16::try_from(sectors.len())?.to_le_bytes());
+    record.extend_from_slice(&TRK_SECT.to_le_bytes());
+    record.extend_from_slice(&u16::try_from(track_length)?.to_le_bytes());
+    record.push(track_number);
+    record.push(0); // track type
+    record.append(&mut descriptors);
+    record.append(&mut sector_data);
+    record.extend_from_slice(&timing);
+    Ok(record)
+}
+
+/// Creates the file from the records of all tracks ordered by cylinder and head
+pub fn stx_file_from_records(records: Vec<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    result.extend_from_slice(b"RSY\0");
+    result.extend_from_slice(&STX_VERSION.to_le_bytes());
+    result.extend_from_slice(&0u16.to_le_bytes()); // tool
+    result.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    result.push(u8::try_from(records.len())?);
+    result.push(STX_REVISION);
+    result.extend_from_slice(&0u32.to_le_bytes()); // reserved
+    records
+        .iter()
+        .for_each(|record| result.extend_from_slice(record));
+    Ok(result)
+}
+
+pub fn stx_image_to_bytes(image: &RawImage) -> anyhow::Result<Vec<u8>> {
+    ensure!(
+        image.density == Density::SingleDouble,
+        "STX images only support double density"
+    );
+
+    let mut tracks: Vec<_> = image.tracks.iter().collect();
+    tracks.sort_by_key(|f| (f.cylinder, f.head));
+
+    let mut records = Vec::new();
+    for track in tracks {
+        ensure!(
+            matches!(track.encoding, Encoding::MFM),
+            "Track {} {} is not MFM encoded",
+            track.cylinder,
+            track.head
+        );
+        let flux_timings = expand_reduced_pulses(&track.simulate_read(1)?);
+        records.push(stx_track_record(track.cylinder, track.head, &flux_timings)?);
+    }
+
+    stx_file_from_records(records)
+}
+
+pub fn write_stx_image(image: &RawImage, path: &str) -> anyhow::Result<()> {
+    println!("Writing STX to {path} ...");
+    fs::write(path, stx_image_to_bytes(image)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_reader::{
+            image_iso::{
+                generate_iso_data_header, generate_iso_data_with_broken_crc,
+                generate_iso_data_with_crc, generate_iso_gap, generate_iso_sectorheader,
+                generate_iso_track, IsoGeometry,
+            },
+            image_stx::parse_stx_image,
+        },
+        rawtrack::RawTrack,
+        track_parser::{iso::IsoTrackParser, TrackParser},
+    };
+    use util::{bitstream::BitStreamCollector, mfm::MfmEncoder, DensityMapEntry, DiskType};
+
+    fn image_of(tracks: Vec<RawTrack>) -> RawImage {
+        RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks,
+        }
+    }
+
+    fn track_of(cylinder: u32, head: u32, trackbuf: Vec<u8>) -> RawTrack {
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        RawTrack::new(cylinder, head, trackbuf, densitymap, Encoding::MFM)
+    }
+
+    /// Writes the image as STX and reads it back
+    fn roundtrip(image: &RawImage, name: &str) -> RawImage {
+        let path = std::env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        write_stx_image(image, path).unwrap();
+        let result = parse_stx_image(path).unwrap();
+        fs::remove_file(path).unwrap();
+        result
+    }
+
+    fn scan(track: &RawTrack) -> Vec<ScannedSector> {
+        scan_iso_sectors(
+            &expand_reduced_pulses(&track.simulate_read(1).unwrap()),
+            Density::SingleDouble,
+        )
+    }
+
+    #[test]
+    fn stx_roundtrip_test() {
+        let sectors: Vec<u8> = (0..2 * 9 * 512).map(|f| (f % 253) as u8).collect();
+        let mut sectors_in = sectors.chunks_exact(512);
+        let tracks = (0..2)
+            .map(|head| {
+                let trackbuf =
+                    generate_iso_track(1, head, &IsoGeometry::new(9), &mut sectors_in).unwrap();
+                track_of(1, head, trackbuf)
+            })
+            .collect();
+
+        let image = roundtrip(&image_of(tracks), "stx_roundtrip_test.stx");
+        assert_eq!(image.tracks.len(), 2);
+
+        let mut payload = Vec::new();
+        for track in &image.tracks {
+            let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+            parser.expect_track(track.cylinder, track.head);
+            payload.append(
+                &mut parser
+                    .parse_raw_track(&track.simulate_read(1).unwrap())
+                    .unwrap()
+                    .payload,
+            );
+        }
+        assert_eq!(payload, sectors);
+    }
+
+    /// Builds the STX file of a standard Atari ST track with 9 sectors of 512 bytes
+    /// as described by the Pasti documentation.
+    fn reference_stx(cylinder: u8, head: u8, sectors: &[u8]) -> Vec<u8> {
+        let mut descriptors = Vec::new();
+        for (index, _) in sectors.chunks_exact(512).enumerate() {
+            let sector = index as u8 + 1;
+            let mut crc = crc16::State::<crc16::CCITT_FALSE>::new();
+            crc.update(&[0xa1, 0xa1, 0xa1, 0xfe, cylinder, head, sector, 2]);
+            // Gap 1 is followed by gap 2 and the sync of the first sector header.
+            // Every sector occupies 614 bytes on the track.
+            let bit_position = (60 + 12 + index * 614) as u16 * 8;
+            // 512 bytes with 32 µs each
+            let read_time: u16 = 16384;
+
+            descriptors.extend_from_slice(&(index as u32 * 512).to_le_bytes());
+            descriptors.extend_from_slice(&bit_position.to_le_bytes());
+            descriptors.extend_from_slice(&read_time.to_le_bytes());
+            descriptors.extend_from_slice(&[cylinder, head, sector, 2]);
+            descriptors.extend_from_slice(&crc.get().to_be_bytes());
+            descriptors.extend_from_slice(&[0, 0]); // FDC flags and reserved
+        }
+
+        let record_size = 16 + descriptors.len() + sectors.len();
+        let mut stx = Vec::new();
+        // File descriptor
+        stx.extend_from_slice(b"RSY\0");
+        stx.extend_from_slice(&[3, 0, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0]);
+        // Track descriptor
+        stx.extend_from_slice(&(record_size as u32).to_le_bytes());
+        stx.extend_from_slice(&0u32.to_le_bytes());
+        stx.extend_from_slice(&9u16.to_le_bytes());
+        stx.extend_from_slice(&1u16.to_le_bytes()); // sector descriptors only
+        stx.extend_from_slice(&6250u16.to_le_bytes()); // bytes of a rotation at 300 RPM
+        stx.push(cylinder | (head << 7));
+        stx.push(0);
+        stx.append(&mut descriptors);
+        stx.extend_from_slice(sectors);
+        stx
+    }
+
+    #[test]
+    fn stx_reference_test() {
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf =
+            generate_iso_track(5, 1, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+        let image = image_of(vec![track_of(5, 1, trackbuf)]);
+
+        let reference = reference_stx(5, 1, &sectors);
+        assert_eq!(stx_image_to_bytes(&image).unwrap(), reference);
+
+        // The reference is readable and provides the same sectors
+        let path = std::env::temp_dir().join("stx_reference_test.stx");
+        fs::write(&path, &reference).unwrap();
+        let parsed = parse_stx_image(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let track = parsed.tracks.first().unwrap();
+        assert_eq!((track.cylinder, track.head), (5, 1));
+        let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        parser.expect_track(5, 1);
+        let payload = parser
+            .parse_raw_track(&track.simulate_read(1).unwrap())
+            .unwrap();
+        assert_eq!(payload.payload, sectors);
+    }
+
+    #[test]
+    fn stx_crc_error_test() {
+        let mut trackbuf: Vec<u8> = Vec::new();
+        let mut collector = BitStreamCollector::new(|f| trackbuf.push(f));
+        let mut encoder = MfmEncoder::new(|cell| collector.feed(cell));
+
+        generate_iso_gap(60, 0x4e, &mut encoder);
+        for sector in 1..=9 {
+            let data = [sector; 512];
+            generate_iso_sectorheader(12, 0, 0, sector, 2, &mut encoder);
+            generate_iso_gap(22, 0x4e, &mut encoder);
+            generate_iso_data_header(12, &mut encoder, None);
+            if sector == 4 {
+                generate_iso_data_with_broken_crc(&data, &mut encoder);
+            } else {
+                generate_iso_data_with_crc(&data, &mut encoder, None);
+            }
+            generate_iso_gap(40, 0x4e, &mut encoder);
+        }
+        generate_iso_gap(200, 0x4e, &mut encoder);
+
+        let image = image_of(vec![track_of(0, 0, trackbuf)]);
+        let bytes = stx_image_to_bytes(&image).unwrap();
+        // File descriptor, track descriptor and the flags of the fourth sector descriptor
+        let flags_offset = 16 + TRACK_DESCRIPTOR_SIZE + 3 * SECTOR_DESCRIPTOR_SIZE + 14;
+        assert_eq!(bytes.get(flags_offset), Some(&FDC_FLAG_CRC_ERROR));
+
+        let image = roundtrip(&image, "stx_crc_error_test.stx");
+        let sectors = scan(image.tracks.first().unwrap());
+        let crc_errors: Vec<u8> = sectors
+            .iter()
+            .filter(|f| !f.data_crc_valid)
+            .map(|f| f.header[2])
+            .collect();
+        assert_eq!(sectors.len(), 9);
+        assert_eq!(crc_errors, vec![4]);
+        assert_eq!(sectors.get(3).unwrap().data, vec![4; 512]);
+    }
+
+    #[test]
+    fn stx_timing_test() {
+        let sectors = vec![0; 9 * 512];
+        let trackbuf =
+            generate_iso_track(0, 0, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+
+        // The data of the first sector follows the second sync of the track
+        let data_start = trackbuf
+            .windows(6)
+            .enumerate()
+            .filter(|(_, f)| *f == [0x44, 0x89, 0x44, 0x89, 0x44, 0x89])
+            .nth(1)
+            .unwrap()
+            .0
+            + 8;
+
+        // The second half of the first sector is written with shorter cells
+        let densitymap = vec![
+            DensityMapEntry {
+                number_of_cellbytes: data_start + 512,
+                cell_size: PulseDuration(168),
+            },
+            DensityMapEntry {
+                number_of_cellbytes: 512,
+                cell_size: PulseDuration(150),
+            },
+            DensityMapEntry {
+                number_of_cellbytes: trackbuf.len() - data_start - 1024,
+                cell_size: PulseDuration(168),
+            },
+        ];
+        let original = RawTrack::new(0, 0, trackbuf, densitymap, Encoding::MFM);
+
+        let image = roundtrip(&image_of(vec![original]), "stx_timing_test.stx");
+        let variable: Vec<u8> = scan(image.tracks.first().unwrap())
+            .iter()
+            .filter(|f| f.variable_bit_width)
+            .map(|f| f.header[2])
+            .collect();
+        assert_eq!(variable, vec![1]);
+    }
+}
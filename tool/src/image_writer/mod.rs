@@ -0,0 +1,135 @@
+use std::{ffi::OsStr, fs, path::Path};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    error::ToolError,
+    image_reader::{image_d64::d64_layout, supported_read_formats, FormatInfo},
+    rawtrack::RawImage,
+    track_parser::{decode_raw_track, track_parser_for_extension},
+};
+
+use self::{image_g64::write_g64_image, image_stx::write_stx_image};
+
+pub mod image_g64;
+pub mod image_stx;
+
+/// Every image format which can be created by `write_image`
+#[must_use]
+pub fn supported_write_formats() -> Vec<FormatInfo> {
+    supported_read_formats()
+        .into_iter()
+        .filter(|f| f.can_write)
+        .collect()
+}
+
+/// Writes the image to a file. The format is chosen by the file extension.
+/// Writing to a sector based format is lossy as only the decoded data is kept.
+pub fn write_image(image: &RawImage, path: &str) -> Result<(), ToolError> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| ToolError::MissingExtension(path.into()))?;
+
+    match extension {
+        "g64" => write_g64_image(image, path)?,
+        "stx" => write_stx_image(image, path)?,
+        "adf" | "d64" | "st" | "img" => {
+            println!(
+                "Warning: {extension} only stores the decoded sectors. Timings, copy protections and non standard tracks are lost!"
+            );
+            write_sector_image(image, path, extension)?;
+        }
+        _ => return Err(ToolError::UnknownFormat(extension.into())),
+    }
+
+    Ok(())
+}
+
+/// Decodes every track and writes the concatenated sectors to the file
+fn write_sector_image(image: &RawImage, path: &str, extension: &str) -> anyhow::Result<()> {
+    let track_parser = track_parser_for_extension(extension)
+        .with_context(|| format!("{extension} is an unknown file extension!"))?;
+    let step_size = track_parser.step_size();
+    let mut track_parsers = vec![track_parser];
+
+    let mut tracks: Vec<_> = image
+        .tracks
+        .iter()
+        // C64 disks only use every second cylinder. The half tracks are not part of a D64.
+        .filter(|f| f.cylinder as usize % step_size == 0)
+        .collect();
+    tracks.sort_by_key(|f| (f.cylinder, f.head));
+
+    let mut payload = Vec::new();
+    for track in tracks {
+        let mut decoded = decode_raw_track(track, &mut track_parsers)?;
+        payload.append(&mut decoded.payload);
+    }
+
+    ensure!(!payload.is_empty(), "No track was decodable as {extension}");
+
+    // Only standard and extended disks can be stored as D64
+    if extension == "d64" {
+        d64_layout(payload.len())?;
+    }
+
+    fs::write(path, payload)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_reader::image_iso::{generate_iso_track, IsoGeometry},
+        rawtrack::RawTrack,
+    };
+    use util::{Density, DensityMapEntry, DiskType, Encoding, PulseDuration};
+
+    #[test]
+    fn write_sector_image_test() {
+        let sectors: Vec<u8> = (0..2 * 9 * 512).map(|f| (f % 249) as u8).collect();
+        let mut sectors_in = sectors.chunks_exact(512);
+
+        // Provide the tracks in reverse order to check the sorting
+        let mut tracks = Vec::new();
+        for head in 0..2 {
+            let trackbuf =
+                generate_iso_track(0, head, &IsoGeometry::new(9), &mut sectors_in).unwrap();
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(168),
+            }];
+            tracks.insert(
+                0,
+                RawTrack::new(0, head, trackbuf, densitymap, Encoding::MFM),
+            );
+        }
+        let image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks,
+        };
+
+        let path = std::env::temp_dir().join("write_sector_image_test.st");
+        let path = path.to_str().unwrap();
+        write_image(&image, path).unwrap();
+        assert_eq!(fs::read(path).unwrap(), sectors);
+        fs::remove_file(path).unwrap();
+
+        assert!(matches!(
+            write_image(&image, "image.scp"),
+            Err(ToolError::UnknownFormat(_))
+        ));
+    }
+
+    #[test]
+    fn supported_write_formats_test() {
+        let extensions: Vec<&str> = supported_write_formats()
+            .iter()
+            .map(|f| f.extension)
+            .collect();
+        assert_eq!(extensions, ["adf", "d64", "g64", "st", "img", "stx"]);
+    }
+}
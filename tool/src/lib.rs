@@ -33,7 +33,18 @@ macro_rules! ensure_index_mut {
     };
 }
 
+pub mod encoding_override;
+pub mod error;
+pub mod filesystem;
+pub mod heatmap;
 pub mod image_reader;
+pub mod image_writer;
+pub mod localization;
+pub mod metrics;
+pub mod raw_capture;
+pub mod resume;
+pub mod rich_image;
+pub mod sector_order;
 pub mod track_parser;
 
 pub mod rawtrack;
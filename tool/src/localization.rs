@@ -0,0 +1,214 @@
+use std::{collections::BTreeMap, fs, sync::OnceLock};
+
+use anyhow::{bail, Context};
+
+use crate::error::ToolError;
+
+/// Status messages shown to the user by the GUI and the CLI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageId {
+    SystemsReady,
+    UsbInitFailed,
+    NoImageLoaded,
+    Checking,
+    Reading,
+    Writing,
+    LetMeSee,
+    NoKnownFormat,
+    ProbableFormat,
+    DiskRead,
+    ImageWritten,
+    ImageWrittenAndVerified,
+    WaitForVerifications,
+    WriteProtected,
+    ImageConverted,
+    NoDriveSelected,
+    BothDrivesSelected,
+    NoDisk,
+    EstimatedWriteTime,
+}
+
+impl MessageId {
+    pub const ALL: [Self; 19] = [
+        Self::SystemsReady,
+        Self::UsbInitFailed,
+        Self::NoImageLoaded,
+        Self::Checking,
+        Self::Reading,
+        Self::Writing,
+        Self::LetMeSee,
+        Self::NoKnownFormat,
+        Self::ProbableFormat,
+        Self::DiskRead,
+        Self::ImageWritten,
+        Self::ImageWrittenAndVerified,
+        Self::WaitForVerifications,
+        Self::WriteProtected,
+        Self::ImageConverted,
+        Self::NoDriveSelected,
+        Self::BothDrivesSelected,
+        Self::NoDisk,
+        Self::EstimatedWriteTime,
+    ];
+
+    /// Key of the message inside a translation file
+    #[must_use]
+    pub const fn key(self) -> &'static str {
+        match self {
+            Self::SystemsReady => "systems_ready",
+            Self::UsbInitFailed => "usb_init_failed",
+            Self::NoImageLoaded => "no_image_loaded",
+            Self::Checking => "checking",
+            Self::Reading => "reading",
+            Self::Writing => "writing",
+            Self::LetMeSee => "let_me_see",
+            Self::NoKnownFormat => "no_known_format",
+            Self::ProbableFormat => "probable_format",
+            Self::DiskRead => "disk_read",
+            Self::ImageWritten => "image_written",
+            Self::ImageWrittenAndVerified => "image_written_and_verified",
+            Self::WaitForVerifications => "wait_for_verifications",
+            Self::WriteProtected => "write_protected",
+            Self::ImageConverted => "image_converted",
+            Self::NoDriveSelected => "no_drive_selected",
+            Self::BothDrivesSelected => "both_drives_selected",
+            Self::NoDisk => "no_disk",
+            Self::EstimatedWriteTime => "estimated_write_time",
+        }
+    }
+
+    /// English default which is used if no translation is available
+    #[must_use]
+    pub const fn english(self) -> &'static str {
+        match self {
+            Self::SystemsReady => "Systems ready!",
+            Self::UsbInitFailed => "Failed to initialize USB device: {}",
+            Self::NoImageLoaded => "No image loaded",
+            Self::Checking => "Checking...",
+            Self::Reading => "Reading...",
+            Self::Writing => "Writing...",
+            Self::LetMeSee => "Let me see...",
+            Self::NoKnownFormat => "No known format detected",
+            Self::ProbableFormat => "Format is probably {}",
+            Self::DiskRead => "Disk read to image!",
+            Self::ImageWritten => "Image written!",
+            Self::ImageWrittenAndVerified => "--- Disk Image written and verified! ---",
+            Self::WaitForVerifications => "All tracks written. Wait for remaining verifications!",
+            Self::WriteProtected => "Disk is write protected!",
+            Self::ImageConverted => "Image converted to {}",
+            Self::NoDriveSelected => "No drive selected! Please specifiy with -a or -b",
+            Self::BothDrivesSelected => "Specify either drive A or B. NOT BOTH!",
+            Self::NoDisk => "No disk detected",
+            Self::EstimatedWriteTime => "Writing will take about {}",
+        }
+    }
+}
+
+/// Translated messages. Missing keys fall back to English.
+#[derive(Default)]
+pub struct StringTable {
+    translations: BTreeMap<MessageId, String>,
+}
+
+impl StringTable {
+    /// Parses a TOML file with the message keys at the top level.
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let table: toml::Table = content.parse().context("Parsing translation file")?;
+        let mut translations = BTreeMap::new();
+
+        for (key, value) in table {
+            let Some(value) = value.as_str() else {
+                bail!("Value of {key} must be a string");
+            };
+
+            match MessageId::ALL.iter().find(|f| f.key() == key) {
+                Some(id) => {
+                    translations.insert(*id, value.to_owned());
+                }
+                None => println!("Translation of unknown message {key} ignored"),
+            }
+        }
+
+        Ok(Self { translations })
+    }
+
+    /// Loads `~/.usbfloppytracer/language.toml` if it exists
+    pub fn load() -> anyhow::Result<Self> {
+        let path = home::home_dir()
+            .context("Home Directoy not available")?
+            .join(".usbfloppytracer/language.toml");
+
+        let content = fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"))?;
+        Self::parse(&content)
+    }
+
+    #[must_use]
+    pub fn get(&self, id: MessageId) -> &str {
+        self.translations
+            .get(&id)
+            .map_or_else(|| id.english(), String::as_str)
+    }
+}
+
+static STRING_TABLE: OnceLock<StringTable> = OnceLock::new();
+
+/// Provides the message in the language of the user
+#[must_use]
+pub fn tr(id: MessageId) -> &'static str {
+    STRING_TABLE
+        .get_or_init(|| StringTable::load().unwrap_or_default())
+        .get(id)
+}
+
+/// Provides the message with the placeholder `{}` replaced by the argument
+#[must_use]
+pub fn tr_with(id: MessageId, argument: &str) -> String {
+    tr(id).replacen("{}", argument, 1)
+}
+
+/// Provides the message of an error. Known errors are translated.
+#[must_use]
+pub fn error_message(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<ToolError>() {
+        Some(ToolError::WriteProtected) => tr(MessageId::WriteProtected).into(),
+        Some(ToolError::NoDisk) => tr(MessageId::NoDisk).into(),
+        _ => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_table_test() {
+        let table = StringTable::parse(
+            "# German\nreading = \"Lese...\"\n\nimage_converted = \"Abbild nach {} konvertiert\"\nunknown = \"x\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(table.get(MessageId::Reading), "Lese...");
+        assert_eq!(
+            table.get(MessageId::ImageConverted),
+            "Abbild nach {} konvertiert"
+        );
+        // Missing translations fall back to English
+        assert_eq!(table.get(MessageId::Writing), "Writing...");
+
+        assert!(StringTable::parse("reading = Lese...").is_err());
+        assert!(StringTable::parse("reading").is_err());
+        assert!(StringTable::parse("reading = 5").is_err());
+        assert_eq!(
+            StringTable::parse(r#"reading = "\"Lese\"""#)
+                .unwrap()
+                .get(MessageId::Reading),
+            "\"Lese\""
+        );
+
+        // Every key must be unique
+        let mut keys: Vec<_> = MessageId::ALL.iter().map(|f| f.key()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(keys.len(), MessageId::ALL.len());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::usb_commands::UsbAnswer;
+
+/// Upper bounds of the `max_err` histogram in ticks of the 84 MHz timer.
+/// The firmware accepts deviations of about a third of a cell.
+const MAX_ERR_BUCKETS: [u32; 7] = [10, 20, 30, 40, 50, 60, 80];
+
+/// Outcome of the last write of a track
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackResult {
+    Verified { max_err: u32 },
+    Unverified,
+    Failed,
+}
+
+/// Wall clock time the device spent on a track with the operations of its answer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackTiming {
+    pub cylinder: u32,
+    pub head: u32,
+    pub duration: Duration,
+    pub writes: u32,
+    pub reads: u32,
+    pub result: TrackResult,
+}
+
+/// Statistics of writing an image, collected from the answers of the device
+#[derive(Default, Debug)]
+pub struct WriteMetrics {
+    tracks_verified: u64,
+    tracks_failed: u64,
+    tracks_unverified: u64,
+    writes: u64,
+    reads: u64,
+    retries: u64,
+    /// Number of verified tracks with a `max_err` up to the bound of the bucket.
+    /// The buckets are not cumulative.
+    max_err_buckets: [u64; MAX_ERR_BUCKETS.len()],
+    max_err_sum: u64,
+    /// Result of every track by cylinder and head. A repeated write replaces the result.
+    track_results: BTreeMap<(u32, u32), TrackResult>,
+    /// Time at which the transfer of every track still awaiting its answer was started
+    track_starts: BTreeMap<(u32, u32), Instant>,
+    /// Answer of the previous track. With a pipeline, the device only starts afterwards.
+    last_answer: Option<Instant>,
+    track_timings: Vec<TrackTiming>,
+}
+
+impl WriteMetrics {
+    /// Marks the moment the transfer of a track is issued to measure its duration
+    pub fn track_started(&mut self, cylinder: u32, head: u32) {
+        self.track_starts.insert((cylinder, head), Instant::now());
+    }
+
+    pub fn record(&mut self, answer: &UsbAnswer) {
+        let (writes, reads) = match answer {
+            UsbAnswer::WrittenAndVerified {
+                cylinder,
+                head,
+                writes,
+                reads,
+                ..
+            } if *reads == 0 => {
+                // Written without verification. There is no deviation to record.
+                self.tracks_unverified += 1;
+                self.track_results
+                    .insert((*cylinder, *head), TrackResult::Unverified);
+                (writes, reads)
+            }
+            UsbAnswer::WrittenAndVerified {
+                cylinder,
+                head,
+                writes,
+                reads,
+                max_err,
+                ..
+            } => {
+                self.tracks_verified += 1;
+                self.track_results.insert(
+                    (*cylinder, *head),
+                    TrackResult::Verified { max_err: *max_err },
+                );
+                self.max_err_sum += u64::from(*max_err);
+                if let Some(bucket) = MAX_ERR_BUCKETS
+                    .iter()
+                    .position(|f| max_err <= f)
+                    .and_then(|f| self.max_err_buckets.get_mut(f))
+                {
+                    *bucket += 1;
+                }
+                (writes, reads)
+            }
+            UsbAnswer::Fail {
+                cylinder,
+                head,
+                writes,
+                reads,
+                ..
+            } => {
+                self.tracks_failed += 1;
+                self.track_results
+                    .insert((*cylinder, *head), TrackResult::Failed);
+                (writes, reads)
+            }
+            UsbAnswer::GotCmd { .. }
+            | UsbAnswer::WriteProtected
+            | UsbAnswer::OutOfMemory { .. }
+            | UsbAnswer::VerifyHistogram(_)
+            | UsbAnswer::TrackShortened { .. } => return,
+        };
+
+        if let UsbAnswer::WrittenAndVerified { cylinder, head, .. }
+        | UsbAnswer::Fail { cylinder, head, .. } = answer
+        {
+            let now = Instant::now();
+            if let (Some(start), Some(result)) = (
+                self.track_starts.remove(&(*cylinder, *head)),
+                self.track_results.get(&(*cylinder, *head)),
+            ) {
+                // Tracks transferred ahead of time wait for the previous one to be finished
+                let start = self.last_answer.map_or(start, |f| f.max(start));
+                self.track_timings.push(TrackTiming {
+                    cylinder: *cylinder,
+                    head: *head,
+                    duration: now - start,
+                    writes: *writes,
+                    reads: *reads,
+                    result: *result,
+                });
+            }
+            self.last_answer = Some(now);
+        }
+
+        self.writes += u64::from(*writes);
+        self.reads += u64::from(*reads);
+        self.retries += u64::from(writes.saturating_sub(1));
+    }
+
+    #[must_use]
+    pub fn track_results(&self) -> &BTreeMap<(u32, u32), TrackResult> {
+        &self.track_results
+    }
+
+    /// Duration of every track in the order of their answers. Repeated writes are listed again.
+    #[must_use]
+    pub fn track_timings(&self) -> &[TrackTiming] {
+        &self.track_timings
+    }
+
+    /// Provides the duration of every track as CSV with a header line
+    #[must_use]
+    pub fn timings_to_csv(&self) -> String {
+        let mut csv = String::from("cylinder,head,duration_ms,writes,reads,result\n");
+        for timing in &self.track_timings {
+            let result = match timing.result {
+                TrackResult::Verified { .. } => "verified",
+                TrackResult::Unverified => "unverified",
+                TrackResult::Failed => "failed",
+            };
+            csv.push_str(&format!(
+                "{},{},{},{},{},{result}\n",
+                timing.cylinder,
+                timing.head,
+                timing.duration.as_millis(),
+                timing.writes,
+                timing.reads,
+            ));
+        }
+        csv
+    }
+
+    /// Provides the metrics in the text based exposition format of Prometheus
+    #[must_use]
+    pub fn to_prometheus(&self) -> String {
+        let mut lines = Vec::new();
+
+        for (name, help, value) in [
+            (
+                "tracks_verified",
+                "Tracks which were written and verified",
+                self.tracks_verified,
+            ),
+            (
+                "tracks_failed",
+                "Tracks which failed to verify",
+                self.tracks_failed,
+            ),
+            (
+                "tracks_unverified",
+                "Tracks which were written without verification",
+                self.tracks_unverified,
+            ),
+            ("writes", "Write operations of all tracks", self.writes),
+            ("reads", "Verify reads of all tracks", self.reads),
+            ("retries", "Writes which had to be repeated", self.retries),
+        ] {
+            lines.push(format!("# HELP usbfloppytracer_{name}_total {help}"));
+            lines.push(format!("# TYPE usbfloppytracer_{name}_total counter"));
+            lines.push(format!("usbfloppytracer_{name}_total {value}"));
+        }
+
+        let name = "usbfloppytracer_max_err_ticks";
+        lines.push(format!(
+            "# HELP {name} Largest deviation of a verified track in ticks of 84 MHz"
+        ));
+        lines.push(format!("# TYPE {name} histogram"));
+        // Buckets of Prometheus also count everything below their bound
+        let mut count = 0;
+        for (bound, bucket) in MAX_ERR_BUCKETS.iter().zip(self.max_err_buckets) {
+            count += bucket;
+            lines.push(format!("{name}_bucket{{le=\"{bound}\"}} {count}"));
+        }
+        lines.push(format!(
+            "{name}_bucket{{le=\"+Inf\"}} {}",
+            self.tracks_verified
+        ));
+        lines.push(format!("{name}_sum {}", self.max_err_sum));
+        lines.push(format!("{name}_count {}", self.tracks_verified));
+
+        lines.join("\n") + "\n"
+    }
+
+    /// Writes the metrics for the textfile collector of the node exporter.
+    /// The file is replaced at once to never provide a partial file.
+    pub fn write_textfile(&self, path: &Path) -> anyhow::Result<()> {
+        let temporary = path.with_extension("prom.tmp");
+        fs::write(&temporary, self.to_prometheus())?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_metrics_test() {
+        let verified = |writes, max_err| UsbAnswer::WrittenAndVerified {
+            cylinder: 0,
+            head: 0,
+            writes,
+            reads: writes * 2,
+            max_err,
+            write_precomp: 0,
+        };
+
+        let mut metrics = WriteMetrics::default();
+        metrics.record(&verified(1, 15));
+        metrics.record(&verified(3, 35));
+        metrics.record(&verified(1, 200));
+        metrics.record(&UsbAnswer::Fail {
+            cylinder: 1,
+            head: 0,
+            writes: 5,
+            reads: 10,
+            error: "Verification failed".into(),
+        });
+        metrics.record(&UsbAnswer::WrittenAndVerified {
+            cylinder: 2,
+            head: 0,
+            writes: 1,
+            reads: 0,
+            max_err: 0,
+            write_precomp: 0,
+        });
+        metrics.record(&UsbAnswer::GotCmd { reception: None });
+
+        let text = metrics.to_prometheus();
+        for line in [
+            "usbfloppytracer_tracks_verified_total 3",
+            "usbfloppytracer_tracks_failed_total 1",
+            "usbfloppytracer_tracks_unverified_total 1",
+            "usbfloppytracer_writes_total 11",
+            "usbfloppytracer_reads_total 20",
+            "usbfloppytracer_retries_total 6",
+            "usbfloppytracer_max_err_ticks_bucket{le=\"10\"} 0",
+            "usbfloppytracer_max_err_ticks_bucket{le=\"20\"} 1",
+            "usbfloppytracer_max_err_ticks_bucket{le=\"40\"} 2",
+            "usbfloppytracer_max_err_ticks_bucket{le=\"80\"} 2",
+            "usbfloppytracer_max_err_ticks_bucket{le=\"+Inf\"} 3",
+            "usbfloppytracer_max_err_ticks_sum 250",
+            "usbfloppytracer_max_err_ticks_count 3",
+        ] {
+            assert!(text.lines().any(|f| f == line), "{line} is missing");
+        }
+    }
+
+    #[test]
+    fn timings_to_csv_test() {
+        let mut metrics = WriteMetrics::default();
+        metrics.track_started(0, 0);
+        metrics.track_started(0, 1);
+        metrics.record(&UsbAnswer::WrittenAndVerified {
+            cylinder: 0,
+            head: 0,
+            writes: 2,
+            reads: 3,
+            max_err: 20,
+            write_precomp: 0,
+        });
+        metrics.record(&UsbAnswer::Fail {
+            cylinder: 0,
+            head: 1,
+            writes: 5,
+            reads: 10,
+            error: "Verification failed".into(),
+        });
+        // Never started, so there is no duration
+        metrics.record(&UsbAnswer::Fail {
+            cylinder: 1,
+            head: 0,
+            writes: 1,
+            reads: 1,
+            error: "Verification failed".into(),
+        });
+
+        let csv = metrics.timings_to_csv();
+        let rows: Vec<Vec<&str>> = csv.lines().map(|f| f.split(',').collect()).collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows.iter().all(|f| f.len() == 6));
+        let cells = |row: usize, columns: std::ops::Range<usize>| {
+            rows.get(row).and_then(|f| f.get(columns)).unwrap().to_vec()
+        };
+        assert_eq!(cells(0, 2..3), ["duration_ms"]);
+        assert_eq!(cells(1, 0..2), ["0", "0"]);
+        assert_eq!(cells(1, 3..6), ["2", "3", "verified"]);
+        assert_eq!(cells(2, 0..2), ["0", "1"]);
+        assert_eq!(cells(2, 3..6), ["5", "10", "failed"]);
+    }
+}
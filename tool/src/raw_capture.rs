@@ -0,0 +1,156 @@
+use std::{
+    convert::TryInto,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, ensure, Context};
+use util::{Density, PulseDuration};
+
+use crate::track_parser::{track_parser_for_extension, TrackPayload};
+
+/// Identifies the file format and its version
+const RAW_CAPTURE_MAGIC: &[u8; 8] = b"UFTRAW01";
+/// Magic, cylinder, head and density
+const RAW_CAPTURE_HEADER_SIZE: usize = 8 + 4 + 4 + 1;
+
+/// Flux timings of a single read as they were provided by the device.
+/// Allows the development of track parsers without hardware.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RawCapture {
+    pub cylinder: u32,
+    pub head: u32,
+    pub density: Density,
+    pub flux_timings: Vec<PulseDuration>,
+}
+
+impl RawCapture {
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(RAW_CAPTURE_HEADER_SIZE + self.flux_timings.len() * 4);
+        result.extend_from_slice(RAW_CAPTURE_MAGIC);
+        result.extend_from_slice(&self.cylinder.to_le_bytes());
+        result.extend_from_slice(&self.head.to_le_bytes());
+        result.push(match self.density {
+            Density::SingleDouble => 0,
+            Density::High => 1,
+        });
+        for pulse in &self.flux_timings {
+            result.extend_from_slice(&pulse.0.to_le_bytes());
+        }
+        result
+    }
+
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let (header, flux_timings) = data
+            .split_at_checked(RAW_CAPTURE_HEADER_SIZE)
+            .context("Raw capture is too short")?;
+        let (magic, header) = header.split_at(RAW_CAPTURE_MAGIC.len());
+        ensure!(magic == RAW_CAPTURE_MAGIC, "Not a raw capture");
+
+        let word = |offset: usize| -> anyhow::Result<u32> {
+            let bytes = header
+                .get(offset..offset + 4)
+                .context(index_out_of_bounds!())?;
+            Ok(u32::from_le_bytes(bytes.try_into()?))
+        };
+
+        let density = match header.get(8) {
+            Some(0) => Density::SingleDouble,
+            Some(1) => Density::High,
+            _ => bail!("Raw capture has an unknown density"),
+        };
+
+        ensure!(
+            flux_timings.len() % 4 == 0,
+            "Raw capture ends with an incomplete flux timing"
+        );
+
+        Ok(Self {
+            cylinder: word(0)?,
+            head: word(4)?,
+            density,
+            flux_timings: flux_timings
+                .chunks_exact(4)
+                .map(|f| Ok(PulseDuration(i32::from_le_bytes(f.try_into()?))))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// Stores the capture in the directory. Every read attempt gets its own file.
+    pub fn save(&self, directory: &Path, attempt: usize) -> anyhow::Result<PathBuf> {
+        let path = directory.join(format!(
+            "c{:02}_h{}_r{}.raw",
+            self.cylinder, self.head, attempt
+        ));
+        fs::write(&path, self.to_bytes())?;
+        Ok(path)
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Reading {path}"))?;
+        Self::from_bytes(&data)
+    }
+}
+
+/// Decodes a raw capture with the parser of a sector based image format
+pub fn parse_raw_capture(path: &str, file_extension: &str) -> anyhow::Result<TrackPayload> {
+    let capture = RawCapture::load(path)?;
+    let mut track_parser = track_parser_for_extension(file_extension)
+        .with_context(|| format!("{file_extension} is an unknown file extension!"))?;
+
+    ensure!(
+        track_parser.track_density() == capture.density,
+        "Capture was recorded with {:?} density but {file_extension} expects {:?}",
+        capture.density,
+        track_parser.track_density()
+    );
+
+    track_parser.expect_track(capture.cylinder, capture.head);
+    track_parser.parse_flux_timings(&capture.flux_timings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_reader::image_iso::{generate_iso_track, IsoGeometry},
+        rawtrack::RawTrack,
+        track_parser::expand_reduced_pulses,
+    };
+    use util::{DensityMapEntry, Encoding};
+
+    #[test]
+    fn raw_capture_test() {
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 241) as u8).collect();
+        let trackbuf =
+            generate_iso_track(2, 1, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let track = RawTrack::new(2, 1, trackbuf, densitymap, Encoding::MFM);
+        let capture = RawCapture {
+            cylinder: 2,
+            head: 1,
+            density: Density::SingleDouble,
+            flux_timings: expand_reduced_pulses(&track.simulate_read(2).unwrap()),
+        };
+
+        let bytes = capture.to_bytes();
+        assert_eq!(RawCapture::from_bytes(&bytes).unwrap(), capture);
+        assert!(RawCapture::from_bytes(bytes.split_last().unwrap().1).is_err());
+        assert!(RawCapture::from_bytes(b"UFTRAW00").is_err());
+
+        let directory = std::env::temp_dir().join("raw_capture_test");
+        fs::create_dir_all(&directory).unwrap();
+        let path = capture.save(&directory, 3).unwrap();
+        assert!(path.ends_with("c02_h1_r3.raw"));
+        let path = path.to_str().unwrap();
+
+        assert_eq!(parse_raw_capture(path, "st").unwrap().payload, sectors);
+        // The density of the capture doesn't fit
+        assert!(parse_raw_capture(path, "img").is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
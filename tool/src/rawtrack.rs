@@ -1,10 +1,35 @@
 use anyhow::{ensure, Context};
-use std::cell::RefCell;
+
+use crate::error::ToolError;
+use std::{cell::RefCell, convert::TryFrom, time::Duration};
 use util::{
     bitstream::to_bit_stream, fluxpulse::FluxPulseGenerator, Bit, Density, DensityMap, DiskType,
-    Encoding, RawCellData, STM_TIMER_MHZ,
+    Encoding, GapGenerator, PulseDuration, RawCellData, VerifyWindows, WriteGateMargins,
+    PULSE_REDUCE_SHIFT, STM_TIMER_HZ, STM_TIMER_MHZ, WRITE_PREFILL_PULSES,
 };
 
+/// Default margin for `RawTrack::check_rotation_margin`.
+/// Drives are usually specified with a speed tolerance of 1.5%.
+pub const DEFAULT_ROTATION_MARGIN_PERCENT: f64 = 1.5;
+
+/// Stepping to the next cylinder including the time for the head to settle
+const SEEK_OVERHEAD_SECONDS: f64 = 0.02;
+
+/// Tracks deviating more than this from the typical duration of the image are irregular
+const UNUSUAL_LENGTH_PERCENT: f64 = 2.0;
+
+/// Cells shorter than this require the drive in high density mode.
+/// Between the 1µs cells of high density and the 2µs cells of double density.
+const HIGH_DENSITY_CELL_SIZE_LIMIT: i32 = 126;
+
+/// Distinguishes plain data tracks from tracks which are likely part of a copy protection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackClass {
+    Standard,
+    /// Flux timings, a non flux reversal area, multiple densities or an unusual length
+    Irregular,
+}
+
 pub struct RawImage {
     pub density: Density,
     pub disk_type: DiskType,
@@ -29,6 +54,129 @@ impl RawImage {
             })
         });
     }
+
+    /// Orders the tracks by cylinder and head to avoid unnecessary movement of the head.
+    /// The order of tracks is also the order of the expected verifications.
+    pub fn sort_tracks_for_seeking(&mut self) {
+        self.tracks.sort_by_key(|f| (f.cylinder, f.head));
+    }
+
+    /// Rough estimation of the time needed to write and verify every track.
+    /// Writing starts at the index which takes half a rotation on average.
+    /// The verification reads the track in the following rotation.
+    #[must_use]
+    pub fn estimated_write_time(&self, rpm: f64) -> Duration {
+        let rotation = 60.0 / rpm;
+        let mut seconds = 0.0;
+        let mut last_cylinder = None;
+
+        for track in &self.tracks {
+            seconds += rotation / 2.0 + track.calculate_duration_of_track();
+            if !track.write_only {
+                seconds += rotation;
+            }
+            if last_cylinder != Some(track.cylinder) {
+                seconds += SEEK_OVERHEAD_SECONDS;
+            }
+            last_cylinder = Some(track.cylinder);
+        }
+
+        Duration::from_secs_f64(seconds)
+    }
+
+    /// Median of the duration of all tracks
+    fn typical_track_duration(&self) -> Option<f64> {
+        let mut durations: Vec<f64> = self
+            .tracks
+            .iter()
+            .map(RawTrack::calculate_duration_of_track)
+            .collect();
+        durations.sort_by(f64::total_cmp);
+        durations.get(durations.len() / 2).copied()
+    }
+
+    /// Classifies every track in the order of the tracks
+    #[must_use]
+    pub fn classify_tracks(&self) -> Vec<TrackClass> {
+        let typical_duration = self.typical_track_duration().unwrap_or_default();
+        self.tracks
+            .iter()
+            .map(|f| f.classify(typical_duration))
+            .collect()
+    }
+
+    /// Ensures the density map of every track covers its data exactly.
+    /// Otherwise the transfer of the track to the device would fail.
+    pub fn check_densitymaps(&self) -> Result<(), ToolError> {
+        self.tracks.iter().try_for_each(RawTrack::check_densitymap)
+    }
+
+    /// Removes every track which is not of the given class
+    pub fn retain_track_class(&mut self, class: TrackClass) {
+        let mut classes = self.classify_tracks().into_iter();
+        self.tracks
+            .retain(|_| classes.next().is_some_and(|f| f == class));
+    }
+
+    /// Compares the image against the drive which shall write it.
+    /// Returns a warning for every mismatch that is likely a configuration mistake.
+    #[must_use]
+    pub fn check_drive_compatibility(&self, drive_type: DiskType) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        match (self.disk_type, drive_type) {
+            (DiskType::Inch5_25, DiskType::Inch3_5) => warnings.push(
+                "Warning: The image is meant for 5.25\" disks but a 3.5\" drive is selected. Use a 5.25\" drive to get a disk readable by the original system.".into(),
+            ),
+            (DiskType::Inch3_5, DiskType::Inch5_25) => warnings.push(
+                "Warning: The image is meant for 3.5\" disks but a 5.25\" drive is selected. Use a 3.5\" drive to get a disk readable by the original system.".into(),
+            ),
+            _ => {}
+        }
+
+        if self.density == Density::SingleDouble {
+            let high_density_tracks: Vec<String> = self
+                .tracks
+                .iter()
+                .filter(|f| {
+                    f.flux_timings.is_none()
+                        && f.densitymap
+                            .iter()
+                            .any(|g| g.cell_size.0 < HIGH_DENSITY_CELL_SIZE_LIMIT)
+                })
+                .map(|f| format!("{}:{}", f.cylinder, f.head))
+                .collect();
+
+            if let Some(first_track) = high_density_tracks.first() {
+                warnings.push(format!(
+                    "Warning: {} tracks starting with {first_track} contain high density data but the drive will be configured for double density. Verification will fail. Check the image or its format.",
+                    high_density_tracks.len()
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// Flippy disks are double density 5.25" disks whose back side is written with a simulated index.
+    /// Returns a warning for every property of the image which doesn't fit to this.
+    #[must_use]
+    pub fn check_flippy_compatibility(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if matches!(self.disk_type, DiskType::Inch3_5) {
+            warnings.push(
+                "Warning: Flippy mode is meant for 5.25\" disks but the image is meant for 3.5\" disks. Disable flippy mode.".into(),
+            );
+        }
+        if self.density == Density::High {
+            warnings.push(
+                "Warning: Flippy mode is meant for double density disks but the image has high density.".into(),
+            );
+        }
+
+        warnings
+    }
 }
 
 pub struct RawTrack {
@@ -39,6 +187,16 @@ pub struct RawTrack {
     pub encoding: Encoding,
     pub write_precompensation: u32,
     pub has_non_flux_reversal_area: bool,
+    /// Forces the generator for long gaps. Otherwise chosen by `has_non_flux_reversal_area`.
+    pub gap_generator: Option<GapGenerator>,
+    pub verify_windows: VerifyWindows,
+    /// Precomputed flux timings. If provided, these are written directly
+    /// and `raw_data` with its `densitymap` is ignored.
+    pub flux_timings: Option<Vec<PulseDuration>>,
+    /// Skip the verification after writing. The device still reports the track as written.
+    pub write_only: bool,
+    /// Requires a device with `FEATURE_WRITE_GATE_MARGINS` if not the default
+    pub write_gate_margins: WriteGateMargins,
 }
 
 impl RawTrack {
@@ -58,6 +216,11 @@ impl RawTrack {
             encoding,
             write_precompensation: 0,
             has_non_flux_reversal_area: false,
+            gap_generator: None,
+            verify_windows: VerifyWindows::default(),
+            flux_timings: None,
+            write_only: false,
+            write_gate_margins: WriteGateMargins::default(),
         }
     }
 
@@ -78,11 +241,110 @@ impl RawTrack {
             encoding,
             write_precompensation: 0,
             has_non_flux_reversal_area,
+            gap_generator: None,
+            verify_windows: VerifyWindows::default(),
+            flux_timings: None,
+            write_only: false,
+            write_gate_margins: WriteGateMargins::default(),
+        }
+    }
+
+    /// Creates a track which bypasses the encoding and is written as provided.
+    #[must_use]
+    pub fn new_with_flux_timings(
+        cylinder: u32,
+        head: u32,
+        flux_timings: Vec<PulseDuration>,
+        encoding: Encoding,
+    ) -> Self {
+        Self {
+            cylinder,
+            head,
+            raw_data: Vec::new(),
+            densitymap: Vec::new(),
+            encoding,
+            write_precompensation: 0,
+            has_non_flux_reversal_area: false,
+            gap_generator: None,
+            verify_windows: VerifyWindows::default(),
+            flux_timings: Some(flux_timings),
+            write_only: false,
+            write_gate_margins: WriteGateMargins::default(),
         }
     }
 
+    /// Generator used for long gaps without flux reversals while writing
+    #[must_use]
+    pub fn effective_gap_generator(&self) -> GapGenerator {
+        match self.gap_generator {
+            Some(gap_generator) => gap_generator,
+            None if self.has_non_flux_reversal_area => GapGenerator::NonFluxReversal,
+            None => GapGenerator::WeakBits,
+        }
+    }
+
+    /// Converts the flux timings into the reduced form used by the USB protocol.
+    /// It is the same form as used for reading tracks.
+    pub fn reduced_flux_timings(&self) -> anyhow::Result<Vec<u8>> {
+        let flux_timings = self.flux_timings.as_ref().context("No flux timings")?;
+
+        flux_timings
+            .iter()
+            .map(|pulse| {
+                let reduced_pulse = (pulse.0 + (1 << (PULSE_REDUCE_SHIFT - 1))) >> PULSE_REDUCE_SHIFT;
+                u8::try_from(reduced_pulse)
+                    .ok()
+                    .filter(|f| *f != 0)
+                    .with_context(|| format!("Flux timing {} can't be transferred", pulse.0))
+            })
+            .collect()
+    }
+
+    /// Generates the flux of this track as if it was read back from disk.
+    /// The result has the reduced form provided by the device during reading.
+    pub fn simulate_read(&self, revolutions: usize) -> anyhow::Result<Vec<u8>> {
+        if self.flux_timings.is_some() {
+            return Ok(self.reduced_flux_timings()?.repeat(revolutions));
+        }
+
+        let first_cell_size = self.densitymap.first().context("Missing densitymap data")?;
+        let cell_data_parts = RawCellData::split_in_parts(&self.densitymap, &self.raw_data)
+            .context("Failed to split raw cell data")?;
+
+        let mut result = Vec::new();
+        let mut write_prod_fpg = FluxPulseGenerator::new(
+            |f| {
+                // Same rounding as performed by the firmware
+                let mut reduced_pulse = f.0 >> PULSE_REDUCE_SHIFT;
+                if f.0 & 0b100 != 0 {
+                    reduced_pulse += 1;
+                }
+                result.push(reduced_pulse.clamp(0, 0xff) as u8);
+            },
+            first_cell_size.cell_size.0 as u32,
+        );
+
+        for _ in 0..revolutions {
+            for part in &cell_data_parts {
+                write_prod_fpg.cell_duration = part.cell_size.0 as u32;
+
+                for cell_byte in part.cells {
+                    to_bit_stream(*cell_byte, |bit| write_prod_fpg.feed(bit));
+                }
+            }
+        }
+        write_prod_fpg.flush();
+
+        Ok(result)
+    }
+
     #[must_use]
     pub fn calculate_duration_of_track(&self) -> f64 {
+        if let Some(flux_timings) = &self.flux_timings {
+            let ticks: i64 = flux_timings.iter().map(|f| i64::from(f.0)).sum();
+            return ticks as f64 / STM_TIMER_HZ;
+        }
+
         let mut accumulator = 0.0;
 
         for entry in &self.densitymap {
@@ -93,20 +355,151 @@ impl RawTrack {
         accumulator
     }
 
-    pub fn assert_fits_into_rotation(&self, rpm: f64) -> anyhow::Result<()> {
-        let seconds_per_rotation = 60.0 / rpm;
-        let duration_of_track = self.calculate_duration_of_track();
+    /// Extends the gap at the end of the track until it fills a rotation of a drive
+    /// which is faster than `rpm` by the margin. This places the splice of index aligned
+    /// tracks always at the same position. Only tracks ending with a repeated 16 cell
+    /// pattern, like the gaps of ISO and Amiga tracks, are extended.
+    /// Returns the number of added bytes.
+    pub fn fill_rotation(&mut self, rpm: f64, margin_percent: f64) -> usize {
+        if self.flux_timings.is_some() {
+            return 0;
+        }
+        let Some(cell_size) = self.densitymap.last().map(|f| f.cell_size) else {
+            return 0;
+        };
+        let gap_word = match self.raw_data.len().checked_sub(4) {
+            Some(start) => match self.raw_data.get(start..) {
+                Some([a, b, c, d]) if [a, b] == [c, d] => [*c, *d],
+                _ => return 0,
+            },
+            None => return 0,
+        };
 
-        ensure!(
-        duration_of_track < seconds_per_rotation,
-            "Error: With {} seconds, the track {} will not fit into one single rotation of the disk!",
-            duration_of_track, self.cylinder
-        );
+        let fastest_rpm = rpm * (1.0 + margin_percent / 100.0);
+        let remaining = 60.0 / fastest_rpm - self.calculate_duration_of_write();
+        let seconds_per_byte = 8.0 * f64::from(cell_size.0) / STM_TIMER_HZ;
+        // Stay below a complete rotation and keep the pattern of the gap
+        let words = ((remaining / seconds_per_byte - 1.0) / 2.0).floor();
+        if words < 1.0 {
+            return 0;
+        }
+        let added = 2 * words as usize;
+
+        for _ in 0..words as usize {
+            self.raw_data.extend_from_slice(&gap_word);
+        }
+        if let Some(last_entry) = self.densitymap.last_mut() {
+            last_entry.number_of_cellbytes += added;
+        }
+        added
+    }
+
+    /// Describes the density map in human readable form. One line per entry.
+    #[must_use]
+    pub fn format_densitymap(&self) -> String {
+        let mut result = format!("Cylinder {} Head {}\n", self.cylinder, self.head);
+
+        for entry in &self.densitymap {
+            let data_rate_kbps = STM_TIMER_HZ / f64::from(entry.cell_size.0) / 1000.0;
+            result += &format!(
+                "  {:6} cellbytes with cell size {:3} ({:.1} kbps)\n",
+                entry.number_of_cellbytes, entry.cell_size.0, data_rate_kbps
+            );
+        }
+
+        result
+    }
+
+    /// Tracks of flux timings have no density map and are always fine
+    pub fn check_densitymap(&self) -> Result<(), ToolError> {
+        if self.flux_timings.is_some() {
+            return Ok(());
+        }
+
+        let densitymap_bytes: usize = self.densitymap.iter().map(|f| f.number_of_cellbytes).sum();
+        if densitymap_bytes != self.raw_data.len() {
+            return Err(ToolError::DensityMapMismatch {
+                cylinder: self.cylinder,
+                head: self.head,
+                densitymap_bytes,
+                raw_data_bytes: self.raw_data.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Duration of the write including the lead-in and lead-out of the write gate
+    #[must_use]
+    pub fn calculate_duration_of_write(&self) -> f64 {
+        let margins_us = self.write_gate_margins.lead_in_us + self.write_gate_margins.lead_out_us;
+        self.calculate_duration_of_track() + f64::from(margins_us) * 1e-6
+    }
+
+    pub fn assert_fits_into_rotation(&self, rpm: f64) -> Result<(), ToolError> {
+        let seconds_per_rotation = 60.0 / rpm;
+        let duration_of_track = self.calculate_duration_of_write();
+
+        if duration_of_track >= seconds_per_rotation {
+            return Err(ToolError::TrackTooLong {
+                cylinder: self.cylinder,
+                head: self.head,
+                duration: duration_of_track,
+            });
+        }
 
         Ok(())
     }
 
+    /// Checks if the track also fits into one rotation of a drive which is faster
+    /// than the given RPM by the margin. Returns a warning if the track is that long.
+    #[must_use]
+    pub fn check_rotation_margin(&self, rpm: f64, margin_percent: f64) -> Option<String> {
+        let fastest_rpm = rpm * (1.0 + margin_percent / 100.0);
+
+        self.assert_fits_into_rotation(fastest_rpm).err().map(|_| {
+            format!(
+                "Warning: Track {} {} leaves less than {margin_percent}% of a rotation unused. Writing might fail on drives which are faster than {rpm} RPM.",
+                self.cylinder, self.head
+            )
+        })
+    }
+
+    /// Decides if the track is a plain data track or probably part of a copy protection.
+    /// The duration is compared against the typical duration of the tracks of the image.
+    #[must_use]
+    pub fn classify(&self, typical_duration: f64) -> TrackClass {
+        let duration = self.calculate_duration_of_track();
+        let unusual_length = typical_duration > 0.0
+            && (duration - typical_duration).abs() / typical_duration * 100.0
+                > UNUSUAL_LENGTH_PERCENT;
+
+        if self.flux_timings.is_some()
+            || self.has_non_flux_reversal_area
+            || self.densitymap.len() > 1
+            || unusual_length
+        {
+            TrackClass::Irregular
+        } else {
+            TrackClass::Standard
+        }
+    }
+
     pub fn check_writability(&self) -> anyhow::Result<()> {
+        if self.flux_timings.is_some() {
+            // Flux timings are written as they are. Just make sure we can transfer them.
+            let flux_data = self.reduced_flux_timings()?;
+            ensure!(
+                flux_data.len() >= WRITE_PREFILL_PULSES,
+                "Track {} {} has only {} flux timings. At least {} are required for writing.",
+                self.cylinder,
+                self.head,
+                flux_data.len(),
+                WRITE_PREFILL_PULSES
+            );
+            return Ok(());
+        }
+
         let first_cell_size = self.densitymap.get(0).context("Missing densitymap data")?;
         let first_cell_size = first_cell_size.cell_size.0;
 
@@ -257,6 +650,7 @@ impl TrackFilter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use util::{DensityMapEntry, DRIVE_3_5_RPM};
 
     #[test]
     fn track_filter_test() {
@@ -296,4 +690,268 @@ mod tests {
         let filter = TrackFilter::new("-");
         assert!(filter.is_err());
     }
+
+    #[test]
+    fn sort_tracks_for_seeking_test() {
+        let track = |cylinder, head| RawTrack::new(cylinder, head, Vec::new(), Vec::new(), Encoding::MFM);
+
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![track(2, 1), track(0, 0), track(2, 0), track(1, 1), track(0, 1)],
+        };
+
+        image.sort_tracks_for_seeking();
+
+        let order: Vec<(u32, u32)> = image.tracks.iter().map(|f| (f.cylinder, f.head)).collect();
+        assert_eq!(order, vec![(0, 0), (0, 1), (1, 1), (2, 0), (2, 1)]);
+    }
+
+    #[test]
+    fn estimated_write_time_test() {
+        let track =
+            |cylinder, head| RawTrack::new(cylinder, head, Vec::new(), Vec::new(), Encoding::MFM);
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![track(0, 0), track(0, 1)],
+        };
+
+        // Half a rotation waiting for the index and one for verification per track
+        assert_eq!(image.estimated_write_time(300.0).as_millis(), 620);
+
+        image.tracks.get_mut(1).unwrap().write_only = true;
+        assert_eq!(image.estimated_write_time(300.0).as_millis(), 420);
+    }
+
+    #[test]
+    fn format_densitymap_test() {
+        let densitymap = vec![
+            DensityMapEntry {
+                number_of_cellbytes: 100,
+                cell_size: PulseDuration(168),
+            },
+            DensityMapEntry {
+                number_of_cellbytes: 20,
+                cell_size: PulseDuration(84),
+            },
+        ];
+        let track = RawTrack::new(3, 1, vec![0; 120], densitymap, Encoding::MFM);
+
+        assert_eq!(
+            track.format_densitymap(),
+            "Cylinder 3 Head 1\n     100 cellbytes with cell size 168 (500.0 kbps)\n      20 cellbytes with cell size  84 (1000.0 kbps)\n"
+        );
+    }
+
+    #[test]
+    fn flux_timings_test() {
+        let flux_timings = vec![PulseDuration(336), PulseDuration(507), PulseDuration(668)];
+        let track = RawTrack::new_with_flux_timings(1, 0, flux_timings, Encoding::MFM);
+
+        assert_eq!(track.reduced_flux_timings().unwrap(), vec![42, 63, 84]);
+        assert!((track.calculate_duration_of_track() - 1511.0 / STM_TIMER_HZ).abs() < 1e-9);
+        // Not enough to start the transmission
+        assert!(track.check_writability().is_err());
+
+        let flux_timings = [PulseDuration(336)].repeat(WRITE_PREFILL_PULSES);
+        let track = RawTrack::new_with_flux_timings(1, 0, flux_timings, Encoding::MFM);
+        assert!(track.check_writability().is_ok());
+
+        let too_long = vec![PulseDuration(336), PulseDuration(5000)];
+        let track = RawTrack::new_with_flux_timings(1, 0, too_long, Encoding::MFM);
+        assert!(track.check_writability().is_err());
+    }
+
+    #[test]
+    fn check_densitymap_test() {
+        let densitymap = vec![
+            DensityMapEntry {
+                number_of_cellbytes: 100,
+                cell_size: PulseDuration(168),
+            },
+            DensityMapEntry {
+                number_of_cellbytes: 20,
+                cell_size: PulseDuration(84),
+            },
+        ];
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![
+                RawTrack::new(0, 0, vec![0; 120], densitymap.clone(), Encoding::MFM),
+                RawTrack::new_with_flux_timings(0, 1, vec![PulseDuration(336)], Encoding::MFM),
+            ],
+        };
+        assert!(image.check_densitymaps().is_ok());
+
+        // One byte of the track is not covered by the density map
+        image
+            .tracks
+            .push(RawTrack::new(1, 0, vec![0; 121], densitymap, Encoding::MFM));
+        assert!(matches!(
+            image.check_densitymaps(),
+            Err(ToolError::DensityMapMismatch {
+                cylinder: 1,
+                head: 0,
+                densitymap_bytes: 120,
+                raw_data_bytes: 121,
+            })
+        ));
+    }
+
+    #[test]
+    fn fill_rotation_test() {
+        // Ends with the gap of an ISO track. 0x4E as MFM cells.
+        let mut raw_data = vec![0x44, 0x89, 0x44, 0x89];
+        raw_data.extend([0x92, 0x54].repeat(3000));
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: raw_data.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let mut track = RawTrack::new(0, 0, raw_data, densitymap, Encoding::MFM);
+
+        let added = track.fill_rotation(DRIVE_3_5_RPM, 1.5);
+        assert!(added > 0);
+        assert_eq!(added % 2, 0);
+        assert!(track.check_densitymap().is_ok());
+        assert!(track.raw_data.ends_with(&[0x92, 0x54, 0x92, 0x54]));
+        // A few bytes short of the rotation of the faster drive
+        assert!(track
+            .assert_fits_into_rotation(DRIVE_3_5_RPM * 1.015)
+            .is_ok());
+        assert!(track
+            .assert_fits_into_rotation(DRIVE_3_5_RPM * 1.015 + 0.1)
+            .is_err());
+        // Filled tracks stay as they are
+        assert_eq!(track.fill_rotation(DRIVE_3_5_RPM, 1.5), 0);
+
+        // Without a repeating end, the track is unknown and not touched
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: 4,
+            cell_size: PulseDuration(168),
+        }];
+        let mut track = RawTrack::new(0, 0, vec![1, 2, 3, 4], densitymap, Encoding::MFM);
+        assert_eq!(track.fill_rotation(DRIVE_3_5_RPM, 1.5), 0);
+    }
+
+    #[test]
+    fn check_rotation_margin_test() {
+        // 99% of a rotation with 300.05 RPM
+        let flux_timings = vec![PulseDuration(4000); 4157];
+        let track = RawTrack::new_with_flux_timings(1, 0, flux_timings, Encoding::MFM);
+
+        assert!(track.assert_fits_into_rotation(300.05).is_ok());
+        assert!(track.check_rotation_margin(300.05, 0.5).is_none());
+        assert!(track.check_rotation_margin(300.05, 1.5).is_some());
+
+        // The write gate margins are written in addition to the track
+        let mut track = track;
+        track.write_gate_margins = WriteGateMargins {
+            lead_in_us: 700,
+            lead_out_us: 700,
+        };
+        assert!(track.assert_fits_into_rotation(300.05).is_ok());
+        assert!(track.check_rotation_margin(300.05, 0.5).is_some());
+        assert!(track.assert_fits_into_rotation(301.0).is_err());
+        track.write_gate_margins = WriteGateMargins::default();
+        assert!(track.assert_fits_into_rotation(301.0).is_ok());
+    }
+
+    #[test]
+    fn check_drive_compatibility_test() {
+        let track = |cylinder, cell_size| {
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: 100,
+                cell_size: PulseDuration(cell_size),
+            }];
+            RawTrack::new(cylinder, 0, vec![0; 100], densitymap, Encoding::MFM)
+        };
+
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![track(0, 168), track(1, 168)],
+        };
+        assert!(image
+            .check_drive_compatibility(DiskType::Inch3_5)
+            .is_empty());
+        assert_eq!(image.check_drive_compatibility(DiskType::Inch5_25).len(), 1);
+
+        image.tracks.push(track(2, 84));
+        let warnings = image.check_drive_compatibility(DiskType::Inch3_5);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings
+            .first()
+            .unwrap()
+            .contains("1 tracks starting with 2:0"));
+
+        // Fine for a high density image
+        image.density = Density::High;
+        assert!(image
+            .check_drive_compatibility(DiskType::Inch3_5)
+            .is_empty());
+    }
+
+    #[test]
+    fn check_flippy_compatibility_test() {
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch5_25,
+            tracks: Vec::new(),
+        };
+        assert!(image.check_flippy_compatibility().is_empty());
+
+        image.disk_type = DiskType::Inch3_5;
+        image.density = Density::High;
+        assert_eq!(image.check_flippy_compatibility().len(), 2);
+    }
+
+    #[test]
+    fn classify_tracks_test() {
+        let track = |cylinder, cellbytes| {
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: cellbytes,
+                cell_size: PulseDuration(168),
+            }];
+            RawTrack::new(cylinder, 0, vec![0; cellbytes], densitymap, Encoding::MFM)
+        };
+
+        let mut protected = track(3, 6250);
+        protected.has_non_flux_reversal_area = true;
+        let mut multi_density = track(4, 6000);
+        multi_density.densitymap.push(DensityMapEntry {
+            number_of_cellbytes: 250,
+            cell_size: PulseDuration(160),
+        });
+
+        let mut image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+            tracks: vec![
+                track(0, 6250),
+                track(1, 6300),
+                track(2, 6600),
+                protected,
+                multi_density,
+                RawTrack::new_with_flux_timings(5, 0, vec![PulseDuration(336)], Encoding::MFM),
+            ],
+        };
+
+        assert_eq!(
+            image.classify_tracks(),
+            vec![
+                TrackClass::Standard,
+                TrackClass::Standard,
+                TrackClass::Irregular,
+                TrackClass::Irregular,
+                TrackClass::Irregular,
+                TrackClass::Irregular,
+            ]
+        );
+
+        image.retain_track_class(TrackClass::Standard);
+        let cylinders: Vec<u32> = image.tracks.iter().map(|f| f.cylinder).collect();
+        assert_eq!(cylinders, vec![0, 1]);
+    }
 }
@@ -0,0 +1,111 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::rawtrack::RawTrack;
+
+/// Progress of an interrupted write. Stored next to the image to continue later.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ResumeManifest {
+    /// File name of the image which was written
+    pub image: String,
+    /// Tracks which were written and verified, in the order of the write
+    pub verified: Vec<(u32, u32)>,
+}
+
+/// Default location of the manifest belonging to an image
+pub fn manifest_path(image_path: &Path) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".resume");
+    PathBuf::from(path)
+}
+
+fn image_name(image_path: &Path) -> String {
+    image_path
+        .file_name()
+        .map_or_else(String::new, |f| f.to_string_lossy().into_owned())
+}
+
+impl ResumeManifest {
+    pub fn new(image_path: &Path) -> Self {
+        Self {
+            image: image_name(image_path),
+            verified: Vec::new(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("Writing {}", path.display()))
+    }
+
+    /// Reads the manifest at `path` and ensures it was created for the image
+    pub fn read(path: &Path, image_path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        let manifest: Self = serde_json::from_slice(&data)?;
+        ensure!(
+            manifest.image == image_name(image_path),
+            "{} belongs to {}",
+            path.display(),
+            manifest.image
+        );
+        Ok(manifest)
+    }
+
+    /// Called after the image was completely written
+    pub fn remove(path: &Path) -> anyhow::Result<()> {
+        if path.exists() {
+            fs::remove_file(path).with_context(|| format!("Removing {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn mark_verified(&mut self, cylinder: u32, head: u32) {
+        if !self.verified.contains(&(cylinder, head)) {
+            self.verified.push((cylinder, head));
+        }
+    }
+
+    pub fn is_verified(&self, track: &RawTrack) -> bool {
+        self.verified.contains(&(track.cylinder, track.head))
+    }
+
+    /// Most recently verified track
+    pub fn last_verified(&self) -> Option<(u32, u32)> {
+        self.verified.last().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_roundtrip_test() {
+        let dir = std::env::temp_dir().join(format!("resume_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("disk.st");
+        let path = manifest_path(&image_path);
+
+        let mut manifest = ResumeManifest::new(&image_path);
+        manifest.mark_verified(0, 0);
+        manifest.mark_verified(0, 1);
+        manifest.mark_verified(0, 1);
+        assert_eq!(manifest.verified, [(0, 0), (0, 1)]);
+        assert_eq!(manifest.last_verified(), Some((0, 1)));
+
+        manifest.write(&path).unwrap();
+        assert_eq!(ResumeManifest::read(&path, &image_path).unwrap(), manifest);
+
+        // A manifest of another image must not be applied
+        assert!(ResumeManifest::read(&path, &dir.join("other.st")).is_err());
+
+        ResumeManifest::remove(&path).unwrap();
+        assert!(ResumeManifest::read(&path, &image_path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
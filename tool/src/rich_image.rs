@@ -0,0 +1,161 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{ensure, Context};
+use serde::{Deserialize, Serialize};
+
+/// Name of the manifest inside the container
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Describes how a single track was read
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackRecord {
+    pub cylinder: u32,
+    pub head: u32,
+    pub encoding: String,
+    /// Indices of the sectors in the order of the blob
+    pub sectors: Vec<u32>,
+    /// Additional reads after the first one which were necessary to decode the track
+    pub retries: usize,
+    /// Read stability score between 0 and 100. Not available for blank tracks.
+    pub stability_score: Option<u32>,
+    /// Everything that was noticed during reading
+    pub warnings: Vec<String>,
+    /// Indices of the sectors which were kept despite a wrong checksum
+    #[serde(default)]
+    pub bad_crc_sectors: Vec<u32>,
+}
+
+impl TrackRecord {
+    fn blob_name(&self) -> String {
+        format!("tracks/{:03}_{}.bin", self.cylinder, self.head)
+    }
+}
+
+/// Content of the manifest of a container
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// File extension of the plain image which can be exported
+    pub format: String,
+    pub tracks: Vec<TrackRecord>,
+}
+
+/// Writes a tar archive with the payload of every track as its own blob
+/// and a JSON manifest with the metadata of the read.
+pub struct RichImageWriter {
+    builder: tar::Builder<File>,
+    manifest: Manifest,
+}
+
+fn append_file(builder: &mut tar::Builder<File>, name: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+impl RichImageWriter {
+    pub fn create(path: &Path, format: &str) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+        Ok(Self {
+            builder: tar::Builder::new(file),
+            manifest: Manifest {
+                format: format.to_string(),
+                tracks: Vec::new(),
+            },
+        })
+    }
+
+    pub fn add_track(&mut self, record: TrackRecord, payload: &[u8]) -> anyhow::Result<()> {
+        append_file(&mut self.builder, &record.blob_name(), payload)?;
+        self.manifest.tracks.push(record);
+        Ok(())
+    }
+
+    /// The manifest is stored last as it is only complete after every track
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        let manifest = serde_json::to_vec_pretty(&self.manifest)?;
+        append_file(&mut self.builder, MANIFEST_NAME, &manifest)?;
+        self.builder.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+/// Provides the manifest and the plain image which consists of all tracks in order
+pub fn load_rich_image(path: &Path) -> anyhow::Result<(Manifest, Vec<u8>)> {
+    let file = File::open(path).with_context(|| format!("Reading {}", path.display()))?;
+    let mut archive = tar::Archive::new(file);
+
+    let mut manifest = None;
+    let mut blobs = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        if name == MANIFEST_NAME {
+            manifest = Some(serde_json::from_slice::<Manifest>(&data)?);
+        } else {
+            blobs.push((name, data));
+        }
+    }
+
+    let manifest = manifest.context("Container has no manifest")?;
+    let mut image = Vec::new();
+    for record in &manifest.tracks {
+        let blob_name = record.blob_name();
+        let (_, data) = blobs
+            .iter()
+            .find(|(name, _)| *name == blob_name)
+            .with_context(|| format!("Container has no data for {blob_name}"))?;
+        image.extend_from_slice(data);
+    }
+    ensure!(
+        blobs.len() == manifest.tracks.len(),
+        "Container has data which is not in the manifest"
+    );
+
+    Ok((manifest, image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rich_image_test() {
+        let path = std::env::temp_dir().join(format!("rich_image_test_{}.tar", std::process::id()));
+        let record = |cylinder: u32, warnings: Vec<String>| TrackRecord {
+            cylinder,
+            head: 0,
+            encoding: "MFM".into(),
+            sectors: (1..=9).collect(),
+            retries: warnings.len(),
+            stability_score: Some(100),
+            warnings,
+            bad_crc_sectors: Vec::new(),
+        };
+
+        let mut writer = RichImageWriter::create(&path, "st").unwrap();
+        writer.add_track(record(0, Vec::new()), &[1; 4608]).unwrap();
+        writer
+            .add_track(record(1, vec!["Decoded in attempt 2".into()]), &[2; 4608])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let (manifest, image) = load_rich_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(manifest.format, "st");
+        assert_eq!(manifest.tracks.len(), 2);
+        assert_eq!(manifest.tracks.get(1).unwrap().retries, 1);
+        assert_eq!(image.len(), 2 * 4608);
+        assert_eq!(image.get(4608), Some(&2));
+    }
+}
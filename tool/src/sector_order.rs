@@ -0,0 +1,203 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context};
+
+use crate::track_parser::TrackPayload;
+
+/// Physical order of the sectors of every track as observed during reading.
+/// Stored next to a sector image to write it again with the same interleave.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct SectorOrder {
+    /// Index of every sector and the position of its header in ticks after the index.
+    /// Ordered by the position.
+    tracks: BTreeMap<(u32, u32), Vec<(u32, usize)>>,
+}
+
+impl SectorOrder {
+    /// Reading `image.st` stores the order in `image.st.interleave.txt`.
+    /// The extension is kept so an `image.img` doesn't pick up the order of another image.
+    #[must_use]
+    pub fn path_for_image(path: &Path) -> PathBuf {
+        let mut file_name = path.as_os_str().to_owned();
+        file_name.push(".interleave.txt");
+        PathBuf::from(file_name)
+    }
+
+    #[must_use]
+    pub fn number_of_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Takes the order of a track. Returns false if the position of a sector is unknown.
+    pub fn insert(&mut self, track: &TrackPayload) -> bool {
+        let positions: Option<Vec<(u32, usize)>> = track
+            .sectors
+            .iter()
+            .map(|f| f.position().map(|position| (f.index(), position)))
+            .collect();
+
+        match positions {
+            Some(mut positions) if !positions.is_empty() => {
+                positions.sort_by_key(|f| f.1);
+                self.tracks.insert((track.cylinder, track.head), positions);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Provides the sectors of a track in the order of their appearance.
+    /// They are given as their position in the image, which stores them ordered by index.
+    #[must_use]
+    pub fn track(&self, cylinder: u32, head: u32) -> Option<Vec<usize>> {
+        let sectors = self.tracks.get(&(cylinder, head))?;
+        let mut indices: Vec<u32> = sectors.iter().map(|f| f.0).collect();
+        indices.sort_unstable();
+
+        sectors
+            .iter()
+            .map(|(index, _)| indices.binary_search(index).ok())
+            .collect()
+    }
+
+    /// Position of the first sector header of a track in ticks after the index
+    #[must_use]
+    pub fn first_position(&self, cylinder: u32, head: u32) -> Option<usize> {
+        self.tracks
+            .get(&(cylinder, head))?
+            .first()
+            .map(|(_, position)| *position)
+    }
+
+    /// One line per track with cylinder, head and every sector as index@position
+    #[must_use]
+    pub fn to_text(&self) -> String {
+        self.tracks
+            .iter()
+            .map(|((cylinder, head), sectors)| {
+                let sectors: Vec<String> = sectors
+                    .iter()
+                    .map(|(index, position)| format!("{index}@{position}"))
+                    .collect();
+                format!("{cylinder} {head} {}\n", sectors.join(" "))
+            })
+            .collect()
+    }
+
+    pub fn from_text(text: &str) -> anyhow::Result<Self> {
+        let mut result = Self::default();
+
+        for line in text.lines().filter(|f| !f.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            let cylinder = fields.next().context("Missing cylinder")?.parse()?;
+            let head = fields.next().context("Missing head")?.parse()?;
+
+            let mut sectors = fields
+                .map(|field| {
+                    let (index, position) = field
+                        .split_once('@')
+                        .with_context(|| format!("Expected index@position instead of {field}"))?;
+                    Ok((index.parse()?, position.parse()?))
+                })
+                .collect::<anyhow::Result<Vec<(u32, usize)>>>()?;
+            sectors.sort_by_key(|f| f.1);
+
+            ensure!(
+                !sectors.is_empty(),
+                "Track {cylinder} {head} has no sectors"
+            );
+            ensure!(
+                result.tracks.insert((cylinder, head), sectors).is_none(),
+                "Track {cylinder} {head} is listed twice"
+            );
+        }
+
+        Ok(result)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, self.to_text())?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        Self::from_text(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_reader::image_iso::{generate_iso_track_in_order, IsoGeometry},
+        rawtrack::RawTrack,
+        track_parser::{iso::IsoTrackParser, TrackParser},
+    };
+    use util::{Density, DensityMapEntry, Encoding, PulseDuration};
+
+    #[test]
+    fn sector_order_test() {
+        let order = SectorOrder::from_text("0 0 1@100 4@400 2@700 5@1000 3@1300\n").unwrap();
+        assert_eq!(order.track(0, 0), Some(vec![0, 3, 1, 4, 2]));
+        assert_eq!(order.track(0, 1), None);
+        assert_eq!(SectorOrder::from_text(&order.to_text()).unwrap(), order);
+
+        assert!(SectorOrder::from_text("0 0 1@100 2").is_err());
+        assert!(SectorOrder::from_text("0 0").is_err());
+        assert!(SectorOrder::from_text("0 0 1@100\n0 0 1@100").is_err());
+
+        assert_eq!(
+            SectorOrder::path_for_image(Path::new("dir/image.st")),
+            Path::new("dir/image.st.interleave.txt")
+        );
+        assert_ne!(
+            SectorOrder::path_for_image(Path::new("image.img")),
+            SectorOrder::path_for_image(Path::new("image.st"))
+        );
+    }
+
+    #[test]
+    fn recorded_order_test() {
+        let sectors = vec![0xe5; 9 * 512];
+        let order = [0, 5, 1, 6, 2, 7, 3, 8, 4];
+        let trackbuf = generate_iso_track_in_order(
+            4,
+            0,
+            &IsoGeometry::new(9),
+            &mut sectors.chunks_exact(512),
+            &order,
+        )
+        .unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let track = RawTrack::new(4, 0, trackbuf, densitymap, Encoding::MFM);
+
+        let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        parser.expect_track(4, 0);
+        let payload = parser
+            .parse_raw_track(&track.simulate_read(1).unwrap())
+            .unwrap();
+
+        let mut sector_order = SectorOrder::default();
+        assert!(sector_order.insert(&payload));
+        assert_eq!(sector_order.track(4, 0), Some(order.to_vec()));
+
+        // Not every sector is placed
+        assert!(generate_iso_track_in_order(
+            4,
+            0,
+            &IsoGeometry::new(9),
+            &mut sectors.chunks_exact(512),
+            &order[1..],
+        )
+        .is_err());
+    }
+}
@@ -1,21 +1,39 @@
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    sync::atomic::{AtomicU16, Ordering},
+};
 
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
 use util::{
     duration_of_rotation_as_stm_tim_raw,
     fluxpulse::FluxPulseToCells,
     mfm::{MfmDataSeperator, RawMfmWord},
-    Density, PulseDuration, DRIVE_3_5_RPM,
+    Density, DiskType, PulseDuration, DRIVE_3_5_RPM,
 };
 
-use crate::{rawtrack::TrackFilter, track_parser::concatenate_sectors};
+use crate::{
+    error::ToolError, image_reader::image_adf::AMIGA_SYNC_WORD, rawtrack::TrackFilter,
+    track_parser::concatenate_sectors,
+};
 
-use super::{CollectedSector, TrackParser, TrackPayload};
+use super::{read_rpm, CollectedSector, TrackParser, TrackPayload};
 
 const AMIGA_MFM_MASK: u32 = 0x5555_5555;
 const WORDS_PER_SECTOR: usize = 128;
 pub const SECTORS_PER_AMIGA_DD_TRACK: usize = 11;
 
+static SYNC_WORD: AtomicU16 = AtomicU16::new(AMIGA_SYNC_WORD);
+
+/// Changes the sync word of every following parser for custom trackdisk formats
+pub fn set_amiga_sync_word(sync_word: u16) {
+    SYNC_WORD.store(sync_word, Ordering::Relaxed);
+}
+
+/// Sync word in front of every sector header
+pub fn amiga_sync_word() -> u16 {
+    SYNC_WORD.load(Ordering::Relaxed)
+}
+
 fn read_even_bits<'a>(iterator: &mut impl Iterator<Item = &'a RawMfmWord>) -> u32 {
     match iterator.next() {
         Some(RawMfmWord::Raw(raw)) => raw & AMIGA_MFM_MASK,
@@ -27,11 +45,18 @@ pub struct AmigaTrackParser {
     collected_sectors: Option<Vec<CollectedSector>>,
     expected_sectors_per_track: usize,
     expected_track_number: Option<u32>,
+    sync_word: u16,
 }
 
 impl AmigaTrackParser {
+    /// Uses the configured sync word
     #[must_use]
     pub fn new(disk_type: Density) -> Self {
+        Self::with_sync_word(disk_type, amiga_sync_word())
+    }
+
+    #[must_use]
+    pub fn with_sync_word(disk_type: Density, sync_word: u16) -> Self {
         let expected_sectors_per_track = match disk_type {
             Density::High => 22,
             Density::SingleDouble => 11,
@@ -41,6 +66,7 @@ impl AmigaTrackParser {
             collected_sectors: None,
             expected_sectors_per_track,
             expected_track_number: None,
+            sync_word,
         }
     }
 }
@@ -51,18 +77,26 @@ impl TrackParser for AmigaTrackParser {
     }
 
     fn duration_to_record(&self) -> usize {
-        duration_of_rotation_as_stm_tim_raw(DRIVE_3_5_RPM) * 110 / 100
+        duration_of_rotation_as_stm_tim_raw(read_rpm(DRIVE_3_5_RPM)) * 110 / 100
     }
 
-    fn parse_raw_track(&mut self, track: &[u8]) -> anyhow::Result<TrackPayload> {
+    fn parse_flux_timings(&mut self, track: &[PulseDuration]) -> anyhow::Result<TrackPayload> {
         let expected_track_number = self.expected_track_number.context(program_flow_error!())?;
         let cellsize_2micros = 168;
         let mut mfm_words: Vec<RawMfmWord> = Vec::new();
-        let mut mfmd = MfmDataSeperator::new(|f| mfm_words.push(f));
+        let mut mfmd = MfmDataSeperator::with_sync_word(|f| mfm_words.push(f), self.sync_word);
         let mut pulseparser = FluxPulseToCells::new(|val| mfmd.feed(val), cellsize_2micros);
 
-        for mfm_word in track {
-            pulseparser.feed(PulseDuration(i32::from(*mfm_word) << 3));
+        for pulse in track {
+            pulseparser.feed(*pulse);
+        }
+
+        // Without any sync, the surface was never formatted. This is not a failed read.
+        if !mfm_words.iter().any(|f| matches!(f, RawMfmWord::SyncWord)) {
+            bail!(ToolError::BlankTrack {
+                cylinder: expected_track_number >> 1,
+                head: expected_track_number & 1,
+            });
         }
 
         let mut iterator = mfm_words.iter();
@@ -105,9 +139,10 @@ impl TrackParser for AmigaTrackParser {
                 .len()
                 == self.expected_sectors_per_track
         );
+        // Keep the sectors for take_collected_sectors
         let collected_sectors = self
             .collected_sectors
-            .take()
+            .clone()
             .context(program_flow_error!())?;
 
         Ok(concatenate_sectors(
@@ -122,6 +157,18 @@ impl TrackParser for AmigaTrackParser {
         self.collected_sectors = Some(Vec::new());
     }
 
+    fn take_collected_sectors(&mut self) -> Vec<CollectedSector> {
+        self.collected_sectors.take().unwrap_or_default()
+    }
+
+    fn expected_sectors(&self) -> Option<usize> {
+        Some(self.expected_sectors_per_track)
+    }
+
+    fn disk_type(&self) -> Option<DiskType> {
+        Some(DiskType::Inch3_5)
+    }
+
     fn step_size(&self) -> usize {
         1
     }
@@ -134,10 +181,14 @@ impl TrackParser for AmigaTrackParser {
         "AmigaDOS"
     }
 
+    fn track_encoding(&self) -> &'static str {
+        "MFM"
+    }
+
     fn default_trackfilter(&self) -> crate::rawtrack::TrackFilter {
         TrackFilter {
             cyl_start: Some(0),
-            cyl_end: Some(79),
+            cyl_end: Some(util::DEFAULT_MAX_CYLINDER),
             head: None,
         }
     }
@@ -231,13 +282,17 @@ fn parse_amiga_sector<'a>(
     Ok(CollectedSector {
         index: sector,
         payload: sector_data,
+        bit_width_profile: None,
+        variable_bit_width: false,
+        position: None,
+        bad_crc: false,
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::image_reader::image_adf::generate_track;
+    use crate::image_reader::image_adf::{generate_track, AMIGA_SYNC_WORD};
     use std::vec;
     use util::{bitstream::to_bit_stream, fluxpulse::FluxPulseGenerator};
     const BYTES_PER_SECTOR: usize = WORDS_PER_SECTOR * 4;
@@ -252,7 +307,7 @@ mod tests {
         let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR);
         assert_eq!(sectors.len(), 11);
 
-        let trackbuf = generate_track(30, 1, &mut sectors).unwrap();
+        let trackbuf = generate_track(30, 1, 11, 0, AMIGA_SYNC_WORD, &mut sectors).unwrap();
         let mut pulse_data = Vec::new();
         let mut pulse_generator = FluxPulseGenerator::new(|f| pulse_data.push(f.0 as u8), 168 >> 3);
         for i in trackbuf {
@@ -273,4 +328,44 @@ mod tests {
         assert_eq!(*result.payload.get(200).unwrap(), 126);
         assert_eq!(*result.payload.get(300).unwrap(), 83);
     }
+
+    #[test]
+    fn custom_sync_word_test() {
+        let buffer = vec![0x34; BYTES_PER_SECTOR * SECTORS_PER_AMIGA_DD_TRACK];
+        let mut sectors = buffer.chunks_exact(BYTES_PER_SECTOR);
+        let trackbuf = generate_track(30, 1, 11, 0, 0x8914, &mut sectors).unwrap();
+        let mut pulse_data = Vec::new();
+        let mut pulse_generator = FluxPulseGenerator::new(|f| pulse_data.push(f.0 as u8), 168 >> 3);
+        for i in trackbuf {
+            to_bit_stream(i, |bit| pulse_generator.feed(bit));
+        }
+        to_bit_stream(0x55, |bit| pulse_generator.feed(bit));
+        pulse_generator.flush();
+
+        let mut parser = AmigaTrackParser::with_sync_word(Density::SingleDouble, 0x8914);
+        parser.expect_track(30, 1);
+        assert_eq!(parser.parse_raw_track(&pulse_data).unwrap().payload, buffer);
+
+        // The standard sync word is never found
+        let mut parser = AmigaTrackParser::with_sync_word(Density::SingleDouble, AMIGA_SYNC_WORD);
+        parser.expect_track(30, 1);
+        assert!(parser.parse_raw_track(&pulse_data).is_err());
+    }
+
+    #[test]
+    fn blank_track_test() {
+        // Pulses of the same length never form a sync word
+        let pulse_data = vec![42u8; 20000];
+
+        let mut parser = AmigaTrackParser::new(Density::SingleDouble);
+        parser.expect_track(30, 1);
+        let result = parser.parse_raw_track(&pulse_data);
+        assert!(matches!(
+            result.err().unwrap().downcast_ref::<ToolError>(),
+            Some(ToolError::BlankTrack {
+                cylinder: 30,
+                head: 1
+            })
+        ));
+    }
 }
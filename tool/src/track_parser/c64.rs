@@ -1,24 +1,39 @@
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
+use std::convert::TryFrom;
 use util::{
-    c64_geometry::{get_track_settings, TrackConfiguration},
+    bitstream::BitStreamCollector,
+    c64_geometry::{get_track_settings, HalfTrack, TrackConfiguration},
     duration_of_rotation_as_stm_tim_raw,
     fluxpulse::FluxPulseToCells,
     gcr::{GcrDecoder, GcrDecoderResult},
-    Density, PulseDuration, DRIVE_5_25_RPM,
+    Density, DensityMapEntry, DiskType, Encoding, PulseDuration, DRIVE_5_25_RPM,
 };
 
-use crate::{rawtrack::TrackFilter, track_parser::concatenate_sectors};
+use crate::{
+    error::ToolError,
+    image_reader::image_d64::generate_track,
+    rawtrack::{RawTrack, TrackFilter},
+    track_parser::concatenate_sectors,
+};
 
-use super::{CollectedSector, TrackParser, TrackPayload};
+use super::{read_rpm, CollectedSector, TrackParser, TrackPayload};
 
 pub struct C64TrackParser {
     collected_sectors: Option<Vec<CollectedSector>>,
     track_config: Option<TrackConfiguration>,
     expected_track_number: Option<u32>,
+    expected_cylinder: Option<u32>,
 }
 
 const SECTOR_SIZE: usize = 256;
 
+/// Recordings with less pulses are considered to be the noise of an unformatted track
+const KILLER_TRACK_MIN_PULSES: usize = 1000;
+
+/// Share of single cell intervals in a killer track. It consists of ones only.
+/// Noise of damaged or unformatted tracks has intervals of all lengths.
+const KILLER_TRACK_MIN_ONES_PERCENT: usize = 90;
+
 impl C64TrackParser {
     #[must_use]
     pub fn new() -> Self {
@@ -26,6 +41,7 @@ impl C64TrackParser {
             collected_sectors: None,
             track_config: None,
             expected_track_number: None,
+            expected_cylinder: None,
         }
     }
 }
@@ -45,8 +61,12 @@ impl TrackParser for C64TrackParser {
         "C64 1541"
     }
 
+    fn track_encoding(&self) -> &'static str {
+        "GCR"
+    }
+
     fn duration_to_record(&self) -> usize {
-        duration_of_rotation_as_stm_tim_raw(DRIVE_5_25_RPM) * 110 / 100
+        duration_of_rotation_as_stm_tim_raw(read_rpm(DRIVE_5_25_RPM)) * 110 / 100
     }
 
     fn track_density(&self) -> Density {
@@ -61,7 +81,7 @@ impl TrackParser for C64TrackParser {
         }
     }
 
-    fn parse_raw_track(&mut self, track: &[u8]) -> anyhow::Result<TrackPayload> {
+    fn parse_flux_timings(&mut self, track: &[PulseDuration]) -> anyhow::Result<TrackPayload> {
         let track_config = self.track_config.as_ref().context("No track expected!")?;
 
         let mut gcr_results = Vec::new();
@@ -69,9 +89,23 @@ impl TrackParser for C64TrackParser {
         let mut pulseparser =
             FluxPulseToCells::new(|val| decoder.feed(val), track_config.cellsize as i32);
 
-        track
+        track.iter().for_each(|f| pulseparser.feed(*f));
+
+        // Killer tracks are written without a single sync mark to let the 1541 search forever.
+        // Unlike a blank track, the surface is magnetized and provides flux.
+        if !gcr_results
             .iter()
-            .for_each(|f| pulseparser.feed(PulseDuration(i32::from(*f) << 3)));
+            .any(|f| matches!(f, GcrDecoderResult::Sync))
+        {
+            let cylinder = self.expected_cylinder.context(program_flow_error!())?;
+            if track.len() < KILLER_TRACK_MIN_PULSES {
+                bail!(ToolError::BlankTrack { cylinder, head: 0 });
+            }
+            if is_killer_track(track, track_config.cellsize as i32) {
+                bail!(ToolError::KillerTrack { cylinder, head: 0 });
+            }
+            bail!("No sync mark found on track {cylinder}");
+        }
 
         let mut iterator = gcr_results.iter();
 
@@ -121,9 +155,19 @@ impl TrackParser for C64TrackParser {
                                 // Activate DAM reading for the next 40 data bytes
                                 awaiting_data_block = 20;
                             }
+                            let header_track_number =
+                                u32::from(*sector_header.get(2).context("Header too short")?);
+                            let expected_track_number =
+                                self.expected_track_number.context("No track selected!")?;
+                            let on_half_track = self
+                                .expected_cylinder
+                                .is_some_and(|f| HalfTrack(f).is_half_track());
+
+                            // Half tracks might contain the data of either neighbour
                             ensure!(
-                                *sector_header.get(2).context("Header too short")? as u32
-                                    == self.expected_track_number.context("No track selected!")?
+                                header_track_number == expected_track_number
+                                    || (on_half_track
+                                        && header_track_number == expected_track_number + 1)
                             );
                         } else {
                             println!(
@@ -161,6 +205,10 @@ impl TrackParser for C64TrackParser {
                             collected_sectors.push(CollectedSector {
                                 index: u32::from(ensure_index!(sector_header[1])),
                                 payload: sector_data,
+                                bit_width_profile: None,
+                                variable_bit_width: false,
+                                position: None,
+                                bad_crc: false,
                             });
 
                             if collected_sectors.len() == track_config.sectors as usize {
@@ -185,33 +233,116 @@ impl TrackParser for C64TrackParser {
                 .len()
                 == track_config.sectors as usize
         );
+        // Keep the sectors for take_collected_sectors
         let collected_sectors = self
             .collected_sectors
-            .take()
+            .clone()
             .context(program_flow_error!())?;
 
         Ok(concatenate_sectors(
             collected_sectors,
-            (self.expected_track_number.context("Program flow error")? - 1) << 1,
+            self.expected_cylinder.context(program_flow_error!())?,
             0,
         ))
     }
 
     fn expect_track(&mut self, cylinder: u32, head: u32) {
         assert_eq!(head, 0, "C64 disks have no second side!");
-        let expected_track_number = (cylinder >> 1) + 1;
+        let expected_track_number = HalfTrack(cylinder).track();
         let track_config = get_track_settings(expected_track_number as usize);
 
         self.track_config = Some(track_config);
         self.expected_track_number = Some(expected_track_number);
+        self.expected_cylinder = Some(cylinder);
         self.collected_sectors = Some(Vec::new());
     }
 
+    fn take_collected_sectors(&mut self) -> Vec<CollectedSector> {
+        self.collected_sectors.take().unwrap_or_default()
+    }
+
+    fn expected_sectors(&self) -> Option<usize> {
+        self.track_config.as_ref().map(|f| usize::from(f.sectors))
+    }
+
+    fn sector_size(&self) -> Option<usize> {
+        Some(SECTOR_SIZE)
+    }
+
+    fn disk_type(&self) -> Option<DiskType> {
+        Some(DiskType::Inch5_25)
+    }
+
     fn step_size(&self) -> usize {
         2
     }
 }
 
+/// A killer track consists of ones only. Almost every interval is a single cell.
+fn is_killer_track(track: &[PulseDuration], cellsize: i32) -> bool {
+    let ones = track
+        .iter()
+        .filter(|f| f.0 > cellsize / 2 && f.0 < cellsize * 3 / 2)
+        .count();
+    ones * 100 >= track.len() * KILLER_TRACK_MIN_ONES_PERCENT
+}
+
+/// Converts one rotation of a read track into cells as stored by G64.
+/// Used for killer tracks which can't be reconstructed from sectors.
+#[must_use]
+pub fn killer_track_cells(track: &[PulseDuration], cylinder: u32) -> Vec<u8> {
+    let track_config = get_track_settings(HalfTrack(cylinder).track() as usize);
+    let rotation = duration_of_rotation_as_stm_tim_raw(DRIVE_5_25_RPM) as i32;
+
+    let mut cells = Vec::new();
+    let mut collector = BitStreamCollector::new(|f| cells.push(f));
+    let mut pulseparser =
+        FluxPulseToCells::new(|val| collector.feed(val), track_config.cellsize as i32);
+
+    let mut position = 0;
+    for pulse in track.iter().take_while(|f| {
+        position += f.0;
+        position <= rotation
+    }) {
+        pulseparser.feed(*pulse);
+    }
+
+    cells
+}
+
+/// Provides a read track as it is stored in a G64 image. Sectors are encoded again
+/// while killer tracks are kept with the cells of their recording.
+pub fn g64_track(
+    track: &TrackPayload,
+    killer_track: Option<&[PulseDuration]>,
+) -> anyhow::Result<RawTrack> {
+    let track_number = HalfTrack(track.cylinder).track();
+    let track_config = get_track_settings(track_number as usize);
+
+    let raw_data = match killer_track {
+        Some(flux_timings) => killer_track_cells(flux_timings, track.cylinder),
+        None => {
+            let (trackbuf, _) = generate_track(
+                u8::try_from(track_number)?,
+                &mut track.payload.chunks_exact(SECTOR_SIZE),
+            )?;
+            trackbuf
+        }
+    };
+
+    let densitymap = vec![DensityMapEntry {
+        number_of_cellbytes: raw_data.len(),
+        cell_size: PulseDuration(track_config.cellsize as i32),
+    }];
+    Ok(RawTrack::new(
+        track.cylinder,
+        0,
+        raw_data,
+        densitymap,
+        Encoding::GCR,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -260,4 +391,83 @@ mod tests {
         assert_eq!(*result.payload.get(200).unwrap(), 126);
         assert_eq!(*result.payload.get(300).unwrap(), 83);
     }
+
+    #[test]
+    fn half_track_parse_test() {
+        // Data of track 11 is found on the half track 10.5
+        let tracknum = 11;
+        let half_track_cylinder = 19;
+
+        let track_config = get_track_settings(tracknum);
+        let buffer: Vec<u8> = (0..SECTOR_SIZE * track_config.sectors as usize)
+            .map(|f| (f % 233) as u8)
+            .collect();
+        let (trackbuf, _) =
+            generate_track(tracknum as u8, &mut buffer.chunks_exact(SECTOR_SIZE)).unwrap();
+
+        let mut pulse_data = Vec::new();
+        let mut pulse_generator = FluxPulseGenerator::new(
+            |f| pulse_data.push(f.0 as u8),
+            track_config.cellsize as u32 >> 3,
+        );
+        for i in trackbuf {
+            to_bit_stream(i, |bit| pulse_generator.feed(bit));
+        }
+        to_bit_stream(0x55, |bit| pulse_generator.feed(bit));
+        pulse_generator.flush();
+
+        let mut parser = C64TrackParser::new();
+        parser.expect_track(half_track_cylinder, 0);
+        let result = parser.parse_raw_track(&pulse_data).unwrap();
+        assert_eq!(result.cylinder, half_track_cylinder);
+        assert_eq!(result.payload, buffer);
+
+        // But not on a whole track
+        parser.expect_track(half_track_cylinder - 1, 0);
+        assert!(parser.parse_raw_track(&pulse_data).is_err());
+    }
+
+    #[test]
+    fn killer_track_test() {
+        let track_config = get_track_settings(1);
+        // Only ones without a single zero to end the sync
+        let pulse_data = vec![PulseDuration(track_config.cellsize as i32); 80000];
+
+        let mut parser = C64TrackParser::new();
+        parser.expect_track(0, 0);
+        let error = parser.parse_flux_timings(&pulse_data).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::KillerTrack { cylinder: 0, .. })
+        ));
+
+        // Without flux, the track is just blank
+        parser.expect_track(0, 0);
+        let error = parser
+            .parse_flux_timings(pulse_data.get(..10).unwrap())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::BlankTrack { .. })
+        ));
+
+        // Noise of a damaged track is a read failure
+        let mut rng = SmallRng::seed_from_u64(0x42);
+        let noise: Vec<PulseDuration> = (0..80000)
+            .map(|_| {
+                PulseDuration(track_config.cellsize as i32 * (1 + (rng.next_u32() % 4) as i32))
+            })
+            .collect();
+        parser.expect_track(0, 0);
+        let error = parser.parse_flux_timings(&noise).err().unwrap();
+        assert!(error.downcast_ref::<ToolError>().is_none());
+
+        // One rotation is kept
+        let cells = killer_track_cells(&pulse_data, 0);
+        let expected =
+            duration_of_rotation_as_stm_tim_raw(DRIVE_5_25_RPM) / track_config.cellsize / 8;
+        assert!(cells.len().abs_diff(expected) <= 1);
+        assert!(cells.iter().all(|f| *f == 0xff));
+    }
 }
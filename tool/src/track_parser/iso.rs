@@ -1,19 +1,275 @@
-use anyhow::{ensure, Context};
+use std::{
+    cell::Cell,
+    convert::TryInto,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{bail, ensure, Context};
 use util::{
     duration_of_rotation_as_stm_tim_raw,
     fluxpulse::FluxPulseToCells,
+    fm::{FmDecoder, FmWord},
     mfm::{MfmDecoder, MfmWord, ISO_SYNC_BYTE},
-    Density, DiskType, PulseDuration, DRIVE_3_5_RPM, DRIVE_5_25_RPM, DRIVE_SLOWEST_RPM,
-    PULSE_REDUCE_SHIFT,
+    Density, DiskType, PulseDuration, DRIVE_3_5_RPM, DRIVE_5_25_RPM, STM_TIMER_HZ,
 };
 
 use crate::{
+    error::ToolError,
     image_reader::image_iso::{ISO_DAM, ISO_IDAM},
     rawtrack::TrackFilter,
     track_parser::concatenate_sectors,
 };
 
-use super::{CollectedSector, TrackParser, TrackPayload};
+use super::{rotation_rpm, CollectedSector, DynTrackParser, TrackParser, TrackPayload};
+
+/// Number of data bytes measured together for the bit width profile of a sector.
+/// The same granularity is used by the timing records of STX images.
+const BIT_WIDTH_PROFILE_BYTES: usize = 16;
+/// A sector is considered to have a variable bit width if the cell sizes of the
+/// profile differ by more than this from each other.
+const BIT_WIDTH_VARIATION_PERCENT: i32 = 5;
+
+/// Layout of the 1.2 MB disks of PCs
+const SECTORS_PER_TRACK_5_25_HD: usize = 15;
+
+/// Number of bytes after the sector header in which the data address mark is accepted.
+/// Like the WD1772 of the Atari ST, which allows tighter gaps for 10 and 11 sectors per track.
+const DAM_SEARCH_WINDOW: i32 = 43;
+
+/// Higher sector numbers are usually part of a copy protection and not of the layout
+const MAX_SECTORS_PER_TRACK: u32 = 21;
+
+/// More duplicate sector headers in a recording let the parser assume a 5.25 inch drive.
+/// Only used if the duration of a rotation couldn't be measured.
+pub const DEFAULT_DUPLICATE_HEADER_THRESHOLD: usize = 5;
+
+static DUPLICATE_HEADER_THRESHOLD: AtomicUsize =
+    AtomicUsize::new(DEFAULT_DUPLICATE_HEADER_THRESHOLD);
+
+/// Changes the number of duplicate sector headers above which a 5.25 inch drive is assumed
+pub fn set_duplicate_header_threshold(threshold: usize) {
+    DUPLICATE_HEADER_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// A measured rotation outside of this range in milliseconds is a mismatch of
+/// sector headers or a drive with a failing motor. It isn't used to decide the drive.
+const PLAUSIBLE_ROTATION_MS: RangeInclusive<f64> = 150.0..=215.0;
+
+/// Decides for the drive whose rotation is closer to the measured one
+fn disk_type_of_rotation(rotation: usize) -> DiskType {
+    let rotation_5_25 = duration_of_rotation_as_stm_tim_raw(DRIVE_5_25_RPM);
+    let rotation_3_5 = duration_of_rotation_as_stm_tim_raw(DRIVE_3_5_RPM);
+    if rotation < (rotation_5_25 + rotation_3_5) / 2 {
+        DiskType::Inch5_25
+    } else {
+        DiskType::Inch3_5
+    }
+}
+
+/// Provides the average cell size of every `BIT_WIDTH_PROFILE_BYTES` data bytes.
+/// Expects the time at which every byte was decoded, starting with the byte before the data.
+fn bit_width_profile(byte_times: &[usize]) -> Vec<PulseDuration> {
+    // Every byte consists of 16 MFM cells
+    let cells_per_block = BIT_WIDTH_PROFILE_BYTES * 16;
+
+    byte_times
+        .iter()
+        .step_by(BIT_WIDTH_PROFILE_BYTES)
+        .collect::<Vec<_>>()
+        .windows(2)
+        .filter_map(|f| match f {
+            [start, end] => Some(PulseDuration(((**end - **start) / cells_per_block) as i32)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Checks the profile for the intra sector bit width variation used by
+/// copy protections like Macrodos / Speedlock.
+fn has_bit_width_variation(profile: &[PulseDuration], cellsize: i32) -> bool {
+    let min = profile.iter().map(|f| f.0).min();
+    let max = profile.iter().map(|f| f.0).max();
+
+    match (min, max) {
+        (Some(min), Some(max)) => (max - min) * 100 > cellsize * BIT_WIDTH_VARIATION_PERCENT,
+        _ => false,
+    }
+}
+
+/// Encoding of the cells. FM is used by single density disks and some
+/// disks use it only on a few tracks, e.g. the first one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsoEncoding {
+    Fm,
+    Mfm,
+}
+
+/// Decodes the flux timings to words and provides the time at which every word was decoded.
+/// Address marks of FM are provided like a sync of MFM followed by the address mark.
+fn decode_iso_words(
+    track: &[PulseDuration],
+    encoding: IsoEncoding,
+    cellsize: i32,
+) -> (Vec<MfmWord>, Vec<usize>) {
+    let mut words: Vec<MfmWord> = Vec::new();
+    let mut word_times: Vec<usize> = Vec::new();
+    let elapsed = Cell::new(0);
+
+    let feed_pulses = |sink: &mut dyn FnMut(util::Bit)| {
+        let mut pulseparser = FluxPulseToCells::new(sink, cellsize);
+        track.iter().for_each(|f| {
+            elapsed.set(elapsed.get() + f.0 as usize);
+            pulseparser.feed(*f);
+        });
+    };
+
+    match encoding {
+        IsoEncoding::Mfm => {
+            let mut mfmd = MfmDecoder::new(|f| {
+                words.push(f);
+                word_times.push(elapsed.get());
+            });
+            feed_pulses(&mut |val| mfmd.feed(val));
+        }
+        IsoEncoding::Fm => {
+            let mut fmd = FmDecoder::new(|f| {
+                let time = elapsed.get();
+                match f {
+                    FmWord::Enc(val) => words.push(MfmWord::Enc(val)),
+                    FmWord::AddressMark(val) => {
+                        words.push(MfmWord::SyncWord);
+                        word_times.push(time);
+                        words.push(MfmWord::Enc(val));
+                    }
+                }
+                word_times.push(time);
+            });
+            feed_pulses(&mut |val| fmd.feed(val));
+        }
+    }
+
+    (words, word_times)
+}
+
+/// Provides the CRC after the address mark. MFM also covers the sync bytes in front of it.
+fn address_mark_crc(encoding: IsoEncoding, address_mark: u8) -> crc16::State<crc16::CCITT_FALSE> {
+    let mut crc = crc16::State::<crc16::CCITT_FALSE>::new();
+    if encoding == IsoEncoding::Mfm {
+        crc.update(&[ISO_SYNC_BYTE, ISO_SYNC_BYTE, ISO_SYNC_BYTE]);
+    }
+    crc.update(&[address_mark]);
+    crc
+}
+
+/// Sector as found on the track, including the content of the header and a wrong data CRC
+pub struct ScannedSector {
+    /// Cylinder, head, sector, size and CRC of the header
+    pub header: [u8; 6],
+    /// Time of the header since the start of the read in ticks of the 84 MHz timer
+    pub header_position: usize,
+    /// Sector data without the CRC
+    pub data: Vec<u8>,
+    pub data_crc_valid: bool,
+    /// Time it took to read the sector data in ticks
+    pub data_duration: usize,
+    pub bit_width_profile: Vec<PulseDuration>,
+    pub variable_bit_width: bool,
+}
+
+/// Number of words after a sector header in which the data is expected
+const MAX_WORDS_BEFORE_DATA: usize = 40;
+
+/// Provides the bytes of the words in the range if they are not interrupted by a sync word
+fn encoded_bytes(words: &[MfmWord], range: std::ops::Range<usize>) -> Option<Vec<u8>> {
+    words
+        .get(range)?
+        .iter()
+        .map(|f| match f {
+            MfmWord::Enc(val) => Some(*val),
+            MfmWord::SyncWord => None,
+        })
+        .collect()
+}
+
+/// Provides every sector of an MFM track with a valid header and complete data,
+/// even if the CRC of the data is wrong. Unlike the track parser, nothing is
+/// expected about the track. Used to archive copy protected disks.
+#[must_use]
+pub fn scan_iso_sectors(track: &[PulseDuration], density: Density) -> Vec<ScannedSector> {
+    let cellsize = match density {
+        Density::High => 84,
+        Density::SingleDouble => 168,
+    };
+    let (words, word_times) = decode_iso_words(track, IsoEncoding::Mfm, cellsize);
+
+    let mut sectors = Vec::new();
+    // Valid header with its position and the index of the word after it
+    let mut header: Option<([u8; 6], usize, usize)> = None;
+    let mut index = 0;
+
+    while let Some(word) = words.get(index) {
+        index += 1;
+        if *word != MfmWord::SyncWord {
+            continue;
+        }
+
+        match words.get(index) {
+            Some(MfmWord::Enc(ISO_IDAM)) => {
+                let position = word_times.get(index).copied();
+                index += 1;
+
+                header = encoded_bytes(&words, index..index + 6).and_then(|bytes| {
+                    let mut crc = address_mark_crc(IsoEncoding::Mfm, ISO_IDAM);
+                    crc.update(&bytes);
+                    let header: [u8; 6] = bytes.try_into().ok()?;
+                    (crc.get() == 0).then_some((header, position?, index + 6))
+                });
+            }
+            Some(MfmWord::Enc(ISO_DAM)) => {
+                index += 1;
+
+                let Some((header, header_position, header_end)) = header.take() else {
+                    continue;
+                };
+                if index - header_end > MAX_WORDS_BEFORE_DATA {
+                    continue;
+                }
+
+                let sector_size = 128 << (header[3] & 3);
+                let Some(mut data) = encoded_bytes(&words, index..index + sector_size + 2) else {
+                    continue;
+                };
+                let mut crc = address_mark_crc(IsoEncoding::Mfm, ISO_DAM);
+                crc.update(&data);
+                data.truncate(sector_size);
+
+                // The end of the address mark is the start of the first data byte
+                let byte_times = word_times
+                    .get(index - 1..index + sector_size)
+                    .unwrap_or_default();
+                let bit_width_profile = bit_width_profile(byte_times);
+
+                sectors.push(ScannedSector {
+                    header,
+                    header_position,
+                    data,
+                    data_crc_valid: crc.get() == 0,
+                    data_duration: match (byte_times.first(), byte_times.last()) {
+                        (Some(start), Some(end)) => end - start,
+                        _ => 0,
+                    },
+                    variable_bit_width: has_bit_width_variation(&bit_width_profile, cellsize),
+                    bit_width_profile,
+                });
+                index += sector_size + 2;
+            }
+            _ => {}
+        }
+    }
+
+    sectors
+}
 
 pub struct IsoTrackParser {
     collected_sectors: Option<Vec<CollectedSector>>,
@@ -21,44 +277,77 @@ pub struct IsoTrackParser {
     expected_cylinder: Option<u32>,
     expected_head: Option<u32>,
     density: Density,
+    encoding: IsoEncoding,
     assumed_disk_type: Option<DiskType>,
+    measure_timing: bool,
+    accept_bad_crc: bool,
 }
 
 impl IsoTrackParser {
     #[must_use]
     pub fn new(expected_sectors_per_track: Option<usize>, density: Density) -> Self {
+        Self::new_with_encoding(expected_sectors_per_track, density, IsoEncoding::Mfm)
+    }
+
+    #[must_use]
+    pub fn new_with_encoding(
+        expected_sectors_per_track: Option<usize>,
+        density: Density,
+        encoding: IsoEncoding,
+    ) -> Self {
         Self {
             collected_sectors: None,
             expected_sectors_per_track,
             expected_cylinder: None,
             expected_head: None,
             density,
+            encoding,
             assumed_disk_type: None,
+            measure_timing: false,
+            accept_bad_crc: false,
         }
     }
 }
 
 impl TrackParser for IsoTrackParser {
     fn default_file_extension(&self) -> &str {
-        match self.density {
-            Density::High => "img",
-            Density::SingleDouble => "st",
+        match (self.encoding, self.density) {
+            (IsoEncoding::Mfm, Density::SingleDouble) => "st",
+            (IsoEncoding::Mfm, Density::High) | (IsoEncoding::Fm, _) => "img",
         }
     }
 
     fn format_name(&self) -> &str {
-        match self.density {
-            Density::High => "High Density ISO - could be MS-DOS",
-            Density::SingleDouble => "Double Density ISO - could be Atari ST",
+        match (self.encoding, self.density) {
+            (IsoEncoding::Mfm, Density::High) => "High Density ISO - could be MS-DOS",
+            (IsoEncoding::Mfm, Density::SingleDouble) => "Double Density ISO - could be Atari ST",
+            (IsoEncoding::Fm, Density::High) => "FM ISO with high data rate",
+            (IsoEncoding::Fm, Density::SingleDouble) => "Single Density FM ISO - could be IBM 3740",
         }
     }
 
-    fn duration_to_record(&self) -> usize {
-        let rpm = match self.assumed_disk_type {
-            Some(DiskType::Inch3_5) => DRIVE_3_5_RPM,
-            Some(DiskType::Inch5_25) => DRIVE_5_25_RPM,
-            None => DRIVE_SLOWEST_RPM,
+    fn track_encoding(&self) -> &'static str {
+        match self.encoding {
+            IsoEncoding::Fm => "FM",
+            IsoEncoding::Mfm => "MFM",
+        }
+    }
+
+    fn alternative_encoding(&self) -> Option<DynTrackParser> {
+        let encoding = match self.encoding {
+            IsoEncoding::Fm => IsoEncoding::Mfm,
+            IsoEncoding::Mfm => IsoEncoding::Fm,
         };
+        // The number of sectors usually differs between the encodings
+        let mut parser = Self::new_with_encoding(None, self.density, encoding);
+        parser.assumed_disk_type = self.assumed_disk_type;
+        parser.measure_timing = self.measure_timing;
+        parser.accept_bad_crc = self.accept_bad_crc;
+        Some(Box::new(parser))
+    }
+
+    fn duration_to_record(&self) -> usize {
+        let rpm = rotation_rpm(self.assumed_disk_type);
 
         let percent = match self.density {
             Density::High => 108,
@@ -71,46 +360,80 @@ impl TrackParser for IsoTrackParser {
         self.density
     }
 
+    fn set_disk_type(&mut self, disk_type: DiskType) {
+        self.assumed_disk_type = Some(disk_type);
+        // No need to learn the layout from a possibly damaged first track
+        if matches!(disk_type, DiskType::Inch5_25)
+            && self.density == Density::High
+            && self.encoding == IsoEncoding::Mfm
+        {
+            self.expected_sectors_per_track
+                .get_or_insert(SECTORS_PER_TRACK_5_25_HD);
+        }
+    }
+
+    fn set_accept_bad_crc(&mut self, accept_bad_crc: bool) {
+        self.accept_bad_crc = accept_bad_crc;
+    }
+
+    fn set_measure_timing(&mut self, measure_timing: bool) {
+        self.measure_timing = measure_timing;
+    }
+
+    fn disk_type(&self) -> Option<DiskType> {
+        self.assumed_disk_type
+    }
+
     fn default_trackfilter(&self) -> crate::rawtrack::TrackFilter {
         TrackFilter {
             cyl_start: Some(0),
-            cyl_end: Some(79),
+            cyl_end: Some(util::DEFAULT_MAX_CYLINDER),
             head: None,
         }
     }
-    fn parse_raw_track(&mut self, track: &[u8]) -> anyhow::Result<TrackPayload> {
+    fn parse_flux_timings(&mut self, track: &[PulseDuration]) -> anyhow::Result<TrackPayload> {
         //println!("{:x?}", track);
 
-        let mut mfm_words: Vec<MfmWord> = Vec::new();
-        let mut mfmd = MfmDecoder::new(|f| mfm_words.push(f));
-
         let cellsize = match self.density {
             Density::High => 84,
             Density::SingleDouble => 168,
         };
+        // FM allows flux changes in neighbouring cells which requires cells of twice the length
+        let cellsize = match self.encoding {
+            IsoEncoding::Fm => cellsize * 2,
+            IsoEncoding::Mfm => cellsize,
+        };
+        let encoding = self.encoding;
 
-        let mut pulseparser = FluxPulseToCells::new(|val| mfmd.feed(val), cellsize);
-
-        track
-            .iter()
-            .for_each(|f| pulseparser.feed(PulseDuration(i32::from(*f) << PULSE_REDUCE_SHIFT)));
+        // Time at which every word was decoded to measure the bit width
+        let (mfm_words, word_times) = decode_iso_words(track, encoding, cellsize);
 
+        let number_of_words = mfm_words.len();
         let mut iterator = mfm_words.into_iter();
 
         let mut awaiting_dam = 0;
         let mut sector_header = Vec::new();
+        let mut header_position = None;
         let mut number_of_duplicate_sector_headers_found_in_stream = 0;
+        // Time between two occurrences of the same sector header
+        let mut rotation = None;
+        let mut sync_words_found = 0;
 
         // Search for Syncs until the end.
         while let Some(searchword) = iterator.next() {
             awaiting_dam -= 1;
 
             if matches!(searchword, MfmWord::SyncWord) {
+                sync_words_found += 1;
                 let address_mark_type = iterator.next();
 
                 match address_mark_type {
                     Some(MfmWord::Enc(ISO_IDAM)) => {
                         sector_header.clear();
+                        // The address mark was the last word taken
+                        header_position = word_times
+                            .get(number_of_words - iterator.len() - 1)
+                            .copied();
 
                         for _ in 0..6 {
                             if let Some(MfmWord::Enc(val)) = iterator.next() {
@@ -120,8 +443,7 @@ impl TrackParser for IsoTrackParser {
 
                         let sector_index = ensure_index!(sector_header[2]);
 
-                        let mut crc = crc16::State::<crc16::CCITT_FALSE>::new();
-                        crc.update(&[ISO_SYNC_BYTE, ISO_SYNC_BYTE, ISO_SYNC_BYTE, ISO_IDAM]);
+                        let mut crc = address_mark_crc(encoding, ISO_IDAM);
                         crc.update(&sector_header);
                         let crc16 = crc.get();
                         if crc16 == 0 {
@@ -132,11 +454,21 @@ impl TrackParser for IsoTrackParser {
                                 .as_mut()
                                 .context(program_flow_error!())?;
 
-                            if collected_sectors
+                            if let Some(sector) = collected_sectors
                                 .iter()
-                                .any(|f| f.index == u32::from(sector_index))
+                                .find(|f| f.index == u32::from(sector_index))
                             {
                                 number_of_duplicate_sector_headers_found_in_stream += 1;
+                                if rotation.is_none()
+                                    && let (Some(first), Some(again)) =
+                                        (sector.position, header_position)
+                                {
+                                    rotation = Some(again - first);
+                                }
+                                // Another chance for data which was kept with a wrong checksum
+                                if sector.bad_crc {
+                                    awaiting_dam = DAM_SEARCH_WINDOW;
+                                }
                             } else if ensure_index!(sector_header[0]) as u32
                                 != self.expected_cylinder.context(program_flow_error!())?
                             {
@@ -147,7 +479,7 @@ impl TrackParser for IsoTrackParser {
                                 );
                             } else {
                                 // Activate DAM reading for the next 40 data bytes
-                                awaiting_dam = 40;
+                                awaiting_dam = DAM_SEARCH_WINDOW;
                             }
 
                             ensure!(
@@ -162,6 +494,7 @@ impl TrackParser for IsoTrackParser {
                     Some(MfmWord::Enc(ISO_DAM)) if awaiting_dam > 0 => {
                         let sector_size = 128 << ensure_index!(sector_header[3]);
                         let mut sector_data = Vec::with_capacity(sector_size + 2);
+                        let data_start = number_of_words - iterator.len();
 
                         for _ in 0..sector_size + 2 {
                             if let Some(MfmWord::Enc(val)) = iterator.next() {
@@ -174,30 +507,51 @@ impl TrackParser for IsoTrackParser {
 
                         let sector_index = ensure_index!(sector_header[2]);
 
-                        let mut crc = crc16::State::<crc16::CCITT_FALSE>::new();
-                        crc.update(&[ISO_SYNC_BYTE, ISO_SYNC_BYTE, ISO_SYNC_BYTE, ISO_DAM]);
+                        let mut crc = address_mark_crc(encoding, ISO_DAM);
                         crc.update(&sector_data);
-                        let crc16 = crc.get();
-                        if crc16 == 0 {
+                        let bad_crc = crc.get() != 0;
+                        if bad_crc {
+                            log::warn!("DAM CRC Error Sector {}", sector_index);
+                        }
+                        if !bad_crc || self.accept_bad_crc {
                             let collected_sectors = self
                                 .collected_sectors
                                 .as_mut()
                                 .context(program_flow_error!())?;
 
+                            // Replaces the data of a previous read with a wrong checksum
+                            collected_sectors.retain(|f| f.index != u32::from(sector_index));
                             sector_data.resize(sector_size, 0); // remove CRC at the end
+
+                            // The end of the address mark is the start of the first data byte
+                            let profile = bit_width_profile(
+                                word_times
+                                    .get(data_start - 1..data_start + sector_size)
+                                    .unwrap_or_default(),
+                            );
+                            let variable_bit_width = has_bit_width_variation(&profile, cellsize);
+                            if variable_bit_width {
+                                log::info!("Variable bit width in sector {}", sector_index);
+                            }
+                            let bit_width_profile =
+                                (variable_bit_width || self.measure_timing).then_some(profile);
+
                             collected_sectors.push(CollectedSector {
                                 index: u32::from(sector_index),
                                 payload: sector_data,
+                                bit_width_profile,
+                                variable_bit_width,
+                                position: header_position,
+                                bad_crc,
                             });
 
                             if let Some(expected_sectors_per_track) = self.expected_sectors_per_track &&
-                                expected_sectors_per_track == collected_sectors.len()
+                                expected_sectors_per_track == collected_sectors.len() &&
+                                !collected_sectors.iter().any(|f| f.bad_crc)
                             {
                                 // Exit it after we got all expected sectors.
                                 break;
                             }
-                        } else {
-                            log::warn!("DAM CRC Error Sector {}", sector_index);
                         }
                     }
                     _ => {}
@@ -205,6 +559,14 @@ impl TrackParser for IsoTrackParser {
             }
         }
 
+        // Without any sync, the surface was never formatted. This is not a failed read.
+        if sync_words_found == 0 {
+            bail!(ToolError::BlankTrack {
+                cylinder: self.expected_cylinder.context(program_flow_error!())?,
+                head: self.expected_head.context(program_flow_error!())?,
+            });
+        }
+
         // we need to at least have one sector. if not, this read was not successful at all
         ensure!(
             self.collected_sectors
@@ -215,16 +577,34 @@ impl TrackParser for IsoTrackParser {
         );
 
         self.assumed_disk_type.get_or_insert_with(|| {
-            println!(
-                "Number of duplicate sectors in stream: {number_of_duplicate_sector_headers_found_in_stream}"
-            );
-            if number_of_duplicate_sector_headers_found_in_stream > 5 {
-                println!("Assume 5.25 inch drive.");
-                DiskType::Inch5_25
+            let rotation = rotation.filter(|rotation| {
+                let rotation_ms = *rotation as f64 / STM_TIMER_HZ * 1000.0;
+                println!("Measured rotation of {rotation_ms:.1} ms");
+                let plausible = PLAUSIBLE_ROTATION_MS.contains(&rotation_ms);
+                if !plausible {
+                    println!("Rotation is implausible and ignored");
+                }
+                plausible
+            });
+            let disk_type = if let Some(rotation) = rotation {
+                disk_type_of_rotation(rotation)
             } else {
-                println!("Assume 3.5 inch drive.");
-                DiskType::Inch3_5
+                println!(
+                    "Number of duplicate sectors in stream: {number_of_duplicate_sector_headers_found_in_stream}"
+                );
+                if number_of_duplicate_sector_headers_found_in_stream
+                    > DUPLICATE_HEADER_THRESHOLD.load(Ordering::Relaxed)
+                {
+                    DiskType::Inch5_25
+                } else {
+                    DiskType::Inch3_5
+                }
+            };
+            match disk_type {
+                DiskType::Inch5_25 => println!("Assume 5.25 inch drive."),
+                DiskType::Inch3_5 => println!("Assume 3.5 inch drive."),
             }
+            disk_type
         });
 
         ensure!(self.assumed_disk_type.is_some());
@@ -248,13 +628,33 @@ impl TrackParser for IsoTrackParser {
                 .context(program_flow_error!())?
                 .len();
 
+            // A missed sector would be taken as layout for all following tracks.
+            // Sectors are numbered from 1 without gaps by ISO formats.
+            let mut sector_numbers: Vec<u32> = self
+                .collected_sectors
+                .as_ref()
+                .context(program_flow_error!())?
+                .iter()
+                .map(CollectedSector::index)
+                .filter(|f| (1..=MAX_SECTORS_PER_TRACK).contains(f))
+                .collect();
+            sector_numbers.sort_unstable();
+            ensure!(
+                sector_numbers
+                    .iter()
+                    .copied()
+                    .eq(1..=sector_numbers.len() as u32),
+                "Sectors {sector_numbers:?} have gaps. Some were probably not found."
+            );
+
             println!("Assume {collected_sector_number} sectors per track from now on...");
             self.expected_sectors_per_track = Some(collected_sector_number);
         }
 
+        // Keep the sectors for take_collected_sectors
         let collected_sectors = self
             .collected_sectors
-            .take()
+            .clone()
             .context(program_flow_error!())?;
 
         Ok(concatenate_sectors(
@@ -270,7 +670,328 @@ impl TrackParser for IsoTrackParser {
         self.collected_sectors = Some(Vec::new());
     }
 
+    fn take_collected_sectors(&mut self) -> Vec<CollectedSector> {
+        self.collected_sectors.take().unwrap_or_default()
+    }
+
+    fn expected_sectors(&self) -> Option<usize> {
+        self.expected_sectors_per_track
+    }
+
     fn step_size(&self) -> usize {
         1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_reader::image_iso::{generate_iso_track, IsoGeometry},
+        rawtrack::RawTrack,
+    };
+    use util::{fm::FmEncoder, DensityMapEntry, Encoding};
+
+    /// Single density track with sectors of 128 bytes like IBM 3740.
+    /// The data of the sector at `bad_crc_index` gets a wrong checksum.
+    fn fm_track_flux(
+        cylinder: u8,
+        head: u8,
+        sectors: &[u8],
+        bad_crc_index: Option<usize>,
+    ) -> Vec<PulseDuration> {
+        let with_crc = |address_mark: u8, data: &[u8], broken: bool| {
+            let mut crc = address_mark_crc(IsoEncoding::Fm, address_mark);
+            crc.update(data);
+            let mut result = data.to_vec();
+            result.extend_from_slice(&(crc.get() ^ u16::from(broken)).to_be_bytes());
+            result.into_iter().map(FmWord::Enc)
+        };
+
+        let mut words = vec![FmWord::Enc(0xff); 40];
+        for (index, sector) in sectors.chunks_exact(128).enumerate() {
+            let header = [cylinder, head, index as u8 + 1, 0];
+            words.extend([FmWord::Enc(0); 6]);
+            words.push(FmWord::AddressMark(ISO_IDAM));
+            words.extend(with_crc(ISO_IDAM, &header, false));
+            words.extend([FmWord::Enc(0xff); 11]);
+            words.extend([FmWord::Enc(0); 6]);
+            words.push(FmWord::AddressMark(ISO_DAM));
+            words.extend(with_crc(ISO_DAM, sector, bad_crc_index == Some(index)));
+            words.extend([FmWord::Enc(0xff); 27]);
+        }
+
+        let mut cells = Vec::new();
+        let mut encoder = FmEncoder::new(|f: util::Bit| cells.push(f.0));
+        words.into_iter().for_each(|f| encoder.feed(f));
+
+        let mut flux = Vec::new();
+        let mut duration = 0;
+        for cell in cells {
+            duration += 336;
+            if cell {
+                flux.push(PulseDuration(duration));
+                duration = 0;
+            }
+        }
+        flux
+    }
+
+    #[test]
+    fn fm_track_test() {
+        let sectors: Vec<u8> = (0..16 * 128).map(|f| (f % 253) as u8).collect();
+        let flux = fm_track_flux(0, 0, &sectors, None);
+
+        let mut parser =
+            IsoTrackParser::new_with_encoding(Some(16), Density::SingleDouble, IsoEncoding::Fm);
+        parser.expect_track(0, 0);
+        assert_eq!(parser.parse_flux_timings(&flux).unwrap().payload, sectors);
+
+        // Without a single sync of MFM, the track looks unformatted
+        let mut parser = IsoTrackParser::new(None, Density::SingleDouble);
+        parser.expect_track(0, 0);
+        let error = parser.parse_flux_timings(&flux).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::BlankTrack { .. })
+        ));
+
+        let mut alternative = parser.alternative_encoding().unwrap();
+        assert_eq!(alternative.track_encoding(), "FM");
+        alternative.expect_track(0, 0);
+        assert_eq!(
+            alternative.parse_flux_timings(&flux).unwrap().payload,
+            sectors
+        );
+    }
+
+    #[test]
+    fn accept_bad_crc_test() {
+        let sectors: Vec<u8> = (0..16 * 128).map(|f| (f % 253) as u8).collect();
+        let flux = fm_track_flux(0, 0, &sectors, Some(4));
+        let parse = |accept_bad_crc: bool| {
+            let mut parser =
+                IsoTrackParser::new_with_encoding(Some(16), Density::SingleDouble, IsoEncoding::Fm);
+            parser.set_accept_bad_crc(accept_bad_crc);
+            parser.expect_track(0, 0);
+            parser.parse_flux_timings(&flux)
+        };
+
+        // The broken sector is missing
+        assert!(parse(false).is_err());
+
+        let payload = parse(true).unwrap();
+        assert_eq!(payload.payload, sectors);
+        let bad_crc: Vec<u32> = payload
+            .sectors
+            .iter()
+            .filter(|f| f.has_bad_crc())
+            .map(CollectedSector::index)
+            .collect();
+        assert_eq!(bad_crc, vec![5]);
+    }
+
+    #[test]
+    fn drive_type_5_25_hd_test() {
+        let flux_of_track = |sectors_per_track| {
+            let sectors: Vec<u8> = (0..sectors_per_track * 512)
+                .map(|f| (f % 251) as u8)
+                .collect();
+            let geometry = IsoGeometry::new(sectors_per_track);
+            let trackbuf =
+                generate_iso_track(0, 0, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(84),
+            }];
+            let track = RawTrack::new(0, 0, trackbuf, densitymap, Encoding::MFM);
+            (track.simulate_read(2).unwrap(), sectors)
+        };
+        let parser_for_5_25 = || {
+            let mut parser = IsoTrackParser::new(None, Density::High);
+            let duration_3_5 = parser.duration_to_record();
+            parser.set_disk_type(DiskType::Inch5_25);
+            // The drive rotates faster
+            assert!(parser.duration_to_record() < duration_3_5);
+            parser.expect_track(0, 0);
+            parser
+        };
+
+        let (flux, sectors) = flux_of_track(15);
+        let mut parser = parser_for_5_25();
+        assert_eq!(parser.parse_raw_track(&flux).unwrap().payload, sectors);
+
+        // A track with a missing sector is not taken as layout
+        let (flux, _) = flux_of_track(14);
+        assert!(parser_for_5_25().parse_raw_track(&flux).is_err());
+        let mut parser = IsoTrackParser::new(None, Density::High);
+        parser.expect_track(0, 0);
+        assert!(parser.parse_raw_track(&flux).is_ok());
+
+        // Double density disks of 5.25 inch drives have no fixed layout
+        let mut parser = IsoTrackParser::new(None, Density::SingleDouble);
+        parser.set_disk_type(DiskType::Inch5_25);
+        assert_eq!(parser.expected_sectors(), None);
+    }
+
+    #[test]
+    fn disk_type_from_rotation_test() {
+        let sectors: Vec<u8> = (0..7 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf =
+            generate_iso_track(0, 0, &IsoGeometry::new(7), &mut sectors.chunks_exact(512)).unwrap();
+        let gap = trackbuf.get(trackbuf.len() - 2..).unwrap().to_vec();
+
+        // Fill a rotation of 300 and 360 RPM with the same data rate.
+        // A rotation of 272 ms is implausible and the duplicate headers decide.
+        for (track_len, is_5_25) in [(12500, false), (10416, true), (17000, true)] {
+            let mut trackbuf = trackbuf.clone();
+            while trackbuf.len() < track_len {
+                trackbuf.extend_from_slice(&gap);
+            }
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(168),
+            }];
+            let track = RawTrack::new(0, 0, trackbuf, densitymap, Encoding::MFM);
+
+            let mut parser = IsoTrackParser::new(None, Density::SingleDouble);
+            parser.expect_track(0, 0);
+            parser
+                .parse_raw_track(&track.simulate_read(2).unwrap())
+                .unwrap();
+            assert_eq!(
+                matches!(parser.disk_type(), Some(DiskType::Inch5_25)),
+                is_5_25
+            );
+        }
+    }
+
+    #[test]
+    fn selected_disk_type_test() {
+        let sectors: Vec<u8> = (0..7 * 512).map(|f| (f % 251) as u8).collect();
+        let mut trackbuf =
+            generate_iso_track(0, 0, &IsoGeometry::new(7), &mut sectors.chunks_exact(512)).unwrap();
+        let gap = trackbuf.get(trackbuf.len() - 2..).unwrap().to_vec();
+        // A rotation of 360 RPM
+        while trackbuf.len() < 10416 {
+            trackbuf.extend_from_slice(&gap);
+        }
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let track = RawTrack::new(0, 0, trackbuf, densitymap, Encoding::MFM);
+
+        // The selection of the user is kept instead of guessing from the rotation
+        let mut parser = IsoTrackParser::new(None, Density::SingleDouble);
+        parser.set_disk_type(DiskType::Inch3_5);
+        parser.expect_track(0, 0);
+        parser
+            .parse_raw_track(&track.simulate_read(2).unwrap())
+            .unwrap();
+        assert!(matches!(parser.disk_type(), Some(DiskType::Inch3_5)));
+    }
+
+    #[test]
+    fn bit_width_variation_test() {
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf =
+            generate_iso_track(0, 0, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+
+        // The data of the first sector follows the second sync of the track
+        let data_start = trackbuf
+            .windows(6)
+            .enumerate()
+            .filter(|(_, f)| *f == [0x44, 0x89, 0x44, 0x89, 0x44, 0x89])
+            .nth(1)
+            .unwrap()
+            .0
+            + 8;
+
+        // Some bytes in the middle of the sector are written with shorter cells
+        let densitymap = vec![
+            DensityMapEntry {
+                number_of_cellbytes: data_start + 256,
+                cell_size: PulseDuration(168),
+            },
+            DensityMapEntry {
+                number_of_cellbytes: 256,
+                cell_size: PulseDuration(148),
+            },
+            DensityMapEntry {
+                number_of_cellbytes: trackbuf.len() - data_start - 512,
+                cell_size: PulseDuration(168),
+            },
+        ];
+        let track = RawTrack::new(0, 0, trackbuf, densitymap, Encoding::MFM);
+
+        let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        parser.expect_track(0, 0);
+        let payload = parser
+            .parse_raw_track(&track.simulate_read(1).unwrap())
+            .unwrap();
+        assert_eq!(payload.payload, sectors);
+
+        let protected: Vec<u32> = payload
+            .sectors
+            .iter()
+            .filter(|f| f.bit_width_profile().is_some())
+            .map(CollectedSector::index)
+            .collect();
+        assert_eq!(protected, vec![1]);
+
+        let profile = payload
+            .sectors
+            .first()
+            .unwrap()
+            .bit_width_profile()
+            .unwrap();
+        assert_eq!(profile.len(), 512 / BIT_WIDTH_PROFILE_BYTES);
+        assert!(profile.iter().any(|f| f.0 < 155));
+    }
+
+    #[test]
+    fn atari_extended_formats_test() {
+        let flux_of_track = |geometry: &IsoGeometry| {
+            let sectors: Vec<u8> = (0..geometry.sectors_per_track * 512)
+                .map(|f| (f % 251) as u8)
+                .collect();
+            let trackbuf =
+                generate_iso_track(5, 1, geometry, &mut sectors.chunks_exact(512)).unwrap();
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: trackbuf.len(),
+                cell_size: PulseDuration(168),
+            }];
+            let track = RawTrack::new(5, 1, trackbuf, densitymap, Encoding::MFM);
+            (track.simulate_read(2).unwrap(), sectors)
+        };
+
+        // Some formatters use a longer gap after the sector header than the writer.
+        // With 27 bytes, the data address mark ends 43 bytes after the header.
+        for geometry in [
+            IsoGeometry::new(10),
+            IsoGeometry::new(11),
+            IsoGeometry {
+                gap3a_size: 27,
+                ..IsoGeometry::new(11)
+            },
+        ] {
+            let (flux, sectors) = flux_of_track(&geometry);
+            let mut parser = IsoTrackParser::new(None, Density::SingleDouble);
+            parser.expect_track(5, 1);
+            assert_eq!(parser.parse_raw_track(&flux).unwrap().payload, sectors);
+            assert_eq!(parser.expected_sectors(), Some(geometry.sectors_per_track));
+        }
+
+        // A missing sector is not taken as layout
+        let geometry = IsoGeometry {
+            sector_ids: Some(vec![1, 2, 3, 4, 5, 6, 8, 9, 10, 11]),
+            ..IsoGeometry::new(10)
+        };
+        let (flux, _) = flux_of_track(&geometry);
+        let mut parser = IsoTrackParser::new(None, Density::SingleDouble);
+        parser.expect_track(5, 1);
+        assert!(parser.parse_raw_track(&flux).is_err());
+        assert_eq!(parser.expected_sectors(), None);
+    }
+}
@@ -1,33 +1,171 @@
-use std::{ffi::OsStr, fs::File, io::Write, path::Path};
+use std::{
+    cmp::Reverse,
+    convert::TryFrom,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fs::{self, File},
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        LazyLock, RwLock,
+    },
+};
 
 use anyhow::{bail, ensure, Context};
 use chrono::Local;
+use pretty_hex::{HexConfig, PrettyHex};
 use rusb::DeviceHandle;
-use util::{duration_of_rotation_as_stm_tim_raw, Density, DriveSelectState, DRIVE_SLOWEST_RPM};
+use util::{
+    duration_of_rotation_as_stm_tim_raw, Density, DensityMapEntry, DiskType, DriveSelectState,
+    Encoding, PulseDuration, DEFAULT_MAX_CYLINDER, DRIVE_3_5_RPM, DRIVE_5_25_RPM,
+    DRIVE_SLOWEST_RPM, PULSE_REDUCE_SHIFT,
+};
 
 use crate::{
-    rawtrack::TrackFilter,
-    track_parser::{amiga::AmigaTrackParser, c64::C64TrackParser, iso::IsoTrackParser},
-    usb_commands::{configure_device, read_raw_track},
+    error::ToolError,
+    filesystem::FileSystem,
+    image_reader::{
+        image_adf,
+        image_iso::{generate_iso_track, IsoGeometry, IsoPreset},
+        image_stx::sector_timing_values,
+    },
+    image_writer::{
+        image_g64::g64_image_to_bytes,
+        image_stx::{stx_file_from_records, stx_track_record},
+    },
+    raw_capture::RawCapture,
+    rawtrack::{RawImage, RawTrack, TrackFilter},
+    rich_image::{RichImageWriter, TrackRecord},
+    sector_order::SectorOrder,
+    track_parser::{
+        amiga::AmigaTrackParser,
+        c64::{g64_track, C64TrackParser},
+        iso::{IsoEncoding, IsoTrackParser},
+    },
+    usb_commands::{
+        check_operation_deadline, configure_device, ensure_cylinder_allowed, max_read_cylinder,
+        read_raw_track, read_raw_track_high_resolution,
+    },
 };
 
 pub mod amiga;
 pub mod c64;
 pub mod iso;
+pub mod verify;
 
 pub struct TrackPayload {
     pub cylinder: u32,
     pub head: u32,
     pub payload: Vec<u8>,
+    /// Sectors ordered by their index. Their data was moved into the payload.
+    /// Use `sector_payloads` to access it.
+    pub sectors: Vec<CollectedSector>,
+    /// Position of the data of every sector in the payload
+    sector_ranges: Vec<Range<usize>>,
+}
+
+impl TrackPayload {
+    /// Track without sectors, e.g. a blank track which was filled with zeros
+    #[must_use]
+    pub fn filled_with_zeros(cylinder: u32, head: u32, size: usize) -> Self {
+        Self {
+            cylinder,
+            head,
+            payload: vec![0; size],
+            sectors: Vec::new(),
+            sector_ranges: Vec::new(),
+        }
+    }
+
+    /// Provides every sector together with its data
+    pub fn sector_payloads(&self) -> impl Iterator<Item = (&CollectedSector, &[u8])> {
+        let payload = &self.payload;
+        self.sectors.iter().zip(
+            self.sector_ranges
+                .iter()
+                .map(move |f| payload.get(f.clone()).unwrap_or_default()),
+        )
+    }
+
+    /// Takes the sectors out of the track and moves their data back into them
+    #[must_use]
+    pub fn into_sectors(self) -> Vec<CollectedSector> {
+        let Self {
+            payload,
+            sectors,
+            sector_ranges,
+            ..
+        } = self;
+
+        sectors
+            .into_iter()
+            .zip(sector_ranges)
+            .map(|(mut sector, range)| {
+                sector.payload = payload.get(range).unwrap_or_default().to_vec();
+                sector
+            })
+            .collect()
+    }
 }
 
+#[derive(Clone)]
 pub struct CollectedSector {
     index: u32,
     payload: Vec<u8>,
+    /// Average cell size of every 16 data bytes if the bit width varies inside the sector
+    /// or if the timing was measured on request.
+    bit_width_profile: Option<Vec<PulseDuration>>,
+    /// The bit width varies inside the sector.
+    /// This is used by copy protections like Macrodos / Speedlock.
+    variable_bit_width: bool,
+    /// Time of the sector header since the start of the read in ticks of the 84 MHz timer.
+    /// Unknown for sectors combined from multiple reads.
+    position: Option<usize>,
+    /// The checksum of the data is wrong. Only kept if requested.
+    bad_crc: bool,
+}
+
+impl CollectedSector {
+    /// Index of the sector as found in the header
+    #[must_use]
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Cell sizes of the sector if the bit width varies inside of it
+    /// or if the timing was measured
+    #[must_use]
+    pub fn bit_width_profile(&self) -> Option<&[PulseDuration]> {
+        self.bit_width_profile.as_deref()
+    }
+
+    /// Probably protected by Macrodos / Speedlock
+    #[must_use]
+    pub fn has_variable_bit_width(&self) -> bool {
+        self.variable_bit_width
+    }
+
+    /// Time of the sector header since the start of the read if known
+    #[must_use]
+    pub fn position(&self) -> Option<usize> {
+        self.position
+    }
+
+    /// The data was kept despite a wrong checksum
+    #[must_use]
+    pub fn has_bad_crc(&self) -> bool {
+        self.bad_crc
+    }
 }
 
 pub trait TrackParser {
-    fn parse_raw_track(&mut self, track: &[u8]) -> anyhow::Result<TrackPayload>;
+    /// Parses flux timings which were reduced by `PULSE_REDUCE_SHIFT`
+    fn parse_raw_track(&mut self, track: &[u8]) -> anyhow::Result<TrackPayload> {
+        self.parse_flux_timings(&expand_reduced_pulses(track))
+    }
+    fn parse_flux_timings(&mut self, track: &[PulseDuration]) -> anyhow::Result<TrackPayload>;
     fn expect_track(&mut self, cylinder: u32, head: u32);
     fn step_size(&self) -> usize;
     fn track_density(&self) -> Density;
@@ -35,6 +173,88 @@ pub trait TrackParser {
     fn format_name(&self) -> &str;
     fn default_trackfilter(&self) -> TrackFilter;
     fn default_file_extension(&self) -> &str;
+    /// Provides the sectors with a valid checksum which were found since `expect_track`.
+    /// Useful to combine multiple reads which failed to provide a complete track.
+    fn take_collected_sectors(&mut self) -> Vec<CollectedSector>;
+    /// Number of sectors expected on the current track if known
+    fn expected_sectors(&self) -> Option<usize>;
+    /// Size of every sector if the format defines it
+    fn sector_size(&self) -> Option<usize> {
+        None
+    }
+    /// Use the rotation speed of this drive type instead of guessing it.
+    /// Only relevant for formats which exist for both drive types.
+    /// Formats bound to a single drive type like Amiga and C64 ignore it
+    /// and keep providing their own with `disk_type`.
+    fn set_disk_type(&mut self, _disk_type: DiskType) {}
+    /// Keep the bit width profile of every sector instead of only those with a variable bit width.
+    /// Only relevant for formats which can have a variable bit width.
+    fn set_measure_timing(&mut self, _measure_timing: bool) {}
+    /// Keep sectors with a wrong checksum of the data instead of dropping them.
+    /// Only relevant for formats which support it.
+    fn set_accept_bad_crc(&mut self, _accept_bad_crc: bool) {}
+    /// Drive type the disk is made for if known
+    fn disk_type(&self) -> Option<DiskType>;
+    /// Encoding of the cells for the geometry description
+    fn track_encoding(&self) -> &'static str;
+    /// Parser of the same format with another encoding. Used for disks
+    /// which mix encodings, e.g. with FM on the first track and MFM on the others.
+    fn alternative_encoding(&self) -> Option<DynTrackParser> {
+        None
+    }
+}
+
+/// Uses the drive type selected by the user for reading.
+/// Tells the user if the format is bound to another drive type.
+pub fn apply_drive_type(track_parser: &mut dyn TrackParser, drive_type: DiskType) {
+    track_parser.set_disk_type(drive_type);
+
+    if let Some(format_disk_type) = track_parser.disk_type()
+        && format_disk_type != drive_type
+    {
+        println!(
+            "{} disks are always read as {format_disk_type:?}. The selected drive type is ignored.",
+            track_parser.format_name()
+        );
+    }
+}
+
+/// Restores the resolution of flux timings which were reduced by `PULSE_REDUCE_SHIFT`
+#[must_use]
+pub fn expand_reduced_pulses(track: &[u8]) -> Vec<PulseDuration> {
+    track
+        .iter()
+        .map(|f| PulseDuration(i32::from(*f) << PULSE_REDUCE_SHIFT))
+        .collect()
+}
+
+/// Reads a track and provides the flux timings independent of the resolution used for transfer
+fn read_flux_timings(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    cylinder: u32,
+    head: u32,
+    wait_for_index: bool,
+    duration_to_record: usize,
+    high_resolution: bool,
+) -> anyhow::Result<Vec<PulseDuration>> {
+    if high_resolution {
+        read_raw_track_high_resolution(
+            usb_handles,
+            cylinder,
+            head,
+            wait_for_index,
+            duration_to_record,
+        )
+    } else {
+        let raw_data = read_raw_track(
+            usb_handles,
+            cylinder,
+            head,
+            wait_for_index,
+            duration_to_record,
+        )?;
+        Ok(expand_reduced_pulses(&raw_data))
+    }
 }
 
 fn concatenate_sectors(
@@ -47,174 +267,2440 @@ fn concatenate_sectors(
 
     let mut track_data = Vec::with_capacity(collected_sectors.len() * 512);
 
-    collected_sectors
+    let sector_ranges = collected_sectors
         .iter_mut()
-        .for_each(|f| track_data.append(&mut f.payload));
+        .map(|f| {
+            let start = track_data.len();
+            track_data.append(&mut f.payload);
+            start..track_data.len()
+        })
+        .collect();
 
     TrackPayload {
         cylinder,
         head,
         payload: track_data,
+        sectors: collected_sectors,
+        sector_ranges,
     }
 }
 
-type PossibleFormats = Vec<String>;
-type DynTrackParser = Box<dyn TrackParser>;
+/// Writes every sector of the track to its own file named `cCC_hH_sS.bin`
+fn write_sector_files(directory: &Path, track: &TrackPayload) -> anyhow::Result<()> {
+    for (sector, data) in track.sector_payloads() {
+        let filename = format!(
+            "c{:02}_h{}_s{}.bin",
+            track.cylinder, track.head, sector.index
+        );
+        fs::write(directory.join(filename), data)?;
+    }
+    Ok(())
+}
 
-pub fn read_first_track_discover_format(
-    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
-    select_drive: DriveSelectState,
-    index_sim_frequency: u32,
-) -> anyhow::Result<(Option<DynTrackParser>, PossibleFormats)> {
-    // For some reason, the High density can read both densities on the first few cylinders...
-    // This is very useful and I assume not random at all
-    // But there is one problem as it seems. For yet unknown reasons I can't read a flipped 5.25 inch disk
-    // with High Density as it introduces flux changes that shouldn't be there.
-    // With Double density I don't have that problem.
-    // As High Density can also be read on the first track, I believe that this is ok as well to just go for Double here.
-    configure_device(
-        usb_handles,
-        select_drive,
-        Density::SingleDouble,
-        index_sim_frequency,
-    )?;
+fn is_blank_track_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<ToolError>(),
+        Some(ToolError::BlankTrack { .. })
+    )
+}
 
-    // We need to make sure to read more than we need.
-    // We only have one chance here. So just get 125% of the first track with the slowest drive we support.
-    let duration_to_record = duration_of_rotation_as_stm_tim_raw(DRIVE_SLOWEST_RPM) * 125 / 100;
+fn is_killer_track_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<ToolError>(),
+        Some(ToolError::KillerTrack { .. })
+    )
+}
 
-    let track_parsers: Vec<DynTrackParser> = vec![
-        Box::new(AmigaTrackParser::new(util::Density::SingleDouble)),
-        Box::new(C64TrackParser::new()),
-        Box::new(IsoTrackParser::new(None, Density::SingleDouble)),
-        Box::new(IsoTrackParser::new(None, Density::High)),
-    ];
-    let cylinder = 0;
-    let head = 0;
+/// Result of parsing a track which was recorded over multiple revolutions
+pub struct RevolutionParseResult {
+    pub track: TrackPayload,
+    /// Index of the first revolution which was decoded successfully
+    pub revolution: usize,
+    /// Number of revolutions which were decoded successfully
+    pub decoded_revolutions: usize,
+    /// True if all successfully decoded revolutions provide the same payload
+    pub revolutions_agree: bool,
+}
 
-    let raw_data = read_raw_track(usb_handles, cylinder, head, false, duration_to_record)?;
+/// Splits a raw read over multiple revolutions into overlapping windows.
+/// Every window starts one rotation after the previous one and is `window_duration` long.
+/// As reading is not index aligned, the start of a window is arbitrary.
+fn split_revolutions(
+    raw_data: &[PulseDuration],
+    revolutions: usize,
+    rotation_duration: usize,
+    window_duration: usize,
+) -> Vec<&[PulseDuration]> {
+    // Accumulated duration at the start of every pulse
+    let mut positions = Vec::with_capacity(raw_data.len() + 1);
+    let mut accumulator = 0;
+    positions.push(accumulator);
+    for pulse in raw_data {
+        accumulator += pulse.0 as usize;
+        positions.push(accumulator);
+    }
 
-    let mut possible_track_parser: Option<DynTrackParser> = None;
-    let mut possible_formats = Vec::new();
+    let index_at = |duration: usize| positions.partition_point(|&p| p < duration);
 
-    for mut parser in track_parsers {
-        parser.expect_track(cylinder, head);
+    (0..revolutions)
+        .filter_map(|revolution| {
+            let start = index_at(revolution * rotation_duration);
+            let end = index_at(revolution * rotation_duration + window_duration)
+                .min(raw_data.len());
+            raw_data.get(start..end).filter(|window| !window.is_empty())
+        })
+        .collect()
+}
 
-        log::debug!("Trying format {}", parser.format_name());
+/// Parses every recorded revolution independently and reports which one was used.
+pub fn parse_revolutions(
+    track_parser: &mut dyn TrackParser,
+    raw_data: &[PulseDuration],
+    cylinder: u32,
+    head: u32,
+    revolutions: usize,
+) -> anyhow::Result<RevolutionParseResult> {
+    let rotation_duration =
+        duration_of_rotation_as_stm_tim_raw(rotation_rpm(track_parser.disk_type()));
+    let windows = split_revolutions(
+        raw_data,
+        revolutions,
+        rotation_duration,
+        track_parser.duration_to_record(),
+    );
 
-        let possible_track = parser.parse_raw_track(&raw_data);
-        match possible_track {
-            Ok(_track) => {
-                possible_formats.push(parser.format_name().into());
+    let mut result: Option<RevolutionParseResult> = None;
+    let mut all_blank = !windows.is_empty();
+    let mut all_killer = !windows.is_empty();
+
+    for (revolution, window) in windows.iter().enumerate() {
+        track_parser.expect_track(cylinder, head);
 
-                let old = possible_track_parser.replace(parser);
-                if old.is_some() {
-                    log::warn!("Warning: Multiple possible formats ?!?!?!?!")
+        match track_parser.parse_flux_timings(window) {
+            Ok(track) => {
+                if let Some(result) = result.as_mut() {
+                    result.decoded_revolutions += 1;
+                    if result.track.payload != track.payload {
+                        log::warn!(
+                            "Revolution {} of track {} {} differs from revolution {}",
+                            revolution,
+                            cylinder,
+                            head,
+                            result.revolution
+                        );
+                        result.revolutions_agree = false;
+                    }
+                } else {
+                    result = Some(RevolutionParseResult {
+                        track,
+                        revolution,
+                        decoded_revolutions: 1,
+                        revolutions_agree: true,
+                    });
                 }
             }
-            Err(x) => log::debug!("Parsing aborted: {}", x),
-        };
+            Err(x) => {
+                all_blank &= is_blank_track_error(&x);
+                all_killer &= is_killer_track_error(&x);
+                log::debug!("Revolution {} not decodable: {}", revolution, x);
+            }
+        }
     }
 
-    Ok((possible_track_parser, possible_formats))
+    if result.is_none() && all_blank {
+        bail!(ToolError::BlankTrack { cylinder, head });
+    }
+
+    if result.is_none() && all_killer {
+        bail!(ToolError::KillerTrack { cylinder, head });
+    }
+
+    result.context(format!(
+        "None of the {} revolutions of track {} {} was decodable",
+        windows.len(),
+        cylinder,
+        head
+    ))
 }
 
-pub fn read_tracks_to_diskimage(
+/// Result of combining the sectors of multiple reads of the same track
+pub struct MajorityReadResult {
+    pub track: TrackPayload,
+    /// Number of reads which were performed
+    pub reads: usize,
+    /// Number of reads which provided the complete track on their own
+    pub complete_reads: usize,
+    /// Number of sectors which were missing in at least one of the reads
+    pub recovered_sectors: usize,
+    /// Number of sectors which were read with different content
+    pub disagreeing_sectors: usize,
+    /// Number of sectors with a wrong checksum in every read.
+    /// Their content is the majority of every byte.
+    pub voted_sectors: usize,
+}
+
+/// Majority of every byte of differing copies of a sector with their number of reads.
+/// On a tie, the earlier copy wins.
+fn vote_bytes(copies: &[(&[u8], usize)]) -> Vec<u8> {
+    let len = copies.iter().map(|f| f.0.len()).max().unwrap_or(0);
+
+    (0..len)
+        .filter_map(|position| {
+            let mut votes: Vec<(u8, usize)> = Vec::new();
+            for (payload, reads) in copies {
+                if let Some(byte) = payload.get(position) {
+                    if let Some(vote) = votes.iter_mut().find(|f| f.0 == *byte) {
+                        vote.1 += reads;
+                    } else {
+                        votes.push((*byte, *reads));
+                    }
+                }
+            }
+            votes.into_iter().rev().max_by_key(|f| f.1).map(|f| f.0)
+        })
+        .collect()
+}
+
+/// Combines the sectors of multiple reads.
+/// For every sector index, the payload found most often is taken.
+/// Sectors with a wrong checksum in every read are combined byte by byte.
+/// The bit width profile is taken from the read which provided the payload.
+/// Returns the combined sectors, the number of sectors with disagreeing reads
+/// and the number of sectors combined byte by byte.
+fn vote_sectors(reads: Vec<Vec<CollectedSector>>) -> (Vec<CollectedSector>, usize, usize) {
+    // Every differing copy of a sector with the number of reads providing it
+    let mut candidates: BTreeMap<u32, Vec<(CollectedSector, usize)>> = BTreeMap::new();
+
+    for sector in reads.into_iter().flatten() {
+        let votes = candidates.entry(sector.index).or_default();
+        if let Some(vote) = votes
+            .iter_mut()
+            .find(|f| f.0.payload == sector.payload && f.0.bad_crc == sector.bad_crc)
+        {
+            vote.1 += 1;
+        } else {
+            votes.push((sector, 1));
+        }
+    }
+
+    let disagreeing_sectors = candidates.values().filter(|f| f.len() > 1).count();
+    let mut voted_sectors = 0;
+
+    let sectors = candidates
+        .into_values()
+        .filter_map(|votes| {
+            let bad_crc = votes.iter().all(|f| f.0.bad_crc);
+            let payload = if bad_crc && votes.len() > 1 {
+                voted_sectors += 1;
+                let copies: Vec<(&[u8], usize)> = votes
+                    .iter()
+                    .map(|f| (f.0.payload.as_slice(), f.1))
+                    .collect();
+                Some(vote_bytes(&copies))
+            } else {
+                None
+            };
+
+            // A valid checksum always wins. On a tie, the first read wins
+            let (mut sector, _) = votes
+                .into_iter()
+                .rev()
+                .max_by_key(|f| (!f.0.bad_crc, f.1))?;
+            if let Some(payload) = payload {
+                sector.payload = payload;
+            }
+            sector.position = None;
+            Some(sector)
+        })
+        .collect();
+
+    (sectors, disagreeing_sectors, voted_sectors)
+}
+
+/// Reads a track multiple times and combines the sectors with valid checksum of all reads.
+/// A sector which is damaged in one read might be fine in another.
+/// Sectors with a wrong checksum are only combined if the parser keeps them.
+/// Stops early if a single read already provides the complete track.
+pub fn read_track_majority(
     usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
-    track_filter: Option<TrackFilter>,
-    filepath: &str,
-    select_drive: DriveSelectState,
-    index_sim_frequency: u32,
-) -> anyhow::Result<()> {
-    let (mut track_parser, filepath) = if filepath == "justread" {
-        let (possible_track_parser, possible_formats) =
-            read_first_track_discover_format(usb_handles, select_drive, index_sim_frequency)?;
+    track_parser: &mut dyn TrackParser,
+    cylinder: u32,
+    head: u32,
+    reads: usize,
+    high_resolution: bool,
+) -> anyhow::Result<MajorityReadResult> {
+    ensure!(reads >= 1, "At least one read must be performed");
 
-        let track_parser = possible_track_parser.context("Unable to detect floppy format!")?;
-        println!("Format is probably '{:?}'", possible_formats);
+    let mut sectors_of_reads = Vec::with_capacity(reads);
 
-        let now = Local::now();
-        let time_str = now.format("%Y%m%d_%H%M%S");
-        let filepath = format!("{}.{}", time_str, track_parser.default_file_extension());
+    for read in 0..reads {
+        // Alternate index alignment like the normal retries do
+        let wait_for_index = aligned_to_index(read, false);
+        let raw_data = read_flux_timings(
+            usb_handles,
+            cylinder,
+            head,
+            wait_for_index,
+            track_parser.duration_to_record(),
+            high_resolution,
+        )?;
 
-        println!("Resulting image will be {filepath}");
+        track_parser.expect_track(cylinder, head);
+        match track_parser.parse_flux_timings(&raw_data) {
+            Ok(track) if !track.sectors.iter().any(CollectedSector::has_bad_crc) => {
+                return Ok(MajorityReadResult {
+                    track,
+                    reads: read + 1,
+                    complete_reads: 1,
+                    recovered_sectors: 0,
+                    disagreeing_sectors: 0,
+                    voted_sectors: 0,
+                });
+            }
+            Ok(track) => {
+                log::debug!(
+                    "Read {} of track {} {} has sectors with a wrong checksum",
+                    read,
+                    cylinder,
+                    head
+                );
+                sectors_of_reads.push(track.into_sectors());
+            }
+            Err(x) => {
+                log::debug!(
+                    "Read {} of track {} {} incomplete: {}",
+                    read,
+                    cylinder,
+                    head,
+                    x
+                );
+                sectors_of_reads.push(track_parser.take_collected_sectors());
+            }
+        }
+    }
 
-        (track_parser, filepath)
-    } else {
-        let file_extension = Path::new(filepath)
-            .extension()
-            .and_then(OsStr::to_str)
-            .context("No file extension!")?;
-
-        let track_parser: DynTrackParser = match file_extension {
-            "adf" => Box::new(AmigaTrackParser::new(util::Density::SingleDouble)),
-            "d64" => Box::new(C64TrackParser::new()),
-            "st" => Box::new(IsoTrackParser::new(None, Density::SingleDouble)),
-            "img" => Box::new(IsoTrackParser::new(None, Density::High)),
-            _ => bail!("{} is an unknown file extension!", file_extension),
-        };
+    let expected_sectors = track_parser
+        .expected_sectors()
+        .context("Number of sectors on this track is unknown")?;
+
+    let indices_per_read: Vec<BTreeSet<u32>> = sectors_of_reads
+        .iter()
+        .map(|f| f.iter().map(|sector| sector.index).collect())
+        .collect();
+    let (sectors, disagreeing_sectors, voted_sectors) = vote_sectors(sectors_of_reads);
+
+    ensure!(
+        sectors.len() == expected_sectors,
+        "Only {} of {} sectors found in {} reads of track {} {}",
+        sectors.len(),
+        expected_sectors,
+        reads,
+        cylinder,
+        head
+    );
+
+    let recovered_sectors = sectors
+        .iter()
+        .filter(|sector| !indices_per_read.iter().all(|f| f.contains(&sector.index)))
+        .count();
+
+    Ok(MajorityReadResult {
+        track: concatenate_sectors(sectors, cylinder, head),
+        reads,
+        complete_reads: 0,
+        recovered_sectors,
+        disagreeing_sectors,
+        voted_sectors,
+    })
+}
+
+/// Number of reads of a track until it is considered unreadable
+const READ_ATTEMPTS: usize = 5;
+
+/// Names of the formats able to decode a track. The most plausible one comes first.
+type PossibleFormats = Vec<String>;
+type DynTrackParser = Box<dyn TrackParser>;
+
+/// Creates a parser to read disks of a format
+pub type TrackParserFactory = Box<dyn Fn() -> Box<dyn TrackParser> + Send + Sync>;
+
+static TRACK_PARSERS: LazyLock<RwLock<HashMap<String, TrackParserFactory>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Adds support for reading a format which is not part of the tool.
+/// The parser is preferred over a built-in one of the same extension.
+pub fn register_track_parser(extension: &str, factory: TrackParserFactory) {
+    TRACK_PARSERS
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .insert(extension.to_lowercase(), factory);
+}
+
+/// Provides the parser for the sector based image format of a file extension
+#[must_use]
+pub fn track_parser_for_extension(file_extension: &str) -> Option<DynTrackParser> {
+    let factories = TRACK_PARSERS
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    if let Some(factory) = factories.get(&file_extension.to_lowercase()) {
+        return Some(factory());
+    }
 
-        (track_parser, filepath.into())
+    let track_parser: DynTrackParser = match file_extension {
+        "adf" => Box::new(AmigaTrackParser::new(util::Density::SingleDouble)),
+        "d64" => Box::new(C64TrackParser::new()),
+        "st" => Box::new(IsoTrackParser::new(None, Density::SingleDouble)),
+        "img" => Box::new(IsoTrackParser::new(None, Density::High)),
+        _ => return None,
     };
-    let track_filter = track_filter.unwrap_or_else(|| track_parser.default_trackfilter());
+    Some(track_parser)
+}
 
-    let duration_to_record = track_parser.duration_to_record();
-    configure_device(
-        usb_handles,
-        select_drive,
-        track_parser.track_density(),
-        index_sim_frequency,
-    )?;
+/// Provides the tracks to read if no filter was given. Formats which end at the
+/// last standard cylinder are read up to the configured maximum cylinder.
+pub fn configured_trackfilter(track_parser: &dyn TrackParser) -> TrackFilter {
+    let mut track_filter = track_parser.default_trackfilter();
+    if track_filter.cyl_end == Some(DEFAULT_MAX_CYLINDER) {
+        track_filter.cyl_end = Some(max_read_cylinder());
+    }
+    track_filter
+}
 
-    let mut cylinder_begin = track_filter.cyl_start.unwrap_or(0);
-    let mut cylinder_end = track_filter
-        .cyl_end
-        .context("Please specify the last cylinder to read!")?;
+/// Decodes a track which is about to be written as if it was read back from disk.
+/// The first parser able to decode the track provides the result.
+pub fn decode_raw_track(
+    track: &RawTrack,
+    track_parsers: &mut [DynTrackParser],
+) -> anyhow::Result<TrackPayload> {
+    // Two revolutions allow sectors to cross the index
+    let raw_data = track.simulate_read(2)?;
 
-    if cylinder_begin == cylinder_end {
-        cylinder_begin = 0;
+    for parser in track_parsers.iter_mut() {
+        parser.expect_track(track.cylinder, track.head);
+
+        match parser.parse_raw_track(&raw_data) {
+            Ok(payload) => return Ok(payload),
+            Err(x) => log::debug!("{} not decodable: {}", parser.format_name(), x),
+        }
+    }
+
+    bail!(
+        "Track {} {} is not decodable by any known format",
+        track.cylinder,
+        track.head
+    )
+}
+
+/// Recording duration of the discovery in percent of a rotation of the slowest drive
+pub const DEFAULT_DISCOVER_MARGIN_PERCENT: u32 = 125;
+
+static DISCOVER_MARGIN_PERCENT: AtomicU32 = AtomicU32::new(DEFAULT_DISCOVER_MARGIN_PERCENT);
+static DISCOVER_HIGH_DENSITY: AtomicBool = AtomicBool::new(false);
+
+/// Changes the recording duration of the discovery. A longer recording helps with
+/// slow drives. At least one full rotation is required.
+pub fn set_discover_margin(percent: u32) -> anyhow::Result<()> {
+    ensure!(
+        (100..=400).contains(&percent),
+        "Discover margin must be between 100 and 400%"
+    );
+    DISCOVER_MARGIN_PERCENT.store(percent, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Changes the density which is tried first during the discovery
+pub fn set_discover_density(density: Density) {
+    DISCOVER_HIGH_DENSITY.store(density == Density::High, Ordering::Relaxed);
+}
+
+/// Speed of the drive used to size the recordings. Zero if unknown.
+static READ_RPM: AtomicU64 = AtomicU64::new(0);
+
+/// Sizes the recordings of a read for the measured speed of the drive
+/// instead of assuming the slowest drive. Speeds up reading with fast drives.
+pub fn set_read_rpm(rpm: f64) -> anyhow::Result<()> {
+    ensure!(
+        (250.0..=400.0).contains(&rpm),
+        "Read RPM must be between 250 and 400"
+    );
+    READ_RPM.store(rpm.to_bits(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Speed of the drive while reading. `assumed_rpm` is used if none was configured.
+pub fn read_rpm(assumed_rpm: f64) -> f64 {
+    match READ_RPM.load(Ordering::Relaxed) {
+        0 => assumed_rpm,
+        bits => f64::from_bits(bits),
+    }
+}
+
+/// Speed of the drive while reading a disk of the given type.
+/// The slowest drive is assumed if the type is unknown.
+pub fn rotation_rpm(disk_type: Option<DiskType>) -> f64 {
+    read_rpm(match disk_type {
+        Some(DiskType::Inch3_5) => DRIVE_3_5_RPM,
+        Some(DiskType::Inch5_25) => DRIVE_5_25_RPM,
+        None => DRIVE_SLOWEST_RPM,
+    })
+}
+
+/// Densities in the order of the discovery.
+/// The other density is tried if the first one doesn't provide a known format.
+fn discover_densities() -> [Density; 2] {
+    // For some reason, the High density can read both densities on the first few cylinders...
+    // This is very useful and I assume not random at all
+    // But there is one problem as it seems. For yet unknown reasons I can't read a flipped 5.25 inch disk
+    // with High Density as it introduces flux changes that shouldn't be there.
+    // With Double density I don't have that problem.
+    // As High Density can also be read on the first track, I believe that this is ok as well to just go for Double here.
+    if DISCOVER_HIGH_DENSITY.load(Ordering::Relaxed) {
+        [Density::High, Density::SingleDouble]
     } else {
-        cylinder_end += 1;
+        [Density::SingleDouble, Density::High]
+    }
+}
+
+/// Tries to discover the format of a track with both densities
+fn discover_format_with_densities(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    cylinder: u32,
+) -> anyhow::Result<(Option<DynTrackParser>, PossibleFormats)> {
+    let mut result = (None, Vec::new());
+
+    for density in discover_densities() {
+        configure_device(usb_handles, select_drive, density, index_sim_frequency)?;
+
+        result = discover_format_on_track(usb_handles, cylinder, 0)?;
+        if result.0.is_some() {
+            break;
+        }
+        log::debug!("No known format found with {density:?} density");
     }
 
-    let heads = match track_filter.head {
-        Some(0) => 0..1,
-        Some(1) => 1..2,
-        None => 0..2,
-        _ => bail!(program_flow_error!()),
-    };
+    Ok(result)
+}
 
-    println!("Reading cylinders {cylinder_begin} to {cylinder_end}");
-    let mut outfile = File::create(filepath)?;
+pub fn read_first_track_discover_format(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+) -> anyhow::Result<(Option<DynTrackParser>, PossibleFormats)> {
+    discover_format_with_densities(usb_handles, select_drive, index_sim_frequency, 0)
+}
 
-    for cylinder in (cylinder_begin..cylinder_end).step_by(track_parser.step_size()) {
-        for head in heads.clone() {
-            track_parser.expect_track(cylinder, head);
+/// Like `read_first_track_discover_format` but tries multiple cylinders until
+/// a known format is found. Useful for disks with a damaged first track.
+/// The cylinder which provided the result is returned as well.
+pub fn discover_scan(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    cylinders_to_try: &[u32],
+) -> anyhow::Result<(Option<DynTrackParser>, PossibleFormats, Option<u32>)> {
+    for cylinder in cylinders_to_try {
+        println!("Try to discover format on cylinder {cylinder}");
 
-            let mut possible_track: Option<TrackPayload> = None;
+        let (possible_track_parser, possible_formats) = discover_format_with_densities(
+            usb_handles,
+            select_drive,
+            index_sim_frequency,
+            *cylinder,
+        )?;
 
-            for _ in 0..5 {
-                let raw_data =
-                    read_raw_track(usb_handles, cylinder, head, false, duration_to_record)?;
-                let track = track_parser.parse_raw_track(&raw_data).ok();
+        if possible_track_parser.is_some() {
+            return Ok((possible_track_parser, possible_formats, Some(*cylinder)));
+        }
+    }
 
-                if track.is_some() {
-                    possible_track = track;
-                    break;
-                }
+    Ok((None, Vec::new(), None))
+}
 
-                println!("Reading of track {cylinder} {head} not successful. Try again...")
-            }
+/// Cylinders read by `probe_cylinders` to tell 40 and 80 cylinder disks apart
+pub const DEFAULT_PROBE_CYLINDERS: [u32; 4] = [39, 40, 79, 80];
 
-            let track =
-                possible_track.context(format!("Unable to read track {} {}", cylinder, head))?;
+/// Result of reading a single cylinder during `probe_cylinders`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CylinderProbe {
+    /// Cylinder of the drive
+    pub cylinder: u32,
+    /// Format which decoded the track. None if no data was found.
+    pub format: Option<String>,
+    /// Cylinder of the disk according to the track. Half the cylinder of the drive
+    /// for a 40 cylinder disk in an 80 cylinder drive.
+    pub disk_cylinder: Option<u32>,
+}
 
-            ensure!(cylinder == track.cylinder);
-            ensure!(head == track.head);
+impl CylinderProbe {
+    /// The disk has only every second cylinder of the drive
+    #[must_use]
+    pub fn double_step(&self) -> bool {
+        self.disk_cylinder.is_some_and(|f| f != self.cylinder)
+    }
+}
+
+/// Likely number of cylinders of a disk, derived from the probes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CylinderCount {
+    /// Counted in cylinders of the disk
+    pub cylinders: u32,
+    /// The disk was written with a 40 cylinder drive and read with an 80 cylinder drive
+    pub double_step: bool,
+    /// The last probe still had data. The disk might have even more cylinders.
+    pub open_end: bool,
+}
+
+/// Reads the given cylinders on head 0 and reports which of them have decodable data.
+/// Cylinders beyond the configured maximum are skipped.
+pub fn probe_cylinders(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    cylinders: &[u32],
+) -> anyhow::Result<Vec<CylinderProbe>> {
+    let duration_to_record = duration_of_rotation_as_stm_tim_raw(DRIVE_SLOWEST_RPM)
+        * DISCOVER_MARGIN_PERCENT.load(Ordering::Relaxed) as usize
+        / 100;
+    let mut probes = Vec::new();
 
-            outfile.write_all(&track.payload)?;
+    for cylinder in cylinders.iter().copied() {
+        if cylinder > max_read_cylinder() {
+            println!("Cylinder {cylinder} skipped as it is beyond the maximum cylinder");
+            continue;
         }
+
+        let mut probe = CylinderProbe {
+            cylinder,
+            format: None,
+            disk_cylinder: None,
+        };
+
+        'densities: for density in discover_densities() {
+            configure_device(usb_handles, select_drive, density, index_sim_frequency)?;
+            let raw_data = read_raw_track(usb_handles, cylinder, 0, false, duration_to_record)?;
+
+            for mut parser in all_track_parsers()
+                .into_iter()
+                .filter(|f| f.track_density() == density)
+            {
+                // Formats with double steps, like the C64, already expect every second cylinder
+                let mut disk_cylinders = vec![cylinder];
+                if parser.step_size() == 1 && cylinder > 0 {
+                    disk_cylinders.push(cylinder / 2);
+                }
+
+                for disk_cylinder in disk_cylinders {
+                    parser.expect_track(disk_cylinder, 0);
+                    if parser.parse_raw_track(&raw_data).is_ok() {
+                        probe.format = Some(parser.format_name().to_owned());
+                        probe.disk_cylinder = Some(disk_cylinder);
+                        break 'densities;
+                    }
+                }
+            }
+        }
+
+        match (&probe.format, probe.double_step()) {
+            (None, _) => println!("Cylinder {cylinder}: No data found"),
+            (Some(format), false) => println!("Cylinder {cylinder}: {format}"),
+            (Some(format), true) => println!(
+                "Cylinder {cylinder}: {format} of cylinder {}",
+                probe.disk_cylinder.context(program_flow_error!())?
+            ),
+        }
+        probes.push(probe);
     }
 
-    Ok(())
+    Ok(probes)
+}
+
+/// The cylinder of the last probe with data is assumed to be the last one of the disk.
+/// None if no probe found any data.
+#[must_use]
+pub fn estimate_cylinder_count(probes: &[CylinderProbe]) -> Option<CylinderCount> {
+    let last_with_data = probes
+        .iter()
+        .filter(|f| f.disk_cylinder.is_some())
+        .max_by_key(|f| f.cylinder)?;
+    let last_probed = probes.iter().map(|f| f.cylinder).max()?;
+
+    Some(CylinderCount {
+        cylinders: last_with_data.disk_cylinder? + 1,
+        double_step: last_with_data.double_step(),
+        open_end: last_with_data.cylinder == last_probed,
+    })
+}
+
+fn all_track_parsers() -> Vec<DynTrackParser> {
+    vec![
+        Box::new(AmigaTrackParser::new(util::Density::SingleDouble)),
+        Box::new(C64TrackParser::new()),
+        Box::new(IsoTrackParser::new(None, Density::SingleDouble)),
+        Box::new(IsoTrackParser::new(None, Density::High)),
+        Box::new(IsoTrackParser::new_with_encoding(
+            None,
+            Density::SingleDouble,
+            IsoEncoding::Fm,
+        )),
+    ]
+}
+
+/// Reads a track and provides the data of a single sector.
+/// Every known format is tried with both densities. The sector is also found
+/// if other sectors of the track are damaged.
+pub fn read_sector(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    cylinder: u32,
+    head: u32,
+    sector: u32,
+) -> anyhow::Result<Vec<u8>> {
+    // See discover_format_on_track for the duration
+    let duration_to_record = duration_of_rotation_as_stm_tim_raw(DRIVE_SLOWEST_RPM) * 125 / 100;
+
+    for density in [Density::SingleDouble, Density::High] {
+        configure_device(usb_handles, select_drive, density, index_sim_frequency)?;
+        let raw_data = read_flux_timings(
+            usb_handles,
+            cylinder,
+            head,
+            false,
+            duration_to_record,
+            false,
+        )?;
+
+        if let Some((format_name, payload)) =
+            find_sector(&raw_data, density, cylinder, head, sector)
+        {
+            println!("Sector {sector} found as {format_name}");
+            return Ok(payload);
+        }
+    }
+
+    bail!("Sector {sector} not found on track {cylinder} {head}")
+}
+
+/// Tries all parsers of the density on the flux timings and
+/// provides the name of the format and the payload of the first match.
+fn find_sector(
+    raw_data: &[PulseDuration],
+    density: Density,
+    cylinder: u32,
+    head: u32,
+    sector: u32,
+) -> Option<(String, Vec<u8>)> {
+    let track_parsers = all_track_parsers()
+        .into_iter()
+        .filter(|f| f.track_density() == density)
+        // C64 disks have no second side
+        .filter(|f| head == 0 || f.step_size() == 1);
+
+    for mut parser in track_parsers {
+        parser.expect_track(cylinder, head);
+
+        // A failed track might still provide the requested sector
+        if let Err(x) = parser.parse_flux_timings(raw_data) {
+            log::debug!("{} not decodable: {}", parser.format_name(), x);
+        }
+
+        if let Some(found) = parser
+            .take_collected_sectors()
+            .into_iter()
+            .find(|f| f.index == sector)
+        {
+            return Some((parser.format_name().to_string(), found.payload));
+        }
+    }
+
+    None
+}
+
+/// Reads a track, replaces the data of a single sector and provides the track encoded again.
+/// The track must be readable without errors as all other sectors are kept.
+/// If the format doesn't define the number of sectors, a neighbouring track
+/// must have the same number of sectors. A preset provides the gaps of ISO tracks.
+pub fn patch_sector(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    (cylinder, head, sector): (u32, u32, u32),
+    data: &[u8],
+    preset: Option<IsoPreset>,
+) -> anyhow::Result<RawTrack> {
+    // See discover_format_on_track for the duration
+    let duration_to_record = duration_of_rotation_as_stm_tim_raw(DRIVE_SLOWEST_RPM) * 125 / 100;
+
+    for density in [Density::SingleDouble, Density::High] {
+        configure_device(usb_handles, select_drive, density, index_sim_frequency)?;
+        let raw_data = read_flux_timings(
+            usb_handles,
+            cylinder,
+            head,
+            false,
+            duration_to_record,
+            false,
+        )?;
+
+        let track_parsers = all_track_parsers()
+            .into_iter()
+            .filter(|f| f.track_density() == density)
+            .filter(|f| head == 0 || f.step_size() == 1);
+
+        for mut parser in track_parsers {
+            let layout_learned = parser.expected_sectors().is_none();
+            parser.expect_track(cylinder, head);
+
+            match parser.parse_flux_timings(&raw_data) {
+                Ok(payload) if payload.sectors.iter().any(|f| f.index == sector) => {
+                    println!("Track {cylinder} {head} read as {}", parser.format_name());
+                    if layout_learned {
+                        confirm_sectors_on_neighbour(
+                            usb_handles,
+                            parser.as_mut(),
+                            cylinder,
+                            head,
+                            duration_to_record,
+                        )?;
+                    }
+                    let expected_sectors =
+                        parser.expected_sectors().context(program_flow_error!())?;
+                    let track = encode_patched_track(
+                        parser.as_ref(),
+                        payload,
+                        sector,
+                        data,
+                        expected_sectors,
+                        preset,
+                    )?;
+
+                    let rpm = match parser.disk_type() {
+                        Some(DiskType::Inch5_25) => DRIVE_5_25_RPM,
+                        _ => DRIVE_3_5_RPM,
+                    };
+                    track.assert_fits_into_rotation(rpm)?;
+                    track.check_writability()?;
+                    return Ok(track);
+                }
+                Ok(_) => {}
+                Err(x) => log::debug!("{} not decodable: {}", parser.format_name(), x),
+            }
+        }
+    }
+
+    bail!("Track {cylinder} {head} with sector {sector} couldn't be read completely")
+}
+
+/// The parser has learned the number of sectors from the track to patch.
+/// A missing sector at the end of it would go unnoticed. Reading the previous
+/// or next track with the same expectation makes sure that none is missing.
+fn confirm_sectors_on_neighbour(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    parser: &mut dyn TrackParser,
+    cylinder: u32,
+    head: u32,
+    duration_to_record: usize,
+) -> anyhow::Result<()> {
+    let step_size = parser.step_size() as u32;
+    let neighbour = if cylinder >= step_size {
+        cylinder - step_size
+    } else {
+        cylinder + step_size
+    };
+
+    let raw_data = read_flux_timings(
+        usb_handles,
+        neighbour,
+        head,
+        false,
+        duration_to_record,
+        false,
+    )?;
+    parser.expect_track(neighbour, head);
+    parser.parse_flux_timings(&raw_data).with_context(|| {
+        format!(
+            "Track {neighbour} {head} doesn't have the {} sectors of track {cylinder} {head}. Some might be missing.",
+            parser.expected_sectors().unwrap_or_default()
+        )
+    })?;
+    parser.expect_track(cylinder, head);
+    Ok(())
+}
+
+/// Replaces the data of a sector and encodes the track again in the format of the parser.
+/// Partial tracks are refused as a missing sector would be lost.
+fn encode_patched_track(
+    parser: &dyn TrackParser,
+    payload: TrackPayload,
+    sector: u32,
+    data: &[u8],
+    expected_sectors: usize,
+    preset: Option<IsoPreset>,
+) -> anyhow::Result<RawTrack> {
+    let TrackPayload { cylinder, head, .. } = payload;
+    let mut sectors = payload.into_sectors();
+
+    ensure!(
+        sectors.len() == expected_sectors,
+        "Track {cylinder} {head} has {} of {expected_sectors} sectors. Only complete tracks can be patched.",
+        sectors.len()
+    );
+
+    let patched = sectors
+        .iter_mut()
+        .find(|f| f.index == sector)
+        .with_context(|| format!("Sector {sector} not found on track {cylinder} {head}"))?;
+    ensure!(
+        patched.payload.len() == data.len(),
+        "Sector {sector} has {} bytes but {} bytes were given",
+        patched.payload.len(),
+        data.len()
+    );
+    patched.payload = data.to_vec();
+
+    let sector_size = sectors.first().map_or(0, |f| f.payload.len());
+    ensure!(
+        sectors.iter().all(|f| f.payload.len() == sector_size),
+        "Sectors of different sizes on track {cylinder} {head} are not supported"
+    );
+    let track_data: Vec<u8> = sectors
+        .iter()
+        .flat_map(|f| f.payload.iter().copied())
+        .collect();
+    let mut sectors_in = track_data.chunks_exact(sector_size);
+
+    let trackbuf = match (parser.default_file_extension(), parser.track_encoding()) {
+        ("adf", _) => image_adf::generate_track(
+            cylinder,
+            head,
+            sectors.len() as u32,
+            0,
+            amiga::amiga_sync_word(),
+            &mut sectors_in,
+        )?,
+        ("st" | "img", "MFM") => {
+            let sector_ids = sectors
+                .iter()
+                .map(|f| u8::try_from(f.index))
+                .collect::<Result<Vec<u8>, _>>()?;
+            let geometry = IsoGeometry {
+                sector_ids: Some(sector_ids),
+                ..preset.map_or_else(
+                    || IsoGeometry::new(sectors.len()),
+                    |f| f.geometry(sectors.len()),
+                )
+            };
+            generate_iso_track(cylinder, head, &geometry, &mut sectors_in)?
+        }
+        _ => bail!(
+            "Tracks of format {} can't be encoded again",
+            parser.format_name()
+        ),
+    };
+
+    let cell_size = match parser.track_density() {
+        Density::SingleDouble => 168,
+        Density::High => 84,
+    };
+    let densitymap = vec![DensityMapEntry {
+        number_of_cellbytes: trackbuf.len(),
+        cell_size: PulseDuration(cell_size),
+    }];
+    Ok(RawTrack::new(
+        cylinder,
+        head,
+        trackbuf,
+        densitymap,
+        Encoding::MFM,
+    ))
+}
+
+/// A parser which was able to decode the track during format discovery
+struct FormatCandidate {
+    parser: DynTrackParser,
+    /// Number of sectors found on the track
+    sectors: usize,
+    /// The number of sectors matches the expectation of the parser
+    complete: bool,
+    /// Another track was decoded as well. None if this wasn't checked.
+    confirmed: Option<bool>,
+}
+
+/// Orders the candidates from the most to the least plausible one.
+/// A confirmation on another track is the strongest evidence, followed by
+/// a complete track and the number of sectors. Without any difference,
+/// the last parser of `all_track_parsers` is preferred.
+fn rank_format_candidates(candidates: &mut [FormatCandidate]) {
+    candidates.reverse();
+    candidates.sort_by_key(|f| Reverse((f.confirmed == Some(true), f.complete, f.sectors)));
+}
+
+fn discover_format_on_track(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    cylinder: u32,
+    head: u32,
+) -> anyhow::Result<(Option<DynTrackParser>, PossibleFormats)> {
+    // We need to make sure to read more than we need.
+    // We only have one chance here. So just get more than a rotation with the slowest drive we support.
+    let duration_to_record = duration_of_rotation_as_stm_tim_raw(DRIVE_SLOWEST_RPM)
+        * DISCOVER_MARGIN_PERCENT.load(Ordering::Relaxed) as usize
+        / 100;
+
+    let track_parsers = all_track_parsers();
+
+    let raw_data = read_raw_track(usb_handles, cylinder, head, false, duration_to_record)?;
+
+    let mut candidates = Vec::new();
+
+    for mut parser in track_parsers {
+        parser.expect_track(cylinder, head);
+
+        log::debug!("Trying format {}", parser.format_name());
+
+        let possible_track = parser.parse_raw_track(&raw_data);
+        match possible_track {
+            Ok(track) => {
+                let sectors = track.sectors.len();
+                candidates.push(FormatCandidate {
+                    complete: parser.expected_sectors() == Some(sectors),
+                    sectors,
+                    parser,
+                    confirmed: None,
+                });
+            }
+            Err(x) => log::debug!("Parsing aborted: {}", x),
+        };
+    }
+
+    // The track might be decodable by multiple formats.
+    // Check which of them is able to decode the next track as well.
+    if candidates.len() > 1 {
+        // A failed read is stored as None to avoid retrying it for every candidate.
+        let mut next_tracks: BTreeMap<u32, Option<Vec<u8>>> = BTreeMap::new();
+
+        for candidate in &mut candidates {
+            let next_cylinder = cylinder + candidate.parser.step_size() as u32;
+            let raw_data = match next_tracks.entry(next_cylinder) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    read_raw_track(usb_handles, next_cylinder, head, false, duration_to_record)
+                        .map_err(|err| {
+                            log::warn!("Unable to read track {next_cylinder} {head}: {err:#}")
+                        })
+                        .ok(),
+                ),
+            };
+
+            // Without the other track, the candidate stays unconfirmed.
+            let Some(raw_data) = raw_data else {
+                continue;
+            };
+
+            candidate.parser.expect_track(next_cylinder, head);
+            candidate.confirmed = Some(candidate.parser.parse_raw_track(raw_data).is_ok());
+        }
+
+        rank_format_candidates(&mut candidates);
+        log::warn!(
+            "Multiple possible formats. Ranked as {:?}",
+            candidates
+                .iter()
+                .map(|f| f.parser.format_name())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    let possible_formats = candidates
+        .iter()
+        .map(|f| f.parser.format_name().into())
+        .collect();
+    let mut possible_track_parser = candidates.into_iter().next().map(|f| f.parser);
+    if let Some(parser) = possible_track_parser.as_mut() {
+        parser.expect_track(cylinder, head);
+    }
+
+    Ok((possible_track_parser, possible_formats))
+}
+
+/// Reads the first track of the inserted disk and compares the density of the
+/// data found there against the density about to be written.
+/// Returns a warning if they don't match. An unformatted disk will not cause a warning.
+pub fn check_media_density(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    intended_density: Density,
+) -> anyhow::Result<Option<String>> {
+    let (possible_track_parser, possible_formats) =
+        read_first_track_discover_format(usb_handles, select_drive, index_sim_frequency)?;
+
+    let Some(track_parser) = possible_track_parser else {
+        return Ok(None);
+    };
+
+    Ok(density_mismatch_warning(
+        &possible_formats,
+        track_parser.track_density(),
+        intended_density,
+    ))
+}
+
+fn density_mismatch_warning(
+    possible_formats: &[String],
+    media_density: Density,
+    intended_density: Density,
+) -> Option<String> {
+    match (media_density, intended_density) {
+        (Density::High, Density::SingleDouble) | (Density::SingleDouble, Density::High) => Some(format!(
+            "Warning: Disk contains {:?} which is {:?} density but {:?} density shall be written. Verification might fail!",
+            possible_formats, media_density, intended_density
+        )),
+        _ => None,
+    }
+}
+
+/// Some tracks are only decodable either with or without index alignment.
+/// Alternate between both to increase the chance of success.
+/// The positions of the sectors are only meaningful from the index.
+fn aligned_to_index(attempt: usize, positions_needed: bool) -> bool {
+    positions_needed || attempt % 2 == 1
+}
+
+/// Scores below this indicate a dirty disk or drive
+const LOW_STABILITY_SCORE: u32 = 50;
+
+/// Effort which was necessary to read a track
+pub struct ReadStability {
+    /// Number of reads until the track was decoded
+    pub reads: usize,
+    /// Number of revolutions which were decoded successfully
+    pub decoded_revolutions: usize,
+    /// Number of revolutions which were recorded per read
+    pub revolutions: usize,
+    /// True if all decoded revolutions or combined reads provided the same data
+    pub consistent: bool,
+}
+
+impl ReadStability {
+    /// Score between 0 and 100. A track which was decoded in the first read
+    /// with every revolution providing the same data gets 100.
+    #[must_use]
+    pub fn score(&self) -> u32 {
+        let reads = 1.0 / self.reads.max(1) as f64;
+        let revolutions = self.decoded_revolutions as f64 / self.revolutions.max(1) as f64;
+        let consistency = if self.consistent { 1.0 } else { 0.5 };
+        (100.0 * reads * revolutions * consistency).round() as u32
+    }
+}
+
+/// Options for reading a disk to an image
+pub struct ReadOptions {
+    /// Number of revolutions to record and decode independently
+    pub revolutions: usize,
+    /// Number of reads to combine if a track can't be read in one piece. 0 disables this.
+    pub majority_reads: usize,
+    /// Sectors with a wrong checksum in every read of `majority_reads` are combined
+    /// by taking the majority of every byte
+    pub vote_raw_bytes: bool,
+    /// Transfer the flux timings in full resolution instead of reducing them
+    /// by `PULSE_REDUCE_SHIFT`. This is more precise but doubles the amount of
+    /// data transferred via USB which might be too much for high density disks.
+    pub high_resolution: bool,
+    /// Fill tracks which are found to be unformatted in every attempt with zeros
+    /// instead of aborting. Useful for partially formatted disks.
+    pub allow_blank_tracks: bool,
+    /// Suppress the diagnostic output of every track. Errors are still reported.
+    pub quiet: bool,
+    /// Write every sector to its own file in this directory instead of creating an image
+    pub split_sectors: Option<PathBuf>,
+    /// Describe the detected geometry in a text file next to the image
+    pub write_geometry: bool,
+    /// Store the flux timings of every read attempt in this directory
+    /// to allow the development of track parsers without hardware
+    pub save_raw: Option<PathBuf>,
+    /// Store the physical order of the sectors next to the image to write it again
+    /// with the same interleave. Every read waits for the index to know where the track begins.
+    pub preserve_interleave: bool,
+    /// List the files of this filesystem which touch sectors that were not recovered
+    pub affected_files: Option<FileSystem>,
+    /// Additionally store every track with the metadata of the read in this container
+    pub rich_output: Option<PathBuf>,
+    /// Store the cell size of every 16 data bytes of every sector next to the image.
+    /// The values use the unit of the timing records of STX images.
+    pub capture_timing: bool,
+    /// Additionally store the last read of every track as STX image at this path.
+    /// Every read waits for the index as STX stores the positions of the sectors.
+    pub stx_output: Option<PathBuf>,
+    /// The disk is inserted flipped to read its other side with the top head.
+    /// Requires the index simulation as the index hole is missing on this side.
+    pub flippy_side: bool,
+    /// Fill tracks which can't be read with zeros and continue with the next track
+    /// instead of aborting the whole read
+    pub skip_bad_tracks: bool,
+    /// Abort skipping after this many unreadable tracks in a row
+    /// as this hints to a problem of the drive instead of the disk
+    pub max_consecutive_failures: Option<usize>,
+    /// Decode with the parser of this format instead of deriving it from the
+    /// file extension or detecting it
+    pub format: Option<String>,
+    /// Keep sectors with a wrong checksum of the data instead of dropping them.
+    /// Used for protections with intentionally broken sectors or for recovery.
+    pub accept_bad_crc: bool,
+    /// Additionally write a hex dump of the decoded data of every track to this text file
+    pub read_dump: Option<PathBuf>,
+    /// Type of the connected drive. Sizes the recordings for its rotation and
+    /// replaces the guess of the disk type of formats which exist for both.
+    pub drive_type: Option<DiskType>,
+    /// Additionally store the tracks of a C64 disk as G64 image at this path.
+    /// Killer tracks are kept with the cells of their last read.
+    pub g64_output: Option<PathBuf>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            revolutions: 1,
+            majority_reads: 0,
+            vote_raw_bytes: false,
+            high_resolution: false,
+            allow_blank_tracks: false,
+            quiet: false,
+            split_sectors: None,
+            write_geometry: false,
+            save_raw: None,
+            preserve_interleave: false,
+            affected_files: None,
+            rich_output: None,
+            capture_timing: false,
+            stx_output: None,
+            flippy_side: false,
+            skip_bad_tracks: false,
+            max_consecutive_failures: None,
+            format: None,
+            accept_bad_crc: false,
+            read_dump: None,
+            drive_type: None,
+            g64_output: None,
+        }
+    }
+}
+
+/// Describes how the disk was interpreted during reading.
+/// Every track is given as cylinder, head, number of sectors and encoding.
+fn geometry_description(
+    track_parser: &dyn TrackParser,
+    tracks: &[(u32, u32, usize, &str)],
+) -> String {
+    let disk_type = match track_parser.disk_type() {
+        Some(DiskType::Inch3_5) => "3.5\"",
+        Some(DiskType::Inch5_25) => "5.25\"",
+        None => "Unknown",
+    };
+    let cylinders: BTreeSet<u32> = tracks.iter().map(|f| f.0).collect();
+    let heads: BTreeSet<u32> = tracks.iter().map(|f| f.1).collect();
+    let sectors: BTreeSet<usize> = tracks.iter().map(|f| f.2).collect();
+    let encodings: BTreeSet<&str> = tracks.iter().map(|f| f.3).collect();
+
+    let mut lines = vec![
+        format!("Format: {}", track_parser.format_name()),
+        format!("Density: {:?}", track_parser.track_density()),
+        format!("Disk type: {disk_type}"),
+        format!(
+            "Cylinders: {} from {} to {}",
+            cylinders.len(),
+            cylinders.first().unwrap_or(&0),
+            cylinders.last().unwrap_or(&0)
+        ),
+        format!("Heads: {heads:?}"),
+    ];
+
+    if let [sectors_per_track] = sectors.iter().collect::<Vec<_>>().as_slice() {
+        lines.push(format!("Sectors per track: {sectors_per_track}"));
+    } else {
+        lines.push("Sectors per track:".into());
+        for (cylinder, head, sectors, _) in tracks {
+            lines.push(format!("  Cylinder {cylinder} head {head}: {sectors}"));
+        }
+    }
+
+    if let [encoding] = encodings.iter().collect::<Vec<_>>().as_slice() {
+        lines.push(format!("Encoding: {encoding}"));
+    } else {
+        lines.push("Encoding:".into());
+        for (cylinder, head, _, encoding) in tracks {
+            lines.push(format!("  Cylinder {cylinder} head {head}: {encoding}"));
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Hex dump of the decoded data of a track headed by the warnings of the read
+fn track_dump(track: &TrackPayload, encoding: &str, warnings: &[String]) -> String {
+    let cfg = HexConfig {
+        title: false,
+        ..HexConfig::default()
+    };
+    let mut dump = format!(
+        "Cylinder {} Head {} Encoding {encoding} Sectors {}\n",
+        track.cylinder,
+        track.head,
+        track.sectors.len()
+    );
+    for warning in warnings {
+        dump.push_str(&format!("Warning: {warning}\n"));
+    }
+    dump.push_str(&format!("{:?}\n\n", track.payload.hex_conf(cfg)));
+    dump
+}
+
+/// Lists the files of the image which touch one of the unrecovered byte ranges
+/// Best effort as the image is already written. A damaged file system is only reported.
+fn report_affected_files(file_system: FileSystem, filepath: &str, unrecovered: &[Range<usize>]) {
+    if unrecovered.is_empty() {
+        println!("Every sector was recovered. No file is affected.");
+        return;
+    }
+
+    let bytes: usize = unrecovered.iter().map(ExactSizeIterator::len).sum();
+    let affected = fs::read(filepath)
+        .map_err(anyhow::Error::from)
+        .and_then(|image| file_system.affected_files(&image, unrecovered));
+
+    match affected {
+        Ok(affected) if affected.is_empty() => {
+            println!("{bytes} bytes were not recovered but no file is affected.");
+        }
+        Ok(affected) => {
+            println!("{bytes} bytes were not recovered. Affected are:");
+            for name in affected {
+                println!("  {name}");
+            }
+        }
+        Err(error) => {
+            println!("{bytes} bytes were not recovered. Affected files are unknown: {error:#}");
+        }
+    }
+}
+
+/// The flipped side has no index hole and the sectors are not aligned to the simulated one
+fn ensure_flippy_side_readable(
+    options: &ReadOptions,
+    index_sim_frequency: u32,
+) -> anyhow::Result<()> {
+    ensure!(
+        !options.flippy_side || index_sim_frequency != 0,
+        "Reading the flipped side of a disk requires the index simulation"
+    );
+    // The position of the simulated index is not related to the data
+    ensure!(
+        !options.flippy_side || (!options.preserve_interleave && options.stx_output.is_none()),
+        "The positions of the sectors are unknown on the flipped side of a disk"
+    );
+    Ok(())
+}
+
+/// An explicitly selected format takes precedence over the file extension
+fn track_parser_for_image(format: Option<&str>, filepath: &str) -> anyhow::Result<DynTrackParser> {
+    if let Some(format) = format {
+        return track_parser_for_extension(format)
+            .with_context(|| format!("{format} is an unknown format!"));
+    }
+
+    let file_extension = Path::new(filepath)
+        .extension()
+        .and_then(OsStr::to_str)
+        .context("No file extension!")?;
+
+    track_parser_for_extension(file_extension)
+        .with_context(|| format!("{file_extension} is an unknown file extension!"))
+}
+
+/// Counts an unreadable track which is skipped. Too many in a row hint to a problem
+/// of the drive instead of the disk and abort the read.
+fn count_unreadable_track(
+    consecutive_failures: &mut usize,
+    max_consecutive_failures: Option<usize>,
+) -> anyhow::Result<()> {
+    *consecutive_failures += 1;
+    if let Some(max_consecutive_failures) = max_consecutive_failures {
+        ensure!(
+            *consecutive_failures < max_consecutive_failures,
+            "{consecutive_failures} tracks in a row were unreadable. Aborting as the drive might be the problem."
+        );
+    }
+    Ok(())
+}
+
+/// The bottom head would see the flipped side rotating in reverse. The flipped side is
+/// read with the top head and was written from its own image, so it is stored as head 0.
+fn heads_to_read(head: Option<u32>, flippy_side: bool) -> anyhow::Result<Range<u32>> {
+    Ok(match head {
+        Some(1) if flippy_side => {
+            bail!("The flipped side of a disk can only be read with head 0")
+        }
+        _ if flippy_side => 0..1,
+        Some(0) => 0..1,
+        Some(1) => 1..2,
+        None => 0..2,
+        _ => bail!(program_flow_error!()),
+    })
+}
+
+pub fn read_tracks_to_diskimage(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    track_filter: Option<TrackFilter>,
+    filepath: &str,
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    options: &ReadOptions,
+) -> anyhow::Result<()> {
+    let revolutions = options.revolutions;
+    let majority_reads = options.majority_reads;
+    ensure!(revolutions >= 1, "At least one revolution must be recorded");
+
+    let (mut track_parser, filepath) = if options.format.is_none() && filepath == "justread" {
+        let (possible_track_parser, possible_formats) =
+            read_first_track_discover_format(usb_handles, select_drive, index_sim_frequency)?;
+
+        let track_parser = possible_track_parser.context("Unable to detect floppy format!")?;
+        println!("Format is probably '{:?}'", possible_formats);
+
+        let now = Local::now();
+        let time_str = now.format("%Y%m%d_%H%M%S");
+        let filepath = format!("{}.{}", time_str, track_parser.default_file_extension());
+
+        println!("Resulting image will be {filepath}");
+
+        (track_parser, filepath)
+    } else {
+        (
+            track_parser_for_image(options.format.as_deref(), filepath)?,
+            filepath.into(),
+        )
+    };
+    let track_filter =
+        track_filter.unwrap_or_else(|| configured_trackfilter(track_parser.as_ref()));
+    ensure!(
+        options.affected_files.is_none() || options.split_sectors.is_none(),
+        "Affected files can only be determined for an image"
+    );
+    ensure!(
+        !options.preserve_interleave
+            || matches!(track_parser.default_file_extension(), "st" | "img"),
+        "The interleave can only be preserved for ISO disks"
+    );
+    ensure!(
+        !options.capture_timing || matches!(track_parser.default_file_extension(), "st" | "img"),
+        "The timing can only be captured for ISO disks"
+    );
+    track_parser.set_measure_timing(options.capture_timing);
+    ensure!(
+        !options.accept_bad_crc || matches!(track_parser.default_file_extension(), "st" | "img"),
+        "Sectors with a wrong checksum can only be kept for ISO disks"
+    );
+    track_parser.set_accept_bad_crc(options.accept_bad_crc);
+    ensure!(
+        !options.vote_raw_bytes || matches!(track_parser.default_file_extension(), "st" | "img"),
+        "Bytes of sectors can only be voted for ISO disks"
+    );
+    if let Some(drive_type) = options.drive_type {
+        apply_drive_type(track_parser.as_mut(), drive_type);
+    }
+    ensure!(
+        options.stx_output.is_none() || track_parser.default_file_extension() == "st",
+        "STX images can only be created of double density ISO disks"
+    );
+    ensure!(
+        options.g64_output.is_none() || track_parser.default_file_extension() == "d64",
+        "G64 images can only be created of C64 disks"
+    );
+    ensure_flippy_side_readable(options, index_sim_frequency)?;
+    ensure!(
+        options.max_consecutive_failures.is_none() || options.skip_bad_tracks,
+        "The number of consecutive failures is only limited when skipping bad tracks"
+    );
+    ensure!(
+        options.max_consecutive_failures != Some(0),
+        "At least one failure must be allowed"
+    );
+    // Tracks which can't be decoded are tried again with the other encoding of the format
+    let mut alternative_parser = track_parser.alternative_encoding();
+
+    // Every additional revolution is appended to the recording of the first one
+    let duration_to_record = track_parser.duration_to_record()
+        + duration_of_rotation_as_stm_tim_raw(rotation_rpm(track_parser.disk_type()))
+            * (revolutions - 1);
+    configure_device(
+        usb_handles,
+        select_drive,
+        track_parser.track_density(),
+        index_sim_frequency,
+    )?;
+
+    let mut cylinder_begin = track_filter.cyl_start.unwrap_or(0);
+    let mut cylinder_end = track_filter
+        .cyl_end
+        .context("Please specify the last cylinder to read!")?;
+    // Fail before the first track is read
+    ensure_cylinder_allowed(cylinder_end)?;
+
+    if cylinder_begin == cylinder_end {
+        cylinder_begin = 0;
+    } else {
+        cylinder_end += 1;
+    }
+
+    let heads = heads_to_read(track_filter.head, options.flippy_side)?;
+
+    if !options.quiet {
+        println!("Reading cylinders {cylinder_begin} to {cylinder_end}");
+    }
+    let mut outfile = match &options.split_sectors {
+        Some(directory) => {
+            fs::create_dir_all(directory)?;
+            None
+        }
+        None => Some(File::create(&filepath)?),
+    };
+    if let Some(directory) = &options.save_raw {
+        fs::create_dir_all(directory)?;
+    }
+    let mut last_track_size: Option<usize> = None;
+    let mut last_sector_size: Option<usize> = None;
+    let mut tracks_read = 0;
+    let mut lowest_stability: Option<(u32, u32, u32)> = None;
+    let mut geometry = Vec::new();
+    let mut sector_order = SectorOrder::default();
+    // One line per sector with cylinder, head, sector and the timing values
+    let mut timing = String::new();
+    let mut stx_records = Vec::new();
+    let mut g64_tracks = Vec::new();
+    let mut read_dump = String::new();
+    let mut rich_image = match &options.rich_output {
+        Some(path) => Some(RichImageWriter::create(
+            path,
+            track_parser.default_file_extension(),
+        )?),
+        None => None,
+    };
+    // Byte ranges of the image which were filled instead of read
+    let mut unrecovered: Vec<Range<usize>> = Vec::new();
+    let mut image_size = 0;
+    let mut skipped_tracks = Vec::new();
+    let mut consecutive_failures = 0;
+
+    for cylinder in (cylinder_begin..cylinder_end).step_by(track_parser.step_size()) {
+        for head in heads.clone() {
+            let mut possible_track: Option<TrackPayload> = None;
+            let mut stability: Option<ReadStability> = None;
+            let mut blank_attempts = 0;
+            let mut killer_attempts = 0;
+            // Last recording of a killer track as it can't be reconstructed from sectors
+            let mut killer_track = None;
+            let mut track_encoding = track_parser.track_encoding();
+            // Collected for the rich output
+            let mut warnings = Vec::new();
+            let mut stx_record = None;
+
+            for attempt in 0..READ_ATTEMPTS {
+                let wait_for_index = aligned_to_index(
+                    attempt,
+                    options.preserve_interleave || options.stx_output.is_some(),
+                );
+                check_operation_deadline(usb_handles)?;
+                let raw_data = read_flux_timings(
+                    usb_handles,
+                    cylinder,
+                    head,
+                    wait_for_index,
+                    duration_to_record,
+                    options.high_resolution,
+                )?;
+                if let Some(directory) = &options.save_raw {
+                    let capture = RawCapture {
+                        cylinder,
+                        head,
+                        density: track_parser.track_density(),
+                        flux_timings: raw_data.clone(),
+                    };
+                    capture.save(directory, attempt)?;
+                }
+                // The last read is stored even if it wasn't decodable to preserve the defects
+                if options.stx_output.is_some() {
+                    stx_record = Some(stx_track_record(cylinder, head, &raw_data)?);
+                }
+                track_encoding = track_parser.track_encoding();
+                let mut result = parse_revolutions(
+                    track_parser.as_mut(),
+                    &raw_data,
+                    cylinder,
+                    head,
+                    revolutions,
+                );
+                if let Err(primary_error) = &result
+                    && let Some(alternative_parser) = alternative_parser.as_mut()
+                {
+                    let alternative_result = parse_revolutions(
+                        alternative_parser.as_mut(),
+                        &raw_data,
+                        cylinder,
+                        head,
+                        revolutions,
+                    );
+                    // The encoding only changes if the track was decoded with it
+                    if alternative_result.is_ok() {
+                        result = alternative_result;
+                        track_encoding = alternative_parser.track_encoding();
+                    } else if is_blank_track_error(primary_error) {
+                        // The track is only blank if it is for both encodings
+                        result = alternative_result;
+                    }
+                }
+
+                if matches!(&result, Err(x) if is_blank_track_error(x)) {
+                    blank_attempts += 1;
+                }
+
+                if matches!(&result, Err(x) if is_killer_track_error(x)) {
+                    killer_attempts += 1;
+                    killer_track = Some(raw_data);
+                }
+
+                if let Ok(result) = result {
+                    if !result.revolutions_agree {
+                        warnings.push("Decoded revolutions differ".to_string());
+                    }
+                    if attempt > 0 {
+                        warnings.push(format!(
+                            "Decoded in attempt {} {} index alignment",
+                            attempt + 1,
+                            if wait_for_index { "with" } else { "without" }
+                        ));
+                    }
+                    if track_encoding != track_parser.track_encoding() {
+                        warnings.push(format!("Track is {track_encoding} encoded"));
+                    }
+                    if revolutions > 1 && !options.quiet {
+                        println!(
+                            "Track {cylinder} {head} decoded from revolution {}. {} of {} revolutions decoded. {}",
+                            result.revolution,
+                            result.decoded_revolutions,
+                            revolutions,
+                            if result.revolutions_agree {
+                                "All agree."
+                            } else {
+                                "They DIFFER!"
+                            }
+                        );
+                    }
+                    if attempt > 0 && !options.quiet {
+                        println!(
+                            "Track {cylinder} {head} decoded in attempt {} {} index alignment",
+                            attempt + 1,
+                            if wait_for_index { "with" } else { "without" }
+                        );
+                    }
+                    if track_encoding != track_parser.track_encoding() && !options.quiet {
+                        println!("Track {cylinder} {head} is {track_encoding} encoded");
+                    }
+                    stability = Some(ReadStability {
+                        reads: attempt + 1,
+                        decoded_revolutions: result.decoded_revolutions,
+                        revolutions,
+                        consistent: result.revolutions_agree,
+                    });
+                    possible_track = Some(result.track);
+                    break;
+                }
+
+                if !options.quiet {
+                    println!("Reading of track {cylinder} {head} not successful. Try again...");
+                }
+            }
+
+            // A track is only considered blank if not a single attempt found data on it
+            if possible_track.is_none()
+                && options.allow_blank_tracks
+                && blank_attempts == READ_ATTEMPTS
+            {
+                let track_size = last_track_size
+                    .context("Unable to know the size of a blank track before any other track")?;
+                if !options.quiet {
+                    println!("Track {cylinder} {head} is blank. Filled with zeros.");
+                }
+                warnings.push("Blank track filled with zeros".to_string());
+                possible_track = Some(TrackPayload::filled_with_zeros(
+                    cylinder, head, track_size,
+                ));
+                unrecovered.push(image_size..image_size + track_size);
+            }
+
+            // Killer tracks are part of a protection and not a defect. Reading continues.
+            if possible_track.is_none() && killer_attempts == READ_ATTEMPTS {
+                let track_size = track_parser
+                    .expected_sectors()
+                    .zip(last_sector_size.or(track_parser.sector_size()))
+                    .map(|(sectors, sector_size)| sectors * sector_size)
+                    .context("Unable to know the size of a killer track before any other track")?;
+                if !options.quiet {
+                    println!(
+                        "Track {cylinder} {head} has no sync mark. Probably a killer track. Filled with zeros."
+                    );
+                }
+                warnings.push("Killer track filled with zeros".to_string());
+                possible_track = Some(TrackPayload::filled_with_zeros(
+                    cylinder, head, track_size,
+                ));
+                unrecovered.push(image_size..image_size + track_size);
+            } else {
+                killer_track = None;
+            }
+
+            if possible_track.is_none() && majority_reads > 0 {
+                if !options.quiet {
+                    println!(
+                        "Combine sectors of {majority_reads} reads of track {cylinder} {head}..."
+                    );
+                }
+                // Damaged sectors must be kept to vote on their bytes
+                track_parser.set_accept_bad_crc(options.accept_bad_crc || options.vote_raw_bytes);
+                let result = read_track_majority(
+                    usb_handles,
+                    track_parser.as_mut(),
+                    cylinder,
+                    head,
+                    majority_reads,
+                    options.high_resolution,
+                );
+                track_parser.set_accept_bad_crc(options.accept_bad_crc);
+                let result = result?;
+                if !options.quiet {
+                    println!(
+                        "Track {cylinder} {head} combined from {} reads. {} sectors recovered, {} sectors disagreed, {} sectors voted byte by byte.",
+                        result.reads,
+                        result.recovered_sectors,
+                        result.disagreeing_sectors,
+                        result.voted_sectors
+                    );
+                }
+                warnings.push(format!(
+                    "Combined from {} reads with {} disagreeing sectors",
+                    result.reads, result.disagreeing_sectors
+                ));
+                stability = Some(ReadStability {
+                    reads: READ_ATTEMPTS + result.reads,
+                    decoded_revolutions: 1,
+                    revolutions: 1,
+                    consistent: result.disagreeing_sectors == 0,
+                });
+                possible_track = Some(result.track);
+            }
+
+            if possible_track.is_none() && options.skip_bad_tracks {
+                count_unreadable_track(
+                    &mut consecutive_failures,
+                    options.max_consecutive_failures,
+                )?;
+
+                let track_size = last_track_size
+                    .context("Unable to know the size of a bad track before any other track")?;
+                println!("Track {cylinder} {head} is unreadable. Skipped and filled with zeros.");
+                warnings.push("Unreadable track filled with zeros".to_string());
+                possible_track = Some(TrackPayload::filled_with_zeros(
+                    cylinder, head, track_size,
+                ));
+                unrecovered.push(image_size..image_size + track_size);
+                skipped_tracks.push((cylinder, head));
+            } else {
+                consecutive_failures = 0;
+            }
+
+            let track =
+                possible_track.context(format!("Unable to read track {} {}", cylinder, head))?;
+
+            ensure!(cylinder == track.cylinder);
+            ensure!(head == track.head);
+
+            // Blank tracks have no meaningful score
+            if let Some(stability) = &stability {
+                let score = stability.score();
+                if score < 100 && !options.quiet {
+                    println!("Track {cylinder} {head} has a read stability score of {score}");
+                }
+                if lowest_stability.is_none_or(|(lowest, _, _)| score < lowest) {
+                    lowest_stability = Some((score, cylinder, head));
+                }
+            }
+
+            for sector in &track.sectors {
+                if sector.variable_bit_width {
+                    if !options.quiet {
+                        println!(
+                            "Sector {} of track {cylinder} {head} has a variable bit width. Probably protected by Macrodos / Speedlock.",
+                            sector.index
+                        );
+                    }
+                    warnings.push(format!("Sector {} has a variable bit width", sector.index));
+                }
+
+                if sector.bad_crc {
+                    if !options.quiet {
+                        println!(
+                            "Sector {} of track {cylinder} {head} was kept despite a wrong checksum",
+                            sector.index
+                        );
+                    }
+                    warnings.push(format!("Sector {} has a bad CRC", sector.index));
+                }
+
+                if options.capture_timing
+                    && let Some(profile) = &sector.bit_width_profile
+                {
+                    match sector_timing_values(profile, sector.payload.len()) {
+                        Ok(values) => {
+                            let values: Vec<String> = values.iter().map(u16::to_string).collect();
+                            timing.push_str(&format!(
+                                "{cylinder} {head} {} {}\n",
+                                sector.index,
+                                values.join(" ")
+                            ));
+                        }
+                        Err(err) => {
+                            println!(
+                                "Timing of sector {} of track {cylinder} {head} is not stored: {err}",
+                                sector.index
+                            );
+                            warnings.push(format!("Sector {} has no valid timing", sector.index));
+                        }
+                    }
+                }
+            }
+
+            if options.read_dump.is_some() {
+                read_dump.push_str(&track_dump(&track, track_encoding, &warnings));
+            }
+
+            if let Some(rich_image) = &mut rich_image {
+                let record = TrackRecord {
+                    cylinder,
+                    head,
+                    encoding: track_encoding.to_string(),
+                    sectors: track.sectors.iter().map(|f| f.index).collect(),
+                    retries: stability
+                        .as_ref()
+                        .map_or(READ_ATTEMPTS, |f| f.reads)
+                        .saturating_sub(1),
+                    stability_score: stability.as_ref().map(ReadStability::score),
+                    warnings,
+                    bad_crc_sectors: track
+                        .sectors
+                        .iter()
+                        .filter(|f| f.bad_crc)
+                        .map(|f| f.index)
+                        .collect(),
+                };
+                rich_image.add_track(record, &track.payload)?;
+            }
+
+            if let Some(stx_record) = stx_record {
+                stx_records.push(stx_record);
+            }
+
+            if options.g64_output.is_some() {
+                g64_tracks.push(g64_track(&track, killer_track.as_deref())?);
+            }
+
+            if options.preserve_interleave && !sector_order.insert(&track) {
+                println!(
+                    "Sector order of track {cylinder} {head} is unknown. It will be written with the usual interleave."
+                );
+            }
+
+            match (&mut outfile, &options.split_sectors) {
+                (Some(outfile), _) => outfile.write_all(&track.payload)?,
+                (None, Some(directory)) => write_sector_files(directory, &track)?,
+                (None, None) => bail!(program_flow_error!()),
+            }
+            last_track_size = Some(track.payload.len());
+            if let Some((_, data)) = track.sector_payloads().next() {
+                last_sector_size = Some(data.len());
+            }
+            image_size += track.payload.len();
+            geometry.push((cylinder, head, track.sectors.len(), track_encoding));
+            tracks_read += 1;
+        }
+    }
+
+    match &options.split_sectors {
+        Some(directory) => println!("{tracks_read} tracks read to {}", directory.display()),
+        None => println!("{tracks_read} tracks read to {filepath}"),
+    }
+
+    if !skipped_tracks.is_empty() {
+        println!("{} unreadable tracks were skipped:", skipped_tracks.len());
+        for (cylinder, head) in &skipped_tracks {
+            println!("  cylinder {cylinder} head {head}");
+        }
+    }
+
+    if options.write_geometry {
+        let path = Path::new(&filepath).with_extension("geometry.txt");
+        let description = geometry_description(track_parser.as_ref(), &geometry);
+        fs::write(&path, description)?;
+        println!("Geometry written to {}", path.display());
+    }
+
+    if let Some(file_system) = options.affected_files {
+        report_affected_files(file_system, &filepath, &unrecovered);
+    }
+
+    if let (Some(rich_image), Some(path)) = (rich_image, &options.rich_output) {
+        rich_image.finish()?;
+        println!("Tracks and read metadata written to {}", path.display());
+    }
+
+    if options.preserve_interleave {
+        let path = SectorOrder::path_for_image(Path::new(&filepath));
+        sector_order.save(&path)?;
+        println!("Sector order written to {}", path.display());
+    }
+
+    if let Some(path) = &options.stx_output {
+        fs::write(path, stx_file_from_records(stx_records)?)?;
+        println!("STX image written to {}", path.display());
+    }
+
+    if let Some(path) = &options.g64_output {
+        let image = RawImage {
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch5_25,
+            tracks: g64_tracks,
+        };
+        fs::write(path, g64_image_to_bytes(&image)?)?;
+        println!("G64 image written to {}", path.display());
+    }
+
+    if let Some(path) = &options.read_dump {
+        fs::write(path, read_dump)?;
+        println!("Hex dump written to {}", path.display());
+    }
+
+    if options.capture_timing {
+        let path = Path::new(&filepath).with_extension("timing.txt");
+        fs::write(&path, timing)?;
+        println!("Sector timing written to {}", path.display());
+    }
+
+    if let Some((score, cylinder, head)) = lowest_stability {
+        println!("Lowest read stability score is {score} on track {cylinder} {head}");
+        if score < LOW_STABILITY_SCORE {
+            println!("The read was hardly stable. Consider cleaning the disk and the drive head.");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_track_parser_test() {
+        assert!(track_parser_for_extension("hfe").is_none());
+
+        register_track_parser(
+            "hfe",
+            Box::new(|| Box::new(IsoTrackParser::new(None, Density::High))),
+        );
+        let track_parser = track_parser_for_extension("hfe").unwrap();
+        assert!(matches!(track_parser.track_density(), Density::High));
+    }
+
+    #[test]
+    fn density_mismatch_warning_test() {
+        let formats = vec![String::from("ISO")];
+        assert_eq!(
+            density_mismatch_warning(&formats, Density::High, Density::SingleDouble).unwrap(),
+            "Warning: Disk contains [\"ISO\"] which is High density but SingleDouble density shall be written. Verification might fail!"
+        );
+        assert!(density_mismatch_warning(&formats, Density::SingleDouble, Density::High).is_some());
+        assert!(density_mismatch_warning(&formats, Density::High, Density::High).is_none());
+        assert!(
+            density_mismatch_warning(&formats, Density::SingleDouble, Density::SingleDouble)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn aligned_to_index_test() {
+        let alignments: Vec<bool> = (0..5).map(|f| aligned_to_index(f, false)).collect();
+        assert_eq!(alignments, [false, true, false, true, false]);
+        assert!((0..5).all(|f| aligned_to_index(f, true)));
+    }
+
+    #[test]
+    fn discover_settings_test() {
+        assert!(set_discover_margin(99).is_err());
+        assert!(set_discover_margin(401).is_err());
+        assert!(set_discover_margin(DEFAULT_DISCOVER_MARGIN_PERCENT).is_ok());
+
+        assert_eq!(discover_densities(), [Density::SingleDouble, Density::High]);
+        set_discover_density(Density::High);
+        assert_eq!(discover_densities(), [Density::High, Density::SingleDouble]);
+        set_discover_density(Density::SingleDouble);
+    }
+
+    #[test]
+    fn read_rpm_test() {
+        assert!(set_read_rpm(200.0).is_err());
+        assert!(set_read_rpm(f64::NAN).is_err());
+        // The slowest drive is assumed without a configured speed
+        assert_eq!(read_rpm(DRIVE_SLOWEST_RPM), DRIVE_SLOWEST_RPM);
+    }
+
+    #[test]
+    fn split_revolutions_test() {
+        let raw_data = vec![PulseDuration(800); 30];
+
+        let windows = split_revolutions(&raw_data, 3, 8000, 12000);
+        let lengths: Vec<usize> = windows.iter().map(|w| w.len()).collect();
+        // The last window is cut by the end of the recording
+        assert_eq!(lengths, vec![15, 15, 10]);
+
+        // A revolution without data is not provided
+        let windows = split_revolutions(&raw_data, 5, 12000, 12000);
+        let lengths: Vec<usize> = windows.iter().map(|w| w.len()).collect();
+        assert_eq!(lengths, vec![15, 15]);
+    }
+
+    #[test]
+    fn geometry_description_test() {
+        let track_parser = C64TrackParser::new();
+        let tracks = [(0, 0, 21, "GCR"), (2, 0, 21, "GCR"), (34, 0, 19, "GCR")];
+        assert_eq!(
+            geometry_description(&track_parser, &tracks),
+            "Format: C64 1541\nDensity: SingleDouble\nDisk type: 5.25\"\nCylinders: 3 from 0 to 34\nHeads: {0}\nSectors per track:\n  Cylinder 0 head 0: 21\n  Cylinder 2 head 0: 21\n  Cylinder 34 head 0: 19\nEncoding: GCR\n"
+        );
+
+        let track_parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        let tracks = [(0, 0, 9, "MFM"), (0, 1, 9, "MFM")];
+        assert!(geometry_description(&track_parser, &tracks)
+            .ends_with("Sectors per track: 9\nEncoding: MFM\n"));
+
+        // The first track is FM encoded
+        let tracks = [(0, 0, 16, "FM"), (1, 0, 9, "MFM")];
+        assert!(geometry_description(&track_parser, &tracks)
+            .ends_with("Encoding:\n  Cylinder 0 head 0: FM\n  Cylinder 1 head 0: MFM\n"));
+    }
+
+    #[test]
+    fn read_stability_test() {
+        let mut stability = ReadStability {
+            reads: 1,
+            decoded_revolutions: 3,
+            revolutions: 3,
+            consistent: true,
+        };
+        assert_eq!(stability.score(), 100);
+
+        stability.decoded_revolutions = 2;
+        assert_eq!(stability.score(), 67);
+
+        stability.reads = 2;
+        stability.consistent = false;
+        assert_eq!(stability.score(), 17);
+    }
+
+    #[test]
+    fn rank_format_candidates_test() {
+        let candidate = |density, sectors, complete, confirmed| FormatCandidate {
+            parser: Box::new(IsoTrackParser::new(None, density)),
+            sectors,
+            complete,
+            confirmed,
+        };
+        let ranked_densities = |mut candidates: Vec<FormatCandidate>| {
+            rank_format_candidates(&mut candidates);
+            candidates
+                .iter()
+                .map(|f| f.parser.track_density())
+                .collect::<Vec<_>>()
+        };
+
+        // Only the double density parser is able to decode the next track
+        assert_eq!(
+            ranked_densities(vec![
+                candidate(Density::SingleDouble, 9, true, Some(true)),
+                candidate(Density::High, 18, true, Some(false)),
+            ]),
+            vec![Density::SingleDouble, Density::High]
+        );
+
+        // Both decode the next track but only one finds every expected sector
+        assert_eq!(
+            ranked_densities(vec![
+                candidate(Density::High, 18, false, Some(true)),
+                candidate(Density::SingleDouble, 9, true, Some(true)),
+            ]),
+            vec![Density::SingleDouble, Density::High]
+        );
+
+        // Without any evidence, the last one is preferred
+        assert_eq!(
+            ranked_densities(vec![
+                candidate(Density::SingleDouble, 9, true, None),
+                candidate(Density::High, 9, true, None),
+            ]),
+            vec![Density::High, Density::SingleDouble]
+        );
+    }
+
+    #[test]
+    fn vote_sectors_test() {
+        let sector = |index: u32, value: u8| CollectedSector {
+            index,
+            payload: vec![value; 4],
+            bit_width_profile: None,
+            variable_bit_width: false,
+            position: None,
+            bad_crc: false,
+        };
+
+        let reads = vec![
+            vec![sector(0, 1), sector(2, 7)],
+            vec![sector(1, 2), sector(2, 8)],
+            vec![sector(0, 1), sector(2, 8)],
+        ];
+
+        let (sectors, disagreeing_sectors, voted_sectors) = vote_sectors(reads);
+        let result: Vec<(u32, Vec<u8>)> =
+            sectors.into_iter().map(|f| (f.index, f.payload)).collect();
+        assert_eq!(
+            result,
+            vec![(0, vec![1; 4]), (1, vec![2; 4]), (2, vec![8; 4])]
+        );
+        assert_eq!(disagreeing_sectors, 1);
+        assert_eq!(voted_sectors, 0);
+
+        // On a tie, the first read wins
+        let (sectors, _, _) = vote_sectors(vec![vec![sector(3, 5)], vec![sector(3, 6)]]);
+        let result: Vec<Vec<u8>> = sectors.into_iter().map(|f| f.payload).collect();
+        assert_eq!(result, vec![vec![5; 4]]);
+
+        // The timing is kept from the read which provided the payload
+        let timed_sector = |value: u8, cell_size: i32| CollectedSector {
+            bit_width_profile: Some(vec![PulseDuration(cell_size); 2]),
+            variable_bit_width: true,
+            ..sector(3, value)
+        };
+        let (sectors, _, _) = vote_sectors(vec![
+            vec![timed_sector(5, 160)],
+            vec![timed_sector(6, 170)],
+            vec![timed_sector(6, 175)],
+        ]);
+        let sector = sectors.first().unwrap();
+        assert_eq!(sector.payload, vec![6; 4]);
+        assert!(sector.variable_bit_width);
+        assert_eq!(
+            sector.bit_width_profile(),
+            Some(&[PulseDuration(170); 2][..])
+        );
+    }
+
+    #[test]
+    fn vote_bytes_test() {
+        let bad_sector = |payload: &[u8]| CollectedSector {
+            index: 4,
+            payload: payload.to_vec(),
+            bit_width_profile: None,
+            variable_bit_width: false,
+            position: None,
+            bad_crc: true,
+        };
+
+        // Every read has a different damaged byte
+        let reads = vec![
+            vec![bad_sector(&[9, 2, 3, 4])],
+            vec![bad_sector(&[1, 9, 3, 4])],
+            vec![bad_sector(&[1, 2, 9, 4])],
+        ];
+        let (sectors, disagreeing_sectors, voted_sectors) = vote_sectors(reads);
+        let sector = sectors.first().unwrap();
+        assert_eq!(sector.payload, vec![1, 2, 3, 4]);
+        assert!(sector.has_bad_crc());
+        assert_eq!((disagreeing_sectors, voted_sectors), (1, 1));
+
+        // A valid checksum is preferred over the vote
+        let mut good_sector = bad_sector(&[5, 5, 5, 5]);
+        good_sector.bad_crc = false;
+        let reads = vec![
+            vec![bad_sector(&[1, 2, 3, 4])],
+            vec![bad_sector(&[1, 2, 3, 4])],
+            vec![good_sector],
+        ];
+        let (sectors, _, voted_sectors) = vote_sectors(reads);
+        assert_eq!(sectors.first().unwrap().payload, vec![5; 4]);
+        assert_eq!(voted_sectors, 0);
+
+        // On a tie, the earlier copy wins
+        assert_eq!(vote_bytes(&[(&[1, 2], 1), (&[3, 2, 7], 1)]), vec![1, 2, 7]);
+    }
+
+    #[test]
+    fn write_sector_files_test() {
+        let sector = |index: u32, value: u8| CollectedSector {
+            index,
+            payload: vec![value; 4],
+            bit_width_profile: None,
+            variable_bit_width: false,
+            position: None,
+            bad_crc: false,
+        };
+        let track = concatenate_sectors(vec![sector(2, 7), sector(1, 3)], 5, 1);
+        assert_eq!(track.payload, vec![3, 3, 3, 3, 7, 7, 7, 7]);
+        // The data is only kept once in the payload
+        assert!(track.sectors.iter().all(|f| f.payload.is_empty()));
+
+        let directory = std::env::temp_dir().join("write_sector_files_test");
+        fs::create_dir_all(&directory).unwrap();
+        write_sector_files(&directory, &track).unwrap();
+        assert_eq!(
+            fs::read(directory.join("c05_h1_s1.bin")).unwrap(),
+            vec![3; 4]
+        );
+        assert_eq!(
+            fs::read(directory.join("c05_h1_s2.bin")).unwrap(),
+            vec![7; 4]
+        );
+        fs::remove_dir_all(&directory).unwrap();
+
+        let sectors: Vec<(u32, Vec<u8>)> = track
+            .into_sectors()
+            .into_iter()
+            .map(|f| (f.index, f.payload))
+            .collect();
+        assert_eq!(sectors, vec![(1, vec![3; 4]), (2, vec![7; 4])]);
+    }
+
+    #[test]
+    fn track_dump_test() {
+        let sector = CollectedSector {
+            index: 1,
+            payload: b"ABCD".to_vec(),
+            bit_width_profile: None,
+            variable_bit_width: false,
+            position: None,
+            bad_crc: true,
+        };
+        let track = concatenate_sectors(vec![sector], 3, 0);
+        let dump = track_dump(&track, "MFM", &["Sector 1 has a bad CRC".to_string()]);
+        let lines: Vec<&str> = dump.lines().collect();
+
+        assert_eq!(
+            lines.first(),
+            Some(&"Cylinder 3 Head 0 Encoding MFM Sectors 1")
+        );
+        assert_eq!(lines.get(1), Some(&"Warning: Sector 1 has a bad CRC"));
+        let hex = lines.get(2).unwrap();
+        assert!(hex.contains("41 42 43 44"), "{hex}");
+        assert!(hex.ends_with("ABCD"), "{hex}");
+    }
+
+    #[test]
+    fn blank_track_test() {
+        // Pulses of the same length never form a sync word
+        let raw_data = vec![PulseDuration(336); 20000];
+        let mut track_parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+
+        let result = parse_revolutions(&mut track_parser, &raw_data, 3, 0, 1);
+        assert!(is_blank_track_error(&result.err().unwrap()));
+    }
+
+    #[test]
+    fn decode_raw_track_test() {
+        let geometry = IsoGeometry::new(9);
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let mut sectors_in = sectors.chunks_exact(512);
+
+        let trackbuf = generate_iso_track(2, 1, &geometry, &mut sectors_in).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let track = RawTrack::new(2, 1, trackbuf, densitymap, Encoding::MFM);
+
+        let mut track_parsers: Vec<DynTrackParser> = vec![
+            Box::new(AmigaTrackParser::new(Density::SingleDouble)),
+            Box::new(IsoTrackParser::new(Some(9), Density::SingleDouble)),
+        ];
+
+        let payload = decode_raw_track(&track, &mut track_parsers).unwrap();
+        assert_eq!(payload.cylinder, 2);
+        assert_eq!(payload.head, 1);
+        assert_eq!(payload.payload, sectors);
+
+        // The sectors are still available after a successful parse
+        let mut collected_sectors = track_parsers.last_mut().unwrap().take_collected_sectors();
+        collected_sectors.sort_by_key(|f| f.index);
+        let indices: Vec<u32> = collected_sectors.iter().map(|f| f.index).collect();
+        assert_eq!(indices, (1..=9).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn find_sector_test() {
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf =
+            generate_iso_track(2, 1, &IsoGeometry::new(9), &mut sectors.chunks_exact(512)).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let track = RawTrack::new(2, 1, trackbuf, densitymap, Encoding::MFM);
+        let raw_data = expand_reduced_pulses(&track.simulate_read(2).unwrap());
+
+        let (format_name, payload) =
+            find_sector(&raw_data, Density::SingleDouble, 2, 1, 5).unwrap();
+        assert_eq!(format_name, "Double Density ISO - could be Atari ST");
+        assert_eq!(Some(payload.as_slice()), sectors.chunks_exact(512).nth(4));
+
+        // Neither a missing sector nor the wrong density provide data
+        assert!(find_sector(&raw_data, Density::SingleDouble, 2, 1, 10).is_none());
+        assert!(find_sector(&raw_data, Density::High, 2, 1, 5).is_none());
+    }
+
+    #[test]
+    fn flippy_side_test() {
+        assert_eq!(heads_to_read(None, false).unwrap(), 0..2);
+        assert_eq!(heads_to_read(Some(1), false).unwrap(), 1..2);
+        // Only the top head reads the flipped side
+        assert_eq!(heads_to_read(None, true).unwrap(), 0..1);
+        assert_eq!(heads_to_read(Some(0), true).unwrap(), 0..1);
+        assert!(heads_to_read(Some(1), true).is_err());
+
+        let mut options = ReadOptions {
+            flippy_side: true,
+            ..ReadOptions::default()
+        };
+        assert!(ensure_flippy_side_readable(&options, 5).is_ok());
+        assert!(ensure_flippy_side_readable(&options, 0).is_err());
+        options.preserve_interleave = true;
+        assert!(ensure_flippy_side_readable(&options, 5).is_err());
+    }
+
+    #[test]
+    fn count_unreadable_track_test() {
+        // The third unreadable track in a row aborts
+        let mut consecutive_failures = 0;
+        assert!(count_unreadable_track(&mut consecutive_failures, Some(3)).is_ok());
+        assert!(count_unreadable_track(&mut consecutive_failures, Some(3)).is_ok());
+        let error = count_unreadable_track(&mut consecutive_failures, Some(3)).unwrap_err();
+        assert!(error.to_string().starts_with("3 tracks in a row"));
+
+        // Without a limit every track is skipped
+        let mut consecutive_failures = 0;
+        for _ in 0..100 {
+            assert!(count_unreadable_track(&mut consecutive_failures, None).is_ok());
+        }
+        assert_eq!(consecutive_failures, 100);
+    }
+
+    #[test]
+    fn track_parser_for_image_test() {
+        let extension = |format, filepath| {
+            track_parser_for_image(format, filepath).map(|f| f.default_file_extension().to_string())
+        };
+        assert_eq!(extension(None, "disk.adf").unwrap(), "adf");
+        // The format overrides the extension
+        assert_eq!(extension(Some("st"), "disk.bin").unwrap(), "st");
+        assert_eq!(extension(Some("img"), "disk").unwrap(), "img");
+
+        assert!(extension(None, "disk").is_err());
+        assert!(extension(None, "disk.bin").is_err());
+        assert!(extension(Some("bin"), "disk.adf").is_err());
+    }
+
+    #[test]
+    fn patch_sector_test() {
+        let geometry = IsoGeometry::new(9);
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 251) as u8).collect();
+        let trackbuf = generate_iso_track(2, 1, &geometry, &mut sectors.chunks_exact(512)).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        let reduced = RawTrack::new(2, 1, trackbuf, densitymap, Encoding::MFM)
+            .simulate_read(2)
+            .unwrap();
+
+        let mut parser = IsoTrackParser::new(Some(9), Density::SingleDouble);
+        parser.expect_track(2, 1);
+        let payload = parser.parse_raw_track(&reduced).unwrap();
+        // The size of the sector must match
+        assert!(encode_patched_track(&parser, payload, 3, &[0x55; 256], 9, None).is_err());
+
+        // A partial track would lose the missing sector
+        parser.expect_track(2, 1);
+        let payload = parser.parse_raw_track(&reduced).unwrap();
+        assert!(encode_patched_track(&parser, payload, 3, &[0x55; 512], 10, None).is_err());
+
+        parser.expect_track(2, 1);
+        let payload = parser.parse_raw_track(&reduced).unwrap();
+        let track = encode_patched_track(&parser, payload, 3, &[0x55; 512], 9, None).unwrap();
+        assert_eq!(track.densitymap.first().unwrap().cell_size.0, 168);
+
+        let mut expected = sectors;
+        expected.get_mut(2 * 512..3 * 512).unwrap().fill(0x55);
+        parser.expect_track(2, 1);
+        let patched = parser
+            .parse_raw_track(&track.simulate_read(2).unwrap())
+            .unwrap();
+        assert_eq!(patched.payload, expected);
+    }
+
+    #[test]
+    fn estimate_cylinder_count_test() {
+        let probe = |cylinder, disk_cylinder: Option<u32>| CylinderProbe {
+            cylinder,
+            format: disk_cylinder.map(|_| "Double Density ISO - could be Atari ST".to_owned()),
+            disk_cylinder,
+        };
+
+        assert_eq!(
+            estimate_cylinder_count(&[probe(39, None), probe(40, None)]),
+            None
+        );
+
+        // 40 cylinder disk in a 40 cylinder drive
+        let count = estimate_cylinder_count(&[probe(39, Some(39)), probe(40, None)]).unwrap();
+        assert_eq!(
+            count,
+            CylinderCount {
+                cylinders: 40,
+                double_step: false,
+                open_end: false
+            }
+        );
+
+        // 40 cylinder disk in an 80 cylinder drive
+        let probes = [
+            probe(39, Some(19)),
+            probe(40, Some(20)),
+            probe(79, Some(39)),
+            probe(80, None),
+        ];
+        assert!(probes.first().unwrap().double_step());
+        let count = estimate_cylinder_count(&probes).unwrap();
+        assert_eq!((count.cylinders, count.double_step), (40, true));
+
+        // Data on the last probe might continue on further cylinders
+        let probes = [probe(39, Some(39)), probe(79, Some(79))];
+        let count = estimate_cylinder_count(&probes).unwrap();
+        assert_eq!(
+            (count.cylinders, count.double_step, count.open_end),
+            (80, false, true)
+        );
+    }
 }
@@ -0,0 +1,422 @@
+use anyhow::{bail, ensure, Context};
+use rusb::DeviceHandle;
+use util::{
+    cross_correlate, duration_of_rotation_as_stm_tim_raw,
+    fluxpulse::FluxPulseToCells,
+    mfm::{MfmDecoder, MfmWord},
+    Correlation, Density, DriveSelectState, PulseDuration,
+    DRIVE_SLOWEST_RPM, VERIFY_THRESHOLD_PERCENT,
+};
+
+use crate::{
+    error::ToolError,
+    image_reader::image_iso::{ISO_DAM, ISO_IDAM},
+    rawtrack::{RawImage, RawTrack},
+    track_parser::{
+        all_track_parsers, expand_reduced_pulses, iso::IsoTrackParser, read_flux_timings, read_rpm,
+        DynTrackParser, TrackParser, TrackPayload, READ_ATTEMPTS,
+    },
+    usb_commands::configure_device,
+};
+
+/// Number of pulses of a region which is compared between two reads
+const DOUBLE_READ_REGION: usize = 256;
+/// Pulses a region may be shifted in the second read. Covers jitter of the index
+/// and pulses which were only detected in one of the reads.
+const DOUBLE_READ_SLACK: usize = 64;
+
+/// Parts of an ISO track which carry information. Gaps are ignored.
+#[derive(Debug, PartialEq, Eq)]
+enum IsoTrackElement {
+    /// Cylinder, head, sector, size and CRC
+    Header(Vec<u8>),
+    /// Sector data including CRC
+    Data(Vec<u8>),
+}
+
+/// Extracts the sector headers and data blocks in the order of appearance
+fn iso_track_structure(track: &[PulseDuration], density: Density) -> Vec<IsoTrackElement> {
+    let mut mfm_words: Vec<MfmWord> = Vec::new();
+    let mut mfmd = MfmDecoder::new(|f| mfm_words.push(f));
+
+    let cellsize = match density {
+        Density::High => 84,
+        Density::SingleDouble => 168,
+    };
+
+    let mut pulseparser = FluxPulseToCells::new(|val| mfmd.feed(val), cellsize);
+    track.iter().for_each(|f| pulseparser.feed(*f));
+
+    let mut iterator = mfm_words.into_iter();
+    let mut elements = Vec::new();
+    let mut sector_size = None;
+
+    while let Some(searchword) = iterator.next() {
+        if !matches!(searchword, MfmWord::SyncWord) {
+            continue;
+        }
+
+        match iterator.next() {
+            Some(MfmWord::Enc(ISO_IDAM)) => {
+                if let Some(header) = take_bytes(&mut iterator, 6) {
+                    sector_size = header.get(3).map(|f| 128_usize << (f & 3));
+                    elements.push(IsoTrackElement::Header(header));
+                }
+            }
+            Some(MfmWord::Enc(ISO_DAM)) => {
+                // Data without a header before can't be interpreted
+                if let Some(size) = sector_size.take()
+                    && let Some(data) = take_bytes(&mut iterator, size + 2)
+                {
+                    elements.push(IsoTrackElement::Data(data));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    elements
+}
+
+/// Provides the next bytes if they are not interrupted by a sync word
+fn take_bytes(iterator: &mut impl Iterator<Item = MfmWord>, len: usize) -> Option<Vec<u8>> {
+    let bytes: Vec<u8> = iterator
+        .take(len)
+        .map_while(|f| match f {
+            MfmWord::Enc(val) => Some(val),
+            MfmWord::SyncWord => None,
+        })
+        .collect();
+    (bytes.len() == len).then_some(bytes)
+}
+
+/// Decodes an ISO track and compares its structure with the track of the image.
+/// Gaps are ignored but every sector header and data block must match in content
+/// and order. As the track of the image was generated with the geometry of the image,
+/// presets and sector IDs are respected. This detects tracks where every sector has
+/// a valid CRC but the track itself is malformed.
+pub fn verify_iso_track_structure(
+    track: &[PulseDuration],
+    image_track: &RawTrack,
+    density: Density,
+) -> anyhow::Result<TrackPayload> {
+    let (cylinder, head) = (image_track.cylinder, image_track.head);
+    let mut parser = IsoTrackParser::new(None, density);
+    parser.expect_track(cylinder, head);
+    let payload = parser.parse_flux_timings(track)?;
+
+    let expected = iso_track_structure(
+        &expand_reduced_pulses(&image_track.simulate_read(1)?),
+        density,
+    );
+    let actual = iso_track_structure(track, density);
+    ensure!(
+        !expected.is_empty(),
+        "Track {cylinder} {head} of the image has no ISO structure"
+    );
+
+    // The recording might start anywhere on the track and covers more than a revolution.
+    // Search for one full revolution of the track of the image.
+    let found = (0..expected.len()).any(|rotation| {
+        actual.windows(expected.len()).any(|window| {
+            let rotated = expected.iter().cycle().skip(rotation).take(expected.len());
+            window.iter().eq(rotated)
+        })
+    });
+
+    ensure!(
+        found,
+        "Structure of track {cylinder} {head} differs from the image"
+    );
+
+    Ok(payload)
+}
+
+/// Decodes the track of an image and provides the parser which was able to do so.
+/// The density is provided if the track is an ISO track with MFM encoding.
+fn decode_image_track(
+    track: &RawTrack,
+) -> anyhow::Result<(DynTrackParser, TrackPayload, Option<Density>)> {
+    let raw_data = track.simulate_read(2)?;
+
+    // The structure check is only possible with MFM
+    for density in [Density::SingleDouble, Density::High] {
+        let mut parser = IsoTrackParser::new(None, density);
+        parser.expect_track(track.cylinder, track.head);
+
+        if let Ok(payload) = parser.parse_raw_track(&raw_data) {
+            return Ok((Box::new(parser), payload, Some(density)));
+        }
+    }
+
+    for mut parser in all_track_parsers() {
+        parser.expect_track(track.cylinder, track.head);
+
+        if let Ok(payload) = parser.parse_raw_track(&raw_data) {
+            return Ok((parser, payload, None));
+        }
+    }
+
+    bail!(
+        "Track {} {} is not decodable by any known format",
+        track.cylinder,
+        track.head
+    )
+}
+
+/// Reads a track from disk and compares the decoded data against the image.
+/// ISO tracks are additionally checked for their structure.
+pub fn verify_track(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    track: &RawTrack,
+) -> anyhow::Result<()> {
+    let (mut parser, expected, iso_density) = decode_image_track(track)?;
+    let mut last_error = None;
+
+    for _ in 0..READ_ATTEMPTS {
+        let flux_timings = read_flux_timings(
+            usb_handles,
+            track.cylinder,
+            track.head,
+            true,
+            parser.duration_to_record(),
+            false,
+        )?;
+
+        parser.expect_track(track.cylinder, track.head);
+        let result = if let Some(density) = iso_density {
+            verify_iso_track_structure(&flux_timings, track, density)
+        } else {
+            parser.parse_flux_timings(&flux_timings)
+        };
+
+        match result {
+            Ok(payload) if payload.payload == expected.payload => return Ok(()),
+            Ok(_) => {
+                last_error = Some(anyhow::anyhow!(
+                    "Data of track {} {} differs from the image",
+                    track.cylinder,
+                    track.head
+                ));
+            }
+            Err(error) => last_error = Some(error),
+        }
+    }
+
+    Err(last_error.context(program_flow_error!())?)
+}
+
+/// Reads back the tracks of an image without writing and compares them.
+/// Every track is checked and all failures are reported at the end.
+pub fn verify_image(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    image: &RawImage,
+    select_drive: DriveSelectState,
+    index_sim_frequency: u32,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    configure_device(
+        usb_handles,
+        select_drive,
+        image.density,
+        index_sim_frequency,
+    )?;
+
+    let mut failed_tracks = Vec::new();
+
+    for track in &image.tracks {
+        match verify_track(usb_handles, track) {
+            Ok(()) => {
+                if !quiet {
+                    println!("Verified cylinder {} head {}", track.cylinder, track.head);
+                }
+            }
+            Err(error) => {
+                println!("{error}");
+                failed_tracks.push((track.cylinder, track.head));
+            }
+        }
+    }
+
+    if !failed_tracks.is_empty() {
+        bail!(ToolError::VerificationsFailed(failed_tracks));
+    }
+
+    Ok(())
+}
+
+/// Compares two reads of a track which both start at the index.
+/// The first read is split into regions which are searched in the second read around the
+/// position the previous region was found at. Provides the number of regions which
+/// were not found and the number of compared regions.
+#[must_use]
+pub fn compare_reads(
+    first: &[PulseDuration],
+    second: &[PulseDuration],
+    threshold: i32,
+) -> (usize, usize) {
+    let mut position: usize = 0;
+    let mut disagreeing = 0;
+    let mut compared = 0;
+
+    for region in first.chunks_exact(DOUBLE_READ_REGION) {
+        let start = position.saturating_sub(DOUBLE_READ_SLACK);
+        let end = (position + DOUBLE_READ_REGION + DOUBLE_READ_SLACK).min(second.len());
+        // The second read might be shorter. Only compare what both have seen.
+        let Some(window) = second
+            .get(start..end)
+            .filter(|f| f.len() >= DOUBLE_READ_REGION)
+        else {
+            break;
+        };
+
+        compared += 1;
+        match cross_correlate(
+            window.iter().copied(),
+            region.iter().copied(),
+            DOUBLE_READ_REGION,
+            Correlation::FirstMatch,
+            |x, y| x.similar(&y, threshold),
+        ) {
+            Some(offset) => position = start + offset + DOUBLE_READ_REGION,
+            None => {
+                disagreeing += 1;
+                position += DOUBLE_READ_REGION;
+            }
+        }
+    }
+
+    (disagreeing, compared)
+}
+
+/// Reads every track twice and compares the reads with each other instead of the
+/// written data. This finds media which accepted a write but doesn't hold it reliably.
+/// `tracks` provides cylinder, head and cell size of every track.
+pub fn double_read_verify(
+    usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    tracks: &[(u32, u32, PulseDuration)],
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let duration_to_record = duration_of_rotation_as_stm_tim_raw(read_rpm(DRIVE_SLOWEST_RPM));
+    let mut failed_tracks = Vec::new();
+
+    for (cylinder, head, cell_size) in tracks {
+        let read = || {
+            read_flux_timings(
+                usb_handles,
+                *cylinder,
+                *head,
+                true,
+                duration_to_record,
+                false,
+            )
+        };
+        let first = read()?;
+        let second = read()?;
+
+        let threshold = cell_size.0 * VERIFY_THRESHOLD_PERCENT / 100;
+        match compare_reads(&first, &second, threshold) {
+            (0, compared) if compared > 0 => {
+                if !quiet {
+                    println!("Both reads of cylinder {cylinder} head {head} agree");
+                }
+            }
+            (disagreeing, compared) => {
+                println!(
+                    "The reads of cylinder {cylinder} head {head} disagree in {disagreeing} of {compared} regions"
+                );
+                failed_tracks.push((*cylinder, *head));
+            }
+        }
+    }
+
+    if !failed_tracks.is_empty() {
+        bail!(ToolError::VerificationsFailed(failed_tracks));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use util::{DensityMapEntry, Encoding};
+
+    use super::*;
+    use crate::image_reader::image_iso::{generate_iso_track, IsoGeometry};
+
+    fn iso_track(geometry: &IsoGeometry, sectors: &[u8]) -> RawTrack {
+        let trackbuf = generate_iso_track(3, 1, geometry, &mut sectors.chunks_exact(512)).unwrap();
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: trackbuf.len(),
+            cell_size: PulseDuration(168),
+        }];
+        RawTrack::new(3, 1, trackbuf, densitymap, Encoding::MFM)
+    }
+
+    fn flux(track: &RawTrack) -> Vec<PulseDuration> {
+        expand_reduced_pulses(&track.simulate_read(2).unwrap())
+    }
+
+    #[test]
+    fn verify_iso_track_structure_test() {
+        let sectors: Vec<u8> = (0..9 * 512).map(|f| (f % 247) as u8).collect();
+
+        let image_track = iso_track(&IsoGeometry::new(9), &sectors);
+        let payload =
+            verify_iso_track_structure(&flux(&image_track), &image_track, Density::SingleDouble)
+                .unwrap();
+        assert_eq!(payload.payload, sectors);
+
+        // Every sector is fine but the order doesn't match the image
+        let mut interleaved = IsoGeometry::new(9);
+        interleaved.interleaving = 1;
+        let disk_track = iso_track(&interleaved, &sectors);
+        assert!(
+            verify_iso_track_structure(&flux(&disk_track), &image_track, Density::SingleDouble)
+                .is_err()
+        );
+
+        // The geometry of the image is respected
+        let mut numbered = IsoGeometry::new(9);
+        numbered.sector_ids = Some((1..=9).rev().collect());
+        numbered.gap_fill = 0xe5;
+        let image_track = iso_track(&numbered, &sectors);
+        assert!(verify_iso_track_structure(
+            &flux(&image_track),
+            &image_track,
+            Density::SingleDouble
+        )
+        .is_ok());
+        assert!(
+            verify_iso_track_structure(&flux(&disk_track), &image_track, Density::SingleDouble)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn compare_reads_test() {
+        // Pseudo random MFM like pulses
+        let first: Vec<PulseDuration> = (0..2000u32)
+            .map(|f| PulseDuration(168 * (2 + ((f * 7919) ^ (f >> 3)) as i32 % 3)))
+            .collect();
+        let threshold = 168 * VERIFY_THRESHOLD_PERCENT / 100;
+
+        assert_eq!(compare_reads(&first, &first, threshold), (0, 7));
+
+        // The second read saw a few more pulses before the data and has some jitter
+        let second: Vec<PulseDuration> = [PulseDuration(336); 10]
+            .iter()
+            .chain(first.iter())
+            .enumerate()
+            .map(|(i, f)| PulseDuration(f.0 + if i % 2 == 0 { 20 } else { -20 }))
+            .collect();
+        assert_eq!(compare_reads(&first, &second, threshold).0, 0);
+
+        // Destroy the content of a single region
+        let mut second = first.clone();
+        for pulse in second.iter_mut().skip(600).take(20) {
+            pulse.0 = 1000;
+        }
+        assert_eq!(compare_reads(&first, &second, threshold), (1, 7));
+    }
+}
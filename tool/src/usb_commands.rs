@@ -1,18 +1,206 @@
-use std::time::Duration;
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use anyhow::{bail, ensure, Context};
-use rusb::DeviceHandle;
-use util::{Density, DriveSelectState};
+use util::{
+    capabilities::{Capabilities, FEATURE_VERIFY_WINDOWS, GET_CAPABILITIES},
+    reception_checksum, CableType, Correlation, Density, DriveSelectState, GapGenerator,
+    PulseDuration, VerifyHistogram, VerifyWindows, CONFIRM_RECEPTION, CONFIRM_RECEPTION_COMMAND,
+    DEFAULT_HEAD_SETTLE_MS, DEFAULT_MAX_CYLINDER, DEFAULT_STEP_RATE_MS,
+    DENSITY_LATCHED_AT_MOTOR_ON, DETAILED_VERIFY, EXTENDED_DENSITY_MAP, GAP_GENERATOR_DISABLED,
+    LONGEST_AGREEMENT_CORRELATION, MAX_CYLINDER, MAX_HEAD_SETTLE_MS, MAX_STEP_RATE_MS,
+    MAX_VERIFY_THRESHOLD_EXTRA_PERCENT, MAX_WRITE_PIPELINE_DEPTH, VERIFY_THRESHOLD_EXTRA_SHIFT,
+    WRITE_HEAP_RESERVE, WRITE_PREFILL_PULSES, WRITE_TRACK_COMMAND, WRITE_WITHOUT_VERIFY,
+};
+
+use crate::{error::ToolError, rawtrack::RawTrack, usb_device::UsbTransport};
+
+static INVERT_DENSITY_SELECT: AtomicBool = AtomicBool::new(false);
+static DENSITY_LATCHED: AtomicBool = AtomicBool::new(false);
+static CABLE_TYPE: AtomicU32 = AtomicU32::new(CableType::PcTwist.to_bits());
+static HEAD_SETTLE_MS: AtomicU32 = AtomicU32::new(DEFAULT_HEAD_SETTLE_MS);
+static STEP_RATE_MS: AtomicU32 = AtomicU32::new(DEFAULT_STEP_RATE_MS);
+static DETAILED_VERIFY_ENABLED: AtomicBool = AtomicBool::new(false);
+static MAX_CYLINDER_SETTING: AtomicU32 = AtomicU32::new(MAX_CYLINDER);
+static MAX_CYLINDER_CONFIGURED: AtomicBool = AtomicBool::new(false);
+static VERIFY_THRESHOLD_EXTRA: AtomicU32 = AtomicU32::new(0);
+/// Point in time at which the operation is aborted together with the configured seconds
+static OPERATION_DEADLINE: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+
+/// Drive the density select signal with the opposite polarity on every
+/// following configuration. Required for some non standard drives.
+pub fn set_invert_density_select(invert: bool) {
+    INVERT_DENSITY_SELECT.store(invert, Ordering::Relaxed);
+}
+
+/// Restart the motor on every following configuration which changes the density.
+/// Required for drives which only read the density select signal at motor on.
+pub fn set_density_latched_at_motor_on(latched: bool) {
+    DENSITY_LATCHED.store(latched, Ordering::Relaxed);
+}
+
+/// Assign the drive select and motor enable lines according to the cable
+/// on every following configuration
+pub fn set_cable_type(cable_type: CableType) {
+    CABLE_TYPE.store(cable_type.to_bits(), Ordering::Relaxed);
+}
+
+/// Wait this long after stepping before the head is used on every following
+/// configuration. Older drives might need more time after large seeks.
+pub fn set_head_settle_time(settle_ms: u32) -> anyhow::Result<()> {
+    ensure!(
+        (1..=MAX_HEAD_SETTLE_MS).contains(&settle_ms),
+        "The head settle time must be between 1 and {MAX_HEAD_SETTLE_MS} ms"
+    );
+    HEAD_SETTLE_MS.store(settle_ms, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Wait this long between two step pulses on every following configuration.
+/// Worn drives might lose steps with the default rate.
+pub fn set_step_rate(step_rate_ms: u32) -> anyhow::Result<()> {
+    ensure!(
+        (DEFAULT_STEP_RATE_MS..=MAX_STEP_RATE_MS).contains(&step_rate_ms),
+        "The step rate must be between {DEFAULT_STEP_RATE_MS} and {MAX_STEP_RATE_MS} ms"
+    );
+    STEP_RATE_MS.store(step_rate_ms, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Allow reading and writing up to this cylinder on every following operation.
+/// Only some drives can step beyond the last cylinder of standard formats.
+pub fn set_max_cylinder(max_cylinder: u32) -> anyhow::Result<()> {
+    ensure!(
+        (DEFAULT_MAX_CYLINDER..=MAX_CYLINDER).contains(&max_cylinder),
+        "The maximum cylinder must be between {DEFAULT_MAX_CYLINDER} and {MAX_CYLINDER}"
+    );
+    MAX_CYLINDER_SETTING.store(max_cylinder, Ordering::Relaxed);
+    MAX_CYLINDER_CONFIGURED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Last cylinder which may be read or written
+pub fn max_cylinder() -> u32 {
+    MAX_CYLINDER_SETTING.load(Ordering::Relaxed)
+}
+
+/// Last cylinder which is read if no tracks were given.
+/// Stays at the last standard cylinder unless the maximum cylinder was configured.
+pub fn max_read_cylinder() -> u32 {
+    if MAX_CYLINDER_CONFIGURED.load(Ordering::Relaxed) {
+        max_cylinder()
+    } else {
+        DEFAULT_MAX_CYLINDER
+    }
+}
 
-use crate::rawtrack::RawTrack;
+/// Refuses cylinders beyond the configured maximum cylinder
+pub fn ensure_cylinder_allowed(cylinder: u32) -> anyhow::Result<()> {
+    let max_cylinder = max_cylinder();
+    ensure!(
+        cylinder <= max_cylinder,
+        "Cylinder {cylinder} is beyond the maximum cylinder {max_cylinder}. \
+        The maximum cylinder was lowered for this drive."
+    );
+    Ok(())
+}
+
+/// Refuses a maximum cylinder which the firmware is unable to step to
+pub fn ensure_max_cylinder_supported(capabilities: &Capabilities) -> anyhow::Result<()> {
+    ensure!(
+        max_cylinder() <= u32::from(capabilities.max_cylinder),
+        "The firmware only reaches cylinder {}",
+        capabilities.max_cylinder
+    );
+    Ok(())
+}
+
+/// Refuses to write encoded tracks if the firmware doesn't know `WRITE_TRACK_COMMAND`.
+/// Older firmware doesn't report its capabilities at all.
+pub fn ensure_write_track_supported(capabilities: Option<&Capabilities>) -> anyhow::Result<()> {
+    ensure!(
+        capabilities.is_some_and(|f| f.has_feature(FEATURE_VERIFY_WINDOWS)),
+        "The firmware doesn't support the write command of this tool. Please update it."
+    );
+    Ok(())
+}
+
+/// Loosen the verification of higher cylinders on every following configuration.
+/// The threshold grows linearly and is raised by this percentage of a cell at the last cylinder.
+pub fn set_verify_threshold_extra(extra_percent: u32) -> anyhow::Result<()> {
+    ensure!(
+        extra_percent <= MAX_VERIFY_THRESHOLD_EXTRA_PERCENT,
+        "The additional verify tolerance must be at most {MAX_VERIFY_THRESHOLD_EXTRA_PERCENT} percent"
+    );
+    VERIFY_THRESHOLD_EXTRA.store(extra_percent, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Additional verify tolerance at the last cylinder in percent of the cell size
+pub fn verify_threshold_extra() -> u32 {
+    VERIFY_THRESHOLD_EXTRA.load(Ordering::Relaxed)
+}
+
+/// Abort the operation once it runs longer than this, starting now.
+/// Unattended setups shall not wait forever for a hung drive.
+pub fn set_operation_timeout(timeout_secs: u64) -> anyhow::Result<()> {
+    ensure!(
+        timeout_secs > 0,
+        "The operation timeout must be at least 1 second"
+    );
+    *OPERATION_DEADLINE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = Some((
+        Instant::now() + Duration::from_secs(timeout_secs),
+        timeout_secs,
+    ));
+    Ok(())
+}
+
+fn operation_deadline() -> Option<(Instant, u64)> {
+    *OPERATION_DEADLINE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Aborts the running operation of the device once the operation timeout is exceeded.
+/// The device stops the motor after its motor off delay as it is idle afterwards.
+pub fn check_operation_deadline(handles: &impl UsbTransport) -> anyhow::Result<()> {
+    check_deadline(handles, operation_deadline())
+}
+
+fn check_deadline(
+    handles: &impl UsbTransport,
+    deadline: Option<(Instant, u64)>,
+) -> anyhow::Result<()> {
+    if let Some((deadline, seconds)) = deadline
+        && Instant::now() >= deadline
+    {
+        handles.abort()?;
+        bail!(ToolError::OperationTimeout { seconds });
+    }
+    Ok(())
+}
+
+/// Request the distribution of verify differences after every written track
+/// on every following configuration.
+pub fn set_detailed_verify(detailed: bool) {
+    DETAILED_VERIFY_ENABLED.store(detailed, Ordering::Relaxed);
+}
 
 pub fn configure_device(
-    handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    handles: &impl UsbTransport,
     select_drive: DriveSelectState,
     density: Density,
     index_sim_frequency: u32,
 ) -> anyhow::Result<()> {
-    let (handle, _endpoint_in, endpoint_out) = handles;
     let timeout = Duration::from_secs(10);
 
     let mut command_buf = [0u8; 3 * 4];
@@ -29,6 +217,22 @@ pub fn configure_device(
         settings |= 2;
     }
 
+    if INVERT_DENSITY_SELECT.load(Ordering::Relaxed) {
+        settings |= 4;
+    }
+
+    if DETAILED_VERIFY_ENABLED.load(Ordering::Relaxed) {
+        settings |= DETAILED_VERIFY;
+    }
+
+    if DENSITY_LATCHED.load(Ordering::Relaxed) {
+        settings |= DENSITY_LATCHED_AT_MOTOR_ON;
+    }
+    settings |= CABLE_TYPE.load(Ordering::Relaxed) << 3;
+    settings |= HEAD_SETTLE_MS.load(Ordering::Relaxed) << 8;
+    settings |= STEP_RATE_MS.load(Ordering::Relaxed) << 16;
+    settings |= VERIFY_THRESHOLD_EXTRA.load(Ordering::Relaxed) << VERIFY_THRESHOLD_EXTRA_SHIFT;
+
     writer
         .next()
         .context(program_flow_error!())?
@@ -44,33 +248,111 @@ pub fn configure_device(
         .context(program_flow_error!())?
         .clone_from_slice(&u32::to_le_bytes(index_sim_frequency));
 
-    handle
-        .write_bulk(*endpoint_out, &command_buf, timeout)
+    handles
+        .write_bulk(&command_buf, timeout)
         .context("Bulk Write failed - USB Problem?")?;
 
     Ok(())
 }
 
+/// Keeps the motor spinning for the given time after every operation.
+/// The firmware doesn't go below `MIN_MOTOR_OFF_DELAY_MS`.
+pub fn set_motor_off_delay(
+    handles: &impl UsbTransport,
+    motor_off_delay_ms: u32,
+) -> anyhow::Result<()> {
+    let timeout = Duration::from_secs(10);
+
+    let mut command_buf = [0u8; 2 * 4];
+
+    let mut writer = command_buf.chunks_mut(4);
+
+    writer
+        .next()
+        .context(program_flow_error!())?
+        .clone_from_slice(&u32::to_le_bytes(0x1234_0006));
+
+    writer
+        .next()
+        .context(program_flow_error!())?
+        .clone_from_slice(&u32::to_le_bytes(motor_off_delay_ms));
+
+    handles
+        .write_bulk(&command_buf, timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    Ok(())
+}
+
+/// Reads a track with flux timings reduced by `PULSE_REDUCE_SHIFT` to fit into a byte.
 pub fn read_raw_track(
-    handles: &(DeviceHandle<rusb::Context>, u8, u8),
+    handles: &impl UsbTransport,
+    cylinder: u32,
+    head: u32,
+    wait_for_index: bool,
+    duration_to_record: usize,
+) -> anyhow::Result<Vec<u8>> {
+    read_track_data(
+        handles,
+        cylinder,
+        head,
+        wait_for_index,
+        false,
+        duration_to_record,
+    )
+}
+
+/// Reads a track with flux timings in full resolution.
+/// Every timing requires two bytes instead of one which doubles the amount of data
+/// transferred via USB. In exchange, no precision is lost by `PULSE_REDUCE_SHIFT`.
+pub fn read_raw_track_high_resolution(
+    handles: &impl UsbTransport,
+    cylinder: u32,
+    head: u32,
+    wait_for_index: bool,
+    duration_to_record: usize,
+) -> anyhow::Result<Vec<PulseDuration>> {
+    let data = read_track_data(
+        handles,
+        cylinder,
+        head,
+        wait_for_index,
+        true,
+        duration_to_record,
+    )?;
+
+    ensure!(
+        data.len() % 2 == 0,
+        "Odd number of bytes for 16 bit timings"
+    );
+
+    data.chunks_exact(2)
+        .map(|f| Ok(PulseDuration(i32::from(u16::from_le_bytes(f.try_into()?)))))
+        .collect()
+}
+
+fn read_track_data(
+    handles: &impl UsbTransport,
     cylinder: u32,
     head: u32,
     wait_for_index: bool,
+    high_resolution: bool,
     duration_to_record: usize,
 ) -> anyhow::Result<Vec<u8>> {
-    let (handle, endpoint_in, endpoint_out) = handles;
     let timeout = Duration::from_secs(10);
 
+    ensure_cylinder_allowed(cylinder)?;
     println!("Read raw track from Cyl:{cylinder} Head:{head}");
 
     let mut command_buf = [0u8; 64];
     let mut writer = command_buf.chunks_mut(4);
 
     let wait_for_index = if wait_for_index { 1 << 9 } else { 0 };
+    let high_resolution = if high_resolution { 1 << 10 } else { 0 };
 
     let header = vec![
         0x1234_0004,
-        cylinder | (head << 8) | wait_for_index,
+        cylinder | (head << 8) | wait_for_index | high_resolution,
         duration_to_record as u32,
     ];
 
@@ -81,8 +363,8 @@ pub fn read_raw_track(
             .clone_from_slice(&u32::to_le_bytes(word));
     }
 
-    handle
-        .write_bulk(*endpoint_out, &command_buf, timeout)
+    handles
+        .write_bulk(&command_buf, timeout)
         .context("Write Bulk Transfer failed - USB Problem?")?;
 
     let mut result = Vec::with_capacity(800 * 64); // TODO magic number
@@ -90,8 +372,8 @@ pub fn read_raw_track(
     loop {
         let mut in_buf = [0u8; 64];
 
-        let size = handle
-            .read_bulk(*endpoint_in, &mut in_buf, timeout)
+        let size = handles
+            .read_bulk(&mut in_buf, timeout)
             .context("Read Bulk failed - USB Problem?")?;
 
         if size == 64 {
@@ -112,11 +394,33 @@ pub fn read_raw_track(
     Ok(result)
 }
 
-pub fn write_raw_track(
-    handles: &(DeviceHandle<rusb::Context>, u8, u8),
-    track: &RawTrack,
-) -> anyhow::Result<()> {
-    let (handle, _endpoint_in, endpoint_out) = handles;
+/// Settle delay after writing in steps as transferred to the device
+fn packed_settle_delay(track: &RawTrack) -> u32 {
+    track.verify_windows.settle_delay_us / VerifyWindows::SETTLE_DELAY_STEP_US
+}
+
+/// Shares the word with the compare window which never needs the highest bit
+fn packed_correlation(track: &RawTrack) -> u32 {
+    match track.verify_windows.correlation {
+        Correlation::FirstMatch => 0,
+        Correlation::LongestAgreement => LONGEST_AGREEMENT_CORRELATION,
+    }
+}
+
+/// Shares the word with the read data window which never needs the highest bit
+fn packed_write_only(track: &RawTrack) -> u32 {
+    if track.write_only {
+        WRITE_WITHOUT_VERIFY
+    } else {
+        0
+    }
+}
+
+pub fn write_raw_track(handles: &impl UsbTransport, track: &RawTrack) -> anyhow::Result<()> {
+    if track.flux_timings.is_some() {
+        return write_flux_track(handles, track);
+    }
+
     let timeout = Duration::from_secs(10);
 
     let mut command_buf = [0u8; 64];
@@ -136,26 +440,60 @@ pub fn write_raw_track(
 
     ensure!(track.head <= 1);
     ensure!(track.cylinder <= 0xff);
+    ensure_cylinder_allowed(track.cylinder)?;
     ensure!(track.write_precompensation <= 0xff);
+    ensure!(track.verify_windows.compare <= 0x7fff);
+    ensure!(track.verify_windows.read_data <= 0x7fff);
+    ensure!(track.verify_windows.skip_pulses <= 0xff);
+    ensure!(track.verify_windows.settle_delay_us <= VerifyWindows::MAX_SETTLE_DELAY_US);
 
-    let non_flux_reversal_mask = if track.has_non_flux_reversal_area {
-        0x200
-    } else {
+    let (non_flux_reversal_mask, gap_generator_disabled) = match track.effective_gap_generator() {
+        GapGenerator::WeakBits => (0, 0),
+        GapGenerator::NonFluxReversal => (0x200, 0),
+        GapGenerator::Disabled => (0, GAP_GENERATOR_DISABLED),
+    };
+
+    // Slow data rates need cell sizes which don't fit into the compact format
+    let extended_density_map = if track.densitymap.iter().all(|f| f.packed().is_some()) {
         0
+    } else {
+        EXTENDED_DENSITY_MAP
     };
 
     let header = vec![
-        0x1234_0001,
+        WRITE_TRACK_COMMAND,
         expected_size as u32,
         remaining_blocks as u32,
-        // Fields 00000000 PPPPPPPP 000000NH CCCCCCCC
+        // Fields SSSSSSSS PPPPPPPP DDDDDDNH CCCCCCCC
         track.cylinder
             | (track.head << 8)
             | non_flux_reversal_mask
-            | (track.write_precompensation << 16),
-        track.densitymap.len() as u32,
+            | (packed_settle_delay(track) << 10)
+            | (track.write_precompensation << 16)
+            | ((track.verify_windows.skip_pulses as u32) << 24),
+        // Fields WRRRRRRR RRRRRRRR LCCCCCCC CCCCCCCC
+        (track.verify_windows.read_data << 16) as u32
+            | track.verify_windows.compare as u32
+            | packed_correlation(track)
+            | packed_write_only(track),
+        track.densitymap.len() as u32
+            | extended_density_map
+            | gap_generator_disabled
+            | CONFIRM_RECEPTION
+            | track.write_gate_margins.packed(),
     ];
 
+    // The density map has to fit behind the header into the first transfer
+    let max_density_map_len = 64 / 4 - header.len();
+    ensure!(
+        track.densitymap.len() <= max_density_map_len,
+        "Track {} {} has {} data rates but the write command only has room for {}",
+        track.cylinder,
+        track.head,
+        track.densitymap.len(),
+        max_density_map_len
+    );
+
     for i in header {
         writer
             .next()
@@ -164,26 +502,355 @@ pub fn write_raw_track(
     }
 
     for density_entry in &track.densitymap {
-        ensure!(density_entry.cell_size.0 < 512);
+        let packed = if extended_density_map == 0 {
+            density_entry.packed()
+        } else {
+            density_entry.packed_extended()
+        };
+        let packed = packed.with_context(|| {
+            format!(
+                "Track {} {} has a cell size of {} for {} bytes which can't be transferred",
+                track.cylinder,
+                track.head,
+                density_entry.cell_size.0,
+                density_entry.number_of_cellbytes
+            )
+        })?;
 
         writer
             .next()
             .context(program_flow_error!())?
-            .clone_from_slice(&u32::to_le_bytes(
-                ((density_entry.number_of_cellbytes as u32) << 9)
-                    | density_entry.cell_size.0 as u32,
-            ));
+            .clone_from_slice(&u32::to_le_bytes(packed));
     }
 
-    handle.write_bulk(*endpoint_out, &command_buf, timeout)?;
+    handles.write_bulk(&command_buf, timeout)?;
 
-    for block in track.raw_data.chunks(64) {
-        handle.write_bulk(*endpoint_out, block, timeout)?;
+    write_track_data(handles, &track.raw_data, timeout)
+}
+
+/// Transfers the data of a track behind its header. If the transfer breaks off,
+/// the device is told to forget the received part. It would take the following
+/// commands as data of the track otherwise.
+fn write_track_data(
+    handles: &impl UsbTransport,
+    data: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    for block in data.chunks(64) {
+        if let Err(error) = handles.write_bulk(block, timeout) {
+            handles.abort()?;
+            return Err(error.into());
+        }
     }
 
     Ok(())
 }
 
+/// Number of tracks which may wait in the device while another one is written.
+/// The requested depth is limited to let the waiting tracks and the written one
+/// fit into the free heap of the device together. At least one track can always wait.
+pub fn write_pipeline_depth(requested: usize, free_heap: usize, tracks: &[RawTrack]) -> usize {
+    // Flux timings are transferred with one byte per pulse
+    let largest_track = tracks
+        .iter()
+        .map(|f| f.flux_timings.as_ref().map_or(f.raw_data.len(), Vec::len))
+        .max()
+        .unwrap_or(0);
+    // The reserve is only needed for the track being written
+    let tracks_in_heap = free_heap.saturating_sub(WRITE_HEAP_RESERVE) / largest_track.max(1);
+
+    requested
+        .min(MAX_WRITE_PIPELINE_DEPTH)
+        .min(tracks_in_heap.saturating_sub(1))
+        .max(1)
+}
+
+/// Transfers the flux timings of a track without any further encoding.
+/// The device answers the same way as with `write_raw_track`.
+fn write_flux_track(handles: &impl UsbTransport, track: &RawTrack) -> anyhow::Result<()> {
+    let timeout = Duration::from_secs(10);
+
+    let mut command_buf = [0u8; 64];
+
+    let flux_data = track.reduced_flux_timings()?;
+    let expected_size = flux_data.len();
+    ensure!(
+        expected_size >= WRITE_PREFILL_PULSES,
+        "Track {} {} has too few flux timings to be written",
+        track.cylinder,
+        track.head
+    );
+    let mut remaining_blocks = expected_size / 64;
+    if expected_size % 64 != 0 {
+        remaining_blocks += 1;
+    }
+
+    println!(
+        "Request write and verify of flux timings for Cyl:{} Head:{}",
+        track.cylinder, track.head
+    );
+
+    let mut writer = command_buf.chunks_mut(4);
+
+    ensure!(track.head <= 1);
+    ensure!(track.cylinder <= 0xff);
+    ensure_cylinder_allowed(track.cylinder)?;
+    ensure!(track.verify_windows.compare <= 0x7fff);
+    ensure!(track.verify_windows.read_data <= 0x7fff);
+    ensure!(track.verify_windows.skip_pulses <= 0xff);
+    ensure!(track.verify_windows.settle_delay_us <= VerifyWindows::MAX_SETTLE_DELAY_US);
+
+    let header = vec![
+        0x1234_0005,
+        expected_size as u32,
+        remaining_blocks as u32,
+        // Fields SSSSSSSS 00000000 DDDDDD0H CCCCCCCC
+        track.cylinder
+            | (track.head << 8)
+            | (packed_settle_delay(track) << 10)
+            | ((track.verify_windows.skip_pulses as u32) << 24),
+        // Fields WRRRRRRR RRRRRRRR LCCCCCCC CCCCCCCC
+        (track.verify_windows.read_data << 16) as u32
+            | track.verify_windows.compare as u32
+            | packed_correlation(track)
+            | packed_write_only(track),
+        CONFIRM_RECEPTION | track.write_gate_margins.packed(),
+    ];
+
+    for i in header {
+        writer
+            .next()
+            .context(program_flow_error!())?
+            .clone_from_slice(&u32::to_le_bytes(i));
+    }
+
+    handles.write_bulk(&command_buf, timeout)?;
+
+    write_track_data(handles, &flux_data, timeout)
+}
+
+/// Signals of the configured drive which are available without spinning the motor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriveStatus {
+    pub write_protected: bool,
+    /// Derived from the disk change signal which stays active after inserting
+    /// a disk until the head was stepped. None if the firmware doesn't report it.
+    pub disk_present: Option<bool>,
+}
+
+/// Requests the disk change signal in addition to the write protection.
+/// Older firmware ignores it and answers without it.
+const DRIVE_STATUS_WITH_DISK_CHANGE: u32 = 1;
+
+/// Queries the status of the configured drive without spinning the motor.
+/// The drive must be selected using `configure_device` before.
+pub fn query_drive_status(handles: &impl UsbTransport) -> anyhow::Result<DriveStatus> {
+    let timeout = Duration::from_secs(10);
+
+    let mut command_buf = u32::to_le_bytes(0x1234_0007).to_vec();
+    command_buf.extend_from_slice(&u32::to_le_bytes(DRIVE_STATUS_WITH_DISK_CHANGE));
+    handles
+        .write_bulk(&command_buf, timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    let mut in_buf = [0u8; 64];
+    let size = handles.read_bulk(&mut in_buf, timeout)?;
+    let response_text =
+        std::str::from_utf8(&ensure_index!(in_buf[0..size])).context("UTF8 error")?;
+
+    parse_drive_status(response_text)
+}
+
+/// Accepts the answer with and without the disk change signal
+fn parse_drive_status(response_text: &str) -> anyhow::Result<DriveStatus> {
+    let flag = |value: &str| match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => bail!("Unexpected answer from device: {}", response_text),
+    };
+
+    match response_text.split(' ').collect::<Vec<_>>().as_slice() {
+        ["DriveStatus", write_protected] => Ok(DriveStatus {
+            write_protected: flag(write_protected)?,
+            disk_present: None,
+        }),
+        ["DriveStatus", write_protected, disk_present] => Ok(DriveStatus {
+            write_protected: flag(write_protected)?,
+            disk_present: Some(flag(disk_present)?),
+        }),
+        _ => bail!("Unexpected answer from device: {}", response_text),
+    }
+}
+
+/// Checks the write protection of the inserted disk without spinning the motor.
+/// The drive must be selected using `configure_device` before.
+pub fn is_write_protected(handles: &impl UsbTransport) -> anyhow::Result<bool> {
+    Ok(query_drive_status(handles)?.write_protected)
+}
+
+/// Checks if a disk is inserted in the configured drive.
+/// A freshly inserted disk is only detected after stepping the head.
+/// The drive is recalibrated in this case to check again.
+/// None if the firmware doesn't report the disk change signal.
+pub fn is_disk_present(handles: &impl UsbTransport) -> anyhow::Result<Option<bool>> {
+    match query_drive_status(handles)?.disk_present {
+        Some(false) => {}
+        disk_present => return Ok(disk_present),
+    }
+
+    recalibrate(handles)?;
+    Ok(query_drive_status(handles)?.disk_present)
+}
+
+/// Moves the head of the configured drive to track 0 to get a known position
+pub fn recalibrate(handles: &impl UsbTransport) -> anyhow::Result<()> {
+    let timeout = Duration::from_secs(10);
+
+    handles
+        .write_bulk(&u32::to_le_bytes(0x1234_000F), timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    let mut in_buf = [0u8; 64];
+    let size = handles.read_bulk(&mut in_buf, timeout)?;
+    let response_text =
+        std::str::from_utf8(&ensure_index!(in_buf[0..size])).context("UTF8 error")?;
+
+    match response_text {
+        "Recalibrated" => Ok(()),
+        "Fail Track0NotFound" => bail!("Track 0 not found. Is a drive connected?"),
+        _ => bail!("Unexpected answer from device: {}", response_text),
+    }
+}
+
+/// Measures how long the drive needs after writing until it can read again.
+/// Provides the time in µs. The content of the given track is destroyed.
+pub fn measure_write_recovery(
+    handles: &impl UsbTransport,
+    cylinder: u32,
+    head: u32,
+) -> anyhow::Result<u32> {
+    ensure!(head <= 1);
+    ensure_cylinder_allowed(cylinder)?;
+    let timeout = Duration::from_secs(10);
+
+    let mut command_buf = [0u8; 2 * 4];
+    let mut writer = command_buf.chunks_mut(4);
+
+    writer
+        .next()
+        .context(program_flow_error!())?
+        .clone_from_slice(&u32::to_le_bytes(0x1234_0011));
+
+    writer
+        .next()
+        .context(program_flow_error!())?
+        .clone_from_slice(&u32::to_le_bytes(cylinder | (head << 8)));
+
+    handles
+        .write_bulk(&command_buf, timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    let mut in_buf = [0u8; 64];
+    let size = handles.read_bulk(&mut in_buf, timeout)?;
+    let response_text =
+        std::str::from_utf8(&ensure_index!(in_buf[0..size])).context("UTF8 error")?;
+
+    match response_text.split(' ').collect::<Vec<_>>().as_slice() {
+        ["WriteRecovery", microseconds] => Ok(microseconds.parse()?),
+        ["Fail", "WriteProtected"] => bail!(ToolError::WriteProtected),
+        ["Fail", reason] => bail!("Unable to measure the write recovery: {reason}"),
+        _ => bail!("Unexpected answer from device: {}", response_text),
+    }
+}
+
+/// Asks the device what it supports. Intended to be called once when a feature depends on it.
+/// Older firmware doesn't answer, which is detected by a short timeout.
+pub fn query_capabilities(handles: &impl UsbTransport) -> anyhow::Result<Capabilities> {
+    let timeout = Duration::from_secs(1);
+
+    handles
+        .write_bulk(&u32::to_le_bytes(GET_CAPABILITIES), timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    let mut in_buf = [0u8; 64];
+    let size = match handles.read_bulk(&mut in_buf, timeout) {
+        Ok(size) => size,
+        Err(rusb::Error::Timeout) => {
+            bail!("The firmware doesn't report its capabilities. Please update it.")
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let answer = &ensure_index!(in_buf[0..size]);
+    Capabilities::from_bytes(answer)
+        .with_context(|| format!("Unexpected answer from device: {answer:?}"))
+}
+
+/// Position of the head as the device believes it to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadStatus {
+    /// Unknown before the first recalibration or after a failed seek
+    pub cylinder: Option<u32>,
+    /// Seeks which failed since the device was started. The firmware notices
+    /// lost steps if track 0 is not where it is expected.
+    pub failed_seeks: u32,
+}
+
+/// Provides the cylinder the device believes the head of the configured drive to be on.
+/// Useful to detect a desync between the host and the drive, e.g. after `recalibrate`.
+pub fn query_position(handles: &impl UsbTransport) -> anyhow::Result<HeadStatus> {
+    let timeout = Duration::from_secs(10);
+
+    handles
+        .write_bulk(&u32::to_le_bytes(0x1234_0010), timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    let mut in_buf = [0u8; 64];
+    let size = handles.read_bulk(&mut in_buf, timeout)?;
+    let response_text =
+        std::str::from_utf8(&ensure_index!(in_buf[0..size])).context("UTF8 error")?;
+
+    match response_text.split(' ').collect::<Vec<_>>().as_slice() {
+        ["Position", cylinder, failed_seeks] => Ok(HeadStatus {
+            cylinder: match *cylinder {
+                "Unknown" => None,
+                cylinder => Some(cylinder.parse()?),
+            },
+            failed_seeks: failed_seeks.parse()?,
+        }),
+        _ => bail!("Unexpected answer from device: {}", response_text),
+    }
+}
+
+/// Duration of a rotation of the configured drive in timer ticks, measured by the device
+/// between the index pulses. Waits up to a second for the spinning motor to provide it.
+pub fn query_rotation(handles: &impl UsbTransport) -> anyhow::Result<u32> {
+    let timeout = Duration::from_secs(10);
+    let start = Instant::now();
+
+    loop {
+        handles
+            .write_bulk(&u32::to_le_bytes(0x1234_0014), timeout)
+            .context("Bulk Write failed - USB Problem?")?;
+
+        let mut in_buf = [0u8; 64];
+        let size = handles.read_bulk(&mut in_buf, timeout)?;
+        let response_text =
+            std::str::from_utf8(&ensure_index!(in_buf[0..size])).context("UTF8 error")?;
+
+        match response_text.split(' ').collect::<Vec<_>>().as_slice() {
+            ["Rotation", "Unknown"] if start.elapsed() < Duration::from_secs(1) => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            ["Rotation", "Unknown"] => {
+                bail!("The rotation of the drive couldn't be measured. Is a disk inserted?")
+            }
+            ["Rotation", rotation_ticks] => return Ok(rotation_ticks.parse()?),
+            _ => bail!("Unexpected answer from device: {}", response_text),
+        }
+    }
+}
+
 pub enum UsbAnswer {
     WrittenAndVerified {
         cylinder: u32,
@@ -200,20 +867,116 @@ pub enum UsbAnswer {
         reads: u32,
         error: String,
     },
-    GotCmd,
+    /// The track was received. Provides the size and checksum of the assembled track
+    /// unless the firmware is too old.
+    GotCmd {
+        reception: Option<(usize, u32)>,
+    },
     WriteProtected,
+    /// The track doesn't fit into the memory of the device and was dropped
+    OutOfMemory {
+        cylinder: u32,
+        head: u32,
+        size: usize,
+    },
+    /// Precedes a successful verify if detailed verify was configured
+    VerifyHistogram(VerifyHistogram),
+    /// The track was too long for the measured rotation of the drive.
+    /// The device removed cell bytes at its end before writing it.
+    TrackShortened {
+        cylinder: u32,
+        head: u32,
+        removed_bytes: usize,
+    },
 }
 
-pub fn wait_for_answer(
-    handles: &(DeviceHandle<rusb::Context>, u8, u8),
-) -> anyhow::Result<UsbAnswer> {
-    let (handle, endpoint_in, _endpoint_out) = handles;
+/// Compares the size and checksum of the track assembled by the device with the sent one.
+/// This detects transfer problems before the track is written.
+/// The device waits for the result and discards the track on a mismatch.
+pub fn check_reception(
+    handles: &impl UsbTransport,
+    track: &RawTrack,
+    reception: Option<(usize, u32)>,
+) -> anyhow::Result<()> {
+    // Older firmware writes the track without waiting for a confirmation
+    let Some((size, checksum)) = reception else {
+        return Ok(());
+    };
+
+    let (expected_size, expected_checksum) = if track.flux_timings.is_some() {
+        let flux_data = track.reduced_flux_timings()?;
+        (flux_data.len(), reception_checksum(&[], &flux_data))
+    } else {
+        (
+            track.raw_data.len(),
+            reception_checksum(&track.densitymap, &track.raw_data),
+        )
+    };
+
+    let received = size == expected_size && checksum == expected_checksum;
+    confirm_reception(handles, received)?;
+
+    if !received {
+        // Tracks queued behind this one are not written either
+        handles.abort()?;
+        bail!(ToolError::TransferCorrupted {
+            cylinder: track.cylinder,
+            head: track.head,
+        });
+    }
+    Ok(())
+}
+
+/// Size and checksum of the track given by a "GotCmd" answer. Older firmware doesn't provide them.
+pub fn parse_reception(response_split: &[&str]) -> anyhow::Result<Option<(usize, u32)>> {
+    Ok(match (response_split.get(1), response_split.get(2)) {
+        (Some(size), Some(checksum)) => Some((size.parse()?, checksum.parse()?)),
+        _ => None,
+    })
+}
+
+/// Allows the device to write the last received track or tells it to discard the track
+fn confirm_reception(handles: &impl UsbTransport, write: bool) -> anyhow::Result<()> {
     let timeout = Duration::from_secs(10);
 
+    let mut command_buf = [0u8; 2 * 4];
+    let mut writer = command_buf.chunks_mut(4);
+
+    writer
+        .next()
+        .context(program_flow_error!())?
+        .clone_from_slice(&u32::to_le_bytes(CONFIRM_RECEPTION_COMMAND));
+
+    writer
+        .next()
+        .context(program_flow_error!())?
+        .clone_from_slice(&u32::to_le_bytes(u32::from(write)));
+
+    handles
+        .write_bulk(&command_buf, timeout)
+        .context("Bulk Write failed - USB Problem?")?;
+
+    Ok(())
+}
+
+pub fn wait_for_answer(handles: &impl UsbTransport) -> anyhow::Result<UsbAnswer> {
+    check_operation_deadline(handles)?;
+
+    // Don't wait beyond the operation timeout. A timeout of 0 would wait forever.
+    let timeout = operation_deadline().map_or(Duration::from_secs(10), |(deadline, _)| {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .clamp(Duration::from_millis(1), Duration::from_secs(10))
+    });
+
     // TODO copy pasta
     let mut in_buf = [0u8; 64];
 
-    let size = handle.read_bulk(*endpoint_in, &mut in_buf, timeout)?;
+    let result = handles.read_bulk(&mut in_buf, timeout);
+    if result.is_err() {
+        check_operation_deadline(handles)?;
+    }
+    let size = result?;
 
     let response_text =
         std::str::from_utf8(&ensure_index!(in_buf[0..size])).context("UTF8 error")?;
@@ -237,7 +1000,9 @@ pub fn wait_for_answer(
                 write_precomp,
             }
         }
-        "GotCmd" => UsbAnswer::GotCmd,
+        "GotCmd" => UsbAnswer::GotCmd {
+            reception: parse_reception(&response_split)?,
+        },
         "Fail" => {
             let cylinder = ensure_index!(response_split[1]).parse()?;
             let head = ensure_index!(response_split[2]).parse()?;
@@ -253,6 +1018,593 @@ pub fn wait_for_answer(
             }
         }
         "WriteProtected" => UsbAnswer::WriteProtected,
+        "OutOfMemory" => UsbAnswer::OutOfMemory {
+            cylinder: ensure_index!(response_split[1]).parse()?,
+            head: ensure_index!(response_split[2]).parse()?,
+            size: ensure_index!(response_split[3]).parse()?,
+        },
+        "Shortened" => UsbAnswer::TrackShortened {
+            cylinder: ensure_index!(response_split[1]).parse()?,
+            head: ensure_index!(response_split[2]).parse()?,
+            removed_bytes: ensure_index!(response_split[3]).parse()?,
+        },
+        "Histogram" => {
+            let mut histogram = VerifyHistogram::default();
+            for (index, bucket) in histogram.buckets.iter_mut().enumerate() {
+                *bucket = ensure_index!(response_split[index + 1]).parse()?;
+            }
+            UsbAnswer::VerifyHistogram(histogram)
+        }
         _ => bail!("Unexpected answer from device: {}", response_text),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+    };
+
+    use util::{DensityMapEntry, Encoding};
+
+    use super::*;
+
+    /// Records the transfers to the device and gives the scripted answers
+    #[derive(Default)]
+    struct RecordingTransport {
+        answers: RefCell<VecDeque<Vec<u8>>>,
+        written: RefCell<Vec<Vec<u8>>>,
+        /// Number of successful transfers before the connection breaks
+        failing_transfer: Option<usize>,
+        aborted: Cell<bool>,
+    }
+
+    impl UsbTransport for RecordingTransport {
+        fn write_bulk(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            if self.failing_transfer == Some(self.written.borrow().len()) {
+                return Err(rusb::Error::Pipe);
+            }
+            self.written.borrow_mut().push(data.to_vec());
+            Ok(data.len())
+        }
+
+        fn read_bulk(&self, data: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+            // The real device also runs into a timeout if nothing is left to say
+            let answer = self
+                .answers
+                .borrow_mut()
+                .pop_front()
+                .ok_or(rusb::Error::Timeout)?;
+            data.get_mut(..answer.len())
+                .ok_or(rusb::Error::Overflow)?
+                .copy_from_slice(&answer);
+            Ok(answer.len())
+        }
+
+        fn abort(&self) -> Result<(), ToolError> {
+            self.aborted.set(true);
+            Ok(())
+        }
+    }
+
+    /// The settings of the device are process wide.
+    /// Tests changing them must not run in parallel.
+    static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Settings word of the configuration with the current settings
+    fn configured_settings(select_drive: DriveSelectState, density: Density) -> u32 {
+        let usb = RecordingTransport::default();
+        configure_device(&usb, select_drive, density, 0).unwrap();
+        let commands = usb.commands();
+        assert_eq!(commands.len(), 1);
+        let command = commands.first().unwrap();
+        assert_eq!(command.first(), Some(&0x1234_0002));
+        *command.get(1).unwrap()
+    }
+
+    impl RecordingTransport {
+        fn answering(answers: &[&[u8]]) -> Self {
+            Self {
+                answers: RefCell::new(answers.iter().map(|f| f.to_vec()).collect()),
+                ..Default::default()
+            }
+        }
+
+        /// Every transfer as words. Incomplete words at the end are dropped.
+        fn commands(&self) -> Vec<Vec<u32>> {
+            self.written
+                .borrow()
+                .iter()
+                .map(|f| {
+                    f.chunks_exact(4)
+                        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn write_pipeline_depth_test() {
+        let track = |bytes: usize| {
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: bytes,
+                cell_size: PulseDuration(84),
+            }];
+            RawTrack::new(0, 0, vec![0; bytes], densitymap, Encoding::MFM)
+        };
+        let free_heap = 13509 * 8;
+
+        // Double density tracks leave enough room
+        let tracks = [track(12_500)];
+        assert_eq!(write_pipeline_depth(1, free_heap, &tracks), 1);
+        assert_eq!(write_pipeline_depth(3, free_heap, &tracks), 3);
+        assert_eq!(
+            write_pipeline_depth(9, free_heap, &tracks),
+            MAX_WRITE_PIPELINE_DEPTH
+        );
+
+        // Three high density tracks fit into the heap. One is written while two wait.
+        let tracks = [track(12_500), track(25_000)];
+        assert_eq!(write_pipeline_depth(3, free_heap, &tracks), 2);
+
+        // A single waiting track was always possible
+        assert_eq!(write_pipeline_depth(3, free_heap, &[track(100_000)]), 1);
+    }
+
+    #[test]
+    fn drive_status_test() {
+        // Firmware without the disk change signal
+        assert_eq!(
+            parse_drive_status("DriveStatus 1").unwrap(),
+            DriveStatus {
+                write_protected: true,
+                disk_present: None
+            }
+        );
+        assert_eq!(
+            parse_drive_status("DriveStatus 0 1").unwrap(),
+            DriveStatus {
+                write_protected: false,
+                disk_present: Some(true)
+            }
+        );
+        assert!(parse_drive_status("DriveStatus 2").is_err());
+        assert!(parse_drive_status("DriveStatus 0 1 1").is_err());
+    }
+
+    #[test]
+    fn write_protection_test() {
+        let usb = RecordingTransport::answering(&[b"DriveStatus 1 1", b"DriveStatus 0 1"]);
+        assert!(is_write_protected(&usb).unwrap());
+        assert!(!is_write_protected(&usb).unwrap());
+        let commands = usb.commands();
+        assert_eq!(commands.len(), 2);
+        assert!(commands.iter().all(|f| f.first() == Some(&0x1234_0007)));
+
+        // The firmware can't answer without a selected drive
+        let usb = RecordingTransport::answering(&[b"Fail NoDriveSelected"]);
+        assert!(is_write_protected(&usb).is_err());
+    }
+
+    #[test]
+    fn recalibrate_test() {
+        let usb = RecordingTransport::answering(&[b"Recalibrated"]);
+        recalibrate(&usb).unwrap();
+        assert_eq!(usb.commands(), [[0x1234_000F]]);
+
+        let usb = RecordingTransport::answering(&[b"Fail Track0NotFound"]);
+        let error = recalibrate(&usb).unwrap_err();
+        assert!(error.to_string().starts_with("Track 0 not found"));
+    }
+
+    #[test]
+    fn invert_density_select_test() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let settings = configured_settings(DriveSelectState::A, Density::High);
+        assert_eq!(settings & 0b110, 0b010);
+
+        set_invert_density_select(true);
+        let inverted = configured_settings(DriveSelectState::A, Density::High);
+        let inverted_dd = configured_settings(DriveSelectState::A, Density::SingleDouble);
+        set_invert_density_select(false);
+
+        // The density is still transferred as it is. The firmware inverts the signal.
+        assert_eq!(inverted & 0b110, 0b110);
+        assert_eq!(inverted_dd & 0b110, 0b100);
+    }
+
+    #[test]
+    fn cable_type_test() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let settings = configured_settings(DriveSelectState::B, Density::SingleDouble);
+        assert_eq!((settings >> 3) & 0b11, CableType::PcTwist.to_bits());
+        assert_eq!(settings & 1, 1);
+
+        set_cable_type(CableType::Shugart);
+        let settings = configured_settings(DriveSelectState::B, Density::SingleDouble);
+        set_cable_type(CableType::PcTwist);
+
+        assert_eq!((settings >> 3) & 0b11, CableType::Shugart.to_bits());
+        assert_eq!(settings & 1, 1);
+    }
+
+    #[test]
+    fn density_latched_at_motor_on_test() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let settings = configured_settings(DriveSelectState::A, Density::High);
+        assert_eq!(settings & DENSITY_LATCHED_AT_MOTOR_ON, 0);
+
+        set_density_latched_at_motor_on(true);
+        let settings = configured_settings(DriveSelectState::A, Density::High);
+        set_density_latched_at_motor_on(false);
+
+        // The flag must not disturb the other settings
+        assert_ne!(settings & DENSITY_LATCHED_AT_MOTOR_ON, 0);
+        assert_eq!(
+            settings & !DENSITY_LATCHED_AT_MOTOR_ON,
+            configured_settings(DriveSelectState::A, Density::High)
+        );
+    }
+
+    #[test]
+    fn head_settle_time_test() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let settings = configured_settings(DriveSelectState::A, Density::SingleDouble);
+        assert_eq!((settings >> 8) & 0xff, DEFAULT_HEAD_SETTLE_MS);
+
+        set_head_settle_time(MAX_HEAD_SETTLE_MS).unwrap();
+        let settings = configured_settings(DriveSelectState::A, Density::SingleDouble);
+        assert_eq!((settings >> 8) & 0xff, MAX_HEAD_SETTLE_MS);
+
+        // Rejected values keep the previous setting
+        assert!(set_head_settle_time(0).is_err());
+        assert!(set_head_settle_time(MAX_HEAD_SETTLE_MS + 1).is_err());
+        assert_eq!(HEAD_SETTLE_MS.load(Ordering::Relaxed), MAX_HEAD_SETTLE_MS);
+
+        set_head_settle_time(DEFAULT_HEAD_SETTLE_MS).unwrap();
+    }
+
+    #[test]
+    fn write_recovery_test() {
+        let usb = RecordingTransport::answering(&[b"WriteRecovery 588"]);
+        assert_eq!(measure_write_recovery(&usb, 10, 1).unwrap(), 588);
+        assert_eq!(usb.commands(), [[0x1234_0011, 10 | (1 << 8)]]);
+
+        let usb = RecordingTransport::answering(&[b"Fail WriteProtected"]);
+        let error = measure_write_recovery(&usb, 10, 0).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::WriteProtected)
+        ));
+
+        let usb = RecordingTransport::answering(&[b"Fail NoDriveSelected"]);
+        let error = measure_write_recovery(&usb, 10, 0).unwrap_err();
+        assert!(error.to_string().ends_with("NoDriveSelected"));
+
+        // Invalid positions are refused before anything is sent
+        let usb = RecordingTransport::default();
+        assert!(measure_write_recovery(&usb, 10, 2).is_err());
+        assert!(usb.commands().is_empty());
+    }
+
+    #[test]
+    fn operation_deadline_test() {
+        assert!(set_operation_timeout(0).is_err());
+
+        let usb = RecordingTransport::default();
+        check_deadline(&usb, None).unwrap();
+        check_deadline(&usb, Some((Instant::now() + Duration::from_secs(60), 60))).unwrap();
+        assert!(!usb.aborted.get());
+
+        // An exceeded deadline aborts the operation of the device
+        let error = check_deadline(&usb, Some((Instant::now(), 60))).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ToolError>(),
+            Some(ToolError::OperationTimeout { seconds: 60 })
+        ));
+        assert!(usb.aborted.get());
+        assert!(usb.commands().is_empty());
+    }
+
+    #[test]
+    fn query_position_test() {
+        let usb = RecordingTransport::answering(&[b"Position 42 0", b"Position Unknown 3"]);
+        let status = query_position(&usb).unwrap();
+        assert_eq!((status.cylinder, status.failed_seeks), (Some(42), 0));
+        // The position is lost before the first recalibration
+        let status = query_position(&usb).unwrap();
+        assert_eq!((status.cylinder, status.failed_seeks), (None, 3));
+        assert_eq!(usb.commands(), [[0x1234_0010], [0x1234_0010]]);
+
+        let usb = RecordingTransport::answering(&[b"Position 42"]);
+        assert!(query_position(&usb).is_err());
+    }
+
+    #[test]
+    fn disk_present_test() {
+        let usb = RecordingTransport::answering(&[b"DriveStatus 0 1"]);
+        assert_eq!(is_disk_present(&usb).unwrap(), Some(true));
+        assert_eq!(usb.commands().len(), 1);
+
+        // A missing disk is checked again after stepping the head
+        let usb = RecordingTransport::answering(&[
+            b"DriveStatus 0 0",
+            b"Recalibrated",
+            b"DriveStatus 0 1",
+        ]);
+        assert_eq!(is_disk_present(&usb).unwrap(), Some(true));
+        let commands = usb.commands();
+        let command_ids: Vec<u32> = commands.iter().filter_map(|f| f.first().copied()).collect();
+        assert_eq!(command_ids, [0x1234_0007, 0x1234_000F, 0x1234_0007]);
+
+        let usb = RecordingTransport::answering(&[
+            b"DriveStatus 0 0",
+            b"Recalibrated",
+            b"DriveStatus 0 0",
+        ]);
+        assert_eq!(is_disk_present(&usb).unwrap(), Some(false));
+
+        // Older firmware doesn't know the disk change signal
+        let usb = RecordingTransport::answering(&[b"DriveStatus 0"]);
+        assert_eq!(is_disk_present(&usb).unwrap(), None);
+    }
+
+    #[test]
+    fn step_rate_test() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let settings = configured_settings(DriveSelectState::A, Density::SingleDouble);
+        assert_eq!((settings >> 16) & 0xff, DEFAULT_STEP_RATE_MS);
+
+        set_step_rate(12).unwrap();
+        let settings = configured_settings(DriveSelectState::A, Density::SingleDouble);
+        assert_eq!((settings >> 16) & 0xff, 12);
+
+        // The drives are not specified for faster steps
+        assert!(set_step_rate(DEFAULT_STEP_RATE_MS - 1).is_err());
+        assert!(set_step_rate(MAX_STEP_RATE_MS + 1).is_err());
+        assert_eq!(STEP_RATE_MS.load(Ordering::Relaxed), 12);
+
+        set_step_rate(DEFAULT_STEP_RATE_MS).unwrap();
+    }
+
+    #[test]
+    fn max_cylinder_test() {
+        let _lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Every cylinder the drive can reach is allowed, but only standard ones are read
+        assert_eq!(max_cylinder(), MAX_CYLINDER);
+        assert_eq!(max_read_cylinder(), DEFAULT_MAX_CYLINDER);
+
+        set_max_cylinder(81).unwrap();
+        assert_eq!(max_read_cylinder(), 81);
+        assert!(ensure_cylinder_allowed(81).is_ok());
+        assert!(ensure_cylinder_allowed(82).is_err());
+
+        assert!(set_max_cylinder(DEFAULT_MAX_CYLINDER - 1).is_err());
+        assert!(set_max_cylinder(MAX_CYLINDER + 1).is_err());
+        assert_eq!(max_cylinder(), 81);
+
+        let mut capabilities = Capabilities {
+            firmware_version: [0, 3, 0],
+            features: 0,
+            max_cylinder: 81,
+            densities: 0,
+            sensors: 0,
+            free_heap: 0,
+        };
+        assert!(ensure_max_cylinder_supported(&capabilities).is_ok());
+        capabilities.max_cylinder = 80;
+        assert!(ensure_max_cylinder_supported(&capabilities).is_err());
+
+        MAX_CYLINDER_SETTING.store(MAX_CYLINDER, Ordering::Relaxed);
+        MAX_CYLINDER_CONFIGURED.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn write_track_header_test() {
+        let track = |entries: usize| {
+            let densitymap = (0..entries)
+                .map(|_| DensityMapEntry {
+                    number_of_cellbytes: 10,
+                    cell_size: PulseDuration(84),
+                })
+                .collect();
+            RawTrack::new(0, 0, vec![0; entries * 10], densitymap, Encoding::MFM)
+        };
+
+        // Ten entries fill the first transfer behind the six words of the header
+        let usb = RecordingTransport::default();
+        write_raw_track(&usb, &track(10)).unwrap();
+        let written = usb.written.borrow();
+        let command = written.first().unwrap();
+        assert_eq!(command.len(), 64);
+        assert!(command.starts_with(&WRITE_TRACK_COMMAND.to_le_bytes()));
+        assert_eq!(command.get(20), Some(&10));
+        assert_eq!(written.len(), 3);
+
+        // An eleventh entry would be cut off
+        let usb = RecordingTransport::default();
+        let error = write_raw_track(&usb, &track(11)).unwrap_err();
+        assert!(error.to_string().contains("only has room for 10"));
+        assert!(usb.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn write_track_supported_test() {
+        let mut capabilities = Capabilities {
+            firmware_version: [0, 3, 0],
+            features: FEATURE_VERIFY_WINDOWS,
+            max_cylinder: 83,
+            densities: 0,
+            sensors: 0,
+            free_heap: 0,
+        };
+        assert!(ensure_write_track_supported(Some(&capabilities)).is_ok());
+
+        // Firmware which would ignore the write command
+        capabilities.features = 0;
+        assert!(ensure_write_track_supported(Some(&capabilities)).is_err());
+        assert!(ensure_write_track_supported(None).is_err());
+    }
+
+    #[test]
+    fn write_track_abort_test() {
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: 200,
+            cell_size: PulseDuration(84),
+        }];
+        let track = RawTrack::new(0, 0, vec![0; 200], densitymap, Encoding::MFM);
+
+        // The complete transfer needs no abort
+        let usb = RecordingTransport::default();
+        write_raw_track(&usb, &track).unwrap();
+        assert_eq!(usb.written.borrow().len(), 5);
+        assert!(!usb.aborted.get());
+
+        // The device must forget the track if the transfer breaks off behind the second block
+        let usb = RecordingTransport {
+            failing_transfer: Some(3),
+            ..Default::default()
+        };
+        assert!(write_raw_track(&usb, &track).is_err());
+        assert_eq!(usb.written.borrow().len(), 3);
+        assert!(usb.aborted.get());
+
+        // The header alone doesn't need it
+        let usb = RecordingTransport {
+            failing_transfer: Some(0),
+            ..Default::default()
+        };
+        assert!(write_raw_track(&usb, &track).is_err());
+        assert!(!usb.aborted.get());
+    }
+
+    #[test]
+    fn motor_off_delay_test() {
+        let usb = RecordingTransport::default();
+        set_motor_off_delay(&usb, 3000).unwrap();
+        assert_eq!(usb.commands(), [[0x1234_0006, 3000]]);
+    }
+
+    #[test]
+    fn read_high_resolution_test() {
+        // Every timing is transferred with two bytes. An empty packet ends the track.
+        let packet: Vec<u8> = [0x34, 0x12].repeat(32);
+        let usb = RecordingTransport::answering(&[&packet, &packet, &[]]);
+        let timings = read_raw_track_high_resolution(&usb, 2, 1, true, 5000).unwrap();
+        assert_eq!(timings, vec![PulseDuration(0x1234); 64]);
+        assert_eq!(
+            usb.commands().first().unwrap().get(..3).unwrap(),
+            [0x1234_0004, 2 | (1 << 8) | (1 << 9) | (1 << 10), 5000]
+        );
+
+        // Errors of the device are reported as text
+        let usb = RecordingTransport::answering(&[b"Fail NoDriveSelected"]);
+        let error = read_raw_track_high_resolution(&usb, 2, 1, true, 5000).unwrap_err();
+        assert_eq!(error.to_string(), "Fail NoDriveSelected");
+    }
+
+    #[test]
+    fn write_track_skip_pulses_test() {
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: 10,
+            cell_size: PulseDuration(84),
+        }];
+        let mut track = RawTrack::new(3, 1, vec![0; 10], densitymap, Encoding::MFM);
+        track.verify_windows.skip_pulses = 30;
+
+        let usb = RecordingTransport::default();
+        write_raw_track(&usb, &track).unwrap();
+        let configuration = *usb.commands().first().unwrap().get(3).unwrap();
+        assert_eq!(configuration >> 24, 30);
+        assert_eq!(configuration & 0x1ff, 3 | (1 << 8));
+    }
+
+    #[test]
+    fn write_track_write_only_test() {
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: 10,
+            cell_size: PulseDuration(84),
+        }];
+        let mut track = RawTrack::new(3, 1, vec![0; 10], densitymap, Encoding::MFM);
+        track.verify_windows.read_data = 0x7fff;
+
+        let windows = |track: &RawTrack| {
+            let usb = RecordingTransport::default();
+            write_raw_track(&usb, track).unwrap();
+            *usb.commands().first().unwrap().get(4).unwrap()
+        };
+
+        assert_eq!(windows(&track) & WRITE_WITHOUT_VERIFY, 0);
+        track.write_only = true;
+        // The flag must not disturb the read data window
+        let packed = windows(&track);
+        assert_eq!(packed & WRITE_WITHOUT_VERIFY, WRITE_WITHOUT_VERIFY);
+        assert_eq!((packed & !WRITE_WITHOUT_VERIFY) >> 16, 0x7fff);
+        assert_eq!(packed & 0xffff, VerifyWindows::DEFAULT_COMPARE as u32);
+
+        // The highest bit is taken by the flag
+        track.verify_windows.read_data = 0x8000;
+        assert!(write_raw_track(&RecordingTransport::default(), &track).is_err());
+    }
+
+    #[test]
+    fn write_track_gap_generator_test() {
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: 10,
+            cell_size: PulseDuration(84),
+        }];
+        let mut track = RawTrack::new(3, 1, vec![0; 10], densitymap, Encoding::MFM);
+
+        // Provides the non flux reversal flag and the disabled flag of the header
+        let gap_flags = |track: &RawTrack| {
+            let usb = RecordingTransport::default();
+            write_raw_track(&usb, track).unwrap();
+            let header = usb.commands().swap_remove(0);
+            let word = |index: usize| *header.get(index).unwrap();
+            (word(3) & 0x200, word(5) & GAP_GENERATOR_DISABLED)
+        };
+
+        // Without a choice the generator follows the content of the track
+        assert_eq!(gap_flags(&track), (0, 0));
+        track.has_non_flux_reversal_area = true;
+        assert_eq!(gap_flags(&track), (0x200, 0));
+
+        track.gap_generator = Some(GapGenerator::WeakBits);
+        assert_eq!(gap_flags(&track), (0, 0));
+        track.gap_generator = Some(GapGenerator::Disabled);
+        assert_eq!(gap_flags(&track), (0, GAP_GENERATOR_DISABLED));
+        track.has_non_flux_reversal_area = false;
+        track.gap_generator = Some(GapGenerator::NonFluxReversal);
+        assert_eq!(gap_flags(&track), (0x200, 0));
+    }
+
+    #[test]
+    fn write_track_settle_delay_test() {
+        let densitymap = vec![DensityMapEntry {
+            number_of_cellbytes: 10,
+            cell_size: PulseDuration(84),
+        }];
+        let mut track = RawTrack::new(3, 1, vec![0; 10], densitymap, Encoding::MFM);
+        track.verify_windows.settle_delay_us = 2500;
+
+        let usb = RecordingTransport::default();
+        write_raw_track(&usb, &track).unwrap();
+        let configuration = *usb.commands().first().unwrap().get(3).unwrap();
+        assert_eq!((configuration >> 10) & 0x3f, 25);
+        assert_eq!(configuration & 0x1ff, 3 | (1 << 8));
+
+        // Six bits are not enough for longer delays
+        track.verify_windows.settle_delay_us = VerifyWindows::MAX_SETTLE_DELAY_US + 100;
+        let usb = RecordingTransport::default();
+        assert!(write_raw_track(&usb, &track).is_err());
+        assert!(usb.written.borrow().is_empty());
+    }
+}
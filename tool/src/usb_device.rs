@@ -1,14 +1,19 @@
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, Context};
-use rusb::{Device, DeviceDescriptor, DeviceHandle, Direction, TransferType, UsbContext};
-use util::{USB_PID, USB_VID};
+use anyhow::Context;
+use rusb::{
+    Device, DeviceDescriptor, DeviceHandle, Direction, Recipient, RequestType, TransferType,
+    UsbContext,
+};
+use util::{USB_ABORT_REQUEST, USB_PID, USB_VID};
+
+use crate::error::ToolError;
 
 fn open_usb_device<T: UsbContext>(
     context: &mut T,
     vid: u16,
     pid: u16,
-) -> anyhow::Result<(Device<T>, DeviceDescriptor, DeviceHandle<T>)> {
+) -> Result<(Device<T>, DeviceDescriptor, DeviceHandle<T>), ToolError> {
     let devices = context.devices()?;
 
     for device in devices.iter() {
@@ -20,16 +25,52 @@ fn open_usb_device<T: UsbContext>(
         };
 
         if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-            match device.open() {
-                Ok(handle) => {
-                    return Ok((device, device_desc, handle));
-                }
-                Err(e) => bail!("Device found but failed to open: {}", e),
-            }
+            let handle = device.open().map_err(ToolError::DeviceOpenFailed)?;
+            return Ok((device, device_desc, handle));
         }
     }
 
-    Err(anyhow!("Unable to find USB Floppy Tracer"))
+    Err(ToolError::DeviceNotFound)
+}
+
+/// Requests the device to abort the currently running operation.
+/// Works even if the bulk endpoints are busy.
+pub fn abort_operation(handles: &(DeviceHandle<rusb::Context>, u8, u8)) -> Result<(), ToolError> {
+    let (handle, _endpoint_in, _endpoint_out) = handles;
+
+    handle.write_control(
+        rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device),
+        USB_ABORT_REQUEST,
+        0,
+        0,
+        &[],
+        Duration::from_millis(100),
+    )?;
+    Ok(())
+}
+
+/// Bulk transfers to and from the device. Allows to replace the device in tests.
+pub trait UsbTransport {
+    fn write_bulk(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_bulk(&self, data: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    /// Requests the device to abort the currently running operation
+    fn abort(&self) -> Result<(), ToolError>;
+}
+
+impl UsbTransport for (DeviceHandle<rusb::Context>, u8, u8) {
+    fn write_bulk(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        let (handle, _endpoint_in, endpoint_out) = self;
+        handle.write_bulk(*endpoint_out, data, timeout)
+    }
+
+    fn read_bulk(&self, data: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let (handle, endpoint_in, _endpoint_out) = self;
+        handle.read_bulk(*endpoint_in, data, timeout)
+    }
+
+    fn abort(&self) -> Result<(), ToolError> {
+        abort_operation(self)
+    }
 }
 
 pub fn clear_buffers(handles: &(DeviceHandle<rusb::Context>, u8, u8)) {
@@ -37,6 +78,11 @@ pub fn clear_buffers(handles: &(DeviceHandle<rusb::Context>, u8, u8)) {
     let timeout = Duration::from_millis(10);
     let mut in_buf = [0u8; 64];
 
+    // Stop whatever might still be running from an aborted session
+    if let Err(e) = abort_operation(handles) {
+        println!("Unable to abort the running operation: {e}");
+    }
+
     loop {
         let Ok(size) = handle.read_bulk(*endpoint_in, &mut in_buf, timeout) else {
             return;
@@ -45,7 +91,7 @@ pub fn clear_buffers(handles: &(DeviceHandle<rusb::Context>, u8, u8)) {
     }
 }
 
-pub fn init_usb() -> anyhow::Result<(DeviceHandle<rusb::Context>, u8, u8)> {
+pub fn init_usb() -> Result<(DeviceHandle<rusb::Context>, u8, u8), ToolError> {
     let mut context = rusb::Context::new()?;
 
     let (device, _device_desc, mut handle) = open_usb_device(&mut context, USB_VID, USB_PID)?;
@@ -1,19 +1,167 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
+    fmt,
     fs::File,
     io::{self, BufRead},
+    ops::RangeInclusive,
     time::Duration,
 };
 
-use anyhow::{bail, Context};
+use anyhow::{bail, ensure, Context};
 use rusb::DeviceHandle;
 use util::Density;
 
 use crate::{
     rawtrack::{RawImage, RawTrack},
-    usb_commands::write_raw_track,
+    usb_commands::{check_reception, parse_reception, wait_for_answer, write_raw_track, UsbAnswer},
+    usb_device::UsbTransport,
 };
 
+/// Added to the write precompensation of double density disks written with a drive
+/// which only supports high density media. Such drives write with a lower current.
+pub const HD_DRIVE_DD_MEDIA_EXTRA_PRECOMPENSATION: u32 = 4;
+
+/// Additional passes over the tracks which failed to verify
+/// if a drive only supports high density media
+pub const HD_DRIVE_DD_MEDIA_EXTRA_PASSES: usize = 2;
+
+/// Bundles the parameters of writing for common scenarios.
+/// Parameters given explicitly on the command line take precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteProfile {
+    /// Old or sensitive media. Stronger precompensation, more time and more attempts.
+    Conservative,
+    /// The defaults of every parameter
+    Normal,
+    /// Fresh media written in bulk. Less waiting and a looser verification of inner tracks.
+    Aggressive,
+}
+
+impl WriteProfile {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "conservative" => Some(Self::Conservative),
+            "normal" => Some(Self::Normal),
+            "aggressive" => Some(Self::Aggressive),
+            _ => None,
+        }
+    }
+
+    /// Added to the write precompensation of every track without flux timings
+    #[must_use]
+    pub const fn extra_precompensation(self) -> u32 {
+        match self {
+            Self::Conservative => 2,
+            Self::Normal | Self::Aggressive => 0,
+        }
+    }
+
+    /// Additional passes over the tracks which failed to verify
+    #[must_use]
+    pub const fn extra_passes(self) -> usize {
+        match self {
+            Self::Conservative => 2,
+            Self::Normal | Self::Aggressive => 0,
+        }
+    }
+
+    /// Additional verify threshold at the last cylinder in percent of the cell size
+    #[must_use]
+    pub const fn verify_threshold_extra(self) -> Option<u32> {
+        match self {
+            Self::Conservative | Self::Normal => None,
+            Self::Aggressive => Some(5),
+        }
+    }
+
+    /// Wait after writing a track before verifying it
+    #[must_use]
+    pub const fn settle_delay_us(self) -> Option<u32> {
+        match self {
+            Self::Conservative => Some(500),
+            Self::Normal | Self::Aggressive => None,
+        }
+    }
+
+    /// Wait after stepping before the head is used
+    #[must_use]
+    pub const fn head_settle_ms(self) -> Option<u32> {
+        match self {
+            Self::Conservative => Some(30),
+            Self::Normal => None,
+            Self::Aggressive => Some(15),
+        }
+    }
+}
+
+/// Cell sizes of high density, double density on 5.25" and double density disks.
+/// Checked for gaps in the configuration in addition to the cell sizes of the samples.
+const USUAL_CELL_SIZES: [u32; 3] = [84, 140, 168];
+
+/// Upper limit of the tested values. Depends on the bit cell size of the disk.
+fn maximum_write_precompensation(image: &RawImage) -> anyhow::Result<u32> {
+    Ok(match (image.density, image.disk_type) {
+        (Density::High, util::DiskType::Inch3_5) => 12,
+        (Density::SingleDouble, util::DiskType::Inch3_5) => 22,
+        (Density::SingleDouble, util::DiskType::Inch5_25) => 14,
+        (_, _) => bail!("Unsupported for write precompensation!"),
+    })
+}
+
+/// Writes a single track with every write precompensation value and prints
+/// the maximum error of each verification as soon as it is available.
+/// Useful to examine a problematic track without a full calibration.
+pub fn precompensation_sweep(
+    usb_handles: &impl UsbTransport,
+    mut image: RawImage,
+    cylinder: u32,
+    head: u32,
+) -> anyhow::Result<Vec<(u32, Option<u32>)>> {
+    let maximum_write_precompensation = maximum_write_precompensation(&image)?;
+
+    let track = image
+        .tracks
+        .iter_mut()
+        .find(|f| f.cylinder == cylinder && f.head == head)
+        .with_context(|| format!("Image has no track {cylinder} {head}"))?;
+    ensure!(
+        track.flux_timings.is_none(),
+        "Flux timings are written without write precompensation"
+    );
+    // The error is only known after verification
+    track.write_only = false;
+
+    let mut results = Vec::new();
+
+    for write_precomp in 0..maximum_write_precompensation {
+        track.write_precompensation = write_precomp;
+        write_raw_track(usb_handles, track)?;
+
+        // Wait for the result of this value before trying the next one
+        let max_err = loop {
+            match wait_for_answer(usb_handles)? {
+                UsbAnswer::GotCmd { reception } => check_reception(usb_handles, track, reception)?,
+                UsbAnswer::WrittenAndVerified { max_err, .. } => break Some(max_err),
+                UsbAnswer::Fail { .. } => break None,
+                UsbAnswer::WriteProtected => bail!("Disk is write protected!"),
+                UsbAnswer::OutOfMemory { cylinder, head, .. } => {
+                    bail!("Track {cylinder} {head} doesn't fit into the memory of the device")
+                }
+                UsbAnswer::VerifyHistogram(_) | UsbAnswer::TrackShortened { .. } => {}
+            }
+        };
+
+        match max_err {
+            Some(max_err) => println!("write_precomp:{write_precomp} max_err:{max_err}"),
+            None => println!("write_precomp:{write_precomp} failed"),
+        }
+        results.push((write_precomp, max_err));
+    }
+
+    Ok(results)
+}
+
 pub fn calibration(
     usb_handles: &(DeviceHandle<rusb::Context>, u8, u8),
     mut image: RawImage,
@@ -25,18 +173,15 @@ pub fn calibration(
     // we want to filter especially that out here
     let cylinders_to_calibrate = vec![0, 10, 20, 30, 39, 40, 41, 42, 43, 44, 50, 60, 70, 75, 79];
 
-    let maximum_write_precompensation = match (image.density, image.disk_type) {
-        (Density::High, util::DiskType::Inch3_5) => 12,
-        (Density::SingleDouble, util::DiskType::Inch3_5) => 22,
-        (Density::SingleDouble, util::DiskType::Inch5_25) => 14,
-        (_, _) => bail!("Unsupported for write precompensation!"),
-    };
+    let maximum_write_precompensation = maximum_write_precompensation(&image)?;
 
     let mut results: HashMap<usize, Vec<usize>> = HashMap::new();
 
+    // The written track is required to confirm its reception
     let process_answer = |inner_results: &mut HashMap<usize, Vec<usize>>,
-                          last: bool|
+                          written_track: Option<&RawTrack>|
      -> anyhow::Result<()> {
+        let last = written_track.is_none();
         let timeout = Duration::from_secs(10);
 
         // TODO copy pasta
@@ -75,7 +220,12 @@ pub fn calibration(
                         break;
                     }
                 }
-                "GotCmd" => break, // Continue with next track!
+                "GotCmd" => {
+                    if let Some(track) = written_track {
+                        check_reception(usb_handles, track, parse_reception(&response_split)?)?;
+                    }
+                    break; // Continue with next track!
+                }
                 "Fail" => {
                     println!(
                         "Failed writing track {} head {} - num_writes:{}, num_reads:{}",
@@ -96,6 +246,12 @@ pub fn calibration(
                     }
                 }
                 "WriteProtected" => bail!("Disk is write protected!"),
+                "OutOfMemory" => bail!(
+                    "Track {} {} doesn't fit into the memory of the device",
+                    ensure_index!(response_split[1]),
+                    ensure_index!(response_split[2]),
+                ),
+                "Histogram" => {}
                 _ => bail!("Unexpected answer from device: {}", response_text),
             }
         }
@@ -122,11 +278,11 @@ pub fn calibration(
             track.write_precompensation = write_precomp;
             write_raw_track(usb_handles, track)?;
 
-            process_answer(&mut results, false)?;
+            process_answer(&mut results, Some(&*track))?;
         }
     }
     // get last answer
-    process_answer(&mut results, true)?;
+    process_answer(&mut results, None)?;
 
     println!("{results:?}");
 
@@ -161,14 +317,80 @@ struct Sample {
     wprecomp: u32,
 }
 
+/// How a write precompensation was derived from the samples of the configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrecompDecision {
+    pub cellsize: u32,
+    pub cylinder: u32,
+    /// Value interpolated between the cylinders of the samples with the next smaller
+    /// or equal cell size and the cell size of these samples
+    pub left: Option<(f32, u32)>,
+    /// Same as `left` for the samples with the next larger or equal cell size
+    pub right: Option<(f32, u32)>,
+    /// Interpolated between `left` and `right`
+    pub result: Option<u32>,
+}
+
+impl fmt::Display for PrecompDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cell size {} cylinder {}", self.cellsize, self.cylinder)?;
+        if let Some((value, cellsize)) = self.left {
+            write!(f, ", left {value:.1} at cell size {cellsize}")?;
+        }
+        if let Some((value, cellsize)) = self.right {
+            write!(f, ", right {value:.1} at cell size {cellsize}")?;
+        }
+        match self.result {
+            Some(result) => write!(f, " -> {result}"),
+            None => write!(f, " -> no samples"),
+        }
+    }
+}
+
+/// Diagnostics of a write precompensation configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WprecompValidation {
+    pub samples: usize,
+    /// Line number and content of every line which is neither a sample nor a comment
+    pub ignored_lines: Vec<(usize, String)>,
+    /// Cylinders covered by the samples of every cell size
+    pub coverage: Vec<(u32, RangeInclusive<u32>)>,
+    /// Cylinders of a cell size for which no write precompensation can be calculated
+    pub gaps: Vec<(u32, RangeInclusive<u32>)>,
+}
+
+impl fmt::Display for WprecompValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} samples parsed", self.samples)?;
+        for (number, line) in &self.ignored_lines {
+            writeln!(f, "Line {number} ignored as it isn't 3 numbers: {line}")?;
+        }
+        for (cellsize, cylinders) in &self.coverage {
+            writeln!(
+                f,
+                "Cell size {cellsize} has samples for cylinders {} to {}",
+                cylinders.start(),
+                cylinders.end()
+            )?;
+        }
+        for (cellsize, cylinders) in &self.gaps {
+            writeln!(
+                f,
+                "No write precompensation for cell size {cellsize} at cylinders {} to {}",
+                cylinders.start(),
+                cylinders.end()
+            )?;
+        }
+        Ok(())
+    }
+}
+
 pub struct WritePrecompDb {
     samples: Vec<Sample>,
 }
 
 impl WritePrecompDb {
     pub fn new() -> anyhow::Result<Self> {
-        let mut samples = Vec::new();
-
         let wprecomp_path = home::home_dir()
             .context("Home Directoy not available")?
             .join(".usbfloppytracer/wprecomp.cfg");
@@ -179,9 +401,31 @@ impl WritePrecompDb {
             f
         })?;
 
-        let lines = io::BufReader::new(file).lines();
+        let (db, _) = Self::parse(io::BufReader::new(file))?;
+        Ok(db)
+    }
+
+    /// Parses every line with 3 numbers as sample.
+    /// All other lines are provided with their line number.
+    /// Lines which aren't valid UTF-8 are reported and parsed with the invalid parts replaced.
+    fn parse(reader: impl BufRead) -> anyhow::Result<(Self, Vec<(usize, String)>)> {
+        let mut samples = Vec::new();
+        let mut ignored_lines = Vec::new();
+
+        for (index, line) in reader.split(b'\n').enumerate() {
+            let line = line?;
+            let line = match String::from_utf8(line) {
+                Ok(line) => line,
+                Err(err) => {
+                    println!(
+                        "Line {} of the write precompensation config isn't valid UTF-8",
+                        index + 1
+                    );
+                    String::from_utf8_lossy(err.as_bytes()).into_owned()
+                }
+            };
+            let line = line.trim_end_matches('\r').to_owned();
 
-        for line in lines.flatten() {
             let number_parts: Vec<u32> = line
                 .split_ascii_whitespace()
                 .filter_map(|d| d.parse().ok())
@@ -197,12 +441,72 @@ impl WritePrecompDb {
                     cylinder,
                     wprecomp,
                 });
+            } else {
+                ignored_lines.push((index + 1, line));
             }
         }
 
         samples.sort();
 
-        Ok(Self { samples })
+        Ok((Self { samples }, ignored_lines))
+    }
+
+    /// Parses a configuration like `new` but reports what was made of it instead of
+    /// silently skipping lines. Gaps are searched up to `max_cylinder`.
+    pub fn validate(reader: impl BufRead, max_cylinder: u32) -> anyhow::Result<WprecompValidation> {
+        let (db, ignored_lines) = Self::parse(reader)?;
+
+        // Empty lines and comments are not a mistake
+        let ignored_lines = ignored_lines
+            .into_iter()
+            .filter(|(_, line)| {
+                let line = line.trim();
+                !line.is_empty() && !line.starts_with('#')
+            })
+            .collect();
+
+        let sample_cellsizes: BTreeSet<u32> = db.samples.iter().map(|f| f.cellsize).collect();
+
+        let coverage = sample_cellsizes
+            .iter()
+            .filter_map(|cellsize| {
+                let cylinders = db
+                    .samples
+                    .iter()
+                    .filter(|f| f.cellsize == *cellsize)
+                    .map(|f| f.cylinder);
+                Some((*cellsize, cylinders.clone().min()?..=cylinders.max()?))
+            })
+            .collect();
+
+        let mut gaps: Vec<(u32, RangeInclusive<u32>)> = Vec::new();
+        let cellsizes: BTreeSet<u32> = sample_cellsizes
+            .into_iter()
+            .chain(USUAL_CELL_SIZES)
+            .collect();
+        for cellsize in cellsizes {
+            for cylinder in 0..=max_cylinder {
+                if db.calculate(cellsize, cylinder).is_some() {
+                    continue;
+                }
+                // Neighboring cylinders are reported as one range
+                match gaps.last_mut() {
+                    Some((gap_cellsize, cylinders))
+                        if *gap_cellsize == cellsize && *cylinders.end() + 1 == cylinder =>
+                    {
+                        *cylinders = *cylinders.start()..=cylinder;
+                    }
+                    _ => gaps.push((cellsize, cylinder..=cylinder)),
+                }
+            }
+        }
+
+        Ok(WprecompValidation {
+            samples: db.samples.len(),
+            ignored_lines,
+            coverage,
+            gaps,
+        })
     }
 
     fn lerp_left(&self, cellsize: u32, cylinder: u32) -> Option<(f32, u32)> {
@@ -264,22 +568,216 @@ impl WritePrecompDb {
 
     #[must_use]
     pub fn calculate(&self, cellsize: u32, cylinder: u32) -> Option<u32> {
+        self.explain(cellsize, cylinder).result
+    }
+
+    /// Same as `calculate` but also provides the interpolated samples
+    #[must_use]
+    pub fn explain(&self, cellsize: u32, cylinder: u32) -> PrecompDecision {
         // cell sizes are left to right, so the x axis
         // cylinders are top to bottom, so the y axis
-        let (left_result, left_cellsize) = self.lerp_left(cellsize, cylinder)?;
-        let (right_result, right_cellsize) = self.lerp_right(cellsize, cylinder)?;
+        let left = self.lerp_left(cellsize, cylinder);
+        let right = self.lerp_right(cellsize, cylinder);
+
+        let result = match (left, right) {
+            (Some((left_result, left_cellsize)), Some((_, right_cellsize)))
+                if left_cellsize == right_cellsize =>
+            {
+                Some(left_result.round() as u32)
+            }
+            (Some((left_result, left_cellsize)), Some((right_result, right_cellsize))) => {
+                let cellsize_factor =
+                    (cellsize - left_cellsize) as f32 / (right_cellsize - left_cellsize) as f32;
+
+                Some(
+                    (1.0 - cellsize_factor)
+                        .mul_add(left_result, cellsize_factor * right_result)
+                        .round() as u32,
+                )
+            }
+            _ => None,
+        };
+
+        PrecompDecision {
+            cellsize,
+            cylinder,
+            left,
+            right,
+            result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque};
 
-        if left_cellsize == right_cellsize {
-            return Some(left_result.round() as u32);
+    use super::*;
+    use crate::error::ToolError;
+    use util::{DensityMapEntry, DiskType, Encoding, PulseDuration, DEFAULT_MAX_CYLINDER};
+
+    /// Gives the scripted answers and records the configuration word of every track write
+    #[derive(Default)]
+    struct ScriptedTransport {
+        answers: RefCell<VecDeque<String>>,
+        write_configurations: RefCell<Vec<u32>>,
+    }
+
+    impl UsbTransport for ScriptedTransport {
+        fn write_bulk(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+            if data.starts_with(&util::WRITE_TRACK_COMMAND.to_le_bytes()) {
+                let configuration = data
+                    .get(12..16)
+                    .and_then(|f| f.try_into().ok())
+                    .ok_or(rusb::Error::InvalidParam)?;
+                self.write_configurations
+                    .borrow_mut()
+                    .push(u32::from_le_bytes(configuration));
+            }
+            Ok(data.len())
         }
 
-        let cellsize_factor =
-            (cellsize - left_cellsize) as f32 / (right_cellsize - left_cellsize) as f32;
+        fn read_bulk(&self, data: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+            let answer = self
+                .answers
+                .borrow_mut()
+                .pop_front()
+                .ok_or(rusb::Error::Timeout)?;
+            data.get_mut(..answer.len())
+                .ok_or(rusb::Error::Overflow)?
+                .copy_from_slice(answer.as_bytes());
+            Ok(answer.len())
+        }
+
+        fn abort(&self) -> Result<(), ToolError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_profile_test() {
+        assert_eq!(WriteProfile::from_name("fast"), None);
+
+        let conservative = WriteProfile::from_name("conservative").unwrap();
+        assert_eq!(conservative, WriteProfile::Conservative);
+        assert_eq!(conservative.extra_precompensation(), 2);
+        assert_eq!(conservative.extra_passes(), 2);
+        assert_eq!(conservative.settle_delay_us(), Some(500));
+
+        // The defaults of every parameter are kept
+        let normal = WriteProfile::from_name("normal").unwrap();
+        assert_eq!(normal.extra_precompensation(), 0);
+        assert_eq!(normal.extra_passes(), 0);
+        assert_eq!(normal.verify_threshold_extra(), None);
+        assert_eq!(normal.settle_delay_us(), None);
+        assert_eq!(normal.head_settle_ms(), None);
+
+        let aggressive = WriteProfile::from_name("aggressive").unwrap();
+        assert_eq!(aggressive.verify_threshold_extra(), Some(5));
+        assert!(aggressive.head_settle_ms() < conservative.head_settle_ms());
+    }
+
+    #[test]
+    fn precompensation_sweep_test() {
+        let track = |cylinder| {
+            let densitymap = vec![DensityMapEntry {
+                number_of_cellbytes: 100,
+                cell_size: PulseDuration(168),
+            }];
+            RawTrack::new(cylinder, 0, vec![0xaa; 100], densitymap, Encoding::MFM)
+        };
+        let image = || RawImage {
+            tracks: vec![track(78), track(79)],
+            density: Density::SingleDouble,
+            disk_type: DiskType::Inch3_5,
+        };
+
+        // Every value waits for its result. The fifth one fails to verify.
+        let usb = ScriptedTransport::default();
+        for write_precomp in 0..22 {
+            let max_err = 30 - write_precomp;
+            usb.answers.borrow_mut().push_back("GotCmd".into());
+            usb.answers.borrow_mut().push_back(if write_precomp == 4 {
+                "Fail 79 0 5 10 Mismatch".into()
+            } else {
+                format!("WrittenAndVerified 79 0 1 1 {max_err} {write_precomp}")
+            });
+        }
+
+        let results = precompensation_sweep(&usb, image(), 79, 0).unwrap();
+        assert_eq!(results.len(), 22);
+        assert_eq!(results.first(), Some(&(0, Some(30))));
+        assert_eq!(results.get(4), Some(&(4, None)));
+        assert_eq!(results.last(), Some(&(21, Some(9))));
+        assert!(usb.answers.borrow().is_empty());
+
+        // Only the requested track is written with a rising precompensation
+        let configurations = usb.write_configurations.borrow();
+        assert!(configurations.iter().all(|f| f & 0xff == 79));
+        let values: Vec<u32> = configurations.iter().map(|f| (f >> 16) & 0xff).collect();
+        assert_eq!(values, (0..22).collect::<Vec<u32>>());
+
+        assert!(precompensation_sweep(&ScriptedTransport::default(), image(), 80, 0).is_err());
+    }
+
+    #[test]
+    fn explain_test() {
+        let config = "151  0  4
+151 30  8
+168 10 9
+";
+        let (db, _) = WritePrecompDb::parse(config.as_bytes()).unwrap();
+
+        // Samples of the same cell size are only interpolated between the cylinders
+        let decision = db.explain(151, 15);
+        assert_eq!(decision.left, Some((6.0, 151)));
+        assert_eq!(decision.right, decision.left);
+        assert_eq!(decision.result, Some(6));
+
+        let decision = db.explain(160, 15);
+        assert_eq!(decision.right, Some((9.0, 168)));
+        assert_eq!(decision.result, db.calculate(160, 15));
+        assert_eq!(
+            decision.to_string(),
+            "cell size 160 cylinder 15, left 6.0 at cell size 151, right 9.0 at cell size 168 -> 8"
+        );
+
+        // Nothing is known below the smallest cell size
+        let decision = db.explain(84, 15);
+        assert_eq!(decision.left, None);
+        assert_eq!(decision.result, None);
+        assert!(decision.to_string().ends_with("-> no samples"));
+    }
 
-        Some(
-            (1.0 - cellsize_factor)
-                .mul_add(left_result, cellsize_factor * right_result)
-                .round() as u32,
-        )
+    #[test]
+    fn validate_test() {
+        let config = "# This is a comment.
+151  0  4
+151 30  8
+
+151 43
+168 10 9
+";
+        let validation = WritePrecompDb::validate(config.as_bytes(), DEFAULT_MAX_CYLINDER).unwrap();
+        assert_eq!(validation.samples, 3);
+        assert_eq!(validation.ignored_lines, [(5, "151 43".to_string())]);
+        assert_eq!(validation.coverage, [(151, 0..=30), (168, 10..=10)]);
+
+        // Nothing is known below the smallest cell size
+        assert!(validation.gaps.contains(&(84, 0..=DEFAULT_MAX_CYLINDER)));
+        // Nothing is known in front of the first sample of the largest cell size
+        assert!(validation.gaps.contains(&(168, 0..=9)));
+        assert!(!validation.gaps.iter().any(|(cellsize, _)| *cellsize == 151));
+
+        // Gaps are searched up to the given cylinder
+        let validation = WritePrecompDb::validate(config.as_bytes(), 83).unwrap();
+        assert!(validation.gaps.contains(&(84, 0..=83)));
+
+        // A line which isn't valid UTF-8 doesn't hide the following ones
+        let config = b"151 0 4\r\n\xff\xfe\r\n151 30 8\r\n168 10 9 \xff\n";
+        let validation = WritePrecompDb::validate(&config[..], DEFAULT_MAX_CYLINDER).unwrap();
+        assert_eq!(validation.samples, 3);
+        assert_eq!(validation.ignored_lines.len(), 1);
+        assert_eq!(validation.ignored_lines.first().unwrap().0, 2);
     }
 }
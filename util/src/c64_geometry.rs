@@ -1,3 +1,5 @@
+use core::fmt;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct TrackConfiguration {
     pub cellsize: usize,
@@ -5,32 +7,108 @@ pub struct TrackConfiguration {
     pub gap_size: usize,
 }
 
+/// Sectors per track of the four speed zones, starting with the outermost zone
+pub const SECTORS_PER_ZONE: [u8; 4] = [21, 19, 18, 17];
+
+/// Number of tracks of a standard 1541 disk
+pub const STANDARD_TRACKS: usize = 35;
+
+/// Number of tracks of an extended disk. Tracks beyond 35 belong to the innermost zone.
+pub const EXTENDED_TRACKS: usize = 40;
+
 #[must_use]
 pub fn get_track_settings(track: usize) -> TrackConfiguration {
     assert_ne!(track, 0, "We are starting with 1 here!");
     if track <= 17 {
         TrackConfiguration {
             cellsize: 227,
-            sectors: 21,
+            sectors: SECTORS_PER_ZONE[0],
             gap_size: 8,
         }
     } else if track <= 24 {
         TrackConfiguration {
             cellsize: 245,
-            sectors: 19,
+            sectors: SECTORS_PER_ZONE[1],
             gap_size: 17,
         }
     } else if track <= 30 {
         TrackConfiguration {
             cellsize: 262,
-            sectors: 18,
+            sectors: SECTORS_PER_ZONE[2],
             gap_size: 12,
         }
     } else {
         TrackConfiguration {
             cellsize: 280,
-            sectors: 17,
+            sectors: SECTORS_PER_ZONE[3],
             gap_size: 9,
         }
     }
 }
+
+/// Number of sectors of a track, starting with track 1
+#[must_use]
+pub fn sectors_per_track(track: usize) -> u8 {
+    get_track_settings(track).sectors
+}
+
+/// Number of sectors of a disk with the given number of tracks
+#[must_use]
+pub fn sectors_total(tracks: usize) -> usize {
+    (1..=tracks)
+        .map(|f| usize::from(sectors_per_track(f)))
+        .sum()
+}
+
+/// Head position of a 1541 drive expressed as cylinder of the drive used for writing.
+/// An 80 track 5.25" drive has twice the track density of a 1541.
+/// So every cylinder is a half track of the 1541 and half tracks are just odd cylinders.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HalfTrack(pub u32);
+
+impl HalfTrack {
+    /// Number of the 1541 track, starting with 1. Half tracks provide the track below.
+    #[must_use]
+    pub fn track(&self) -> u32 {
+        (self.0 >> 1) + 1
+    }
+
+    #[must_use]
+    pub fn is_half_track(&self) -> bool {
+        self.0 & 1 != 0
+    }
+}
+
+impl fmt::Display for HalfTrack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_half_track() {
+            write!(f, "{}.5", self.track())
+        } else {
+            write!(f, "{}", self.track())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_track_test() {
+        assert_eq!(HalfTrack(0).track(), 1);
+        assert!(!HalfTrack(68).is_half_track());
+        assert_eq!(format!("{}", HalfTrack(68)), "35");
+        assert!(HalfTrack(69).is_half_track());
+        assert_eq!(format!("{}", HalfTrack(69)), "35.5");
+    }
+
+    #[test]
+    fn sectors_total_test() {
+        assert_eq!(sectors_per_track(1), 21);
+        assert_eq!(sectors_per_track(18), 19);
+        assert_eq!(sectors_per_track(25), 18);
+        assert_eq!(sectors_per_track(40), 17);
+        assert_eq!(sectors_total(STANDARD_TRACKS), 683);
+        assert_eq!(sectors_total(EXTENDED_TRACKS), 768);
+    }
+}
@@ -0,0 +1,140 @@
+use core::convert::TryInto;
+
+/// Command which requests the capabilities of the device
+pub const GET_CAPABILITIES: u32 = 0x1234_0012;
+
+/// Start of the answer. Distinguishes it from the text answers of the device.
+const MAGIC: &[u8; 4] = b"Caps";
+/// Increased if the layout of the answer changes
+const LAYOUT_VERSION: u8 = 1;
+
+/// Flux timings can be written without encoding
+pub const FEATURE_FLUX_WRITE: u32 = 1 << 0;
+/// The distribution of verify differences is reported on request
+pub const FEATURE_DETAILED_VERIFY: u32 = 1 << 1;
+/// The time the drive needs after writing can be measured
+pub const FEATURE_WRITE_RECOVERY: u32 = 1 << 2;
+/// The position of the head can be queried
+pub const FEATURE_HEAD_POSITION: u32 = 1 << 3;
+/// Write protection and disk presence can be queried without spinning the motor
+pub const FEATURE_DRIVE_STATUS: u32 = 1 << 4;
+/// Transfers with more than 64 KiB of flux are supported
+pub const FEATURE_EXTENDED_DENSITY_MAP: u32 = 1 << 5;
+/// The write commands accept `WriteGateMargins`
+pub const FEATURE_WRITE_GATE_MARGINS: u32 = 1 << 6;
+/// The duration of a rotation measured between the index pulses can be queried
+pub const FEATURE_ROTATION: u32 = 1 << 7;
+/// Encoded tracks are written with `WRITE_TRACK_COMMAND` which carries the verify windows
+pub const FEATURE_VERIFY_WINDOWS: u32 = 1 << 8;
+
+pub const SENSOR_INDEX: u8 = 1 << 0;
+pub const SENSOR_DISK_CHANGE: u8 = 1 << 1;
+pub const SENSOR_WRITE_PROTECT: u8 = 1 << 2;
+
+pub const DENSITY_SINGLE_DOUBLE: u8 = 1 << 0;
+pub const DENSITY_HIGH: u8 = 1 << 1;
+
+/// Properties of the device, reported once at startup to configure the host.
+///
+/// The answer has a fixed layout of `Capabilities::SIZE` bytes:
+///
+/// | Offset | Size | Content                              |
+/// |--------|------|--------------------------------------|
+/// | 0      | 4    | "Caps"                               |
+/// | 4      | 1    | Layout version                       |
+/// | 5      | 3    | Firmware version major, minor, patch |
+/// | 8      | 4    | Features, little endian              |
+/// | 12     | 1    | Last reachable cylinder              |
+/// | 13     | 1    | Supported densities                  |
+/// | 14     | 1    | Wired sensors                        |
+/// | 15     | 1    | Reserved                             |
+/// | 16     | 4    | Free heap in bytes, little endian    |
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub firmware_version: [u8; 3],
+    /// Combination of the `FEATURE_` flags
+    pub features: u32,
+    pub max_cylinder: u8,
+    /// Combination of the `DENSITY_` flags
+    pub densities: u8,
+    /// Combination of the `SENSOR_` flags
+    pub sensors: u8,
+    /// Memory of the device which is free when asked. Waiting tracks are stored there.
+    pub free_heap: u32,
+}
+
+impl Capabilities {
+    pub const SIZE: usize = 20;
+
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut data = [0; Self::SIZE];
+        data[0..4].copy_from_slice(MAGIC);
+        data[4] = LAYOUT_VERSION;
+        data[5..8].copy_from_slice(&self.firmware_version);
+        data[8..12].copy_from_slice(&self.features.to_le_bytes());
+        data[12] = self.max_cylinder;
+        data[13] = self.densities;
+        data[14] = self.sensors;
+        data[16..20].copy_from_slice(&self.free_heap.to_le_bytes());
+        data
+    }
+
+    /// Interprets an answer of the device.
+    /// None if it is no capabilities answer or has an unknown layout.
+    #[must_use]
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data: [u8; Self::SIZE] = data.try_into().ok()?;
+
+        if data[0..4] != *MAGIC || data[4] != LAYOUT_VERSION {
+            return None;
+        }
+
+        Some(Self {
+            firmware_version: [data[5], data[6], data[7]],
+            features: u32::from_le_bytes([data[8], data[9], data[10], data[11]]),
+            max_cylinder: data[12],
+            densities: data[13],
+            sensors: data[14],
+            free_heap: u32::from_le_bytes([data[16], data[17], data[18], data[19]]),
+        })
+    }
+
+    #[must_use]
+    pub const fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    #[must_use]
+    pub const fn has_sensor(&self, sensor: u8) -> bool {
+        self.sensors & sensor == sensor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_parse_test() {
+        let sample = [
+            b'C', b'a', b'p', b's', 1, 0, 2, 1, 0x3f, 0, 0, 0, 83, 3, 7, 0, 0x28, 0xa6, 0x01, 0,
+        ];
+        let capabilities = Capabilities::from_bytes(&sample).unwrap();
+
+        assert_eq!(capabilities.firmware_version, [0, 2, 1]);
+        assert!(capabilities.has_feature(FEATURE_FLUX_WRITE | FEATURE_EXTENDED_DENSITY_MAP));
+        assert_eq!(capabilities.max_cylinder, 83);
+        assert_eq!(capabilities.densities, DENSITY_SINGLE_DOUBLE | DENSITY_HIGH);
+        assert!(capabilities.has_sensor(SENSOR_INDEX | SENSOR_WRITE_PROTECT));
+        assert_eq!(capabilities.free_heap, 13509 * 8);
+        assert_eq!(capabilities.to_bytes(), sample);
+
+        // Text answers, truncated answers and unknown layouts
+        assert_eq!(Capabilities::from_bytes(b"Fail NoDriveSelected"), None);
+        assert_eq!(Capabilities::from_bytes(sample.get(..19).unwrap()), None);
+        let mut newer = sample;
+        *newer.get_mut(4).unwrap() = 2;
+        assert_eq!(Capabilities::from_bytes(&newer), None);
+    }
+}
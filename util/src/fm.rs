@@ -0,0 +1,217 @@
+use crate::Bit;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FmWord {
+    Enc(u8),
+    /// Data byte with missing clock bits. Precedes every header and data block.
+    AddressMark(u8),
+}
+
+/// Clock bits of the address marks in front of headers and data blocks
+pub const FM_ADDRESS_MARK_CLOCK: u8 = 0xC7;
+/// Clock bits of the index address mark
+pub const FM_INDEX_MARK_CLOCK: u8 = 0xD7;
+/// Regular data bytes have every clock bit set
+const FM_DATA_CLOCK: u8 = 0xff;
+
+/*
+ FM has a clock bit in front of every data bit. It is always set except for the address marks.
+
+ Id Address Mark 0xFE with clock 0xC7
+ Data  1 1 1 1 1 1 1 0   0xFE
+ Clk  1 1 0 0 0 1 1 1    0xC7
+ FM   1111010101111110   0xF57E
+
+ Data Address Mark 0xFB with clock 0xC7
+ FM   1111010101101111   0xF56F
+
+ Deleted Data Address Mark 0xF8 with clock 0xC7
+ FM   1111010101101010   0xF56A
+
+ Index Address Mark 0xFC with clock 0xD7
+ FM   1111011101111010   0xF77A
+*/
+
+/// Interleaves clock and data bits to the 16 cells of a byte
+#[must_use]
+pub fn fm_cells(clock: u8, data: u8) -> u16 {
+    (0..8).rev().fold(0, |cells, bit| {
+        (cells << 2) | (u16::from((clock >> bit) & 1) << 1) | u16::from((data >> bit) & 1)
+    })
+}
+
+/// Provides the address mark if the cells form one
+fn address_mark(cells: u16) -> Option<u8> {
+    [
+        (FM_ADDRESS_MARK_CLOCK, 0xFE),
+        (FM_ADDRESS_MARK_CLOCK, 0xFB),
+        (FM_ADDRESS_MARK_CLOCK, 0xF8),
+        (FM_INDEX_MARK_CLOCK, 0xFC),
+    ]
+    .iter()
+    .find(|(clock, data)| fm_cells(*clock, *data) == cells)
+    .map(|(_, data)| *data)
+}
+
+pub struct FmEncoder<T>
+where
+    T: FnMut(Bit),
+{
+    sink: T,
+}
+
+impl<T> FmEncoder<T>
+where
+    T: FnMut(Bit),
+{
+    pub fn new(sink: T) -> Self {
+        Self { sink }
+    }
+
+    fn feed_raw16(&mut self, mut val: u16) {
+        for _ in 0..16 {
+            (self.sink)(Bit((val & 0x8000) != 0));
+            val <<= 1;
+        }
+    }
+
+    pub fn feed_encoded8(&mut self, val: u8) {
+        self.feed_raw16(fm_cells(FM_DATA_CLOCK, val));
+    }
+
+    pub fn feed_address_mark(&mut self, val: u8, clock: u8) {
+        self.feed_raw16(fm_cells(clock, val));
+    }
+
+    pub fn feed(&mut self, inval: FmWord) {
+        match inval {
+            FmWord::Enc(x) => self.feed_encoded8(x),
+            FmWord::AddressMark(x) => self.feed_address_mark(x, FM_ADDRESS_MARK_CLOCK),
+        }
+    }
+}
+
+pub struct FmDecoder<T>
+where
+    T: FnMut(FmWord),
+{
+    sink: T,
+    sync_buffer: u16,
+    byte_buffer: u8,
+    shift_count: u8,
+    in_sync: bool,
+    zero_count: i32,
+}
+
+impl<T> FmDecoder<T>
+where
+    T: FnMut(FmWord),
+{
+    pub fn new(sink: T) -> Self {
+        Self {
+            sink,
+            sync_buffer: 0,
+            byte_buffer: 0,
+            shift_count: 0,
+            in_sync: false,
+            zero_count: 0,
+        }
+    }
+
+    pub fn feed(&mut self, cell: Bit) {
+        // Outside of address marks, every second cell is a set clock bit.
+        // Two zeros in a row are caused by a damaged area or a different encoding.
+        let invalid_pattern = !cell.0 && self.zero_count >= 1;
+
+        if cell.0 {
+            self.zero_count = 0;
+        } else {
+            self.zero_count += 1;
+        }
+
+        self.sync_buffer = (self.sync_buffer << 1) | u16::from(cell.0);
+        if let Some(mark) = address_mark(self.sync_buffer) {
+            self.in_sync = true;
+            self.shift_count = 0;
+            self.byte_buffer = 0;
+            (self.sink)(FmWord::AddressMark(mark));
+            return;
+        }
+
+        if self.in_sync && invalid_pattern {
+            // Wait for the next address mark instead of providing garbage.
+            self.in_sync = false;
+        }
+
+        if self.in_sync {
+            if (self.shift_count & 1) == 1 {
+                self.byte_buffer <<= 1;
+                self.byte_buffer |= u8::from(cell.0);
+            }
+            self.shift_count += 1;
+            if self.shift_count == 16 {
+                self.shift_count = 0;
+                (self.sink)(FmWord::Enc(self.byte_buffer));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fm_cells_test() {
+        assert_eq!(fm_cells(FM_ADDRESS_MARK_CLOCK, 0xFE), 0xF57E);
+        assert_eq!(fm_cells(FM_ADDRESS_MARK_CLOCK, 0xFB), 0xF56F);
+        assert_eq!(fm_cells(FM_ADDRESS_MARK_CLOCK, 0xF8), 0xF56A);
+        assert_eq!(fm_cells(FM_INDEX_MARK_CLOCK, 0xFC), 0xF77A);
+        assert_eq!(fm_cells(FM_DATA_CLOCK, 0x00), 0xAAAA);
+    }
+
+    #[test]
+    fn fm_decoder_test() {
+        let input = [
+            FmWord::Enc(0xff),
+            FmWord::Enc(0x00),
+            FmWord::AddressMark(0xFE),
+            FmWord::Enc(1),
+            FmWord::Enc(0xfe),
+            FmWord::Enc(0x00),
+            FmWord::AddressMark(0xFB),
+            FmWord::Enc(0xe5),
+        ];
+        let mut cells: Vec<Bit> = Vec::new();
+        let mut encoder = FmEncoder::new(|val| cells.push(val));
+        input.iter().for_each(|word| encoder.feed(*word));
+
+        let decode = |cells: &[Bit]| {
+            let mut result: Vec<FmWord> = Vec::new();
+            let mut decoder = FmDecoder::new(|val| result.push(val));
+            cells.iter().for_each(|cell| decoder.feed(*cell));
+            result
+        };
+
+        // Nothing is provided before the first address mark
+        assert_eq!(
+            decode(&cells),
+            input.iter().skip(2).copied().collect::<Vec<_>>()
+        );
+
+        // Missing clock bits in the data end the block until the next address mark
+        cells
+            .iter_mut()
+            .skip(3 * 16)
+            .take(16)
+            .for_each(|cell| *cell = Bit(false));
+        assert_eq!(
+            decode(&cells),
+            vec![
+                FmWord::AddressMark(0xFE),
+                FmWord::AddressMark(0xFB),
+                FmWord::Enc(0xe5),
+            ]
+        );
+    }
+}
@@ -11,11 +11,14 @@ extern crate alloc;
 
 pub mod bitstream;
 pub mod c64_geometry;
+pub mod capabilities;
 pub mod fluxpulse;
+pub mod fm;
 pub mod gcr;
 pub mod mfm;
 
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 use ouroboros::self_referencing;
 
 #[derive(Clone, Copy, Debug)]
@@ -31,7 +34,7 @@ pub enum Encoding {
     GCR,
     MFM,
 }
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DiskType {
     Inch3_5,
     Inch5_25,
@@ -44,7 +47,56 @@ pub enum DriveSelectState {
     B,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Wiring of the drive select and motor enable lines between controller and drives.
+/// The lines are named after the pins of the 34 pin connector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CableType {
+    /// PC cable with twist. A uses pin 14 and 10, B uses pin 12 and 16.
+    PcTwist,
+    /// Cable without twist. The lines of A and B are swapped compared to `PcTwist`.
+    /// A PC drive jumpered to the second position becomes drive A.
+    Straight,
+    /// Pin 10, 12 and 14 select drive 0 to 2. Pin 16 spins the motors of all drives.
+    /// A is drive 0 and B is drive 1.
+    Shugart,
+}
+
+impl CableType {
+    /// Encoding for the configuration command
+    #[must_use]
+    pub const fn to_bits(self) -> u32 {
+        match self {
+            Self::PcTwist => 0,
+            Self::Straight => 1,
+            Self::Shugart => 2,
+        }
+    }
+
+    /// Unknown values fall back to `PcTwist`
+    #[must_use]
+    pub const fn from_bits(bits: u32) -> Self {
+        match bits {
+            1 => Self::Straight,
+            2 => Self::Shugart,
+            _ => Self::PcTwist,
+        }
+    }
+}
+
+/// Fills long runs of cells without flux reversal while writing.
+/// A drive is unable to keep such a gap quiet on its own. Its automatic gain control
+/// amplifies the noise until random flux reversals are read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GapGenerator {
+    /// Pulses of 2.5 cells which are too long for the encoding and read back as random data
+    WeakBits,
+    /// A single long pause without any flux reversal until the next data
+    NonFluxReversal,
+    /// The gap is written as it is
+    Disabled,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Density {
     High,
     SingleDouble,
@@ -67,6 +119,126 @@ pub struct DensityMapEntry {
     pub cell_size: PulseDuration,
 }
 
+impl DensityMapEntry {
+    // Transferred as a single word with 23 bits for the length and 9 bits for the cell size
+    pub const MAX_CELL_SIZE: i32 = 0x1ff;
+    pub const MAX_NUMBER_OF_CELLBYTES: usize = (1 << 23) - 1;
+
+    /// Packs the entry for the write command. None if a field doesn't fit
+    /// or if the cell size is 0 which would stall the pulse generator of the device.
+    #[must_use]
+    pub fn packed(&self) -> Option<u32> {
+        if !(1..=Self::MAX_CELL_SIZE).contains(&self.cell_size.0)
+            || self.number_of_cellbytes > Self::MAX_NUMBER_OF_CELLBYTES
+        {
+            return None;
+        }
+
+        Some(((self.number_of_cellbytes as u32) << 9) | self.cell_size.0 as u32)
+    }
+
+    #[must_use]
+    pub fn unpacked(packed: u32) -> Self {
+        Self {
+            number_of_cellbytes: (packed >> 9) as usize,
+            cell_size: PulseDuration((packed & 0x1ff) as i32),
+        }
+    }
+
+    /// Packs the entry with 16 bits for both fields. Used for slow data rates.
+    /// None if a field doesn't fit or if the cell size is 0.
+    #[must_use]
+    pub fn packed_extended(&self) -> Option<u32> {
+        let cell_size = u16::try_from(self.cell_size.0).ok().filter(|f| *f > 0)?;
+        let number_of_cellbytes = u16::try_from(self.number_of_cellbytes).ok()?;
+        Some((u32::from(number_of_cellbytes) << 16) | u32::from(cell_size))
+    }
+
+    #[must_use]
+    pub fn unpacked_extended(packed: u32) -> Self {
+        Self {
+            number_of_cellbytes: (packed >> 16) as usize,
+            cell_size: PulseDuration((packed & 0xffff) as i32),
+        }
+    }
+}
+
+/// Command which writes an encoded track.
+/// The header carries the verify windows in front of the size of the density map.
+/// Older firmware only knows `0x1234_0001` without them and ignores this command.
+pub const WRITE_TRACK_COMMAND: u32 = 0x1234_0015;
+
+/// Flag in the size of the density map of the write command.
+/// The entries are packed with `DensityMapEntry::packed_extended`.
+pub const EXTENDED_DENSITY_MAP: u32 = 1 << 31;
+
+/// Flag in the size of the density map of the write command.
+/// Both generators for long gaps without flux reversals are disabled.
+pub const GAP_GENERATOR_DISABLED: u32 = 1 << 30;
+
+/// Flag in the size of the density map or the margins of the flux header of the write commands.
+/// After answering with the size and checksum of the received track, the device waits for
+/// `CONFIRM_RECEPTION_COMMAND` before the track is written.
+pub const CONFIRM_RECEPTION: u32 = 1 << 29;
+
+/// Command which answers a track received with `CONFIRM_RECEPTION`.
+/// The parameter is 1 to write the track and 0 to discard it.
+pub const CONFIRM_RECEPTION_COMMAND: u32 = 0x1234_0013;
+
+/// Bits of the size of the density map of the write command which give the number of entries.
+/// The other bits carry flags and the `WriteGateMargins`.
+pub const DENSITY_MAP_SIZE_MASK: u32 = 0xff;
+
+/// Extends the time in which the write gate is active around the data of a track.
+/// `lead_in_us` delays the first flux reversal after the write starts at the index.
+/// `lead_out_us` keeps the write gate active after the last flux reversal.
+/// Both are zero by default which keeps the timing of previous versions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteGateMargins {
+    pub lead_in_us: u32,
+    pub lead_out_us: u32,
+}
+
+impl WriteGateMargins {
+    /// Limited by the 16 bit timer which generates the flux reversals
+    pub const MAX_US: u32 = 700;
+
+    /// Fields 0000OOOO OOOOOOII IIIIIIII 00000000
+    /// to be combined with the size of the density map or the flux header.
+    #[must_use]
+    pub fn packed(&self) -> u32 {
+        (self.lead_in_us.min(Self::MAX_US) << 8) | (self.lead_out_us.min(Self::MAX_US) << 18)
+    }
+
+    #[must_use]
+    pub fn unpacked(packed: u32) -> Self {
+        Self {
+            lead_in_us: ((packed >> 8) & 0x3ff).min(Self::MAX_US),
+            lead_out_us: ((packed >> 18) & 0x3ff).min(Self::MAX_US),
+        }
+    }
+
+    #[must_use]
+    pub fn lead_in_ticks(&self) -> u32 {
+        self.lead_in_us * STM_TIMER_MHZ as u32
+    }
+
+    #[must_use]
+    pub fn lead_out_ticks(&self) -> u32 {
+        self.lead_out_us * STM_TIMER_MHZ as u32
+    }
+}
+
+/// Flag in the verify windows of the write commands.
+/// The track is only written and the verification is skipped.
+/// The read data window never needs this bit as it is limited by `VerifyWindows::MAX_READ_DATA`.
+pub const WRITE_WITHOUT_VERIFY: u32 = 1 << 31;
+
+/// Flag in the verify windows of the write commands.
+/// Selects `Correlation::LongestAgreement` for the verification.
+/// The compare window never needs this bit as it is limited by `VerifyWindows::MAX_COMPARE`.
+pub const LONGEST_AGREEMENT_CORRELATION: u32 = 1 << 15;
+
 pub const DRIVE_5_25_RPM: f64 = 361.0; // Normally 360 RPM would be correct. But the drive might be faster. Let's be safe here.
 pub const DRIVE_3_5_RPM: f64 = 300.05; // Normally 300 RPM would be correct. But the drive might be faster. Let's be safe here.
 pub const DRIVE_SLOWEST_RPM: f64 = DRIVE_3_5_RPM; // If the drive is not known, we use this for reading.
@@ -76,16 +248,325 @@ pub const STM_TIMER_HZ: f64 = 84e6;
 
 pub const PULSE_REDUCE_SHIFT: usize = 3;
 
+/// Pulses the device buffers before the transmission of a track is started.
+/// Tracks of flux timings must provide at least this many.
+pub const WRITE_PREFILL_PULSES: usize = 70;
+
+/// The motor keeps spinning at least this long after the last operation.
+/// Operations of the firmware rely on this as a timeout.
+pub const MIN_MOTOR_OFF_DELAY_MS: u32 = 1200;
+
+/// Time the head needs to come to rest after stepping before it can be used
+pub const DEFAULT_HEAD_SETTLE_MS: u32 = 20;
+/// The head settle time is transferred with 8 bits of the configure command.
+/// Zero selects `DEFAULT_HEAD_SETTLE_MS` to stay compatible with older tools.
+pub const MAX_HEAD_SETTLE_MS: u32 = 0xff;
+
+/// Time between two step pulses. The firmware works in steps of 2 ms.
+pub const DEFAULT_STEP_RATE_MS: u32 = 4;
+/// The step rate is transferred with 8 bits of the configure command.
+/// Zero selects `DEFAULT_STEP_RATE_MS` to stay compatible with older tools.
+pub const MAX_STEP_RATE_MS: u32 = 0xff;
+
+/// Last cylinder of standard formats which every drive can reach
+pub const DEFAULT_MAX_CYLINDER: u32 = 79;
+/// Last cylinder which capable drives can reach. Used by over-formatted disks.
+pub const MAX_CYLINDER: u32 = 83;
+
+/// Window sizes used by the firmware to cross correlate the ground truth against
+/// the flux read back from the disk during verification.
+///
+/// Both windows are allocated as `VecDeque<PulseDuration>` on the heap of the firmware.
+/// `compare` is allocated 8 times and `read_data` 2 times, each entry taking 4 bytes.
+/// Larger windows are more robust against speed variations on long tracks
+/// but cost memory and time. Smaller windows are faster on short tracks.
+///
+/// The first `skip_pulses` of the ground truth are not verified as the start of a
+/// track is often unstable because of the write splice. The compare window is matched
+/// against the read data after the skipped pulses. So the read data window must cover
+/// both which limits the number of skipped pulses to `read_data - compare`.
+///
+/// Some drives need time to recover after writing before the read back is reliable.
+/// `settle_delay_us` is waited after writing before the verification starts.
+///
+/// `correlation` decides which match of the compare window is used for synchronization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VerifyWindows {
+    pub compare: usize,
+    pub read_data: usize,
+    pub skip_pulses: usize,
+    pub settle_delay_us: u32,
+    pub correlation: Correlation,
+}
+
+impl VerifyWindows {
+    pub const DEFAULT_COMPARE: usize = 20;
+    pub const DEFAULT_READ_DATA: usize = 200;
+    pub const DEFAULT_SKIP_PULSES: usize = 6;
+
+    // Upper bounds to avoid exhausting the heap of the firmware
+    pub const MAX_COMPARE: usize = 64;
+    pub const MAX_READ_DATA: usize = 1000;
+    // Transferred as a single byte
+    pub const MAX_SKIP_PULSES: usize = 0xff;
+    // Transferred with 6 bits in steps of 100µs
+    pub const SETTLE_DELAY_STEP_US: u32 = 100;
+    pub const MAX_SETTLE_DELAY_US: u32 = 63 * Self::SETTLE_DELAY_STEP_US;
+
+    /// Limits the windows to sane values. A zero size selects the default.
+    /// The compare window must always be smaller than the read data window.
+    #[must_use]
+    pub fn bounded(self) -> Self {
+        let compare = match self.compare {
+            0 => Self::DEFAULT_COMPARE,
+            x => x.min(Self::MAX_COMPARE),
+        };
+        let read_data = match self.read_data {
+            0 => Self::DEFAULT_READ_DATA,
+            x => x.min(Self::MAX_READ_DATA),
+        }
+        .max(compare * 2);
+        let skip_pulses = match self.skip_pulses {
+            0 => Self::DEFAULT_SKIP_PULSES,
+            x => x.min(Self::MAX_SKIP_PULSES),
+        };
+
+        Self {
+            compare,
+            read_data,
+            skip_pulses: skip_pulses.min(read_data - compare),
+            settle_delay_us: self.settle_delay_us.min(Self::MAX_SETTLE_DELAY_US)
+                / Self::SETTLE_DELAY_STEP_US
+                * Self::SETTLE_DELAY_STEP_US,
+            correlation: self.correlation,
+        }
+    }
+}
+
+impl Default for VerifyWindows {
+    fn default() -> Self {
+        Self {
+            compare: Self::DEFAULT_COMPARE,
+            read_data: Self::DEFAULT_READ_DATA,
+            skip_pulses: Self::DEFAULT_SKIP_PULSES,
+            settle_delay_us: 0,
+            correlation: Correlation::FirstMatch,
+        }
+    }
+}
+
+/// Strategy to synchronize the ground truth with the data read back from disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Correlation {
+    /// Use the first position where the compare window matches. This is fast
+    /// but might pick a false position on tracks with repetitive patterns.
+    #[default]
+    FirstMatch,
+    /// Check every position where the compare window matches and use the one
+    /// which agrees with the ground truth for the most pulses afterwards.
+    LongestAgreement,
+}
+
+/// Agreements are only followed up to this number of pulses. This bounds the search
+/// of `Correlation::LongestAgreement` on the firmware to `read_data * MAX_AGREEMENT` comparisons.
+pub const MAX_AGREEMENT: usize = 4 * VerifyWindows::MAX_COMPARE;
+
+/// Searches the offset into the read data at which the ground truth starts.
+/// Every offset where the first `compare` pulses are similar is a candidate.
+/// `similar` is called with the ground truth and the read back pulse.
+pub fn cross_correlate<R, G>(
+    read_data: R,
+    ground_truth: G,
+    compare: usize,
+    correlation: Correlation,
+    similar: impl Fn(PulseDuration, PulseDuration) -> bool,
+) -> Option<usize>
+where
+    R: ExactSizeIterator<Item = PulseDuration> + Clone,
+    G: Iterator<Item = PulseDuration> + Clone,
+{
+    let offsets = 0..=read_data.len().checked_sub(compare)?;
+    let agreement = |offset: usize| {
+        read_data
+            .clone()
+            .skip(offset)
+            .zip(ground_truth.clone())
+            .take_while(|(x, y)| similar(*y, *x))
+            .take(MAX_AGREEMENT)
+            .count()
+    };
+
+    match correlation {
+        Correlation::FirstMatch => offsets.into_iter().find(|offset| {
+            read_data
+                .clone()
+                .skip(*offset)
+                .zip(ground_truth.clone())
+                .take(compare)
+                .all(|(x, y)| similar(y, x))
+        }),
+        Correlation::LongestAgreement => {
+            let mut best: Option<(usize, usize)> = None;
+            for offset in offsets {
+                let agreement = agreement(offset);
+                // The earliest one wins if multiple positions agree equally long
+                if agreement >= compare && !matches!(best, Some(best) if best.1 >= agreement) {
+                    best = Some((offset, agreement));
+                    if agreement == MAX_AGREEMENT {
+                        break;
+                    }
+                }
+            }
+            best.map(|(offset, _)| offset)
+        }
+    }
+}
+
+/// Takes pulses of the ground truth until one differs from `last`.
+/// Repeated pulses at the start lack the entropy to correlate with the read data.
+/// Provides the number of repeated pulses and the first different one.
+/// None if the data ends before, e.g. on a track of a single repeated pattern.
+pub fn skip_repeated_pulses(
+    last: PulseDuration,
+    mut next: impl FnMut() -> Option<PulseDuration>,
+) -> Option<(usize, PulseDuration)> {
+    let mut repeated = 0;
+    loop {
+        let pulse = next()?;
+        if pulse.0 != last.0 {
+            return Some((repeated, pulse));
+        }
+        repeated += 1;
+    }
+}
+
 pub const USB_VID: u16 = 0x1209; // https://pid.codes/
 pub const USB_PID: u16 = 0x27dd;
+/// Vendor control request to abort the currently running operation
+pub const USB_ABORT_REQUEST: u8 = 0x10;
+
+/// Heap which must stay free after receiving a track for assembling and verifying it.
+/// The verification of a track holds the pulses of eight compare windows as ground truth
+/// and of two read data windows. 1 KiB remains for the operation itself and the answers.
+pub const WRITE_HEAP_RESERVE: usize = (8 * VerifyWindows::MAX_COMPARE
+    + 2 * VerifyWindows::MAX_READ_DATA)
+    * core::mem::size_of::<PulseDuration>()
+    + 1024;
+/// Number of received tracks the device keeps while another track is written.
+/// Each of them and the one being written must fit into the heap.
+pub const MAX_WRITE_PIPELINE_DEPTH: usize = 3;
+
+/// CRC-32 of a track as transferred to the device. The device answers with it after
+/// assembling the track to confirm the reception before writing it.
+/// Tracks of flux timings have no density map.
+#[must_use]
+pub fn reception_checksum(densitymap: &[DensityMapEntry], data: &[u8]) -> u32 {
+    let densitymap_bytes = densitymap.iter().flat_map(|f| {
+        IntoIterator::into_iter((f.cell_size.0 as u32).to_le_bytes())
+            .chain((f.number_of_cellbytes as u32).to_le_bytes())
+    });
+
+    let mut crc = !0u32;
+    for byte in densitymap_bytes.chain(data.iter().copied()) {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0xedb8_8320
+            };
+        }
+    }
+    !crc
+}
 
 #[must_use]
 pub fn duration_of_rotation_as_stm_tim_raw(rpm: f64) -> usize {
     (60.0 / rpm * STM_TIMER_HZ) as usize
 }
 
+/// Upper limit of the offset of the simulated index for flipped disks
+pub const MAX_FLIPPY_OFFSET_US: u32 = 1000;
+/// Unit of the offset given with `-f`. 1000 ticks of the 84 MHz timer are about 11.9 µs.
+pub const FLIPPY_OFFSET_STEP_TICKS: u32 = 1000;
+
+/// Provides the period of the simulated index signal for flipped 5.25" disks in timer ticks.
+/// This is the value which is transferred as `index_sim_frequency`.
+///
+/// PC 5.25" drives rotate with 360 RPM. That's 6 rotations per second which
+/// are 84 MHz / 6 = 14 000 000 ticks per rotation. The period is shortened by the
+/// offset in timer ticks as some drives rotate a bit faster. Otherwise the verification
+/// after writing might miss the next rotation. None if the offset is out of range.
+#[must_use]
+pub fn flippy_index_frequency(offset_ticks: u32) -> Option<u32> {
+    const ROTATIONS_PER_SECOND: u32 = 360 / 60;
+
+    if offset_ticks > MAX_FLIPPY_OFFSET_US * STM_TIMER_MHZ as u32 {
+        return None;
+    }
+
+    let period = STM_TIMER_HZ as u32 / ROTATIONS_PER_SECOND;
+    Some(period - offset_ticks)
+}
+
+/// Length of the simulated index pulse in timer ticks. About 2.4ms.
+pub const INDEX_SIM_PULSE_TICKS: u32 = 200_000;
+
+/// Provides the period of the simulated index signal for a frequency in Hz.
+/// None if the period is not longer than the pulse itself or doesn't fit into the timer.
+#[must_use]
+pub fn index_sim_period(frequency_hz: f64) -> Option<u32> {
+    // Rounding by hand as this is also used without std
+    let period = STM_TIMER_HZ / frequency_hz + 0.5;
+
+    (period > f64::from(INDEX_SIM_PULSE_TICKS) && period <= f64::from(u32::MAX))
+        .then_some(period as u32)
+}
+
 pub type DensityMap = Vec<DensityMapEntry>;
 
+/// Share of a rotation in per mille which stays unwritten at the end of a track.
+/// The write must not reach the start of the same track again.
+pub const SPLICE_GUARD_PERMILLE: u64 = 10;
+
+/// Shortens the end of a track which would come too close to its own start during writing.
+/// `tail_cells` are written after the track with the last cell size.
+/// Only the last part of the density map is shortened as it usually ends with a gap.
+/// Returns the number of removed cell bytes.
+pub fn shorten_to_rotation(
+    speeds: &mut DensityMap,
+    cells: &mut Vec<u8>,
+    rotation_ticks: u32,
+    tail_cells: u32,
+) -> usize {
+    let byte_ticks = |entry: &DensityMapEntry| u64::from(entry.cell_size.0.unsigned_abs()) * 8;
+
+    let Some(last) = speeds.last() else {
+        return 0;
+    };
+    let tail_ticks = u64::from(tail_cells) * byte_ticks(last) / 8;
+    let available_ticks = u64::from(rotation_ticks) * (1000 - SPLICE_GUARD_PERMILLE) / 1000;
+    let track_ticks: u64 = speeds
+        .iter()
+        .map(|f| f.number_of_cellbytes as u64 * byte_ticks(f))
+        .sum::<u64>()
+        + tail_ticks;
+
+    if track_ticks <= available_ticks || byte_ticks(last) == 0 {
+        return 0;
+    }
+
+    // The last part is never removed completely
+    let excess_bytes = (track_ticks - available_ticks).div_ceil(byte_ticks(last)) as usize;
+    let removed = excess_bytes.min(last.number_of_cellbytes.saturating_sub(1));
+
+    if let Some(last) = speeds.last_mut() {
+        last.number_of_cellbytes -= removed;
+    }
+    cells.truncate(cells.len() - removed);
+    removed
+}
+
 #[must_use]
 pub fn reduce_densitymap(densitymap: DensityMap) -> DensityMap {
     let mut result: DensityMap = Vec::new();
@@ -101,11 +582,11 @@ pub fn reduce_densitymap(densitymap: DensityMap) -> DensityMap {
     }
     result
 }
-#[self_referencing]
+#[self_referencing(pub_extras)]
 pub struct RawCellData {
     pub speeds: DensityMap,
     pub cells: Vec<u8>,
-    pub has_non_flux_reversal_area: bool,
+    pub gap_generator: GapGenerator,
     #[borrows(cells, speeds)]
     #[covariant]
     pub parts: Vec<RawCellPart<'this>>,
@@ -140,12 +621,12 @@ impl RawCellData {
     pub fn construct(
         speeds: DensityMap,
         cells: Vec<u8>,
-        has_non_flux_reversal_area: bool,
+        gap_generator: GapGenerator,
     ) -> Option<Self> {
         RawCellDataTryBuilder {
             speeds,
             cells,
-            has_non_flux_reversal_area,
+            gap_generator,
             parts_builder: |cells, speeds| Self::split_in_parts(speeds, cells).ok_or(()),
         }
         .try_build()
@@ -169,13 +650,454 @@ impl PulseDuration {
     }
 }
 
+/// Number of buckets of `VerifyHistogram`
+pub const VERIFY_HISTOGRAM_BUCKETS: usize = 8;
+/// Width of a bucket of `VerifyHistogram` in ticks of the timer
+pub const VERIFY_HISTOGRAM_STEP: i32 = 4;
+
+/// Largest accepted deviation of a read back pulse during verification
+/// in percent of the cell size
+pub const VERIFY_THRESHOLD_PERCENT: i32 = 35;
+
+/// Largest additional verify threshold at the last cylinder in percent of the cell size.
+/// Transferred with 4 bits of the configure command. Zero keeps the threshold constant.
+pub const MAX_VERIFY_THRESHOLD_EXTRA_PERCENT: u32 = 10;
+/// Position of the additional verify threshold in the settings of the drive configuration
+pub const VERIFY_THRESHOLD_EXTRA_SHIFT: u32 = 24;
+
+/// Verify threshold of a cylinder in percent of the cell size.
+/// Higher cylinders are the inner tracks of the disk where the bits are packed denser.
+/// The additional threshold grows linearly up to `DEFAULT_MAX_CYLINDER`.
+#[must_use]
+pub fn verify_threshold_percent(cylinder: u32, extra_percent: u32) -> i32 {
+    let extra = extra_percent.min(MAX_VERIFY_THRESHOLD_EXTRA_PERCENT)
+        * cylinder.min(DEFAULT_MAX_CYLINDER)
+        / DEFAULT_MAX_CYLINDER;
+    VERIFY_THRESHOLD_PERCENT + extra as i32
+}
+
+/// Flag in the settings of the drive configuration.
+/// A `VerifyHistogram` is reported after every successful verification.
+pub const DETAILED_VERIFY: u32 = 1 << 5;
+
+/// Flag in the settings of the drive configuration.
+/// A spinning motor is stopped if the density changes. Some drives only take over
+/// the density select signal when the motor is switched on.
+pub const DENSITY_LATCHED_AT_MOTOR_ON: u32 = 1 << 6;
+
+/// Distribution of the differences between read back and ground truth pulses.
+/// The buckets are centered around zero. The outer ones also count every larger difference.
+/// Errors clustering on one side are systematic while a wide spread is caused by noise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VerifyHistogram {
+    pub buckets: [u32; VERIFY_HISTOGRAM_BUCKETS],
+}
+
+impl VerifyHistogram {
+    pub fn feed(&mut self, reference: PulseDuration, readback: PulseDuration) {
+        let half = VERIFY_HISTOGRAM_BUCKETS as i32 / 2;
+        let index = ((readback.0 - reference.0).div_euclid(VERIFY_HISTOGRAM_STEP) + half)
+            .clamp(0, VERIFY_HISTOGRAM_BUCKETS as i32 - 1);
+
+        if let Some(bucket) = self.buckets.get_mut(index as usize) {
+            *bucket = bucket.saturating_add(1);
+        }
+    }
+
+    /// Smallest difference counted by the bucket if it's not an outer one
+    #[must_use]
+    pub fn lower_bound(index: usize) -> i32 {
+        (index as i32 - VERIFY_HISTOGRAM_BUCKETS as i32 / 2) * VERIFY_HISTOGRAM_STEP
+    }
+}
+
+/// Observes the difference between ground truth and read back pulses during verification.
+/// If the read back pulses are consistently longer or shorter, the drive is rotating
+/// with a different speed than expected. This is a different problem than the
+/// random errors caused by bad media.
+#[derive(Default)]
+pub struct PhaseDriftDetector {
+    pulses: u32,
+    accumulated_diff: i64,
+    segment_diff: i32,
+    segments: u32,
+    /// Segments drifting to longer pulses minus segments drifting to shorter ones
+    trend: i32,
+}
+
+impl PhaseDriftDetector {
+    const SEGMENT_SIZE: u32 = 64;
+    const MIN_SEGMENTS: u32 = 8;
+
+    pub fn feed(&mut self, reference: PulseDuration, readback: PulseDuration) {
+        let diff = readback.0 - reference.0;
+        self.accumulated_diff += i64::from(diff);
+        self.segment_diff += diff;
+        self.pulses += 1;
+
+        if self.pulses % Self::SEGMENT_SIZE == 0 {
+            self.segments += 1;
+            self.trend += self.segment_diff.signum();
+            self.segment_diff = 0;
+        }
+    }
+
+    /// Provides the drift in timer ticks per 1000 pulses.
+    /// Only available if at least 90% of the observed segments drift into the same direction.
+    #[must_use]
+    pub fn drift(&self) -> Option<i32> {
+        if self.segments < Self::MIN_SEGMENTS {
+            return None;
+        }
+
+        // 90% in one direction and 10% in the other one results in a trend of 80%
+        if self.trend.unsigned_abs() * 10 < self.segments * 8 {
+            return None;
+        }
+
+        let drift = self.accumulated_diff * 1000 / i64::from(self.pulses);
+        i32::try_from(drift).ok().filter(|f| *f != 0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn reception_checksum_test() {
+        // Check value of CRC-32
+        assert_eq!(reception_checksum(&[], b"123456789"), 0xcbf4_3926);
+
+        let densitymap = [DensityMapEntry {
+            number_of_cellbytes: 9,
+            cell_size: PulseDuration(168),
+        }];
+        assert_ne!(
+            reception_checksum(&densitymap, b"123456789"),
+            reception_checksum(&[], b"123456789")
+        );
+    }
+
+    #[test]
+    fn verify_histogram_test() {
+        let mut histogram = VerifyHistogram::default();
+        for readback in [100, 101, 103, 97, 80, 200] {
+            histogram.feed(PulseDuration(100), PulseDuration(readback));
+        }
+
+        assert_eq!(histogram.buckets, [1, 0, 0, 1, 3, 0, 0, 1]);
+        assert_eq!(VerifyHistogram::lower_bound(0), -16);
+        assert_eq!(VerifyHistogram::lower_bound(4), 0);
+    }
+
+    #[test]
+    fn shorten_to_rotation_test() {
+        let speeds = || {
+            vec![
+                DensityMapEntry {
+                    number_of_cellbytes: 6000,
+                    cell_size: PulseDuration(168),
+                },
+                DensityMapEntry {
+                    number_of_cellbytes: 250,
+                    cell_size: PulseDuration(168),
+                },
+            ]
+        };
+        let rotation_ticks = 6250 * 8 * 168;
+
+        // A full rotation leaves no room for the guard
+        let mut densitymap = speeds();
+        let mut cells = vec![0x4e; 6250];
+        let removed = shorten_to_rotation(&mut densitymap, &mut cells, rotation_ticks, 16);
+        assert_eq!(removed, 65);
+        assert_eq!(cells.len(), 6250 - 65);
+        assert_eq!(
+            densitymap
+                .iter()
+                .map(|f| f.number_of_cellbytes)
+                .sum::<usize>(),
+            cells.len()
+        );
+
+        // Tracks which already fit stay untouched
+        assert_eq!(
+            shorten_to_rotation(&mut densitymap, &mut cells, rotation_ticks, 16),
+            0
+        );
+
+        // The data before the last part is never removed
+        let mut densitymap = speeds();
+        let mut cells = vec![0x4e; 6250];
+        assert_eq!(
+            shorten_to_rotation(&mut densitymap, &mut cells, 5000 * 8 * 168, 0),
+            249
+        );
+    }
+
     #[test]
     fn duration_of_rotation_as_stm_tim_raw_test() {
         let result = duration_of_rotation_as_stm_tim_raw(300.0);
         assert_eq!(result as u32, 16_800_000);
     }
+
+    #[test]
+    fn flippy_index_frequency_test() {
+        assert_eq!(flippy_index_frequency(0), Some(14_000_000));
+        // -f 3 like it was always calculated
+        assert_eq!(
+            flippy_index_frequency(3 * FLIPPY_OFFSET_STEP_TICKS),
+            Some(13_997_000)
+        );
+        assert_eq!(flippy_index_frequency(100 * 84), Some(13_991_600));
+        assert_eq!(flippy_index_frequency(MAX_FLIPPY_OFFSET_US * 84 + 1), None);
+    }
+
+    #[test]
+    fn index_sim_period_test() {
+        assert_eq!(index_sim_period(6.0), flippy_index_frequency(0));
+        assert_eq!(index_sim_period(5.0), Some(16_800_000));
+        assert_eq!(index_sim_period(500.0), None);
+        assert_eq!(index_sim_period(0.0), None);
+        assert_eq!(index_sim_period(-6.0), None);
+    }
+
+    #[test]
+    fn phase_drift_detector_test() {
+        let reference = PulseDuration(336);
+
+        // Read back pulses are always a bit longer
+        let mut detector = PhaseDriftDetector::default();
+        for _ in 0..1000 {
+            detector.feed(reference, PulseDuration(338));
+        }
+        assert_eq!(detector.drift(), Some(2000));
+
+        // Random errors are not a drift
+        let mut detector = PhaseDriftDetector::default();
+        for i in 0..1000 {
+            let readback = if i % 2 == 0 { 346 } else { 326 };
+            detector.feed(reference, PulseDuration(readback));
+        }
+        assert_eq!(detector.drift(), None);
+
+        // Not enough data to decide
+        let mut detector = PhaseDriftDetector::default();
+        for _ in 0..100 {
+            detector.feed(reference, PulseDuration(338));
+        }
+        assert_eq!(detector.drift(), None);
+    }
+
+    #[test]
+    fn density_map_entry_packing_test() {
+        let entry = DensityMapEntry {
+            number_of_cellbytes: 12_668,
+            cell_size: PulseDuration(DensityMapEntry::MAX_CELL_SIZE),
+        };
+        let unpacked = DensityMapEntry::unpacked(entry.packed().unwrap());
+        assert_eq!(unpacked.number_of_cellbytes, entry.number_of_cellbytes);
+        assert_eq!(unpacked.cell_size, entry.cell_size);
+
+        // Would overflow into the number of cell bytes
+        let entry = DensityMapEntry {
+            number_of_cellbytes: 100,
+            cell_size: PulseDuration(512),
+        };
+        assert_eq!(entry.packed(), None);
+
+        let entry = DensityMapEntry {
+            number_of_cellbytes: DensityMapEntry::MAX_NUMBER_OF_CELLBYTES + 1,
+            cell_size: PulseDuration(168),
+        };
+        assert_eq!(entry.packed(), None);
+
+        // A cell size of 0 is never transferred
+        let entry = DensityMapEntry {
+            number_of_cellbytes: 100,
+            cell_size: PulseDuration(0),
+        };
+        assert_eq!(entry.packed(), None);
+        assert_eq!(entry.packed_extended(), None);
+    }
+
+    #[test]
+    fn extended_density_map_test() {
+        // A synthetic slow track with a cell size beyond the compact format
+        let densitymap: DensityMap = [(100, 168), (3000, 800)]
+            .iter()
+            .map(|&(number_of_cellbytes, cell_size)| DensityMapEntry {
+                number_of_cellbytes,
+                cell_size: PulseDuration(cell_size),
+            })
+            .collect();
+        assert!(densitymap.iter().any(|f| f.packed().is_none()));
+
+        let transferred: DensityMap = densitymap
+            .iter()
+            .map(|f| DensityMapEntry::unpacked_extended(f.packed_extended().unwrap()))
+            .collect();
+
+        let cells = vec![0xaa; 3100];
+        let data = RawCellData::construct(transferred, cells, GapGenerator::WeakBits).unwrap();
+        let parts = data.borrow_parts();
+        let cell_sizes: Vec<PulseDuration> = parts.iter().map(|f| f.cell_size).collect();
+        assert_eq!(cell_sizes, vec![PulseDuration(168), PulseDuration(800)]);
+        assert_eq!(parts.iter().map(|f| f.cells.len()).sum::<usize>(), 3100);
+
+        let entry = DensityMapEntry {
+            number_of_cellbytes: 0x10000,
+            cell_size: PulseDuration(800),
+        };
+        assert_eq!(entry.packed_extended(), None);
+    }
+
+    #[test]
+    fn cable_type_bits_test() {
+        for cable_type in [CableType::PcTwist, CableType::Straight, CableType::Shugart] {
+            assert_eq!(CableType::from_bits(cable_type.to_bits()), cable_type);
+        }
+        // Fits into the two bits of the configuration
+        assert!(CableType::Shugart.to_bits() <= 0b11);
+        assert_eq!(CableType::from_bits(3), CableType::PcTwist);
+    }
+
+    #[test]
+    fn verify_windows_bounded_test() {
+        let windows = VerifyWindows {
+            compare: 0,
+            read_data: 0,
+            skip_pulses: 0,
+            settle_delay_us: 0,
+            correlation: Correlation::FirstMatch,
+        };
+        assert_eq!(windows.bounded(), VerifyWindows::default());
+
+        let windows = VerifyWindows {
+            compare: 1000,
+            read_data: 10,
+            skip_pulses: 1000,
+            settle_delay_us: 100_000,
+            correlation: Correlation::LongestAgreement,
+        };
+        let windows = windows.bounded();
+        assert_eq!(windows.compare, VerifyWindows::MAX_COMPARE);
+        assert_eq!(windows.read_data, VerifyWindows::MAX_COMPARE * 2);
+        // The skipped pulses must still fit into the read data window
+        assert_eq!(windows.skip_pulses, VerifyWindows::MAX_COMPARE);
+        assert_eq!(windows.settle_delay_us, VerifyWindows::MAX_SETTLE_DELAY_US);
+
+        // The delay is transferred in steps
+        let windows = VerifyWindows {
+            settle_delay_us: 650,
+            ..VerifyWindows::default()
+        };
+        assert_eq!(windows.bounded().settle_delay_us, 600);
+    }
+
+    #[test]
+    fn write_gate_margins_test() {
+        let margins = WriteGateMargins {
+            lead_in_us: 50,
+            lead_out_us: 700,
+        };
+        let packed = margins.packed();
+        assert_eq!(packed & (DENSITY_MAP_SIZE_MASK | EXTENDED_DENSITY_MAP), 0);
+        assert_eq!(WriteGateMargins::unpacked(packed | 5), margins);
+        assert_eq!(margins.lead_in_ticks(), 4200);
+
+        // The first flux reversal must still be reachable by the timer
+        let margins = WriteGateMargins {
+            lead_in_us: 1000,
+            lead_out_us: 0,
+        };
+        let unpacked = WriteGateMargins::unpacked(margins.packed());
+        assert_eq!(unpacked.lead_in_us, WriteGateMargins::MAX_US);
+        assert!(unpacked.lead_in_ticks() < 0xffff - 400);
+        assert_eq!(WriteGateMargins::unpacked(0), WriteGateMargins::default());
+    }
+
+    #[test]
+    fn verify_threshold_percent_test() {
+        assert_eq!(verify_threshold_percent(79, 0), VERIFY_THRESHOLD_PERCENT);
+        assert_eq!(verify_threshold_percent(0, 10), VERIFY_THRESHOLD_PERCENT);
+        assert_eq!(
+            verify_threshold_percent(40, 10),
+            VERIFY_THRESHOLD_PERCENT + 5
+        );
+        assert_eq!(
+            verify_threshold_percent(79, 10),
+            VERIFY_THRESHOLD_PERCENT + 10
+        );
+        // Limited beyond the last standard cylinder and to the maximum
+        assert_eq!(
+            verify_threshold_percent(83, 40),
+            VERIFY_THRESHOLD_PERCENT + 10
+        );
+    }
+
+    #[test]
+    fn cross_correlate_test() {
+        let pulses = |durations: &[i32]| -> Vec<PulseDuration> {
+            durations.iter().map(|f| PulseDuration(*f * 100)).collect()
+        };
+        let similar = |x: PulseDuration, y: PulseDuration| x.similar(&y, 35);
+
+        // The ground truth starts with a repetitive pattern which also occurs
+        // in the read data before the real position of the ground truth.
+        let ground_truth = pulses(&[2, 2, 2, 2, 3, 4, 2, 3]);
+        let read_data = pulses(&[4, 2, 2, 2, 2, 2, 2, 3, 4, 2, 3, 2]);
+
+        let correlate = |correlation| {
+            cross_correlate(
+                read_data.iter().copied(),
+                ground_truth.iter().copied(),
+                4,
+                correlation,
+                similar,
+            )
+        };
+        // The first match is shifted by two pulses
+        assert_eq!(correlate(Correlation::FirstMatch), Some(1));
+        assert_eq!(correlate(Correlation::LongestAgreement), Some(3));
+
+        // Both strategies agree if the pattern is unique
+        let read_data = pulses(&[3, 4, 2, 2, 2, 2, 3, 4, 2, 3]);
+        let correlate = |correlation| {
+            cross_correlate(
+                read_data.iter().copied(),
+                ground_truth.iter().copied(),
+                4,
+                correlation,
+                similar,
+            )
+        };
+        assert_eq!(correlate(Correlation::FirstMatch), Some(2));
+        assert_eq!(correlate(Correlation::LongestAgreement), Some(2));
+
+        assert_eq!(
+            cross_correlate(
+                pulses(&[3, 3, 3]).iter().copied(),
+                ground_truth.iter().copied(),
+                4,
+                Correlation::LongestAgreement,
+                similar,
+            ),
+            None
+        );
+
+        // The search stops at the first position which agrees for the maximum length
+        let uniform = vec![PulseDuration(2); 3 * MAX_AGREEMENT];
+        assert_eq!(
+            cross_correlate(
+                uniform.iter().copied(),
+                uniform.iter().copied(),
+                4,
+                Correlation::LongestAgreement,
+                similar,
+            ),
+            Some(0)
+        );
+    }
 }
@@ -23,6 +23,8 @@ where
 
 const ISO_SYNC_WORD: u16 = 0x4489;
 pub const ISO_SYNC_BYTE: u8 = 0xA1;
+/// Precedes the index address mark. Only used at the start of a track.
+pub const ISO_IAM_SYNC_WORD: u16 = 0x5224;
 
 /*
  Iso Sync Word 0x4489
@@ -31,6 +33,12 @@ pub const ISO_SYNC_BYTE: u8 = 0xA1;
  MFM  0100010010101001   0x44A9 as it would be if encoded correctly
  Sync 0100010010001001   0x4489 is damaged to be detected separate to normal data.
 
+ Index Sync Word 0x5224
+ Data  1 1 0 0 0 0 1 0   0xC2
+ Clk  0 0 0 1 1 1 0 0
+ MFM  0101001010100100   0x52A4 as it would be if encoded correctly
+ Sync 0101001000100100   0x5224 with the clock bit between bit 4 and 3 missing
+
  Gap Byte 0x4e as Mfm Word 0x9254
  Data  0 1 0 0 1 1 1 0
  Clk  1 0 0 1 0 0 0 0
@@ -154,6 +162,14 @@ where
     }
 
     pub fn feed(&mut self, cell: Bit) {
+        // MFM never has two ones in a row and not more than three zeros.
+        // Anything else is caused by a damaged or noisy area of the disk.
+        let invalid_pattern = if cell.0 {
+            self.zero_count == 0
+        } else {
+            self.zero_count >= 3
+        };
+
         if cell.0 {
             self.zero_count = 0;
         } else {
@@ -171,6 +187,12 @@ where
             }
         }
 
+        if self.in_sync && invalid_pattern {
+            // The bit alignment can't be trusted anymore.
+            // Wait for the next sync word instead of providing garbage.
+            self.in_sync = false;
+        }
+
         if self.in_sync {
             if (self.shift_count & 1) == 1 {
                 self.byte_buffer <<= 1;
@@ -194,6 +216,8 @@ where
     word_buffer: u32,
     in_sync: bool,
     shift_count: u8,
+    /// Two sync words in a row
+    sync_pattern: u32,
 }
 
 impl<T> MfmDataSeperator<T>
@@ -201,18 +225,24 @@ where
     T: FnMut(RawMfmWord),
 {
     pub fn new(sink: T) -> Self {
+        Self::with_sync_word(sink, 0x4489)
+    }
+
+    /// Synchronizes on two repetitions of `sync_word` instead of 0x4489
+    pub fn with_sync_word(sink: T, sync_word: u16) -> Self {
         Self {
             sink,
             sync_buffer: 0,
             word_buffer: 0,
             in_sync: false,
             shift_count: 0,
+            sync_pattern: (u32::from(sync_word) << 16) | u32::from(sync_word),
         }
     }
 
     pub fn feed(&mut self, cell: Bit) {
         self.sync_buffer = (self.sync_buffer << 1) | u64::from(cell.0);
-        if (self.sync_buffer & 0xffff_ffff) == 0x4489_4489 {
+        if (self.sync_buffer & 0xffff_ffff) == u64::from(self.sync_pattern) {
             self.in_sync = true;
             self.shift_count = 0;
             self.word_buffer = 0;
@@ -275,4 +305,68 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn mfm_decoder_resync_test() {
+        let input = [
+            MfmWord::SyncWord,
+            MfmWord::SyncWord,
+            MfmWord::SyncWord,
+            MfmWord::Enc(1),
+            MfmWord::Enc(2),
+            MfmWord::Enc(3),
+            MfmWord::Enc(0x4e),
+            MfmWord::Enc(0x4e),
+            MfmWord::SyncWord,
+            MfmWord::SyncWord,
+            MfmWord::SyncWord,
+            MfmWord::Enc(0xfe),
+            MfmWord::Enc(5),
+        ];
+        let mut cells: Vec<Bit> = Vec::new();
+        let mut encoder = MfmEncoder::new(|val| cells.push(val));
+        input.iter().for_each(|word| encoder.feed(*word));
+
+        let decode = |cells: &[Bit]| {
+            let mut result: Vec<MfmWord> = Vec::new();
+            let mut decoder = MfmDecoder::new(|val| result.push(val));
+            cells.iter().for_each(|cell| decoder.feed(*cell));
+            result
+        };
+
+        assert_eq!(
+            decode(&cells),
+            vec![
+                MfmWord::SyncWord,
+                MfmWord::Enc(1),
+                MfmWord::Enc(2),
+                MfmWord::Enc(3),
+                MfmWord::Enc(0x4e),
+                MfmWord::Enc(0x4e),
+                // The sync word is only detected after three repetitions
+                MfmWord::Enc(ISO_SYNC_BYTE),
+                MfmWord::Enc(ISO_SYNC_BYTE),
+                MfmWord::SyncWord,
+                MfmWord::Enc(0xfe),
+                MfmWord::Enc(5),
+            ]
+        );
+
+        // Damage the second data byte. Nothing after it is trustworthy until the next sync.
+        cells
+            .iter_mut()
+            .skip(4 * 16)
+            .take(16)
+            .for_each(|cell| *cell = Bit(true));
+        assert_eq!(
+            decode(&cells),
+            vec![
+                MfmWord::SyncWord,
+                MfmWord::Enc(1),
+                MfmWord::SyncWord,
+                MfmWord::Enc(0xfe),
+                MfmWord::Enc(5),
+            ]
+        );
+    }
 }